@@ -1,96 +1,1593 @@
-use std::fs::File;
-use std::io::{BufWriter, Write};
-use std::path::{Path, PathBuf};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsString;
+use std::fs::{File, Metadata, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, Instant};
 
 use tar::{Builder, Header, HeaderMode};
 
-use crate::processor::{Entry, EntryType};
+use crate::batch::{self, BatchWriter};
+use crate::cancel::Cancellation;
+use crate::detector::file_identity;
+use crate::dictionary::{self, Dictionary};
+use crate::journal::Progress;
+use crate::manifest::{Compromised, Manifest, ManifestDiff, ManifestEntry, EMBEDDED_MANIFEST_NAME};
+use crate::policy::Policy;
+use crate::processor::{Digest, Entry, EntryType};
+use crate::provenance::{self, Provenance, PROVENANCE_FILE_NAME};
+use crate::report::{self, SkipCode, SnapshotReport};
+use crate::warnings::Warning;
 
-pub fn create_tarball<P: AsRef<Path>>(
+/// Prefix marking an entry which records the removal of a path since an earlier snapshot.
+pub const WHITEOUT_PREFIX: &str = ".wh.";
+
+/// Size of the zeroed blocks closing every tarball.
+const END_OF_ARCHIVE_SIZE: u64 = 1024;
+
+/// How often a resumable tarball is synced and records how far it got.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A record of an entry written into the tarball, used to verify the archive afterwards.
+pub struct ArchivedEntry {
+    pub path: PathBuf,
+    pub digest: Option<Digest>,
+}
+
+/// An archive written by `write_archive`, along with whatever the sink produced when finished.
+pub struct WrittenArchive<O> {
+    pub archived: Vec<ArchivedEntry>,
+    pub manifest: Manifest,
+    pub output: O,
+}
+
+/// A destination for the entries of a snapshot. Entries arrive in path order, with every path
+/// relative to the archive root.
+pub trait ArchiveSink {
+    type Output;
+    type Error: From<std::io::Error>;
+
+    /// Adds a file whose captured content is read from `encoded`: a single zstd frame of
+    /// `encoded_size` bytes, which must be read to the end. A file whose content wasn't captured
+    /// comes with nothing to read, and without a digest. `size` is the size of the content itself,
+    /// which for a drained named pipe isn't the one its metadata gives.
+    fn add_file(
+        &mut self,
+        metadata: &Metadata,
+        relative_path: &Path,
+        size: u64,
+        digest: Option<&Digest>,
+        encoded_size: u64,
+        encoded: &mut dyn Read,
+    ) -> Result<(), Self::Error>;
+
+    fn add_dir(&mut self, metadata: &Metadata, relative_path: &Path) -> Result<(), Self::Error>;
+
+    /// Adds a symlink whose target is given relative to the archive root, or relative to the link
+    /// itself when the link was relative to begin with.
+    fn add_symlink(
+        &mut self,
+        metadata: &Metadata,
+        relative_path: &Path,
+        target: &Path,
+    ) -> Result<(), Self::Error>;
+
+    /// Records the removal of a path since an earlier snapshot.
+    fn add_whiteout(&mut self, relative_path: &Path) -> Result<(), Self::Error>;
+
+    /// Records the manifest of the snapshot, with the digest of every file in it, once every entry
+    /// has been added.
+    fn add_manifest(&mut self, manifest: &Manifest) -> Result<(), Self::Error>;
+
+    /// Records how the snapshot was taken, after everything else.
+    fn add_provenance(&mut self, provenance: &Provenance) -> Result<(), Self::Error>;
+
+    fn finish(self) -> Result<Self::Output, Self::Error>;
+}
+
+/// Writes the captured entries into a tarball file, returning a record of what was written along
+/// with the manifest of the snapshot. The tarball is written beside its final path and only
+/// renamed into place once it's complete and synced, so a snapshot dying partway never leaves a
+/// truncated tarball behind under the final name.
+pub fn create_tarball<P1: AsRef<Path>, P2: AsRef<Path>>(
+    origin: P1,
+    entries: impl IntoIterator<Item = Entry>,
+    tarball_path: P2,
+    options: &TarOptions,
+    since: Option<&mut ManifestDiff>,
+    report: &mut SnapshotReport,
+) -> std::io::Result<(Vec<ArchivedEntry>, Manifest)> {
+    let tarball_path = tarball_path.as_ref();
+    log::info!("Creating tarbell at {}", tarball_path.display());
+    let mut temp_name = tarball_path.as_os_str().to_owned();
+    temp_name.push(format!(".tmp-{}", std::process::id()));
+    let temp_path = PathBuf::from(temp_name);
+
+    let written = create_file(&temp_path, options.output_mode)
+        .and_then(|file| {
+            if let Some(size) = options.preallocate {
+                log::debug!("Preallocating {} bytes for {}", size, temp_path.display());
+                file.set_len(size)?;
+            }
+            let sink = TarSink::new(BufWriter::new(file), options).resumable(&temp_path, 0);
+            write_tarball(origin, entries, &temp_path, sink, options, since, report)
+        })
+        .and_then(|written| {
+            std::fs::rename(&temp_path, tarball_path)?;
+            sync_parent(tarball_path)?;
+            Ok(written)
+        });
+    if written.is_err() {
+        discard_partial(&temp_path, options);
+    }
+    written
+}
+
+/// Carries on writing the tarball a snapshot left partway, keeping the entries it got to write
+/// and sync, then renames it into place the same as `create_tarball`. The entries must be those
+/// the snapshot was writing, in the same order.
+pub fn resume_tarball<P1: AsRef<Path>, P2: AsRef<Path>>(
+    origin: P1,
+    entries: impl IntoIterator<Item = Entry>,
+    tarball_path: P2,
+    progress: &Progress,
+    options: &TarOptions,
+    since: Option<&mut ManifestDiff>,
+    report: &mut SnapshotReport,
+) -> std::io::Result<(Vec<ArchivedEntry>, Manifest)> {
+    let tarball_path = tarball_path.as_ref();
+    let partial_path = &progress.partial_path;
+    log::info!(
+        "Resuming tarbell at {} after the {} entries written to {}",
+        tarball_path.display(),
+        progress.entries,
+        partial_path.display()
+    );
+    let written = OpenOptions::new()
+        .write(true)
+        .open(partial_path)
+        .and_then(|mut file| {
+            // anything past the last synced entry may be torn
+            file.set_len(progress.offset)?;
+            file.seek(SeekFrom::Start(progress.offset))?;
+            let sink = TarSink::new(BufWriter::new(file), options)
+                .resumable(partial_path, progress.entries);
+            write_tarball(origin, entries, partial_path, sink, options, since, report)
+        })
+        .and_then(|written| {
+            std::fs::rename(partial_path, tarball_path)?;
+            sync_parent(tarball_path)?;
+            Ok(written)
+        });
+    if written.is_err() {
+        discard_partial(partial_path, options);
+    }
+    written
+}
+
+/// Removes a tarball which failed partway, unless it's kept for the next run to resume.
+fn discard_partial(partial_path: &Path, options: &TarOptions) {
+    if options.progress.is_some() {
+        log::info!(
+            "Keeping {} for --resume to carry on writing",
+            partial_path.display()
+        );
+    } else if let Err(err) = std::fs::remove_file(partial_path) {
+        log::debug!("Failed to remove {}: {}", partial_path.display(), err);
+    }
+}
+
+fn write_tarball<P: AsRef<Path>>(
     origin: P,
-    entries: Vec<Entry>,
-    tarball_path: P,
-) -> std::io::Result<()> {
-    log::info!("Creating tarbell with {} entries", entries.len());
+    entries: impl IntoIterator<Item = Entry>,
+    tarball_path: &Path,
+    sink: TarSink<BufWriter<File>>,
+    options: &TarOptions,
+    since: Option<&mut ManifestDiff>,
+    report: &mut SnapshotReport,
+) -> std::io::Result<(Vec<ArchivedEntry>, Manifest)> {
+    let written = write_archive(origin, entries, sink, options, since, report)?;
+    let mut file = written
+        .output
+        .into_inner()
+        .map_err(|err| err.into_error())?;
+    if options.preallocate.is_some() {
+        let length = file.stream_position()?;
+        file.set_len(length)?;
+    }
+    file.sync_all()?;
+    check_tarball_size(tarball_path)?;
+    report.bytes_written = file.metadata()?.len();
+    Ok((written.archived, written.manifest))
+}
 
-    let tarball_file = File::create(tarball_path)?;
-    let tarball_writer = BufWriter::new(tarball_file);
+/// Creates a file for output, such as a tarball or its sidecars, with the given permissions. The
+/// permissions are set as the file is created, so its content is never readable more widely.
+pub fn create_file<P: AsRef<Path>>(path: P, mode: Option<u32>) -> std::io::Result<File> {
+    let mut open_options = OpenOptions::new();
+    open_options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        if let Some(mode) = mode {
+            open_options.mode(mode);
+            let file = open_options.open(path)?;
+            // the umask may have narrowed the mode, and an existing file keeps its own
+            file.set_permissions(std::fs::Permissions::from_mode(mode))?;
+            return Ok(file);
+        }
+    }
+    #[cfg(not(unix))]
+    if mode.is_some() {
+        log::warn!(
+            "Output permissions can't be set on this platform; creating {} with default permissions.",
+            path.as_ref().display()
+        );
+    }
+    open_options.open(path)
+}
+
+/// Syncs the directory holding a path, so a rename into it survives a crash.
+#[cfg(unix)]
+pub fn sync_parent(path: &Path) -> std::io::Result<()> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    File::open(parent)?.sync_all()
+}
 
-    let mut builder = Builder::new(tarball_writer);
-    builder.mode(HeaderMode::Complete);
+#[cfg(not(unix))]
+pub fn sync_parent(_path: &Path) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Appends the captured entries to an existing tarball, overwriting its end-of-archive marker at
+/// `offset`, as found by `archive_end`. Every entry is its own zstd frame, so nothing already in
+/// the tarball is recompressed. Should appending fail partway, the tarball is cut back to what it
+/// held before.
+pub fn append_tarball<P1: AsRef<Path>, P2: AsRef<Path>>(
+    origin: P1,
+    entries: impl IntoIterator<Item = Entry>,
+    tarball_path: P2,
+    offset: u64,
+    options: &TarOptions,
+    since: Option<&mut ManifestDiff>,
+    report: &mut SnapshotReport,
+) -> std::io::Result<(Vec<ArchivedEntry>, Manifest)> {
+    let tarball_path = tarball_path.as_ref();
+    log::info!(
+        "Appending to tarbell at {} from offset {}",
+        tarball_path.display(),
+        offset
+    );
+    let appended = append_entries(
+        origin,
+        entries,
+        tarball_path,
+        offset,
+        options,
+        since,
+        report,
+    );
+    if appended.is_err() {
+        // zeroes past the last old entry make up a fresh end-of-archive marker
+        let restored = OpenOptions::new()
+            .write(true)
+            .open(tarball_path)
+            .and_then(|file| {
+                file.set_len(offset)?;
+                file.set_len(offset + 1024)
+            });
+        if let Err(err) = restored {
+            log::error!(
+                "Failed to cut {} back to its previous entries: {}",
+                tarball_path.display(),
+                err
+            );
+        }
+    }
+    appended
+}
+
+fn append_entries<P: AsRef<Path>>(
+    origin: P,
+    entries: impl IntoIterator<Item = Entry>,
+    tarball_path: &Path,
+    offset: u64,
+    options: &TarOptions,
+    since: Option<&mut ManifestDiff>,
+    report: &mut SnapshotReport,
+) -> std::io::Result<(Vec<ArchivedEntry>, Manifest)> {
+    let mut file = OpenOptions::new().write(true).open(tarball_path)?;
+    file.seek(SeekFrom::Start(offset))?;
+    let sink = TarSink::new(BufWriter::new(file), options);
+    let written = write_archive(origin, entries, sink, options, since, report)?;
+    let mut file = written
+        .output
+        .into_inner()
+        .map_err(|err| err.into_error())?;
+    // whatever followed the old end-of-archive marker is no longer part of the tarball
+    let length = file.stream_position()?;
+    file.set_len(length)?;
+    check_tarball_size(tarball_path)?;
+    report.bytes_written = length;
+    Ok((written.archived, written.manifest))
+}
+
+/// Estimates how large a tarball of the given entries grows: a header and padding for every
+/// entry on top of its capture, plus the end-of-archive marker.
+pub fn estimate_size(entry_count: usize, encoded_bytes: u64) -> u64 {
+    encoded_bytes + entry_count as u64 * 1024 + 1024
+}
 
-    let origin_buf = origin.as_ref().to_path_buf();
+/// Magic numbers of the formats a whole tarball may be compressed with: zstd, gzip, xz and bzip2.
+const STREAM_MAGIC: [&[u8]; 4] = [
+    &[0x28, 0xB5, 0x2F, 0xFD],
+    &[0x1F, 0x8B],
+    &[0xFD, b'7', b'z', b'X', b'Z', 0x00],
+    b"BZh",
+];
+
+/// Finds the offset of a tarball's end-of-archive marker, right after its last entry. Refuses a
+/// tarball compressed as a whole, which can't be appended to without rewriting it.
+pub fn archive_end<P: AsRef<Path>>(tarball_path: P) -> std::io::Result<u64> {
+    let mut reader = BufReader::new(File::open(tarball_path)?);
+    let start = reader.fill_buf()?;
+    if STREAM_MAGIC.iter().any(|magic| start.starts_with(magic)) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "archive is compressed as a whole stream; only archives written by saved_in_time, \
+             which compress each member on its own, can be appended to",
+        ));
+    }
+    entries_end(reader)
+}
+
+/// Finds the offset right after the last entry of a tar stream, seeking past every entry's data.
+fn entries_end<R: Read + Seek>(reader: R) -> std::io::Result<u64> {
+    let mut archive = tar::Archive::new(reader);
+    let mut end = 0;
+    for entry in archive.entries_with_seek()? {
+        let entry = entry?;
+        end = entry.raw_file_position() + entry.size().div_ceil(512) * 512;
+    }
+    Ok(end)
+}
+
+/// Checks a finished tarball is exactly as long as its entries and end-of-archive marker add up
+/// to, so an entry whose data fell short of or ran past its header is caught before the tarball
+/// is put in place.
+fn check_tarball_size(tarball_path: &Path) -> std::io::Result<()> {
+    let file = File::open(tarball_path)?;
+    let length = file.metadata()?.len();
+    let expected = entries_end(BufReader::new(file))? + END_OF_ARCHIVE_SIZE;
+    if length != expected {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "Tarball is {} bytes but its entries account for {}",
+                length, expected
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Hands the captured entries to the sink as they stream in, releasing each entry's capture once
+/// it's been added. Files unchanged since the snapshot being diffed against are left out, and
+/// paths removed since are recorded as whiteouts.
+pub fn write_archive<P: AsRef<Path>, S: ArchiveSink>(
+    origin: P,
+    entries: impl IntoIterator<Item = Entry>,
+    sink: S,
+    options: &TarOptions,
+    since: Option<&mut ManifestDiff>,
+    report: &mut SnapshotReport,
+) -> Result<WrittenArchive<S::Output>, S::Error> {
+    let start = Instant::now();
+    let written = write_entries(origin.as_ref(), entries, sink, options, since, report);
+    report.durations.archive_ms = report::millis(start.elapsed());
+    if let Some(largest) = &mut report.largest {
+        largest.finish();
+    }
+    if let Some(breakdown) = &mut report.breakdown {
+        let origin = origin.as_ref();
+        // subtrees filtered out never stream past, but still have their rows
+        for skipped in &report.skipped {
+            if skipped.code != SkipCode::Excluded || !skipped.path.is_dir() {
+                continue;
+            }
+            if let Ok(relative_path) = find_relative_path(origin, &skipped.path) {
+                breakdown.record_directory(&relative_path);
+            }
+        }
+        breakdown.finish();
+    }
+    written
+}
 
+fn write_entries<S: ArchiveSink>(
+    origin: &Path,
+    entries: impl IntoIterator<Item = Entry>,
+    mut sink: S,
+    options: &TarOptions,
+    mut since: Option<&mut ManifestDiff>,
+    report: &mut SnapshotReport,
+) -> Result<WrittenArchive<S::Output>, S::Error> {
+    let mut archived = Vec::new();
+    let mut manifest = Manifest::new(report.hash_algorithm, Vec::new());
+    manifest.modified_window = report.modified_window.clone();
+    // link count of each hard linked inode, along with the manifest indices of its files
+    let mut link_sets: HashMap<(u64, u64), (u64, Vec<usize>)> = HashMap::new();
     for entry in entries {
-        let relative_path = find_relative_path(&origin_buf, &entry.path);
+        // a snapshot which ran out of time while capturing still archives what it captured
+        if options.cancel.keep_partial() {
+            if !report.partial {
+                log::warn!(
+                    "Ran out of time while archiving; finishing with the entries written so far"
+                );
+                report.partial = true;
+                break;
+            }
+        } else {
+            options.cancel.check()?;
+        }
+        let manifest_entry = ManifestEntry::from_entry(origin, &entry)?;
+        if report.largest.is_some() || report.breakdown.is_some() {
+            tally(report, &manifest_entry, &entry.entry_type)?;
+        }
+        let links = link_count(&entry.metadata);
+        if matches!(entry.entry_type, EntryType::File(..)) && links > 1 {
+            if let Some(identity) = file_identity(&entry.metadata) {
+                link_sets
+                    .entry(identity)
+                    .or_insert_with(|| (links, Vec::new()))
+                    .1
+                    .push(manifest.entries.len());
+            }
+        }
+        let unchanged = since
+            .as_mut()
+            .is_some_and(|diff| diff.observe(&manifest_entry));
+        if let (false, Some(compressed_size), Some(level)) = (
+            unchanged,
+            manifest_entry.compressed_size,
+            manifest_entry.level,
+        ) {
+            report
+                .compression
+                .record(level, manifest_entry.size, compressed_size);
+        }
+        manifest.entries.push(manifest_entry);
+        if unchanged {
+            log::debug!("Skipping unchanged entry {}", entry.path.display());
+            report.unchanged_files += 1;
+            continue;
+        }
 
+        let relative_path = find_relative_path(origin, &entry.path)?;
         match entry.entry_type {
-            EntryType::File(data) => {
-                let mut header = Header::new_old();
-                header.set_metadata(&entry.metadata);
-                let size = data.metadata()?.len();
-                log::debug!("New entry {} with size {}", relative_path.display(), size);
-                header.set_size(size);
-                header.set_cksum();
-                builder.append_data(&mut header, &relative_path, data)?;
-            }
-            EntryType::Symlink => match entry.path.read_link() {
+            EntryType::File(data, digest) => {
+                let size = entry.metadata.len();
+                add_captured(
+                    &mut sink,
+                    &entry.metadata,
+                    &relative_path,
+                    size,
+                    digest.as_ref(),
+                    data,
+                )?;
+                archived.push(ArchivedEntry {
+                    path: relative_path,
+                    digest,
+                });
+                report.files += 1;
+            }
+            // a drained named pipe is archived as a file holding what was drained from it
+            EntryType::Drained(data, digest, size) => {
+                add_captured(
+                    &mut sink,
+                    &entry.metadata,
+                    &relative_path,
+                    size,
+                    Some(&digest),
+                    data,
+                )?;
+                archived.push(ArchivedEntry {
+                    path: relative_path,
+                    digest: Some(digest),
+                });
+                report.files += 1;
+            }
+            EntryType::Symlink(_) => match entry.path.read_link() {
                 Ok(link) => {
-                    if !link.starts_with(origin.as_ref()) {
+                    let resolved = resolve_link(&entry.path, &link);
+                    if !resolved.starts_with(origin) {
                         log::error!(
                             "Symlink points outside of the target directory: {}",
                             link.display()
                         );
+                        report.skip(
+                            &options.policy,
+                            &entry.path,
+                            SkipCode::OutsideTarget,
+                            "symlink points outside of the target",
+                        );
+                        check_refused(report)?;
                         continue;
                     }
-                    let mut header = Header::new_old();
-                    header.set_metadata(&entry.metadata);
-                    header.set_entry_type(tar::EntryType::Symlink);
-                    header.set_size(0);
-                    header.set_cksum();
                     log::debug!(
                         "New symlink {} -> {}",
                         relative_path.display(),
                         link.display()
                     );
-                    builder.append_link(
-                        &mut header,
-                        relative_path,
-                        find_relative_path(&origin_buf, link),
-                    )?;
+                    // relative links keep pointing at the same entry wherever they're restored,
+                    // so absolute ones are rewritten relative to the link's own directory
+                    let target = if link.is_relative() {
+                        link
+                    } else {
+                        relative_link(&entry.path, &resolved)
+                    };
+                    sink.add_symlink(&entry.metadata, &relative_path, &target)?;
+                    archived.push(ArchivedEntry {
+                        path: relative_path,
+                        digest: None,
+                    });
+                    report.symlinks += 1;
                 }
-                Err(_) => {
+                Err(err) => {
                     log::error!("Failed to resolve symlink: {}", entry.path.display());
-                    continue;
+                    report.skip(&options.policy, &entry.path, SkipCode::Unreadable, err);
+                    check_refused(report)?;
                 }
             },
-            EntryType::Directory => {
+            EntryType::Directory(_) => {
                 log::debug!("New directory {}", relative_path.display());
-                builder.append_dir(&relative_path, &relative_path)?;
+                sink.add_dir(&entry.metadata, &relative_path)?;
+                archived.push(ArchivedEntry {
+                    path: relative_path,
+                    digest: None,
+                });
+                report.directories += 1;
             }
         }
     }
 
-    builder.into_inner()?.flush()
+    for (links, indices) in link_sets.into_values() {
+        if indices.len() as u64 >= links {
+            continue;
+        }
+        report.warnings.record(Warning::PartialHardlink);
+        log::warn!(
+            "File {} has {} hard links but only {} are in the snapshot; restoring it won't recreate the others.",
+            manifest.entries[indices[0]].path.display(),
+            links,
+            indices.len()
+        );
+        for index in indices {
+            manifest.entries[index].partial_hardlink = true;
+        }
+    }
+
+    // paths a partial snapshot didn't get to aren't known to be removed
+    if let Some(diff) = since.filter(|_| !report.partial) {
+        for removed_path in diff.removed() {
+            let whiteout_path = whiteout_path(&removed_path);
+            log::debug!("New whiteout {}", whiteout_path.display());
+            sink.add_whiteout(&removed_path)?;
+            archived.push(ArchivedEntry {
+                path: whiteout_path,
+                digest: None,
+            });
+            report.whiteouts += 1;
+        }
+    }
+
+    log::info!("Wrote {} entries", archived.len());
+    manifest.compromises = report
+        .compromises()
+        .map(|(skipped, compromise)| Compromised {
+            // a listed path outside the target has no name in the archive
+            path: find_relative_path(origin, &skipped.path)
+                .unwrap_or_else(|_| skipped.path.clone()),
+            compromise,
+            reason: skipped.reason.clone(),
+        })
+        .collect();
+    sink.add_manifest(&manifest)?;
+    sink.add_provenance(&Provenance {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        id: report.id.clone(),
+        revision: report.timestamp.clone(),
+        target: origin.to_path_buf(),
+        invoked_as: report.invoked_as.clone(),
+        arguments: provenance::recorded_arguments(std::env::args().skip(1)),
+        hash_algorithm: report.hash_algorithm,
+        dictionary_id: report.dictionary_id,
+        metadata_only: report.metadata_only,
+        partial: report.partial,
+        entry_count: archived.len(),
+    })?;
+    Ok(WrittenArchive {
+        archived,
+        manifest,
+        output: sink.finish()?,
+    })
 }
 
-fn find_relative_path<P1: AsRef<Path>, P2: AsRef<Path>>(origin: P1, relative: P2) -> PathBuf {
-    let origin_path_path = origin.as_ref();
-    let relative_path = relative.as_ref();
+/// Format a snapshot archive is written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ArchiveFormat {
+    /// A tarball, or a cpio archive or SquashFS image when the output is named for one.
+    Tar,
+    /// A zip archive, readable by the tools Windows comes with.
+    Zip,
+}
 
-    if !relative_path.starts_with(origin_path_path) {
-        panic!(
-            "Something went wrong; could not find relative path between {} and {}",
-            origin_path_path.display(),
-            relative_path.display()
+/// Settings controlling how entries are laid out in a tarball.
+#[derive(Clone)]
+pub struct TarOptions {
+    /// Files up to this many bytes are batched per directory.
+    pub batch_threshold: Option<u64>,
+    pub compression_level: i32,
+    /// Dictionary captured files were compressed with, which batches are compressed with too.
+    pub dictionary: Option<Dictionary>,
+    /// Mode recorded for every file instead of its captured mode.
+    pub file_mode: Option<u32>,
+    /// Mode recorded for every directory instead of its captured mode.
+    pub dir_mode: Option<u32>,
+    /// Bytes to reserve for a new tarball up front, trimmed to what was written once it's done.
+    pub preallocate: Option<u64>,
+    /// Permissions of a new tarball instead of those the umask allows.
+    pub output_mode: Option<u32>,
+    /// Record the manifest of the snapshot in the tarball, so restoring can check every file
+    /// against the digest it was captured with.
+    pub embed_manifest: bool,
+    /// Checkpoint directory a new tarball records how far it got in, so writing it can resume
+    /// after a crash. Nothing is recorded while batching, as batches are only written at the end.
+    pub progress: Option<PathBuf>,
+    /// How the archive treats the entries it can't write faithfully.
+    pub policy: Policy,
+    /// Asks writing the archive to stop once the entry being written is done.
+    pub cancel: Cancellation,
+}
+
+/// A writer whose output can be synced to disk, so progress recorded after it survives a crash.
+pub trait SyncWrite: Write + Seek {
+    fn sync(&mut self) -> std::io::Result<()>;
+}
+
+impl SyncWrite for BufWriter<File> {
+    fn sync(&mut self) -> std::io::Result<()> {
+        self.flush()?;
+        self.get_ref().sync_data()
+    }
+}
+
+/// Writes entries into a tar stream, storing every file as its own zstd frame. Small files can be
+/// batched per directory instead; batches are buffered and written once every entry has been
+/// added, ahead of the records describing the snapshot.
+pub struct TarSink<W: Write> {
+    builder: Builder<W>,
+    options: TarOptions,
+    batches: BTreeMap<PathBuf, BatchWriter>,
+    /// Number of files, directories and symlinks added so far.
+    added: usize,
+    /// Number of leading entries already in the tarball, which are passed over.
+    skip: usize,
+    /// The tarball under its temporary name, and when its progress was last recorded, for a
+    /// tarball which can be resumed.
+    progress: Option<(PathBuf, Option<Instant>)>,
+}
+
+impl<W: SyncWrite> TarSink<W> {
+    pub fn new(sink: W, options: &TarOptions) -> Self {
+        let mut builder = Builder::new(sink);
+        builder.mode(HeaderMode::Complete);
+        Self {
+            builder,
+            options: options.clone(),
+            batches: BTreeMap::new(),
+            added: 0,
+            skip: 0,
+            progress: None,
+        }
+    }
+
+    /// Records how far the tarball at `partial_path` gets in the options' checkpoint directory,
+    /// passing over its first `skip` entries, which an earlier run already wrote.
+    pub fn resumable(mut self, partial_path: &Path, skip: usize) -> Self {
+        if self.options.progress.is_some() && self.options.batch_threshold.is_none() {
+            self.progress = Some((partial_path.to_path_buf(), None));
+            self.skip = skip;
+        }
+        self
+    }
+
+    /// Whether the next entry was already written by an earlier run, counting it either way.
+    fn pass_over(&mut self) -> bool {
+        self.added += 1;
+        self.added <= self.skip
+    }
+
+    /// Syncs what was written and records how far it got, if it hasn't for a while.
+    fn record_progress(&mut self) -> std::io::Result<()> {
+        let (Some((partial_path, recorded)), Some(directory)) =
+            (&mut self.progress, &self.options.progress)
+        else {
+            return Ok(());
+        };
+        // the first entry is recorded right away, so the tarball can be found however soon the
+        // snapshot dies
+        if recorded.is_some_and(|recorded| recorded.elapsed() < PROGRESS_INTERVAL) {
+            return Ok(());
+        }
+        let sink = self.builder.get_mut();
+        sink.sync()?;
+        let progress = Progress {
+            partial_path: partial_path.clone(),
+            entries: self.added,
+            offset: sink.stream_position()?,
+        };
+        progress.write(directory)?;
+        log::debug!(
+            "Recorded {} entries written up to offset {}",
+            progress.entries,
+            progress.offset
         );
+        *recorded = Some(Instant::now());
+        Ok(())
+    }
+
+    fn append_generated(&mut self, path: &Path, data: &[u8]) -> std::io::Result<()> {
+        let mut header = Header::new_old();
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_mode(0o644);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mtime(0);
+        header.set_size(data.len() as u64);
+        header.set_cksum();
+        self.builder.append_data(&mut header, path, data)
+    }
+
+    fn write_batches(&mut self) -> std::io::Result<()> {
+        for (directory, batch) in std::mem::take(&mut self.batches) {
+            let batch_path = directory.join(batch::BATCH_FILE_NAME);
+            let data = batch.finish(
+                self.options.compression_level,
+                self.options.dictionary.as_ref(),
+            )?;
+            log::debug!(
+                "New batch {} with size {}",
+                batch_path.display(),
+                data.len()
+            );
+            self.append_generated(&batch_path, &data)?;
+        }
+        Ok(())
+    }
+}
+
+impl<W: SyncWrite> ArchiveSink for TarSink<W> {
+    type Output = W;
+    type Error = std::io::Error;
+
+    fn add_file(
+        &mut self,
+        metadata: &Metadata,
+        relative_path: &Path,
+        size: u64,
+        _digest: Option<&Digest>,
+        encoded_size: u64,
+        encoded: &mut dyn Read,
+    ) -> std::io::Result<()> {
+        if self.pass_over() {
+            std::io::copy(encoded, &mut std::io::sink())?;
+            return Ok(());
+        }
+        // a file captured without its content has nothing to batch, and stays an empty entry
+        if self
+            .options
+            .batch_threshold
+            .is_some_and(|threshold| size <= threshold)
+            && encoded_size > 0
+            && is_batchable(relative_path)
+        {
+            let name = relative_path.file_name().unwrap().to_str().unwrap();
+            let directory = relative_path.parent().unwrap().to_path_buf();
+            log::debug!("Batching entry {}", relative_path.display());
+            return self
+                .batches
+                .entry(directory)
+                .or_insert_with(BatchWriter::new)
+                .push(
+                    name,
+                    metadata,
+                    self.options.file_mode,
+                    dictionary::decoder(encoded, self.options.dictionary.as_ref())?,
+                );
+        }
+        let mut header = Header::new_old();
+        header.set_metadata(metadata);
+        // a drained named pipe is stored as the file it was captured as
+        header.set_entry_type(tar::EntryType::Regular);
+        if let Some(mode) = self.options.file_mode {
+            header.set_mode(mode);
+        }
+        header.set_size(encoded_size);
+        header.set_cksum();
+        self.builder
+            .append_data(&mut header, relative_path, encoded)?;
+        self.record_progress()
+    }
+
+    fn add_dir(&mut self, metadata: &Metadata, relative_path: &Path) -> std::io::Result<()> {
+        if self.pass_over() {
+            return Ok(());
+        }
+        let mut header = Header::new_old();
+        header.set_metadata(metadata);
+        if let Some(mode) = self.options.dir_mode {
+            header.set_mode(mode);
+        }
+        header.set_size(0);
+        header.set_cksum();
+        self.builder
+            .append_data(&mut header, relative_path, std::io::empty())?;
+        self.record_progress()
+    }
+
+    fn add_symlink(
+        &mut self,
+        metadata: &Metadata,
+        relative_path: &Path,
+        target: &Path,
+    ) -> std::io::Result<()> {
+        if self.pass_over() {
+            return Ok(());
+        }
+        let mut header = Header::new_old();
+        header.set_metadata(metadata);
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_size(0);
+        header.set_cksum();
+        // a target too long for the header's 100 bytes goes ahead of it in a GNU long link
+        // record, the same way long paths go in long name records
+        self.builder
+            .append_link(&mut header, relative_path, target)?;
+        self.record_progress()
+    }
+
+    fn add_whiteout(&mut self, relative_path: &Path) -> std::io::Result<()> {
+        self.append_generated(&whiteout_path(relative_path), &[])
+    }
+
+    fn add_manifest(&mut self, manifest: &Manifest) -> std::io::Result<()> {
+        // every entry has been added, so the batches are complete
+        self.write_batches()?;
+        if self.options.embed_manifest {
+            let data = serde_json::to_vec(manifest)?;
+            self.append_generated(Path::new(EMBEDDED_MANIFEST_NAME), &data)?;
+        }
+        Ok(())
+    }
+
+    fn add_provenance(&mut self, provenance: &Provenance) -> std::io::Result<()> {
+        let data = serde_json::to_vec_pretty(provenance)?;
+        self.append_generated(Path::new(PROVENANCE_FILE_NAME), &data)
+    }
+
+    /// Finishes the tarball, handing back the flushed sink.
+    fn finish(self) -> std::io::Result<W> {
+        let mut sink = self.builder.into_inner()?;
+        sink.flush()?;
+        Ok(sink)
     }
+}
+
+/// The path of the whiteout entry recording the removal of a path.
+pub fn whiteout_path(removed_path: &Path) -> PathBuf {
+    let mut whiteout_name = OsString::from(WHITEOUT_PREFIX);
+    whiteout_name.push(removed_path.file_name().unwrap_or_default());
+    removed_path.with_file_name(whiteout_name)
+}
 
-    let relative_path = origin_path_path.join(
-        relative_path
-            .strip_prefix(origin_path_path)
-            .expect("Something went wrong; could not strip prefix."),
+/// Fails once the policy refused to carry on past a path left out, so the archive is abandoned.
+fn check_refused(report: &SnapshotReport) -> std::io::Result<()> {
+    match &report.refusal {
+        Some(refusal) => Err(std::io::Error::other(format!(
+            "Refused to carry on past {}",
+            refusal
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Counts an entry towards the largest entries and the breakdown by directory.
+fn tally(
+    report: &mut SnapshotReport,
+    manifest_entry: &ManifestEntry,
+    entry_type: &EntryType,
+) -> std::io::Result<()> {
+    let path = &manifest_entry.path;
+    match entry_type {
+        EntryType::File(data, _) | EntryType::Drained(data, ..) => {
+            let encoded_size = data.metadata()?.len();
+            if let Some(largest) = &mut report.largest {
+                largest.record(path, manifest_entry.size, encoded_size);
+            }
+            if let Some(breakdown) = &mut report.breakdown {
+                breakdown.record_file(path, manifest_entry.size, encoded_size);
+            }
+        }
+        EntryType::Directory(_) => {
+            if let Some(breakdown) = &mut report.breakdown {
+                breakdown.record_directory(path);
+            }
+        }
+        EntryType::Symlink(_) => {}
+    }
+    Ok(())
+}
+
+/// Hands a file's capture to the sink, checking the sink read all of it.
+fn add_captured<S: ArchiveSink>(
+    sink: &mut S,
+    metadata: &Metadata,
+    relative_path: &Path,
+    size: u64,
+    digest: Option<&Digest>,
+    mut data: File,
+) -> Result<(), S::Error> {
+    // the processor leaves the cursor wherever encoding finished
+    data.seek(SeekFrom::Start(0))?;
+    let encoded_size = data.metadata()?.len();
+    log::debug!(
+        "New entry {} with size {}",
+        relative_path.display(),
+        encoded_size
     );
+    let mut reader = CountingReader::new(data);
+    sink.add_file(
+        metadata,
+        relative_path,
+        size,
+        digest,
+        encoded_size,
+        &mut reader,
+    )?;
+    if reader.count != encoded_size {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "Wrote {} bytes for {} but its header declares {}",
+                reader.count,
+                relative_path.display(),
+                encoded_size
+            ),
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Counts the bytes read through it, to check what was archived against the header size.
+struct CountingReader<R> {
+    inner: R,
+    count: u64,
+}
+
+impl<R> CountingReader<R> {
+    fn new(inner: R) -> Self {
+        Self { inner, count: 0 }
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.count += read as u64;
+        Ok(read)
+    }
+}
+
+#[cfg(unix)]
+pub fn link_count(metadata: &Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.nlink()
+}
+
+#[cfg(not(unix))]
+pub fn link_count(_metadata: &Metadata) -> u64 {
+    1
+}
+
+/// The bytes of a path as archive formats without a text encoding of their own store it.
+#[cfg(unix)]
+pub fn path_bytes(path: &Path) -> Cow<'_, [u8]> {
+    use std::os::unix::ffi::OsStrExt;
+    Cow::Borrowed(path.as_os_str().as_bytes())
+}
+
+#[cfg(not(unix))]
+pub fn path_bytes(path: &Path) -> Cow<'_, [u8]> {
+    Cow::Owned(path.to_string_lossy().replace('\\', "/").into_bytes())
+}
+
+/// The path stored as the bytes `path_bytes` gives.
+#[cfg(unix)]
+pub fn bytes_path(bytes: &[u8]) -> PathBuf {
+    use std::os::unix::ffi::OsStrExt;
+    PathBuf::from(std::ffi::OsStr::from_bytes(bytes))
+}
+
+#[cfg(not(unix))]
+pub fn bytes_path(bytes: &[u8]) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(bytes).into_owned())
+}
+
+/// Lexically resolves where a symlink points, without following any links along the way.
+pub fn resolve_link(link_path: &Path, link: &Path) -> PathBuf {
+    let joined = link_path.parent().unwrap_or(link_path).join(link);
+    let mut resolved = PathBuf::new();
+    for component in joined.components() {
+        match component {
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            Component::CurDir => {}
+            component => resolved.push(component),
+        }
+    }
+    resolved
+}
+
+/// Expresses a resolved link target as a path relative to the directory holding the link.
+pub fn relative_link(link_path: &Path, resolved: &Path) -> PathBuf {
+    let directory = resolve_link(link_path, Path::new("."));
+    let common = directory
+        .components()
+        .zip(resolved.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let mut relative = PathBuf::new();
+    for _ in directory.components().skip(common) {
+        relative.push(Component::ParentDir);
+    }
+    for component in resolved.components().skip(common) {
+        relative.push(component);
+    }
+    if relative.as_os_str().is_empty() {
+        relative.push(Component::CurDir);
+    }
+    relative
+}
+
+fn is_batchable(relative_path: &Path) -> bool {
+    relative_path.parent().is_some()
+        && relative_path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(batch::is_batchable_name)
+}
+
+/// Finds the path an entry is stored under in the archive: the entry's path relative to the
+/// origin, nested under the origin's own directory name. The tar crate writes its separators as
+/// forward slashes on every platform, so archives taken on Windows extract anywhere. Fails for a
+/// path outside the origin, which has no name in the archive.
+pub fn find_relative_path<P1: AsRef<Path>, P2: AsRef<Path>>(
+    origin: P1,
+    relative: P2,
+) -> std::io::Result<PathBuf> {
+    let origin_path = origin.as_ref();
+    let relative_path = relative.as_ref();
+
+    let stripped_path = relative_path.strip_prefix(origin_path).map_err(|_| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "{} is outside of {}",
+                relative_path.display(),
+                origin_path.display()
+            ),
+        )
+    })?;
+
+    Ok(match origin_path.file_name() {
+        Some(name) => Path::new(name).join(stripped_path),
+        None => stripped_path.to_path_buf(),
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::hashing::{HashAlgorithm, Hasher};
+    use crate::processor;
+
+    pub(crate) fn tar_options() -> TarOptions {
+        TarOptions {
+            batch_threshold: None,
+            compression_level: 3,
+            dictionary: None,
+            file_mode: None,
+            dir_mode: None,
+            preallocate: None,
+            output_mode: None,
+            embed_manifest: false,
+            progress: None,
+            policy: Policy::default(),
+            cancel: Cancellation::default(),
+        }
+    }
+
+    /// Snapshots the target into a tarball in `output`, returning its path and what was archived.
+    fn snapshot(target: &Path, output: &Path) -> (PathBuf, Vec<ArchivedEntry>) {
+        let options = processor::tests::options(output);
+        let mut report = SnapshotReport::default();
+        let processed = processor::process_directory(target, 5, &options, &mut report).unwrap();
+        let tarball_path = output.join("snapshot.tar.zst");
+        let (archived, _) = create_tarball(
+            target,
+            processed.entries,
+            &tarball_path,
+            &tar_options(),
+            None,
+            &mut report,
+        )
+        .unwrap();
+        assert_eq!(report.bytes_written, tarball_path.metadata().unwrap().len());
+        (tarball_path, archived)
+    }
+
+    /// Snapshots the target into a tarball written with the options, returning its bytes. The
+    /// revision a pass is stamped with is the one thing which differs between runs, so it's pinned.
+    fn pinned_tarball(target: &Path, tar_options: &TarOptions) -> Vec<u8> {
+        let output = tempfile::tempdir().unwrap();
+        let options = processor::tests::options(output.path());
+        let mut report = SnapshotReport::default();
+        let processed = processor::process_directory(target, 5, &options, &mut report).unwrap();
+        report.timestamp = Some("2024-01-01T12:00:00+00:00".to_string());
+        let tarball_path = output.path().join("snapshot.tar.zst");
+        create_tarball(
+            target,
+            processed.entries,
+            &tarball_path,
+            tar_options,
+            None,
+            &mut report,
+        )
+        .unwrap();
+        std::fs::read(tarball_path).unwrap()
+    }
+
+    #[test]
+    fn snapshots_of_an_unchanged_target_are_byte_for_byte_identical() {
+        let target = tempfile::tempdir().unwrap();
+        for path in ["b", "a/z", "a/y", "c"] {
+            std::fs::create_dir_all(target.path().join(path).parent().unwrap()).unwrap();
+            std::fs::write(target.path().join(path), path.repeat(100)).unwrap();
+        }
+        assert!(
+            pinned_tarball(target.path(), &tar_options())
+                == pinned_tarball(target.path(), &tar_options()),
+            "two snapshots of the same target differ"
+        );
+    }
 
-    relative_path
+    /// Nothing to sync in memory.
+    impl SyncWrite for std::io::Cursor<Vec<u8>> {
+        fn sync(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn tarballs_written_in_memory_match_those_written_to_disk() {
+        let target = tempfile::tempdir().unwrap();
+        for path in ["a", "sub/b", "sub/c"] {
+            std::fs::create_dir_all(target.path().join(path).parent().unwrap()).unwrap();
+            std::fs::write(target.path().join(path), path.repeat(100)).unwrap();
+        }
+        let output = tempfile::tempdir().unwrap();
+        let options = processor::tests::options(output.path());
+        let mut report = SnapshotReport::default();
+        let processed =
+            processor::process_directory(target.path(), 5, &options, &mut report).unwrap();
+        report.timestamp = Some("2024-01-01T12:00:00+00:00".to_string());
+        let sink = TarSink::new(std::io::Cursor::new(Vec::new()), &tar_options());
+        let written = write_archive(
+            target.path(),
+            processed.entries,
+            sink,
+            &tar_options(),
+            None,
+            &mut report,
+        )
+        .unwrap();
+
+        assert_eq!(written.archived.len(), 5);
+        assert!(
+            written.output.into_inner() == pinned_tarball(target.path(), &tar_options()),
+            "the tarball written in memory differs from the one written to disk"
+        );
+    }
+
+    /// Records every call it's handed as a line, reading each file's capture to the end.
+    #[derive(Default)]
+    struct RecordingSink {
+        calls: Vec<String>,
+    }
+
+    impl ArchiveSink for RecordingSink {
+        type Output = Vec<String>;
+        type Error = std::io::Error;
+
+        fn add_file(
+            &mut self,
+            _metadata: &Metadata,
+            relative_path: &Path,
+            size: u64,
+            digest: Option<&Digest>,
+            encoded_size: u64,
+            encoded: &mut dyn Read,
+        ) -> std::io::Result<()> {
+            let mut captured = Vec::new();
+            encoded.read_to_end(&mut captured)?;
+            assert_eq!(captured.len() as u64, encoded_size);
+            let content = String::from_utf8(zstd::decode_all(&captured[..])?).unwrap();
+            assert_eq!(content.len() as u64, size);
+            assert!(digest.is_some());
+            self.calls
+                .push(format!("file {}: {}", relative_path.display(), content));
+            Ok(())
+        }
+
+        fn add_dir(&mut self, _metadata: &Metadata, relative_path: &Path) -> std::io::Result<()> {
+            self.calls.push(format!("dir {}", relative_path.display()));
+            Ok(())
+        }
+
+        fn add_symlink(
+            &mut self,
+            _metadata: &Metadata,
+            relative_path: &Path,
+            target: &Path,
+        ) -> std::io::Result<()> {
+            self.calls.push(format!(
+                "symlink {} -> {}",
+                relative_path.display(),
+                target.display()
+            ));
+            Ok(())
+        }
+
+        fn add_whiteout(&mut self, relative_path: &Path) -> std::io::Result<()> {
+            self.calls
+                .push(format!("whiteout {}", relative_path.display()));
+            Ok(())
+        }
+
+        fn add_manifest(&mut self, manifest: &Manifest) -> std::io::Result<()> {
+            self.calls
+                .push(format!("manifest of {}", manifest.entries.len()));
+            Ok(())
+        }
+
+        fn add_provenance(&mut self, provenance: &Provenance) -> std::io::Result<()> {
+            self.calls
+                .push(format!("provenance of {}", provenance.entry_count));
+            Ok(())
+        }
+
+        fn finish(self) -> std::io::Result<Vec<String>> {
+            Ok(self.calls)
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn sinks_are_handed_entries_in_path_order_then_the_records() {
+        let root = tempfile::tempdir().unwrap();
+        let target = root.path().join("target");
+        for path in ["b", "sub/c", "a"] {
+            std::fs::create_dir_all(target.join(path).parent().unwrap()).unwrap();
+            std::fs::write(target.join(path), path).unwrap();
+        }
+        std::os::unix::fs::symlink(target.join("a"), target.join("link")).unwrap();
+        let write = |since: Option<&mut ManifestDiff>| {
+            let output = tempfile::tempdir().unwrap();
+            let options = processor::tests::options(output.path());
+            let mut report = SnapshotReport::default();
+            let processed =
+                processor::process_directory(&target, 5, &options, &mut report).unwrap();
+            write_archive(
+                &target,
+                processed.entries,
+                RecordingSink::default(),
+                &tar_options(),
+                since,
+                &mut report,
+            )
+            .unwrap()
+        };
+
+        let written = write(None);
+        // absolute links are handed over relative to the link
+        assert_eq!(
+            written.output,
+            [
+                "dir target/",
+                "file target/a: a",
+                "file target/b: b",
+                "symlink target/link -> a",
+                "dir target/sub",
+                "file target/sub/c: sub/c",
+                "manifest of 6",
+                "provenance of 6",
+            ]
+        );
+
+        // unchanged files are left out, and a path gone since is handed over as a whiteout
+        let mut previous = written.manifest;
+        let mut gone = previous.entries[1].clone();
+        gone.path = PathBuf::from("target/gone");
+        previous.entries.push(gone);
+        let mut diff = ManifestDiff::new(&previous, true);
+        assert_eq!(
+            write(Some(&mut diff)).output,
+            [
+                "dir target/",
+                "symlink target/link -> a",
+                "dir target/sub",
+                "whiteout target/gone",
+                "manifest of 6",
+                "provenance of 4",
+            ]
+        );
+    }
+
+    #[test]
+    fn preallocated_tarballs_are_trimmed_to_what_was_written() {
+        let target = tempfile::tempdir().unwrap();
+        std::fs::write(target.path().join("file"), "content".repeat(1000)).unwrap();
+        let expected = pinned_tarball(target.path(), &tar_options());
+        // estimates run both over and under what's written
+        for size in [1 << 20, 1] {
+            let options = TarOptions {
+                preallocate: Some(size),
+                ..tar_options()
+            };
+            assert!(
+                pinned_tarball(target.path(), &options) == expected,
+                "preallocating {} bytes changed the tarball",
+                size
+            );
+        }
+    }
+
+    #[test]
+    fn a_failed_write_leaves_the_previous_tarball_in_place() {
+        let target = tempfile::tempdir().unwrap();
+        std::fs::write(target.path().join("file"), "content").unwrap();
+        let output = tempfile::tempdir().unwrap();
+        let options = processor::tests::options(output.path());
+        let mut report = SnapshotReport::default();
+        let processed =
+            processor::process_directory(target.path(), 5, &options, &mut report).unwrap();
+        // a capture which can't be read back fails the write partway through the tarball
+        let unreadable = File::create(output.path().join("unreadable")).unwrap();
+        let entries = processed.entries.map(|mut entry| {
+            if let EntryType::File(file, _) = &mut entry.entry_type {
+                *file = unreadable.try_clone().unwrap();
+            }
+            entry
+        });
+
+        let tarball_path = output.path().join("snapshot.tar.zst");
+        std::fs::write(&tarball_path, "the previous snapshot").unwrap();
+        let written = create_tarball(
+            target.path(),
+            entries,
+            &tarball_path,
+            &tar_options(),
+            None,
+            &mut report,
+        );
+        assert!(written.is_err());
+
+        assert_eq!(
+            std::fs::read(&tarball_path).unwrap(),
+            b"the previous snapshot"
+        );
+        let mut left: Vec<OsString> = std::fs::read_dir(output.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        left.sort();
+        assert_eq!(left, ["snapshot.tar.zst", "unreadable"]);
+    }
+
+    /// Whether nothing holds a lock on the file, which a capture it was swapped in for holds for
+    /// as long as it's open.
+    #[cfg(unix)]
+    fn released(path: &Path) -> bool {
+        use std::os::fd::AsRawFd;
+        let file = File::open(path).unwrap();
+        unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) == 0 }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn each_capture_is_released_once_written() {
+        use std::os::fd::AsRawFd;
+
+        let target = tempfile::tempdir().unwrap();
+        for path in ["a", "b", "sub/c", "sub/d"] {
+            std::fs::create_dir_all(target.path().join(path).parent().unwrap()).unwrap();
+            std::fs::write(target.path().join(path), path).unwrap();
+        }
+        let output = tempfile::tempdir().unwrap();
+        let options = processor::tests::options(output.path());
+        let mut report = SnapshotReport::default();
+        let processed =
+            processor::process_directory(target.path(), 5, &options, &mut report).unwrap();
+        // every capture is swapped for a copy locked for as long as it's open
+        let mut handed_out: Vec<PathBuf> = Vec::new();
+        let entries = processed.entries.map(|mut entry| {
+            for earlier in &handed_out {
+                assert!(
+                    released(earlier),
+                    "{} was still held when the next entry was asked for",
+                    earlier.display()
+                );
+            }
+            if let EntryType::File(file, _) = &mut entry.entry_type {
+                let copy = output.path().join(format!("capture-{}", handed_out.len()));
+                file.seek(SeekFrom::Start(0)).unwrap();
+                let mut locked = File::options()
+                    .read(true)
+                    .write(true)
+                    .create_new(true)
+                    .open(&copy)
+                    .unwrap();
+                std::io::copy(file, &mut locked).unwrap();
+                locked.seek(SeekFrom::Start(0)).unwrap();
+                assert_eq!(unsafe { libc::flock(locked.as_raw_fd(), libc::LOCK_EX) }, 0);
+                assert!(!released(&copy));
+                *file = locked;
+                handed_out.push(copy);
+            }
+            entry
+        });
+
+        let tarball_path = output.path().join("snapshot.tar.zst");
+        create_tarball(
+            target.path(),
+            entries,
+            &tarball_path,
+            &tar_options(),
+            None,
+            &mut report,
+        )
+        .unwrap();
+        assert_eq!(handed_out.len(), 4);
+        assert!(handed_out.iter().all(|capture| released(capture)));
+    }
+
+    #[test]
+    fn files_read_back_from_a_tarball_match_their_digests() {
+        let target = tempfile::tempdir().unwrap();
+        let mut fixture = BTreeMap::new();
+        fixture.insert("empty", Vec::new());
+        fixture.insert("small", b"small".to_vec());
+        // spans several read buffers, and compresses poorly
+        let large: Vec<u8> = (0..3 * processor::DEFAULT_READ_BUFFER_SIZE as u32)
+            .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+            .collect();
+        fixture.insert("sub/large", large);
+        for (path, content) in &fixture {
+            let path = target.path().join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+        let output = tempfile::tempdir().unwrap();
+        let (tarball_path, archived) = snapshot(target.path(), output.path());
+
+        let digests: BTreeMap<PathBuf, Digest> = archived
+            .into_iter()
+            .filter_map(|entry| Some((entry.path, entry.digest?)))
+            .collect();
+        let name = PathBuf::from(target.path().file_name().unwrap());
+        let mut tarball = tar::Archive::new(File::open(&tarball_path).unwrap());
+        let mut read_back = BTreeMap::new();
+        for member in tarball.entries().unwrap() {
+            let member = member.unwrap();
+            if member.header().entry_type() != tar::EntryType::Regular {
+                continue;
+            }
+            let path = member.path().unwrap().into_owned();
+            let Ok(relative) = path.strip_prefix(&name) else {
+                continue;
+            };
+            let mut content = Vec::new();
+            zstd::Decoder::new(member)
+                .unwrap()
+                .read_to_end(&mut content)
+                .unwrap();
+            let mut hasher = Hasher::new(HashAlgorithm::Sha256);
+            hasher.update(&content);
+            assert_eq!(Some(&hasher.finalize()), digests.get(&path), "{:?}", path);
+            read_back.insert(relative.to_string_lossy().into_owned(), content);
+        }
+        let fixture: BTreeMap<String, Vec<u8>> = fixture
+            .into_iter()
+            .map(|(path, content)| (path.to_string(), content))
+            .collect();
+        assert_eq!(read_back, fixture);
+    }
+
+    #[test]
+    fn a_tarball_cut_short_fails_the_size_check() {
+        let target = tempfile::tempdir().unwrap();
+        std::fs::write(target.path().join("file"), "content").unwrap();
+        let output = tempfile::tempdir().unwrap();
+        let (tarball_path, _) = snapshot(target.path(), output.path());
+        check_tarball_size(&tarball_path).unwrap();
+
+        let length = tarball_path.metadata().unwrap().len();
+        let file = OpenOptions::new().write(true).open(&tarball_path).unwrap();
+        file.set_len(length - 512).unwrap();
+        let err = check_tarball_size(&tarball_path).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_targets_past_the_header_limit_are_kept_whole() {
+        let target = tempfile::tempdir().unwrap();
+        let linked = PathBuf::from("d".repeat(60))
+            .join("e".repeat(60))
+            .join("file");
+        std::fs::create_dir_all(target.path().join(linked.parent().unwrap())).unwrap();
+        std::fs::write(target.path().join(&linked), "linked").unwrap();
+        std::os::unix::fs::symlink(&linked, target.path().join("link")).unwrap();
+        assert!(linked.as_os_str().len() > 100);
+        let output = tempfile::tempdir().unwrap();
+        let (tarball_path, _) = snapshot(target.path(), output.path());
+
+        let link_path = Path::new(target.path().file_name().unwrap()).join("link");
+        let mut tarball = tar::Archive::new(File::open(&tarball_path).unwrap());
+        let link = tarball
+            .entries()
+            .unwrap()
+            .map(Result::unwrap)
+            .find(|member| member.path().unwrap() == link_path)
+            .expect("the symlink wasn't archived");
+        assert_eq!(link.header().entry_type(), tar::EntryType::Symlink);
+        assert_eq!(link.link_name().unwrap().as_deref(), Some(linked.as_path()));
+
+        let restored = tempfile::tempdir().unwrap();
+        let options = crate::restorer::RestoreOptions::default();
+        crate::restorer::restore_tarball(&tarball_path, restored.path(), &options).unwrap();
+        let restored_link = restored.path().join(link_path);
+        assert_eq!(std::fs::read_link(&restored_link).unwrap(), linked);
+        assert_eq!(std::fs::read_to_string(&restored_link).unwrap(), "linked");
+    }
+
+    #[test]
+    fn paths_outside_the_origin_have_no_relative_path() {
+        let origin = Path::new("/srv/world");
+        assert_eq!(
+            find_relative_path(origin, "/srv/world/region/r.0.0.mca").unwrap(),
+            Path::new("world/region/r.0.0.mca")
+        );
+        let err = find_relative_path(origin, "/srv/other/level.dat").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
 }
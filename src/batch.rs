@@ -0,0 +1,173 @@
+use std::fs::Metadata;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::ops::Range;
+use std::time::UNIX_EPOCH;
+
+use crate::dictionary::{self, Dictionary};
+
+/// Name of the archive entry holding a directory's batched small files.
+pub const BATCH_FILE_NAME: &str = ".sit-batch";
+
+const BATCH_MAGIC: &str = "SITBATCH1";
+
+/// A file stored inside a batch, with the metadata needed to restore it.
+pub struct BatchMember {
+    pub name: String,
+    pub mode: u32,
+    pub uid: u64,
+    pub gid: u64,
+    pub mtime: u64,
+    pub data: Range<usize>,
+}
+
+/// Builds the content of a batch entry: an index describing every member followed by the
+/// members' concatenated bytes, all compressed into a single zstd frame.
+pub struct BatchWriter {
+    index: Vec<u8>,
+    data: Vec<u8>,
+    count: usize,
+}
+
+impl BatchWriter {
+    pub fn new() -> Self {
+        Self {
+            index: Vec::new(),
+            data: Vec::new(),
+            count: 0,
+        }
+    }
+
+    pub fn push(
+        &mut self,
+        name: &str,
+        metadata: &Metadata,
+        mode_override: Option<u32>,
+        mut data: impl Read,
+    ) -> std::io::Result<()> {
+        let start = self.data.len();
+        data.read_to_end(&mut self.data)?;
+        let (mode, uid, gid) = owner_and_mode(metadata);
+        let mode = mode_override.unwrap_or(mode);
+        let mtime = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |duration| duration.as_secs());
+        writeln!(
+            self.index,
+            "{} {:o} {} {} {} {}",
+            self.data.len() - start,
+            mode,
+            uid,
+            gid,
+            mtime,
+            name
+        )?;
+        self.count += 1;
+        Ok(())
+    }
+
+    pub fn finish(
+        self,
+        compression_level: i32,
+        dictionary: Option<&Dictionary>,
+    ) -> std::io::Result<Vec<u8>> {
+        let mut encoder = dictionary::encoder(Vec::new(), compression_level, dictionary)?;
+        writeln!(encoder, "{}", BATCH_MAGIC)?;
+        writeln!(encoder, "{}", self.count)?;
+        encoder.write_all(&self.index)?;
+        encoder.write_all(&self.data)?;
+        encoder.finish()
+    }
+}
+
+/// Whether a file name can be recorded in a batch index.
+pub fn is_batchable_name(name: &str) -> bool {
+    !name.contains('\n') && name != BATCH_FILE_NAME
+}
+
+/// Decompresses a batch entry, returning its members and the concatenated data they index into.
+pub fn read_batch<R: Read>(
+    reader: R,
+    dictionary: Option<&Dictionary>,
+) -> std::io::Result<(Vec<BatchMember>, Vec<u8>)> {
+    let mut reader = BufReader::new(dictionary::decoder(reader, dictionary)?);
+    let mut line = String::new();
+
+    reader.read_line(&mut line)?;
+    if line.trim_end() != BATCH_MAGIC {
+        return Err(invalid_batch("missing batch header"));
+    }
+    line.clear();
+    reader.read_line(&mut line)?;
+    let count: usize = line
+        .trim_end()
+        .parse()
+        .map_err(|_| invalid_batch("invalid member count"))?;
+
+    let mut members = Vec::with_capacity(count);
+    let mut offset = 0;
+    for _ in 0..count {
+        line.clear();
+        reader.read_line(&mut line)?;
+        let mut fields = line.trim_end_matches('\n').splitn(6, ' ');
+        let mut next_field = || {
+            fields
+                .next()
+                .ok_or_else(|| invalid_batch("truncated index"))
+        };
+        let length: usize = parse_field(next_field()?, 10)?;
+        let mode = parse_field(next_field()?, 8)? as u32;
+        let uid = parse_field(next_field()?, 10)? as u64;
+        let gid = parse_field(next_field()?, 10)? as u64;
+        let mtime = parse_field(next_field()?, 10)? as u64;
+        let name = next_field()?.to_string();
+        members.push(BatchMember {
+            name,
+            mode,
+            uid,
+            gid,
+            mtime,
+            data: offset..offset + length,
+        });
+        offset += length;
+    }
+
+    let mut data = Vec::with_capacity(offset);
+    reader.read_to_end(&mut data)?;
+    if data.len() != offset {
+        return Err(invalid_batch("data length does not match index"));
+    }
+    Ok((members, data))
+}
+
+fn parse_field(field: &str, radix: u32) -> std::io::Result<usize> {
+    usize::from_str_radix(field, radix).map_err(|_| invalid_batch("invalid index field"))
+}
+
+fn invalid_batch(reason: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("Invalid batch entry: {}", reason),
+    )
+}
+
+#[cfg(unix)]
+fn owner_and_mode(metadata: &Metadata) -> (u32, u64, u64) {
+    use std::os::unix::fs::MetadataExt;
+    (
+        metadata.mode() & 0o7777,
+        metadata.uid() as u64,
+        metadata.gid() as u64,
+    )
+}
+
+#[cfg(not(unix))]
+fn owner_and_mode(metadata: &Metadata) -> (u32, u64, u64) {
+    let mode = if metadata.permissions().readonly() {
+        0o444
+    } else {
+        0o644
+    };
+    (mode, 0, 0)
+}
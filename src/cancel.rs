@@ -0,0 +1,87 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::Instant;
+
+use crate::notify;
+
+/// Asks a snapshot to stop gracefully, by a signal or once its deadline passes. Clones share the
+/// same request, so the handle given to the capture sees what the signal handler's copy was told.
+#[derive(Debug, Clone, Default)]
+pub struct Cancellation {
+    state: Arc<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    cancelled: AtomicBool,
+    deadline: OnceLock<Deadline>,
+}
+
+#[derive(Debug)]
+struct Deadline {
+    at: Instant,
+    /// Keep whatever was done by the deadline as a partial snapshot, instead of abandoning it.
+    allow_partial: bool,
+}
+
+impl Cancellation {
+    /// Handles SIGINT and SIGTERM by asking the snapshot to stop once the file being read is done.
+    /// A second signal gives up on stopping gracefully, running only `before_exit` before exiting
+    /// with `exit_code`.
+    pub fn install_handler(&self, exit_code: i32, before_exit: fn()) -> Result<(), ctrlc::Error> {
+        let state = Arc::clone(&self.state);
+        ctrlc::set_handler(move || {
+            if state.cancelled.swap(true, Ordering::SeqCst) {
+                log::error!("Received a second signal; exiting immediately");
+                before_exit();
+                std::process::exit(exit_code);
+            }
+            log::warn!(
+                "Received a signal; stopping after the current file. Signal again to exit immediately."
+            );
+            notify::stopping();
+        })
+    }
+
+    /// Stops the snapshot the same way a signal does once the deadline passes.
+    pub fn set_deadline(&self, at: Instant, allow_partial: bool) {
+        let _ = self.state.deadline.set(Deadline { at, allow_partial });
+    }
+
+    /// Whether the deadline set for the snapshot has passed.
+    pub fn timed_out(&self) -> bool {
+        self.state
+            .deadline
+            .get()
+            .is_some_and(|deadline| Instant::now() >= deadline.at)
+    }
+
+    /// Whether the deadline has passed and what was done by then should be kept as a partial
+    /// snapshot. A signal still abandons the snapshot.
+    pub fn keep_partial(&self) -> bool {
+        !self.state.cancelled.load(Ordering::SeqCst)
+            && self
+                .state
+                .deadline
+                .get()
+                .is_some_and(|deadline| deadline.allow_partial && Instant::now() >= deadline.at)
+    }
+
+    /// Whether a graceful stop has been requested, by a signal or by the deadline passing.
+    pub fn is_cancelled(&self) -> bool {
+        self.state.cancelled.load(Ordering::SeqCst) || self.timed_out()
+    }
+
+    /// Fails with `Interrupted` once a graceful stop has been requested, for loops to bail out
+    /// with.
+    pub fn check(&self) -> std::io::Result<()> {
+        if self.is_cancelled() {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::Interrupted,
+                "Snapshot cancelled",
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,150 @@
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::archiver::{create_file, sync_parent};
+use crate::lock;
+
+/// How long to wait for another run updating the catalog to finish.
+const LOCK_WAIT: Duration = Duration::from_secs(30);
+
+/// A snapshot as recorded in the catalog, one JSON object per line.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Record {
+    pub id: String,
+    /// Id of the snapshot an incremental snapshot was taken against, if it's in the catalog.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent: Option<String>,
+    pub target: PathBuf,
+    pub output: PathBuf,
+    /// The manifest the snapshot wrote, which later incremental snapshots are taken against.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub manifest: Option<PathBuf>,
+    /// The revision the captured entries were confirmed stable at, in RFC 3339.
+    pub timestamp: String,
+    pub files: usize,
+    pub directories: usize,
+    pub symlinks: usize,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub duration_ms: u64,
+}
+
+impl Record {
+    pub fn taken(&self) -> Option<DateTime<Utc>> {
+        DateTime::parse_from_rfc3339(&self.timestamp)
+            .ok()
+            .map(|taken| taken.with_timezone(&Utc))
+    }
+}
+
+/// Reads every record in the catalog, oldest first. A missing catalog holds no records, and lines
+/// which can't be read are skipped with a warning, so one bad line never hides the rest.
+pub fn read<P: AsRef<Path>>(catalog_path: P) -> std::io::Result<Vec<Record>> {
+    let catalog_path = catalog_path.as_ref();
+    let text = match std::fs::read_to_string(catalog_path) {
+        Ok(text) => text,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+    let mut records = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(line) {
+            Ok(record) => records.push(record),
+            Err(err) => log::warn!(
+                "Skipping line {} of catalog {}: {}",
+                number + 1,
+                catalog_path.display(),
+                err
+            ),
+        }
+    }
+    Ok(records)
+}
+
+/// Finds the record of a snapshot by its id.
+pub fn find<P: AsRef<Path>>(catalog_path: P, id: &str) -> std::io::Result<Option<Record>> {
+    Ok(read(catalog_path)?
+        .into_iter()
+        .find(|record| record.id == id))
+}
+
+/// Adds the record to the end of the catalog.
+pub fn append<P: AsRef<Path>>(
+    catalog_path: P,
+    record: &Record,
+    mode: Option<u32>,
+) -> std::io::Result<()> {
+    let line = serde_json::to_string(record)?;
+    rewrite(catalog_path.as_ref(), mode, |mut lines| {
+        lines.push(line);
+        lines
+    })
+}
+
+/// Drops the records of snapshots written to any of the outputs, once they're removed. Lines
+/// which can't be read are kept as they are.
+pub fn forget<P: AsRef<Path>>(
+    catalog_path: P,
+    outputs: &[PathBuf],
+    mode: Option<u32>,
+) -> std::io::Result<()> {
+    rewrite(catalog_path.as_ref(), mode, |lines| {
+        lines
+            .into_iter()
+            .filter(|line| {
+                serde_json::from_str::<Record>(line)
+                    .map_or(true, |record| !outputs.contains(&record.output))
+            })
+            .collect()
+    })
+}
+
+/// Replaces the catalog with its lines as edited, holding its lock so concurrent runs never lose
+/// each other's records. The new catalog is written beside the old one and renamed over it once
+/// it's complete and synced, so a run dying partway leaves the old one whole.
+fn rewrite(
+    catalog_path: &Path,
+    mode: Option<u32>,
+    edit: impl FnOnce(Vec<String>) -> Vec<String>,
+) -> std::io::Result<()> {
+    // held until the catalog is replaced
+    let _lock = lock::acquire(catalog_path, Some(LOCK_WAIT)).map_err(std::io::Error::other)?;
+    let lines = match std::fs::read_to_string(catalog_path) {
+        Ok(text) => text
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(str::to_string)
+            .collect(),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+        Err(err) => return Err(err),
+    };
+
+    let mut partial_name = catalog_path.as_os_str().to_owned();
+    partial_name.push(".partial");
+    let partial_path = PathBuf::from(partial_name);
+    // without a mode of its own, the catalog keeps the one it had
+    #[cfg(unix)]
+    let mode = mode.or_else(|| {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::metadata(catalog_path)
+            .ok()
+            .map(|metadata| metadata.permissions().mode() & 0o7777)
+    });
+    let mut writer = BufWriter::new(create_file(&partial_path, mode)?);
+    for line in edit(lines) {
+        writeln!(writer, "{}", line)?;
+    }
+    writer
+        .into_inner()
+        .map_err(|err| err.into_error())?
+        .sync_all()?;
+    std::fs::rename(&partial_path, catalog_path)?;
+    sync_parent(catalog_path)
+}
@@ -0,0 +1,214 @@
+use std::collections::HashMap;
+use std::fs::{File, Metadata};
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::hashing::HashAlgorithm;
+use crate::processor::Digest;
+
+/// How often the index is written while a pass is capturing files.
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(30);
+
+const INDEX_FILE_NAME: &str = "index.json";
+const DATA_DIRECTORY_NAME: &str = "data";
+
+/// Keeps the captures of a walk on disk, along with an index of which file each capture belongs
+/// to, so a walk interrupted by a crash can resume without capturing those files again.
+pub struct Checkpoint {
+    directory: PathBuf,
+    hash_algorithm: HashAlgorithm,
+    dictionary_id: Option<u32>,
+    records: HashMap<PathBuf, CheckpointRecord>,
+    last_written: Instant,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CheckpointIndex {
+    /// Algorithm the recorded digests were hashed with.
+    #[serde(default)]
+    hash_algorithm: HashAlgorithm,
+    /// Id of the dictionary the captures were compressed with.
+    #[serde(default)]
+    dictionary_id: Option<u32>,
+    records: Vec<CheckpointRecord>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct CheckpointRecord {
+    path: PathBuf,
+    size: u64,
+    mtime_secs: u64,
+    mtime_nanos: u32,
+    digest: String,
+    /// Name of the capture within the data directory.
+    data: String,
+}
+
+impl Checkpoint {
+    /// Starts a fresh checkpoint in the directory, discarding whatever an earlier walk left there.
+    pub fn create<P: AsRef<Path>>(
+        directory: P,
+        hash_algorithm: HashAlgorithm,
+        dictionary_id: Option<u32>,
+    ) -> std::io::Result<Self> {
+        let directory = directory.as_ref().to_path_buf();
+        match std::fs::remove_dir_all(&directory) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+        std::fs::create_dir_all(directory.join(DATA_DIRECTORY_NAME))?;
+        Ok(Self {
+            directory,
+            hash_algorithm,
+            dictionary_id,
+            records: HashMap::new(),
+            last_written: Instant::now(),
+        })
+    }
+
+    /// Picks up the checkpoint an earlier walk left in the directory, as long as its captures were
+    /// hashed with the same algorithm and compressed with the same dictionary.
+    pub fn resume<P: AsRef<Path>>(
+        directory: P,
+        hash_algorithm: HashAlgorithm,
+        dictionary_id: Option<u32>,
+    ) -> std::io::Result<Self> {
+        let directory = directory.as_ref().to_path_buf();
+        let reader = BufReader::new(File::open(directory.join(INDEX_FILE_NAME))?);
+        let index: CheckpointIndex = serde_json::from_reader(reader)?;
+        if index.hash_algorithm != hash_algorithm {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "Captures were hashed with {} instead of {}",
+                    index.hash_algorithm, hash_algorithm
+                ),
+            ));
+        }
+        if index.dictionary_id != dictionary_id {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "Captures were compressed with {} instead of {}",
+                    describe_dictionary(index.dictionary_id),
+                    describe_dictionary(dictionary_id)
+                ),
+            ));
+        }
+        Ok(Self {
+            directory,
+            hash_algorithm,
+            dictionary_id,
+            records: index
+                .records
+                .into_iter()
+                .map(|record| (record.path.clone(), record))
+                .collect(),
+            last_written: Instant::now(),
+        })
+    }
+
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    /// Creates a file to write a capture into, which outlives the process.
+    pub fn create_data_file(&self) -> std::io::Result<(File, PathBuf)> {
+        let (file, path) = tempfile::Builder::new()
+            .prefix("capture-")
+            .suffix(".zst")
+            .tempfile_in(self.directory.join(DATA_DIRECTORY_NAME))?
+            .keep()
+            .map_err(|err| err.error)?;
+        Ok((file, path))
+    }
+
+    /// Finds an earlier capture of the file, as long as the file still looks the way it did when
+    /// it was captured.
+    pub fn lookup(&self, path: &Path, metadata: &Metadata) -> Option<(File, Digest, PathBuf)> {
+        let record = self.records.get(path)?;
+        let (mtime_secs, mtime_nanos) = mtime(metadata)?;
+        if record.size != metadata.len()
+            || record.mtime_secs != mtime_secs
+            || record.mtime_nanos != mtime_nanos
+        {
+            return None;
+        }
+        let mut digest = Digest::default();
+        hex::decode_to_slice(&record.digest, &mut digest).ok()?;
+        let data_path = self.directory.join(DATA_DIRECTORY_NAME).join(&record.data);
+        let data = File::open(&data_path).ok()?;
+        Some((data, digest, data_path))
+    }
+
+    /// Records a capture of the file, writing the index out if it hasn't been for a while.
+    pub fn record(
+        &mut self,
+        path: &Path,
+        metadata: &Metadata,
+        data_path: &Path,
+        digest: &Digest,
+    ) -> std::io::Result<()> {
+        let (mtime_secs, mtime_nanos) = mtime(metadata).unwrap_or_default();
+        let data = data_path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or_default()
+            .to_string();
+        self.records.insert(
+            path.to_path_buf(),
+            CheckpointRecord {
+                path: path.to_path_buf(),
+                size: metadata.len(),
+                mtime_secs,
+                mtime_nanos,
+                digest: hex::encode(digest),
+                data,
+            },
+        );
+        if self.last_written.elapsed() >= CHECKPOINT_INTERVAL {
+            self.write()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the index out, replacing the previous one only once it's complete.
+    pub fn write(&mut self) -> std::io::Result<()> {
+        let index = CheckpointIndex {
+            hash_algorithm: self.hash_algorithm,
+            dictionary_id: self.dictionary_id,
+            records: self.records.values().cloned().collect(),
+        };
+        let index_path = self.directory.join(INDEX_FILE_NAME);
+        let partial_path = index_path.with_extension("json.partial");
+        let mut writer = BufWriter::new(File::create(&partial_path)?);
+        serde_json::to_writer(&mut writer, &index)?;
+        writer
+            .into_inner()
+            .map_err(|err| err.into_error())?
+            .sync_all()?;
+        std::fs::rename(partial_path, index_path)?;
+        self.last_written = Instant::now();
+        log::debug!("Checkpointed {} captures", self.records.len());
+        Ok(())
+    }
+}
+
+fn describe_dictionary(dictionary_id: Option<u32>) -> String {
+    match dictionary_id {
+        Some(id) => format!("dictionary {}", id),
+        None => "no dictionary".to_string(),
+    }
+}
+
+pub fn mtime(metadata: &Metadata) -> Option<(u64, u32)> {
+    let since_epoch = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some((since_epoch.as_secs(), since_epoch.subsec_nanos()))
+}
@@ -0,0 +1,70 @@
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::ValueEnum;
+
+use crate::archiver::create_file;
+use crate::hashing::HashAlgorithm;
+
+/// The path of the sidecar holding an archive's checksum.
+pub fn sidecar_path<P: AsRef<Path>>(archive_path: P, algorithm: HashAlgorithm) -> PathBuf {
+    let mut sidecar = archive_path.as_ref().as_os_str().to_owned();
+    sidecar.push(format!(".{}", algorithm));
+    PathBuf::from(sidecar)
+}
+
+/// Writes the checksum of a finished archive beside it, in the `<hex>  <file name>` format of
+/// `sha256sum` and `b3sum`.
+pub fn write_sidecar<P: AsRef<Path>>(
+    archive_path: P,
+    algorithm: HashAlgorithm,
+    mode: Option<u32>,
+) -> std::io::Result<PathBuf> {
+    let archive_path = archive_path.as_ref();
+    let digest = hex::encode(algorithm.digest_reader(File::open(archive_path)?)?);
+    let file_name = archive_path
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy();
+    let sidecar = sidecar_path(archive_path, algorithm);
+    let mut file = create_file(&sidecar, mode)?;
+    writeln!(file, "{}  {}", digest, file_name)?;
+    file.sync_all()?;
+    Ok(sidecar)
+}
+
+/// Checks an archive against a checksum sidecar, whose algorithm is told by its extension.
+pub fn verify_sidecar<P1: AsRef<Path>, P2: AsRef<Path>>(
+    archive_path: P1,
+    sidecar: P2,
+) -> std::io::Result<bool> {
+    let sidecar = sidecar.as_ref();
+    let algorithm = sidecar
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .and_then(|extension| {
+            HashAlgorithm::value_variants()
+                .iter()
+                .copied()
+                .find(|algorithm| algorithm.to_string() == extension)
+        })
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "Unknown checksum algorithm for sidecar {}",
+                    sidecar.display()
+                ),
+            )
+        })?;
+    let content = std::fs::read_to_string(sidecar)?;
+    let expected = content.split_whitespace().next().ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Checksum sidecar {} is empty", sidecar.display()),
+        )
+    })?;
+    let digest = hex::encode(algorithm.digest_reader(File::open(archive_path)?)?);
+    Ok(digest.eq_ignore_ascii_case(expected))
+}
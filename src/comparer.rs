@@ -0,0 +1,401 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{File, Metadata};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use serde::Serialize;
+use tar::Archive;
+
+use crate::archiver::{relative_link, resolve_link, WHITEOUT_PREFIX};
+use crate::batch;
+use crate::dictionary::{self, Dictionary};
+use crate::hashing::HashAlgorithm;
+use crate::manifest::{self, Manifest, ManifestEntryKind};
+use crate::provenance;
+use crate::restorer::is_whiteout;
+
+/// Content is only ever compared within one run, so the fastest algorithm does.
+const COMPARE_ALGORITHM: HashAlgorithm = HashAlgorithm::Blake3;
+
+/// How a path in the directory differs from the archive.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Difference {
+    /// In the archive, but missing from the directory.
+    Missing,
+    /// In the directory, but not in the archive.
+    Extra,
+    /// A file whose content differs from the archived copy, or in quick mode whose size or
+    /// modification time does.
+    Content,
+    /// A symlink pointing somewhere else.
+    LinkTarget,
+    Permissions,
+    /// A different kind of entry, like a directory where the archive holds a file.
+    Kind,
+}
+
+impl std::fmt::Display for Difference {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Difference::Missing => write!(f, "missing"),
+            Difference::Extra => write!(f, "extra"),
+            Difference::Content => write!(f, "content"),
+            Difference::LinkTarget => write!(f, "link_target"),
+            Difference::Permissions => write!(f, "permissions"),
+            Difference::Kind => write!(f, "kind"),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct DifferentPath {
+    /// The path relative to the directory compared.
+    pub path: PathBuf,
+    pub difference: Difference,
+}
+
+/// Everything found comparing a directory against an archive, with the number of differences
+/// of each kind.
+#[derive(Serialize, Debug, Default)]
+pub struct Comparison {
+    /// Number of archived paths compared against the directory.
+    pub compared: usize,
+    pub missing: usize,
+    pub extra: usize,
+    pub content: usize,
+    pub link_target: usize,
+    pub permissions: usize,
+    pub kind: usize,
+    pub differences: Vec<DifferentPath>,
+}
+
+impl Comparison {
+    fn record(&mut self, path: &Path, difference: Difference) {
+        log::debug!("{} differs: {}", path.display(), difference);
+        *match difference {
+            Difference::Missing => &mut self.missing,
+            Difference::Extra => &mut self.extra,
+            Difference::Content => &mut self.content,
+            Difference::LinkTarget => &mut self.link_target,
+            Difference::Permissions => &mut self.permissions,
+            Difference::Kind => &mut self.kind,
+        } += 1;
+        self.differences.push(DifferentPath {
+            path: path.to_path_buf(),
+            difference,
+        });
+    }
+}
+
+/// A path as the archive leaves it once every snapshot in it is restored.
+struct Archived {
+    kind: ArchivedKind,
+    mode: u32,
+    mtime: u64,
+    /// Size of a file's content, when the archive records it without decompressing.
+    size: Option<u64>,
+    /// Where a file's content is stored, unless it was captured without its content.
+    source: Option<Source>,
+}
+
+#[derive(PartialEq, Eq)]
+enum ArchivedKind {
+    File,
+    Directory,
+    Symlink(PathBuf),
+}
+
+/// Index of the archive entry holding a file's content: a file of its own, or a batch.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Source {
+    Entry(usize),
+    Batch(usize),
+}
+
+/// Compares a directory against what restoring a tarball produced by `create_tarball` would leave
+/// there, given the same directory the snapshot was taken of: paths missing from either side,
+/// files whose content differs, symlinks pointing elsewhere and permissions which differ. Content
+/// is compared by decompressing and hashing every archived file, unless `quick`, which compares
+/// sizes, where the archive records them, and modification times instead. Files compressed with
+/// a dictionary need that dictionary given.
+pub fn compare_tarball<P1: AsRef<Path>, P2: AsRef<Path>>(
+    tarball_path: P1,
+    directory: P2,
+    dictionary: Option<&Dictionary>,
+    quick: bool,
+) -> std::io::Result<Comparison> {
+    let tarball_path = tarball_path.as_ref();
+    // symlinks are archived relative to where they were found, which takes the absolute path
+    let directory = &directory.as_ref().canonicalize()?;
+    log::info!(
+        "Comparing {} against tarball {}",
+        directory.display(),
+        tarball_path.display()
+    );
+
+    // archived paths are nested under the name of the directory the snapshot was taken of
+    let root = provenance::read_archive(tarball_path)?
+        .first()
+        .and_then(|provenance| provenance.target.file_name().map(PathBuf::from));
+    let relative = |stored_path: &Path| -> PathBuf {
+        match &root {
+            Some(root) => stored_path
+                .strip_prefix(root)
+                .unwrap_or(stored_path)
+                .to_path_buf(),
+            None => stored_path.to_path_buf(),
+        }
+    };
+
+    let archived = read_archived(tarball_path, dictionary, relative)?;
+    let changed = if quick {
+        HashSet::new()
+    } else {
+        changed_files(tarball_path, directory, dictionary, &archived, relative)?
+    };
+
+    let mut comparison = Comparison::default();
+    for (path, archived) in &archived {
+        comparison.compared += 1;
+        let live_path = directory.join(path);
+        let metadata = match std::fs::symlink_metadata(&live_path) {
+            Ok(metadata) => metadata,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                comparison.record(path, Difference::Missing);
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+        match &archived.kind {
+            ArchivedKind::File if metadata.is_file() => {
+                let differs = match archived.source {
+                    Some(_) if !quick => changed.contains(path),
+                    // a file captured without its content only has its metadata to compare
+                    _ => {
+                        archived.size.is_some_and(|size| size != metadata.len())
+                            || archived.mtime != mtime(&metadata)
+                    }
+                };
+                if differs {
+                    comparison.record(path, Difference::Content);
+                }
+            }
+            ArchivedKind::Directory if metadata.is_dir() => {}
+            ArchivedKind::Symlink(link) if metadata.is_symlink() => {
+                if !same_link(&live_path, link)? {
+                    comparison.record(path, Difference::LinkTarget);
+                }
+                continue;
+            }
+            _ => {
+                comparison.record(path, Difference::Kind);
+                continue;
+            }
+        }
+        if !same_permissions(archived.mode, &metadata) {
+            comparison.record(path, Difference::Permissions);
+        }
+    }
+
+    find_extra(directory, Path::new(""), &archived, &mut comparison)?;
+    Ok(comparison)
+}
+
+/// Replays every snapshot in the archive, whiteouts included, into the paths it would restore.
+fn read_archived(
+    tarball_path: &Path,
+    dictionary: Option<&Dictionary>,
+    relative: impl Fn(&Path) -> PathBuf,
+) -> std::io::Result<BTreeMap<PathBuf, Archived>> {
+    let mut archive = Archive::new(BufReader::new(File::open(tarball_path)?));
+    let mut archived = BTreeMap::new();
+
+    for (index, entry) in archive.entries_with_seek()?.enumerate() {
+        let entry = entry?;
+        let stored_path = entry.path()?.into_owned();
+        let header = entry.header();
+        let (mode, mtime) = (header.mode()?, header.mtime()?);
+        match header.entry_type() {
+            tar::EntryType::Directory | tar::EntryType::Symlink => {
+                let kind = match entry.link_name()? {
+                    Some(link) if header.entry_type() == tar::EntryType::Symlink => {
+                        ArchivedKind::Symlink(link.into_owned())
+                    }
+                    _ => ArchivedKind::Directory,
+                };
+                archived.insert(
+                    relative(&stored_path),
+                    Archived {
+                        kind,
+                        mode,
+                        mtime,
+                        size: None,
+                        source: None,
+                    },
+                );
+            }
+            tar::EntryType::Regular if provenance::is_provenance(&stored_path) => {}
+            tar::EntryType::Regular if manifest::is_embedded_manifest(&stored_path) => {
+                // the manifest describes every file as of the snapshot it ends
+                for manifest_entry in Manifest::read_from(entry)?.entries {
+                    if manifest_entry.kind != ManifestEntryKind::File {
+                        continue;
+                    }
+                    if let Some(archived) = archived.get_mut(&relative(&manifest_entry.path)) {
+                        archived.size = Some(manifest_entry.size);
+                    }
+                }
+            }
+            tar::EntryType::Regular if is_whiteout(&stored_path) => {
+                let name = stored_path.file_name().unwrap().to_str().unwrap();
+                let removed = relative(&stored_path.with_file_name(&name[WHITEOUT_PREFIX.len()..]));
+                // removing a directory removes everything beneath it
+                archived.retain(|path, _| !path.starts_with(&removed));
+            }
+            _ if stored_path.file_name() == Some(batch::BATCH_FILE_NAME.as_ref()) => {
+                let (members, _) = batch::read_batch(entry, dictionary)?;
+                for member in members {
+                    archived.insert(
+                        relative(&stored_path.with_file_name(&member.name)),
+                        Archived {
+                            kind: ArchivedKind::File,
+                            mode: member.mode,
+                            mtime: member.mtime,
+                            size: Some(member.data.len() as u64),
+                            source: Some(Source::Batch(index)),
+                        },
+                    );
+                }
+            }
+            _ => {
+                archived.insert(
+                    relative(&stored_path),
+                    Archived {
+                        kind: ArchivedKind::File,
+                        mode,
+                        mtime,
+                        size: None,
+                        // files captured without their content hold no frame to decompress
+                        source: (entry.size() > 0).then_some(Source::Entry(index)),
+                    },
+                );
+            }
+        }
+    }
+    Ok(archived)
+}
+
+/// Finds the archived files whose content differs from the file in the directory, decompressing
+/// each archived copy the archive ends up restoring.
+fn changed_files(
+    tarball_path: &Path,
+    directory: &Path,
+    dictionary: Option<&Dictionary>,
+    archived: &BTreeMap<PathBuf, Archived>,
+    relative: impl Fn(&Path) -> PathBuf,
+) -> std::io::Result<HashSet<PathBuf>> {
+    let is_stored_in = |path: &Path, source: Source| {
+        archived
+            .get(path)
+            .is_some_and(|archived| archived.source == Some(source))
+    };
+    let mut changed = HashSet::new();
+    let mut archive = Archive::new(BufReader::new(File::open(tarball_path)?));
+    for (index, entry) in archive.entries()?.enumerate() {
+        let entry = entry?;
+        let stored_path = entry.path()?.into_owned();
+        if stored_path.file_name() == Some(batch::BATCH_FILE_NAME.as_ref()) {
+            if !archived
+                .values()
+                .any(|archived| archived.source == Some(Source::Batch(index)))
+            {
+                continue;
+            }
+            let (members, data) = batch::read_batch(entry, dictionary)?;
+            for member in members {
+                let path = relative(&stored_path.with_file_name(&member.name));
+                if !is_stored_in(&path, Source::Batch(index)) {
+                    continue;
+                }
+                let digest = COMPARE_ALGORITHM.digest(&data[member.data]);
+                if live_digest(&directory.join(&path))? != Some(digest) {
+                    changed.insert(path);
+                }
+            }
+            continue;
+        }
+        let path = relative(&stored_path);
+        if !is_stored_in(&path, Source::Entry(index)) {
+            continue;
+        }
+        let digest = COMPARE_ALGORITHM.digest_reader(dictionary::decoder(entry, dictionary)?)?;
+        if live_digest(&directory.join(&path))? != Some(digest) {
+            changed.insert(path);
+        }
+    }
+    Ok(changed)
+}
+
+/// Hashes a file in the directory, if it is one.
+fn live_digest(path: &Path) -> std::io::Result<Option<crate::processor::Digest>> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_file() => Ok(Some(
+            COMPARE_ALGORITHM.digest_reader(BufReader::new(File::open(path)?))?,
+        )),
+        Ok(_) => Ok(None),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Records every path in the directory the archive doesn't hold, without descending into what
+/// the archive doesn't hold as a directory.
+fn find_extra(
+    directory: &Path,
+    relative_path: &Path,
+    archived: &BTreeMap<PathBuf, Archived>,
+    comparison: &mut Comparison,
+) -> std::io::Result<()> {
+    for entry in directory.join(relative_path).read_dir()? {
+        let entry = entry?;
+        let path = relative_path.join(entry.file_name());
+        match archived.get(&path).map(|archived| &archived.kind) {
+            None => comparison.record(&path, Difference::Extra),
+            Some(ArchivedKind::Directory) if entry.file_type()?.is_dir() => {
+                find_extra(directory, &path, archived, comparison)?;
+            }
+            Some(_) => {}
+        }
+    }
+    Ok(())
+}
+
+/// Whether the symlink in the directory points where the archived one does. An absolute link is
+/// archived relative to its own directory, so it's compared that way too.
+fn same_link(live_path: &Path, archived_link: &Path) -> std::io::Result<bool> {
+    let link = live_path.read_link()?;
+    Ok(link == archived_link
+        || (link.is_absolute()
+            && relative_link(live_path, &resolve_link(live_path, &link)) == archived_link))
+}
+
+fn mtime(metadata: &Metadata) -> u64 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |duration| duration.as_secs())
+}
+
+#[cfg(unix)]
+fn same_permissions(mode: u32, metadata: &Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    mode & 0o7777 == metadata.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+fn same_permissions(_mode: u32, _metadata: &Metadata) -> bool {
+    true
+}
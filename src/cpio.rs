@@ -0,0 +1,493 @@
+use std::collections::HashMap;
+use std::fs::{File, Metadata};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+
+use crate::archiver::{self, path_bytes, ArchiveSink, ArchivedEntry, TarOptions};
+use crate::detector::file_identity;
+use crate::dictionary;
+use crate::manifest::Manifest;
+use crate::processor::{Digest, Entry};
+use crate::provenance::Provenance;
+use crate::report::SnapshotReport;
+
+/// Magic number starting every header of the SVR4 "newc" format, the one without checksums.
+const NEWC_MAGIC: &str = "070701";
+
+/// Name of the record ending the archive.
+const TRAILER_NAME: &str = "TRAILER!!!";
+
+/// The archive is padded to a multiple of this many bytes, the blocks `cpio` itself writes.
+const BLOCK_SIZE: u64 = 512;
+
+/// File type bits of a header's mode, fixed by the format whatever the platform.
+const TYPE_FILE: u32 = 0o100000;
+const TYPE_DIRECTORY: u32 = 0o040000;
+const TYPE_SYMLINK: u32 = 0o120000;
+const TYPE_MASK: u32 = 0o170000;
+
+/// Size of a newc header: the magic number, then 13 fields of 8 hexadecimal digits.
+const HEADER_SIZE: usize = 6 + 13 * 8;
+
+/// How a cpio archive is compressed as a whole.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpioCompression {
+    None,
+    Zstd,
+    Gzip,
+}
+
+/// How the archive at the path is compressed if it's named as a cpio archive, by `.cpio`,
+/// `.cpio.zst` or `.cpio.gz`. Nothing for any other name, which is written as a tarball.
+pub fn output_compression(path: &Path) -> Option<CpioCompression> {
+    let name = path.file_name()?.to_string_lossy();
+    if name.ends_with(".cpio") {
+        Some(CpioCompression::None)
+    } else if name.ends_with(".cpio.zst") || name.ends_with(".cpio.zstd") {
+        Some(CpioCompression::Zstd)
+    } else if name.ends_with(".cpio.gz") {
+        Some(CpioCompression::Gzip)
+    } else {
+        None
+    }
+}
+
+/// Writes the captured entries into a newc cpio archive, compressed as a whole as asked, the same
+/// way `create_tarball` writes a tarball: beside its final path, only renamed into place once it's
+/// complete and synced. Files are stored decompressed, named relative to the target.
+pub fn create_cpio<P1: AsRef<Path>, P2: AsRef<Path>>(
+    origin: P1,
+    entries: impl IntoIterator<Item = Entry>,
+    cpio_path: P2,
+    compression: CpioCompression,
+    options: &TarOptions,
+    report: &mut SnapshotReport,
+) -> std::io::Result<(Vec<ArchivedEntry>, Manifest)> {
+    let origin = origin.as_ref();
+    let cpio_path = cpio_path.as_ref();
+    log::info!("Creating cpio archive at {}", cpio_path.display());
+    let mut temp_name = cpio_path.as_os_str().to_owned();
+    temp_name.push(format!(".tmp-{}", std::process::id()));
+    let temp_path = PathBuf::from(temp_name);
+
+    let written = archiver::create_file(&temp_path, options.output_mode)
+        .and_then(|file| {
+            let file = BufWriter::new(file);
+            let (archived, manifest, file) = match compression {
+                CpioCompression::None => write_cpio(origin, entries, file, options, report)?,
+                CpioCompression::Zstd => {
+                    let encoder = zstd::Encoder::new(file, options.compression_level)?;
+                    let (archived, manifest, encoder) =
+                        write_cpio(origin, entries, encoder, options, report)?;
+                    (archived, manifest, encoder.finish()?)
+                }
+                CpioCompression::Gzip => {
+                    let encoder = GzEncoder::new(file, flate2::Compression::default());
+                    let (archived, manifest, encoder) =
+                        write_cpio(origin, entries, encoder, options, report)?;
+                    (archived, manifest, encoder.finish()?)
+                }
+            };
+            let file = file.into_inner().map_err(|err| err.into_error())?;
+            file.sync_all()?;
+            report.bytes_written = file.metadata()?.len();
+            Ok((archived, manifest))
+        })
+        .and_then(|written| {
+            std::fs::rename(&temp_path, cpio_path)?;
+            archiver::sync_parent(cpio_path)?;
+            Ok(written)
+        });
+    if written.is_err() {
+        if let Err(err) = std::fs::remove_file(&temp_path) {
+            log::debug!("Failed to remove {}: {}", temp_path.display(), err);
+        }
+    }
+    written
+}
+
+fn write_cpio<W: Write>(
+    origin: &Path,
+    entries: impl IntoIterator<Item = Entry>,
+    writer: W,
+    options: &TarOptions,
+    report: &mut SnapshotReport,
+) -> std::io::Result<(Vec<ArchivedEntry>, Manifest, W)> {
+    let sink = CpioSink::new(writer, origin, options);
+    let written = archiver::write_archive(origin, entries, sink, options, None, report)?;
+    Ok((written.archived, written.manifest, written.output))
+}
+
+/// The fields of a newc header which differ between members.
+struct CpioHeader {
+    ino: u64,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    nlink: u64,
+    mtime: i64,
+    filesize: u64,
+}
+
+/// Writes entries into a newc cpio stream, decompressing every file. Hard linked files share an
+/// inode number, with their content only stored along with the first of them, which is how both
+/// `cpio` and the kernel's initramfs unpacking link the rest to it.
+pub struct CpioSink<W: Write> {
+    writer: W,
+    options: TarOptions,
+    /// Leading directory of every path in the archive, standing for the target itself.
+    root: PathBuf,
+    /// Bytes written so far, which every header and its content are padded by.
+    written: u64,
+    /// Inode numbers given to files, by the identity of the file on disk.
+    inodes: HashMap<(u64, u64), u64>,
+    last_inode: u64,
+}
+
+impl<W: Write> CpioSink<W> {
+    pub fn new(writer: W, origin: &Path, options: &TarOptions) -> Self {
+        Self {
+            writer,
+            options: options.clone(),
+            root: origin.file_name().map(PathBuf::from).unwrap_or_default(),
+            written: 0,
+            inodes: HashMap::new(),
+            last_inode: 0,
+        }
+    }
+
+    fn next_inode(&mut self) -> u64 {
+        self.last_inode += 1;
+        self.last_inode
+    }
+
+    /// The name a path in the archive is stored under: relative to the target, the way `find .`
+    /// names it, with the target itself as `.`.
+    fn member_name(&self, relative_path: &Path) -> PathBuf {
+        match relative_path.strip_prefix(&self.root) {
+            Ok(name) if !name.as_os_str().is_empty() => name.to_path_buf(),
+            Ok(_) => PathBuf::from("."),
+            Err(_) => relative_path.to_path_buf(),
+        }
+    }
+
+    /// Writes a member, reading exactly `filesize` bytes of content from `data`.
+    fn append(
+        &mut self,
+        header: &CpioHeader,
+        name: &Path,
+        data: &mut dyn Read,
+    ) -> std::io::Result<()> {
+        let name = path_bytes(name);
+        let name = name.as_ref();
+        let fields = [
+            header.ino,
+            u64::from(header.mode),
+            u64::from(header.uid),
+            u64::from(header.gid),
+            header.nlink,
+            // the format has no room for times before the epoch or after 2106
+            header.mtime.clamp(0, i64::from(u32::MAX)) as u64,
+            header.filesize,
+            // inode numbers are given out by the archive, so every member is on the same device
+            0,
+            0,
+            0,
+            0,
+            name.len() as u64 + 1,
+            0,
+        ];
+        if let Some(field) = fields.iter().find(|field| **field > u64::from(u32::MAX)) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "{} doesn't fit a cpio header, as {} is too large",
+                    String::from_utf8_lossy(name),
+                    field
+                ),
+            ));
+        }
+        let mut encoded = String::from(NEWC_MAGIC);
+        for field in fields {
+            encoded.push_str(&format!("{:08X}", field));
+        }
+        self.write(encoded.as_bytes())?;
+        self.write(name)?;
+        self.write(&[0])?;
+        self.pad(4)?;
+
+        let copied = std::io::copy(&mut data.take(header.filesize), &mut self.writer)?;
+        self.written += copied;
+        if copied != header.filesize {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "Wrote {} bytes for {} but its header declares {}",
+                    copied,
+                    String::from_utf8_lossy(name),
+                    header.filesize
+                ),
+            ));
+        }
+        self.pad(4)
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        self.writer.write_all(bytes)?;
+        self.written += bytes.len() as u64;
+        Ok(())
+    }
+
+    /// Pads what was written to a multiple of `alignment` bytes with zeroes.
+    fn pad(&mut self, alignment: u64) -> std::io::Result<()> {
+        let padding = self.written.next_multiple_of(alignment) - self.written;
+        self.write(&vec![0; padding as usize])
+    }
+}
+
+impl<W: Write> ArchiveSink for CpioSink<W> {
+    type Output = W;
+    type Error = std::io::Error;
+
+    fn add_file(
+        &mut self,
+        metadata: &Metadata,
+        relative_path: &Path,
+        size: u64,
+        _digest: Option<&Digest>,
+        encoded_size: u64,
+        encoded: &mut dyn Read,
+    ) -> std::io::Result<()> {
+        let mut header = member_header(metadata, 0, TYPE_FILE, self.options.file_mode);
+        let identity = file_identity(metadata).filter(|_| header.nlink > 1);
+        let linked = identity.and_then(|identity| self.inodes.get(&identity).copied());
+        header.ino = match linked {
+            Some(ino) => ino,
+            None => self.next_inode(),
+        };
+        if let Some(identity) = identity {
+            self.inodes.insert(identity, header.ino);
+        }
+        let name = self.member_name(relative_path);
+
+        // a file captured without its content has none to store, and a hard link stores none of
+        // its own
+        if encoded_size == 0 || linked.is_some() {
+            log::debug!("New cpio member {} without content", name.display());
+            std::io::copy(encoded, &mut std::io::sink())?;
+            return self.append(&header, &name, &mut std::io::empty());
+        }
+        header.filesize = size;
+        log::debug!(
+            "New cpio member {} with size {}",
+            name.display(),
+            header.filesize
+        );
+        // the decoder borrows the dictionary for as long as the content is written out
+        let dictionary = self.options.dictionary.clone();
+        let mut decoder = dictionary::decoder(&mut *encoded, dictionary.as_ref())?;
+        self.append(&header, &name, &mut decoder)?;
+        if std::io::copy(&mut decoder, &mut std::io::sink())? > 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "{} holds more than the {} bytes it was captured with",
+                    relative_path.display(),
+                    header.filesize
+                ),
+            ));
+        }
+        drop(decoder);
+        std::io::copy(encoded, &mut std::io::sink())?;
+        Ok(())
+    }
+
+    fn add_dir(&mut self, metadata: &Metadata, relative_path: &Path) -> std::io::Result<()> {
+        let ino = self.next_inode();
+        let mut header = member_header(metadata, ino, TYPE_DIRECTORY, self.options.dir_mode);
+        // the directory's own entry and the one naming it in its parent, leaving out those of
+        // any subdirectories the way `cpio` does
+        header.nlink = 2;
+        let name = self.member_name(relative_path);
+        self.append(&header, &name, &mut std::io::empty())
+    }
+
+    fn add_symlink(
+        &mut self,
+        metadata: &Metadata,
+        relative_path: &Path,
+        target: &Path,
+    ) -> std::io::Result<()> {
+        let ino = self.next_inode();
+        let mut header = member_header(metadata, ino, TYPE_SYMLINK, None);
+        header.nlink = 1;
+        let target = path_bytes(target);
+        let target = target.as_ref();
+        header.filesize = target.len() as u64;
+        let name = self.member_name(relative_path);
+        self.append(&header, &name, &mut &target[..])
+    }
+
+    fn add_whiteout(&mut self, relative_path: &Path) -> std::io::Result<()> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            format!(
+                "cpio archives can't record the removal of {}",
+                relative_path.display()
+            ),
+        ))
+    }
+
+    /// cpio archives only hold the target, for tools other than saved_in_time to read, so the
+    /// records describing the snapshot are left out of them.
+    fn add_manifest(&mut self, _manifest: &Manifest) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn add_provenance(&mut self, _provenance: &Provenance) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    /// Ends the archive with its trailer, handing back the flushed writer.
+    fn finish(mut self) -> std::io::Result<W> {
+        let trailer = CpioHeader {
+            ino: 0,
+            mode: 0,
+            uid: 0,
+            gid: 0,
+            nlink: 1,
+            mtime: 0,
+            filesize: 0,
+        };
+        self.append(&trailer, Path::new(TRAILER_NAME), &mut std::io::empty())?;
+        self.pad(BLOCK_SIZE)?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// A member read back out of a cpio archive.
+pub struct CpioMember {
+    /// Name relative to the target, which is itself `.`.
+    pub name: PathBuf,
+    pub ino: u64,
+    pub mode: u32,
+    pub nlink: u64,
+    pub filesize: u64,
+}
+
+impl CpioMember {
+    pub fn is_file(&self) -> bool {
+        self.mode & TYPE_MASK == TYPE_FILE
+    }
+}
+
+/// Reads back a newc cpio archive the way `create_cpio` writes it, handing every member up to the
+/// trailer to `visit` along with its content, which it needn't read all of.
+pub fn read_cpio<P: AsRef<Path>, E: From<std::io::Error>>(
+    cpio_path: P,
+    compression: CpioCompression,
+    mut visit: impl FnMut(&CpioMember, &mut dyn Read) -> Result<(), E>,
+) -> Result<(), E> {
+    let file = BufReader::new(File::open(cpio_path)?);
+    let mut reader: Box<dyn Read> = match compression {
+        CpioCompression::None => Box::new(file),
+        CpioCompression::Zstd => Box::new(zstd::Decoder::with_buffer(file)?),
+        CpioCompression::Gzip => Box::new(MultiGzDecoder::new(file)),
+    };
+    let mut read = 0;
+    loop {
+        let mut header = [0; HEADER_SIZE];
+        reader.read_exact(&mut header)?;
+        if !header.starts_with(NEWC_MAGIC.as_bytes()) {
+            return Err(invalid_member("a header without the newc magic number").into());
+        }
+        let field = |index: usize| {
+            let start = NEWC_MAGIC.len() + index * 8;
+            std::str::from_utf8(&header[start..start + 8])
+                .ok()
+                .and_then(|field| u64::from_str_radix(field, 16).ok())
+                .ok_or_else(|| invalid_member("a header field which isn't hexadecimal"))
+        };
+        let mut name = vec![0; field(11)? as usize];
+        reader.read_exact(&mut name)?;
+        read += (HEADER_SIZE + name.len()) as u64;
+        skip_padding(&mut reader, &mut read)?;
+        // the name is stored with its terminating NUL
+        name.pop();
+        if name == TRAILER_NAME.as_bytes() {
+            return Ok(());
+        }
+        let member = CpioMember {
+            name: archiver::bytes_path(&name),
+            ino: field(0)?,
+            mode: field(1)? as u32,
+            nlink: field(4)?,
+            filesize: field(6)?,
+        };
+        let mut content = (&mut reader).take(member.filesize);
+        visit(&member, &mut content)?;
+        std::io::copy(&mut content, &mut std::io::sink())?;
+        if content.limit() > 0 {
+            return Err(invalid_member("content cut short").into());
+        }
+        read += member.filesize;
+        skip_padding(&mut reader, &mut read)?;
+    }
+}
+
+/// Reads past the zeroes padding what was read to a multiple of 4 bytes.
+fn skip_padding(reader: &mut dyn Read, read: &mut u64) -> std::io::Result<()> {
+    let padding = read.next_multiple_of(4) - *read;
+    reader.read_exact(&mut vec![0; padding as usize])?;
+    *read += padding;
+    Ok(())
+}
+
+fn invalid_member(problem: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("cpio archive holds {}", problem),
+    )
+}
+
+/// The header of a member with the metadata, of the given type and before its size is filled in.
+#[cfg(unix)]
+fn member_header(metadata: &Metadata, ino: u64, kind: u32, mode: Option<u32>) -> CpioHeader {
+    use std::os::unix::fs::MetadataExt;
+    CpioHeader {
+        ino,
+        mode: kind | (mode.unwrap_or(metadata.mode()) & 0o7777),
+        uid: metadata.uid(),
+        gid: metadata.gid(),
+        nlink: metadata.nlink(),
+        mtime: metadata.mtime(),
+        filesize: 0,
+    }
+}
+
+#[cfg(not(unix))]
+fn member_header(metadata: &Metadata, ino: u64, kind: u32, mode: Option<u32>) -> CpioHeader {
+    let permissions = if metadata.permissions().readonly() {
+        0o555
+    } else {
+        0o755
+    };
+    let mtime = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(std::time::UNIX_EPOCH).ok())
+        .map_or(0, |since| since.as_secs() as i64);
+    CpioHeader {
+        ino,
+        mode: kind | mode.unwrap_or(permissions),
+        uid: 0,
+        gid: 0,
+        nlink: 1,
+        mtime,
+        filesize: 0,
+    }
+}
@@ -0,0 +1,401 @@
+use std::fs::Metadata;
+use std::path::Path;
+use std::time::SystemTime;
+
+use clap::ValueEnum;
+
+use crate::hashing::HashAlgorithm;
+use crate::processor::{open_source, Digest};
+
+/// What was known about a file when its content was captured.
+pub struct CaptureRecord<'a> {
+    pub metadata: &'a Metadata,
+    pub digest: &'a Digest,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stability {
+    /// The file still holds the captured content.
+    Unchanged,
+    /// The file may have changed since it was captured.
+    Changed,
+}
+
+/// Judges whether a file still holds the content captured from it. The processor asks both
+/// before reusing a capture from an earlier pass and right after capturing, to catch a file that
+/// changed while it was being read.
+pub trait ChangeDetector {
+    fn is_stable(
+        &self,
+        path: &Path,
+        metadata: &Metadata,
+        captured: &CaptureRecord,
+        revision: SystemTime,
+    ) -> Stability;
+}
+
+/// Trusts a file to be unchanged while its size, mtime and identity stay the same.
+pub struct MtimeDetector;
+
+impl ChangeDetector for MtimeDetector {
+    fn is_stable(
+        &self,
+        _path: &Path,
+        metadata: &Metadata,
+        captured: &CaptureRecord,
+        _revision: SystemTime,
+    ) -> Stability {
+        if same_content(captured.metadata, metadata) {
+            Stability::Unchanged
+        } else {
+            Stability::Changed
+        }
+    }
+}
+
+/// Trusts a file to be unchanged while its size, change time and identity stay the same. Unlike
+/// the mtime, the change time can't be set back by the writer, so rewrites which restore the mtime
+/// are still caught. Falls back to the mtime where change times aren't available.
+pub struct CtimeDetector;
+
+impl ChangeDetector for CtimeDetector {
+    fn is_stable(
+        &self,
+        path: &Path,
+        metadata: &Metadata,
+        captured: &CaptureRecord,
+        revision: SystemTime,
+    ) -> Stability {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let _ = (path, revision);
+            let before = captured.metadata;
+            if before.len() == metadata.len()
+                && (before.ctime(), before.ctime_nsec())
+                    == (metadata.ctime(), metadata.ctime_nsec())
+                && file_identity(before) == file_identity(metadata)
+            {
+                Stability::Unchanged
+            } else {
+                Stability::Changed
+            }
+        }
+        #[cfg(not(unix))]
+        MtimeDetector.is_stable(path, metadata, captured, revision)
+    }
+}
+
+/// Re-reads the file and compares its digest against the capture, for filesystems whose metadata
+/// can't be trusted. Every check costs a full read of the file.
+pub struct HashDetector {
+    /// Must be the algorithm the capture was hashed with.
+    pub algorithm: HashAlgorithm,
+    /// Re-read files without updating their access time, where the platform allows it.
+    pub preserve_atime: bool,
+}
+
+impl ChangeDetector for HashDetector {
+    fn is_stable(
+        &self,
+        path: &Path,
+        metadata: &Metadata,
+        captured: &CaptureRecord,
+        _revision: SystemTime,
+    ) -> Stability {
+        if metadata.len() != captured.metadata.len() {
+            return Stability::Changed;
+        }
+        let digest = open_source(path, self.preserve_atime)
+            .and_then(|file| self.algorithm.digest_reader(file));
+        match digest {
+            Ok(digest) if digest == *captured.digest => Stability::Unchanged,
+            Ok(_) => Stability::Changed,
+            Err(err) => {
+                log::debug!("Failed to re-hash {}: {}", path.display(), err);
+                Stability::Changed
+            }
+        }
+    }
+}
+
+/// Trusts a file to be unchanged only while every one of its detectors does.
+pub struct CompositeDetector(pub Vec<Box<dyn ChangeDetector>>);
+
+impl ChangeDetector for CompositeDetector {
+    fn is_stable(
+        &self,
+        path: &Path,
+        metadata: &Metadata,
+        captured: &CaptureRecord,
+        revision: SystemTime,
+    ) -> Stability {
+        // cheaper detectors come first, so a change they notice spares the rest
+        let changed = self.0.iter().any(|detector| {
+            detector.is_stable(path, metadata, captured, revision) == Stability::Changed
+        });
+        if changed {
+            Stability::Changed
+        } else {
+            Stability::Unchanged
+        }
+    }
+}
+
+/// Change-detection strategies selectable from the command line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum ChangeDetection {
+    Mtime,
+    Ctime,
+    Hash,
+}
+
+/// Builds the detector for the selected strategies, combining them when there are several.
+pub fn build_detector(
+    strategies: &[ChangeDetection],
+    hash_algorithm: HashAlgorithm,
+    preserve_atime: bool,
+) -> Box<dyn ChangeDetector> {
+    let mut strategies = strategies.to_vec();
+    strategies.sort();
+    strategies.dedup();
+    let mut detectors: Vec<Box<dyn ChangeDetector>> = strategies
+        .into_iter()
+        .map(|strategy| -> Box<dyn ChangeDetector> {
+            match strategy {
+                ChangeDetection::Mtime => Box::new(MtimeDetector),
+                ChangeDetection::Ctime => Box::new(CtimeDetector),
+                ChangeDetection::Hash => Box::new(HashDetector {
+                    algorithm: hash_algorithm,
+                    preserve_atime,
+                }),
+            }
+        })
+        .collect();
+    match detectors.len() {
+        0 => Box::new(MtimeDetector),
+        1 => detectors.pop().unwrap(),
+        _ => Box::new(CompositeDetector(detectors)),
+    }
+}
+
+/// Whether two observations of a file describe the same content, judged by size, mtime and, where
+/// available, the file's identity.
+pub fn same_content(before: &Metadata, after: &Metadata) -> bool {
+    before.len() == after.len()
+        && before.modified().ok() == after.modified().ok()
+        && file_identity(before) == file_identity(after)
+}
+
+#[cfg(unix)]
+pub fn file_identity(metadata: &Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+pub fn file_identity(_metadata: &Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::time::Duration;
+
+    use super::*;
+
+    /// A file as captured: its metadata and the digest of what it held.
+    struct Captured {
+        path: std::path::PathBuf,
+        metadata: Metadata,
+        digest: Digest,
+    }
+
+    impl Captured {
+        fn new(path: &Path, content: &str) -> Self {
+            std::fs::write(path, content).unwrap();
+            // set well in the past, so restoring it after a rewrite can be told from the rewrite
+            let modified = SystemTime::now() - Duration::from_secs(3600);
+            std::fs::File::options()
+                .write(true)
+                .open(path)
+                .unwrap()
+                .set_modified(modified)
+                .unwrap();
+            Self {
+                path: path.to_path_buf(),
+                metadata: path.metadata().unwrap(),
+                digest: HashAlgorithm::Sha256.digest(content.as_bytes()),
+            }
+        }
+
+        /// Rewrites the file, then sets its mtime back to the captured one, as some tools do.
+        fn rewrite_keeping_mtime(&self, content: &str) {
+            // change times are only as fine as the kernel's clock tick
+            std::thread::sleep(Duration::from_millis(20));
+            std::fs::write(&self.path, content).unwrap();
+            std::fs::File::options()
+                .write(true)
+                .open(&self.path)
+                .unwrap()
+                .set_modified(self.metadata.modified().unwrap())
+                .unwrap();
+        }
+
+        fn judged_by(&self, detector: &dyn ChangeDetector) -> Stability {
+            let record = CaptureRecord {
+                metadata: &self.metadata,
+                digest: &self.digest,
+            };
+            let metadata = self.path.metadata().unwrap();
+            detector.is_stable(&self.path, &metadata, &record, SystemTime::now())
+        }
+    }
+
+    fn hash_detector() -> HashDetector {
+        HashDetector {
+            algorithm: HashAlgorithm::Sha256,
+            preserve_atime: false,
+        }
+    }
+
+    #[test]
+    fn mtime_detection_misses_rewrites_which_keep_the_size_and_mtime() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = Captured::new(&dir.path().join("file"), "content");
+        assert_eq!(file.judged_by(&MtimeDetector), Stability::Unchanged);
+
+        file.rewrite_keeping_mtime("CONTENT");
+        assert_eq!(file.judged_by(&MtimeDetector), Stability::Unchanged);
+        file.rewrite_keeping_mtime("longer content");
+        assert_eq!(file.judged_by(&MtimeDetector), Stability::Changed);
+        std::fs::write(&file.path, "content").unwrap();
+        assert_eq!(file.judged_by(&MtimeDetector), Stability::Changed);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn mtime_detection_catches_a_file_replaced_by_another() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = Captured::new(&dir.path().join("file"), "content");
+        // the same size and mtime, but another inode
+        let replacement = dir.path().join("replacement");
+        std::fs::write(&replacement, "CONTENT").unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&replacement)
+            .unwrap()
+            .set_modified(file.metadata.modified().unwrap())
+            .unwrap();
+        std::fs::rename(&replacement, &file.path).unwrap();
+        assert_eq!(file.judged_by(&MtimeDetector), Stability::Changed);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn ctime_detection_catches_rewrites_which_restore_the_mtime() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = Captured::new(&dir.path().join("file"), "content");
+        assert_eq!(file.judged_by(&CtimeDetector), Stability::Unchanged);
+
+        file.rewrite_keeping_mtime("CONTENT");
+        assert_eq!(file.judged_by(&CtimeDetector), Stability::Changed);
+    }
+
+    #[test]
+    fn hash_detection_goes_by_content_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = Captured::new(&dir.path().join("file"), "content");
+        assert_eq!(file.judged_by(&hash_detector()), Stability::Unchanged);
+
+        // a touch leaves the content as it was
+        std::fs::File::options()
+            .write(true)
+            .open(&file.path)
+            .unwrap()
+            .set_modified(SystemTime::now())
+            .unwrap();
+        assert_eq!(file.judged_by(&hash_detector()), Stability::Unchanged);
+        file.rewrite_keeping_mtime("CONTENT");
+        assert_eq!(file.judged_by(&hash_detector()), Stability::Changed);
+    }
+
+    #[test]
+    fn hash_detection_takes_a_file_it_cannot_read_as_changed() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = Captured::new(&dir.path().join("file"), "content");
+        let metadata = file.path.metadata().unwrap();
+        std::fs::remove_file(&file.path).unwrap();
+        let record = CaptureRecord {
+            metadata: &file.metadata,
+            digest: &file.digest,
+        };
+        assert_eq!(
+            hash_detector().is_stable(&file.path, &metadata, &record, SystemTime::now()),
+            Stability::Changed
+        );
+    }
+
+    /// Gives the same answer every time, counting how often it's asked.
+    struct Fixed {
+        answer: Stability,
+        asked: std::rc::Rc<Cell<usize>>,
+    }
+
+    impl ChangeDetector for Fixed {
+        fn is_stable(&self, _: &Path, _: &Metadata, _: &CaptureRecord, _: SystemTime) -> Stability {
+            self.asked.set(self.asked.get() + 1);
+            self.answer
+        }
+    }
+
+    #[test]
+    fn composite_detection_takes_a_change_any_detector_sees() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = Captured::new(&dir.path().join("file"), "content");
+        let asked = std::rc::Rc::new(Cell::new(0));
+        let composite = |answers: &[Stability]| {
+            CompositeDetector(
+                answers
+                    .iter()
+                    .map(|answer| -> Box<dyn ChangeDetector> {
+                        Box::new(Fixed {
+                            answer: *answer,
+                            asked: std::rc::Rc::clone(&asked),
+                        })
+                    })
+                    .collect(),
+            )
+        };
+        use Stability::{Changed, Unchanged};
+
+        assert_eq!(
+            file.judged_by(&composite(&[Unchanged, Unchanged])),
+            Unchanged
+        );
+        assert_eq!(asked.replace(0), 2);
+        assert_eq!(file.judged_by(&composite(&[Unchanged, Changed])), Changed);
+        // the first change seen spares asking the rest
+        assert_eq!(file.judged_by(&composite(&[Changed, Unchanged])), Changed);
+        assert_eq!(asked.replace(0), 3);
+    }
+
+    #[test]
+    fn built_detectors_combine_every_strategy_given() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = Captured::new(&dir.path().join("file"), "content");
+        let build = |strategies: &[ChangeDetection]| {
+            build_detector(strategies, HashAlgorithm::Sha256, false)
+        };
+        let mtime = build(&[]);
+        let combined = build(&[ChangeDetection::Hash, ChangeDetection::Mtime]);
+        assert_eq!(file.judged_by(mtime.as_ref()), Stability::Unchanged);
+        assert_eq!(file.judged_by(combined.as_ref()), Stability::Unchanged);
+
+        // only hashing sees this rewrite, so the combination does too
+        file.rewrite_keeping_mtime("CONTENT");
+        assert_eq!(file.judged_by(mtime.as_ref()), Stability::Unchanged);
+        assert_eq!(file.judged_by(combined.as_ref()), Stability::Changed);
+    }
+}
@@ -0,0 +1,169 @@
+use std::fs::File;
+use std::io::{BufReader, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, OnceLock};
+
+use zstd::dict::{DecoderDictionary, EncoderDictionary};
+
+use crate::archiver::create_file;
+use crate::estimator::collect_files;
+use crate::filter::EntryFilter;
+use crate::provenance;
+
+/// Size of trained dictionaries, the same as zstd's own default.
+const DICTIONARY_SIZE: usize = 112_640;
+/// Number of files sampled to train a dictionary.
+const SAMPLE_FILES: usize = 1024;
+/// Bytes read from the start of each sampled file. What files which look alike share mostly sits
+/// at their start, and small files are what a dictionary helps with anyway.
+const SAMPLE_SIZE: u64 = 64 * 1024;
+
+/// A zstd dictionary trained on files like the ones captured, which every file is compressed
+/// with so that small files compress about as well as they would all in one frame.
+#[derive(Clone)]
+pub struct Dictionary {
+    id: u32,
+    data: Arc<[u8]>,
+    /// Prepared for the compression level it's first used with. Preparing a dictionary for every
+    /// frame takes longer than compressing a small file.
+    encoding: Arc<OnceLock<(i32, EncoderDictionary<'static>)>>,
+    decoding: Arc<DecoderDictionary<'static>>,
+}
+
+impl Dictionary {
+    pub fn read<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        Self::new(std::fs::read(path)?)
+    }
+
+    fn new(data: Vec<u8>) -> std::io::Result<Self> {
+        match zstd::zstd_safe::get_dict_id(&data) {
+            Some(id) => Ok(Self {
+                id: id.get(),
+                decoding: Arc::new(DecoderDictionary::copy(&data)),
+                data: data.into(),
+                encoding: Arc::new(OnceLock::new()),
+            }),
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Not a trained zstd dictionary",
+            )),
+        }
+    }
+
+    /// The id zstd records in every frame compressed with the dictionary.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn write<P: AsRef<Path>>(&self, path: P, mode: Option<u32>) -> std::io::Result<()> {
+        create_file(path, mode)?.write_all(&self.data)
+    }
+}
+
+/// Trains a dictionary on an evenly spaced sample of a directory's files, ordered by path so the
+/// same tree always yields the same dictionary. Returns the dictionary and the number of files
+/// sampled.
+pub fn train<P: AsRef<Path>>(
+    directory_path: P,
+    filters: &[Box<dyn EntryFilter>],
+) -> std::io::Result<(Dictionary, usize)> {
+    let mut files = Vec::new();
+    collect_files(directory_path.as_ref(), filters, &mut files)?;
+    files.sort();
+
+    let stride = files.len().div_ceil(SAMPLE_FILES).max(1);
+    let mut samples = Vec::new();
+    for (path, _) in files.iter().step_by(stride) {
+        log::debug!("Sampling {}", path.display());
+        let mut sample = Vec::new();
+        File::open(path)?
+            .take(SAMPLE_SIZE)
+            .read_to_end(&mut sample)?;
+        if !sample.is_empty() {
+            samples.push(sample);
+        }
+    }
+    let data = zstd::dict::from_samples(&samples, DICTIONARY_SIZE)?;
+    Ok((Dictionary::new(data)?, samples.len()))
+}
+
+/// Starts a zstd frame, compressed with the dictionary if one is given.
+pub fn encoder<W: Write>(
+    writer: W,
+    compression_level: i32,
+    dictionary: Option<&Dictionary>,
+) -> std::io::Result<zstd::Encoder<'_, W>> {
+    let Some(dictionary) = dictionary else {
+        return zstd::Encoder::new(writer, compression_level);
+    };
+    let (prepared_level, prepared) = dictionary.encoding.get_or_init(|| {
+        (
+            compression_level,
+            EncoderDictionary::copy(&dictionary.data, compression_level),
+        )
+    });
+    if *prepared_level == compression_level {
+        zstd::Encoder::with_prepared_dictionary(writer, prepared)
+    } else {
+        zstd::Encoder::with_dictionary(writer, compression_level, &dictionary.data)
+    }
+}
+
+/// Reads a zstd frame, which may have been compressed with the dictionary if one is given.
+pub fn decoder<R: Read>(
+    reader: R,
+    dictionary: Option<&Dictionary>,
+) -> std::io::Result<zstd::Decoder<'_, BufReader<R>>> {
+    match dictionary {
+        Some(dictionary) => {
+            let reader = BufReader::with_capacity(zstd::zstd_safe::DCtx::in_size(), reader);
+            zstd::Decoder::with_prepared_dictionary(reader, &dictionary.decoding)
+        }
+        None => zstd::Decoder::new(reader),
+    }
+}
+
+/// Checks that every snapshot in the archive which was compressed with a dictionary used this
+/// one, going by the dictionary ids their snapshot records name.
+pub fn check_archive<P: AsRef<Path>>(
+    tarball_path: P,
+    dictionary: Option<&Dictionary>,
+) -> std::io::Result<()> {
+    for record in provenance::read_archive(tarball_path)? {
+        let Some(required) = record.dictionary_id else {
+            continue;
+        };
+        let message = match dictionary {
+            Some(dictionary) if dictionary.id() == required => continue,
+            Some(dictionary) => format!(
+                "Archive was compressed with dictionary {}, not {}",
+                required,
+                dictionary.id()
+            ),
+            None => format!(
+                "Archive was compressed with dictionary {}; pass it with --dict",
+                required
+            ),
+        };
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            message,
+        ));
+    }
+    Ok(())
+}
+
+/// Reads the dictionary given for reading an archive, if one is, and checks the archive needs no
+/// other.
+pub fn for_archive<P: AsRef<Path>>(
+    tarball_path: P,
+    dictionary_path: Option<&str>,
+) -> std::io::Result<Option<Dictionary>> {
+    let dictionary = dictionary_path.map(Dictionary::read).transpose()?;
+    check_archive(tarball_path, dictionary.as_ref())?;
+    Ok(dictionary)
+}
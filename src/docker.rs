@@ -0,0 +1,175 @@
+use std::error::Error;
+use std::io::{Read, Write};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Where the Docker daemon listens unless `DOCKER_HOST` names another unix socket.
+const DEFAULT_SOCKET: &str = "/var/run/docker.sock";
+/// How long any one request to the daemon may take.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Containers paused for the snapshot, and since when, so they're unpaused however it ends.
+static PAUSED: Mutex<Option<(Vec<String>, Instant)>> = Mutex::new(None);
+
+#[derive(Debug)]
+pub enum DockerError {
+    Io(std::io::Error),
+    /// The daemon refused a request, with the status and message it answered with.
+    Api(String, u16, String),
+    InvalidName(String),
+    NotRunning(String),
+}
+
+impl Error for DockerError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            DockerError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for DockerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DockerError::Io(err) => write!(f, "Failed to talk to the Docker daemon: {}", err),
+            DockerError::Api(request, status, message) => {
+                write!(f, "Docker refused {} with {}: {}", request, status, message)
+            }
+            DockerError::InvalidName(name) => write!(f, "Invalid container name {:?}", name),
+            DockerError::NotRunning(name) => write!(f, "Container {} is not running", name),
+        }
+    }
+}
+
+impl From<std::io::Error> for DockerError {
+    fn from(value: std::io::Error) -> Self {
+        DockerError::Io(value)
+    }
+}
+
+/// Unpauses the paused containers when dropped, so they're unpaused even if the snapshot panics.
+pub struct PauseGuard;
+
+impl Drop for PauseGuard {
+    fn drop(&mut self) {
+        unpause();
+    }
+}
+
+/// Pauses the containers, after checking every one of them exists and is running so a bad name
+/// fails the snapshot before anything is paused. Containers which are already paused are left to
+/// whoever paused them.
+pub fn pause(containers: &[String]) -> Result<PauseGuard, DockerError> {
+    let mut to_pause = Vec::new();
+    for container in containers {
+        if !container
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'))
+        {
+            return Err(DockerError::InvalidName(container.clone()));
+        }
+        let state = request("GET", &format!("/containers/{}/json", container))?;
+        let state: serde_json::Value = serde_json::from_str(&state)
+            .map_err(|err| DockerError::Io(std::io::Error::from(err)))?;
+        if state["State"]["Paused"].as_bool() == Some(true) {
+            log::warn!("Container {} is already paused; leaving it be", container);
+        } else if state["State"]["Running"].as_bool() == Some(true) {
+            to_pause.push(container.clone());
+        } else {
+            return Err(DockerError::NotRunning(container.clone()));
+        }
+    }
+    if to_pause.is_empty() {
+        return Ok(PauseGuard);
+    }
+
+    let mut record = PAUSED.lock().unwrap();
+    let (paused, _) = record.insert((Vec::new(), Instant::now()));
+    for container in to_pause {
+        request("POST", &format!("/containers/{}/pause", container))?;
+        log::info!("Paused container {}", container);
+        paused.push(container);
+    }
+    Ok(PauseGuard)
+}
+
+/// Unpauses the containers `pause` paused, if it paused any.
+pub fn unpause() {
+    let paused = match PAUSED.lock() {
+        Ok(mut paused) => paused.take(),
+        Err(_) => return,
+    };
+    let Some((containers, since)) = paused else {
+        return;
+    };
+    for container in &containers {
+        match request("POST", &format!("/containers/{}/unpause", container)) {
+            Ok(_) => log::debug!("Unpaused container {}", container),
+            Err(err) => log::error!("Failed to unpause container {}: {}", container, err),
+        }
+    }
+    log::info!(
+        "Unpaused {} containers after {} ms",
+        containers.len(),
+        since.elapsed().as_millis()
+    );
+}
+
+/// Sends a request without a body to the daemon, returning the body of a successful response.
+fn request(method: &str, path: &str) -> Result<String, DockerError> {
+    let mut stream = connect()?;
+    // HTTP/1.0 keeps the daemon from chunking its response
+    write!(
+        stream,
+        "{} {} HTTP/1.0\r\nHost: docker\r\nContent-Length: 0\r\n\r\n",
+        method, path
+    )?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+
+    let (head, body) = response.split_once("\r\n\r\n").unwrap_or((&response, ""));
+    let status = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse::<u16>().ok())
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Malformed response from the Docker daemon",
+            )
+        })?;
+    if (200..300).contains(&status) {
+        return Ok(body.to_string());
+    }
+    let message = serde_json::from_str::<serde_json::Value>(body)
+        .ok()
+        .and_then(|body| body["message"].as_str().map(str::to_string))
+        .unwrap_or_else(|| body.trim().to_string());
+    Err(DockerError::Api(
+        format!("{} {}", method, path),
+        status,
+        message,
+    ))
+}
+
+#[cfg(unix)]
+fn connect() -> std::io::Result<std::os::unix::net::UnixStream> {
+    let host = std::env::var("DOCKER_HOST").ok();
+    let socket = host
+        .as_deref()
+        .and_then(|host| host.strip_prefix("unix://"))
+        .unwrap_or(DEFAULT_SOCKET);
+    let stream = std::os::unix::net::UnixStream::connect(socket)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    Ok(stream)
+}
+
+#[cfg(not(unix))]
+fn connect() -> std::io::Result<std::fs::File> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "Talking to Docker is only supported over a unix socket",
+    ))
+}
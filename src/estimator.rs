@@ -0,0 +1,191 @@
+use std::fs::File;
+use std::io::{BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::dictionary::{self, Dictionary};
+use crate::filter::{self, EntryFilter, EntryKind, FilterDecision};
+
+/// Number of files compressed to build an estimate.
+const SAMPLE_FILES: usize = 64;
+/// Bytes a tar header occupies for every entry.
+const TAR_HEADER_SIZE: u64 = 512;
+
+pub struct Estimate {
+    pub files: usize,
+    pub total_bytes: u64,
+    pub sampled_files: usize,
+    pub sampled_bytes: u64,
+    pub ratio: f64,
+    pub projected_size: u64,
+    pub projected_duration: Duration,
+}
+
+/// Estimates the archive size and capture duration for a directory by compressing an evenly spaced
+/// sample of its files, ordered by path so the same tree always yields the same sample.
+pub fn estimate_directory<P: AsRef<Path>>(
+    directory_path: P,
+    compression_level: i32,
+    dictionary: Option<&Dictionary>,
+    filters: &[Box<dyn EntryFilter>],
+) -> std::io::Result<Estimate> {
+    let mut files = Vec::new();
+    collect_files(directory_path.as_ref(), filters, &mut files)?;
+    files.sort();
+
+    let total_bytes = files.iter().map(|(_, size)| size).sum();
+    let stride = files.len().div_ceil(SAMPLE_FILES).max(1);
+
+    let mut sampled_files = 0;
+    let mut sampled_bytes = 0;
+    let mut compressed_bytes = 0;
+    let start = Instant::now();
+    for (path, size) in files.iter().step_by(stride) {
+        log::debug!("Sampling {}", path.display());
+        let mut counter = CountingWriter::default();
+        let mut encoder = dictionary::encoder(&mut counter, compression_level, dictionary)?;
+        std::io::copy(&mut BufReader::new(File::open(path)?), &mut encoder)?;
+        encoder.finish()?;
+        sampled_files += 1;
+        sampled_bytes += size;
+        compressed_bytes += counter.count;
+    }
+    let elapsed = start.elapsed();
+
+    let ratio = if sampled_bytes == 0 {
+        1.0
+    } else {
+        compressed_bytes as f64 / sampled_bytes as f64
+    };
+    let projected_duration = if sampled_bytes == 0 {
+        Duration::ZERO
+    } else {
+        elapsed.mul_f64(total_bytes as f64 / sampled_bytes as f64)
+    };
+    let padding = files.len() as u64 * TAR_HEADER_SIZE;
+
+    Ok(Estimate {
+        files: files.len(),
+        total_bytes,
+        sampled_files,
+        sampled_bytes,
+        ratio,
+        projected_size: (total_bytes as f64 * ratio) as u64 + padding,
+        projected_duration,
+    })
+}
+
+/// Collects every file under the directory the filters let through, along with its size.
+pub fn collect_files(
+    directory_path: &Path,
+    filters: &[Box<dyn EntryFilter>],
+    files: &mut Vec<(PathBuf, u64)>,
+) -> std::io::Result<()> {
+    for entry in directory_path.read_dir()? {
+        let entry = entry?;
+        let path = entry.path();
+        let file_type = entry.file_type()?;
+        let kind = if file_type.is_dir() {
+            EntryKind::Directory
+        } else if file_type.is_file() {
+            EntryKind::File
+        } else {
+            continue;
+        };
+        match filter::evaluate(filters, &path, kind)? {
+            FilterDecision::PruneSubtree => {}
+            _ if kind == EntryKind::Directory => collect_files(&path, filters, files)?,
+            FilterDecision::Include => files.push((path, entry.metadata()?.len())),
+            FilterDecision::SkipEntry => {}
+        }
+    }
+    Ok(())
+}
+
+#[derive(Default)]
+struct CountingWriter {
+    count: u64,
+}
+
+impl Write for CountingWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.count += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(path: &Path, content: &[u8]) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, content).unwrap();
+    }
+
+    /// Bytes zstd can't do anything with.
+    fn noise(len: usize, seed: u32) -> Vec<u8> {
+        let mut state = seed.wrapping_mul(2654435761) | 1;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn a_sample_of_the_files_projects_the_ratio_of_them_all() {
+        let (text, random) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        for index in 0..200 {
+            let name = format!("dir{}/file{}", index % 7, index);
+            write(
+                &text.path().join(&name),
+                &b"all work and no play ".repeat(200),
+            );
+            write(&random.path().join(&name), &noise(4000, index));
+        }
+
+        let text = estimate_directory(text.path(), 3, None, &[]).unwrap();
+        assert_eq!((text.files, text.total_bytes), (200, 200 * 4200));
+        assert!(text.sampled_files <= SAMPLE_FILES && text.sampled_files > 0);
+        assert!(text.ratio < 0.05, "{}", text.ratio);
+        assert!(text.projected_size < text.total_bytes / 4);
+
+        let random = estimate_directory(random.path(), 3, None, &[]).unwrap();
+        assert!(random.ratio > 0.95, "{}", random.ratio);
+        // the headers every file needs come on top of what can't be compressed
+        assert!(random.projected_size >= random.total_bytes * 95 / 100 + 200 * TAR_HEADER_SIZE);
+    }
+
+    #[test]
+    fn the_same_tree_is_sampled_the_same_way() {
+        let target = tempfile::tempdir().unwrap();
+        for index in 0..150 {
+            write(
+                &target.path().join(format!("{}", index)),
+                &noise(100 + index as usize, index),
+            );
+        }
+        let first = estimate_directory(target.path(), 3, None, &[]).unwrap();
+        let second = estimate_directory(target.path(), 3, None, &[]).unwrap();
+        assert_eq!(first.sampled_files, second.sampled_files);
+        assert_eq!(first.sampled_bytes, second.sampled_bytes);
+        assert_eq!(first.projected_size, second.projected_size);
+    }
+
+    #[test]
+    fn an_empty_tree_projects_nothing() {
+        let target = tempfile::tempdir().unwrap();
+        std::fs::create_dir(target.path().join("empty")).unwrap();
+        let estimate = estimate_directory(target.path(), 3, None, &[]).unwrap();
+        assert_eq!((estimate.files, estimate.projected_size), (0, 0));
+        assert_eq!(estimate.ratio, 1.0);
+    }
+}
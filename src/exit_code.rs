@@ -0,0 +1,118 @@
+use crate::processor::ProcessError;
+
+/// What SIT exits with, telling apart the ways a run can end without reading its log. The numbers
+/// are kept once given out, so new outcomes only ever take new ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitCode {
+    Success = 0,
+    /// Anything failing which isn't told apart by a code of its own, like an archive which can't be
+    /// read or a manifest which can't be parsed.
+    Failed = 1,
+    /// The target doesn't exist, can't be resolved, or vanished during the snapshot.
+    TargetMissing = 2,
+    /// The target isn't a directory, or is a symlink to one refused by `--target-no-follow`.
+    TargetNotDirectory = 3,
+    /// The archive failed verification, or differs from what it was compared against.
+    VerificationFailed = 4,
+    /// Some paths couldn't be captured, or were refused by `--mode`.
+    CaptureIncomplete = 5,
+    /// A signal stopped the snapshot.
+    Interrupted = 6,
+    /// `--timeout` passed before the snapshot was done.
+    TimedOut = 7,
+    /// Another snapshot of the same target is running.
+    TargetLocked = 8,
+    /// A pre-hook failed.
+    HookFailed = 9,
+    /// `--fail-on-empty` found no files to capture.
+    SnapshotEmpty = 10,
+    /// Saving couldn't be paused over RCON.
+    RconFailed = 11,
+    /// Containers couldn't be paused.
+    DockerFailed = 12,
+    /// The snapshot finished, but left out paths it couldn't capture.
+    PartialSuccess = 13,
+    /// The snapshot finished with more warnings than `--fail-on-warning` or `--max-warnings` allow.
+    TooManyWarnings = 14,
+    /// The command line was malformed, or its options contradict each other.
+    BadArguments = 15,
+    /// Capturing failed beyond recovery, like when captures can't be encoded or kept.
+    ProcessingFailed = 16,
+    /// The target kept changing for every pass `--iteration-retries` allows.
+    IterationBoundExceeded = 17,
+    /// The archive, or a file written along with it, couldn't be written.
+    ArchiveWriteFailed = 18,
+}
+
+impl ExitCode {
+    /// Every exit code, in numeric order.
+    pub const ALL: [ExitCode; 19] = [
+        ExitCode::Success,
+        ExitCode::Failed,
+        ExitCode::TargetMissing,
+        ExitCode::TargetNotDirectory,
+        ExitCode::VerificationFailed,
+        ExitCode::CaptureIncomplete,
+        ExitCode::Interrupted,
+        ExitCode::TimedOut,
+        ExitCode::TargetLocked,
+        ExitCode::HookFailed,
+        ExitCode::SnapshotEmpty,
+        ExitCode::RconFailed,
+        ExitCode::DockerFailed,
+        ExitCode::PartialSuccess,
+        ExitCode::TooManyWarnings,
+        ExitCode::BadArguments,
+        ExitCode::ProcessingFailed,
+        ExitCode::IterationBoundExceeded,
+        ExitCode::ArchiveWriteFailed,
+    ];
+
+    pub fn code(self) -> i32 {
+        self as i32
+    }
+
+    /// What exiting with the code means, as listed by `--list-exit-codes`.
+    pub fn meaning(self) -> &'static str {
+        match self {
+            ExitCode::Success => "success",
+            ExitCode::Failed => "failed for another reason",
+            ExitCode::TargetMissing => "target missing",
+            ExitCode::TargetNotDirectory => "target not a directory",
+            ExitCode::VerificationFailed => "verification failed",
+            ExitCode::CaptureIncomplete => "capture incomplete",
+            ExitCode::Interrupted => "interrupted",
+            ExitCode::TimedOut => "timed out",
+            ExitCode::TargetLocked => "target locked by another snapshot",
+            ExitCode::HookFailed => "pre-hook failed",
+            ExitCode::SnapshotEmpty => "snapshot empty",
+            ExitCode::RconFailed => "RCON failed",
+            ExitCode::DockerFailed => "pausing containers failed",
+            ExitCode::PartialSuccess => "completed with paths left out",
+            ExitCode::TooManyWarnings => "too many warnings",
+            ExitCode::BadArguments => "bad arguments",
+            ExitCode::ProcessingFailed => "processing failed",
+            ExitCode::IterationBoundExceeded => "iteration bound exceeded",
+            ExitCode::ArchiveWriteFailed => "writing the archive failed",
+        }
+    }
+
+    pub fn exit(self) -> ! {
+        std::process::exit(self.code())
+    }
+}
+
+impl From<&ProcessError> for ExitCode {
+    fn from(value: &ProcessError) -> Self {
+        match value {
+            ProcessError::PathNotDir(_) => ExitCode::TargetNotDirectory,
+            ProcessError::TargetVanished(_) => ExitCode::TargetMissing,
+            ProcessError::IterationBoundExceeded(_) => ExitCode::IterationBoundExceeded,
+            ProcessError::Refused(_) => ExitCode::CaptureIncomplete,
+            ProcessError::Cancelled => ExitCode::Interrupted,
+            ProcessError::EncodeFailed(..)
+            | ProcessError::CheckpointFailed(..)
+            | ProcessError::TempDirUnavailable(..) => ExitCode::ProcessingFailed,
+        }
+    }
+}
@@ -0,0 +1,333 @@
+use std::collections::HashMap;
+use std::fs::{File, Metadata};
+use std::io::{BufReader, BufWriter, Read};
+use std::path::{Path, PathBuf};
+
+use crate::archiver::{self, ArchiveSink, ArchivedEntry, TarOptions};
+use crate::detector::file_identity;
+use crate::dictionary;
+use crate::hashing::HashAlgorithm;
+use crate::manifest::Manifest;
+use crate::processor::{Digest, Entry};
+use crate::provenance::Provenance;
+use crate::report::SnapshotReport;
+use crate::restorer::{apply_metadata, create_symlink};
+
+/// Suffix of a snapshot directory still being filled, which it keeps should the snapshot fail.
+const INCOMPLETE_SUFFIX: &str = ".incomplete";
+
+/// Where a snapshot directory is filled before it's complete.
+fn incomplete_path(directory: &Path) -> PathBuf {
+    let mut name = directory.as_os_str().to_owned();
+    name.push(INCOMPLETE_SUFFIX);
+    PathBuf::from(name)
+}
+
+/// Writes the captured entries out as a directory tree mirroring the target, hard linking every
+/// file unchanged since the snapshot directory at `link_dest` to its copy there instead of
+/// copying it again. The tree is filled under a name marking it incomplete and only renamed into
+/// place once every entry is written, so a snapshot failing partway leaves a directory which is
+/// plainly not a finished snapshot.
+pub fn create_farm<P1: AsRef<Path>, P2: AsRef<Path>>(
+    origin: P1,
+    entries: impl IntoIterator<Item = Entry>,
+    directory: P2,
+    link_dest: Option<&Path>,
+    hash_algorithm: HashAlgorithm,
+    options: &TarOptions,
+    report: &mut SnapshotReport,
+) -> std::io::Result<(Vec<ArchivedEntry>, Manifest)> {
+    let origin = origin.as_ref();
+    let directory = directory.as_ref();
+    log::info!("Creating snapshot directory at {}", directory.display());
+    if directory.exists() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("{} already exists", directory.display()),
+        ));
+    }
+    let incomplete = incomplete_path(directory);
+    if let Some(parent) = incomplete.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::create_dir(&incomplete)?;
+
+    let sink = FarmSink::new(&incomplete, origin, link_dest, hash_algorithm, options);
+    let written =
+        archiver::write_archive(origin, entries, sink, options, None, report).and_then(|written| {
+            // a snapshot which ran out of time is as incomplete as one which failed
+            if report.partial {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Interrupted,
+                    "ran out of time before every entry was written",
+                ));
+            }
+            std::fs::rename(&incomplete, directory)?;
+            archiver::sync_parent(directory)?;
+            Ok(written)
+        });
+    match written {
+        Ok(written) => {
+            let farmed = written.output;
+            if link_dest.is_some() {
+                log::info!(
+                    "Hard linked {} unchanged files to the previous snapshot",
+                    farmed.linked
+                );
+            }
+            report.unchanged_files = farmed.linked;
+            report.bytes_written = farmed.bytes_written;
+            Ok((written.archived, written.manifest))
+        }
+        Err(err) => {
+            log::warn!(
+                "Left the incomplete snapshot directory at {}",
+                incomplete.display()
+            );
+            Err(err)
+        }
+    }
+}
+
+/// What a `FarmSink` wrote.
+pub struct Farmed {
+    /// Files hard linked to their copies in the previous snapshot.
+    pub linked: usize,
+    /// Bytes of the files copied out of their captures.
+    pub bytes_written: u64,
+}
+
+/// Writes entries out as files, directories and symlinks under a directory standing for the
+/// target, decompressing every file. Files hard linked to each other in the target are hard
+/// linked the same way, and files unchanged since the previous snapshot directory are hard linked
+/// to their copies there.
+pub struct FarmSink {
+    directory: PathBuf,
+    options: TarOptions,
+    /// Leading directory of every path in the snapshot, standing for the target itself.
+    root: PathBuf,
+    /// Snapshot directory whose unchanged files are linked to.
+    link_dest: Option<PathBuf>,
+    hash_algorithm: HashAlgorithm,
+    /// Directories whose permissions and mtimes are applied last, so writing their content
+    /// doesn't bump their mtimes, along with those permissions, owner and mtime.
+    directories: Vec<(PathBuf, u32, (u64, u64), u64)>,
+    /// Where files written so far were written, by the identity of the file on disk.
+    written: HashMap<(u64, u64), PathBuf>,
+    farmed: Farmed,
+}
+
+impl FarmSink {
+    pub fn new(
+        directory: &Path,
+        origin: &Path,
+        link_dest: Option<&Path>,
+        hash_algorithm: HashAlgorithm,
+        options: &TarOptions,
+    ) -> Self {
+        Self {
+            directory: directory.to_path_buf(),
+            options: options.clone(),
+            root: origin.file_name().map(PathBuf::from).unwrap_or_default(),
+            link_dest: link_dest.map(Path::to_path_buf),
+            hash_algorithm,
+            directories: Vec::new(),
+            written: HashMap::new(),
+            farmed: Farmed {
+                linked: 0,
+                bytes_written: 0,
+            },
+        }
+    }
+
+    /// The path relative to the target, empty for the target itself.
+    fn member_name<'a>(&self, relative_path: &'a Path) -> &'a Path {
+        relative_path
+            .strip_prefix(&self.root)
+            .unwrap_or(relative_path)
+    }
+
+    /// Finds the copy of a file in the previous snapshot, as long as it has the size, mtime and
+    /// permissions the file is written with and holds the content the file was captured with.
+    fn previous_copy(
+        &self,
+        name: &Path,
+        size: u64,
+        attributes: &Attributes,
+        digest: &Digest,
+    ) -> Option<PathBuf> {
+        let previous = self.link_dest.as_ref()?.join(name);
+        let metadata = std::fs::symlink_metadata(&previous).ok()?;
+        if !metadata.is_file() || metadata.len() != size {
+            return None;
+        }
+        let found = attributes_of(&metadata, None);
+        if found.mode & 0o7777 != attributes.mode & 0o7777 || found.mtime != attributes.mtime {
+            return None;
+        }
+        let file = File::open(&previous).ok()?;
+        let previous_digest = self
+            .hash_algorithm
+            .digest_reader(BufReader::new(file))
+            .ok()?;
+        (previous_digest == *digest).then_some(previous)
+    }
+}
+
+impl ArchiveSink for FarmSink {
+    type Output = Farmed;
+    type Error = std::io::Error;
+
+    fn add_file(
+        &mut self,
+        metadata: &Metadata,
+        relative_path: &Path,
+        size: u64,
+        digest: Option<&Digest>,
+        encoded_size: u64,
+        encoded: &mut dyn Read,
+    ) -> std::io::Result<()> {
+        let name = self.member_name(relative_path);
+        let path = self.directory.join(name);
+        let attributes = attributes_of(metadata, self.options.file_mode);
+
+        // the first of a set of hard linked files is written, and the rest are linked to it
+        let identity = file_identity(metadata).filter(|_| archiver::link_count(metadata) > 1);
+        if let Some(first) = identity.and_then(|identity| self.written.get(&identity)) {
+            log::debug!("Linking {} to {}", path.display(), first.display());
+            std::fs::hard_link(first, &path)?;
+            std::io::copy(encoded, &mut std::io::sink())?;
+            return Ok(());
+        }
+        if let Some(identity) = identity {
+            self.written.insert(identity, path.clone());
+        }
+
+        let previous =
+            digest.and_then(|digest| self.previous_copy(name, size, &attributes, digest));
+        if let Some(previous) = previous {
+            match std::fs::hard_link(&previous, &path) {
+                Ok(_) => {
+                    log::debug!("Linking unchanged file {}", path.display());
+                    std::io::copy(encoded, &mut std::io::sink())?;
+                    self.farmed.linked += 1;
+                    return Ok(());
+                }
+                // a filesystem out of links for the inode still takes a copy
+                Err(err) => log::debug!(
+                    "Failed to link {} to {}, copying it instead: {}",
+                    path.display(),
+                    previous.display(),
+                    err
+                ),
+            }
+        }
+
+        log::debug!("Writing file {} with size {}", path.display(), size);
+        let mut writer = BufWriter::new(File::create(&path)?);
+        // files captured without their content hold no frame to decompress
+        if encoded_size > 0 {
+            let dictionary = self.options.dictionary.clone();
+            let mut decoder = dictionary::decoder(&mut *encoded, dictionary.as_ref())?;
+            self.farmed.bytes_written += std::io::copy(&mut decoder, &mut writer)?;
+        }
+        std::io::copy(encoded, &mut std::io::sink())?;
+        writer
+            .into_inner()
+            .map_err(|err| err.into_error())?
+            .sync_all()?;
+        apply_metadata(
+            &path,
+            attributes.mode,
+            Some(attributes.owner),
+            attributes.mtime,
+        )
+    }
+
+    fn add_dir(&mut self, metadata: &Metadata, relative_path: &Path) -> std::io::Result<()> {
+        let path = self.directory.join(self.member_name(relative_path));
+        log::debug!("Creating directory {}", path.display());
+        std::fs::create_dir_all(&path)?;
+        let attributes = attributes_of(metadata, self.options.dir_mode);
+        self.directories
+            .push((path, attributes.mode, attributes.owner, attributes.mtime));
+        Ok(())
+    }
+
+    fn add_symlink(
+        &mut self,
+        _metadata: &Metadata,
+        relative_path: &Path,
+        target: &Path,
+    ) -> std::io::Result<()> {
+        let path = self.directory.join(self.member_name(relative_path));
+        log::debug!(
+            "Creating symlink {} -> {}",
+            path.display(),
+            target.display()
+        );
+        create_symlink(target, &path)
+    }
+
+    fn add_whiteout(&mut self, relative_path: &Path) -> std::io::Result<()> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            format!(
+                "a snapshot directory holds every path, so {} can't be recorded as removed",
+                relative_path.display()
+            ),
+        ))
+    }
+
+    // the tree is the snapshot itself, with nowhere to keep a record of it
+    fn add_manifest(&mut self, _manifest: &Manifest) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn add_provenance(&mut self, _provenance: &Provenance) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn finish(self) -> std::io::Result<Farmed> {
+        // children come after their parents, so they're finalized before them
+        for (path, mode, owner, mtime) in self.directories.into_iter().rev() {
+            apply_metadata(&path, mode, Some(owner), mtime)?;
+        }
+        Ok(self.farmed)
+    }
+}
+
+/// The permissions, owner and mtime an entry is written with.
+struct Attributes {
+    mode: u32,
+    owner: (u64, u64),
+    mtime: u64,
+}
+
+#[cfg(unix)]
+fn attributes_of(metadata: &Metadata, mode: Option<u32>) -> Attributes {
+    use std::os::unix::fs::MetadataExt;
+    Attributes {
+        mode: mode.unwrap_or(metadata.mode()),
+        owner: (u64::from(metadata.uid()), u64::from(metadata.gid())),
+        mtime: metadata.mtime().max(0) as u64,
+    }
+}
+
+#[cfg(not(unix))]
+fn attributes_of(metadata: &Metadata, mode: Option<u32>) -> Attributes {
+    let permissions = if metadata.permissions().readonly() {
+        0o555
+    } else {
+        0o755
+    };
+    Attributes {
+        mode: mode.unwrap_or(permissions),
+        owner: (0, 0),
+        mtime: metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(std::time::UNIX_EPOCH).ok())
+            .map_or(0, |since| since.as_secs()),
+    }
+}
@@ -0,0 +1,515 @@
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::fs::{File, Metadata};
+use std::io::Read;
+use std::panic::AssertUnwindSafe;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::SystemTime;
+
+use globset::GlobSet;
+
+/// What kind of entry a filter is deciding on. When dereferencing, symlinks to files or
+/// directories are judged as what they point at, like the rest of the visit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Directory,
+    Symlink,
+}
+
+/// What a filter decided about a path. When several filters disagree, the most restrictive
+/// decision wins.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FilterDecision {
+    Include,
+    /// Leave the entry itself out, while still visiting whatever is beneath a directory.
+    SkipEntry,
+    /// Leave out the entry and everything beneath it, without visiting any of it.
+    PruneSubtree,
+}
+
+/// Decides which paths are captured. Filters are consulted before a file is opened or a directory
+/// is listed.
+pub trait EntryFilter {
+    fn decide(&self, path: &Path, metadata: &Metadata, kind: EntryKind) -> FilterDecision;
+}
+
+impl<F> EntryFilter for F
+where
+    F: Fn(&Path, &Metadata, EntryKind) -> FilterDecision,
+{
+    fn decide(&self, path: &Path, metadata: &Metadata, kind: EntryKind) -> FilterDecision {
+        self(path, metadata, kind)
+    }
+}
+
+/// Prunes specific paths, such as the snapshot's own output when it lives inside the target.
+pub struct ExcludedPaths(pub Vec<PathBuf>);
+
+impl EntryFilter for ExcludedPaths {
+    fn decide(&self, path: &Path, _metadata: &Metadata, _kind: EntryKind) -> FilterDecision {
+        if self.0.iter().any(|excluded| excluded == path) {
+            FilterDecision::PruneSubtree
+        } else {
+            FilterDecision::Include
+        }
+    }
+}
+
+/// Skips files last modified at or before an instant. Directories are always included so the
+/// files beneath them are still considered.
+pub struct ModifiedSince(pub SystemTime);
+
+impl EntryFilter for ModifiedSince {
+    fn decide(&self, _path: &Path, metadata: &Metadata, kind: EntryKind) -> FilterDecision {
+        let predates = metadata.modified().is_ok_and(|modified| modified <= self.0);
+        if kind != EntryKind::Directory && predates {
+            FilterDecision::SkipEntry
+        } else {
+            FilterDecision::Include
+        }
+    }
+}
+
+/// Skips files last modified outside a window: at or before `since`, or at or after `before`,
+/// whichever are given. Directories are always included so the files beneath them are still
+/// considered. Every file skipped is recorded in `found`, which stays with whoever built the
+/// filter.
+pub struct ModifiedWithin {
+    pub since: Option<SystemTime>,
+    pub before: Option<SystemTime>,
+    pub found: Rc<RefCell<BTreeSet<PathBuf>>>,
+}
+
+impl EntryFilter for ModifiedWithin {
+    fn decide(&self, path: &Path, metadata: &Metadata, kind: EntryKind) -> FilterDecision {
+        let Some(modified) = metadata
+            .modified()
+            .ok()
+            .filter(|_| kind != EntryKind::Directory)
+        else {
+            return FilterDecision::Include;
+        };
+        let within = self.since.is_none_or(|since| modified > since)
+            && self.before.is_none_or(|before| modified < before);
+        if within {
+            return FilterDecision::Include;
+        }
+        if self.found.borrow_mut().insert(path.to_path_buf()) {
+            log::debug!("Skipping {}, modified outside the window", path.display());
+        }
+        FilterDecision::SkipEntry
+    }
+}
+
+/// Skips files last modified after the revision a snapshot is taken at, as what they hold now
+/// isn't what they held then. Directories are always included. Every file skipped is recorded in
+/// `found`, which stays with whoever built the filter.
+pub struct AtRevision {
+    pub revision: SystemTime,
+    pub found: Rc<RefCell<BTreeSet<PathBuf>>>,
+}
+
+impl EntryFilter for AtRevision {
+    fn decide(&self, path: &Path, metadata: &Metadata, kind: EntryKind) -> FilterDecision {
+        let after = kind != EntryKind::Directory
+            && metadata
+                .modified()
+                .is_ok_and(|modified| modified > self.revision);
+        if !after {
+            return FilterDecision::Include;
+        }
+        if self.found.borrow_mut().insert(path.to_path_buf()) {
+            log::debug!("Skipping {}, modified after the revision", path.display());
+        }
+        FilterDecision::SkipEntry
+    }
+}
+
+/// Name of the file tagging a directory as a cache, per the Cache Directory Tagging Specification.
+pub const CACHE_TAG_NAME: &str = "CACHEDIR.TAG";
+
+/// Signature a cache tag must start with to count.
+const CACHE_TAG_SIGNATURE: &[u8] = b"Signature: 8a477f597d28d172789f06886806bc55";
+
+/// How much of a directory tagged as a cache is left out, after GNU tar's flags of the same names.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheExclusion {
+    /// `--exclude-caches`: everything beneath the directory but its tag, so a restore still shows
+    /// what was left out.
+    Contents,
+    /// `--exclude-caches-under`: everything beneath the directory, its tag included.
+    Under,
+    /// `--exclude-caches-all`: the directory itself along with everything beneath it.
+    All,
+}
+
+/// Leaves out directories tagged as caches, which hold nothing worth restoring, as far as the
+/// exclusion says.
+pub struct CacheDirectories {
+    pub exclusion: CacheExclusion,
+    /// Every tagged directory found so far, shared with whatever reports on them once the
+    /// snapshot is taken. Directories are judged again by every iteration, but found only once.
+    pub found: Rc<RefCell<BTreeSet<PathBuf>>>,
+}
+
+impl CacheDirectories {
+    fn record(&self, directory: &Path) {
+        if self.found.borrow_mut().insert(directory.to_path_buf()) {
+            log::debug!("Skipping cache directory {}", directory.display());
+        }
+    }
+}
+
+impl EntryFilter for CacheDirectories {
+    fn decide(&self, path: &Path, _metadata: &Metadata, kind: EntryKind) -> FilterDecision {
+        if self.exclusion == CacheExclusion::All {
+            return if kind == EntryKind::Directory && is_cache_directory(path) {
+                self.record(path);
+                FilterDecision::PruneSubtree
+            } else {
+                FilterDecision::Include
+            };
+        }
+        // the directory is kept, and whatever is beneath it judged by its parent
+        let Some(cache) = path.parent().filter(|parent| is_cache_directory(parent)) else {
+            return FilterDecision::Include;
+        };
+        self.record(cache);
+        let tag = kind == EntryKind::File && path.file_name() == Some(CACHE_TAG_NAME.as_ref());
+        if tag && self.exclusion == CacheExclusion::Contents {
+            FilterDecision::Include
+        } else {
+            FilterDecision::PruneSubtree
+        }
+    }
+}
+
+/// Prunes hidden entries: those whose name starts with a dot, and on Windows those with the
+/// hidden attribute as well. Entries are judged by their own name, so the target itself is never
+/// hidden, and paths the `include` globs match are kept whatever they're named.
+pub struct HiddenEntries {
+    pub include: GlobSet,
+    /// Every hidden path left out so far, shared with whatever reports on them once the snapshot
+    /// is taken.
+    pub found: Rc<RefCell<BTreeSet<PathBuf>>>,
+}
+
+impl EntryFilter for HiddenEntries {
+    fn decide(&self, path: &Path, metadata: &Metadata, _kind: EntryKind) -> FilterDecision {
+        let dotted = path
+            .file_name()
+            .is_some_and(|name| name.as_encoded_bytes().starts_with(b"."));
+        if !(dotted || has_hidden_attribute(metadata)) || self.include.is_match(path) {
+            return FilterDecision::Include;
+        }
+        if self.found.borrow_mut().insert(path.to_path_buf()) {
+            log::debug!("Skipping hidden entry {}", path.display());
+        }
+        FilterDecision::PruneSubtree
+    }
+}
+
+#[cfg(windows)]
+fn has_hidden_attribute(metadata: &Metadata) -> bool {
+    use std::os::windows::fs::MetadataExt;
+    const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+    metadata.file_attributes() & FILE_ATTRIBUTE_HIDDEN != 0
+}
+
+#[cfg(not(windows))]
+fn has_hidden_attribute(_metadata: &Metadata) -> bool {
+    false
+}
+
+/// Skips files and symlinks whose owner isn't among `users`, or whose group isn't among `groups`,
+/// leaving either check out when it has no ids to check against. Directories are always included,
+/// so matching files beneath directories owned by others are still found. Every entry skipped is
+/// recorded in `found`, which stays with whoever built the filter.
+pub struct OwnedBy {
+    pub users: Vec<u32>,
+    pub groups: Vec<u32>,
+    pub found: Rc<RefCell<BTreeSet<PathBuf>>>,
+}
+
+impl EntryFilter for OwnedBy {
+    fn decide(&self, path: &Path, metadata: &Metadata, kind: EntryKind) -> FilterDecision {
+        let Some((uid, gid)) = owner_of(metadata).filter(|_| kind != EntryKind::Directory) else {
+            return FilterDecision::Include;
+        };
+        if (self.users.is_empty() || self.users.contains(&uid))
+            && (self.groups.is_empty() || self.groups.contains(&gid))
+        {
+            return FilterDecision::Include;
+        }
+        if self.found.borrow_mut().insert(path.to_path_buf()) {
+            log::debug!("Skipping {}, owned by {}:{}", path.display(), uid, gid);
+        }
+        FilterDecision::SkipEntry
+    }
+}
+
+#[cfg(unix)]
+fn owner_of(metadata: &Metadata) -> Option<(u32, u32)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.uid(), metadata.gid()))
+}
+
+#[cfg(not(unix))]
+fn owner_of(_metadata: &Metadata) -> Option<(u32, u32)> {
+    None
+}
+
+/// Size of the buffer the user and group databases fill in with the strings of an entry.
+#[cfg(unix)]
+const LOOKUP_BUFFER_SIZE: usize = 16 * 1024;
+
+/// Resolves a user name, or a numeric uid, to a uid.
+#[cfg(unix)]
+pub fn resolve_user(user: &str) -> std::io::Result<u32> {
+    if let Ok(uid) = user.parse() {
+        return Ok(uid);
+    }
+    let name = lookup_name(user)?;
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0; LOOKUP_BUFFER_SIZE];
+    let mut found = std::ptr::null_mut();
+    let code = unsafe {
+        libc::getpwnam_r(
+            name.as_ptr(),
+            &mut passwd,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut found,
+        )
+    };
+    if code != 0 {
+        return Err(std::io::Error::from_raw_os_error(code));
+    }
+    if found.is_null() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("there is no user named {}", user),
+        ));
+    }
+    Ok(passwd.pw_uid)
+}
+
+/// Resolves a group name, or a numeric gid, to a gid.
+#[cfg(unix)]
+pub fn resolve_group(group: &str) -> std::io::Result<u32> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+    let name = lookup_name(group)?;
+    let mut entry: libc::group = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0; LOOKUP_BUFFER_SIZE];
+    let mut found = std::ptr::null_mut();
+    let code = unsafe {
+        libc::getgrnam_r(
+            name.as_ptr(),
+            &mut entry,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut found,
+        )
+    };
+    if code != 0 {
+        return Err(std::io::Error::from_raw_os_error(code));
+    }
+    if found.is_null() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("there is no group named {}", group),
+        ));
+    }
+    Ok(entry.gr_gid)
+}
+
+#[cfg(unix)]
+fn lookup_name(name: &str) -> std::io::Result<std::ffi::CString> {
+    std::ffi::CString::new(name).map_err(|_| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("{:?} can't name a user or group", name),
+        )
+    })
+}
+
+#[cfg(not(unix))]
+pub fn resolve_user(_user: &str) -> std::io::Result<u32> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "files have no Unix owner on this platform",
+    ))
+}
+
+#[cfg(not(unix))]
+pub fn resolve_group(_group: &str) -> std::io::Result<u32> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "files have no Unix group on this platform",
+    ))
+}
+
+/// Whether the directory holds a cache tag with the standard signature.
+fn is_cache_directory(path: &Path) -> bool {
+    let mut signature = [0; CACHE_TAG_SIGNATURE.len()];
+    File::open(path.join(CACHE_TAG_NAME))
+        .and_then(|mut tag| tag.read_exact(&mut signature))
+        .is_ok_and(|_| signature == CACHE_TAG_SIGNATURE)
+}
+
+/// Runs every filter over the path. Paths which can't be inspected are included, so visiting them
+/// records the failure, while a panicking filter fails the path it was deciding on.
+pub fn evaluate(
+    filters: &[Box<dyn EntryFilter>],
+    path: &Path,
+    kind: EntryKind,
+) -> std::io::Result<FilterDecision> {
+    // a symlink the walk keeps as one is judged by the link itself, not whatever it points at
+    let metadata = if kind == EntryKind::Symlink {
+        path.symlink_metadata()
+    } else {
+        path.metadata().or_else(|_| path.symlink_metadata())
+    };
+    let metadata = match metadata {
+        Ok(metadata) => metadata,
+        Err(_) => return Ok(FilterDecision::Include),
+    };
+    let mut decision = FilterDecision::Include;
+    for filter in filters {
+        // the decision is made before the visitor touches its state for the path
+        let filter_decision =
+            std::panic::catch_unwind(AssertUnwindSafe(|| filter.decide(path, &metadata, kind)))
+                .map_err(|_| std::io::Error::other("entry filter panicked"))?;
+        decision = decision.max(filter_decision);
+    }
+    Ok(decision)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Lays out a target holding a tagged cache, a directory with a tag lacking the signature, and
+    /// an ordinary file.
+    fn fixture() -> tempfile::TempDir {
+        let target = tempfile::tempdir().unwrap();
+        let cache = target.path().join("cache");
+        std::fs::create_dir_all(cache.join("nested")).unwrap();
+        std::fs::write(
+            cache.join(CACHE_TAG_NAME),
+            [CACHE_TAG_SIGNATURE, b"\n# a cache\n"].concat(),
+        )
+        .unwrap();
+        std::fs::write(cache.join("blob"), "cached").unwrap();
+        std::fs::write(cache.join("nested/blob"), "cached").unwrap();
+        let unsigned = target.path().join("unsigned");
+        std::fs::create_dir(&unsigned).unwrap();
+        std::fs::write(unsigned.join(CACHE_TAG_NAME), "not a cache").unwrap();
+        std::fs::write(unsigned.join("kept"), "kept").unwrap();
+        std::fs::write(target.path().join("file"), "kept").unwrap();
+        target
+    }
+
+    /// Walks the target the way the visitor does, collecting the paths the filters let through
+    /// relative to it.
+    fn included(target: &Path, filters: &[Box<dyn EntryFilter>]) -> BTreeSet<String> {
+        fn walk(
+            directory: &Path,
+            target: &Path,
+            filters: &[Box<dyn EntryFilter>],
+            found: &mut BTreeSet<String>,
+        ) {
+            for entry in directory.read_dir().unwrap() {
+                let path = entry.unwrap().path();
+                let kind = if path.is_dir() {
+                    EntryKind::Directory
+                } else {
+                    EntryKind::File
+                };
+                let decision = evaluate(filters, &path, kind).unwrap();
+                if decision == FilterDecision::PruneSubtree {
+                    continue;
+                }
+                if decision == FilterDecision::Include {
+                    let relative = path.strip_prefix(target).unwrap();
+                    found.insert(relative.to_string_lossy().into_owned());
+                }
+                if kind == EntryKind::Directory {
+                    walk(&path, target, filters, found);
+                }
+            }
+        }
+        let mut found = BTreeSet::new();
+        walk(target, target, filters, &mut found);
+        found
+    }
+
+    fn excluding(exclusion: CacheExclusion) -> (BTreeSet<String>, BTreeSet<PathBuf>) {
+        let target = fixture();
+        let found = Rc::new(RefCell::new(BTreeSet::new()));
+        let filters: Vec<Box<dyn EntryFilter>> = vec![Box::new(CacheDirectories {
+            exclusion,
+            found: Rc::clone(&found),
+        })];
+        let included = included(target.path(), &filters);
+        let found = found
+            .borrow()
+            .iter()
+            .map(|path| path.strip_prefix(target.path()).unwrap().to_path_buf())
+            .collect();
+        (included, found)
+    }
+
+    fn paths(paths: &[&str]) -> BTreeSet<String> {
+        paths.iter().map(|path| path.to_string()).collect()
+    }
+
+    #[test]
+    fn cache_tags_need_the_signature() {
+        let target = fixture();
+        assert!(is_cache_directory(&target.path().join("cache")));
+        assert!(!is_cache_directory(&target.path().join("unsigned")));
+        assert!(!is_cache_directory(target.path()));
+    }
+
+    #[test]
+    fn excluding_caches_keeps_the_directory_and_its_tag() {
+        let (included, found) = excluding(CacheExclusion::Contents);
+        let expected = paths(&[
+            "cache",
+            "cache/CACHEDIR.TAG",
+            "file",
+            "unsigned",
+            "unsigned/CACHEDIR.TAG",
+            "unsigned/kept",
+        ]);
+        assert_eq!(included, expected);
+        assert_eq!(found, BTreeSet::from([PathBuf::from("cache")]));
+    }
+
+    #[test]
+    fn excluding_caches_under_keeps_only_the_directory() {
+        let (included, found) = excluding(CacheExclusion::Under);
+        let expected = paths(&[
+            "cache",
+            "file",
+            "unsigned",
+            "unsigned/CACHEDIR.TAG",
+            "unsigned/kept",
+        ]);
+        assert_eq!(included, expected);
+        assert_eq!(found, BTreeSet::from([PathBuf::from("cache")]));
+    }
+
+    #[test]
+    fn excluding_caches_all_leaves_out_the_directory() {
+        let (included, found) = excluding(CacheExclusion::All);
+        let expected = paths(&["file", "unsigned", "unsigned/CACHEDIR.TAG", "unsigned/kept"]);
+        assert_eq!(included, expected);
+        assert_eq!(found, BTreeSet::from([PathBuf::from("cache")]));
+    }
+}
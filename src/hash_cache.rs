@@ -0,0 +1,171 @@
+use std::collections::HashMap;
+use std::fs::{File, Metadata};
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::archiver::{create_file, find_relative_path};
+use crate::checkpoint;
+use crate::hashing::HashAlgorithm;
+use crate::manifest::{Manifest, ManifestEntryKind};
+use crate::processor::Digest;
+
+/// Version of the cache format written by this build. Caches of any other version are ignored.
+pub const HASH_CACHE_VERSION: u32 = 1;
+
+/// Digests of the files captured by earlier snapshots, along with the size and mtime each file had
+/// when it was hashed, so a file which still looks the same needn't be read and hashed again.
+pub struct HashCache {
+    origin: PathBuf,
+    hash_algorithm: HashAlgorithm,
+    /// Digests which may be reused, keyed by the path of their file in the manifest.
+    cached: HashMap<PathBuf, CachedDigest>,
+    /// Digests of the files captured by this snapshot, which are written out for the next one.
+    recorded: HashMap<PathBuf, CachedDigest>,
+    /// Number of files whose cached digest was reused.
+    pub reused: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+struct HashCacheFile {
+    version: u32,
+    hash_algorithm: HashAlgorithm,
+    entries: Vec<CachedDigest>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct CachedDigest {
+    path: PathBuf,
+    size: u64,
+    mtime_secs: u64,
+    mtime_nanos: u32,
+    digest: String,
+}
+
+impl HashCache {
+    /// Reads the cache written by an earlier snapshot of the origin. A missing cache is an empty
+    /// one, as is a cache hashed with another algorithm or written in another format.
+    pub fn read<P: AsRef<Path>, O: AsRef<Path>>(
+        path: P,
+        origin: O,
+        hash_algorithm: HashAlgorithm,
+    ) -> std::io::Result<Self> {
+        let path = path.as_ref();
+        let mut cache = Self {
+            origin: origin.as_ref().to_path_buf(),
+            hash_algorithm,
+            cached: HashMap::new(),
+            recorded: HashMap::new(),
+            reused: 0,
+        };
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                log::debug!("No hash cache at {} yet", path.display());
+                return Ok(cache);
+            }
+            Err(err) => return Err(err),
+        };
+        let read: HashCacheFile = serde_json::from_reader(BufReader::new(file))?;
+        if read.version != HASH_CACHE_VERSION {
+            log::warn!(
+                "Ignoring hash cache {}, written in version {} of its format",
+                path.display(),
+                read.version
+            );
+        } else if read.hash_algorithm != hash_algorithm {
+            log::warn!(
+                "Ignoring hash cache {}, hashed with {} rather than {}",
+                path.display(),
+                read.hash_algorithm,
+                hash_algorithm
+            );
+        } else {
+            cache.cached = read
+                .entries
+                .into_iter()
+                .map(|cached| (cached.path.clone(), cached))
+                .collect();
+        }
+        Ok(cache)
+    }
+
+    /// Drops the digests of files which don't look in the earlier snapshot the way they did when
+    /// they were hashed. A file whose cached digest is reused is never read, so it's only left out
+    /// of the archive, as unchanged since that snapshot, if the cache agrees with its manifest.
+    pub fn retain_unchanged(&mut self, previous: &Manifest) {
+        if previous.hash_algorithm != self.hash_algorithm {
+            self.cached.clear();
+            return;
+        }
+        let previous: HashMap<&Path, _> = previous
+            .entries
+            .iter()
+            .filter(|entry| entry.kind == ManifestEntryKind::File)
+            .map(|entry| (entry.path.as_path(), entry))
+            .collect();
+        self.cached.retain(|path, cached| {
+            previous.get(path.as_path()).is_some_and(|entry| {
+                entry.size == cached.size
+                    && entry.mtime == cached.mtime_secs
+                    && entry.digest.as_ref() == Some(&cached.digest)
+            })
+        });
+    }
+
+    /// Finds the digest the file was hashed to, as long as it still has the size and mtime it had
+    /// then.
+    pub fn lookup(&self, path: &Path, metadata: &Metadata) -> Option<Digest> {
+        let cached = self
+            .cached
+            .get(&find_relative_path(&self.origin, path).ok()?)?;
+        let (mtime_secs, mtime_nanos) = checkpoint::mtime(metadata)?;
+        if cached.size != metadata.len()
+            || cached.mtime_secs != mtime_secs
+            || cached.mtime_nanos != mtime_nanos
+        {
+            return None;
+        }
+        let mut digest = Digest::default();
+        hex::decode_to_slice(&cached.digest, &mut digest).ok()?;
+        Some(digest)
+    }
+
+    /// Records the digest of a captured file, replacing whatever an earlier capture recorded.
+    pub fn record(&mut self, path: &Path, metadata: &Metadata, digest: &Digest) {
+        let Some((mtime_secs, mtime_nanos)) = checkpoint::mtime(metadata) else {
+            return;
+        };
+        let Ok(path) = find_relative_path(&self.origin, path) else {
+            return;
+        };
+        self.recorded.insert(
+            path.clone(),
+            CachedDigest {
+                path,
+                size: metadata.len(),
+                mtime_secs,
+                mtime_nanos,
+                digest: hex::encode(digest),
+            },
+        );
+    }
+
+    /// Writes out the digests recorded by this snapshot, leaving out files it didn't capture.
+    pub fn write<P: AsRef<Path>>(&self, path: P, mode: Option<u32>) -> std::io::Result<()> {
+        let mut entries: Vec<CachedDigest> = self.recorded.values().cloned().collect();
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        let cache = HashCacheFile {
+            version: HASH_CACHE_VERSION,
+            hash_algorithm: self.hash_algorithm,
+            entries,
+        };
+        let mut writer = BufWriter::new(create_file(path, mode)?);
+        serde_json::to_writer(&mut writer, &cache)?;
+        writer
+            .into_inner()
+            .map_err(|err| err.into_error())?
+            .sync_all()
+    }
+}
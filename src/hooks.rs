@@ -0,0 +1,182 @@
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::report::SnapshotReport;
+
+/// How often a running hook is checked on.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Post-hooks armed once the snapshot starts, waiting for it to end.
+static POST_HOOKS: Mutex<Option<PostHooks>> = Mutex::new(None);
+
+/// A command run around the snapshot, either through the shell or, when given as a JSON array,
+/// as the program and arguments themselves.
+#[derive(Debug, Clone)]
+pub enum Hook {
+    Shell(String),
+    Argv(Vec<String>),
+}
+
+impl std::fmt::Display for Hook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Hook::Shell(command) => write!(f, "{}", command),
+            Hook::Argv(argv) => write!(f, "{}", argv.join(" ")),
+        }
+    }
+}
+
+/// Parses a hook, taking a JSON array of strings as the program and its arguments and anything
+/// else as a shell command.
+pub fn parse_hook(value: &str) -> Result<Hook, String> {
+    if !value.trim_start().starts_with('[') {
+        return Ok(Hook::Shell(value.to_string()));
+    }
+    match serde_json::from_str::<Vec<String>>(value) {
+        Ok(argv) if !argv.is_empty() => Ok(Hook::Argv(argv)),
+        Ok(_) => Err("hook must name a program".to_string()),
+        Err(err) => Err(format!("invalid argument array: {}", err)),
+    }
+}
+
+struct PostHooks {
+    hooks: Vec<Hook>,
+    timeout: Duration,
+    output: PathBuf,
+}
+
+/// Runs every pre-hook in order, stopping at the first which fails or outlives the timeout.
+pub fn run_pre_hooks(hooks: &[Hook], timeout: Duration, output: &Path) -> std::io::Result<()> {
+    let env = [("SIT_OUTPUT", output.display().to_string())];
+    for hook in hooks {
+        let status = run(hook, "pre-hook", timeout, &env)?;
+        if !status.success() {
+            return Err(std::io::Error::other(format!(
+                "Pre-hook `{}` failed with {}",
+                hook, status
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Arms the post-hooks, which `run_post_hooks` then runs however the snapshot ends.
+pub fn arm_post_hooks(hooks: Vec<Hook>, timeout: Duration, output: PathBuf) {
+    *POST_HOOKS.lock().unwrap() = Some(PostHooks {
+        hooks,
+        timeout,
+        output,
+    });
+}
+
+/// Runs the armed post-hooks, if they haven't run yet, with the outcome of the snapshot exposed
+/// through `SIT_STATUS` (the exit code), `SIT_OUTPUT`, `SIT_FILES` and `SIT_BYTES`. Every hook
+/// runs even if an earlier one fails.
+pub fn run_post_hooks(status: i32, report: &SnapshotReport) {
+    let armed = match POST_HOOKS.lock() {
+        Ok(mut armed) => armed.take(),
+        Err(_) => return,
+    };
+    let Some(post_hooks) = armed else {
+        return;
+    };
+    let env = [
+        ("SIT_STATUS", status.to_string()),
+        ("SIT_OUTPUT", post_hooks.output.display().to_string()),
+        ("SIT_FILES", report.files.to_string()),
+        ("SIT_BYTES", report.bytes_written.to_string()),
+    ];
+    for hook in &post_hooks.hooks {
+        match run(hook, "post-hook", post_hooks.timeout, &env) {
+            Ok(status) if status.success() => {}
+            Ok(status) => log::error!("Post-hook `{}` failed with {}", hook, status),
+            Err(err) => log::error!("Failed to run post-hook `{}`: {}", hook, err),
+        }
+    }
+}
+
+/// Runs a hook to completion, logging what it prints, and kills it should it outlive the timeout.
+fn run(
+    hook: &Hook,
+    label: &str,
+    timeout: Duration,
+    env: &[(&str, String)],
+) -> std::io::Result<ExitStatus> {
+    log::info!("Running {} `{}`", label, hook);
+    let mut command = match hook {
+        Hook::Shell(shell_command) if cfg!(windows) => {
+            let mut command = Command::new("cmd");
+            command.arg("/C").arg(shell_command);
+            command
+        }
+        Hook::Shell(shell_command) => {
+            let mut command = Command::new("sh");
+            command.arg("-c").arg(shell_command);
+            command
+        }
+        Hook::Argv(argv) => {
+            let mut command = Command::new(&argv[0]);
+            command.args(&argv[1..]);
+            command
+        }
+    };
+    let mut child = command
+        .envs(env.iter().map(|(key, value)| (key, value)))
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let prefix = format!("[{}] ", label);
+    let forwarders = [
+        child
+            .stdout
+            .take()
+            .map(|stdout| forward(stdout, prefix.clone(), log::Level::Info)),
+        child
+            .stderr
+            .take()
+            .map(|stderr| forward(stderr, prefix, log::Level::Warn)),
+    ];
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            // something the hook left running in the background may still hold its output open
+            while forwarders
+                .iter()
+                .flatten()
+                .any(|forwarder| !forwarder.is_finished())
+                && Instant::now() < deadline
+            {
+                std::thread::sleep(POLL_INTERVAL);
+            }
+            return Ok(status);
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!("Hook `{}` timed out after {} ms", hook, timeout.as_millis()),
+            ));
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Logs every line a hook prints, tagged with the hook it came from.
+fn forward<R: Read + Send + 'static>(
+    reader: R,
+    prefix: String,
+    level: log::Level,
+) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        for line in BufReader::new(reader).lines().map_while(Result::ok) {
+            log::log!(level, "{}{}", prefix, line);
+        }
+    })
+}
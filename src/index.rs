@@ -0,0 +1,215 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Take};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use tar::Archive;
+
+use crate::archiver::create_file;
+use crate::batch;
+use crate::dictionary::Dictionary;
+use crate::manifest::{self, Manifest};
+use crate::provenance::{self, Provenance};
+use crate::restorer::is_whiteout;
+
+/// Version of the index format written by this build. Indexes of any other version are ignored.
+pub const INDEX_VERSION: u32 = 1;
+
+/// Where every member of an archive lies in it, so readers after a few paths can seek straight to
+/// them instead of reading through every header before them.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Index {
+    pub version: u32,
+    /// Size of the archive the index was built from, which an archive changed since no longer has.
+    pub archive_size: u64,
+    /// Every member of the archive, in the order it holds them.
+    pub entries: Vec<IndexEntry>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct IndexEntry {
+    /// The member's path in the archive, the whiteout's own for a removed path.
+    pub path: PathBuf,
+    pub kind: IndexedKind,
+    /// Offset of the member's first header, so reading from there reads just the member.
+    pub offset: u64,
+    /// Bytes the member's headers and content take up in the archive.
+    pub length: u64,
+    /// Where a symlink points.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link: Option<PathBuf>,
+    /// Names of the files in a batch.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub members: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum IndexedKind {
+    File,
+    Directory,
+    Symlink,
+    Batch,
+    Whiteout,
+    Manifest,
+    Record,
+}
+
+/// The path of the sidecar holding an archive's index.
+pub fn sidecar_path<P: AsRef<Path>>(archive_path: P) -> PathBuf {
+    let mut sidecar = archive_path.as_ref().as_os_str().to_owned();
+    sidecar.push(".idx");
+    PathBuf::from(sidecar)
+}
+
+impl Index {
+    /// Indexes a finished archive by reading back its headers, seeking past everything else but
+    /// batches, which are decompressed for the names of their files.
+    pub fn build<P: AsRef<Path>>(
+        tarball_path: P,
+        dictionary: Option<&Dictionary>,
+    ) -> std::io::Result<Self> {
+        let tarball_path = tarball_path.as_ref();
+        let archive_size = std::fs::metadata(tarball_path)?.len();
+        let mut archive = Archive::new(BufReader::new(File::open(tarball_path)?));
+        let mut entries = Vec::new();
+        // a member's headers start right where the one before it ends
+        let mut offset = 0;
+
+        for entry in archive.entries_with_seek()? {
+            let entry = entry?;
+            let path = entry.path()?.into_owned();
+            let end = entry.raw_file_position() + entry.size().next_multiple_of(512);
+            let mut link = None;
+            let mut members = Vec::new();
+            let kind = match entry.header().entry_type() {
+                tar::EntryType::Directory => IndexedKind::Directory,
+                tar::EntryType::Symlink => {
+                    link = entry.link_name()?.map(|link| link.into_owned());
+                    IndexedKind::Symlink
+                }
+                _ if provenance::is_provenance(&path) => IndexedKind::Record,
+                _ if manifest::is_embedded_manifest(&path) => IndexedKind::Manifest,
+                _ if is_whiteout(&path) => IndexedKind::Whiteout,
+                _ if path.file_name() == Some(batch::BATCH_FILE_NAME.as_ref()) => {
+                    let (batched, _) = batch::read_batch(entry, dictionary)?;
+                    members = batched.into_iter().map(|member| member.name).collect();
+                    IndexedKind::Batch
+                }
+                _ => IndexedKind::File,
+            };
+            entries.push(IndexEntry {
+                path,
+                kind,
+                offset,
+                length: end - offset,
+                link,
+                members,
+            });
+            offset = end;
+        }
+        Ok(Self {
+            version: INDEX_VERSION,
+            archive_size,
+            entries,
+        })
+    }
+
+    pub fn write<P: AsRef<Path>>(&self, path: P, mode: Option<u32>) -> std::io::Result<()> {
+        let mut writer = BufWriter::new(create_file(path, mode)?);
+        serde_json::to_writer(&mut writer, self)?;
+        writer
+            .into_inner()
+            .map_err(|err| err.into_error())?
+            .sync_all()
+    }
+
+    /// Reads the index beside an archive, as long as it was built from the archive as it is now.
+    /// A missing, unreadable or outdated index is nothing to go by, so readers fall back to
+    /// reading through the archive.
+    pub fn read_for<P: AsRef<Path>>(tarball_path: P) -> Option<Self> {
+        let tarball_path = tarball_path.as_ref();
+        let sidecar = sidecar_path(tarball_path);
+        let file = match File::open(&sidecar) {
+            Ok(file) => file,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return None,
+            Err(err) => {
+                log::warn!("Failed to read index {}: {}", sidecar.display(), err);
+                return None;
+            }
+        };
+        let index: Self = match serde_json::from_reader(BufReader::new(file)) {
+            Ok(index) => index,
+            Err(err) => {
+                log::warn!("Ignoring index {}: {}", sidecar.display(), err);
+                return None;
+            }
+        };
+        let archive_size = std::fs::metadata(tarball_path).ok()?.len();
+        if index.version != INDEX_VERSION || index.archive_size != archive_size {
+            log::warn!(
+                "Ignoring index {}, as it wasn't built from the archive as it is now",
+                sidecar.display()
+            );
+            return None;
+        }
+        log::debug!("Reading archive through index {}", sidecar.display());
+        Some(index)
+    }
+
+    /// The snapshot records and manifests the archive holds, read straight from where they are.
+    /// There's one manifest for every snapshot, in order, or none for a snapshot which didn't
+    /// record its own, the same as `manifest::read_embedded`.
+    pub fn read_records<P: AsRef<Path>>(
+        &self,
+        tarball_path: P,
+    ) -> std::io::Result<Vec<(Provenance, Option<Manifest>)>> {
+        let tarball_path = tarball_path.as_ref();
+        let mut records = Vec::new();
+        let mut manifest = None;
+        for indexed in &self.entries {
+            match indexed.kind {
+                IndexedKind::Manifest => {
+                    let mut archive = open_member(tarball_path, indexed)?;
+                    manifest = Some(Manifest::read_from(member(&mut archive, indexed)?)?);
+                }
+                IndexedKind::Record => {
+                    let mut archive = open_member(tarball_path, indexed)?;
+                    let provenance = Provenance::read(member(&mut archive, indexed)?)?;
+                    records.push((provenance, manifest.take()));
+                }
+                _ => {}
+            }
+        }
+        Ok(records)
+    }
+}
+
+/// Opens the archive at an indexed member, reading no further than its end.
+pub fn open_member<P: AsRef<Path>>(
+    tarball_path: P,
+    indexed: &IndexEntry,
+) -> std::io::Result<Archive<Take<File>>> {
+    let mut file = File::open(tarball_path)?;
+    file.seek(SeekFrom::Start(indexed.offset))?;
+    Ok(Archive::new(file.take(indexed.length)))
+}
+
+/// Reads the one member an archive opened by `open_member` holds, refusing anything but the
+/// member indexed there.
+pub fn member<'a, R: Read>(
+    archive: &'a mut Archive<R>,
+    indexed: &IndexEntry,
+) -> std::io::Result<tar::Entry<'a, R>> {
+    let entry = archive.entries()?.next().transpose()?;
+    match entry {
+        Some(entry) if entry.path()? == indexed.path => Ok(entry),
+        _ => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "Index doesn't match the archive at {}; write it again with --index",
+                indexed.path.display()
+            ),
+        )),
+    }
+}
@@ -0,0 +1,326 @@
+use std::error::Error;
+use std::fs::{File, Metadata};
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::checkpoint::{self, Checkpoint};
+use crate::hashing::HashAlgorithm;
+use crate::manifest::ManifestEntryKind;
+use crate::processor::{Digest, Entry, EntryType, Processed};
+
+const JOURNAL_FILE_NAME: &str = "journal.json";
+const PROGRESS_FILE_NAME: &str = "progress.json";
+
+/// Lists every entry a finished capture compiled, in the order they're archived, so a snapshot
+/// which dies while writing its archive can be resumed from the captures kept in its checkpoint
+/// without capturing again.
+#[derive(Serialize, Deserialize)]
+pub struct Journal {
+    target: PathBuf,
+    /// The revision the captured entries were confirmed stable at, in RFC 3339.
+    pub revision: Option<String>,
+    pub metadata_only: bool,
+    entries: Vec<JournalEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct JournalEntry {
+    path: PathBuf,
+    kind: ManifestEntryKind,
+    size: u64,
+    mtime_secs: u64,
+    mtime_nanos: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    digest: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    children: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    level: Option<i32>,
+}
+
+/// How far writing the archive got: the number of entries written and synced into the partial
+/// archive, and the offset right after the last of them.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Progress {
+    /// The archive being written, under its temporary name.
+    pub partial_path: PathBuf,
+    pub entries: usize,
+    pub offset: u64,
+}
+
+#[derive(Debug)]
+pub enum ResumeError {
+    Io(std::io::Error),
+    /// The journal was written for a snapshot of another target.
+    OtherTarget(PathBuf),
+    /// These paths changed since they were captured, so the captures no longer match them.
+    Changed(Vec<PathBuf>),
+}
+
+impl Error for ResumeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ResumeError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for ResumeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ResumeError::Io(err) => write!(f, "{}", err),
+            ResumeError::OtherTarget(target) => {
+                write!(
+                    f,
+                    "Journal was written for a snapshot of {}",
+                    target.display()
+                )
+            }
+            ResumeError::Changed(paths) => {
+                write!(f, "{} paths changed since they were captured", paths.len())
+            }
+        }
+    }
+}
+
+impl From<std::io::Error> for ResumeError {
+    fn from(value: std::io::Error) -> Self {
+        ResumeError::Io(value)
+    }
+}
+
+impl Journal {
+    pub fn new(target: &Path, entries: &[Entry], revision: Option<String>) -> Self {
+        let mut metadata_only = false;
+        let entries = entries
+            .iter()
+            .map(|entry| {
+                let (kind, digest, children) = match &entry.entry_type {
+                    EntryType::File(_, digest) => {
+                        metadata_only |= digest.is_none();
+                        (ManifestEntryKind::File, digest.map(hex::encode), None)
+                    }
+                    EntryType::Drained(_, digest, _) => {
+                        (ManifestEntryKind::File, Some(hex::encode(digest)), None)
+                    }
+                    EntryType::Directory(children) => {
+                        (ManifestEntryKind::Directory, None, Some(*children))
+                    }
+                    EntryType::Symlink(digest) => {
+                        (ManifestEntryKind::Symlink, digest.map(hex::encode), None)
+                    }
+                };
+                let (mtime_secs, mtime_nanos) =
+                    checkpoint::mtime(&entry.metadata).unwrap_or_default();
+                JournalEntry {
+                    path: entry.path.clone(),
+                    kind,
+                    size: entry.metadata.len(),
+                    mtime_secs,
+                    mtime_nanos,
+                    digest,
+                    children,
+                    level: entry.level,
+                }
+            })
+            .collect();
+        Self {
+            target: target.to_path_buf(),
+            revision,
+            metadata_only,
+            entries,
+        }
+    }
+
+    /// Writes the journal into the checkpoint directory, replacing the previous one only once
+    /// it's complete, along with the progress of writing an archive from it.
+    pub fn write<P: AsRef<Path>>(&self, checkpoint_directory: P) -> std::io::Result<()> {
+        let checkpoint_directory = checkpoint_directory.as_ref();
+        match std::fs::remove_file(checkpoint_directory.join(PROGRESS_FILE_NAME)) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+        write_json(&checkpoint_directory.join(JOURNAL_FILE_NAME), self)
+    }
+
+    /// Reads the journal a snapshot left in the checkpoint directory, if it got as far as
+    /// writing one.
+    pub fn read<P: AsRef<Path>>(checkpoint_directory: P) -> std::io::Result<Option<Self>> {
+        read_json(&checkpoint_directory.as_ref().join(JOURNAL_FILE_NAME))
+    }
+
+    /// Checks every path in the journal is still as it was captured, hashing the content of files
+    /// again, and reopens their captures from the checkpoint. Refused with the changed paths if
+    /// any path changed.
+    pub fn resume(
+        self,
+        target: &Path,
+        checkpoint: &Checkpoint,
+        hash_algorithm: HashAlgorithm,
+    ) -> Result<Processed, ResumeError> {
+        if self.target != target {
+            return Err(ResumeError::OtherTarget(self.target));
+        }
+        let mut entries = Vec::with_capacity(self.entries.len());
+        let mut changed = Vec::new();
+        let mut encoded_bytes = 0;
+        for recorded in self.entries {
+            let metadata = match recorded.kind {
+                ManifestEntryKind::Symlink => std::fs::symlink_metadata(&recorded.path),
+                _ => std::fs::metadata(&recorded.path),
+            };
+            let resumed = match metadata {
+                Ok(metadata) if recorded.matches(&metadata) => recorded
+                    .reopen(&metadata, checkpoint, hash_algorithm)?
+                    .map(|entry_type| (metadata, entry_type)),
+                _ => None,
+            };
+            match resumed {
+                Some((metadata, entry_type)) => {
+                    if let EntryType::File(data, _) = &entry_type {
+                        encoded_bytes += data.metadata()?.len();
+                    }
+                    entries.push(Entry {
+                        path: recorded.path,
+                        metadata,
+                        entry_type,
+                        level: recorded.level,
+                    });
+                }
+                None => {
+                    log::debug!("{} changed since it was captured", recorded.path.display());
+                    changed.push(recorded.path);
+                }
+            }
+        }
+        if !changed.is_empty() {
+            return Err(ResumeError::Changed(changed));
+        }
+        let file_count = entries
+            .iter()
+            .filter(|entry| matches!(entry.entry_type, EntryType::File(..)))
+            .count();
+        Ok(Processed {
+            entry_count: entries.len(),
+            file_count,
+            encoded_bytes,
+            entries: Box::new(entries.into_iter()),
+            failed: Vec::new(),
+        })
+    }
+}
+
+impl JournalEntry {
+    /// Whether the path still looks the way it did when it was captured.
+    fn matches(&self, metadata: &Metadata) -> bool {
+        let kind = if metadata.is_symlink() {
+            ManifestEntryKind::Symlink
+        } else if metadata.is_dir() {
+            ManifestEntryKind::Directory
+        } else {
+            ManifestEntryKind::File
+        };
+        kind == self.kind
+            && (kind != ManifestEntryKind::File || metadata.len() == self.size)
+            && checkpoint::mtime(metadata) == Some((self.mtime_secs, self.mtime_nanos))
+    }
+
+    /// Reopens the capture of a file, as long as its content still hashes to the recorded
+    /// digest. Anything else has nothing to reopen.
+    fn reopen(
+        &self,
+        metadata: &Metadata,
+        checkpoint: &Checkpoint,
+        hash_algorithm: HashAlgorithm,
+    ) -> std::io::Result<Option<EntryType>> {
+        match (self.kind, &self.digest) {
+            (ManifestEntryKind::Directory, _) => Ok(Some(EntryType::Directory(
+                self.children.unwrap_or_default(),
+            ))),
+            (ManifestEntryKind::Symlink, digest) => {
+                let digest = match digest {
+                    Some(digest) => {
+                        let mut decoded = Digest::default();
+                        hex::decode_to_slice(digest, &mut decoded).map_err(|err| {
+                            std::io::Error::new(std::io::ErrorKind::InvalidData, err)
+                        })?;
+                        Some(decoded)
+                    }
+                    None => None,
+                };
+                Ok(Some(EntryType::Symlink(digest)))
+            }
+            (ManifestEntryKind::File, None) => {
+                Ok(Some(EntryType::File(tempfile::tempfile()?, None)))
+            }
+            (ManifestEntryKind::File, Some(digest)) => {
+                let Some((data, captured, _)) = checkpoint.lookup(&self.path, metadata) else {
+                    return Ok(None);
+                };
+                let current: Digest = match File::open(&self.path) {
+                    Ok(file) => hash_algorithm.digest_reader(BufReader::new(file))?,
+                    Err(_) => return Ok(None),
+                };
+                Ok((hex::encode(captured) == *digest && current == captured)
+                    .then_some(EntryType::File(data, Some(captured))))
+            }
+        }
+    }
+}
+
+impl Progress {
+    /// Records how far writing the archive got, replacing the previous record only once it's
+    /// complete.
+    pub fn write<P: AsRef<Path>>(&self, checkpoint_directory: P) -> std::io::Result<()> {
+        write_json(
+            &checkpoint_directory.as_ref().join(PROGRESS_FILE_NAME),
+            self,
+        )
+    }
+
+    /// Reads how far writing the archive got before the snapshot died, if it recorded any
+    /// progress.
+    pub fn read<P: AsRef<Path>>(checkpoint_directory: P) -> std::io::Result<Option<Self>> {
+        read_json(&checkpoint_directory.as_ref().join(PROGRESS_FILE_NAME))
+    }
+
+    /// Removes the partial archive a snapshot left beside its output, if it recorded one in the
+    /// checkpoint, before the checkpoint starts over without it.
+    pub fn discard<P: AsRef<Path>>(checkpoint_directory: P) {
+        if let Ok(Some(progress)) = Self::read(checkpoint_directory) {
+            match std::fs::remove_file(&progress.partial_path) {
+                Ok(_) => log::info!(
+                    "Removed partial archive {}",
+                    progress.partial_path.display()
+                ),
+                Err(err) => log::debug!(
+                    "Failed to remove {}: {}",
+                    progress.partial_path.display(),
+                    err
+                ),
+            }
+        }
+    }
+}
+
+fn write_json<T: Serialize>(path: &Path, value: &T) -> std::io::Result<()> {
+    let partial_path = path.with_extension("json.partial");
+    let mut writer = BufWriter::new(File::create(&partial_path)?);
+    serde_json::to_writer(&mut writer, value)?;
+    writer
+        .into_inner()
+        .map_err(|err| err.into_error())?
+        .sync_all()?;
+    std::fs::rename(partial_path, path)
+}
+
+fn read_json<T: for<'de> Deserialize<'de>>(path: &Path) -> std::io::Result<Option<T>> {
+    match File::open(path) {
+        Ok(file) => Ok(Some(serde_json::from_reader(BufReader::new(file))?)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
@@ -0,0 +1,449 @@
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::{BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+use tar::Archive;
+
+use crate::archiver::WHITEOUT_PREFIX;
+use crate::batch;
+use crate::dictionary::{self, Dictionary};
+use crate::index::{self, Index, IndexedKind};
+use crate::manifest;
+use crate::provenance::{self, Provenance};
+use crate::restorer::{is_whiteout, whited_out_path};
+
+/// Most paths suggested in place of one the archive doesn't hold.
+const NEAR_MISSES: usize = 5;
+
+/// Edits a path may be off by and still be suggested, however short it is.
+const NEAR_MISS_DISTANCE: usize = 3;
+
+/// The members one snapshot wrote to an archive, along with its record.
+pub struct ListedSnapshot {
+    /// Missing for members written after the last record, by a snapshot which never finished.
+    pub provenance: Option<Provenance>,
+    pub members: Vec<Member>,
+}
+
+pub struct Member {
+    pub path: PathBuf,
+    pub kind: MemberKind,
+    /// Read only when asked for, and never for a removed path.
+    pub details: Option<MemberDetails>,
+}
+
+/// What the archive records of a member besides its path.
+pub struct MemberDetails {
+    pub mode: u32,
+    pub uid: u64,
+    pub gid: u64,
+    pub mtime: u64,
+    /// Bytes a file holds once decompressed, and nothing for anything else.
+    pub size: u64,
+}
+
+/// How `list` prints the members of an archive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum ListFormat {
+    /// The kind and path of every member, under a heading for every snapshot.
+    #[default]
+    Short,
+    /// Like `short`, along with every member's permissions, owner, size and mtime.
+    Long,
+    /// A header row, then a row for every member giving its snapshot, kind, path, link,
+    /// permissions, owner, size and mtime.
+    Csv,
+    /// A line of JSON for every member, with the same fields as `csv`.
+    Json,
+}
+
+pub enum MemberKind {
+    File,
+    Directory,
+    Symlink(PathBuf),
+    /// A whiteout, removing the path when restored over an earlier snapshot.
+    Removed,
+}
+
+/// Lists the members of a tarball produced by `create_tarball`, grouped by the snapshot which
+/// wrote them, oldest first. Batched files are listed one by one, which takes decompressing their
+/// batches, so files compressed with a dictionary need that dictionary given, unless the archive
+/// has an index listing them. With `detailed`, every member's details are read as well, which
+/// takes decompressing every file to tell its size, index or not.
+pub fn list_tarball<P: AsRef<Path>>(
+    tarball_path: P,
+    dictionary: Option<&Dictionary>,
+    detailed: bool,
+) -> std::io::Result<Vec<ListedSnapshot>> {
+    if let Some(index) = Index::read_for(&tarball_path).filter(|_| !detailed) {
+        return list_index(tarball_path.as_ref(), &index);
+    }
+    let mut archive = Archive::new(BufReader::new(File::open(tarball_path)?));
+    let mut snapshots = Vec::new();
+    let mut members = Vec::new();
+
+    for entry in archive.entries_with_seek()? {
+        let mut entry = entry?;
+        let relative_path = entry.path()?.into_owned();
+        let details = |entry: &mut tar::Entry<_>, size| -> std::io::Result<_> {
+            if !detailed {
+                return Ok(None);
+            }
+            let header = entry.header();
+            Ok(Some(MemberDetails {
+                mode: header.mode()?,
+                uid: header.uid()?,
+                gid: header.gid()?,
+                mtime: header.mtime()?,
+                size,
+            }))
+        };
+        match entry.header().entry_type() {
+            tar::EntryType::Directory => members.push(Member {
+                path: relative_path,
+                kind: MemberKind::Directory,
+                details: details(&mut entry, 0)?,
+            }),
+            tar::EntryType::Symlink => {
+                let link = entry
+                    .link_name()?
+                    .map(|link| link.into_owned())
+                    .unwrap_or_default();
+                members.push(Member {
+                    path: relative_path,
+                    kind: MemberKind::Symlink(link),
+                    details: details(&mut entry, 0)?,
+                });
+            }
+            tar::EntryType::Regular if provenance::is_provenance(&relative_path) => {
+                // the record ends its snapshot
+                snapshots.push(ListedSnapshot {
+                    provenance: Some(Provenance::read(entry)?),
+                    members: std::mem::take(&mut members),
+                });
+            }
+            tar::EntryType::Regular if manifest::is_embedded_manifest(&relative_path) => {}
+            tar::EntryType::Regular if is_whiteout(&relative_path) => {
+                let name = relative_path.file_name().unwrap().to_str().unwrap();
+                members.push(Member {
+                    path: relative_path.with_file_name(&name[WHITEOUT_PREFIX.len()..]),
+                    kind: MemberKind::Removed,
+                    details: None,
+                });
+            }
+            _ if relative_path.file_name() == Some(batch::BATCH_FILE_NAME.as_ref()) => {
+                let (batched, _) = batch::read_batch(entry, dictionary)?;
+                members.extend(batched.into_iter().map(|member| Member {
+                    path: relative_path.with_file_name(member.name),
+                    kind: MemberKind::File,
+                    details: detailed.then(|| MemberDetails {
+                        mode: member.mode,
+                        uid: member.uid,
+                        gid: member.gid,
+                        mtime: member.mtime,
+                        size: member.data.len() as u64,
+                    }),
+                }));
+            }
+            _ => {
+                // a file captured without its content holds no frame to decompress
+                let size = if detailed && entry.header().size()? > 0 {
+                    let mut decoder = dictionary::decoder(&mut entry, dictionary)?;
+                    std::io::copy(&mut decoder, &mut std::io::sink())?
+                } else {
+                    0
+                };
+                members.push(Member {
+                    path: relative_path,
+                    kind: MemberKind::File,
+                    details: details(&mut entry, size)?,
+                })
+            }
+        }
+    }
+
+    if !members.is_empty() {
+        snapshots.push(ListedSnapshot {
+            provenance: None,
+            members,
+        });
+    }
+    Ok(snapshots)
+}
+
+/// Lists the members of a tarball from its index, reading nothing of it but the snapshot records.
+fn list_index(tarball_path: &Path, index: &Index) -> std::io::Result<Vec<ListedSnapshot>> {
+    let mut snapshots = Vec::new();
+    let mut members = Vec::new();
+    for indexed in &index.entries {
+        let kind = match indexed.kind {
+            IndexedKind::File => MemberKind::File,
+            IndexedKind::Directory => MemberKind::Directory,
+            IndexedKind::Symlink => MemberKind::Symlink(indexed.link.clone().unwrap_or_default()),
+            IndexedKind::Whiteout => {
+                members.push(Member {
+                    path: whited_out_path(&indexed.path),
+                    kind: MemberKind::Removed,
+                    details: None,
+                });
+                continue;
+            }
+            IndexedKind::Batch => {
+                members.extend(indexed.members.iter().map(|name| Member {
+                    path: indexed.path.with_file_name(name),
+                    kind: MemberKind::File,
+                    details: None,
+                }));
+                continue;
+            }
+            IndexedKind::Manifest => continue,
+            IndexedKind::Record => {
+                let mut archive = index::open_member(tarball_path, indexed)?;
+                snapshots.push(ListedSnapshot {
+                    provenance: Some(Provenance::read(index::member(&mut archive, indexed)?)?),
+                    members: std::mem::take(&mut members),
+                });
+                continue;
+            }
+        };
+        members.push(Member {
+            path: indexed.path.clone(),
+            kind,
+            details: None,
+        });
+    }
+
+    if !members.is_empty() {
+        snapshots.push(ListedSnapshot {
+            provenance: None,
+            members,
+        });
+    }
+    Ok(snapshots)
+}
+
+/// A member as `csv` and `json` listings describe it.
+#[derive(Serialize)]
+struct ListedMember<'a> {
+    /// Number of the snapshot in the archive, counting from 1.
+    snapshot: usize,
+    id: Option<&'a str>,
+    kind: &'static str,
+    path: &'a Path,
+    link: Option<&'a Path>,
+    /// Permissions in octal.
+    mode: Option<String>,
+    uid: Option<u64>,
+    gid: Option<u64>,
+    size: Option<u64>,
+    /// In RFC 3339.
+    mtime: Option<String>,
+}
+
+/// Fields of a `csv` listing, in order.
+const CSV_HEADER: &str = "snapshot,id,kind,path,link,mode,uid,gid,size,mtime";
+
+/// Prints the listed snapshots in the given format.
+pub fn write_listing(
+    snapshots: &[ListedSnapshot],
+    format: ListFormat,
+    out: &mut impl Write,
+) -> std::io::Result<()> {
+    if format == ListFormat::Csv {
+        writeln!(out, "{}", CSV_HEADER)?;
+    }
+    for (index, snapshot) in snapshots.iter().enumerate() {
+        if matches!(format, ListFormat::Short | ListFormat::Long) {
+            match &snapshot.provenance {
+                Some(provenance) => writeln!(
+                    out,
+                    "Snapshot {}: {} taken {} of {}, {} entries",
+                    index + 1,
+                    provenance.id.as_deref().unwrap_or("(no id)"),
+                    provenance.revision.as_deref().unwrap_or("partially"),
+                    provenance.target.display(),
+                    provenance.entry_count
+                )?,
+                None => writeln!(
+                    out,
+                    "Snapshot {}: unfinished, {} entries",
+                    index + 1,
+                    snapshot.members.len()
+                )?,
+            }
+        }
+        let id = snapshot
+            .provenance
+            .as_ref()
+            .and_then(|provenance| provenance.id.as_deref());
+        for member in &snapshot.members {
+            let kind = match member.kind {
+                MemberKind::File => "file",
+                MemberKind::Directory => "directory",
+                MemberKind::Symlink(_) => "symlink",
+                MemberKind::Removed => "removed",
+            };
+            let link = match &member.kind {
+                MemberKind::Symlink(link) => Some(link.as_path()),
+                _ => None,
+            };
+            let details = member.details.as_ref();
+            match format {
+                ListFormat::Short | ListFormat::Long => {
+                    let details = match details.filter(|_| format == ListFormat::Long) {
+                        Some(details) => format!(
+                            "{} {:>11} {:>12} {} ",
+                            mode_string(&member.kind, details.mode),
+                            format!("{}/{}", details.uid, details.gid),
+                            details.size,
+                            format_mtime(details.mtime, "%Y-%m-%d %H:%M")
+                        ),
+                        // removed paths are lined up with the rest
+                        None if format == ListFormat::Long => format!("{:53}", ""),
+                        None => String::new(),
+                    };
+                    write!(out, "  {:<10} {}{}", kind, details, member.path.display())?;
+                    match link {
+                        Some(link) => writeln!(out, " -> {}", link.display())?,
+                        None => writeln!(out)?,
+                    }
+                }
+                ListFormat::Csv | ListFormat::Json => {
+                    let listed = ListedMember {
+                        snapshot: index + 1,
+                        id,
+                        kind,
+                        path: &member.path,
+                        link,
+                        mode: details.map(|details| format!("{:04o}", details.mode & 0o7777)),
+                        uid: details.map(|details| details.uid),
+                        gid: details.map(|details| details.gid),
+                        size: details.map(|details| details.size),
+                        mtime: details.map(|details| format_mtime(details.mtime, "%+")),
+                    };
+                    if format == ListFormat::Json {
+                        serde_json::to_writer(&mut *out, &listed)?;
+                        writeln!(out)?;
+                    } else {
+                        write_csv_row(out, &listed)?;
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn write_csv_row(out: &mut impl Write, member: &ListedMember) -> std::io::Result<()> {
+    let optional = |value: Option<String>| value.unwrap_or_default();
+    let fields = [
+        member.snapshot.to_string(),
+        member.id.unwrap_or_default().to_string(),
+        member.kind.to_string(),
+        member.path.to_string_lossy().into_owned(),
+        optional(member.link.map(|link| link.to_string_lossy().into_owned())),
+        optional(member.mode.clone()),
+        optional(member.uid.map(|uid| uid.to_string())),
+        optional(member.gid.map(|gid| gid.to_string())),
+        optional(member.size.map(|size| size.to_string())),
+        optional(member.mtime.clone()),
+    ];
+    let row: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+    writeln!(out, "{}", row.join(","))
+}
+
+/// Quotes a CSV field when it holds anything which would otherwise break up the row.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Permissions in the style of `ls -l`, led by the kind of member.
+fn mode_string(kind: &MemberKind, mode: u32) -> String {
+    let mut shown = String::from(match kind {
+        MemberKind::Directory => 'd',
+        MemberKind::Symlink(_) => 'l',
+        _ => '-',
+    });
+    for (bit, flag) in [
+        (0o400, 'r'),
+        (0o200, 'w'),
+        (0o100, 'x'),
+        (0o040, 'r'),
+        (0o020, 'w'),
+        (0o010, 'x'),
+        (0o004, 'r'),
+        (0o002, 'w'),
+        (0o001, 'x'),
+    ] {
+        shown.push(if mode & bit != 0 { flag } else { '-' });
+    }
+    shown
+}
+
+/// Formats seconds since the epoch in UTC.
+fn format_mtime(mtime: u64, format: &str) -> String {
+    chrono::DateTime::from_timestamp(mtime as i64, 0)
+        .map(|mtime| mtime.format(format).to_string())
+        .unwrap_or_default()
+}
+
+/// Finds the files in the tarball with the paths closest to one it doesn't hold, closest first,
+/// to suggest what was meant instead.
+pub fn near_misses<P: AsRef<Path>>(
+    tarball_path: P,
+    relative_path: &Path,
+    dictionary: Option<&Dictionary>,
+) -> std::io::Result<Vec<PathBuf>> {
+    let wanted: Vec<char> = relative_path.to_string_lossy().chars().collect();
+    // longer paths leave more room for mistakes, and a file of the same name counts wherever it is
+    let tolerance = (wanted.len() / 3).max(NEAR_MISS_DISTANCE);
+    // only files still there once every snapshot is restored are worth suggesting
+    let mut files = BTreeSet::new();
+    for member in list_tarball(tarball_path, dictionary, false)?
+        .into_iter()
+        .flat_map(|snapshot| snapshot.members)
+    {
+        match member.kind {
+            MemberKind::File => {
+                files.insert(member.path);
+            }
+            MemberKind::Removed => files.retain(|path| !path.starts_with(&member.path)),
+            _ => {}
+        }
+    }
+    let mut candidates: Vec<(usize, PathBuf)> = files
+        .into_iter()
+        .filter_map(|path| {
+            let candidate: Vec<char> = path.to_string_lossy().chars().collect();
+            let distance = edit_distance(&wanted, &candidate);
+            (distance <= tolerance || path.file_name() == relative_path.file_name())
+                .then_some((distance, path))
+        })
+        .collect();
+    candidates.sort();
+    Ok(candidates
+        .into_iter()
+        .take(NEAR_MISSES)
+        .map(|(_, path)| path)
+        .collect())
+}
+
+/// Number of characters to add, remove or replace to turn one string into the other.
+fn edit_distance(a: &[char], b: &[char]) -> usize {
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.iter().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let replaced = previous[j] + usize::from(a_char != b_char);
+            current.push(replaced.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
@@ -0,0 +1,91 @@
+use std::error::Error;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::hashing::HashAlgorithm;
+
+/// How often a held lock is tried again while waiting for it.
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// An advisory lock on a target, keeping other snapshots of it from running at the same time.
+/// The lock belongs to the open lock file, so the OS releases it however the process ends, and a
+/// lock file left behind by a crashed run doesn't hold anything.
+pub struct TargetLock {
+    _file: File,
+}
+
+#[derive(Debug)]
+pub enum LockError {
+    Io(PathBuf, std::io::Error),
+    /// Another snapshot holds the lock, along with the PID it recorded.
+    Held(PathBuf, Option<u32>),
+}
+
+impl Error for LockError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            LockError::Io(_, err) => Some(err),
+            LockError::Held(..) => None,
+        }
+    }
+}
+
+impl std::fmt::Display for LockError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LockError::Io(path, err) => {
+                write!(f, "Failed to lock {}: {}", path.display(), err)
+            }
+            LockError::Held(path, Some(pid)) => {
+                write!(f, "Lock {} is held by process {}", path.display(), pid)
+            }
+            LockError::Held(path, None) => {
+                write!(f, "Lock {} is held by another process", path.display())
+            }
+        }
+    }
+}
+
+/// The lock file for a target, in the temp directory and named after its canonical path.
+pub fn lock_path(target_path: &Path) -> PathBuf {
+    let digest = HashAlgorithm::Sha256.digest(target_path.as_os_str().as_encoded_bytes());
+    std::env::temp_dir().join(format!("saved_in_time-{}.lock", hex::encode(&digest[..8])))
+}
+
+/// Locks the target, waiting up to `wait` for another snapshot holding it to finish.
+pub fn acquire(target_path: &Path, wait: Option<Duration>) -> Result<TargetLock, LockError> {
+    let path = lock_path(target_path);
+    let io_error = |err| LockError::Io(path.clone(), err);
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)
+        .map_err(io_error)?;
+    let deadline = Instant::now() + wait.unwrap_or_default();
+    loop {
+        match file.try_lock() {
+            Ok(()) => break,
+            Err(TryLockError::WouldBlock) if Instant::now() < deadline => {
+                std::thread::sleep(RETRY_INTERVAL);
+            }
+            Err(TryLockError::WouldBlock) => {
+                let mut holder = String::new();
+                let pid = file
+                    .read_to_string(&mut holder)
+                    .ok()
+                    .and_then(|_| holder.trim().parse().ok());
+                return Err(LockError::Held(path, pid));
+            }
+            Err(TryLockError::Error(err)) => return Err(io_error(err)),
+        }
+    }
+    log::debug!("Locked {}", path.display());
+    file.set_len(0).map_err(io_error)?;
+    file.seek(SeekFrom::Start(0)).map_err(io_error)?;
+    writeln!(file, "{}", std::process::id()).map_err(io_error)?;
+    Ok(TargetLock { _file: file })
+}
@@ -1,41 +1,724 @@
 extern crate core;
 
 mod archiver;
+mod batch;
+mod cancel;
+mod catalog;
+mod checkpoint;
+mod checksum;
+mod comparer;
+mod cpio;
+mod detector;
+mod dictionary;
+mod docker;
+mod estimator;
+mod exit_code;
+mod farm;
+mod filter;
+mod hash_cache;
+mod hashing;
+mod hooks;
+mod index;
+mod journal;
+mod lister;
+mod lock;
+mod manifest;
+#[cfg(feature = "mount")]
+mod mount;
+#[cfg(all(feature = "mount", not(target_os = "linux")))]
+compile_error!(
+    "the `mount` feature speaks Linux's FUSE protocol and only builds on Linux; build without it elsewhere"
+);
+mod notify;
+mod ping;
+mod policy;
+mod preflight;
 mod processor;
+mod provenance;
+mod prune;
+mod rcon;
+mod report;
+mod restorer;
+mod serve;
+mod squashfs;
+mod throttle;
+mod verifier;
+mod warnings;
+mod webhook;
+mod zip_archive;
 
-use std::path::PathBuf;
-use std::time::SystemTime;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use clap::Parser;
+use clap::{Args, Parser, Subcommand};
+use exit_code::ExitCode;
 use log::LevelFilter;
 
-// exist codes
-const TARGET_NOT_EXISTS: i32 = 2;
-const TARGET_NOT_DIR: i32 = 3;
+/// Paths named for every kind of error when summarizing what `--keep-going` left out.
+const SUMMARIZED_PATHS: usize = 5;
 
 /// SIT is a simple tool to take simple snapshots of a changing system.
 /// This tool guarantees every file is backed up in the target zip in a stable state.
 #[derive(Parser, Debug)]
+#[command(subcommand_negates_reqs = true)]
 struct SitArgs {
+    #[command(subcommand)]
+    command: Option<SitCommand>,
     /// Log Level for the application
-    #[arg(short, long, default_value = "info", name = "logger")]
+    #[arg(short, long, default_value = "info", name = "logger", global = true)]
     log_level: LevelFilter,
+    /// List every exit code and what it means, then exit.
+    #[arg(long, name = "list_exit_codes", exclusive = true)]
+    list_exit_codes: bool,
     /// The directory to capture in the snapshot.
-    #[arg(short, long, name = "target")]
-    target_directory: String,
-    /// Output file for the processed directory. The file is contained in a tar.zst format.
+    #[arg(short, long, name = "target", required = true)]
+    target_directory: Option<String>,
+    /// Output file for the processed directory. The file is contained in a tar.zst format, or a
+    /// newc cpio archive when named .cpio, .cpio.zst or .cpio.gz, a SquashFS image when named
+    /// .squashfs or .sqfs, or a zip archive when named .zip.
     #[arg(short, long, default_value = "output.tar.zst", name = "output")]
     output_file: String,
+    /// Format to write the output in, whatever it's named. Defaults to the one its name implies.
+    #[arg(long, name = "format", conflicts_with = "output_dir")]
+    format: Option<archiver::ArchiveFormat>,
+    /// How every file in a zip archive is compressed, each on its own.
+    #[arg(long, default_value = "deflate", name = "zip_compression")]
+    zip_compression: zip_archive::ZipCompression,
+    /// Write the snapshot out as a directory tree mirroring the target, in a new directory inside
+    /// this one named after when the snapshot started, instead of as an archive. The tree is
+    /// named with an `.incomplete` suffix until every entry is written.
+    #[arg(long, name = "output_dir", conflicts_with = "output")]
+    output_dir: Option<String>,
+    /// Hard link the files unchanged since this earlier snapshot directory to their copies in it,
+    /// the way `rsync --link-dest` does, instead of copying them again. A file is unchanged when
+    /// its size, mtime, permissions and digest all match.
+    #[arg(long, name = "link_dest", requires = "output_dir")]
+    link_dest: Option<String>,
     /// Amount of iterations the visitor will run before giving up on getting a valid snapshot.
     #[arg(short, long, default_value = "5", name = "iteration_retries")]
     iteration_retries: i32,
     /// The compression level to use for the output file.
     #[arg(short, long, default_value = "3", name = "compression_level")]
     compression_level: i32,
+    /// Compress the files whose path matches this glob at this level instead, given as
+    /// `<glob>=<level>`. May be given several times, in which case the first matching glob applies.
+    /// Globs match the whole path, with `*` crossing directories, so `*.png` matches anywhere.
+    /// Files batched by `--batch-small-files` are compressed with their batch at the global level.
+    #[arg(long, name = "level_for", value_parser = parse_level_for)]
+    level_for: Vec<processor::LevelOverride>,
+    /// Directory to write captures to until they're archived, instead of the system's temp
+    /// directory. It needs room for the compressed content of the whole target.
+    #[arg(long, name = "temp_dir")]
+    temp_dir: Option<String>,
+    /// Capacity of the buffer files are read through while being captured.
+    #[arg(
+        long,
+        default_value_t = NonZeroUsize::new(processor::DEFAULT_READ_BUFFER_SIZE).unwrap(),
+        name = "read_buffer_size"
+    )]
+    read_buffer_size: NonZeroUsize,
+    /// Capture everything on a single thread, so a run can be reproduced exactly when reporting a
+    /// bug. Snapshots already capture and compress every file on the thread taking them; the flag
+    /// keeps them that way.
+    #[arg(long, name = "single_thread")]
+    single_thread: bool,
+    /// Re-read the archive after writing it, whatever its format, and confirm every entry is
+    /// present and intact, and that the manifest written with `--manifest` agrees with it.
+    #[arg(long)]
+    verify_after_write: bool,
+    /// Estimate the archive size and capture duration by compressing a sample of the target's
+    /// files, without writing an archive.
+    #[arg(long)]
+    estimate: bool,
+    /// Train a compression dictionary on a sample of the target's files and write it to this path,
+    /// without writing an archive.
+    #[arg(long, name = "train_dict", conflicts_with_all = ["estimate", "dict"])]
+    train_dict: Option<String>,
+    /// Compress every captured file with this dictionary, as written by `--train-dict`, which
+    /// shrinks many small files which look alike. The archive's snapshot record names the
+    /// dictionary, which restoring and verifying the archive need.
+    #[arg(long, name = "dict")]
+    dict: Option<String>,
+    /// Capture every entry's metadata without reading the content of files, which are archived
+    /// empty. Files only have to hold still from one pass to the next, so auditing the layout of
+    /// a large tree is fast and the archive small.
+    #[arg(long, conflicts_with_all = ["estimate", "train_dict", "dict"])]
+    metadata_only: bool,
+    /// Capture named pipes as files holding up to this many of the bytes buffered in them,
+    /// which are left out otherwise. Reading takes those bytes from whatever consumes the pipe,
+    /// and never waits for a writer, so only what's already buffered is captured, once.
+    #[arg(
+        long,
+        name = "drain_fifos",
+        conflicts_with_all = ["metadata_only", "checkpoint"]
+    )]
+    drain_fifos: Option<u64>,
+    /// Read the files to capture from this list (one path per line, `-` for stdin) instead of
+    /// walking the target directory. Relative paths are resolved against the target directory.
+    #[arg(long, name = "files_from")]
+    files_from: Option<String>,
+    /// Before capturing, walk the target once without reading any content, warning about every
+    /// directory which can't be listed and file which can't be opened, and estimating how many
+    /// entries the capture visits. Problems surface before the capture is underway, and the
+    /// capture finds the directory entries already cached.
+    #[arg(long, alias = "verify-source-readable", conflicts_with = "files_from")]
+    preflight: bool,
+    /// Write a manifest describing every captured entry to this path.
+    #[arg(long, name = "manifest")]
+    manifest: Option<String>,
+    /// Only archive what changed since the snapshot described by this manifest, recording removed
+    /// paths as whiteout entries.
+    #[arg(long, name = "since_manifest")]
+    since_manifest: Option<String>,
+    /// Cache the digests of captured files in this file, keyed by path, size and mtime, and take
+    /// files which still look the way they did when the previous run hashed them to be unchanged
+    /// since the --since-manifest snapshot, without reading them again.
+    #[arg(
+        long,
+        name = "compare_hash",
+        requires = "since_manifest",
+        conflicts_with_all = ["metadata_only", "checkpoint"]
+    )]
+    compare_hash: Option<String>,
+    /// Batch files up to this many bytes into a single compressed frame per directory.
+    #[arg(long, name = "batch_small_files")]
+    batch_small_files: Option<u64>,
+    /// Treat entries modified this long before a pass started as possibly unstable and confirm
+    /// them on another pass, guarding against coarse filesystem timestamps. Raised automatically
+    /// on filesystems known to have coarse timestamps.
+    #[arg(long, default_value = "2s", name = "mtime_slack", value_parser = humantime::parse_duration)]
+    mtime_slack: Duration,
+    /// How far ahead of the local clock an mtime may be before it's treated as bogus, capturing
+    /// the entry with a warning instead of retrying it until the iteration bound is exceeded.
+    #[arg(long, default_value = "60s", name = "future_mtime_slack", value_parser = humantime::parse_duration)]
+    future_mtime_slack: Duration,
+    /// Judge the target directory itself only by whether its listing holds still, ignoring its
+    /// mtime, so files briefly created and removed in it don't keep the snapshot from finishing.
+    /// Everything beneath it is still judged as usual.
+    #[arg(long, name = "ignore_root_mtime")]
+    ignore_root_mtime: bool,
+    /// Record the digest of where every symlink points in the manifest, so an incremental
+    /// snapshot can tell a symlink pointed elsewhere from one left alone, and only archives the
+    /// former.
+    #[arg(long, name = "hash_symlinks")]
+    hash_symlinks: bool,
+    /// After a pass finds paths changing, wait until their mtimes have held still this long before
+    /// the next pass, so writers which change files in bursts are captured once they're done.
+    #[arg(long, name = "settle", value_parser = humantime::parse_duration)]
+    settle: Option<Duration>,
+    /// Pause the capture between files while the system's 1-minute load average is above this,
+    /// resuming once it drops back, so a snapshot taken while the system is busy gives way to it.
+    #[arg(long, name = "max_load")]
+    max_load: Option<f64>,
+    /// How to judge whether a captured file has changed. Several strategies may be given, separated
+    /// by commas, in which case a file must pass all of them to count as unchanged.
+    #[arg(
+        long,
+        default_value = "mtime",
+        name = "change_detection",
+        value_enum,
+        value_delimiter = ','
+    )]
+    change_detection: Vec<detector::ChangeDetection>,
+    /// Algorithm to hash the content of captured files with, for the manifest and verification.
+    #[arg(long, default_value = "sha256", name = "hash_algo", value_enum)]
+    hash_algo: hashing::HashAlgorithm,
+    /// Capture what symlinks point to instead of the links themselves, expanding symlinked
+    /// directories into full subtrees. Links leading back into their own ancestry are skipped.
+    #[arg(long)]
+    dereference: bool,
+    /// Refuse a target which is a symlink, exiting with code 3, instead of snapshotting the
+    /// directory it resolves to under its resolved path.
+    #[arg(long, overrides_with = "target_follow")]
+    target_no_follow: bool,
+    /// Snapshot the directory a symlinked target resolves to, which is the default. Overrides an
+    /// earlier `--target-no-follow`.
+    #[arg(long, overrides_with = "target_no_follow")]
+    target_follow: bool,
+    /// Read captured files without updating their access time. Only supported on Linux, and only
+    /// for files owned by the user taking the snapshot; other files are read as usual.
+    #[arg(long)]
+    preserve_source_atime: bool,
+    /// Shorthand for ignoring `unreadable`: leave out files which fail to be read or encoded with
+    /// only a warning, whatever `--mode` says, exiting successfully.
+    #[arg(long, conflicts_with_all = ["strict", "keep_going"])]
+    ignore_file_errors: bool,
+    /// Shorthand for `--tolerate unreadable`: carry on past files which fail to be read or
+    /// encoded whatever `--mode` says, finish the archive, catalog and hooks as usual, then
+    /// summarize the paths left out and exit with code 13 if there were any.
+    #[arg(long, alias = "best-effort-files", conflicts_with = "strict")]
+    keep_going: bool,
+    /// How to treat entries which can't be captured faithfully, whether unreadable, outside the
+    /// target, of an unsupported type or a symlink loop, and running out of time. `strict` stops
+    /// at the first such entry without writing an archive, exiting with code 5. `best-effort`
+    /// leaves them out, records them in the manifest and the report, and exits with code 13 if
+    /// there were any. `--ignore-file-errors`, then `--tolerate`, then `--refuse` override it, so
+    /// refusing a compromise always wins; the other policy flags are shorthands for these.
+    #[arg(long, value_enum, name = "mode")]
+    mode: Option<policy::Mode>,
+    /// Carry on past these compromises whatever `--mode` says, leaving out the entries and
+    /// recording them.
+    #[arg(long, value_enum, value_delimiter = ',', name = "tolerate")]
+    tolerate: Vec<policy::Compromise>,
+    /// Stop at these compromises whatever `--mode` and the other flags say.
+    #[arg(long, value_enum, value_delimiter = ',', name = "refuse")]
+    refuse: Vec<policy::Compromise>,
+    /// Exit with code 14 instead of succeeding when the snapshot ran into any warning, such as a
+    /// path left out for being unreadable or a file dated in the future, naming the kinds it ran
+    /// into.
+    #[arg(long, conflicts_with = "max_warnings")]
+    fail_on_warning: bool,
+    /// Exit with code 14 instead of succeeding when the snapshot ran into more warnings than this.
+    #[arg(long, name = "max_warnings")]
+    max_warnings: Option<usize>,
+    /// Only capture files modified after this instant, given as an RFC 3339 timestamp or as
+    /// `@<seconds since the epoch>`.
+    #[arg(long, name = "since_time", value_parser = parse_instant)]
+    since_time: Option<SystemTime>,
+    /// Only capture files modified after this instant, given as how long before now, such as
+    /// `24h`, as an RFC 3339 timestamp or as `@<seconds since the epoch>`. Directories are only
+    /// kept when they hold a captured file, so what's left extracts with the structure around it,
+    /// and the window is recorded in the manifest.
+    #[arg(
+        long,
+        name = "modified_since",
+        value_parser = parse_window_bound,
+        conflicts_with = "since_time"
+    )]
+    modified_since: Option<SystemTime>,
+    /// Only capture files modified before this instant, given the same ways as
+    /// `--modified-since`, and bounding its window when both are given.
+    #[arg(
+        long,
+        name = "modified_before",
+        value_parser = parse_window_bound,
+        conflicts_with = "since_time"
+    )]
+    modified_before: Option<SystemTime>,
+    /// Take the snapshot as of this instant instead of now, given as an RFC 3339 timestamp or as
+    /// `@<seconds since the epoch>`, such as when an external snapshot was taken. Files modified
+    /// after it are left out, and the snapshot is stamped with it. It may only be as far in the
+    /// future as `--future-mtime-slack` allows.
+    #[arg(long, name = "at_revision", value_parser = parse_instant)]
+    at_revision: Option<SystemTime>,
+    /// Leave out files modified less than this long ago, such as `10m`, instead of capturing them
+    /// while they may still be written to. They're logged and listed as skipped, and never hold
+    /// the snapshot up for another pass.
+    #[arg(long, name = "min_age", value_parser = humantime::parse_duration)]
+    min_age: Option<Duration>,
+    /// Leave out hidden files and directories, named with a leading dot, along with everything
+    /// beneath them. On Windows, entries with the hidden attribute are left out as well.
+    #[arg(long, name = "exclude_hidden")]
+    exclude_hidden: bool,
+    /// Capture the hidden paths matching this glob despite `--exclude-hidden`. May be given several
+    /// times. Globs match the whole path, with `*` crossing directories, so `*/.config` keeps every
+    /// `.config` directory.
+    #[arg(long, name = "include", requires = "exclude_hidden", value_parser = parse_glob)]
+    include: Vec<globset::Glob>,
+    /// Leave out the contents of directories tagged as caches with a `CACHEDIR.TAG` file, keeping
+    /// the directory and its tag.
+    #[arg(long)]
+    exclude_caches: bool,
+    /// Leave out everything beneath directories tagged as caches, their tag included, keeping only
+    /// the directory.
+    #[arg(long, conflicts_with = "exclude_caches")]
+    exclude_caches_under: bool,
+    /// Leave out directories tagged as caches altogether.
+    #[arg(long, conflicts_with_all = ["exclude_caches", "exclude_caches_under"])]
+    exclude_caches_all: bool,
+    /// Only capture files and symlinks owned by this user, given by name or uid. May be given
+    /// several times. Directories are only kept when they hold a captured file, while still being
+    /// searched whoever owns them. Unix only.
+    #[arg(long, name = "owner")]
+    owner: Vec<String>,
+    /// Only capture files and symlinks in this group, given by name or gid. May be given several
+    /// times, and combined with `--owner` to require both. Unix only.
+    #[arg(long, name = "group")]
+    group: Vec<String>,
+    /// Record the time this snapshot started capturing to this file, for the next run's
+    /// `--since-time`.
+    #[arg(long, name = "stamp_file")]
+    stamp_file: Option<String>,
+    /// Record this octal mode for every file in the archive instead of its own.
+    #[arg(long, name = "chmod_files", value_parser = parse_mode)]
+    chmod_files: Option<u32>,
+    /// Record this octal mode for every directory in the archive instead of its own.
+    #[arg(long, name = "chmod_dirs", value_parser = parse_mode)]
+    chmod_dirs: Option<u32>,
+    /// Keep captures in this directory while walking, so an interrupted walk can be resumed with
+    /// `--resume`. Once the walk is done, the captured entries are journaled there too, so a
+    /// snapshot which dies while writing the archive can be resumed without capturing again. The
+    /// directory is removed once the archive is written.
+    #[arg(long, name = "checkpoint")]
+    checkpoint: Option<String>,
+    /// Resume from the captures an interrupted walk left in the checkpoint directory. When the
+    /// walk had finished, the journaled entries are checked against the target, hashing every
+    /// file again, and the archive is written from them, carrying on from the last entry synced;
+    /// if any path changed, the resume is refused.
+    #[arg(long, requires = "checkpoint")]
+    resume: bool,
+    /// Wait this long for another snapshot of the same target to finish, instead of exiting
+    /// right away.
+    #[arg(long, name = "lock_wait", value_parser = humantime::parse_duration)]
+    lock_wait: Option<Duration>,
+    /// Shorthand for `--refuse unreadable`: stop without writing an archive at the first file
+    /// which fails to be read or encoded, whatever `--mode` says.
+    #[arg(long)]
+    strict: bool,
+    /// Fail without writing an archive if the snapshot captured no files at all, which usually
+    /// means the target or its filters are misconfigured.
+    #[arg(long)]
+    fail_on_empty: bool,
+    /// Stop the snapshot once it has run this long, exiting without an archive unless
+    /// `--allow-partial` is given.
+    #[arg(long, name = "timeout", value_parser = humantime::parse_duration)]
+    timeout: Option<Duration>,
+    /// Shorthand for `--tolerate partial`: once the timeout passes, archive whatever was captured
+    /// by then instead of abandoning the snapshot. The snapshot still exits with the timeout's
+    /// exit code.
+    #[arg(long, requires = "timeout")]
+    allow_partial: bool,
+    /// Octal permissions of the archive and every file written beside it.
+    #[arg(long, default_value = "600", name = "output_mode", value_parser = parse_mode)]
+    output_mode: u32,
+    /// Reserve the archive's estimated size when creating it, trimming it to the size actually
+    /// written at the end.
+    #[arg(long)]
+    preallocate: bool,
+    /// Append to the output archive if it already exists, instead of replacing it. Entries
+    /// appended for paths already in the archive take precedence on restore.
+    #[arg(long)]
+    append: bool,
+    /// Write a checksum of the finished archive beside it, as `<output>.sha256` or
+    /// `<output>.blake3`, and record the digest of every captured file in the archive, for
+    /// `restore` to check restored files against.
+    #[arg(long, name = "checksum", value_enum)]
+    checksum: Option<hashing::HashAlgorithm>,
+    /// Write an index of the finished archive beside it, as `<output>.idx`, giving where every
+    /// member lies in it, so `list`, `cat` and `extract` seek straight to what they need instead of
+    /// reading through the archive.
+    #[arg(long)]
+    index: bool,
+    /// Run this command before the snapshot starts capturing, aborting the snapshot if it fails.
+    /// Runs through the shell, or directly when given as a JSON array of the program and its
+    /// arguments. May be given several times.
+    #[arg(long, name = "pre_hook", value_parser = hooks::parse_hook)]
+    pre_hook: Vec<hooks::Hook>,
+    /// Run this command once the snapshot ends, whether it succeeded or not, with its exit code,
+    /// archive, file count and archive size in `SIT_STATUS`, `SIT_OUTPUT`, `SIT_FILES` and
+    /// `SIT_BYTES`. Given like `--pre-hook`, and may be given several times.
+    #[arg(long, name = "post_hook", value_parser = hooks::parse_hook)]
+    post_hook: Vec<hooks::Hook>,
+    /// How long a hook may run before it's killed and treated as failed.
+    #[arg(long, default_value = "60s", name = "hook_timeout", value_parser = humantime::parse_duration)]
+    hook_timeout: Duration,
+    /// Address of a Minecraft server's RCON, to pause the server's saving over while the snapshot
+    /// captures, after flushing everything pending. Saving is resumed however the snapshot ends.
+    #[arg(long, name = "rcon", requires = "rcon_password_env")]
+    rcon: Option<String>,
+    /// Environment variable holding the RCON password.
+    #[arg(long, name = "rcon_password_env", requires = "rcon")]
+    rcon_password_env: Option<String>,
+    /// Pause this Docker container while the snapshot captures, unpausing it before the archive
+    /// is written. Given by name or id, and may be given several times.
+    #[arg(long, name = "docker_pause")]
+    docker_pause: Vec<String>,
+    /// Write a JSON report summarizing the snapshot to this path, even if the snapshot fails.
+    #[arg(long, name = "report")]
+    report: Option<String>,
+    /// List every path left out of the snapshot in this file, one JSON object per line giving the
+    /// path, a reason code (excluded, unreadable, outside_target, unstable, too_recent,
+    /// unsupported or symlink_loop) and the reason in full. Written whenever the report is, even
+    /// without `--report`.
+    #[arg(long, name = "report_skipped")]
+    report_skipped: Option<String>,
+    /// Log this many of the largest files captured, and of the directories whose files add up to
+    /// the most, with their sizes before and after compression, and include them in the report.
+    #[arg(long, name = "report_top")]
+    report_top: Option<usize>,
+    /// Log what the files beneath every directory down to this many levels below the target add up
+    /// to, before and after compression, and include it in the report. Directories whose paths
+    /// were all filtered out are listed with nothing.
+    #[arg(long, name = "breakdown_depth")]
+    breakdown_depth: Option<usize>,
+    /// How to order the breakdown by directory.
+    #[arg(
+        long,
+        default_value = "compressed",
+        name = "breakdown_sort",
+        requires = "breakdown_depth"
+    )]
+    breakdown_sort: report::BreakdownSort,
+    /// Record the snapshot in this catalog once it succeeds, as a line of JSON giving its id,
+    /// archive and statistics, so `snapshots` can list it and `restore` and `prune` can find it.
+    #[arg(long, name = "catalog")]
+    catalog: Option<String>,
+    /// POST the outcome of the snapshot to this URL as JSON once it ends, whether it succeeded or
+    /// not. Failing to post is only logged.
+    #[arg(long, name = "webhook")]
+    webhook: Option<String>,
+    /// Post this file instead of the default JSON payload, with `{{name}}` placeholders replaced by
+    /// the payload's fields, so the body can take the shape a chat service expects.
+    #[arg(long, name = "webhook_template", requires = "webhook")]
+    webhook_template: Option<String>,
+    /// How long each attempt at posting to the webhook may take.
+    #[arg(long, default_value = "10s", name = "webhook_timeout", value_parser = humantime::parse_duration)]
+    webhook_timeout: Duration,
+    /// Ping this healthchecks.io style check when the snapshot starts, at `<url>/start`, and again
+    /// once it ends, at `<url>` if it succeeded or `<url>/fail` with the error if it failed.
+    /// Failing to ping is only logged.
+    #[arg(long, name = "ping_url")]
+    ping_url: Option<String>,
+    /// How long each ping may take.
+    #[arg(long, default_value = "10s", name = "ping_timeout", value_parser = humantime::parse_duration)]
+    ping_timeout: Duration,
+    /// Accept any TLS certificate the check's server presents, without verifying it. Anyone able
+    /// to intercept the connection can then read and forge the pings.
+    #[arg(long, requires = "ping_url")]
+    ping_dangerously_skip_tls_verify: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum SitCommand {
+    /// Restore a snapshot archive into a directory.
+    Restore(RestoreArgs),
+    /// Check that a snapshot archive is intact.
+    Verify(VerifyArgs),
+    /// Remove old snapshot archives from a directory, keeping those the retention rules select.
+    Prune(PruneArgs),
+    /// List the snapshots recorded in a catalog.
+    Snapshots(SnapshotsArgs),
+    /// List the members of a snapshot archive, grouped by the snapshot which wrote them.
+    List(ListArgs),
+    /// Write the content of one file in a snapshot archive to stdout.
+    Cat(CatArgs),
+    /// Restore only the paths of a snapshot archive matching globs into a directory.
+    Extract(ExtractArgs),
+    /// Serve a snapshot archive as a read-only filesystem until interrupted.
+    #[cfg(feature = "mount")]
+    Mount(MountArgs),
+    /// Serve a snapshot archive over HTTP, listing its directories and sending its files.
+    Serve(ServeArgs),
+}
+
+#[derive(Args, Debug)]
+struct RestoreArgs {
+    /// The snapshot archive to restore, or the id of a snapshot in the catalog.
+    #[arg(name = "archive")]
+    archive: String,
+    /// Catalog to look the archive up in by snapshot id. Anything not found in it is taken as the
+    /// path of an archive.
+    #[arg(long, name = "catalog")]
+    catalog: Option<String>,
+    /// The directory to restore the snapshot into.
+    #[arg(short, long, default_value = ".", name = "into")]
+    into: String,
+    /// Dictionary the archive's files were compressed with, if they were.
+    #[arg(long, name = "dict")]
+    dict: Option<String>,
+    /// Skip checking restored files against the digests recorded in the archive by `--checksum`.
+    #[arg(long)]
+    no_verify_restore: bool,
+    /// Restore an archive several snapshots were appended to only as far as this snapshot, given
+    /// by its id, or as an instant like `--since` to pick the latest snapshot taken at or before
+    /// it. A snapshot looked up in the catalog is picked out of its archive the same way.
+    #[arg(long, name = "snapshot")]
+    snapshot: Option<String>,
+}
+
+#[derive(Args, Debug)]
+struct VerifyArgs {
+    /// The snapshot archive to verify.
+    #[arg(name = "archive")]
+    archive: String,
+    /// Checksum sidecar to check the archive against. Defaults to a `.sha256` or `.blake3` file
+    /// beside the archive, if there is one.
+    #[arg(long, name = "checksum_file")]
+    checksum_file: Option<String>,
+    /// Dictionary the archive's files were compressed with, if they were.
+    #[arg(long, name = "dict")]
+    dict: Option<String>,
+    /// Compare this directory against the archive instead, as the directory the snapshot was
+    /// taken of, reporting paths missing from either side and files, symlinks and permissions
+    /// which differ. Exits with the verification failure code if anything differs.
+    #[arg(long, name = "against", conflicts_with = "checksum_file")]
+    against: Option<String>,
+    /// Compare files by size and modification time instead of decompressing and hashing them.
+    #[arg(long, requires = "against")]
+    quick: bool,
+    /// Print the comparison as JSON, with the number of differences of each kind.
+    #[arg(long, requires = "against")]
+    json: bool,
+    /// Exit successfully even if the directory differs from the archive.
+    #[arg(long, requires = "against")]
+    expect_differences: bool,
+}
+
+#[derive(Args, Debug)]
+struct PruneArgs {
+    /// The directory holding the snapshot archives, as files ending in `.tar.zst`.
+    #[arg(name = "directory", required_unless_present = "catalog")]
+    directory: Option<String>,
+    /// Catalog the snapshots were recorded in. Without a directory, the archives it records are
+    /// pruned instead, the archives of each target on their own. Removed archives are dropped
+    /// from it.
+    #[arg(long, name = "catalog")]
+    catalog: Option<String>,
+    /// Only prune the archives the catalog records for this target.
+    #[arg(
+        long,
+        name = "target",
+        requires = "catalog",
+        conflicts_with = "directory"
+    )]
+    target: Option<String>,
+    /// Keep this many of the newest archives.
+    #[arg(long, default_value = "0", name = "keep_last")]
+    keep_last: usize,
+    /// Keep the newest archive of each of this many of the latest hours with archives.
+    #[arg(long, default_value = "0", name = "keep_hourly")]
+    keep_hourly: usize,
+    /// Keep the newest archive of each of this many of the latest days with archives.
+    #[arg(long, default_value = "0", name = "keep_daily")]
+    keep_daily: usize,
+    /// Keep the newest archive of each of this many of the latest ISO weeks with archives.
+    #[arg(long, default_value = "0", name = "keep_weekly")]
+    keep_weekly: usize,
+    /// Keep the newest archive of each of this many of the latest months with archives.
+    #[arg(long, default_value = "0", name = "keep_monthly")]
+    keep_monthly: usize,
+    /// Keep the newest archive of each of this many of the latest years with archives.
+    #[arg(long, default_value = "0", name = "keep_yearly")]
+    keep_yearly: usize,
+    /// Show which archives would be kept, and by which rules, without removing anything.
+    #[arg(long)]
+    dry_run: bool,
+}
+
+#[derive(Args, Debug)]
+struct SnapshotsArgs {
+    /// The catalog to list, as written by `--catalog`.
+    #[arg(long, name = "catalog")]
+    catalog: String,
+    /// Only list the snapshots of this target.
+    #[arg(long, name = "target")]
+    target: Option<String>,
+    /// Print every snapshot as a line of JSON instead of a table.
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Args, Debug)]
+struct ListArgs {
+    /// The snapshot archive to list.
+    #[arg(name = "archive")]
+    archive: String,
+    /// Dictionary the archive's files were compressed with, if they were.
+    #[arg(long, name = "dict")]
+    dict: Option<String>,
+    /// How to print the members. Every format but `short` reads every file in the archive to
+    /// tell its size.
+    #[arg(long, value_enum, default_value_t, name = "list_format")]
+    list_format: lister::ListFormat,
+}
+
+#[derive(Args, Debug)]
+struct CatArgs {
+    /// The snapshot archive holding the file.
+    #[arg(name = "archive")]
+    archive: String,
+    /// Path of the file in the archive, as `list` shows it.
+    #[arg(name = "path")]
+    path: PathBuf,
+    /// Dictionary the archive's files were compressed with, if they were.
+    #[arg(long, name = "dict")]
+    dict: Option<String>,
+}
+
+#[derive(Args, Debug)]
+struct ExtractArgs {
+    /// The snapshot archive to extract from.
+    #[arg(name = "archive")]
+    archive: String,
+    /// Globs matching the paths in the archive to extract, as `list` shows them.
+    #[arg(name = "glob", required = true, value_parser = parse_glob)]
+    globs: Vec<globset::Glob>,
+    /// The directory to extract the paths into.
+    #[arg(short, long, default_value = ".", name = "into")]
+    into: String,
+    /// Dictionary the archive's files were compressed with, if they were.
+    #[arg(long, name = "dict")]
+    dict: Option<String>,
+    /// Skip checking extracted files against the digests recorded in the archive by `--checksum`.
+    #[arg(long)]
+    no_verify_restore: bool,
+}
+
+#[cfg(feature = "mount")]
+#[derive(Args, Debug)]
+struct MountArgs {
+    /// The snapshot archive to mount.
+    #[arg(name = "archive")]
+    archive: String,
+    /// The directory to mount the archive at.
+    #[arg(name = "mountpoint")]
+    mountpoint: String,
+    /// Dictionary the archive's files were compressed with, if they were.
+    #[arg(long, name = "dict")]
+    dict: Option<String>,
+    /// Mount an archive several snapshots were appended to only as far as this snapshot, given by
+    /// its id, or as an instant like `--since` to pick the latest snapshot taken at or before it.
+    #[arg(long, name = "snapshot")]
+    snapshot: Option<String>,
+    /// Keep up to this many bytes of decompressed file content cached for repeated reads.
+    #[arg(long, default_value = "67108864", name = "cache_size")]
+    cache_size: u64,
+}
+
+#[derive(Args, Debug)]
+struct ServeArgs {
+    /// The snapshot archive to serve.
+    #[arg(name = "archive")]
+    archive: String,
+    /// Address to listen for requests on.
+    #[arg(long, default_value = "127.0.0.1:8080", name = "listen")]
+    listen: String,
+    /// Dictionary the archive's files were compressed with, if they were.
+    #[arg(long, name = "dict")]
+    dict: Option<String>,
+    /// Serve an archive several snapshots were appended to only as far as this snapshot, given by
+    /// its id, or as an instant like `--since` to pick the latest snapshot taken at or before it.
+    #[arg(long, name = "snapshot")]
+    snapshot: Option<String>,
+    /// Environment variable holding a token every request has to present, as
+    /// `Authorization: Bearer <token>`.
+    #[arg(long, name = "token_env")]
+    token_env: Option<String>,
 }
 
 fn main() {
-    let args = SitArgs::parse();
+    let args = match SitArgs::try_parse() {
+        Ok(args) => args,
+        // help and the version are printed the way clap prints them, but malformed arguments
+        // exit with a code of their own
+        Err(err) if err.use_stderr() => {
+            let _ = err.print();
+            ExitCode::BadArguments.exit();
+        }
+        Err(err) => err.exit(),
+    };
+    if args.list_exit_codes {
+        for code in ExitCode::ALL {
+            println!("{:>3}  {}", code.code(), code.meaning());
+        }
+        return;
+    }
 
     fern::Dispatch::new()
         .format(move |out, message, record| {
@@ -52,47 +735,2431 @@ fn main() {
         .apply()
         .expect("Failed to initialize logging");
 
-    let target_path = PathBuf::from(args.target_directory);
+    match args.command {
+        Some(SitCommand::Restore(ref restore_args)) => restore(restore_args),
+        Some(SitCommand::Verify(ref verify_args)) => verify(verify_args),
+        Some(SitCommand::Prune(ref prune_args)) => prune(prune_args),
+        Some(SitCommand::Snapshots(ref snapshots_args)) => snapshots(snapshots_args),
+        Some(SitCommand::List(ref list_args)) => list(list_args),
+        Some(SitCommand::Cat(ref cat_args)) => cat(cat_args),
+        Some(SitCommand::Extract(ref extract_args)) => extract(extract_args),
+        #[cfg(feature = "mount")]
+        Some(SitCommand::Mount(ref mount_args)) => mount(mount_args),
+        Some(SitCommand::Serve(ref serve_args)) => serve(serve_args),
+        None => snapshot(args),
+    }
+}
+
+fn restore(args: &RestoreArgs) {
+    let start = SystemTime::now();
+    let (archive, cataloged) = match &args.catalog {
+        Some(catalog_path) => match catalog::find(catalog_path, &args.archive) {
+            Ok(Some(record)) => {
+                log::info!(
+                    "Restoring snapshot {} of {} from {}",
+                    record.id,
+                    record.target.display(),
+                    record.output.display()
+                );
+                (record.output.display().to_string(), Some(record.id))
+            }
+            Ok(None) => (args.archive.clone(), None),
+            Err(err) => {
+                log::error!("Failed to read catalog {}: {}", catalog_path, err);
+                ExitCode::Failed.exit();
+            }
+        },
+        None => (args.archive.clone(), None),
+    };
+    // a zip archive holds a single snapshot, which is restored whole
+    if zip_archive::is_zip_archive(Path::new(&archive)).unwrap_or(false) {
+        if args.snapshot.is_some() {
+            log::error!(
+                "--snapshot only applies to tar archives, not the zip archive {}",
+                archive
+            );
+            ExitCode::BadArguments.exit();
+        }
+        notify::status("Restoring");
+        match zip_archive::restore_zip(&archive, &args.into) {
+            Ok(restored) => log::info!("Successfully restored {} files", restored),
+            Err(err) => {
+                log::error!("Failed to restore zip archive: {}", err);
+                ExitCode::Failed.exit();
+            }
+        }
+        log::info!(
+            "Finished everything successfully in {} ms.",
+            start.elapsed().unwrap().as_millis()
+        );
+        return;
+    }
+    let snapshots = match (&args.snapshot, &cataloged) {
+        (Some(selector), _) => match select_snapshot(&archive, selector) {
+            Ok(snapshots) => Some(snapshots),
+            Err(err) => {
+                log::error!("{}", err);
+                ExitCode::Failed.exit();
+            }
+        },
+        // an archive written before snapshots had ids is restored whole
+        (None, Some(id)) => select_snapshot(&archive, id).ok(),
+        (None, None) => None,
+    };
+    notify::status("Restoring");
+    let restored = dictionary::for_archive(&archive, args.dict.as_deref()).and_then(|dictionary| {
+        restorer::restore_tarball(
+            &archive,
+            &args.into,
+            &restorer::RestoreOptions {
+                dictionary: dictionary.as_ref(),
+                verify: !args.no_verify_restore,
+                snapshots,
+                only: None,
+            },
+        )
+    });
+    match restored {
+        Ok(restored) => log::info!("Successfully restored {} files", restored),
+        Err(err) => {
+            log::error!("Failed to restore tarball: {}", err);
+            ExitCode::Failed.exit();
+        }
+    }
+    log::info!(
+        "Finished everything successfully in {} ms.",
+        start.elapsed().unwrap().as_millis()
+    );
+}
+
+#[cfg(feature = "mount")]
+fn mount(args: &MountArgs) {
+    let snapshots = match &args.snapshot {
+        Some(selector) => match select_snapshot(&args.archive, selector) {
+            Ok(snapshots) => Some(snapshots),
+            Err(err) => {
+                log::error!("{}", err);
+                ExitCode::Failed.exit();
+            }
+        },
+        None => None,
+    };
+    let mounted =
+        dictionary::for_archive(&args.archive, args.dict.as_deref()).and_then(|dictionary| {
+            mount::mount_tarball(
+                &args.archive,
+                &args.mountpoint,
+                &mount::MountOptions {
+                    dictionary: dictionary.as_ref(),
+                    snapshots,
+                    cache_size: args.cache_size,
+                },
+            )
+        });
+    if let Err(err) = mounted {
+        log::error!("Failed to mount {}: {}", args.archive, err);
+        ExitCode::Failed.exit();
+    }
+}
+
+fn serve(args: &ServeArgs) {
+    let token = args
+        .token_env
+        .as_ref()
+        .map(|token_env| match std::env::var(token_env) {
+            Ok(token) if !token.is_empty() => token,
+            Ok(_) => {
+                log::error!("The token in {} is empty", token_env);
+                ExitCode::BadArguments.exit();
+            }
+            Err(err) => {
+                log::error!("Failed to read the token from {}: {}", token_env, err);
+                ExitCode::Failed.exit();
+            }
+        });
+    let snapshots = match &args.snapshot {
+        Some(selector) => match select_snapshot(&args.archive, selector) {
+            Ok(snapshots) => Some(snapshots),
+            Err(err) => {
+                log::error!("{}", err);
+                ExitCode::Failed.exit();
+            }
+        },
+        None => None,
+    };
+    let served =
+        dictionary::for_archive(&args.archive, args.dict.as_deref()).and_then(|dictionary| {
+            serve::serve_tarball(
+                &args.archive,
+                &args.listen,
+                &serve::ServeOptions {
+                    dictionary: dictionary.as_ref(),
+                    snapshots,
+                    token,
+                },
+            )
+        });
+    if let Err(err) = served {
+        log::error!("Failed to serve {}: {}", args.archive, err);
+        ExitCode::Failed.exit();
+    }
+}
+
+/// Finds how many of the snapshots appended to an archive to restore to get to the selected one:
+/// the snapshot with the given id, or the latest one taken at or before the given instant.
+fn select_snapshot(archive: &str, selector: &str) -> Result<usize, String> {
+    let records = provenance::read_archive(archive)
+        .map_err(|err| format!("Failed to read archive {}: {}", archive, err))?;
+    if let Some(index) = records
+        .iter()
+        .position(|record| record.id.as_deref() == Some(selector))
+    {
+        return Ok(index + 1);
+    }
+    let instant = parse_instant(selector)
+        .map(chrono::DateTime::<chrono::Utc>::from)
+        .map_err(|_| format!("No snapshot {} in archive {}", selector, archive))?;
+    records
+        .iter()
+        .rposition(|record| record.taken().is_some_and(|taken| taken <= instant))
+        .map(|index| index + 1)
+        .ok_or_else(|| {
+            format!(
+                "No snapshot in archive {} was taken at or before {}",
+                archive,
+                instant.to_rfc3339()
+            )
+        })
+}
+
+fn verify(args: &VerifyArgs) {
+    let start = SystemTime::now();
+    notify::status("Verifying");
+    if let Some(against) = &args.against {
+        compare(args, against);
+        log::info!(
+            "Finished everything successfully in {} ms.",
+            start.elapsed().unwrap().as_millis()
+        );
+        return;
+    }
+    let sidecar = args.checksum_file.as_ref().map(PathBuf::from).or_else(|| {
+        [
+            hashing::HashAlgorithm::Sha256,
+            hashing::HashAlgorithm::Blake3,
+        ]
+        .into_iter()
+        .map(|algorithm| checksum::sidecar_path(&args.archive, algorithm))
+        .find(|sidecar| sidecar.exists())
+    });
+    let checked = dictionary::for_archive(&args.archive, args.dict.as_deref())
+        .map_err(verifier::VerifyError::from)
+        .and_then(|dictionary| {
+            verifier::check_archive(&args.archive, sidecar, dictionary.as_ref())
+        });
+    match checked {
+        Ok(checked) => log::info!("Successfully verified {} files", checked),
+        Err(err) => {
+            log::error!("Failed to verify tarball: {}", err);
+            ExitCode::VerificationFailed.exit();
+        }
+    }
+    log::info!(
+        "Finished everything successfully in {} ms.",
+        start.elapsed().unwrap().as_millis()
+    );
+}
+
+fn compare(args: &VerifyArgs, against: &str) {
+    let compared =
+        dictionary::for_archive(&args.archive, args.dict.as_deref()).and_then(|dictionary| {
+            comparer::compare_tarball(&args.archive, against, dictionary.as_ref(), args.quick)
+        });
+    let comparison = match compared {
+        Ok(comparison) => comparison,
+        Err(err) => {
+            log::error!("Failed to compare {} against the archive: {}", against, err);
+            ExitCode::Failed.exit();
+        }
+    };
+    if args.json {
+        match serde_json::to_string(&comparison) {
+            Ok(json) => println!("{}", json),
+            Err(err) => log::warn!("Failed to serialize the comparison: {}", err),
+        }
+    } else {
+        for different in &comparison.differences {
+            println!(
+                "{:<12} {}",
+                different.difference.to_string(),
+                different.path.display()
+            );
+        }
+    }
+    if comparison.differences.is_empty() {
+        log::info!(
+            "{} matches all {} archived paths",
+            against,
+            comparison.compared
+        );
+        return;
+    }
+    let summary = format!(
+        "{} differs from the archive: {} missing, {} extra, {} with other content, {} symlinks pointing elsewhere, {} with other permissions and {} of another kind",
+        against,
+        comparison.missing,
+        comparison.extra,
+        comparison.content,
+        comparison.link_target,
+        comparison.permissions,
+        comparison.kind
+    );
+    if args.expect_differences {
+        log::info!("{}", summary);
+    } else {
+        log::error!("{}", summary);
+        ExitCode::VerificationFailed.exit();
+    }
+}
+
+fn prune(args: &PruneArgs) {
+    let retention = prune::Retention {
+        last: args.keep_last,
+        hourly: args.keep_hourly,
+        daily: args.keep_daily,
+        weekly: args.keep_weekly,
+        monthly: args.keep_monthly,
+        yearly: args.keep_yearly,
+    };
+    if retention.is_empty() {
+        log::error!("No retention rule was given; refusing to remove every archive");
+        ExitCode::BadArguments.exit();
+    }
+    let groups = match (&args.directory, &args.catalog) {
+        (Some(directory), _) => match prune::find_archives(directory) {
+            Ok(archives) => vec![archives],
+            Err(err) => {
+                log::error!("Failed to list archives in {}: {}", directory, err);
+                ExitCode::Failed.exit();
+            }
+        },
+        (None, Some(catalog_path)) => match catalog::read(catalog_path) {
+            Ok(records) => {
+                let target = args
+                    .target
+                    .as_ref()
+                    .map(|target| resolve_path(Path::new(target)));
+                prune::catalog_archives(records)
+                    .into_iter()
+                    .filter(|(archives_target, _)| {
+                        target
+                            .as_ref()
+                            .is_none_or(|target| archives_target == target)
+                    })
+                    .map(|(_, archives)| archives)
+                    .collect()
+            }
+            Err(err) => {
+                log::error!("Failed to read catalog {}: {}", catalog_path, err);
+                ExitCode::Failed.exit();
+            }
+        },
+        (None, None) => unreachable!("clap requires a directory or a catalog"),
+    };
+
+    let mut removed = Vec::new();
+    for decision in groups
+        .into_iter()
+        .flat_map(|archives| prune::select(archives, &retention))
+    {
+        let path = decision.archive.path.display();
+        let taken = decision.archive.taken.to_rfc3339();
+        if !decision.kept_by.is_empty() {
+            log::info!(
+                "Keeping {} taken at {} ({})",
+                path,
+                taken,
+                decision.kept_by.join(", ")
+            );
+        } else if args.dry_run {
+            log::info!("Would remove {} taken at {}", path, taken);
+        } else {
+            match prune::remove(&decision.archive) {
+                Ok(_) => {
+                    log::info!("Removed {} taken at {}", path, taken);
+                    removed.push(resolve_path(&decision.archive.path));
+                }
+                Err(err) => {
+                    log::error!("Failed to remove {}: {}", path, err);
+                    forget_pruned(args.catalog.as_deref(), &removed);
+                    ExitCode::Failed.exit();
+                }
+            }
+        }
+    }
+    if !args.dry_run {
+        forget_pruned(args.catalog.as_deref(), &removed);
+        log::info!("Removed {} archives", removed.len());
+    }
+}
+
+/// Drops the pruned archives from the catalog, if there is one.
+fn forget_pruned(catalog_path: Option<&str>, removed: &[PathBuf]) {
+    let Some(catalog_path) = catalog_path.filter(|_| !removed.is_empty()) else {
+        return;
+    };
+    match catalog::forget(resolve_path(Path::new(catalog_path)), removed, None) {
+        Ok(_) => log::info!("Dropped the removed archives from catalog {}", catalog_path),
+        Err(err) => log::error!("Failed to update catalog {}: {}", catalog_path, err),
+    }
+}
+
+fn snapshots(args: &SnapshotsArgs) {
+    let records = match catalog::read(&args.catalog) {
+        Ok(records) => records,
+        Err(err) => {
+            log::error!("Failed to read catalog {}: {}", args.catalog, err);
+            ExitCode::Failed.exit();
+        }
+    };
+    let target = args
+        .target
+        .as_ref()
+        .map(|target| resolve_path(Path::new(target)));
+    let records = records.iter().filter(|record| {
+        target
+            .as_ref()
+            .is_none_or(|target| record.target == *target)
+    });
+    for record in records {
+        if args.json {
+            match serde_json::to_string(record) {
+                Ok(line) => println!("{}", line),
+                Err(err) => log::warn!("Failed to serialize snapshot {}: {}", record.id, err),
+            }
+        } else {
+            println!(
+                "{}  {}  {:>8} files  {:>12} bytes  {} -> {}{}",
+                record.id,
+                record.timestamp,
+                record.files,
+                record.bytes_written,
+                record.target.display(),
+                record.output.display(),
+                record
+                    .parent
+                    .as_ref()
+                    .map(|parent| format!(" (since {})", parent))
+                    .unwrap_or_default()
+            );
+        }
+    }
+}
+
+fn list(args: &ListArgs) {
+    let detailed = args.list_format != lister::ListFormat::Short;
+    let listed = dictionary::for_archive(&args.archive, args.dict.as_deref())
+        .and_then(|dictionary| lister::list_tarball(&args.archive, dictionary.as_ref(), detailed));
+    let snapshots = match listed {
+        Ok(snapshots) => snapshots,
+        Err(err) => {
+            log::error!("Failed to list archive {}: {}", args.archive, err);
+            ExitCode::Failed.exit();
+        }
+    };
+    let written =
+        lister::write_listing(&snapshots, args.list_format, &mut std::io::stdout().lock());
+    if let Err(err) = written {
+        log::error!("Failed to list archive {}: {}", args.archive, err);
+        ExitCode::Failed.exit();
+    }
+}
+
+fn cat(args: &CatArgs) {
+    let dictionary = match dictionary::for_archive(&args.archive, args.dict.as_deref()) {
+        Ok(dictionary) => dictionary,
+        Err(err) => {
+            log::error!("Failed to read archive {}: {}", args.archive, err);
+            ExitCode::Failed.exit();
+        }
+    };
+    let mut stdout = std::io::stdout().lock();
+    match restorer::cat_file(&args.archive, &args.path, dictionary.as_ref(), &mut stdout) {
+        Ok(true) => {}
+        Ok(false) => {
+            log::error!(
+                "No file {} in archive {}",
+                args.path.display(),
+                args.archive
+            );
+            if let Ok(near_misses) =
+                lister::near_misses(&args.archive, &args.path, dictionary.as_ref())
+            {
+                if !near_misses.is_empty() {
+                    log::error!("Did you mean:");
+                }
+                for near_miss in near_misses {
+                    log::error!("  {}", near_miss.display());
+                }
+            }
+            ExitCode::Failed.exit();
+        }
+        Err(err) => {
+            log::error!("Failed to read {}: {}", args.path.display(), err);
+            ExitCode::Failed.exit();
+        }
+    }
+}
+
+fn extract(args: &ExtractArgs) {
+    let start = SystemTime::now();
+    let mut globs = globset::GlobSetBuilder::new();
+    for glob in &args.globs {
+        globs.add(glob.clone());
+    }
+    let only = match globs.build() {
+        Ok(only) => only,
+        Err(err) => {
+            log::error!("Invalid globs: {}", err);
+            ExitCode::BadArguments.exit();
+        }
+    };
+    notify::status("Extracting");
+    let extracted =
+        dictionary::for_archive(&args.archive, args.dict.as_deref()).and_then(|dictionary| {
+            restorer::restore_tarball(
+                &args.archive,
+                &args.into,
+                &restorer::RestoreOptions {
+                    dictionary: dictionary.as_ref(),
+                    verify: !args.no_verify_restore,
+                    snapshots: None,
+                    only: Some(only),
+                },
+            )
+        });
+    match extracted {
+        Ok(0) => {
+            log::error!("No files in archive {} match the globs", args.archive);
+            ExitCode::Failed.exit();
+        }
+        Ok(extracted) => log::info!("Successfully extracted {} files", extracted),
+        Err(err) => {
+            log::error!("Failed to extract from tarball: {}", err);
+            ExitCode::Failed.exit();
+        }
+    }
+    log::info!(
+        "Finished everything successfully in {} ms.",
+        start.elapsed().unwrap().as_millis()
+    );
+}
+
+fn snapshot(args: SitArgs) {
+    // estimating and training don't take a snapshot for the check to hear about
+    if let Some(ping_url) = args
+        .ping_url
+        .as_ref()
+        .filter(|_| !args.estimate && args.train_dict.is_none())
+    {
+        ping::start(
+            ping_url,
+            args.ping_timeout,
+            args.ping_dangerously_skip_tls_verify,
+        );
+    }
+    let mut plan = plan_snapshot(&args);
+    if args.estimate {
+        estimate(&args, &plan);
+        return;
+    }
+    if let Some(train_dict) = &args.train_dict {
+        train_dictionary(&args, &plan, train_dict);
+        return;
+    }
+    let webhook_template = args.webhook_template.as_ref().map(|template_path| {
+        match std::fs::read_to_string(template_path) {
+            Ok(template) => template,
+            Err(err) => {
+                abort(
+                    ExitCode::Failed,
+                    format!("Failed to read webhook template {}: {}", template_path, err),
+                );
+            }
+        }
+    });
+
+    // held until the process exits, however it exits
+    let _lock = lock_target(&args, &plan.target_path);
+    let mut captured = capture(&args, &mut plan, webhook_template);
+    let taken = write_archive(&args, &mut plan, &mut captured);
+    finish(&args, &plan, captured, taken);
+}
+
+/// What a snapshot writes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OutputFormat {
+    Tar,
+    Cpio(cpio::CpioCompression),
+    Zip,
+    SquashFs,
+    /// A directory of hard links, named by `--output-dir`.
+    Farm,
+}
+
+impl OutputFormat {
+    fn describe(self) -> &'static str {
+        match self {
+            OutputFormat::Tar => "tarball",
+            OutputFormat::Cpio(_) => "cpio archive",
+            OutputFormat::Zip => "zip archive",
+            OutputFormat::SquashFs => "SquashFS image",
+            OutputFormat::Farm => "snapshot directory",
+        }
+    }
+}
+
+/// Paths a filter left out of the snapshot, gathered while capturing.
+type FoundPaths = Rc<RefCell<BTreeSet<PathBuf>>>;
+
+/// What a snapshot captures and where it writes it, settled from the arguments before anything is
+/// captured.
+struct SnapshotPlan {
+    target_path: PathBuf,
+    /// The path the target was given as, when it's a symlink leading to `target_path`.
+    invoked_as: Option<PathBuf>,
+    output_path: PathBuf,
+    format: OutputFormat,
+    temp_dir: PathBuf,
+    /// Files written beside the snapshot which live inside the target.
+    excluded: Vec<PathBuf>,
+    filters: Vec<Box<dyn filter::EntryFilter>>,
+    outside_window: Option<FoundPaths>,
+    not_owned: Option<FoundPaths>,
+    after_revision: Option<FoundPaths>,
+    hidden_entries: Option<FoundPaths>,
+    cache_directories: Option<FoundPaths>,
+    dictionary: Option<dictionary::Dictionary>,
+}
+
+/// Checks the target and the arguments, refusing those that can't make a snapshot, and builds the
+/// filters deciding what's captured.
+fn plan_snapshot(args: &SitArgs) -> SnapshotPlan {
+    let (target_path, invoked_as) = resolve_target(args);
+
+    let output_path = match &args.output_dir {
+        Some(output_dir) => Path::new(output_dir).join(
+            chrono::Utc::now()
+                .format(provenance::ID_TIME_FORMAT)
+                .to_string(),
+        ),
+        None => PathBuf::from(&args.output_file),
+    };
+    let temp_dir = args
+        .temp_dir
+        .as_ref()
+        .map_or_else(std::env::temp_dir, PathBuf::from);
+    let excluded = excluded_sidecars(args, &target_path, &output_path, &temp_dir);
+
+    let mut filters: Vec<Box<dyn filter::EntryFilter>> =
+        vec![Box::new(filter::ExcludedPaths(excluded.clone()))];
+    if let Some(since_time) = args.since_time {
+        filters.push(Box::new(filter::ModifiedSince(since_time)));
+    }
+    if let (Some(since), Some(before)) = (args.modified_since, args.modified_before) {
+        if since >= before {
+            abort(
+                ExitCode::BadArguments,
+                "--modified-since must come before --modified-before, or no file fits between them"
+                    .to_string(),
+            );
+        }
+    }
+    let not_owned = (!args.owner.is_empty() || !args.group.is_empty()).then(|| {
+        let resolve = |names: &[String], resolve: fn(&str) -> std::io::Result<u32>| {
+            names
+                .iter()
+                .map(|name| {
+                    resolve(name).unwrap_or_else(|err| {
+                        abort(
+                            ExitCode::BadArguments,
+                            format!("Failed to look up {}: {}", name, err),
+                        )
+                    })
+                })
+                .collect()
+        };
+        let not_owned = FoundPaths::default();
+        filters.push(Box::new(filter::OwnedBy {
+            users: resolve(&args.owner, filter::resolve_user),
+            groups: resolve(&args.group, filter::resolve_group),
+            found: Rc::clone(&not_owned),
+        }));
+        not_owned
+    });
+    let outside_window =
+        (args.modified_since.is_some() || args.modified_before.is_some()).then(|| {
+            let outside_window = FoundPaths::default();
+            filters.push(Box::new(filter::ModifiedWithin {
+                since: args.modified_since,
+                before: args.modified_before,
+                found: Rc::clone(&outside_window),
+            }));
+            outside_window
+        });
+    let after_revision = args.at_revision.map(|revision| {
+        let ahead = revision
+            .duration_since(SystemTime::now())
+            .unwrap_or_default();
+        if ahead > args.future_mtime_slack {
+            abort(
+                ExitCode::BadArguments,
+                format!(
+                    "--at-revision is {} in the future, beyond the --future-mtime-slack of {}",
+                    humantime::format_duration(Duration::from_secs(ahead.as_secs())),
+                    humantime::format_duration(args.future_mtime_slack)
+                ),
+            );
+        }
+        let after_revision = FoundPaths::default();
+        filters.push(Box::new(filter::AtRevision {
+            revision,
+            found: Rc::clone(&after_revision),
+        }));
+        after_revision
+    });
+    let hidden_entries = args.exclude_hidden.then(|| {
+        let mut include = globset::GlobSetBuilder::new();
+        for glob in &args.include {
+            include.add(glob.clone());
+        }
+        let hidden_entries = FoundPaths::default();
+        match include.build() {
+            Ok(include) => filters.push(Box::new(filter::HiddenEntries {
+                include,
+                found: Rc::clone(&hidden_entries),
+            })),
+            Err(err) => abort(ExitCode::BadArguments, format!("Invalid globs: {}", err)),
+        }
+        hidden_entries
+    });
+    let cache_exclusion = if args.exclude_caches {
+        Some(filter::CacheExclusion::Contents)
+    } else if args.exclude_caches_under {
+        Some(filter::CacheExclusion::Under)
+    } else if args.exclude_caches_all {
+        Some(filter::CacheExclusion::All)
+    } else {
+        None
+    };
+    let cache_directories = cache_exclusion.map(|exclusion| {
+        let cache_directories = FoundPaths::default();
+        filters.push(Box::new(filter::CacheDirectories {
+            exclusion,
+            found: Rc::clone(&cache_directories),
+        }));
+        cache_directories
+    });
+
+    if args.metadata_only
+        && args
+            .change_detection
+            .contains(&detector::ChangeDetection::Hash)
+    {
+        abort(
+            ExitCode::BadArguments,
+            "Detecting changes by hash reads the content --metadata-only leaves unread".to_string(),
+        );
+    }
+    let format = output_format(args, &output_path);
+    if format != OutputFormat::Tar {
+        let tar_only = [
+            (
+                "--checksum",
+                format == OutputFormat::Farm && args.checksum.is_some(),
+            ),
+            ("--append", args.append),
+            ("--since-manifest", args.since_manifest.is_some()),
+            ("--batch-small-files", args.batch_small_files.is_some()),
+            ("--checkpoint", args.checkpoint.is_some()),
+            ("--preallocate", args.preallocate),
+            ("--index", args.index),
+            ("--catalog", args.catalog.is_some()),
+        ];
+        if let Some((flag, _)) = tar_only.iter().find(|(_, given)| *given) {
+            abort(
+                ExitCode::BadArguments,
+                format!(
+                    "{} only applies to tar archives, not the {} {}",
+                    flag,
+                    format.describe(),
+                    output_path.display()
+                ),
+            );
+        }
+    }
+
+    if args.max_load.is_some() && throttle::load_average().is_none() {
+        log::warn!(
+            "The load average can't be read on this system; --max-load won't pause anything"
+        );
+    }
+
+    if let Some(link_dest) = &args.link_dest {
+        if !Path::new(link_dest).is_dir() {
+            abort(
+                ExitCode::BadArguments,
+                format!("Link destination {} isn't a directory", link_dest),
+            );
+        }
+    }
+
+    let dictionary =
+        args.dict
+            .as_ref()
+            .map(|dict_path| match dictionary::Dictionary::read(dict_path) {
+                Ok(dictionary) => {
+                    log::info!(
+                        "Compressing with dictionary {} from {}",
+                        dictionary.id(),
+                        dict_path
+                    );
+                    dictionary
+                }
+                Err(err) => {
+                    abort(
+                        ExitCode::Failed,
+                        format!("Failed to read dictionary {}: {}", dict_path, err),
+                    );
+                }
+            });
+
+    SnapshotPlan {
+        target_path,
+        invoked_as,
+        output_path,
+        format,
+        temp_dir,
+        excluded,
+        filters,
+        outside_window,
+        not_owned,
+        after_revision,
+        hidden_entries,
+        cache_directories,
+        dictionary,
+    }
+}
+
+/// The target directory, resolved through any symlinks leading to it, along with the path it was
+/// given as when that's a symlink.
+fn resolve_target(args: &SitArgs) -> (PathBuf, Option<PathBuf>) {
+    let target_path = PathBuf::from(
+        args.target_directory
+            .as_ref()
+            .expect("The target directory is required to take a snapshot"),
+    );
     if !target_path.exists() {
-        log::error!("Target directory does not exist: {}", target_path.display());
-        std::process::exit(TARGET_NOT_EXISTS);
+        abort(
+            ExitCode::TargetMissing,
+            format!("Target directory does not exist: {}", target_path.display()),
+        );
     }
     if !target_path.is_dir() {
-        log::error!(
-            "Target directory is not a directory: {}",
-            target_path.display()
+        abort(
+            ExitCode::TargetNotDirectory,
+            format!(
+                "Target directory is not a directory: {}",
+                target_path.display()
+            ),
         );
-        std::process::exit(TARGET_NOT_DIR);
     }
+    match target_path.canonicalize() {
+        Ok(canonical_path) if target_path.is_symlink() => {
+            if args.target_no_follow {
+                abort(
+                    ExitCode::TargetNotDirectory,
+                    format!(
+                        "Target {} is a symlink to {}; give that directory as the target, or drop --target-no-follow",
+                        target_path.display(),
+                        canonical_path.display()
+                    ),
+                );
+            }
+            log::info!(
+                "Target {} is a symlink; snapshotting resolved directory {}",
+                target_path.display(),
+                canonical_path.display()
+            );
+            let invoked_as = std::path::absolute(&target_path).unwrap_or(target_path);
+            (canonical_path, Some(invoked_as))
+        }
+        Ok(canonical_path) => (canonical_path, None),
+        Err(err) => {
+            abort(
+                ExitCode::TargetMissing,
+                format!(
+                    "Failed to resolve target directory {}: {}",
+                    target_path.display(),
+                    err
+                ),
+            );
+        }
+    }
+}
 
-    let start = SystemTime::now();
-    let entries = match processor::process_directory(
-        &target_path,
-        args.iteration_retries,
+/// The files written beside the snapshot which live inside the target, so it doesn't capture
+/// them.
+fn excluded_sidecars(
+    args: &SitArgs,
+    target_path: &Path,
+    output_path: &Path,
+    temp_dir: &Path,
+) -> Vec<PathBuf> {
+    let mut sidecars = vec![
+        match &args.output_dir {
+            // earlier snapshots live beside the new one
+            Some(output_dir) => ("output directory", resolve_path(Path::new(output_dir))),
+            None => ("output file", resolve_path(output_path)),
+        },
+        ("temp directory", resolve_path(temp_dir)),
+    ];
+    if let Some(manifest_path) = &args.manifest {
+        sidecars.push(("manifest", resolve_path(Path::new(manifest_path))));
+    }
+    if let Some(checkpoint) = &args.checkpoint {
+        sidecars.push(("checkpoint", resolve_path(Path::new(checkpoint))));
+    }
+    if let Some(stamp_file) = &args.stamp_file {
+        sidecars.push(("stamp file", resolve_path(Path::new(stamp_file))));
+    }
+    if let Some(report_path) = &args.report {
+        sidecars.push(("report", resolve_path(Path::new(report_path))));
+    }
+    if let Some(skipped_path) = &args.report_skipped {
+        let skipped_path = resolve_path(Path::new(skipped_path));
+        report::list_skipped_in(&skipped_path);
+        sidecars.push(("skipped report", skipped_path));
+    }
+    if let Some(catalog_path) = &args.catalog {
+        sidecars.push(("catalog", resolve_path(Path::new(catalog_path))));
+    }
+    if let Some(algorithm) = args.checksum {
+        let sidecar = checksum::sidecar_path(output_path, algorithm);
+        sidecars.push(("checksum", resolve_path(&sidecar)));
+    }
+    if args.index {
+        sidecars.push(("index", resolve_path(&index::sidecar_path(output_path))));
+    }
+    let mut excluded = Vec::new();
+    for (description, path) in sidecars {
+        if path.starts_with(target_path) && path != target_path {
+            log::info!(
+                "Excluding {} {} from the snapshot since it lives inside the target",
+                description,
+                path.display()
+            );
+            excluded.push(path);
+        }
+    }
+    excluded
+}
+
+/// The format the snapshot is written in, by `--format`, `--output-dir` or the output's name.
+fn output_format(args: &SitArgs, output_path: &Path) -> OutputFormat {
+    if args.output_dir.is_some() {
+        return OutputFormat::Farm;
+    }
+    let zip = match args.format {
+        Some(format) => format == archiver::ArchiveFormat::Zip,
+        None => zip_archive::is_zip(output_path),
+    };
+    match cpio::output_compression(output_path) {
+        _ if zip => OutputFormat::Zip,
+        Some(compression) => OutputFormat::Cpio(compression),
+        None if squashfs::is_squashfs(output_path) => OutputFormat::SquashFs,
+        None => OutputFormat::Tar,
+    }
+}
+
+/// Logs how large the archive would be and how long capturing would take, without writing it.
+fn estimate(args: &SitArgs, plan: &SnapshotPlan) {
+    match estimator::estimate_directory(
+        &plan.target_path,
         args.compression_level,
+        plan.dictionary.as_ref(),
+        &plan.filters,
     ) {
-        Ok(entries) => entries,
+        Ok(estimate) => {
+            log::info!(
+                "Sampled {} of {} files ({} of {} bytes)",
+                estimate.sampled_files,
+                estimate.files,
+                estimate.sampled_bytes,
+                estimate.total_bytes
+            );
+            log::info!("Estimated compression ratio: {:.3}", estimate.ratio);
+            log::info!("Projected archive size: {} bytes", estimate.projected_size);
+            log::info!(
+                "Projected capture duration: {} ms",
+                estimate.projected_duration.as_millis()
+            );
+        }
         Err(err) => {
-            log::error!("Failed to process directory: {}", err);
-            std::process::exit(1);
+            abort(
+                ExitCode::Failed,
+                format!("Failed to estimate directory: {}", err),
+            );
+        }
+    }
+}
+
+/// Trains a dictionary on the target's files and writes it, without taking a snapshot.
+fn train_dictionary(args: &SitArgs, plan: &SnapshotPlan, train_dict: &str) {
+    let trained =
+        dictionary::train(&plan.target_path, &plan.filters).and_then(|(dictionary, sampled)| {
+            dictionary.write(train_dict, Some(args.output_mode))?;
+            Ok((dictionary, sampled))
+        });
+    match trained {
+        Ok((dictionary, sampled)) => log::info!(
+            "Trained dictionary {} of {} bytes on {} sampled files and wrote it at {}",
+            dictionary.id(),
+            dictionary.len(),
+            sampled,
+            train_dict
+        ),
+        Err(err) => {
+            abort(
+                ExitCode::Failed,
+                format!("Failed to train dictionary: {}", err),
+            );
+        }
+    }
+}
+
+/// Locks the target against other snapshots of it, waiting for `--lock-wait` if one is running.
+fn lock_target(args: &SitArgs, target_path: &Path) -> lock::TargetLock {
+    match lock::acquire(target_path, args.lock_wait) {
+        Ok(lock) => lock,
+        Err(err @ lock::LockError::Held(..)) => {
+            abort(
+                ExitCode::TargetLocked,
+                format!(
+                    "Another snapshot of {} is running: {}",
+                    target_path.display(),
+                    err
+                ),
+            );
+        }
+        Err(err) => {
+            abort(ExitCode::Failed, format!("{}", err));
+        }
+    }
+}
+
+/// What capturing the target leaves for the archive to be written from.
+struct Captured {
+    entries: Box<dyn Iterator<Item = processor::Entry>>,
+    failed: Vec<processor::FailedPath>,
+    size_estimate: u64,
+    report: report::SnapshotReport,
+    options: processor::CaptureOptions,
+    previous_manifest: Option<manifest::Manifest>,
+    /// Where the snapshot is appended to the existing archive, with `--append`.
+    append_offset: Option<u64>,
+    /// Whether the entries came from the journal of a capture which had finished.
+    resuming: bool,
+    start: SystemTime,
+}
+
+/// Runs the pre-hooks, pauses whatever writes to the target, and captures it until it's stable,
+/// or resumes a capture which had finished from its journal.
+fn capture(args: &SitArgs, plan: &mut SnapshotPlan, webhook_template: Option<String>) -> Captured {
+    let target_path = &plan.target_path;
+    let output_path = &plan.output_path;
+    let previous_manifest = args.since_manifest.as_ref().map(|since_manifest| {
+        match manifest::Manifest::read(since_manifest) {
+            Ok(previous_manifest) => previous_manifest,
+            Err(err) => {
+                abort(
+                    ExitCode::Failed,
+                    format!("Failed to read manifest {}: {}", since_manifest, err),
+                );
+            }
+        }
+    });
+    if let Some(previous_manifest) = &previous_manifest {
+        if previous_manifest.hash_algorithm != args.hash_algo {
+            log::warn!(
+                "Manifest digests were hashed with {} rather than {}; every file will be archived as changed",
+                previous_manifest.hash_algorithm,
+                args.hash_algo
+            );
+        }
+    }
+
+    let hash_cache =
+        args.compare_hash.as_ref().map(|compare_hash| {
+            match hash_cache::HashCache::read(compare_hash, target_path, args.hash_algo) {
+                Ok(mut hash_cache) => {
+                    if let Some(previous_manifest) = &previous_manifest {
+                        hash_cache.retain_unchanged(previous_manifest);
+                    }
+                    RefCell::new(hash_cache)
+                }
+                Err(err) => {
+                    abort(
+                        ExitCode::Failed,
+                        format!("Failed to read hash cache {}: {}", compare_hash, err),
+                    );
+                }
+            }
+        });
+
+    let mut mtime_slack = args.mtime_slack;
+    if let Some((filesystem, required_slack)) = processor::coarse_filesystem_slack(target_path) {
+        if required_slack > mtime_slack {
+            log::info!(
+                "Target lives on a {} filesystem with coarse timestamps; raising the mtime slack to {} ms",
+                filesystem,
+                required_slack.as_millis()
+            );
+            mtime_slack = required_slack;
+        }
+    }
+
+    let dictionary_id = plan.dictionary.as_ref().map(dictionary::Dictionary::id);
+    let mut journal = None;
+    let checkpoint = args.checkpoint.as_ref().map(|checkpoint_path| {
+        let resumed = if args.resume {
+            match checkpoint::Checkpoint::resume(checkpoint_path, args.hash_algo, dictionary_id) {
+                Ok(checkpoint) => {
+                    log::info!(
+                        "Resuming from {} captures in checkpoint {}",
+                        checkpoint.len(),
+                        checkpoint_path
+                    );
+                    journal = match journal::Journal::read(checkpoint_path) {
+                        Ok(journal) => journal,
+                        Err(err) => {
+                            log::warn!(
+                                "Failed to read the journal in checkpoint {}, capturing again: {}",
+                                checkpoint_path,
+                                err
+                            );
+                            None
+                        }
+                    };
+                    Some(checkpoint)
+                }
+                Err(err) => {
+                    log::warn!(
+                        "Failed to resume from checkpoint {}, starting over: {}",
+                        checkpoint_path,
+                        err
+                    );
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        match resumed.map_or_else(
+            || {
+                journal::Progress::discard(checkpoint_path);
+                checkpoint::Checkpoint::create(checkpoint_path, args.hash_algo, dictionary_id)
+            },
+            Ok,
+        ) {
+            Ok(checkpoint) => RefCell::new(checkpoint),
+            Err(err) => {
+                abort(
+                    ExitCode::Failed,
+                    format!("Failed to create checkpoint {}: {}", checkpoint_path, err),
+                );
+            }
+        }
+    });
+
+    let policy = policy::Policy::new(args.mode, &policy_overrides(args));
+
+    // zstd compresses every capture on the thread taking the snapshot, which --single-thread only
+    // makes explicit
+    log::debug!(
+        "Compressing captures with 1 worker{}",
+        if args.single_thread {
+            ", as --single-thread asks"
+        } else {
+            ""
+        }
+    );
+    let options = processor::CaptureOptions {
+        compression_level: args.compression_level,
+        level_overrides: args.level_for.clone(),
+        read_buffer_size: args.read_buffer_size.get(),
+        filters: std::mem::take(&mut plan.filters),
+        mtime_slack,
+        future_mtime_slack: args.future_mtime_slack,
+        settle: args.settle,
+        best_effort_files: policy.tolerates(policy::Compromise::Unreadable),
+        checkpoint,
+        change_detector: detector::build_detector(
+            &args.change_detection,
+            args.hash_algo,
+            args.preserve_source_atime,
+        ),
+        dereference: args.dereference,
+        hash_algorithm: args.hash_algo,
+        preserve_atime: args.preserve_source_atime,
+        dictionary: plan.dictionary.clone(),
+        metadata_only: args.metadata_only,
+        drain_fifos: args.drain_fifos,
+        hash_cache,
+        ignore_root_mtime: args.ignore_root_mtime,
+        hash_symlinks: args.hash_symlinks,
+        load_throttle: args.max_load.map(throttle::LoadThrottle::new),
+        temp_dir: plan.temp_dir.clone(),
+        min_age: args.min_age,
+        prune_empty_directories: plan.outside_window.is_some() || plan.not_owned.is_some(),
+        policy,
+        cancel: cancel::Cancellation::default(),
+    };
+
+    if let Err(err) = options
+        .cancel
+        .install_handler(ExitCode::Interrupted.code(), resume_paused)
+    {
+        log::warn!(
+            "Failed to handle signals; they'll stop the snapshot abruptly: {}",
+            err
+        );
+    }
+    if let Some(timeout) = args.timeout {
+        options.cancel.set_deadline(
+            Instant::now() + timeout,
+            options.policy.tolerates(policy::Compromise::Partial),
+        );
+    }
+    // only a snapshot runs as a service, once it can be stopped gracefully
+    notify::ready();
+
+    // an archive to append to is checked before spending time capturing
+    let append_offset = if args.append && output_path.exists() {
+        // one dictionary has to be able to read the whole archive
+        let appendable = match &plan.dictionary {
+            Some(dictionary) => dictionary::check_archive(output_path, Some(dictionary)),
+            None => Ok(()),
+        };
+        match appendable.and_then(|_| archiver::archive_end(output_path)) {
+            Ok(offset) => Some(offset),
+            Err(err) => {
+                abort(
+                    ExitCode::Failed,
+                    format!(
+                        "Failed to read archive {} to append to: {}",
+                        output_path.display(),
+                        err
+                    ),
+                );
+            }
+        }
+    } else {
+        None
+    };
+
+    let mut report = report::SnapshotReport {
+        largest: args.report_top.map(report::Largest::new),
+        breakdown: args
+            .breakdown_depth
+            .map(|depth| report::Breakdown::new(depth, args.breakdown_sort)),
+        modified_window: plan
+            .outside_window
+            .is_some()
+            .then(|| manifest::ModifiedWindow {
+                since: args
+                    .modified_since
+                    .map(|since| chrono::DateTime::<chrono::Utc>::from(since).to_rfc3339()),
+                before: args
+                    .modified_before
+                    .map(|before| chrono::DateTime::<chrono::Utc>::from(before).to_rfc3339()),
+            }),
+        ..Default::default()
+    };
+    // from here on, however the snapshot ends, the post-hooks get to clean up after it
+    hooks::arm_post_hooks(
+        args.post_hook.clone(),
+        args.hook_timeout,
+        output_path.clone(),
+    );
+    if let Some(url) = &args.webhook {
+        webhook::arm(webhook::Webhook {
+            url: url.clone(),
+            template: webhook_template,
+            timeout: args.webhook_timeout,
+            target: target_path.clone(),
+            output: output_path.clone(),
+            cancel: options.cancel.clone(),
+        });
+    }
+    if let Err(err) = hooks::run_pre_hooks(&args.pre_hook, args.hook_timeout, output_path) {
+        fail_with_report(
+            args.report.as_deref(),
+            args.output_mode,
+            &report,
+            ExitCode::HookFailed,
+            format!("Aborting the snapshot: {}", err),
+        );
+    }
+    if let (Some(address), Some(password_env)) = (&args.rcon, &args.rcon_password_env) {
+        let paused = std::env::var(password_env)
+            .map_err(|err| {
+                format!(
+                    "Failed to read the RCON password from {}: {}",
+                    password_env, err
+                )
+            })
+            .and_then(|password| {
+                rcon::pause_saving(address, &password).map_err(|err| err.to_string())
+            });
+        if let Err(err) = paused {
+            fail_with_report(
+                args.report.as_deref(),
+                args.output_mode,
+                &report,
+                ExitCode::RconFailed,
+                format!("Failed to pause saving over RCON: {}", err),
+            );
+        }
+        // whatever the flush wrote has to age past the slack to be captured on the first pass
+        log::info!(
+            "Waiting {} ms for the save to settle",
+            mtime_slack.as_millis()
+        );
+        std::thread::sleep(mtime_slack);
+    }
+    let paused_containers = match docker::pause(&args.docker_pause) {
+        Ok(guard) => guard,
+        Err(err) => {
+            fail_with_report(
+                args.report.as_deref(),
+                args.output_mode,
+                &report,
+                ExitCode::DockerFailed,
+                format!("Failed to pause containers: {}", err),
+            );
         }
     };
+    let start = SystemTime::now();
+    let resumed = journal.map(|journal| {
+        log::info!("Capture had finished; checking the journaled entries are unchanged");
+        notify::status("Checking journaled entries");
+        report.timestamp = journal.revision.clone();
+        report.hash_algorithm = args.hash_algo;
+        report.dictionary_id = dictionary_id;
+        report.metadata_only = journal.metadata_only;
+        let checkpoint = options
+            .checkpoint
+            .as_ref()
+            .expect("A journal is only read from a checkpoint")
+            .borrow();
+        match journal.resume(target_path, &checkpoint, args.hash_algo) {
+            Ok(processed) => processed,
+            Err(err) => {
+                if let journal::ResumeError::Changed(paths) = &err {
+                    log::error!("Changed since they were captured:");
+                    for path in paths {
+                        log::error!("  {}", path.display());
+                    }
+                }
+                fail_with_report(
+                    args.report.as_deref(),
+                    args.output_mode,
+                    &report,
+                    ExitCode::Failed,
+                    format!(
+                        "Refusing to resume from the journal; drop --resume to capture again: {}",
+                        err
+                    ),
+                );
+            }
+        }
+    });
+    let resuming = resumed.is_some();
+    if args.preflight && !resuming {
+        notify::status("Checking the target can be read");
+        let start = Instant::now();
+        let preflight = preflight::preflight(target_path, &options.filters, &options.cancel);
+        for (path, err) in &preflight.unreadable {
+            log::warn!("Preflight could not read {}: {}", path.display(), err);
+        }
+        log::info!(
+            "Preflight found about {} entries in {} ms",
+            preflight.entries,
+            start.elapsed().as_millis()
+        );
+        if !preflight.unreadable.is_empty() {
+            log::warn!(
+                "Preflight found {} paths which can't be read, which the capture can't read either",
+                preflight.unreadable.len()
+            );
+        }
+        report.estimated_entries = Some(preflight.entries);
+    }
+    let processed = match (resumed, &args.files_from) {
+        (Some(resumed), _) => Ok(resumed),
+        (None, Some(files_from)) => {
+            let files = match read_file_list(
+                files_from,
+                target_path,
+                &plan.excluded,
+                &options.policy,
+                &mut report,
+            ) {
+                Ok(files) => files,
+                Err(err) => {
+                    fail_with_report(
+                        args.report.as_deref(),
+                        args.output_mode,
+                        &report,
+                        ExitCode::Failed,
+                        format!("Failed to read file list {}: {}", files_from, err),
+                    );
+                }
+            };
+            processor::process_files(&files, args.iteration_retries, &options, &mut report)
+        }
+        (None, None) => {
+            processor::process_directory(target_path, args.iteration_retries, &options, &mut report)
+        }
+    };
+    // everything is captured, so the target may change again while the archive is written
+    drop(paused_containers);
+    rcon::resume_saving();
+    let processed = match processed {
+        Ok(processed) => processed,
+        Err(processor::ProcessError::Cancelled) => exit_stopped(
+            args.report.as_deref(),
+            args.output_mode,
+            args.checkpoint.as_deref(),
+            &options.cancel,
+            &report,
+        ),
+        Err(err @ processor::ProcessError::Refused(_)) => {
+            fail_with_report(
+                args.report.as_deref(),
+                args.output_mode,
+                &report,
+                ExitCode::CaptureIncomplete,
+                format!("{}; not writing an archive", err),
+            );
+        }
+        Err(err) => {
+            fail_with_report(
+                args.report.as_deref(),
+                args.output_mode,
+                &report,
+                ExitCode::from(&err),
+                format!("Failed to process directory: {}", err),
+            );
+        }
+    };
+    if !report.partial && !resuming {
+        log::info!("Captured a stable snapshot in {} passes", report.iterations);
+    }
+    // the snapshot stands for the instant asked for, however long after it was taken
+    if let (Some(revision), false) = (args.at_revision, report.partial) {
+        report.stamp(revision);
+    }
+    if !processed.failed.is_empty() {
+        log::warn!(
+            "Skipped {} paths which could not be captured:",
+            processed.failed.len()
+        );
+        for failed in &processed.failed {
+            log::warn!("  {}: {}", failed.path.display(), failed.error);
+        }
+    }
+    if args.fail_on_empty && processed.file_count == 0 {
+        fail_with_report(
+            args.report.as_deref(),
+            args.output_mode,
+            &report,
+            ExitCode::SnapshotEmpty,
+            "Refusing to write a snapshot without any files".to_string(),
+        );
+    }
+    let size_estimate = archiver::estimate_size(processed.entry_count, processed.encoded_bytes);
+    let mut entries = processed.entries;
+    // a new archive written from a finished capture can be resumed from the checkpoint
+    if let (Some(checkpoint_path), None, false, false) =
+        (&args.checkpoint, append_offset, report.partial, resuming)
+    {
+        let compiled: Vec<processor::Entry> = entries.collect();
+        let journal = journal::Journal::new(target_path, &compiled, report.timestamp.clone());
+        match journal.write(checkpoint_path) {
+            Ok(_) => log::debug!("Journaled {} entries", compiled.len()),
+            Err(err) => log::warn!(
+                "Failed to journal the captured entries; writing the archive can't be resumed: {}",
+                err
+            ),
+        }
+        entries = Box::new(compiled.into_iter());
+    }
+
+    Captured {
+        entries,
+        failed: processed.failed,
+        size_estimate,
+        report,
+        options,
+        previous_manifest,
+        append_offset,
+        resuming,
+        start,
+    }
+}
+
+/// Writes the captured entries in the planned format, along with the manifest, and verifies and
+/// indexes the archive if asked to. Returns when the snapshot was taken.
+fn write_archive(
+    args: &SitArgs,
+    plan: &mut SnapshotPlan,
+    captured: &mut Captured,
+) -> chrono::DateTime<chrono::Utc> {
+    let target_path = &plan.target_path;
+    let output_path = &plan.output_path;
+    let append_offset = captured.append_offset;
+    let report = &mut captured.report;
+    // partial snapshots aren't stamped with a revision, so they're known by when they were taken
+    let taken = report
+        .timestamp
+        .as_deref()
+        .and_then(|timestamp| chrono::DateTime::parse_from_rfc3339(timestamp).ok())
+        .map_or_else(chrono::Utc::now, |taken| taken.with_timezone(&chrono::Utc));
+    report.id = Some(provenance::new_id(target_path, &taken));
+    report.invoked_as = plan.invoked_as.clone();
 
-    match archiver::create_tarball(&target_path, entries, &PathBuf::from(&args.output_file)) {
-        Ok(_) => log::info!(
-            "Successfully created tarball at {}",
-            PathBuf::from(&args.output_file)
-                .canonicalize()
-                .unwrap()
-                .display()
+    // a file list doesn't describe what was removed from the target
+    let mut diff = captured
+        .previous_manifest
+        .as_ref()
+        .map(|previous_manifest| {
+            manifest::ManifestDiff::new(previous_manifest, args.files_from.is_none())
+        });
+    // files modified since the revision weren't removed, and held whatever they held before
+    if let (Some(diff), Some(after_revision)) = (&mut diff, &plan.after_revision) {
+        for path in after_revision.borrow().iter() {
+            if let Ok(relative_path) = archiver::find_relative_path(target_path, path) {
+                diff.carry_over(&relative_path);
+            }
+        }
+    }
+    let tar_options = archiver::TarOptions {
+        batch_threshold: args.batch_small_files,
+        compression_level: args.compression_level,
+        dictionary: plan.dictionary.take(),
+        file_mode: args.chmod_files,
+        dir_mode: args.chmod_dirs,
+        preallocate: args.preallocate.then_some(captured.size_estimate),
+        output_mode: Some(args.output_mode),
+        embed_manifest: args.checksum.is_some(),
+        progress: args
+            .checkpoint
+            .as_ref()
+            .filter(|_| append_offset.is_none() && !report.partial)
+            .map(PathBuf::from),
+        policy: captured.options.policy.clone(),
+        cancel: captured.options.cancel.clone(),
+    };
+    // an archive the journaled entries were being written into is carried on where it was synced
+    let progress = args
+        .checkpoint
+        .as_ref()
+        .filter(|_| captured.resuming && append_offset.is_none())
+        .and_then(
+            |checkpoint_path| match journal::Progress::read(checkpoint_path) {
+                Ok(progress) => progress,
+                Err(err) => {
+                    log::warn!(
+                        "Failed to read how far the archive got, writing it anew: {}",
+                        err
+                    );
+                    None
+                }
+            },
+        )
+        .filter(|progress| {
+            let usable = progress
+                .partial_path
+                .metadata()
+                .is_ok_and(|metadata| metadata.len() >= progress.offset)
+                && progress.partial_path.parent() == output_path.parent();
+            if !usable {
+                log::warn!(
+                    "Partial archive {} can't be carried on; writing the archive anew",
+                    progress.partial_path.display()
+                );
+                if let Err(err) = std::fs::remove_file(&progress.partial_path) {
+                    log::debug!(
+                        "Failed to remove {}: {}",
+                        progress.partial_path.display(),
+                        err
+                    );
+                }
+            }
+            usable
+        });
+    notify::status("Archiving");
+    let entries = std::mem::replace(&mut captured.entries, Box::new(std::iter::empty()));
+    let written = match (plan.format, append_offset, &progress) {
+        (OutputFormat::Cpio(compression), _, _) => cpio::create_cpio(
+            target_path,
+            entries,
+            output_path,
+            compression,
+            &tar_options,
+            report,
+        ),
+        (OutputFormat::Zip, _, _) => zip_archive::create_zip(
+            target_path,
+            entries,
+            output_path,
+            args.zip_compression,
+            &tar_options,
+            report,
+        ),
+        (OutputFormat::Farm, _, _) => farm::create_farm(
+            target_path,
+            entries,
+            output_path,
+            args.link_dest.as_deref().map(Path::new),
+            args.hash_algo,
+            &tar_options,
+            report,
+        ),
+        (OutputFormat::SquashFs, _, _) => {
+            squashfs::create_squashfs(target_path, entries, output_path, &tar_options, report)
+        }
+        (OutputFormat::Tar, Some(offset), _) => archiver::append_tarball(
+            target_path,
+            entries,
+            output_path,
+            offset,
+            &tar_options,
+            diff.as_mut(),
+            report,
         ),
+        (OutputFormat::Tar, None, Some(progress)) => archiver::resume_tarball(
+            target_path,
+            entries,
+            output_path,
+            progress,
+            &tar_options,
+            diff.as_mut(),
+            report,
+        ),
+        (OutputFormat::Tar, None, None) => archiver::create_tarball(
+            target_path,
+            entries,
+            output_path,
+            &tar_options,
+            diff.as_mut(),
+            report,
+        ),
+    };
+    let output_kind = plan.format.describe();
+    let (archived, current_manifest) = match written {
+        Ok(archived) => {
+            log::info!(
+                "Successfully {} {} at {}",
+                if append_offset.is_some() {
+                    "appended to"
+                } else {
+                    "created"
+                },
+                output_kind,
+                output_path.canonicalize().unwrap().display()
+            );
+            archived
+        }
+        Err(_) if captured.options.cancel.is_cancelled() => {
+            log::warn!(
+                "Left {} as it was before the snapshot",
+                output_path.display()
+            );
+            exit_stopped(
+                args.report.as_deref(),
+                args.output_mode,
+                args.checkpoint.as_deref(),
+                &captured.options.cancel,
+                report,
+            );
+        }
+        Err(err) if report.refusal.is_some() => {
+            log::warn!(
+                "Left {} as it was before the snapshot",
+                output_path.display()
+            );
+            fail_with_report(
+                args.report.as_deref(),
+                args.output_mode,
+                report,
+                ExitCode::CaptureIncomplete,
+                format!("{}; not writing an archive", err),
+            );
+        }
         Err(err) => {
-            log::error!("Failed to create tarball: {}", err);
-            std::process::exit(1);
+            fail_with_report(
+                args.report.as_deref(),
+                args.output_mode,
+                report,
+                ExitCode::ArchiveWriteFailed,
+                format!("Failed to write archive: {}", err),
+            );
+        }
+    };
+    if let Some(diff) = &diff {
+        log::info!(
+            "Snapshotted incrementally: {} unchanged files, {} removed paths",
+            diff.unchanged,
+            report.whiteouts
+        );
+    }
+    summarize_capture(args, plan, &captured.options, report);
+
+    if let Some(manifest_path) = &args.manifest {
+        match current_manifest.write(manifest_path, Some(args.output_mode)) {
+            Ok(_) => log::info!("Successfully wrote manifest at {}", manifest_path),
+            Err(err) => {
+                fail_with_report(
+                    args.report.as_deref(),
+                    args.output_mode,
+                    report,
+                    ExitCode::ArchiveWriteFailed,
+                    format!("Failed to write manifest: {}", err),
+                );
+            }
+        }
+    }
+    // without the cache the next snapshot only has to hash every file again
+    if let (Some(compare_hash), Some(hash_cache)) =
+        (&args.compare_hash, &captured.options.hash_cache)
+    {
+        match hash_cache
+            .borrow()
+            .write(compare_hash, Some(args.output_mode))
+        {
+            Ok(_) => log::info!("Successfully wrote hash cache at {}", compare_hash),
+            Err(err) => log::warn!("Failed to write hash cache {}: {}", compare_hash, err),
         }
     }
+
+    if args.verify_after_write {
+        notify::status("Verifying the archive");
+        let verify_start = Instant::now();
+        let verified = match plan.format {
+            OutputFormat::Cpio(compression) => verifier::verify_cpio(
+                target_path,
+                output_path,
+                compression,
+                &archived,
+                args.hash_algo,
+            ),
+            OutputFormat::Zip => verifier::verify_zip(output_path, &archived, args.hash_algo),
+            OutputFormat::Farm => {
+                verifier::verify_farm(target_path, output_path, &archived, args.hash_algo)
+            }
+            OutputFormat::SquashFs => {
+                verifier::verify_squashfs(target_path, output_path, &archived, args.hash_algo)
+            }
+            OutputFormat::Tar => verifier::verify_tarball(
+                output_path,
+                append_offset.unwrap_or(0),
+                &archived,
+                args.hash_algo,
+                tar_options.dictionary.as_ref(),
+            ),
+        }
+        // the manifest written beside the archive has to describe it as well
+        .and_then(|_| match &args.manifest {
+            Some(manifest_path) => {
+                verifier::check_manifest(&manifest::Manifest::read(manifest_path)?, &archived)
+            }
+            None => Ok(()),
+        });
+        report.durations.verify_ms = report::millis(verify_start.elapsed());
+        match verified {
+            Ok(_) => log::info!("Successfully verified {}", output_kind),
+            Err(err) => {
+                fail_with_report(
+                    args.report.as_deref(),
+                    args.output_mode,
+                    report,
+                    ExitCode::VerificationFailed,
+                    format!("Failed to verify {}: {}", output_kind, err),
+                );
+            }
+        }
+    }
+    // the archive is complete and in place, so the checksum describes exactly what's on disk
+    if let Some(algorithm) = args.checksum {
+        match checksum::write_sidecar(output_path, algorithm, Some(args.output_mode)) {
+            Ok(sidecar) => log::info!("Successfully wrote checksum at {}", sidecar.display()),
+            Err(err) => {
+                fail_with_report(
+                    args.report.as_deref(),
+                    args.output_mode,
+                    report,
+                    ExitCode::ArchiveWriteFailed,
+                    format!("Failed to write checksum: {}", err),
+                );
+            }
+        }
+    }
+    if args.index {
+        let sidecar = index::sidecar_path(output_path);
+        let written = index::Index::build(output_path, tar_options.dictionary.as_ref())
+            .and_then(|index| index.write(&sidecar, Some(args.output_mode)));
+        match written {
+            Ok(_) => log::info!("Successfully wrote index at {}", sidecar.display()),
+            Err(err) => {
+                fail_with_report(
+                    args.report.as_deref(),
+                    args.output_mode,
+                    report,
+                    ExitCode::ArchiveWriteFailed,
+                    format!("Failed to write index: {}", err),
+                );
+            }
+        }
+    }
+    taken
+}
+
+/// Logs what the capture left out and how it went, recording the counts in the report.
+fn summarize_capture(
+    args: &SitArgs,
+    plan: &SnapshotPlan,
+    options: &processor::CaptureOptions,
+    report: &mut report::SnapshotReport,
+) {
+    report.compression.log();
+    if let Some(largest) = &report.largest {
+        largest.log();
+    }
+    if let Some(breakdown) = &report.breakdown {
+        breakdown.log();
+    }
+    if let Some(throttle) = &options.load_throttle {
+        if !throttle.paused().is_zero() {
+            log::info!(
+                "Paused the capture for {} s while the system was busy",
+                throttle.paused().as_secs()
+            );
+        }
+    }
+    if let Some(outside_window) = &plan.outside_window {
+        report.outside_window = outside_window.borrow().len();
+        log::info!(
+            "Skipped {} files modified outside the window",
+            report.outside_window
+        );
+    }
+    if let Some(not_owned) = &plan.not_owned {
+        report.not_owned = not_owned.borrow().len();
+        log::info!("Skipped {} entries owned by others", report.not_owned);
+    }
+    if let Some(after_revision) = &plan.after_revision {
+        report.after_revision = after_revision.borrow().len();
+        log::info!(
+            "Skipped {} files modified after the revision",
+            report.after_revision
+        );
+    }
+    if args.min_age.is_some() {
+        log::info!(
+            "Skipped {} files modified within the minimum age",
+            report.too_recent
+        );
+    }
+    if let Some(hidden_entries) = &plan.hidden_entries {
+        report.hidden = hidden_entries.borrow().len();
+        log::info!("Skipped {} hidden entries", report.hidden);
+    }
+    if let Some(cache_directories) = &plan.cache_directories {
+        log::info!(
+            "Skipped {} directories tagged as caches",
+            cache_directories.borrow().len()
+        );
+    }
+    if let Some(hash_cache) = &options.hash_cache {
+        log::info!(
+            "Reused the cached digests of {} files",
+            hash_cache.borrow().reused
+        );
+    }
+}
+
+/// Records the written snapshot in the stamp file and the catalog, decides how the snapshot ends,
+/// and runs the post-hooks and notifications with that ending.
+fn finish(
+    args: &SitArgs,
+    plan: &SnapshotPlan,
+    captured: Captured,
+    taken: chrono::DateTime<chrono::Utc>,
+) {
+    let Captured {
+        failed,
+        report,
+        options,
+        start,
+        ..
+    } = captured;
+    if report.partial {
+        exit_stopped(
+            args.report.as_deref(),
+            args.output_mode,
+            args.checkpoint.as_deref(),
+            &options.cancel,
+            &report,
+        );
+    }
+    if let Some(checkpoint_path) = &args.checkpoint {
+        match std::fs::remove_dir_all(checkpoint_path) {
+            Ok(_) => log::debug!("Removed checkpoint {}", checkpoint_path),
+            Err(err) => log::warn!("Failed to remove checkpoint {}: {}", checkpoint_path, err),
+        }
+    }
+
+    if let Some(stamp_file) = &args.stamp_file {
+        if !failed.is_empty() {
+            // the next run must pick up the paths this one skipped
+            log::warn!(
+                "Not updating stamp file {} since the snapshot is incomplete",
+                stamp_file
+            );
+        } else {
+            let stamp = chrono::DateTime::<chrono::Utc>::from(start).to_rfc3339();
+            let written = archiver::create_file(stamp_file, Some(args.output_mode))
+                .and_then(|mut file| file.write_all((stamp + "\n").as_bytes()));
+            match written {
+                Ok(_) => log::info!("Successfully wrote stamp file at {}", stamp_file),
+                Err(err) => {
+                    fail_with_report(
+                        args.report.as_deref(),
+                        args.output_mode,
+                        &report,
+                        ExitCode::ArchiveWriteFailed,
+                        format!("Failed to write stamp file: {}", err),
+                    );
+                }
+            }
+        }
+    }
+
+    if let Some(catalog_path) = &args.catalog {
+        let catalog_path = resolve_path(Path::new(catalog_path));
+        let recorded = catalog::read(&catalog_path).and_then(|records| {
+            let manifest = args
+                .manifest
+                .as_ref()
+                .map(|manifest_path| resolve_path(Path::new(manifest_path)));
+            // the snapshot diffed against is the latest one which wrote the manifest
+            let parent = args.since_manifest.as_ref().and_then(|since_manifest| {
+                let since_manifest = resolve_path(Path::new(since_manifest));
+                records
+                    .iter()
+                    .rev()
+                    .find(|record| record.manifest.as_ref() == Some(&since_manifest))
+                    .map(|record| record.id.clone())
+            });
+            let record = catalog::Record {
+                id: report.id.clone().unwrap_or_default(),
+                parent,
+                target: plan.target_path.clone(),
+                output: resolve_path(&plan.output_path),
+                manifest,
+                timestamp: taken.to_rfc3339(),
+                files: report.files,
+                directories: report.directories,
+                symlinks: report.symlinks,
+                bytes_read: report.bytes_read,
+                bytes_written: report.bytes_written,
+                duration_ms: report::millis(start.elapsed().unwrap_or_default()),
+            };
+            catalog::append(&catalog_path, &record, Some(args.output_mode))?;
+            Ok(record.id)
+        });
+        match recorded {
+            Ok(id) => log::info!(
+                "Recorded snapshot {} in catalog {}",
+                id,
+                catalog_path.display()
+            ),
+            Err(err) => {
+                fail_with_report(
+                    args.report.as_deref(),
+                    args.output_mode,
+                    &report,
+                    ExitCode::ArchiveWriteFailed,
+                    format!("Failed to record the snapshot in the catalog: {}", err),
+                );
+            }
+        }
+    }
+    let warned = report.warnings.counts().clone();
+    if !warned.is_empty() {
+        summarize_warnings(&warned);
+    }
+    // refused compromises stopped the snapshot before anything was written, so only the ones the
+    // policy reports are left to decide how it ends
+    let compromises: Vec<_> = report
+        .compromises()
+        .filter(|(_, compromise)| options.policy.reports(*compromise))
+        .collect();
+    if !compromises.is_empty() {
+        summarize_compromises(&compromises);
+        let error = format!(
+            "Finished in {} ms, leaving out {} paths which could not be captured faithfully.",
+            start.elapsed().unwrap().as_millis(),
+            compromises.len()
+        );
+        log::warn!("{}", error);
+        exit_with_report(
+            args.report.as_deref(),
+            args.output_mode,
+            &report,
+            ExitCode::PartialSuccess,
+            &error,
+        );
+    }
+    let max_warnings = if args.fail_on_warning {
+        Some(0)
+    } else {
+        args.max_warnings
+    };
+    let total: usize = warned.values().sum();
+    if let Some(max_warnings) = max_warnings.filter(|max_warnings| total > *max_warnings) {
+        let kinds: Vec<String> = warned.keys().map(ToString::to_string).collect();
+        fail_with_report(
+            args.report.as_deref(),
+            args.output_mode,
+            &report,
+            ExitCode::TooManyWarnings,
+            format!(
+                "Finished with {} warnings, more than the {} allowed ({})",
+                total,
+                max_warnings,
+                kinds.join(", ")
+            ),
+        );
+    }
+    write_report(args.report.as_deref(), args.output_mode, &report);
+    hooks::run_post_hooks(0, &report);
+    webhook::send(0, &report, None);
+    ping::success();
     log::info!(
         "Finished everything successfully in {} ms.",
         start.elapsed().unwrap().as_millis()
     );
 }
+
+fn write_report(report_path: Option<&str>, output_mode: u32, report: &report::SnapshotReport) {
+    if let Some(report_path) = report_path {
+        match report.write(report_path, Some(output_mode)) {
+            Ok(_) => log::info!("Successfully wrote report at {}", report_path),
+            Err(err) => log::error!("Failed to write report: {}", err),
+        }
+    }
+    match report.write_skipped(Some(output_mode)) {
+        Ok(Some(skipped_path)) => log::info!(
+            "Listed {} skipped paths at {}",
+            report.skipped.len(),
+            skipped_path.display()
+        ),
+        Ok(None) => {}
+        Err(err) => log::error!("Failed to list skipped paths: {}", err),
+    }
+}
+
+fn exit_with_report(
+    report_path: Option<&str>,
+    output_mode: u32,
+    report: &report::SnapshotReport,
+    code: ExitCode,
+    error: &str,
+) -> ! {
+    write_report(report_path, output_mode, report);
+    resume_paused();
+    hooks::run_post_hooks(code.code(), report);
+    webhook::send(code.code(), report, Some(error));
+    ping::fail(error);
+    code.exit();
+}
+
+/// Logs how many warnings of every kind the snapshot ran into.
+fn summarize_warnings(warned: &BTreeMap<warnings::Warning, usize>) {
+    log::warn!("Ran into {} warnings:", warned.values().sum::<usize>());
+    for (warning, count) in warned {
+        log::warn!("  {}: {}", warning, count);
+    }
+}
+
+/// Logs the paths a snapshot left out as compromises, grouped by compromise, naming only the
+/// first few of every group.
+fn summarize_compromises(compromises: &[(&report::SkippedPath, policy::Compromise)]) {
+    let mut by_compromise: BTreeMap<policy::Compromise, Vec<&Path>> = BTreeMap::new();
+    for (skipped, compromise) in compromises {
+        by_compromise
+            .entry(*compromise)
+            .or_default()
+            .push(&skipped.path);
+    }
+    log::warn!(
+        "Archived what could be captured, leaving out {} paths:",
+        compromises.len()
+    );
+    for (compromise, mut paths) in by_compromise {
+        paths.sort();
+        log::warn!("  {} ({}):", compromise, paths.len());
+        for path in paths.iter().take(SUMMARIZED_PATHS) {
+            log::warn!("    {}", path.display());
+        }
+        if paths.len() > SUMMARIZED_PATHS {
+            log::warn!("    and {} more", paths.len() - SUMMARIZED_PATHS);
+        }
+    }
+}
+
+/// Logs why the snapshot failed before it got underway, and exits.
+fn abort(code: ExitCode, error: String) -> ! {
+    log::error!("{}", error);
+    ping::fail(&error);
+    code.exit();
+}
+
+/// Logs why the snapshot failed, and exits.
+fn fail_with_report(
+    report_path: Option<&str>,
+    output_mode: u32,
+    report: &report::SnapshotReport,
+    code: ExitCode,
+    error: String,
+) -> ! {
+    log::error!("{}", error);
+    exit_with_report(report_path, output_mode, report, code, &error);
+}
+
+/// Spells out the shorthand policy flags as the compromises they ignore, tolerate or refuse.
+fn policy_overrides(args: &SitArgs) -> policy::Overrides {
+    let mut overrides = policy::Overrides {
+        ignore: Vec::new(),
+        tolerate: args.tolerate.clone(),
+        refuse: args.refuse.clone(),
+    };
+    if args.ignore_file_errors {
+        overrides.ignore.push(policy::Compromise::Unreadable);
+    }
+    if args.keep_going {
+        overrides.tolerate.push(policy::Compromise::Unreadable);
+    }
+    if args.allow_partial {
+        overrides.tolerate.push(policy::Compromise::Partial);
+    }
+    if args.strict {
+        overrides.refuse.push(policy::Compromise::Unreadable);
+    }
+    overrides
+}
+
+/// Lets everything paused for the snapshot carry on.
+fn resume_paused() {
+    docker::unpause();
+    rcon::resume_saving();
+}
+
+/// Logs how far a snapshot stopped by a signal or its timeout got, and exits.
+fn exit_stopped(
+    report_path: Option<&str>,
+    output_mode: u32,
+    checkpoint: Option<&str>,
+    cancel: &cancel::Cancellation,
+    report: &report::SnapshotReport,
+) -> ! {
+    let (stopped, code) = if cancel.timed_out() {
+        ("timed out", ExitCode::TimedOut)
+    } else {
+        ("cancelled", ExitCode::Interrupted)
+    };
+    log::warn!(
+        "Snapshot {} after {} passes reading {} bytes, with {} files, {} directories and {} symlinks archived",
+        stopped,
+        report.iterations,
+        report.bytes_read,
+        report.files,
+        report.directories,
+        report.symlinks
+    );
+    if let Some(checkpoint) = checkpoint {
+        log::info!(
+            "Captures are kept in {}; pass --resume to pick up from them",
+            checkpoint
+        );
+    }
+    exit_with_report(
+        report_path,
+        output_mode,
+        report,
+        code,
+        &format!("Snapshot {}", stopped),
+    );
+}
+
+/// Parses an octal permission mode such as `644` or `0755`.
+fn parse_mode(value: &str) -> Result<u32, String> {
+    match u32::from_str_radix(value, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        Ok(_) => Err("mode must be at most 7777".to_string()),
+        Err(_) => Err("mode must be an octal number".to_string()),
+    }
+}
+
+/// Parses a compression level override such as `*.mca=19`.
+fn parse_level_for(value: &str) -> Result<processor::LevelOverride, String> {
+    let (glob, level) = value
+        .rsplit_once('=')
+        .ok_or_else(|| "expected <glob>=<level>".to_string())?;
+    let level: i32 = level
+        .trim()
+        .parse()
+        .map_err(|err| format!("invalid level: {}", err))?;
+    let levels = zstd::compression_level_range();
+    if !levels.contains(&level) {
+        return Err(format!(
+            "level must be between {} and {}",
+            levels.start(),
+            levels.end()
+        ));
+    }
+    let glob = globset::Glob::new(glob)
+        .map_err(|err| format!("invalid glob: {}", err))?
+        .compile_matcher();
+    Ok(processor::LevelOverride { glob, level })
+}
+
+fn parse_glob(value: &str) -> Result<globset::Glob, String> {
+    globset::Glob::new(value).map_err(|err| format!("invalid glob: {}", err))
+}
+
+/// Parses an instant given as an RFC 3339 timestamp or as `@<seconds since the epoch>`.
+fn parse_instant(value: &str) -> Result<SystemTime, String> {
+    let value = value.trim();
+    match value.strip_prefix('@') {
+        Some(seconds) => seconds
+            .parse()
+            .map(|seconds| UNIX_EPOCH + Duration::from_secs(seconds))
+            .map_err(|err| format!("invalid epoch seconds: {}", err)),
+        None => chrono::DateTime::parse_from_rfc3339(value)
+            .map(SystemTime::from)
+            .map_err(|err| format!("invalid RFC 3339 timestamp: {}", err)),
+    }
+}
+
+/// Parses a bound of a modification window, given as how long before now or as an instant.
+fn parse_window_bound(value: &str) -> Result<SystemTime, String> {
+    match humantime::parse_duration(value.trim()) {
+        Ok(ago) => SystemTime::now()
+            .checked_sub(ago)
+            .ok_or_else(|| format!("{} ago is before the epoch", value.trim())),
+        Err(_) => parse_instant(value)
+            .map_err(|err| format!("{}; expected a duration such as 24h or an instant", err)),
+    }
+}
+
+/// Reads the list of files to capture, keeping only regular files that live inside the target.
+fn read_file_list(
+    files_from: &str,
+    target_path: &Path,
+    excluded: &[PathBuf],
+    policy: &policy::Policy,
+    report: &mut report::SnapshotReport,
+) -> std::io::Result<Vec<PathBuf>> {
+    let reader: Box<dyn BufRead> = if files_from == "-" {
+        Box::new(std::io::stdin().lock())
+    } else {
+        Box::new(BufReader::new(File::open(files_from)?))
+    };
+
+    let mut files = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        let path = match target_path.join(&line).canonicalize() {
+            Ok(path) => path,
+            Err(err) => {
+                log::error!("Failed to resolve listed file {}: {}", line, err);
+                report.skip(
+                    policy,
+                    target_path.join(&line),
+                    report::SkipCode::Unreadable,
+                    err,
+                );
+                continue;
+            }
+        };
+        if !path.starts_with(target_path) {
+            log::error!("Listed file is not part of the target: {}", path.display());
+            report.skip(
+                policy,
+                &path,
+                report::SkipCode::OutsideTarget,
+                "listed file is outside the target",
+            );
+            continue;
+        }
+        if excluded.contains(&path) {
+            log::error!(
+                "Listed file is excluded from the snapshot: {}",
+                path.display()
+            );
+            report.skip(
+                policy,
+                &path,
+                report::SkipCode::Excluded,
+                "listed file is excluded from the snapshot",
+            );
+            continue;
+        }
+        if !path.is_file() {
+            log::error!("Listed path is not a file: {}", path.display());
+            report.skip(
+                policy,
+                &path,
+                report::SkipCode::Unreadable,
+                "listed path is not a file",
+            );
+            continue;
+        }
+        files.push(path);
+    }
+    files.sort();
+    files.dedup();
+    Ok(files)
+}
+
+/// Resolves a path which may not exist yet by canonicalizing its closest existing ancestor.
+fn resolve_path(path: &Path) -> PathBuf {
+    if let Ok(canonical_path) = path.canonicalize() {
+        return canonical_path;
+    }
+    let absolute_path = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+    match (absolute_path.parent(), absolute_path.file_name()) {
+        (Some(parent), Some(name)) => resolve_path(parent).join(name),
+        _ => absolute_path,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seconds(instant: SystemTime) -> u64 {
+        instant.duration_since(UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    #[test]
+    fn instants_across_a_transition_are_told_apart_by_their_offsets() {
+        // 02:30 on the night clocks fall back in central Europe happens twice
+        let summer = parse_instant("2026-10-25T02:30:00+02:00").unwrap();
+        let winter = parse_instant("2026-10-25T02:30:00+01:00").unwrap();
+        assert_eq!(seconds(winter) - seconds(summer), 3600);
+        assert_eq!(summer, parse_instant("2026-10-25T00:30:00Z").unwrap());
+
+        // 02:30 on the night clocks go forward never happens, but the offset still places it
+        let skipped = parse_instant("2026-03-29T02:30:00+01:00").unwrap();
+        assert_eq!(skipped, parse_instant("2026-03-29T03:30:00+02:00").unwrap());
+        assert_eq!(
+            skipped,
+            parse_instant(&format!("@{}", seconds(skipped))).unwrap()
+        );
+    }
+
+    #[test]
+    fn instants_without_an_offset_are_refused() {
+        // a local time alone is ambiguous in the hour repeated when clocks fall back
+        assert!(parse_instant("2026-10-25T02:30:00").is_err());
+    }
+
+    #[test]
+    fn window_bounds_without_an_offset_are_refused() {
+        let err = parse_window_bound("2026-10-25 02:30").unwrap_err();
+        assert!(err.contains("expected a duration"), "{}", err);
+    }
+
+    #[test]
+    fn window_bounds_given_as_durations_count_elapsed_time() {
+        // a day ago is 24 hours ago even on the days clocks change
+        let before = seconds(SystemTime::now());
+        let bound = seconds(parse_window_bound(" 1day ").unwrap());
+        let after = seconds(SystemTime::now());
+        assert!(before - 86400 <= bound && bound <= after - 86400);
+        assert_eq!(
+            parse_window_bound("2026-10-25T02:30:00+01:00").unwrap(),
+            parse_instant("2026-10-25T01:30:00Z").unwrap()
+        );
+        assert_eq!(parse_window_bound("@0").unwrap(), UNIX_EPOCH);
+    }
+
+    #[test]
+    fn reading_through_an_empty_buffer_is_refused() {
+        let with_buffer = |size: &str| {
+            SitArgs::try_parse_from(["saved_in_time", "-t", "world", "--read-buffer-size", size])
+        };
+        assert!(with_buffer("0").is_err());
+        assert_eq!(with_buffer("1").unwrap().read_buffer_size.get(), 1);
+    }
+
+    /// How a snapshot with the policy flags treats unreadable paths.
+    fn unreadable(flags: &[&str]) -> policy::Treatment {
+        let args = SitArgs::try_parse_from(
+            ["saved_in_time", "-t", "world", "-o", "world.tar.zst"]
+                .iter()
+                .chain(flags),
+        )
+        .unwrap();
+        policy::Policy::new(args.mode, &policy_overrides(&args))
+            .treatment(policy::Compromise::Unreadable)
+    }
+
+    #[test]
+    fn the_shorthands_take_their_place_among_tolerating_and_refusing() {
+        use policy::Treatment::{Ignore, Refuse, Report};
+        assert_eq!(unreadable(&["--mode", "strict", "--keep-going"]), Report);
+        assert_eq!(unreadable(&["--mode", "best-effort", "--strict"]), Refuse);
+        // refusing wins over tolerating, however either is spelled
+        assert_eq!(
+            unreadable(&["--strict", "--tolerate", "unreadable"]),
+            Refuse
+        );
+        assert_eq!(
+            unreadable(&["--keep-going", "--refuse", "unreadable"]),
+            Refuse
+        );
+        // ignoring comes first, and tolerating leaves what it ignored alone
+        let ignored = [
+            "--mode",
+            "strict",
+            "--ignore-file-errors",
+            "--tolerate",
+            "unreadable",
+        ];
+        assert_eq!(unreadable(&ignored), Ignore);
+        assert_eq!(
+            unreadable(&["--ignore-file-errors", "--refuse", "unreadable"]),
+            Refuse
+        );
+    }
+}
@@ -0,0 +1,380 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use serde::{Deserialize, Serialize, Serializer};
+use tar::Archive;
+
+use crate::archiver::{create_file, find_relative_path};
+use crate::hashing::HashAlgorithm;
+use crate::policy::Compromise;
+use crate::processor::{Entry, EntryType};
+use crate::provenance;
+
+/// Version of the manifest format written by this build. Bumped whenever a change would make
+/// older readers misread a manifest.
+pub const MANIFEST_VERSION: u32 = 1;
+
+/// Name of the entry at the archive root holding the manifest of the snapshot, for snapshots which
+/// record it in the archive.
+pub const EMBEDDED_MANIFEST_NAME: &str = ".sit-manifest.json";
+
+/// Describes the full state of the target captured by a snapshot, so later snapshots can be taken
+/// relative to it.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Manifest {
+    /// Manifests written before the format was versioned are version 1.
+    #[serde(default = "initial_version")]
+    pub version: u32,
+    /// Algorithm the digests of files were hashed with.
+    #[serde(default)]
+    pub hash_algorithm: HashAlgorithm,
+    /// The window files had to be modified within to be captured, when one was given.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modified_window: Option<ModifiedWindow>,
+    pub entries: Vec<ManifestEntry>,
+    /// Paths the snapshot couldn't capture faithfully and left out.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub compromises: Vec<Compromised>,
+}
+
+/// A path left out of a snapshot for a compromise, rather than on request.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Compromised {
+    /// The path in the archive, nested under the target directory's name, unless it lies outside
+    /// the target.
+    #[serde(serialize_with = "serialize_portable")]
+    pub path: PathBuf,
+    pub compromise: Compromise,
+    pub reason: String,
+}
+
+/// Bounds on when files were modified for a snapshot to capture them, in RFC 3339. Files modified
+/// at either bound are left out.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ModifiedWindow {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub since: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub before: Option<String>,
+}
+
+fn initial_version() -> u32 {
+    1
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ManifestEntry {
+    #[serde(serialize_with = "serialize_portable")]
+    pub path: PathBuf,
+    pub kind: ManifestEntryKind,
+    pub size: u64,
+    pub mtime: u64,
+    /// Digest of a file's content, or of where a symlink points when symlinks are hashed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub digest: Option<String>,
+    /// Whether the file is hard linked from paths the snapshot doesn't hold, so restoring it
+    /// won't recreate the link.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub partial_hardlink: bool,
+    /// Number of entries listed in a directory when it was captured, so a restore or verify can
+    /// tell when some of them went missing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub children: Option<usize>,
+    /// Bytes a file's content took once compressed on its own, as captured, for telling how well
+    /// it compresses. Missing when its content wasn't captured.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compressed_size: Option<u64>,
+    /// Compression level the file's content was captured at, alongside its compressed size.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub level: Option<i32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ManifestEntryKind {
+    File,
+    Directory,
+    Symlink,
+}
+
+/// Compares a snapshot against the manifest of an earlier snapshot as the snapshot's entries
+/// stream past.
+pub struct ManifestDiff<'a> {
+    /// Entries of the earlier snapshot which haven't been observed yet.
+    previous: HashMap<&'a Path, &'a ManifestEntry>,
+    /// Whether the current snapshot describes the whole target, so that paths it lacks were
+    /// removed rather than merely left out.
+    track_removed: bool,
+    /// Number of files whose content and modification time match the earlier snapshot, along
+    /// with symlinks whose hashed target does.
+    pub unchanged: usize,
+}
+
+/// Writes a path with forward slashes whatever the platform, the same as its name in the archive,
+/// so a manifest written on Windows reads the same anywhere else.
+fn serialize_portable<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+    if std::path::MAIN_SEPARATOR == '/' {
+        return path.serialize(serializer);
+    }
+    match path.to_str() {
+        Some(path) => serializer.serialize_str(&path.replace(std::path::MAIN_SEPARATOR, "/")),
+        None => Err(serde::ser::Error::custom(
+            "path contains invalid UTF-8 characters",
+        )),
+    }
+}
+
+impl ManifestEntry {
+    pub fn from_entry<P: AsRef<Path>>(origin: P, entry: &Entry) -> std::io::Result<Self> {
+        let compressed_size = match &entry.entry_type {
+            EntryType::File(data, _) | EntryType::Drained(data, ..) if entry.level.is_some() => {
+                data.metadata().ok().map(|metadata| metadata.len())
+            }
+            _ => None,
+        };
+        let (kind, digest, children) = match &entry.entry_type {
+            EntryType::File(_, digest) => (ManifestEntryKind::File, digest.map(hex::encode), None),
+            EntryType::Drained(_, digest, _) => {
+                (ManifestEntryKind::File, Some(hex::encode(digest)), None)
+            }
+            EntryType::Directory(children) => (ManifestEntryKind::Directory, None, Some(*children)),
+            EntryType::Symlink(digest) => {
+                (ManifestEntryKind::Symlink, digest.map(hex::encode), None)
+            }
+        };
+        Ok(Self {
+            path: find_relative_path(origin, &entry.path)?,
+            kind,
+            size: match &entry.entry_type {
+                EntryType::File(..) => entry.metadata.len(),
+                EntryType::Drained(_, _, size) => *size,
+                _ => 0,
+            },
+            mtime: entry
+                .metadata
+                .modified()
+                .ok()
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |duration| duration.as_secs()),
+            digest,
+            partial_hardlink: false,
+            children,
+            compressed_size,
+            level: compressed_size.and(entry.level),
+        })
+    }
+}
+
+impl Manifest {
+    pub fn new(hash_algorithm: HashAlgorithm, entries: Vec<ManifestEntry>) -> Self {
+        Self {
+            version: MANIFEST_VERSION,
+            hash_algorithm,
+            modified_window: None,
+            entries,
+            compromises: Vec::new(),
+        }
+    }
+
+    /// Reads a manifest, refusing one written in a format this build doesn't understand.
+    pub fn read<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        Self::read_from(BufReader::new(File::open(path)?))
+    }
+
+    pub fn read_from<R: Read>(reader: R) -> std::io::Result<Self> {
+        let manifest: Self = serde_json::from_reader(reader).map_err(std::io::Error::from)?;
+        if manifest.version != MANIFEST_VERSION {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "Unsupported manifest version {} (expected {})",
+                    manifest.version, MANIFEST_VERSION
+                ),
+            ));
+        }
+        Ok(manifest)
+    }
+
+    pub fn write<P: AsRef<Path>>(&self, path: P, mode: Option<u32>) -> std::io::Result<()> {
+        let mut writer = BufWriter::new(create_file(path, mode)?);
+        serde_json::to_writer_pretty(&mut writer, self)?;
+        writer.flush()
+    }
+}
+
+pub fn is_embedded_manifest(relative_path: &Path) -> bool {
+    relative_path == Path::new(EMBEDDED_MANIFEST_NAME)
+}
+
+/// Reads the manifests recorded in an archive, seeking past everything else. There's one for every
+/// snapshot written to the archive, in order, or none for a snapshot which didn't record its own.
+pub fn read_embedded<P: AsRef<Path>>(tarball_path: P) -> std::io::Result<Vec<Option<Manifest>>> {
+    let mut archive = Archive::new(BufReader::new(File::open(tarball_path)?));
+    let mut manifests = Vec::new();
+    let mut manifest = None;
+    for entry in archive.entries_with_seek()? {
+        let entry = entry?;
+        let path = entry.path()?.into_owned();
+        if is_embedded_manifest(&path) {
+            manifest = Some(Manifest::read_from(entry)?);
+        } else if provenance::is_provenance(&path) {
+            // the snapshot record is the last entry every snapshot writes
+            manifests.push(manifest.take());
+        }
+    }
+    Ok(manifests)
+}
+
+/// Finds the directories holding fewer entries directly beneath them than were listed in them when
+/// they were captured, given how many each holds, along with both counts.
+pub fn incomplete_directories(
+    entries: &[ManifestEntry],
+    held: impl Fn(&Path) -> usize,
+) -> Vec<(&Path, usize, usize)> {
+    entries
+        .iter()
+        .filter_map(|entry| {
+            let listed = entry.children?;
+            let held = held(&entry.path);
+            (held < listed).then_some((entry.path.as_path(), held, listed))
+        })
+        .collect()
+}
+
+impl<'a> ManifestDiff<'a> {
+    pub fn new(previous: &'a Manifest, track_removed: bool) -> Self {
+        Self {
+            previous: previous
+                .entries
+                .iter()
+                .map(|entry| (entry.path.as_path(), entry))
+                .collect(),
+            track_removed,
+            unchanged: 0,
+        }
+    }
+
+    /// Records an entry of the current snapshot, returning whether it's a file unchanged since the
+    /// earlier snapshot, or a symlink pointing where it did then. Symlinks whose target wasn't
+    /// hashed in both snapshots are taken as changed.
+    pub fn observe(&mut self, entry: &ManifestEntry) -> bool {
+        let unchanged = self
+            .previous
+            .remove(entry.path.as_path())
+            .is_some_and(|previous| match entry.kind {
+                // whether the file's links are all in the snapshot doesn't change its content
+                ManifestEntryKind::File => {
+                    previous.kind == entry.kind
+                        && previous.size == entry.size
+                        && previous.mtime == entry.mtime
+                        && previous.digest == entry.digest
+                }
+                ManifestEntryKind::Symlink => {
+                    previous.kind == entry.kind
+                        && entry.digest.is_some()
+                        && previous.digest == entry.digest
+                }
+                ManifestEntryKind::Directory => false,
+            });
+        if unchanged {
+            self.unchanged += 1;
+        }
+        unchanged
+    }
+
+    /// Takes a path the current snapshot left out as still holding what the earlier snapshot
+    /// captured of it, so it isn't recorded as removed.
+    pub fn carry_over(&mut self, path: &Path) {
+        self.previous.remove(path);
+    }
+
+    /// Paths present in the earlier snapshot which weren't observed in the current one.
+    pub fn removed(&self) -> Vec<PathBuf> {
+        if !self.track_removed {
+            return Vec::new();
+        }
+        let removed: HashSet<&Path> = self.previous.keys().copied().collect();
+        // removing a directory removes everything beneath it
+        let mut removed: Vec<PathBuf> = removed
+            .iter()
+            .filter(|path| {
+                !path
+                    .ancestors()
+                    .skip(1)
+                    .any(|ancestor| removed.contains(ancestor))
+            })
+            .map(|path| path.to_path_buf())
+            .collect();
+        removed.sort();
+        removed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(path: &str, digest: &str) -> ManifestEntry {
+        ManifestEntry {
+            path: PathBuf::from(path),
+            kind: ManifestEntryKind::File,
+            size: 7,
+            mtime: 1_700_000_000,
+            digest: Some(digest.to_string()),
+            partial_hardlink: false,
+            children: None,
+            compressed_size: Some(16),
+            level: Some(3),
+        }
+    }
+
+    #[test]
+    fn manifests_read_back_as_written() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("manifest.json");
+        let entries = vec![file("t/a", "00ff"), file("t/b", "ff00")];
+        Manifest::new(HashAlgorithm::default(), entries.clone())
+            .write(&path, None)
+            .unwrap();
+
+        let manifest = Manifest::read(&path).unwrap();
+        assert_eq!(manifest.version, MANIFEST_VERSION);
+        assert_eq!(manifest.hash_algorithm, HashAlgorithm::default());
+        assert_eq!(manifest.entries, entries);
+    }
+
+    #[test]
+    fn paths_are_written_with_forward_slashes() {
+        let nested: PathBuf = ["t", "sub", "a"].iter().collect();
+        let written = serde_json::to_value(file(nested.to_str().unwrap(), "00ff")).unwrap();
+        assert_eq!(written["path"], "t/sub/a");
+    }
+
+    #[test]
+    fn manifests_written_before_versioning_read_as_version_1() {
+        let manifest = Manifest::read_from(&br#"{"entries": []}"#[..]).unwrap();
+        assert_eq!(manifest.version, 1);
+    }
+
+    #[test]
+    fn manifests_of_an_unknown_version_are_refused() {
+        let written = serde_json::to_vec(&Manifest {
+            version: MANIFEST_VERSION + 1,
+            ..Manifest::new(HashAlgorithm::default(), vec![file("t/a", "00ff")])
+        })
+        .unwrap();
+        let err = Manifest::read_from(&written[..]).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(
+            err.to_string().contains(&format!(
+                "Unsupported manifest version {}",
+                MANIFEST_VERSION + 1
+            )),
+            "{}",
+            err
+        );
+    }
+}
@@ -0,0 +1,1280 @@
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::{CString, OsStr, OsString};
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom, Take, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
+use std::rc::Rc;
+
+use tar::Archive;
+
+use crate::batch;
+use crate::dictionary::{self, Dictionary};
+use crate::manifest::{self, Manifest, ManifestEntryKind};
+use crate::provenance;
+use crate::restorer::{is_whiteout, whited_out_path};
+
+/// Inode of the mountpoint itself.
+const ROOT: u64 = 1;
+
+/// Size of the blocks decompressed file content is cached in.
+const BLOCK_SIZE: u64 = 256 * 1024;
+
+/// How long the kernel may keep names and attributes cached, as an archive never changes while
+/// it's mounted.
+const TTL_SECS: u64 = 3600;
+
+/// Room for the largest request the kernel sends, which never carries more than 1 MiB of data.
+const REQUEST_BUFFER: usize = 1024 * 1024 + 4096;
+
+/// Version of the kernel protocol spoken, which kernels speaking a later minor version fall back
+/// to.
+const FUSE_KERNEL_VERSION: u32 = 7;
+const FUSE_KERNEL_MINOR_VERSION: u32 = 31;
+/// Minor version from which the kernel expects the whole reply to `FUSE_INIT`.
+const FUSE_FULL_INIT_MINOR_VERSION: u32 = 23;
+const FUSE_COMPAT_22_INIT_OUT_SIZE: usize = 24;
+
+const FUSE_LOOKUP: u32 = 1;
+const FUSE_FORGET: u32 = 2;
+const FUSE_GETATTR: u32 = 3;
+const FUSE_READLINK: u32 = 5;
+const FUSE_OPEN: u32 = 14;
+const FUSE_READ: u32 = 15;
+const FUSE_STATFS: u32 = 17;
+const FUSE_RELEASE: u32 = 18;
+const FUSE_FLUSH: u32 = 25;
+const FUSE_INIT: u32 = 26;
+const FUSE_OPENDIR: u32 = 27;
+const FUSE_READDIR: u32 = 28;
+const FUSE_RELEASEDIR: u32 = 29;
+const FUSE_ACCESS: u32 = 34;
+const FUSE_INTERRUPT: u32 = 36;
+const FUSE_DESTROY: u32 = 38;
+const FUSE_BATCH_FORGET: u32 = 42;
+
+/// Keeps the kernel's page cache of a file across opens, as its content never changes.
+const FOPEN_KEEP_CACHE: u32 = 1 << 1;
+
+const IN_HEADER_SIZE: usize = 40;
+const OUT_HEADER_SIZE: usize = 16;
+
+/// Options controlling what `mount_tarball` serves.
+pub struct MountOptions<'a> {
+    /// Dictionary the archive's files were compressed with, if they were.
+    pub dictionary: Option<&'a Dictionary>,
+    /// Only serve this many of the snapshots appended to the archive, oldest first, showing the
+    /// target as the last of them captured it.
+    pub snapshots: Option<usize>,
+    /// Most bytes of decompressed content kept cached for repeated reads.
+    pub cache_size: u64,
+}
+
+/// Serves a tarball produced by `create_tarball` as a read-only filesystem at the mountpoint, the
+/// way `restore_tarball` would restore it, until it's unmounted. A signal unmounts it. Content is
+/// only decompressed when it's read, and kept in a cache of decompressed blocks.
+pub fn mount_tarball<P1: AsRef<Path>, P2: AsRef<Path>>(
+    tarball_path: P1,
+    mountpoint: P2,
+    options: &MountOptions,
+) -> std::io::Result<()> {
+    let tarball_path = tarball_path.as_ref();
+    let mountpoint = mountpoint.as_ref();
+    let tree = Tree::read(tarball_path, options.dictionary, options.snapshots)?;
+    log::info!(
+        "Serving {} entries of {}",
+        tree.nodes.len() - 1,
+        tarball_path.display()
+    );
+
+    let (device, mounted) = Mounted::mount(mountpoint)?;
+    log::info!(
+        "Mounted {} at {}; interrupt to unmount",
+        tarball_path.display(),
+        mountpoint.display()
+    );
+    let unmounting = mounted.clone();
+    if let Err(err) = ctrlc::set_handler(move || {
+        log::info!(
+            "Received a signal; unmounting {}",
+            unmounting.path.display()
+        );
+        if let Err(err) = unmounting.unmount() {
+            log::error!("Failed to unmount {}: {}", unmounting.path.display(), err);
+        }
+    }) {
+        log::warn!(
+            "Failed to handle signals; unmount {} to stop: {}",
+            mountpoint.display(),
+            err
+        );
+    }
+
+    let mut server = Server {
+        device,
+        tarball_path,
+        dictionary: options.dictionary,
+        tree,
+        cache: BlockCache::new(options.cache_size),
+        handles: HashMap::new(),
+        next_handle: 1,
+    };
+    server.serve()?;
+    log::info!("Unmounted {}", mountpoint.display());
+    Ok(())
+}
+
+/// An entry of the archive, as the inode it's served as.
+struct Node {
+    parent: u64,
+    kind: NodeKind,
+    mode: u32,
+    uid: u64,
+    gid: u64,
+    mtime: u64,
+    /// Size of the content, once known. Files compressed into frames of their own only learn it
+    /// from the manifest recorded with them or by being decompressed.
+    size: Cell<Option<u64>>,
+}
+
+enum NodeKind {
+    Directory(BTreeMap<OsString, u64>),
+    File(Content),
+    Symlink(PathBuf),
+}
+
+/// Where the content of a file is stored in the archive.
+#[derive(Clone, Copy)]
+enum Content {
+    /// A compressed frame of its own, at this offset and of this size.
+    Frame(u64, u64),
+    /// A member of the batch at this offset and of this size, spanning this range of the batch
+    /// once decompressed.
+    Batched {
+        offset: u64,
+        size: u64,
+        start: usize,
+        end: usize,
+    },
+    /// Nothing, as the file was empty or captured without its content.
+    Empty,
+}
+
+/// Every path the archive restores, with inode `n` at index `n - 1`.
+struct Tree {
+    nodes: Vec<Node>,
+}
+
+impl Tree {
+    /// Replays the snapshots in the archive, whiteouts included, into the paths they'd restore.
+    fn read(
+        tarball_path: &Path,
+        dictionary: Option<&Dictionary>,
+        snapshots: Option<usize>,
+    ) -> std::io::Result<Self> {
+        let mut tree = Self {
+            nodes: vec![Node {
+                parent: ROOT,
+                kind: NodeKind::Directory(BTreeMap::new()),
+                mode: 0o755,
+                uid: u64::from(unsafe { libc::getuid() }),
+                gid: u64::from(unsafe { libc::getgid() }),
+                mtime: 0,
+                size: Cell::new(Some(0)),
+            }],
+        };
+        let mut archive = Archive::new(BufReader::new(File::open(tarball_path)?));
+        let mut read_snapshots = 0;
+
+        for entry in archive.entries_with_seek()? {
+            let entry = entry?;
+            let relative_path = entry.path()?.into_owned();
+            let header = entry.header();
+            let mut node = Node {
+                parent: ROOT,
+                kind: NodeKind::File(Content::Empty),
+                mode: header.mode()? & 0o7777,
+                uid: header.uid()?,
+                gid: header.gid()?,
+                mtime: header.mtime()?,
+                size: Cell::new(Some(0)),
+            };
+            match header.entry_type() {
+                tar::EntryType::Directory => {
+                    node.kind = NodeKind::Directory(BTreeMap::new());
+                    tree.insert(&relative_path, node);
+                }
+                tar::EntryType::Symlink => {
+                    let link = entry
+                        .link_name()?
+                        .map(|link| link.into_owned())
+                        .unwrap_or_default();
+                    node.size.set(Some(link.as_os_str().len() as u64));
+                    node.kind = NodeKind::Symlink(link);
+                    tree.insert(&relative_path, node);
+                }
+                tar::EntryType::Regular if provenance::is_provenance(&relative_path) => {
+                    read_snapshots += 1;
+                    if snapshots == Some(read_snapshots) {
+                        break;
+                    }
+                }
+                tar::EntryType::Regular if manifest::is_embedded_manifest(&relative_path) => {
+                    // the manifest saves decompressing files just to learn their size
+                    for manifest_entry in Manifest::read_from(entry)?.entries {
+                        if manifest_entry.kind != ManifestEntryKind::File {
+                            continue;
+                        }
+                        if let Some(node) =
+                            tree.find(&manifest_entry.path).map(|ino| tree.node(ino))
+                        {
+                            if matches!(node.kind, NodeKind::File(Content::Frame(..))) {
+                                node.size.set(Some(manifest_entry.size));
+                            }
+                        }
+                    }
+                }
+                tar::EntryType::Regular if is_whiteout(&relative_path) => {
+                    tree.remove(&whited_out_path(&relative_path));
+                }
+                _ if relative_path.file_name() == Some(batch::BATCH_FILE_NAME.as_ref()) => {
+                    let (offset, size) = (entry.raw_file_position(), entry.size());
+                    let (members, _) = batch::read_batch(entry, dictionary)?;
+                    for member in members {
+                        tree.insert(
+                            &relative_path.with_file_name(&member.name),
+                            Node {
+                                parent: ROOT,
+                                kind: NodeKind::File(Content::Batched {
+                                    offset,
+                                    size,
+                                    start: member.data.start,
+                                    end: member.data.end,
+                                }),
+                                mode: member.mode & 0o7777,
+                                uid: member.uid,
+                                gid: member.gid,
+                                mtime: member.mtime,
+                                size: Cell::new(Some(member.data.len() as u64)),
+                            },
+                        );
+                    }
+                }
+                _ => {
+                    // files captured without their content hold no frame to decompress
+                    if entry.size() > 0 {
+                        node.kind =
+                            NodeKind::File(Content::Frame(entry.raw_file_position(), entry.size()));
+                        node.size.set(None);
+                    }
+                    tree.insert(&relative_path, node);
+                }
+            }
+        }
+        Ok(tree)
+    }
+
+    fn node(&self, ino: u64) -> &Node {
+        &self.nodes[ino as usize - 1]
+    }
+
+    fn get(&self, ino: u64) -> Option<&Node> {
+        ino.checked_sub(1)
+            .and_then(|index| self.nodes.get(index as usize))
+    }
+
+    fn children(&self, ino: u64) -> Option<&BTreeMap<OsString, u64>> {
+        match &self.get(ino)?.kind {
+            NodeKind::Directory(children) => Some(children),
+            _ => None,
+        }
+    }
+
+    /// The names of a path in the archive, or nothing for a path which could escape the
+    /// mountpoint.
+    fn names(relative_path: &Path) -> Option<Vec<&OsStr>> {
+        let mut names = Vec::new();
+        for component in relative_path.components() {
+            match component {
+                Component::Normal(name) => names.push(name),
+                Component::CurDir => {}
+                _ => return None,
+            }
+        }
+        Some(names)
+    }
+
+    fn find(&self, relative_path: &Path) -> Option<u64> {
+        Self::names(relative_path)?
+            .into_iter()
+            .try_fold(ROOT, |ino, name| self.children(ino)?.get(name).copied())
+    }
+
+    /// Adds the node at the path, over whatever was there, creating any missing parents. A
+    /// directory added again only has its metadata updated, keeping its children.
+    fn insert(&mut self, relative_path: &Path, mut node: Node) {
+        let Some((name, parents)) = Self::names(relative_path)
+            .as_deref()
+            .and_then(|names| names.split_last())
+            .map(|(name, parents)| (name.to_os_string(), parents.to_vec()))
+        else {
+            log::warn!("Not serving {}", relative_path.display());
+            return;
+        };
+        let mut parent = ROOT;
+        for parent_name in parents {
+            parent = match self
+                .children(parent)
+                .and_then(|children| children.get(parent_name))
+            {
+                Some(&ino) if self.children(ino).is_some() => ino,
+                _ => self.add(
+                    parent,
+                    parent_name.to_os_string(),
+                    Node {
+                        parent,
+                        kind: NodeKind::Directory(BTreeMap::new()),
+                        mode: 0o755,
+                        uid: self.node(ROOT).uid,
+                        gid: self.node(ROOT).gid,
+                        mtime: 0,
+                        size: Cell::new(Some(0)),
+                    },
+                ),
+            };
+        }
+        let existing = self
+            .children(parent)
+            .and_then(|children| children.get(&name))
+            .copied();
+        match (existing, &node.kind) {
+            (Some(ino), NodeKind::Directory(_)) if self.children(ino).is_some() => {
+                let existing = &mut self.nodes[ino as usize - 1];
+                existing.mode = node.mode;
+                existing.uid = node.uid;
+                existing.gid = node.gid;
+                existing.mtime = node.mtime;
+            }
+            _ => {
+                node.parent = parent;
+                self.add(parent, name, node);
+            }
+        }
+    }
+
+    fn add(&mut self, parent: u64, name: OsString, node: Node) -> u64 {
+        self.nodes.push(node);
+        let ino = self.nodes.len() as u64;
+        if let NodeKind::Directory(children) = &mut self.nodes[parent as usize - 1].kind {
+            children.insert(name, ino);
+        }
+        ino
+    }
+
+    /// Removes the path and everything beneath it from its parent. Removed inodes stay allocated,
+    /// as the kernel may still refer to them.
+    fn remove(&mut self, relative_path: &Path) {
+        let (Some(parent), Some(name)) = (
+            relative_path.parent().and_then(|parent| self.find(parent)),
+            relative_path.file_name(),
+        ) else {
+            return;
+        };
+        if let NodeKind::Directory(children) = &mut self.nodes[parent as usize - 1].kind {
+            children.remove(name);
+        }
+    }
+}
+
+/// Decompressed content kept for repeated reads, evicting the least recently read blocks once it
+/// holds more than its budget.
+struct BlockCache {
+    budget: u64,
+    held: u64,
+    clock: u64,
+    /// Blocks by the offset of the content they were decompressed from and their index in it.
+    blocks: HashMap<(u64, u64), CachedBlock>,
+}
+
+struct CachedBlock {
+    data: Rc<Vec<u8>>,
+    /// When the block was last read, on the cache's clock.
+    read: u64,
+}
+
+impl BlockCache {
+    fn new(budget: u64) -> Self {
+        Self {
+            budget,
+            held: 0,
+            clock: 0,
+            blocks: HashMap::new(),
+        }
+    }
+
+    fn get(&mut self, key: (u64, u64)) -> Option<Rc<Vec<u8>>> {
+        self.clock += 1;
+        let block = self.blocks.get_mut(&key)?;
+        block.read = self.clock;
+        Some(block.data.clone())
+    }
+
+    fn insert(&mut self, key: (u64, u64), block: Rc<Vec<u8>>) {
+        let size = block.len() as u64;
+        while self.held + size > self.budget {
+            let Some(oldest) = self
+                .blocks
+                .iter()
+                .min_by_key(|(_, block)| block.read)
+                .map(|(key, _)| *key)
+            else {
+                // a block bigger than the whole budget isn't kept at all
+                return;
+            };
+            if let Some(evicted) = self.blocks.remove(&oldest) {
+                self.held -= evicted.data.len() as u64;
+            }
+        }
+        self.clock += 1;
+        self.held += size;
+        let cached = CachedBlock {
+            data: block,
+            read: self.clock,
+        };
+        if let Some(replaced) = self.blocks.insert(key, cached) {
+            self.held -= replaced.data.len() as u64;
+        }
+    }
+}
+
+/// A decoder left where the last read of an open file stopped, so reading a file from start to
+/// end decompresses it only once.
+struct Cursor<'a> {
+    /// Offset of the frame being decompressed.
+    frame: u64,
+    /// How far into the decompressed content the decoder is.
+    position: u64,
+    decoder: zstd::Decoder<'a, BufReader<Take<File>>>,
+}
+
+struct Server<'a> {
+    device: File,
+    tarball_path: &'a Path,
+    dictionary: Option<&'a Dictionary>,
+    tree: Tree,
+    cache: BlockCache,
+    /// Open files by handle, with the decoder of the last read of each.
+    handles: HashMap<u64, Option<Cursor<'a>>>,
+    next_handle: u64,
+}
+
+/// A request read from the kernel.
+struct Request<'b> {
+    opcode: u32,
+    unique: u64,
+    ino: u64,
+    body: &'b [u8],
+}
+
+impl<'a> Server<'a> {
+    /// Answers requests until the filesystem is unmounted.
+    fn serve(&mut self) -> std::io::Result<()> {
+        let mut buffer = vec![0; REQUEST_BUFFER];
+        loop {
+            let read = match self.device.read(&mut buffer) {
+                Ok(read) => read,
+                Err(err) => match err.raw_os_error() {
+                    // the request was interrupted before it was read
+                    Some(libc::ENOENT | libc::EINTR | libc::EAGAIN) => continue,
+                    Some(libc::ENODEV) => return Ok(()),
+                    _ => return Err(err),
+                },
+            };
+            if read < IN_HEADER_SIZE {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "Short request from the kernel",
+                ));
+            }
+            let request = Request {
+                opcode: u32_at(&buffer, 4),
+                unique: u64_at(&buffer, 8),
+                ino: u64_at(&buffer, 16),
+                body: &buffer[IN_HEADER_SIZE..read],
+            };
+            let reply = match request.opcode {
+                // the kernel expects no reply to these
+                FUSE_FORGET | FUSE_BATCH_FORGET | FUSE_INTERRUPT => continue,
+                FUSE_DESTROY => {
+                    self.reply(request.unique, Ok(Vec::new()))?;
+                    return Ok(());
+                }
+                _ => self.answer(&request),
+            };
+            self.reply(request.unique, reply)?;
+        }
+    }
+
+    fn reply(&mut self, unique: u64, reply: Result<Vec<u8>, i32>) -> std::io::Result<()> {
+        let (error, body) = match reply {
+            Ok(body) => (0, body),
+            Err(errno) => (-errno, Vec::new()),
+        };
+        let mut message = Vec::with_capacity(OUT_HEADER_SIZE + body.len());
+        put_u32(&mut message, (OUT_HEADER_SIZE + body.len()) as u32);
+        put_u32(&mut message, error as u32);
+        put_u64(&mut message, unique);
+        message.extend_from_slice(&body);
+        match self.device.write_all(&message) {
+            // the request was interrupted and no longer wants its reply
+            Err(err) if err.raw_os_error() == Some(libc::ENOENT) => Ok(()),
+            written => written,
+        }
+    }
+
+    /// Answers a request with the body of its reply, or the errno it fails with.
+    fn answer(&mut self, request: &Request) -> Result<Vec<u8>, i32> {
+        let body = request.body;
+        match request.opcode {
+            FUSE_INIT => {
+                let (major, minor) = (u32_at(body, 0), u32_at(body, 4));
+                if major != FUSE_KERNEL_VERSION {
+                    log::error!("Unsupported FUSE protocol version {}.{}", major, minor);
+                    return Err(libc::EPROTO);
+                }
+                let minor = minor.min(FUSE_KERNEL_MINOR_VERSION);
+                let mut reply = Vec::new();
+                put_u32(&mut reply, FUSE_KERNEL_VERSION);
+                put_u32(&mut reply, minor);
+                // max_readahead, as the kernel asked for
+                put_u32(&mut reply, u32_at(body, 8));
+                // flags, max_background and congestion_threshold
+                put_u32(&mut reply, 0);
+                put_u32(&mut reply, 0);
+                // max_write and time_gran
+                put_u32(&mut reply, BLOCK_SIZE as u32);
+                put_u32(&mut reply, 1);
+                // max_pages, map_alignment, flags2, max_stack_depth and the unused rest
+                reply.resize(64, 0);
+                if minor < FUSE_FULL_INIT_MINOR_VERSION {
+                    reply.truncate(FUSE_COMPAT_22_INIT_OUT_SIZE);
+                }
+                Ok(reply)
+            }
+            FUSE_LOOKUP => {
+                let name = body.split(|byte| *byte == 0).next().unwrap_or_default();
+                let ino = *self
+                    .tree
+                    .children(request.ino)
+                    .ok_or(libc::ENOTDIR)?
+                    .get(OsStr::from_bytes(name))
+                    .ok_or(libc::ENOENT)?;
+                let mut reply = Vec::new();
+                put_u64(&mut reply, ino);
+                // generation, entry_valid and attr_valid, in seconds and nanoseconds
+                put_u64(&mut reply, 0);
+                put_u64(&mut reply, TTL_SECS);
+                put_u64(&mut reply, TTL_SECS);
+                put_u32(&mut reply, 0);
+                put_u32(&mut reply, 0);
+                self.put_attr(&mut reply, ino)?;
+                Ok(reply)
+            }
+            FUSE_GETATTR => {
+                let mut reply = Vec::new();
+                // attr_valid in seconds and nanoseconds, and padding
+                put_u64(&mut reply, TTL_SECS);
+                put_u32(&mut reply, 0);
+                put_u32(&mut reply, 0);
+                self.put_attr(&mut reply, request.ino)?;
+                Ok(reply)
+            }
+            FUSE_READLINK => match &self.tree.get(request.ino).ok_or(libc::ENOENT)?.kind {
+                NodeKind::Symlink(link) => Ok(link.as_os_str().as_bytes().to_vec()),
+                _ => Err(libc::EINVAL),
+            },
+            FUSE_OPEN => {
+                if u32_at(body, 0) as i32 & libc::O_ACCMODE != libc::O_RDONLY {
+                    return Err(libc::EROFS);
+                }
+                match self.tree.get(request.ino).ok_or(libc::ENOENT)?.kind {
+                    NodeKind::File(_) => {}
+                    NodeKind::Directory(_) => return Err(libc::EISDIR),
+                    NodeKind::Symlink(_) => return Err(libc::ELOOP),
+                }
+                let handle = self.next_handle;
+                self.next_handle += 1;
+                self.handles.insert(handle, None);
+                Ok(open_reply(handle, FOPEN_KEEP_CACHE))
+            }
+            FUSE_READ => {
+                let (handle, offset, size) = (u64_at(body, 0), u64_at(body, 8), u32_at(body, 16));
+                self.read(request.ino, handle, offset, u64::from(size))
+                    .map_err(|err| {
+                        log::error!("Failed to read from archive: {}", err);
+                        err.raw_os_error().unwrap_or(libc::EIO)
+                    })
+            }
+            FUSE_RELEASE => {
+                self.handles.remove(&u64_at(body, 0));
+                Ok(Vec::new())
+            }
+            FUSE_OPENDIR => {
+                self.tree.children(request.ino).ok_or(libc::ENOTDIR)?;
+                Ok(open_reply(0, 0))
+            }
+            FUSE_READDIR => {
+                let (offset, size) = (u64_at(body, 8), u32_at(body, 16) as usize);
+                let node = self.tree.get(request.ino).ok_or(libc::ENOENT)?;
+                let children = self.tree.children(request.ino).ok_or(libc::ENOTDIR)?;
+                let listed = [
+                    (OsStr::new("."), request.ino),
+                    (OsStr::new(".."), node.parent),
+                ]
+                .into_iter()
+                .chain(children.iter().map(|(name, ino)| (name.as_os_str(), *ino)));
+                let mut reply = Vec::new();
+                for (index, (name, ino)) in listed.enumerate().skip(offset as usize) {
+                    let name = name.as_bytes();
+                    let padded = (24 + name.len() + 7) & !7;
+                    if reply.len() + padded > size {
+                        break;
+                    }
+                    let file_type = match self.tree.node(ino).kind {
+                        NodeKind::Directory(_) => libc::DT_DIR,
+                        NodeKind::File(_) => libc::DT_REG,
+                        NodeKind::Symlink(_) => libc::DT_LNK,
+                    };
+                    put_u64(&mut reply, ino);
+                    // the offset to carry on listing from after this entry
+                    put_u64(&mut reply, index as u64 + 1);
+                    put_u32(&mut reply, name.len() as u32);
+                    put_u32(&mut reply, u32::from(file_type));
+                    reply.extend_from_slice(name);
+                    reply.resize(reply.len().next_multiple_of(8), 0);
+                }
+                Ok(reply)
+            }
+            FUSE_STATFS => {
+                let mut reply = Vec::new();
+                // blocks, bfree and bavail
+                put_u64(&mut reply, 0);
+                put_u64(&mut reply, 0);
+                put_u64(&mut reply, 0);
+                // files and ffree
+                put_u64(&mut reply, self.tree.nodes.len() as u64);
+                put_u64(&mut reply, 0);
+                // bsize, namelen and frsize, with padding and spare room after them
+                put_u32(&mut reply, BLOCK_SIZE as u32);
+                put_u32(&mut reply, 255);
+                put_u32(&mut reply, BLOCK_SIZE as u32);
+                reply.resize(80, 0);
+                Ok(reply)
+            }
+            FUSE_ACCESS => {
+                if u32_at(body, 0) as i32 & libc::W_OK != 0 {
+                    return Err(libc::EROFS);
+                }
+                Ok(Vec::new())
+            }
+            FUSE_FLUSH | FUSE_RELEASEDIR => Ok(Vec::new()),
+            _ => Err(libc::ENOSYS),
+        }
+    }
+
+    /// Writes the attributes of the inode the way the kernel's `fuse_attr` lays them out.
+    fn put_attr(&self, reply: &mut Vec<u8>, ino: u64) -> Result<(), i32> {
+        let node = self.tree.get(ino).ok_or(libc::ENOENT)?;
+        let (file_type, links) = match node.kind {
+            NodeKind::Directory(_) => (libc::S_IFDIR, 2),
+            NodeKind::File(_) => (libc::S_IFREG, 1),
+            NodeKind::Symlink(_) => (libc::S_IFLNK, 1),
+        };
+        let size = self.size(node).map_err(|err| {
+            log::error!("Failed to read from archive: {}", err);
+            libc::EIO
+        })?;
+        put_u64(reply, ino);
+        put_u64(reply, size);
+        put_u64(reply, size.div_ceil(512));
+        // atime, mtime and ctime, in seconds and then nanoseconds
+        for _ in 0..3 {
+            put_u64(reply, node.mtime);
+        }
+        for _ in 0..3 {
+            put_u32(reply, 0);
+        }
+        put_u32(reply, file_type | node.mode);
+        put_u32(reply, links);
+        put_u32(reply, node.uid as u32);
+        put_u32(reply, node.gid as u32);
+        // rdev, blksize and flags
+        put_u32(reply, 0);
+        put_u32(reply, BLOCK_SIZE as u32);
+        put_u32(reply, 0);
+        Ok(())
+    }
+
+    /// The size of the node's content, decompressing a file which only has a frame to go by.
+    fn size(&self, node: &Node) -> std::io::Result<u64> {
+        if let Some(size) = node.size.get() {
+            return Ok(size);
+        }
+        let NodeKind::File(Content::Frame(offset, size)) = node.kind else {
+            return Ok(0);
+        };
+        let size = std::io::copy(&mut self.frame_decoder(offset, size)?, &mut std::io::sink())?;
+        node.size.set(Some(size));
+        Ok(size)
+    }
+
+    fn frame_decoder(
+        &self,
+        offset: u64,
+        size: u64,
+    ) -> std::io::Result<zstd::Decoder<'a, BufReader<Take<File>>>> {
+        let mut file = File::open(self.tarball_path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        dictionary::decoder(file.take(size), self.dictionary)
+    }
+
+    /// Reads up to `size` bytes of the file from `offset`, through the cache.
+    fn read(&mut self, ino: u64, handle: u64, offset: u64, size: u64) -> std::io::Result<Vec<u8>> {
+        let content = match self.tree.get(ino).map(|node| &node.kind) {
+            Some(NodeKind::File(content)) => *content,
+            _ => return Err(std::io::Error::from_raw_os_error(libc::EBADF)),
+        };
+        match content {
+            Content::Empty => Ok(Vec::new()),
+            Content::Batched {
+                offset: batch_offset,
+                size: batch_size,
+                start,
+                end,
+            } => {
+                let data = match self.cache.get((batch_offset, u64::MAX)) {
+                    Some(data) => data,
+                    None => {
+                        let mut file = File::open(self.tarball_path)?;
+                        file.seek(SeekFrom::Start(batch_offset))?;
+                        let (_, data) = batch::read_batch(file.take(batch_size), self.dictionary)?;
+                        let data = Rc::new(data);
+                        self.cache.insert((batch_offset, u64::MAX), data.clone());
+                        data
+                    }
+                };
+                let from = (start as u64 + offset).min(end as u64) as usize;
+                let to = (from as u64 + size).min(end as u64) as usize;
+                Ok(data[from..to].to_vec())
+            }
+            Content::Frame(frame, frame_size) => {
+                let mut read = Vec::with_capacity(size as usize);
+                let mut position = offset;
+                while (read.len() as u64) < size {
+                    let index = position / BLOCK_SIZE;
+                    let block = self.block(handle, frame, frame_size, index)?;
+                    let within = (position - index * BLOCK_SIZE) as usize;
+                    if within >= block.len() {
+                        break;
+                    }
+                    let wanted = (size as usize - read.len()).min(block.len() - within);
+                    read.extend_from_slice(&block[within..within + wanted]);
+                    position += wanted as u64;
+                    if (block.len() as u64) < BLOCK_SIZE {
+                        // only the last block falls short
+                        break;
+                    }
+                }
+                Ok(read)
+            }
+        }
+    }
+
+    /// One block of a frame's decompressed content, from the cache or by carrying on with the
+    /// decoder of the handle's last read, starting the frame over if it's already past the block.
+    fn block(
+        &mut self,
+        handle: u64,
+        frame: u64,
+        frame_size: u64,
+        index: u64,
+    ) -> std::io::Result<Rc<Vec<u8>>> {
+        if let Some(block) = self.cache.get((frame, index)) {
+            return Ok(block);
+        }
+        let start = index * BLOCK_SIZE;
+        let cursor = self.handles.get_mut(&handle).and_then(Option::take);
+        let mut cursor = match cursor {
+            Some(cursor) if cursor.frame == frame && cursor.position <= start => cursor,
+            _ => Cursor {
+                frame,
+                position: 0,
+                decoder: self.frame_decoder(frame, frame_size)?,
+            },
+        };
+        cursor.position += std::io::copy(
+            &mut (&mut cursor.decoder).take(start - cursor.position),
+            &mut std::io::sink(),
+        )?;
+        let mut block = Vec::with_capacity(BLOCK_SIZE as usize);
+        (&mut cursor.decoder)
+            .take(BLOCK_SIZE)
+            .read_to_end(&mut block)?;
+        cursor.position += block.len() as u64;
+        if let Some(slot) = self.handles.get_mut(&handle) {
+            *slot = Some(cursor);
+        }
+        let block = Rc::new(block);
+        self.cache.insert((frame, index), block.clone());
+        Ok(block)
+    }
+}
+
+fn open_reply(handle: u64, open_flags: u32) -> Vec<u8> {
+    let mut reply = Vec::new();
+    put_u64(&mut reply, handle);
+    put_u32(&mut reply, open_flags);
+    put_u32(&mut reply, 0);
+    reply
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> u32 {
+    bytes
+        .get(at..at + 4)
+        .map_or(0, |bytes| u32::from_ne_bytes(bytes.try_into().unwrap()))
+}
+
+fn u64_at(bytes: &[u8], at: usize) -> u64 {
+    bytes
+        .get(at..at + 8)
+        .map_or(0, |bytes| u64::from_ne_bytes(bytes.try_into().unwrap()))
+}
+
+fn put_u32(bytes: &mut Vec<u8>, value: u32) {
+    bytes.extend_from_slice(&value.to_ne_bytes());
+}
+
+fn put_u64(bytes: &mut Vec<u8>, value: u64) {
+    bytes.extend_from_slice(&value.to_ne_bytes());
+}
+
+/// A mounted filesystem, and how it was mounted, so it's unmounted the same way.
+#[derive(Clone)]
+struct Mounted {
+    path: PathBuf,
+    /// The `fusermount` which mounted it for a user who can't mount filesystems, if one did.
+    fusermount: Option<&'static str>,
+}
+
+impl Mounted {
+    /// Mounts a read-only FUSE filesystem at the path, returning the device its requests are read
+    /// from. Users who can't mount filesystems themselves get `fusermount` to do it for them.
+    fn mount(path: &Path) -> std::io::Result<(File, Self)> {
+        let path = path.canonicalize()?;
+        let device = File::options().read(true).write(true).open("/dev/fuse")?;
+        // only the user mounting the archive can read it, whatever owners it records
+        let data = CString::new(format!(
+            "fd={},rootmode={:o},user_id={},group_id={}",
+            device.as_raw_fd(),
+            libc::S_IFDIR,
+            unsafe { libc::getuid() },
+            unsafe { libc::getgid() }
+        ))?;
+        let target = CString::new(path.as_os_str().as_bytes())?;
+        let mounted = unsafe {
+            libc::mount(
+                c"sit".as_ptr(),
+                target.as_ptr(),
+                c"fuse.sit".as_ptr(),
+                libc::MS_RDONLY | libc::MS_NOSUID | libc::MS_NODEV,
+                data.as_ptr().cast(),
+            )
+        };
+        if mounted == 0 {
+            return Ok((
+                device,
+                Self {
+                    path,
+                    fusermount: None,
+                },
+            ));
+        }
+        let err = std::io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::EPERM) {
+            return Err(err);
+        }
+        drop(device);
+        log::debug!("Not allowed to mount {}; trying fusermount", path.display());
+        for fusermount in ["fusermount3", "fusermount"] {
+            match fusermount_device(fusermount, &path) {
+                Ok(device) => {
+                    return Ok((
+                        device,
+                        Self {
+                            path,
+                            fusermount: Some(fusermount),
+                        },
+                    ))
+                }
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err),
+            }
+        }
+        Err(err)
+    }
+
+    /// Unmounts the filesystem, detaching it lazily if it's still in use, which ends serving it.
+    fn unmount(&self) -> std::io::Result<()> {
+        if let Some(fusermount) = self.fusermount {
+            let status = Command::new(fusermount)
+                .arg("-u")
+                .arg("-z")
+                .arg("--")
+                .arg(&self.path)
+                .status()?;
+            return match status.success() {
+                true => Ok(()),
+                false => Err(std::io::Error::other(format!(
+                    "{} exited with {}",
+                    fusermount, status
+                ))),
+            };
+        }
+        let target = CString::new(self.path.as_os_str().as_bytes())?;
+        if unsafe { libc::umount2(target.as_ptr(), 0) } == 0 {
+            return Ok(());
+        }
+        log::warn!(
+            "{} is busy; detaching it once it's no longer in use",
+            self.path.display()
+        );
+        match unsafe { libc::umount2(target.as_ptr(), libc::MNT_DETACH) } {
+            0 => Ok(()),
+            _ => Err(std::io::Error::last_os_error()),
+        }
+    }
+}
+
+/// Has `fusermount` mount the filesystem, receiving the device it opened over a socket the way
+/// it hands it to libfuse.
+fn fusermount_device(fusermount: &str, path: &Path) -> std::io::Result<File> {
+    let mut sockets = [0; 2];
+    if unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_STREAM, 0, sockets.as_mut_ptr()) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let (ours, theirs) = unsafe {
+        (
+            OwnedFd::from_raw_fd(sockets[0]),
+            OwnedFd::from_raw_fd(sockets[1]),
+        )
+    };
+    let status = Command::new(fusermount)
+        .arg("-o")
+        .arg("ro,nosuid,nodev,fsname=sit,subtype=sit")
+        .arg("--")
+        .arg(path)
+        .env("_FUSE_COMMFD", theirs.as_raw_fd().to_string())
+        .status()?;
+    drop(theirs);
+    if !status.success() {
+        return Err(std::io::Error::other(format!(
+            "{} exited with {}",
+            fusermount, status
+        )));
+    }
+
+    let mut byte = [0u8; 1];
+    let mut iov = libc::iovec {
+        iov_base: byte.as_mut_ptr().cast(),
+        iov_len: byte.len(),
+    };
+    // room for the one descriptor passed, aligned for the header in front of it
+    let mut control = [0u64; 8];
+    let mut message: libc::msghdr = unsafe { std::mem::zeroed() };
+    message.msg_iov = &mut iov;
+    message.msg_iovlen = 1;
+    message.msg_control = control.as_mut_ptr().cast();
+    message.msg_controllen = std::mem::size_of_val(&control) as _;
+    if unsafe { libc::recvmsg(ours.as_raw_fd(), &mut message, 0) } <= 0 {
+        return Err(std::io::Error::other(format!(
+            "{} didn't pass on the FUSE device",
+            fusermount
+        )));
+    }
+    let header = unsafe { libc::CMSG_FIRSTHDR(&message) };
+    if header.is_null()
+        || unsafe { (*header).cmsg_level } != libc::SOL_SOCKET
+        || unsafe { (*header).cmsg_type } != libc::SCM_RIGHTS
+    {
+        return Err(std::io::Error::other(format!(
+            "{} didn't pass on the FUSE device",
+            fusermount
+        )));
+    }
+    let fd = unsafe { std::ptr::read_unaligned(libc::CMSG_DATA(header).cast::<libc::c_int>()) };
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archiver::{self, tests::tar_options};
+    use crate::processor;
+    use crate::report::SnapshotReport;
+
+    /// Content spanning a few blocks, and not a whole number of them.
+    fn big_content() -> Vec<u8> {
+        (0..BLOCK_SIZE * 2 + 1000)
+            .map(|index| (index % 251) as u8)
+            .collect()
+    }
+
+    /// Snapshots a target named `world` into `output`, small files batched, and serves the
+    /// tarball with the cache budget, the device being a file nothing is written to.
+    fn serve<'a>(output: &Path, tarball_path: &'a Path, budget: u64) -> Server<'a> {
+        let target = output.join("world");
+        std::fs::create_dir_all(target.join("sub")).unwrap();
+        std::fs::write(target.join("big"), big_content()).unwrap();
+        std::fs::write(target.join("small"), "small").unwrap();
+        std::fs::write(target.join("sub/nested"), "nested").unwrap();
+        std::os::unix::fs::symlink("big", target.join("link")).unwrap();
+
+        let options = processor::tests::options(output);
+        let mut report = SnapshotReport::default();
+        let processed = processor::process_directory(&target, 5, &options, &mut report).unwrap();
+        let tar_options = archiver::TarOptions {
+            batch_threshold: Some(1024),
+            ..tar_options()
+        };
+        archiver::create_tarball(
+            &target,
+            processed.entries,
+            tarball_path,
+            &tar_options,
+            None,
+            &mut report,
+        )
+        .unwrap();
+
+        Server {
+            device: tempfile::tempfile().unwrap(),
+            tarball_path,
+            dictionary: None,
+            tree: Tree::read(tarball_path, None, None).unwrap(),
+            cache: BlockCache::new(budget),
+            handles: HashMap::new(),
+            next_handle: 1,
+        }
+    }
+
+    fn ask(server: &mut Server, opcode: u32, ino: u64, body: &[u8]) -> Result<Vec<u8>, i32> {
+        server.answer(&Request {
+            opcode,
+            unique: 1,
+            ino,
+            body,
+        })
+    }
+
+    fn lookup(server: &mut Server, parent: u64, name: &str) -> Result<u64, i32> {
+        let mut body = name.as_bytes().to_vec();
+        body.push(0);
+        ask(server, FUSE_LOOKUP, parent, &body).map(|reply| u64_at(&reply, 0))
+    }
+
+    fn readdir(server: &mut Server, ino: u64, offset: u64, size: u32) -> Vec<(String, u64, u32)> {
+        let mut body = Vec::new();
+        put_u64(&mut body, 0);
+        put_u64(&mut body, offset);
+        put_u32(&mut body, size);
+        let reply = ask(server, FUSE_READDIR, ino, &body).unwrap();
+        let mut listed = Vec::new();
+        let mut at = 0;
+        while at < reply.len() {
+            let length = u32_at(&reply, at + 16) as usize;
+            let name = String::from_utf8(reply[at + 24..at + 24 + length].to_vec()).unwrap();
+            listed.push((name, u64_at(&reply, at), u32_at(&reply, at + 20)));
+            at += (24 + length).next_multiple_of(8);
+        }
+        listed
+    }
+
+    fn open(server: &mut Server, ino: u64) -> Result<u64, i32> {
+        let mut body = Vec::new();
+        put_u32(&mut body, libc::O_RDONLY as u32);
+        put_u32(&mut body, 0);
+        ask(server, FUSE_OPEN, ino, &body).map(|reply| u64_at(&reply, 0))
+    }
+
+    fn read(server: &mut Server, ino: u64, handle: u64, offset: u64, size: u32) -> Vec<u8> {
+        let mut body = Vec::new();
+        put_u64(&mut body, handle);
+        put_u64(&mut body, offset);
+        put_u32(&mut body, size);
+        ask(server, FUSE_READ, ino, &body).unwrap()
+    }
+
+    #[test]
+    fn looking_up_follows_the_archived_paths() {
+        let output = tempfile::tempdir().unwrap();
+        let tarball_path = output.path().join("snapshot.tar.zst");
+        let mut server = serve(output.path(), &tarball_path, BLOCK_SIZE * 4);
+
+        let world = lookup(&mut server, ROOT, "world").unwrap();
+        let sub = lookup(&mut server, world, "sub").unwrap();
+        let nested = lookup(&mut server, sub, "nested").unwrap();
+        assert!(matches!(server.tree.node(sub).kind, NodeKind::Directory(_)));
+        assert!(matches!(server.tree.node(nested).kind, NodeKind::File(_)));
+        assert_eq!(server.tree.node(nested).parent, sub);
+
+        // the entry's attributes follow its inode, entry and attribute timeouts
+        let mut body = b"big".to_vec();
+        body.push(0);
+        let reply = ask(&mut server, FUSE_LOOKUP, world, &body).unwrap();
+        let big = u64_at(&reply, 0);
+        assert_eq!(u64_at(&reply, 40), big);
+        assert_eq!(u64_at(&reply, 48), big_content().len() as u64);
+
+        assert_eq!(lookup(&mut server, world, "missing"), Err(libc::ENOENT));
+        assert_eq!(lookup(&mut server, nested, "anything"), Err(libc::ENOTDIR));
+    }
+
+    #[test]
+    fn reading_a_directory_lists_it_from_the_offset_given() {
+        let output = tempfile::tempdir().unwrap();
+        let tarball_path = output.path().join("snapshot.tar.zst");
+        let mut server = serve(output.path(), &tarball_path, BLOCK_SIZE * 4);
+        let world = lookup(&mut server, ROOT, "world").unwrap();
+
+        let listed = readdir(&mut server, world, 0, 4096);
+        let names: Vec<&str> = listed.iter().map(|(name, _, _)| name.as_str()).collect();
+        assert_eq!(names, [".", "..", "big", "link", "small", "sub"]);
+        assert_eq!(listed[0].1, world);
+        assert_eq!(listed[1].1, ROOT);
+        let types: Vec<u32> = listed.iter().map(|(_, _, kind)| *kind).collect();
+        let (dir, reg, lnk) = (
+            u32::from(libc::DT_DIR),
+            u32::from(libc::DT_REG),
+            u32::from(libc::DT_LNK),
+        );
+        assert_eq!(types, [dir, dir, reg, lnk, reg, dir]);
+        for (name, ino, _) in &listed[2..] {
+            assert_eq!(lookup(&mut server, world, name), Ok(*ino));
+        }
+
+        // carrying on from an offset, and stopping at an entry that doesn't fit
+        let rest = readdir(&mut server, world, 4, 4096);
+        assert_eq!(rest, listed[4..]);
+        let first = readdir(&mut server, world, 0, 32);
+        assert_eq!(first, listed[..1]);
+
+        let nested = lookup(&mut server, world, "small").unwrap();
+        let mut body = vec![0; 24];
+        body[16..20].copy_from_slice(&4096u32.to_ne_bytes());
+        assert_eq!(
+            ask(&mut server, FUSE_READDIR, nested, &body),
+            Err(libc::ENOTDIR)
+        );
+    }
+
+    #[test]
+    fn reading_a_file_decompresses_blocks_through_the_cache() {
+        let output = tempfile::tempdir().unwrap();
+        let tarball_path = output.path().join("snapshot.tar.zst");
+        let mut server = serve(output.path(), &tarball_path, BLOCK_SIZE * 4);
+        let world = lookup(&mut server, ROOT, "world").unwrap();
+        let big = lookup(&mut server, world, "big").unwrap();
+        let NodeKind::File(Content::Frame(frame, _)) = server.tree.node(big).kind else {
+            panic!("big files should be compressed into frames of their own");
+        };
+        let content = big_content();
+
+        let handle = open(&mut server, big).unwrap();
+        let mut read_back = Vec::new();
+        loop {
+            let read = read(&mut server, big, handle, read_back.len() as u64, 100_000);
+            if read.is_empty() {
+                break;
+            }
+            read_back.extend_from_slice(&read);
+        }
+        assert_eq!(read_back, content);
+        for index in 0..3 {
+            assert!(server.cache.blocks.contains_key(&(frame, index)));
+        }
+        assert!(!server.cache.blocks.contains_key(&(frame, 3)));
+
+        // a read spanning blocks, served from the cache by another handle
+        let other = open(&mut server, big).unwrap();
+        let from = BLOCK_SIZE - 10;
+        assert_eq!(
+            read(&mut server, big, other, from, 20),
+            content[from as usize..from as usize + 20]
+        );
+        assert!(server.handles[&other].is_none());
+        assert!(read(&mut server, big, other, content.len() as u64, 10).is_empty());
+
+        let small = lookup(&mut server, world, "small").unwrap();
+        assert!(matches!(
+            server.tree.node(small).kind,
+            NodeKind::File(Content::Batched { .. })
+        ));
+        let handle = open(&mut server, small).unwrap();
+        assert_eq!(read(&mut server, small, handle, 1, 3), b"mal");
+    }
+
+    #[test]
+    fn blocks_past_the_budget_evict_the_least_recently_read() {
+        let output = tempfile::tempdir().unwrap();
+        let tarball_path = output.path().join("snapshot.tar.zst");
+        let mut server = serve(output.path(), &tarball_path, BLOCK_SIZE * 2);
+        let world = lookup(&mut server, ROOT, "world").unwrap();
+        let big = lookup(&mut server, world, "big").unwrap();
+        let NodeKind::File(Content::Frame(frame, _)) = server.tree.node(big).kind else {
+            panic!("big files should be compressed into frames of their own");
+        };
+        let content = big_content();
+        let handle = open(&mut server, big).unwrap();
+
+        read(&mut server, big, handle, 0, 10);
+        read(&mut server, big, handle, BLOCK_SIZE, 10);
+        // reading the first block again leaves the second the least recently read
+        read(&mut server, big, handle, 0, 10);
+        read(&mut server, big, handle, BLOCK_SIZE * 2, 10);
+        let mut cached: Vec<(u64, u64)> = server.cache.blocks.keys().copied().collect();
+        cached.sort();
+        assert_eq!(cached, [(frame, 0), (frame, 2)]);
+        assert!(server.cache.held <= server.cache.budget);
+
+        // an evicted block is decompressed again, starting the frame over
+        assert_eq!(
+            read(&mut server, big, handle, BLOCK_SIZE, 10),
+            content[BLOCK_SIZE as usize..BLOCK_SIZE as usize + 10]
+        );
+        assert!(server.cache.blocks.contains_key(&(frame, 1)));
+    }
+
+    #[test]
+    fn only_files_open_and_only_for_reading() {
+        let output = tempfile::tempdir().unwrap();
+        let tarball_path = output.path().join("snapshot.tar.zst");
+        let mut server = serve(output.path(), &tarball_path, BLOCK_SIZE);
+        let world = lookup(&mut server, ROOT, "world").unwrap();
+        let link = lookup(&mut server, world, "link").unwrap();
+        let small = lookup(&mut server, world, "small").unwrap();
+
+        assert_eq!(open(&mut server, world), Err(libc::EISDIR));
+        assert_eq!(open(&mut server, link), Err(libc::ELOOP));
+        assert_eq!(
+            ask(&mut server, FUSE_READLINK, link, &[]),
+            Ok(b"big".to_vec())
+        );
+        let mut body = Vec::new();
+        put_u32(&mut body, libc::O_RDWR as u32);
+        put_u32(&mut body, 0);
+        assert_eq!(ask(&mut server, FUSE_OPEN, small, &body), Err(libc::EROFS));
+    }
+}
@@ -0,0 +1,135 @@
+use std::sync::OnceLock;
+use std::time::Duration;
+
+/// The socket systemd listens for notifications on, if the process runs as a notify service.
+static SOCKET: OnceLock<Option<String>> = OnceLock::new();
+
+/// Tells systemd the service is up, and from then on keeps its watchdog fed, if it has one.
+pub fn ready() {
+    send("READY=1");
+    if let Some(interval) = watchdog_interval() {
+        log::debug!(
+            "Pinging the systemd watchdog every {} ms",
+            interval.as_millis()
+        );
+        std::thread::spawn(move || loop {
+            send("WATCHDOG=1");
+            std::thread::sleep(interval);
+        });
+    }
+}
+
+/// Describes what the service is doing, for `systemctl status`.
+pub fn status(status: &str) {
+    send(&format!("STATUS={}", status));
+}
+
+/// Tells systemd the service is shutting down gracefully.
+pub fn stopping() {
+    send("STOPPING=1");
+}
+
+/// Half the watchdog timeout systemd set for this process, so a ping is never late.
+fn watchdog_interval() -> Option<Duration> {
+    let timeout: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    // a watchdog meant for another process, like the shell which started this one
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse() != Ok(std::process::id()) {
+            return None;
+        }
+    }
+    Some(Duration::from_micros(timeout) / 2)
+}
+
+/// Sends a notification to systemd's socket, doing nothing when there's no socket to send to.
+/// Notifications are best effort, so failing to send one only gets logged.
+fn send(state: &str) {
+    let socket = SOCKET.get_or_init(|| std::env::var("NOTIFY_SOCKET").ok());
+    if let Some(socket) = socket {
+        if let Err(err) = send_to(socket, state) {
+            log::debug!("Failed to notify systemd of {}: {}", state, err);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn send_to(socket: &str, state: &str) -> std::io::Result<()> {
+    let datagram = std::os::unix::net::UnixDatagram::unbound()?;
+    match socket.strip_prefix('@') {
+        Some(name) => send_to_abstract(&datagram, name, state),
+        None => datagram.send_to(state.as_bytes(), socket).map(|_| ()),
+    }
+}
+
+/// Sends to a socket in the abstract namespace, which systemd names with a leading `@`.
+#[cfg(target_os = "linux")]
+fn send_to_abstract(
+    datagram: &std::os::unix::net::UnixDatagram,
+    name: &str,
+    state: &str,
+) -> std::io::Result<()> {
+    use std::os::linux::net::SocketAddrExt;
+    let address = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+    datagram
+        .send_to_addr(state.as_bytes(), &address)
+        .map(|_| ())
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn send_to_abstract(
+    _datagram: &std::os::unix::net::UnixDatagram,
+    _name: &str,
+    _state: &str,
+) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "Abstract sockets are only supported on Linux",
+    ))
+}
+
+#[cfg(not(unix))]
+fn send_to(_socket: &str, _state: &str) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "Notifying systemd is only supported on unix",
+    ))
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+    use super::*;
+
+    fn received(socket: &UnixDatagram) -> String {
+        let mut buffer = [0; 64];
+        let received = socket.recv(&mut buffer).unwrap();
+        String::from_utf8_lossy(&buffer[..received]).into_owned()
+    }
+
+    #[test]
+    fn sends_one_datagram_to_a_socket_path() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("notify");
+        let socket = UnixDatagram::bind(&path).unwrap();
+        send_to(path.to_str().unwrap(), "STATUS=Archiving").unwrap();
+        assert_eq!(received(&socket), "STATUS=Archiving");
+    }
+
+    #[test]
+    fn sends_to_an_abstract_socket_named_with_an_at_sign() {
+        let name = format!("saved_in_time-test-{}", std::process::id());
+        let address = SocketAddr::from_abstract_name(&name).unwrap();
+        let socket = UnixDatagram::bind_addr(&address).unwrap();
+        send_to(&format!("@{}", name), "READY=1").unwrap();
+        assert_eq!(received(&socket), "READY=1");
+    }
+
+    #[test]
+    fn a_missing_socket_fails_to_send() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("missing");
+        assert!(send_to(path.to_str().unwrap(), "READY=1").is_err());
+    }
+}
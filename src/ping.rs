@@ -0,0 +1,125 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::WebPkiSupportedAlgorithms;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{DigitallySignedStruct, SignatureScheme};
+
+/// The check pinged when the run started, waiting to hear how it ended.
+static PING: Mutex<Option<Ping>> = Mutex::new(None);
+
+/// A dead man's switch in the style of healthchecks.io: the check is pinged at `<url>/start` when
+/// a run begins, then at `<url>` when it succeeds or `<url>/fail` when it fails, and raises an
+/// alarm when a run never pings at all.
+struct Ping {
+    url: String,
+    agent: ureq::Agent,
+}
+
+/// Pings the check at `<url>/start`, and arms it for `success` or `fail` to ping once the run
+/// ends. With `skip_tls_verify`, the server's certificate is accepted without checking it.
+pub fn start(url: &str, timeout: Duration, skip_tls_verify: bool) {
+    let mut agent = ureq::AgentBuilder::new().timeout(timeout);
+    if skip_tls_verify {
+        log::warn!("Not verifying the TLS certificate of {}", url);
+        match insecure_tls_config() {
+            Ok(config) => agent = agent.tls_config(Arc::new(config)),
+            Err(err) => log::warn!("Failed to set up TLS without verification: {}", err),
+        }
+    }
+    let ping = Ping {
+        url: url.trim_end_matches('/').to_string(),
+        agent: agent.build(),
+    };
+    ping.send("/start", None);
+    *PING.lock().unwrap() = Some(ping);
+}
+
+/// Pings the check to say the run succeeded, if it was started and hasn't heard yet.
+pub fn success() {
+    if let Some(ping) = take() {
+        ping.send("", None);
+    }
+}
+
+/// Pings the check to say the run failed, with why in the body, if it was started and hasn't
+/// heard yet.
+pub fn fail(error: &str) {
+    if let Some(ping) = take() {
+        ping.send("/fail", Some(error));
+    }
+}
+
+fn take() -> Option<Ping> {
+    match PING.lock() {
+        Ok(mut ping) => ping.take(),
+        Err(_) => None,
+    }
+}
+
+impl Ping {
+    /// Pings `<url><suffix>`, posting the body if there is one. Failing to ping is only logged, so
+    /// monitoring trouble never fails the snapshot.
+    fn send(&self, suffix: &str, body: Option<&str>) {
+        let url = format!("{}{}", self.url, suffix);
+        let pinged = match body {
+            Some(body) => self.agent.post(&url).send_string(body),
+            None => self.agent.get(&url).call(),
+        };
+        match pinged {
+            Ok(_) => log::debug!("Pinged {}", url),
+            Err(err) => log::warn!("Failed to ping the check: {}", err),
+        }
+    }
+}
+
+/// A TLS configuration which accepts any certificate the server presents, still checking the
+/// handshake is signed by it.
+fn insecure_tls_config() -> Result<rustls::ClientConfig, rustls::Error> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let verifier = AcceptAnyCertificate(provider.signature_verification_algorithms);
+    Ok(rustls::ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth())
+}
+
+#[derive(Debug)]
+struct AcceptAnyCertificate(WebPkiSupportedAlgorithms);
+
+impl ServerCertVerifier for AcceptAnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.0)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.0)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.supported_schemes()
+    }
+}
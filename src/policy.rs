@@ -0,0 +1,273 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+/// How a snapshot treats the entries it can't capture faithfully.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Mode {
+    /// Stop at the first entry which can't be captured faithfully, without writing an archive.
+    Strict,
+    /// Capture everything that can be, record every entry left out, and exit with code 13 if
+    /// there were any.
+    BestEffort,
+}
+
+/// A way in which a snapshot falls short of capturing the target faithfully.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum Compromise {
+    /// A path couldn't be read, or its capture couldn't be encoded.
+    Unreadable,
+    /// A symlink or listed path leads outside the target.
+    OutsideTarget,
+    /// A path is neither a file, a directory nor a symlink.
+    Unsupported,
+    /// A dereferenced symlink leads back into its own ancestry.
+    SymlinkLoop,
+    /// The snapshot ran out of time, and only holds what held still by then.
+    Partial,
+}
+
+impl std::fmt::Display for Compromise {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Compromise::Unreadable => write!(f, "unreadable"),
+            Compromise::OutsideTarget => write!(f, "outside_target"),
+            Compromise::Unsupported => write!(f, "unsupported"),
+            Compromise::SymlinkLoop => write!(f, "symlink_loop"),
+            Compromise::Partial => write!(f, "partial"),
+        }
+    }
+}
+
+/// What a snapshot does about an entry it can't capture faithfully.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Treatment {
+    /// Stop without writing an archive, exiting with code 5.
+    Refuse,
+    /// Leave the entry out, and exit with code 13 once the snapshot is finished.
+    Report,
+    /// Leave the entry out with only a warning.
+    Ignore,
+}
+
+/// How the flags change the way the mode treats particular compromises. The shorthands such as
+/// `--strict` and `--allow-partial` are spelled out into these lists before the policy is built.
+#[derive(Debug, Default)]
+pub struct Overrides {
+    /// Leave these out with only a warning.
+    pub ignore: Vec<Compromise>,
+    /// Carry on past these instead of refusing them.
+    pub tolerate: Vec<Compromise>,
+    /// Refuse these, whatever else says otherwise.
+    pub refuse: Vec<Compromise>,
+}
+
+/// How a snapshot treats every compromise, as set by `--mode` and the flags overriding it.
+#[derive(Debug, Clone)]
+pub struct Policy {
+    treatments: BTreeMap<Compromise, Treatment>,
+}
+
+impl Policy {
+    /// Starts from how the mode treats every compromise, then applies the overrides on top of it:
+    /// first what's ignored, then what's tolerated, then what's refused, so refusing always wins.
+    pub fn new(mode: Option<Mode>, overrides: &Overrides) -> Self {
+        let mut treatments: BTreeMap<Compromise, Treatment> = Compromise::value_variants()
+            .iter()
+            .map(|&compromise| (compromise, Self::base(mode, compromise)))
+            .collect();
+        for compromise in &overrides.ignore {
+            treatments.insert(*compromise, Treatment::Ignore);
+        }
+        for compromise in &overrides.tolerate {
+            // tolerating what's already carried on past doesn't make it worth reporting
+            if treatments[compromise] == Treatment::Refuse {
+                treatments.insert(*compromise, Treatment::Report);
+            }
+        }
+        for compromise in &overrides.refuse {
+            treatments.insert(*compromise, Treatment::Refuse);
+        }
+        Self { treatments }
+    }
+
+    /// How the mode alone treats the compromise.
+    fn base(mode: Option<Mode>, compromise: Compromise) -> Treatment {
+        match (mode, compromise) {
+            (Some(Mode::Strict), _) => Treatment::Refuse,
+            (Some(Mode::BestEffort), _) => Treatment::Report,
+            // without a mode, only unreadable paths are worth an exit code of their own, and
+            // running out of time leaves no archive
+            (None, Compromise::Unreadable) => Treatment::Report,
+            (None, Compromise::Partial) => Treatment::Refuse,
+            (None, _) => Treatment::Ignore,
+        }
+    }
+
+    pub fn treatment(&self, compromise: Compromise) -> Treatment {
+        self.treatments[&compromise]
+    }
+
+    /// Whether the snapshot carries on past the compromise.
+    pub fn tolerates(&self, compromise: Compromise) -> bool {
+        self.treatment(compromise) != Treatment::Refuse
+    }
+
+    /// Whether leaving out an entry for the compromise makes the snapshot exit with code 13.
+    pub fn reports(&self, compromise: Compromise) -> bool {
+        self.treatment(compromise) == Treatment::Report
+    }
+
+    /// Checks the compromise against the policy, returning the refusal which stops the snapshot
+    /// unless it's tolerated.
+    pub fn refuse<P: AsRef<Path>>(
+        &self,
+        path: P,
+        compromise: Compromise,
+        reason: impl ToString,
+    ) -> Option<Refusal> {
+        if self.tolerates(compromise) {
+            return None;
+        }
+        Some(Refusal {
+            path: path.as_ref().to_path_buf(),
+            compromise,
+            reason: reason.to_string(),
+        })
+    }
+}
+
+/// Reports every compromise, as a library caller without a policy of its own expects.
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            treatments: Compromise::value_variants()
+                .iter()
+                .map(|&compromise| (compromise, Treatment::Report))
+                .collect(),
+        }
+    }
+}
+
+/// A compromise the policy wouldn't carry on past.
+#[derive(Debug, Clone)]
+pub struct Refusal {
+    pub path: PathBuf,
+    pub compromise: Compromise,
+    pub reason: String,
+}
+
+impl std::fmt::Display for Refusal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} ({}): {}",
+            self.path.display(),
+            self.compromise,
+            self.reason
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn treatments(policy: &Policy) -> Vec<Treatment> {
+        Compromise::value_variants()
+            .iter()
+            .map(|&compromise| policy.treatment(compromise))
+            .collect()
+    }
+
+    #[test]
+    fn the_modes_treat_every_compromise_alike() {
+        let strict = Policy::new(Some(Mode::Strict), &Overrides::default());
+        assert!(treatments(&strict).iter().all(|&t| t == Treatment::Refuse));
+        let best_effort = Policy::new(Some(Mode::BestEffort), &Overrides::default());
+        assert!(treatments(&best_effort)
+            .iter()
+            .all(|&t| t == Treatment::Report));
+    }
+
+    #[test]
+    fn without_a_mode_only_unreadable_paths_are_reported() {
+        let policy = Policy::new(None, &Overrides::default());
+        assert_eq!(policy.treatment(Compromise::Unreadable), Treatment::Report);
+        assert_eq!(
+            policy.treatment(Compromise::OutsideTarget),
+            Treatment::Ignore
+        );
+        assert_eq!(policy.treatment(Compromise::Unsupported), Treatment::Ignore);
+        assert_eq!(policy.treatment(Compromise::SymlinkLoop), Treatment::Ignore);
+        assert_eq!(policy.treatment(Compromise::Partial), Treatment::Refuse);
+    }
+
+    #[test]
+    fn ignoring_overrides_any_mode() {
+        let overrides = Overrides {
+            ignore: vec![Compromise::Unreadable],
+            ..Overrides::default()
+        };
+        for mode in [None, Some(Mode::Strict), Some(Mode::BestEffort)] {
+            let policy = Policy::new(mode, &overrides);
+            assert_eq!(policy.treatment(Compromise::Unreadable), Treatment::Ignore);
+        }
+        // it leaves every other compromise to the mode
+        let policy = Policy::new(Some(Mode::Strict), &overrides);
+        assert_eq!(
+            policy.treatment(Compromise::OutsideTarget),
+            Treatment::Refuse
+        );
+    }
+
+    #[test]
+    fn tolerating_leaves_what_was_ignored_ignored() {
+        let overrides = Overrides {
+            ignore: vec![Compromise::Unreadable],
+            tolerate: vec![Compromise::Unreadable],
+            ..Overrides::default()
+        };
+        let policy = Policy::new(Some(Mode::Strict), &overrides);
+        assert_eq!(policy.treatment(Compromise::Unreadable), Treatment::Ignore);
+    }
+
+    #[test]
+    fn tolerating_only_changes_what_was_refused() {
+        let overrides = Overrides {
+            tolerate: vec![Compromise::Unsupported, Compromise::Partial],
+            ..Overrides::default()
+        };
+        let policy = Policy::new(Some(Mode::Strict), &overrides);
+        assert_eq!(policy.treatment(Compromise::Unsupported), Treatment::Report);
+        assert_eq!(policy.treatment(Compromise::SymlinkLoop), Treatment::Refuse);
+        // without a mode, unsupported paths are already only a warning
+        let policy = Policy::new(None, &overrides);
+        assert_eq!(policy.treatment(Compromise::Unsupported), Treatment::Ignore);
+        assert_eq!(policy.treatment(Compromise::Partial), Treatment::Report);
+    }
+
+    #[test]
+    fn refusing_wins_over_everything_else() {
+        let overrides = Overrides {
+            ignore: vec![Compromise::Unreadable],
+            tolerate: vec![Compromise::Unreadable, Compromise::Partial],
+            refuse: vec![
+                Compromise::Unreadable,
+                Compromise::Partial,
+                Compromise::OutsideTarget,
+            ],
+        };
+        let policy = Policy::new(Some(Mode::BestEffort), &overrides);
+        assert_eq!(policy.treatment(Compromise::Unreadable), Treatment::Refuse);
+        assert_eq!(policy.treatment(Compromise::Partial), Treatment::Refuse);
+        assert_eq!(
+            policy.treatment(Compromise::OutsideTarget),
+            Treatment::Refuse
+        );
+        assert_eq!(policy.treatment(Compromise::SymlinkLoop), Treatment::Report);
+    }
+}
@@ -0,0 +1,169 @@
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use crate::cancel::Cancellation;
+use crate::filter::{self, EntryFilter, EntryKind, FilterDecision};
+
+/// What a quick pass over the target found before capturing it.
+#[derive(Default)]
+pub struct Preflight {
+    /// Entries beneath the target the filters let through, estimating how many the walk visits.
+    pub entries: usize,
+    /// Directories which couldn't be listed and files which couldn't be opened, and why.
+    pub unreadable: Vec<(PathBuf, std::io::Error)>,
+}
+
+/// Walks the target once without reading any content, counting the entries the filters let
+/// through, listing every directory and opening every file to confirm it can be read. Whatever
+/// can't be read is collected rather than returned, so every problem is known before the real
+/// walk starts, which then finds the target's directory entries already cached. The pass stops
+/// early once the snapshot is asked to stop.
+pub fn preflight(
+    target: &Path,
+    filters: &[Box<dyn EntryFilter>],
+    cancel: &Cancellation,
+) -> Preflight {
+    let mut found = Preflight::default();
+    walk(target, filters, cancel, &mut found);
+    found
+}
+
+fn walk(
+    directory: &Path,
+    filters: &[Box<dyn EntryFilter>],
+    cancel: &Cancellation,
+    found: &mut Preflight,
+) {
+    let listing = match directory.read_dir() {
+        Ok(listing) => listing,
+        Err(err) => {
+            found.unreadable.push((directory.to_path_buf(), err));
+            return;
+        }
+    };
+    for entry in listing {
+        if cancel.is_cancelled() {
+            return;
+        }
+        let (path, file_type) = match entry.and_then(|entry| Ok((entry.path(), entry.file_type()?)))
+        {
+            Ok(entry) => entry,
+            Err(err) => {
+                found.unreadable.push((directory.to_path_buf(), err));
+                continue;
+            }
+        };
+        let kind = if file_type.is_dir() {
+            EntryKind::Directory
+        } else if file_type.is_file() {
+            EntryKind::File
+        } else if file_type.is_symlink() {
+            EntryKind::Symlink
+        } else {
+            continue;
+        };
+        let decision = match filter::evaluate(filters, &path, kind) {
+            Ok(decision) => decision,
+            Err(err) => {
+                found.unreadable.push((path, err));
+                continue;
+            }
+        };
+        match decision {
+            FilterDecision::PruneSubtree => continue,
+            FilterDecision::SkipEntry if kind != EntryKind::Directory => continue,
+            FilterDecision::SkipEntry => {}
+            FilterDecision::Include => found.entries += 1,
+        }
+        match kind {
+            EntryKind::Directory => walk(&path, filters, cancel, found),
+            // opening a file is enough to tell its permissions allow reading it
+            EntryKind::File => {
+                if let Err(err) = File::open(&path) {
+                    found.unreadable.push((path, err));
+                }
+            }
+            EntryKind::Symlink => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::Metadata;
+
+    use super::*;
+    use crate::processor::tests::write;
+
+    #[test]
+    fn entries_the_filters_let_through_are_counted() {
+        let target = tempfile::tempdir().unwrap();
+        for path in [
+            "a",
+            "sub/b",
+            "sub/c",
+            "pruned/d",
+            "pruned/deeper/e",
+            "skipped",
+        ] {
+            write(&target.path().join(path), path);
+        }
+        let filters: Vec<Box<dyn EntryFilter>> = vec![Box::new(
+            |path: &Path, _: &Metadata, _: EntryKind| match path.file_name().unwrap().to_str() {
+                Some("pruned") => FilterDecision::PruneSubtree,
+                Some("skipped") => FilterDecision::SkipEntry,
+                _ => FilterDecision::Include,
+            },
+        )];
+        let found = preflight(target.path(), &filters, &Cancellation::default());
+        // a, sub, sub/b and sub/c
+        assert_eq!(found.entries, 4);
+        assert!(found.unreadable.is_empty());
+
+        let found = preflight(target.path(), &[], &Cancellation::default());
+        assert_eq!(found.entries, 9);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn paths_which_cant_be_read_are_all_collected() {
+        use std::os::unix::fs::PermissionsExt;
+        if unsafe { libc::geteuid() } == 0 {
+            eprintln!("permissions don't keep root out; skipping");
+            return;
+        }
+        let target = tempfile::tempdir().unwrap();
+        write(&target.path().join("file"), "file");
+        write(&target.path().join("locked/file"), "file");
+        write(&target.path().join("readable"), "readable");
+        for path in ["file", "locked"] {
+            std::fs::set_permissions(target.path().join(path), PermissionsExt::from_mode(0o000))
+                .unwrap();
+        }
+        let found = preflight(target.path(), &[], &Cancellation::default());
+        let mut unreadable: Vec<&Path> = found
+            .unreadable
+            .iter()
+            .map(|(path, _)| path.as_path())
+            .collect();
+        unreadable.sort();
+        assert_eq!(
+            unreadable,
+            [target.path().join("file"), target.path().join("locked")]
+        );
+        std::fs::set_permissions(
+            target.path().join("locked"),
+            PermissionsExt::from_mode(0o755),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn the_walk_stops_once_the_snapshot_is_stopped() {
+        let target = tempfile::tempdir().unwrap();
+        write(&target.path().join("a"), "a");
+        let cancel = Cancellation::default();
+        cancel.set_deadline(std::time::Instant::now(), false);
+        assert_eq!(preflight(target.path(), &[], &cancel).entries, 0);
+    }
+}
@@ -1,91 +1,740 @@
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::fs::{File, Metadata};
-use std::io::Write;
+use std::io::{BufRead, BufReader, Read, Write};
 use std::path::{Path, PathBuf};
-use std::time::SystemTime;
-use tempfile::tempfile;
+use std::time::{Duration, Instant, SystemTime};
+
+use globset::GlobMatcher;
+use serde::Serialize;
+
+use crate::archiver::path_bytes;
+use crate::cancel::Cancellation;
+use crate::checkpoint::Checkpoint;
+use crate::detector::{file_identity, same_content, CaptureRecord, ChangeDetector, Stability};
+use crate::dictionary::{self, Dictionary};
+use crate::filter::{self, EntryFilter, EntryKind, FilterDecision};
+use crate::hash_cache::HashCache;
+use crate::hashing::{HashAlgorithm, Hasher};
+use crate::notify;
+use crate::policy::{Compromise, Policy, Refusal};
+use crate::report::{self, SkipCode, SnapshotReport};
+use crate::throttle::LoadThrottle;
+use crate::warnings::{Warning, WarningCounts};
+
+/// Digest of a file's original (uncompressed) content, with the snapshot's hash algorithm.
+pub type Digest = [u8; 32];
 
 pub struct Entry {
     pub path: PathBuf,
     pub metadata: Metadata,
     pub entry_type: EntryType,
+    /// Compression level a file's content was captured at, unless its content wasn't captured.
+    pub level: Option<i32>,
 }
 
 pub enum EntryType {
-    File(File),
-    Symlink,
-    Directory,
+    /// A file's captured content and its digest, which is missing when only its metadata was
+    /// captured.
+    File(File, Option<Digest>),
+    /// What was drained from a named pipe, along with its digest and size, which the pipe's own
+    /// metadata doesn't tell.
+    Drained(File, Digest, u64),
+    /// A symlink, with the digest of where it points when symlinks are hashed.
+    Symlink(Option<Digest>),
+    /// The number of entries listed in the directory when it was captured, whether or not they
+    /// were captured themselves.
+    Directory(usize),
+}
+
+/// Default capacity of the buffer files are read through while being captured.
+pub const DEFAULT_READ_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Settle windows waited between two passes before revisiting paths which are still changing.
+const SETTLE_WINDOWS: u32 = 10;
+/// How often a wait for paths to settle checks whether the snapshot was cancelled.
+const SETTLE_POLL: Duration = Duration::from_millis(100);
+
+/// A compression level for the files whose path matches a glob, in place of the global level.
+#[derive(Debug, Clone)]
+pub struct LevelOverride {
+    pub glob: GlobMatcher,
+    pub level: i32,
+}
+
+/// Settings controlling how entries are captured.
+pub struct CaptureOptions {
+    pub compression_level: i32,
+    /// Levels for particular files, of which the first whose glob matches a file's path applies.
+    pub level_overrides: Vec<LevelOverride>,
+    pub read_buffer_size: usize,
+    /// Filters deciding which paths are captured.
+    pub filters: Vec<Box<dyn EntryFilter>>,
+    /// Entries modified this close before a visit revision may hide a later modification behind
+    /// the filesystem's timestamp resolution, so their capture is confirmed on another pass.
+    pub mtime_slack: Duration,
+    /// How far ahead of the local clock an mtime may be before it's treated as bogus. Entries
+    /// beyond it are captured with a warning once they hold still, instead of being retried as
+    /// modified after every visit revision.
+    pub future_mtime_slack: Duration,
+    /// How long the paths found changing during a pass must hold still before the next pass, so
+    /// a burst of writes can finish instead of being caught midway by every pass.
+    pub settle: Option<Duration>,
+    /// Record files which fail to encode as failed instead of aborting the snapshot.
+    pub best_effort_files: bool,
+    /// How the capture treats the paths it can't capture faithfully.
+    pub policy: Policy,
+    /// Asks the capture to stop once the path being visited is done.
+    pub cancel: Cancellation,
+    /// Keeps captures on disk so an interrupted walk can resume from them.
+    pub checkpoint: Option<RefCell<Checkpoint>>,
+    /// Judges whether a captured file still holds the captured content.
+    pub change_detector: Box<dyn ChangeDetector>,
+    /// Capture what symlinks point to in place of the links themselves, expanding links to
+    /// directories into full subtrees.
+    pub dereference: bool,
+    /// Algorithm the content of captured files is hashed with.
+    pub hash_algorithm: HashAlgorithm,
+    /// Read captured files without updating their access time, where the platform allows it.
+    pub preserve_atime: bool,
+    /// Dictionary captured files are compressed with.
+    pub dictionary: Option<Dictionary>,
+    /// Capture the metadata of files without reading their content, leaving every file empty.
+    pub metadata_only: bool,
+    /// Capture named pipes as files holding up to this many of the bytes buffered in them,
+    /// draining those bytes from the pipe. Named pipes are left out otherwise.
+    pub drain_fifos: Option<u64>,
+    /// Digests of files unchanged since the earlier snapshot, which files still looking the same
+    /// are taken to hold instead of being read again. Those files are left out of the archive, so
+    /// their captures are left empty.
+    pub hash_cache: Option<RefCell<HashCache>>,
+    /// Judge the target directory itself only by its listing, ignoring its mtime, which moves
+    /// whenever anything is created or removed in it, even if only for a moment.
+    pub ignore_root_mtime: bool,
+    /// Hash where every symlink points, so the manifest tells when one is pointed elsewhere.
+    pub hash_symlinks: bool,
+    /// Pauses the walk between paths while the system is too busy.
+    pub load_throttle: Option<LoadThrottle>,
+    /// Directory captures are written to until they're archived, unless they're checkpointed.
+    pub temp_dir: PathBuf,
+    /// Files modified more recently than this are left out of the snapshot instead of being
+    /// captured, without holding up the pass for another.
+    pub min_age: Option<Duration>,
+    /// Leave out the directories beneath the target holding nothing captured anywhere beneath
+    /// them, for when filters leave most files out and only the structure around the rest counts.
+    pub prune_empty_directories: bool,
+}
+
+impl CaptureOptions {
+    /// The level the file at the path is compressed at.
+    fn compression_level(&self, path: &Path) -> i32 {
+        self.level_overrides
+            .iter()
+            .find(|level_override| level_override.glob.is_match(path))
+            .map_or(self.compression_level, |level_override| {
+                level_override.level
+            })
+    }
+
+    /// Creates an anonymous file in the temp directory to write a capture to.
+    fn temp_file(&self) -> std::io::Result<File> {
+        tempfile::tempfile_in(&self.temp_dir)
+    }
+
+    fn temp_dir_unavailable(&self, err: std::io::Error) -> ProcessError {
+        ProcessError::TempDirUnavailable(self.temp_dir.clone(), err)
+    }
+
+    fn write_checkpoint(&self) -> Result<(), ProcessError> {
+        match &self.checkpoint {
+            Some(checkpoint) => {
+                let mut checkpoint = checkpoint.borrow_mut();
+                checkpoint.write().map_err(|err| {
+                    ProcessError::CheckpointFailed(checkpoint.directory().to_path_buf(), err)
+                })
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// Filesystems whose timestamps are coarser than, or not synchronized with, the local clock,
+/// along with the mtime slack they need. FAT only stores mtimes to 2 seconds, while network
+/// filesystems take their timestamps from the server's clock.
+const COARSE_FILESYSTEMS: &[(&str, Duration)] = &[
+    ("vfat", Duration::from_secs(4)),
+    ("msdos", Duration::from_secs(4)),
+    ("exfat", Duration::from_secs(4)),
+    ("nfs", Duration::from_secs(4)),
+    ("nfs4", Duration::from_secs(4)),
+    ("cifs", Duration::from_secs(4)),
+    ("smb3", Duration::from_secs(4)),
+];
+
+/// Detects whether the path lives on a filesystem with coarse timestamps, returning the
+/// filesystem type and the mtime slack it needs.
+#[cfg(target_os = "linux")]
+pub fn coarse_filesystem_slack(path: &Path) -> Option<(String, Duration)> {
+    let mounts = std::fs::read_to_string("/proc/self/mounts").ok()?;
+    // the mount point closest to the path is the one it lives on
+    let filesystem = mounts
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split(' ');
+            let mount_point = fields.nth(1)?.replace("\\040", " ");
+            let filesystem = fields.next()?;
+            path.starts_with(&mount_point)
+                .then(|| (mount_point.len(), filesystem.to_string()))
+        })
+        .max_by_key(|(length, _)| *length)
+        .map(|(_, filesystem)| filesystem)?;
+    COARSE_FILESYSTEMS
+        .iter()
+        .find(|(name, _)| *name == filesystem)
+        .map(|(_, slack)| (filesystem, *slack))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn coarse_filesystem_slack(_path: &Path) -> Option<(String, Duration)> {
+    None
 }
 
 #[derive(Debug)]
 pub enum ProcessError {
-    UnrecoverableUnknown,
-    PathNotDir,
-    MetadataFetchFailed,
-    IterationBoundExceeded,
+    EncodeFailed(PathBuf, std::io::Error),
+    CheckpointFailed(PathBuf, std::io::Error),
+    PathNotDir(PathBuf),
+    TargetVanished(PathBuf),
+    /// Captures could not be written to the temp directory, as it's full, missing or unwritable.
+    /// Every capture needs it, so there's no carrying on past it.
+    TempDirUnavailable(PathBuf, std::io::Error),
+    /// The iteration bound was reached while these paths were still unstable.
+    IterationBoundExceeded(Vec<PathBuf>),
+    /// A graceful stop was requested partway through a pass.
+    Cancelled,
+    /// The policy refused to carry on past a path which couldn't be captured faithfully.
+    Refused(Refusal),
 }
 
-impl Error for ProcessError {}
+/// Why a path which is neither a file, a directory nor a symlink is left out.
+const UNSUPPORTED: &str = "neither a file, a directory nor a symlink";
+
+/// Why a dereferenced symlink leading back into its own ancestry is left out.
+const SYMLINK_LOOP: &str = "symlink leads back into its own ancestry";
+
+/// Number of unstable paths named when the iteration bound is exceeded.
+const REPORTED_UNSTABLE_PATHS: usize = 5;
+
+impl Error for ProcessError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ProcessError::EncodeFailed(_, err)
+            | ProcessError::CheckpointFailed(_, err)
+            | ProcessError::TempDirUnavailable(_, err) => Some(err),
+            _ => None,
+        }
+    }
+}
 
 impl std::fmt::Display for ProcessError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            ProcessError::UnrecoverableUnknown => write!(f, "Unrecoverable unknown error"),
-            ProcessError::PathNotDir => write!(f, "Path is not a directory"),
-            ProcessError::MetadataFetchFailed => write!(f, "Failed to fetch metadata"),
-            ProcessError::IterationBoundExceeded => write!(f, "Iteration bound exceeded"),
+            ProcessError::EncodeFailed(path, err) => {
+                write!(f, "Failed to encode data for {}: {}", path.display(), err)
+            }
+            ProcessError::CheckpointFailed(path, err) => {
+                write!(f, "Failed to checkpoint into {}: {}", path.display(), err)
+            }
+            ProcessError::PathNotDir(path) => {
+                write!(f, "Path is not a directory: {}", path.display())
+            }
+            ProcessError::TargetVanished(path) => {
+                write!(f, "Target vanished during the snapshot: {}", path.display())
+            }
+            ProcessError::TempDirUnavailable(path, err) => write!(
+                f,
+                "Failed to write captures to the temp directory {}: {}; free up space there or \
+                 pick another directory with --temp-dir",
+                path.display(),
+                err
+            ),
+            ProcessError::Cancelled => write!(f, "Snapshot cancelled"),
+            ProcessError::Refused(refusal) => write!(f, "Refused to carry on past {}", refusal),
+            ProcessError::IterationBoundExceeded(unstable) => {
+                write!(f, "Iteration bound exceeded; still unstable:")?;
+                for path in unstable.iter().take(REPORTED_UNSTABLE_PATHS) {
+                    write!(f, " {}", path.display())?;
+                }
+                if unstable.len() > REPORTED_UNSTABLE_PATHS {
+                    write!(
+                        f,
+                        " (and {} more)",
+                        unstable.len() - REPORTED_UNSTABLE_PATHS
+                    )?;
+                }
+                Ok(())
+            }
         }
     }
 }
 
+/// What a single pass over the target observed.
+#[derive(Debug, Default)]
+pub struct PassReport {
+    /// Entries whose previous capture still matches what is on disk.
+    pub unchanged: usize,
+    /// Entries captured (or captured again) during the pass.
+    pub recaptured: usize,
+    /// Entries which changed while being observed; another pass is needed to capture them.
+    pub unstable: Vec<UnstablePath>,
+    /// Entries which could not be captured at all.
+    pub failed: Vec<FailedPath>,
+    /// Paths a filter left out along with everything beneath them.
+    pub excluded: Vec<PathBuf>,
+    /// Files left out for having been modified within the minimum age.
+    pub too_young: Vec<PathBuf>,
+    /// Paths left out for being neither a file, a directory nor a symlink.
+    pub unsupported: Vec<PathBuf>,
+    /// Dereferenced symlinks left out for leading back into their own ancestry.
+    pub loops: Vec<PathBuf>,
+    /// Bytes read from files captured during the pass.
+    pub bytes_read: u64,
+    /// Warnings run into during the pass.
+    pub warnings: WarningCounts,
+    /// The first compromise the policy refused during the pass, which stops it.
+    pub refusal: Option<Refusal>,
+}
+
+/// A path which changed while being observed, and how.
+#[derive(Debug, Clone, Serialize)]
+pub struct UnstablePath {
+    pub path: PathBuf,
+    pub reason: Instability,
+}
+
+/// How a path was seen to change while being observed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Instability {
+    /// The path disappeared while being visited.
+    Vanished,
+    /// The path was modified after the pass started.
+    ModifiedAfterRevision,
+    /// The file changed while its content was being read.
+    ChangedWhileCaptured,
+    /// Reading the file yielded a different number of bytes than its size, as it grew or shrank
+    /// between being looked at and being read.
+    Resized,
+    /// The file's content changed although its mtime didn't.
+    ChangedWithoutMtime,
+    /// The path was modified within the mtime slack of the pass and has to be confirmed.
+    WithinSlack,
+    /// The path has a future mtime and looked different than on the previous pass.
+    FutureDated,
+    /// Part of the directory's listing could not be read.
+    ListingFailed,
+}
+
+impl std::fmt::Display for Instability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Instability::Vanished => write!(f, "vanished"),
+            Instability::ModifiedAfterRevision => write!(f, "modified after the pass started"),
+            Instability::ChangedWhileCaptured => write!(f, "changed while captured"),
+            Instability::Resized => write!(f, "resized before they were read"),
+            Instability::ChangedWithoutMtime => write!(f, "changed without their mtime moving"),
+            Instability::WithinSlack => write!(f, "modified within the mtime slack"),
+            Instability::FutureDated => write!(f, "future dated and changed"),
+            Instability::ListingFailed => write!(f, "only partly listed"),
+        }
+    }
+}
+
+/// A path which could not be captured, and why.
+#[derive(Debug)]
+pub struct FailedPath {
+    pub path: PathBuf,
+    pub error: std::io::Error,
+}
+
+/// The outcome of visiting a single path during a pass.
+#[derive(Debug)]
+enum Outcome {
+    /// The previous capture still matches what is on disk.
+    Unchanged,
+    /// The path was captured during this pass.
+    Captured,
+    /// The path changed while being observed; it is revisited on the next pass.
+    Unstable(Instability),
+    /// The file was modified within the minimum age. It's left out, but never calls for another
+    /// pass: it's looked at again only if one is needed anyway.
+    TooYoung,
+    /// The path can't be captured; it's recorded and skipped while its siblings carry on.
+    Failed(std::io::Error),
+}
+
+impl Outcome {
+    fn is_stable(&self) -> bool {
+        matches!(self, Outcome::Unchanged | Outcome::Captured)
+    }
+}
+
+impl PassReport {
+    fn record(&mut self, policy: &Policy, path: &Path, outcome: Outcome) {
+        match outcome {
+            Outcome::Unchanged => self.unchanged += 1,
+            Outcome::Captured => self.recaptured += 1,
+            Outcome::Unstable(reason) => self.unstable.push(UnstablePath {
+                path: path.to_path_buf(),
+                reason,
+            }),
+            Outcome::TooYoung => self.too_young.push(path.to_path_buf()),
+            Outcome::Failed(error) => {
+                log::error!("Failed to capture {}: {}", path.display(), error);
+                if self.refusal.is_none() {
+                    self.refusal = policy.refuse(path, Compromise::Unreadable, &error);
+                }
+                self.failed.push(FailedPath {
+                    path: path.to_path_buf(),
+                    error,
+                });
+            }
+        }
+    }
+}
+
+/// Everything captured by processing, along with the paths which had to be left out.
+pub struct Processed {
+    /// The captured entries in path order, streamed so each capture is released once consumed.
+    pub entries: Box<dyn Iterator<Item = Entry>>,
+    pub failed: Vec<FailedPath>,
+    /// Number of entries streamed.
+    pub entry_count: usize,
+    /// Number of files among the entries.
+    pub file_count: usize,
+    /// Combined size of the compressed captures streamed.
+    pub encoded_bytes: u64,
+}
+
 pub fn process_directory<P: AsRef<Path>>(
     directory_path: P,
     max_iterations: i32,
-    compression_level: i32,
-) -> Result<Vec<Entry>, ProcessError> {
+    options: &CaptureOptions,
+    snapshot: &mut SnapshotReport,
+) -> Result<Processed, ProcessError> {
     let path = directory_path.as_ref();
     if !path.is_dir() {
-        return Err(ProcessError::PathNotDir);
+        return Err(ProcessError::PathNotDir(path.to_path_buf()));
     }
     log::debug!("Processing directory {}", path.display());
+    let mut visitor = Visitor::create(path);
+    snapshot.hash_algorithm = options.hash_algorithm;
+    snapshot.dictionary_id = options.dictionary.as_ref().map(Dictionary::id);
+    snapshot.metadata_only = options.metadata_only;
+    let start = Instant::now();
+    let converged = converge(max_iterations, options, snapshot, |revision, report| {
+        // losing the target itself can't be isolated to a subtree
+        if !path.is_dir() {
+            return Err(ProcessError::TargetVanished(path.to_path_buf()));
+        }
+        let visited = visitor.visit(revision, options, report);
+        // whatever a cancelled pass captured is kept for resuming
+        let checkpointed = options.write_checkpoint();
+        visited.and(checkpointed)
+    });
+    snapshot.durations.capture_ms = report::millis(start.elapsed());
+    let failed = match converged {
+        Ok((last_time, report)) => {
+            log::debug!("Compiling with {:#?}", last_time);
+            report.failed
+        }
+        Err(ProcessError::Cancelled) if options.cancel.keep_partial() => {
+            keep_partial(snapshot);
+            let mut dropped = Vec::new();
+            visitor.dropped(&mut dropped);
+            for path in dropped {
+                snapshot.skip(
+                    &options.policy,
+                    path,
+                    SkipCode::Unstable,
+                    "not captured stable in time",
+                );
+            }
+            Vec::new()
+        }
+        Err(err) => return Err(err),
+    };
+    if options.prune_empty_directories {
+        visitor.prune_empty();
+    }
+
+    let (entry_count, file_count, encoded_bytes) = visitor.compiled_size();
+    Ok(Processed {
+        entries: Box::new(Compiled {
+            pending: vec![Pending::Directory(visitor)],
+        }),
+        failed,
+        entry_count,
+        file_count,
+        encoded_bytes,
+    })
+}
+
+/// Captures an explicit list of files instead of walking a directory, applying the same stability
+/// checks to every listed file.
+pub fn process_files(
+    files: &[PathBuf],
+    max_iterations: i32,
+    options: &CaptureOptions,
+    snapshot: &mut SnapshotReport,
+) -> Result<Processed, ProcessError> {
+    log::debug!("Processing {} listed files", files.len());
+    // a listed path the policy refused was refused before anything was captured
+    if let Some(refusal) = snapshot.refusal.clone() {
+        return Err(ProcessError::Refused(refusal));
+    }
+    let mut entries: HashMap<PathBuf, WeakEntry> = HashMap::new();
+    snapshot.hash_algorithm = options.hash_algorithm;
+    snapshot.dictionary_id = options.dictionary.as_ref().map(Dictionary::id);
+    snapshot.metadata_only = options.metadata_only;
+    let start = Instant::now();
+    let converged = converge(max_iterations, options, snapshot, |revision, report| {
+        for path in files {
+            if let Some(throttle) = &options.load_throttle {
+                throttle.wait(&options.cancel);
+            }
+            if options.cancel.is_cancelled() {
+                options.write_checkpoint()?;
+                return Err(ProcessError::Cancelled);
+            }
+            if let Some(refusal) = report.refusal.take() {
+                return Err(ProcessError::Refused(refusal));
+            }
+            let decision = match filter::evaluate(&options.filters, path, EntryKind::File) {
+                Ok(decision) => decision,
+                Err(err) => {
+                    report.record(&options.policy, path, Outcome::Failed(err));
+                    entries.remove(path);
+                    continue;
+                }
+            };
+            if decision != FilterDecision::Include {
+                log::debug!("Filtered out {}", path.display());
+                if decision == FilterDecision::PruneSubtree {
+                    report.excluded.push(path.clone());
+                }
+                entries.remove(path);
+                continue;
+            }
+            entries
+                .entry(path.clone())
+                .or_insert_with(|| WeakEntry::create(path))
+                .visit(revision, options, report)?;
+        }
+        options.write_checkpoint()
+    });
+    snapshot.durations.capture_ms = report::millis(start.elapsed());
+    let failed = match converged {
+        Ok((_, report)) => report.failed,
+        Err(ProcessError::Cancelled) if options.cancel.keep_partial() => {
+            keep_partial(snapshot);
+            for entry in entries
+                .values()
+                .filter(|entry| !entry.stable && !entry.too_young)
+            {
+                snapshot.skip(
+                    &options.policy,
+                    &entry.path,
+                    SkipCode::Unstable,
+                    "not captured stable in time",
+                );
+            }
+            Vec::new()
+        }
+        Err(err) => return Err(err),
+    };
+
+    let mut compiled_entries: Vec<WeakEntry> =
+        entries.into_values().filter(|entry| entry.stable).collect();
+    compiled_entries.sort_by(|a, b| a.path.cmp(&b.path));
+    log::debug!("Compiled {} entries", compiled_entries.len());
+    Ok(Processed {
+        entry_count: compiled_entries.len(),
+        file_count: compiled_entries.len(),
+        encoded_bytes: compiled_entries.iter().map(WeakEntry::encoded_size).sum(),
+        entries: Box::new(compiled_entries.into_iter().map(Entry::from)),
+        failed,
+    })
+}
+
+/// Settles for the entries captured stable before time ran out, leaving out the rest.
+fn keep_partial(snapshot: &mut SnapshotReport) {
+    log::warn!("Ran out of time while capturing; keeping what was captured so far");
+    snapshot.partial = true;
+}
+
+/// Runs passes over the target until one observes no unstable entries, returning the revision and
+/// report of that final pass. Every pass is added to the snapshot report as it completes.
+fn converge<F>(
+    max_iterations: i32,
+    options: &CaptureOptions,
+    snapshot: &mut SnapshotReport,
+    mut pass: F,
+) -> Result<(SystemTime, PassReport), ProcessError>
+where
+    F: FnMut(SystemTime, &mut PassReport) -> Result<(), ProcessError>,
+{
     let mut iterations = 0;
-    let mut last_time = SystemTime::now();
-    let mut visitor =
-        Visitor::create(path, last_time).map_err(|_| ProcessError::MetadataFetchFailed)?;
-    log::debug!("Initial visit: {:#?}", last_time);
-    while match visitor.visit(last_time, compression_level) {
-        Ok(_) => false,
-        Err(recoverable) => {
-            if iterations >= max_iterations {
-                return Err(ProcessError::IterationBoundExceeded);
-            }
-            if recoverable {
-                true
-            } else {
-                return Err(ProcessError::UnrecoverableUnknown);
+    loop {
+        let revision = SystemTime::now();
+        let mut report = PassReport::default();
+        log::debug!("Iteration {} with revision {:#?}", iterations, revision);
+        notify::status(&format!("Visiting the target, iteration {}", iterations));
+        let passed = pass(revision, &mut report);
+        snapshot.iterations += 1;
+        snapshot.bytes_read += report.bytes_read;
+        snapshot.unstable.extend(report.unstable.iter().cloned());
+        snapshot.warnings.add(&report.warnings);
+        passed?;
+        if let Some(refusal) = report.refusal.take() {
+            return Err(ProcessError::Refused(refusal));
+        }
+        log::debug!(
+            "Iteration {}: {} unchanged, {} re-captured, {} unstable, {} failed",
+            iterations,
+            report.unchanged,
+            report.recaptured,
+            report.unstable.len(),
+            report.failed.len()
+        );
+        if report.unstable.is_empty() {
+            snapshot.stamp(revision);
+            let policy = &options.policy;
+            for failed in &report.failed {
+                snapshot.skip(policy, &failed.path, SkipCode::Unreadable, &failed.error);
+            }
+            for excluded in &report.excluded {
+                snapshot.skip(policy, excluded, SkipCode::Excluded, "excluded by a filter");
+            }
+            for young in &report.too_young {
+                snapshot.skip(
+                    policy,
+                    young,
+                    SkipCode::TooRecent,
+                    "modified within the minimum age",
+                );
+            }
+            snapshot.too_recent = report.too_young.len();
+            for unsupported in &report.unsupported {
+                snapshot.skip(policy, unsupported, SkipCode::Unsupported, UNSUPPORTED);
+            }
+            for link in &report.loops {
+                snapshot.skip(policy, link, SkipCode::SymlinkLoop, SYMLINK_LOOP);
             }
+            return Ok((revision, report));
+        }
+        log::info!(
+            "Iteration {}: {} paths changed and will be revisited ({})",
+            iterations,
+            report.unstable.len(),
+            summarize_unstable(&report.unstable)
+        );
+        if iterations >= max_iterations {
+            return Err(ProcessError::IterationBoundExceeded(
+                report
+                    .unstable
+                    .into_iter()
+                    .map(|unstable| unstable.path)
+                    .collect(),
+            ));
+        }
+        if let Some(window) = options.settle {
+            wait_to_settle(&report.unstable, window, options, snapshot)?;
         }
-    } {
-        log::debug!("Iteration {}", iterations);
         iterations += 1;
-        log::debug!("Pushing...");
-        last_time = SystemTime::now();
     }
+}
 
-    let mut compiled_entries = Vec::new();
-    log::debug!("Compiling with {:#?}", last_time);
-    visitor.compile(last_time, &mut compiled_entries);
-    log::debug!("Compiled {} entries", compiled_entries.len());
-    Ok(compiled_entries)
+/// Waits until none of the unstable paths has had its mtime move for a whole window, giving up
+/// after `SETTLE_WINDOWS` windows so a writer which never stops can't hold the snapshot forever.
+fn wait_to_settle(
+    unstable: &[UnstablePath],
+    window: Duration,
+    options: &CaptureOptions,
+    snapshot: &mut SnapshotReport,
+) -> Result<(), ProcessError> {
+    let mtimes = || {
+        unstable
+            .iter()
+            .map(|unstable| {
+                std::fs::symlink_metadata(&unstable.path)
+                    .and_then(|metadata| metadata.modified())
+                    .ok()
+            })
+            .collect::<Vec<_>>()
+    };
+    log::info!(
+        "Waiting for {} paths to hold still for {} ms",
+        unstable.len(),
+        window.as_millis()
+    );
+    notify::status("Waiting for changes to settle");
+    let mut last = mtimes();
+    for _ in 0..SETTLE_WINDOWS {
+        let settled_at = Instant::now() + window;
+        while Instant::now() < settled_at {
+            if options.cancel.is_cancelled() {
+                return Err(ProcessError::Cancelled);
+            }
+            std::thread::sleep(SETTLE_POLL.min(settled_at - Instant::now()));
+        }
+        let current = mtimes();
+        if current == last {
+            return Ok(());
+        }
+        last = current;
+    }
+    snapshot.warnings.record(Warning::KeptChanging);
+    log::warn!(
+        "Paths kept changing for {} settle windows; revisiting them anyway",
+        SETTLE_WINDOWS
+    );
+    Ok(())
 }
 
+/// Counts the unstable paths of a pass by how they changed, such as `3 vanished, 1 changed while
+/// captured`, so a churning target logs a line per pass rather than one per path.
+fn summarize_unstable(unstable: &[UnstablePath]) -> String {
+    let mut counts: Vec<(Instability, usize)> = Vec::new();
+    for path in unstable {
+        match counts.iter_mut().find(|(reason, _)| *reason == path.reason) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((path.reason, 1)),
+        }
+    }
+    counts
+        .iter()
+        .map(|(reason, count)| format!("{} {}", count, reason))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+const UNCAPTURED: &str = "Only captured entries are compiled";
+
 impl From<WeakEntry> for Entry {
     fn from(value: WeakEntry) -> Self {
+        let encoded_data = value.encoded_data.expect(UNCAPTURED);
         Self {
             path: value.path,
-            metadata: value.metadata,
-            entry_type: EntryType::File(value.encoded_data),
+            metadata: value.metadata.expect(UNCAPTURED),
+            entry_type: match value.drained {
+                Some(size) => EntryType::Drained(encoded_data, value.digest, size),
+                None => {
+                    EntryType::File(encoded_data, (!value.metadata_only).then_some(value.digest))
+                }
+            },
+            level: value.level,
         }
     }
 }
@@ -94,8 +743,9 @@ impl From<SymlinkEntry> for Entry {
     fn from(value: SymlinkEntry) -> Self {
         Self {
             path: value.path,
-            metadata: value.metadata,
-            entry_type: EntryType::Symlink,
+            metadata: value.metadata.expect(UNCAPTURED),
+            entry_type: EntryType::Symlink(value.digest),
+            level: None,
         }
     }
 }
@@ -104,286 +754,1883 @@ impl From<&Visitor> for Entry {
     fn from(value: &Visitor) -> Self {
         Self {
             path: value.origin.to_path_buf(),
-            metadata: value.metadata.clone(),
-            entry_type: EntryType::Directory,
+            metadata: value.metadata.clone().expect(UNCAPTURED),
+            entry_type: EntryType::Directory(value.children()),
+            level: None,
         }
     }
 }
 
 struct WeakEntry {
     path: PathBuf,
-    metadata: Metadata,
-    visit_revision: SystemTime,
-    encoded_data: File,
+    /// Metadata observed when the content was last captured.
+    metadata: Option<Metadata>,
+    encoded_data: Option<File>,
+    /// Where the capture is kept when checkpointing.
+    data_path: Option<PathBuf>,
+    digest: Digest,
+    /// Whether the captured content matches the last observation of the file.
+    stable: bool,
+    /// Whether the capture was taken within the mtime slack of its revision and still has to be
+    /// confirmed by hashing the file again on a later pass.
+    suspect: bool,
+    warned_future_mtime: bool,
+    /// Whether only the metadata was captured, leaving the capture empty and the digest unknown.
+    metadata_only: bool,
+    /// Bytes drained from a named pipe, which is only ever drained once.
+    drained: Option<u64>,
+    /// Whether the file was last found modified within the minimum age, and left out.
+    too_young: bool,
+    /// Compression level the content was captured at, unless it wasn't captured.
+    level: Option<i32>,
 }
 
 impl WeakEntry {
-    pub fn new<P: AsRef<Path>>(
-        path: P,
-        visit_revision: SystemTime,
-        compression_level: i32,
-    ) -> Result<Self, bool> {
-        let path_buf = path.as_ref().to_path_buf();
-        let metadata = match path.as_ref().metadata() {
-            Ok(metadata) => metadata,
-            Err(_) => return Err(!path_buf.exists()),
-        };
-        if let Ok(modified) = metadata.modified() {
-            if modified > visit_revision {
-                log::info!(
-                    "File {} was modified after the visit revision; skipping, will revisit.",
-                    path_buf.display()
-                );
-                return Err(true);
-            }
+    pub fn create<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            metadata: None,
+            encoded_data: None,
+            data_path: None,
+            digest: Digest::default(),
+            stable: false,
+            suspect: false,
+            warned_future_mtime: false,
+            metadata_only: false,
+            drained: None,
+            too_young: false,
+            level: None,
         }
-        let mut self_ref = Self {
-            path: path_buf.clone(),
-            metadata,
-            visit_revision,
-            encoded_data: tempfile().map_err(|_| !path_buf.exists())?,
-        };
-        self_ref.fvisit(compression_level)?;
-        Ok(self_ref)
     }
 
     pub fn visit(
         &mut self,
         visit_revision: SystemTime,
-        compression_level: i32,
-    ) -> Result<(), bool> {
+        options: &CaptureOptions,
+        report: &mut PassReport,
+    ) -> Result<(), ProcessError> {
+        let outcome = self.observe(visit_revision, options, report)?;
+        if matches!(outcome, Outcome::Captured) {
+            self.checkpoint(options)?;
+            self.cache_digest(options);
+        }
+        let too_young = matches!(outcome, Outcome::TooYoung);
+        if too_young && !self.too_young {
+            log::info!(
+                "Leaving out {}, modified less than {} ago",
+                self.path.display(),
+                humantime::format_duration(options.min_age.unwrap_or_default())
+            );
+        }
+        self.too_young = too_young;
+        self.stable = outcome.is_stable();
+        report.record(&options.policy, &self.path, outcome);
+        Ok(())
+    }
+
+    fn encoded_size(&self) -> u64 {
+        self.encoded_data
+            .as_ref()
+            .and_then(|encoded_data| encoded_data.metadata().ok())
+            .map_or(0, |metadata| metadata.len())
+    }
+
+    /// Records a confirmed capture in the checkpoint, if there is one.
+    fn checkpoint(&self, options: &CaptureOptions) -> Result<(), ProcessError> {
+        let (checkpoint, data_path, metadata) =
+            match (&options.checkpoint, &self.data_path, &self.metadata) {
+                (Some(checkpoint), Some(data_path), Some(metadata)) => {
+                    (checkpoint, data_path, metadata)
+                }
+                _ => return Ok(()),
+            };
+        let mut checkpoint = checkpoint.borrow_mut();
+        checkpoint
+            .record(&self.path, metadata, data_path, &self.digest)
+            .map_err(|err| {
+                ProcessError::CheckpointFailed(checkpoint.directory().to_path_buf(), err)
+            })
+    }
+
+    /// Records a confirmed capture's digest in the hash cache, if there is one.
+    fn cache_digest(&self, options: &CaptureOptions) {
+        if let (Some(hash_cache), Some(metadata), None) =
+            (&options.hash_cache, &self.metadata, self.drained)
+        {
+            hash_cache
+                .borrow_mut()
+                .record(&self.path, metadata, &self.digest);
+        }
+    }
+
+    fn observe(
+        &mut self,
+        visit_revision: SystemTime,
+        options: &CaptureOptions,
+        report: &mut PassReport,
+    ) -> Result<Outcome, ProcessError> {
         let metadata = match self.path.metadata() {
             Ok(metadata) => metadata,
-            Err(_) => return Err(!self.path.exists()),
+            Err(_) if !self.path.exists() => {
+                log::debug!(
+                    "File {} vanished during the visit; will revisit.",
+                    self.path.display()
+                );
+                return Ok(Outcome::Unstable(Instability::Vanished));
+            }
+            Err(err) => return Ok(Outcome::Failed(err)),
         };
-        if let Ok(modified) = metadata.modified() {
-            if modified > visit_revision {
-                log::info!(
-                    "File {} was modified after the visit revision; skipping, will revisit.",
+        // checked before anything is read, so a file still being written isn't even opened
+        if options
+            .min_age
+            .is_some_and(|min_age| is_too_young(&metadata, min_age, options.future_mtime_slack))
+        {
+            return Ok(Outcome::TooYoung);
+        }
+        if let Some(limit) = options.drain_fifos.filter(|_| is_fifo(&metadata)) {
+            return self.drain(metadata, limit, options);
+        }
+        let future_dated = is_future_dated(&metadata, options.future_mtime_slack);
+        if future_dated && !self.warned_future_mtime {
+            report.warnings.record(Warning::FutureMtime);
+            log::warn!(
+                "File {} has an mtime in the future; judging its stability by its size and identity instead.",
+                self.path.display()
+            );
+            self.warned_future_mtime = true;
+        }
+        let modified = metadata.modified().ok();
+        if !future_dated && modified.is_some_and(|modified| modified > visit_revision) {
+            log::debug!(
+                "File {} was modified after the visit revision; skipping, will revisit.",
+                self.path.display()
+            );
+            return Ok(Outcome::Unstable(Instability::ModifiedAfterRevision));
+        }
+        let matches_capture = self.metadata.as_ref().is_some_and(|captured| {
+            let captured = CaptureRecord {
+                metadata: captured,
+                digest: &self.digest,
+            };
+            options
+                .change_detector
+                .is_stable(&self.path, &metadata, &captured, visit_revision)
+                == Stability::Unchanged
+        });
+        if self.stable && matches_capture {
+            return Ok(Outcome::Unchanged);
+        }
+        // without reading the content there's nothing to tear or to confirm, so the metadata just
+        // has to hold still until the next pass
+        if options.metadata_only {
+            if self.encoded_data.is_none() {
+                let empty = options
+                    .temp_file()
+                    .map_err(|err| options.temp_dir_unavailable(err))?;
+                self.encoded_data = Some(empty);
+            }
+            self.metadata = Some(metadata);
+            self.metadata_only = true;
+            self.level = None;
+            return Ok(Outcome::Captured);
+        }
+        // an mtime in the future can't move forward on modification, but the same file with the
+        // same size across consecutive passes is taken as unchanged
+        if future_dated && self.suspect && matches_capture && file_identity(&metadata).is_some() {
+            self.suspect = false;
+            return Ok(Outcome::Captured);
+        }
+        // a suspect capture which still looks the same is confirmed by its content instead
+        let suspect_digest = (self.suspect && matches_capture).then_some(self.digest);
+
+        let outcome = self.fvisit(options, &metadata, report)?;
+        if !outcome.is_stable() {
+            return Ok(outcome);
+        }
+        // the file must look the same after reading it as before, otherwise the capture may be torn
+        let captured = CaptureRecord {
+            metadata: &metadata,
+            digest: &self.digest,
+        };
+        let unchanged_while_read = self.path.metadata().is_ok_and(|after| {
+            options
+                .change_detector
+                .is_stable(&self.path, &after, &captured, visit_revision)
+                == Stability::Unchanged
+        });
+        if !unchanged_while_read {
+            log::debug!(
+                "File {} changed while it was being captured; will revisit.",
+                self.path.display()
+            );
+            return Ok(Outcome::Unstable(Instability::ChangedWhileCaptured));
+        }
+        let within_slack = within_slack(&metadata, visit_revision, options.mtime_slack);
+        self.metadata = Some(metadata);
+
+        if let Some(suspect_digest) = suspect_digest {
+            if suspect_digest != self.digest {
+                log::debug!(
+                    "File {} changed without its mtime moving; will revisit.",
                     self.path.display()
                 );
-                return Err(true);
+                return Ok(Outcome::Unstable(Instability::ChangedWithoutMtime));
             }
-            if modified < self.visit_revision {
-                self.visit_revision = visit_revision;
-                return Ok(());
+        } else if within_slack {
+            log::debug!(
+                "File {} was modified within the mtime slack of the visit revision; will confirm.",
+                self.path.display()
+            );
+            self.suspect = true;
+            return Ok(Outcome::Unstable(Instability::WithinSlack));
+        }
+        self.suspect = false;
+        Ok(Outcome::Captured)
+    }
+
+    /// Captures what's buffered in a named pipe. Reading drains it, so there's no reading it again
+    /// to confirm the capture: whatever the first pass drained is what's archived, however the
+    /// pipe changes afterwards.
+    fn drain(
+        &mut self,
+        metadata: Metadata,
+        limit: u64,
+        options: &CaptureOptions,
+    ) -> Result<Outcome, ProcessError> {
+        if self.drained.is_some() {
+            return Ok(Outcome::Unchanged);
+        }
+        let drained = drain_fifo(&self.path, limit)
+            .map_err(CaptureFailure::Source)
+            .and_then(|buffered| {
+                let mut encoded_data = options.temp_file().map_err(CaptureFailure::Temp)?;
+                let mut encoder = dictionary::encoder(
+                    &mut encoded_data,
+                    options.compression_level(&self.path),
+                    options.dictionary.as_ref(),
+                )
+                .map_err(CaptureFailure::Encode)?;
+                encoder
+                    .write_all(&buffered)
+                    .map_err(CaptureFailure::Encode)?;
+                encoder.finish().map_err(CaptureFailure::Encode)?;
+                let mut hasher = Hasher::new(options.hash_algorithm);
+                hasher.update(&buffered);
+                Ok((encoded_data, hasher.finalize(), buffered.len() as u64))
+            });
+        match drained {
+            Ok((encoded_data, digest, size)) => {
+                log::debug!("Drained {} bytes from {}", size, self.path.display());
+                self.encoded_data = Some(encoded_data);
+                self.digest = digest;
+                self.metadata = Some(metadata);
+                self.drained = Some(size);
+                self.level = Some(options.compression_level(&self.path));
+                Ok(Outcome::Captured)
+            }
+            Err(CaptureFailure::Temp(err)) => Err(options.temp_dir_unavailable(err)),
+            Err(CaptureFailure::Source(err) | CaptureFailure::Encode(err)) => {
+                Ok(Outcome::Failed(err))
             }
-        } else {
-            return Ok(()); // if we can't get the modified time, we can't do anything
         }
-        self.metadata = metadata;
-        self.fvisit(compression_level)?;
-        self.visit_revision = visit_revision;
-        Ok(())
     }
 
-    fn fvisit(&mut self, compression_level: i32) -> Result<(), bool> {
-        self.encoded_data = tempfile().map_err(|_| !self.path.exists())?;
-        let mut encoder =
-            zstd::Encoder::new(&mut self.encoded_data, compression_level).map_err(|err| {
-                log::error!("Failed to encode data for {}: {}", self.path.display(), err);
-                !self.path.exists()
-            })?;
-        encoder
-            .write_all(&std::fs::read(&self.path).map_err(|_| false)?)
-            .map_err(|err| {
-                log::error!("Failed to encode data for {}: {}", self.path.display(), err);
-                !self.path.exists()
-            })?;
-        encoder.finish().map_err(|_| !self.path.exists())?;
-        Ok(())
+    /// Captures the file's content. Failing to read the file only affects this entry, but failing
+    /// to write the capture out means temp space is unusable for the whole snapshot, unless the
+    /// capture is best effort.
+    fn fvisit(
+        &mut self,
+        options: &CaptureOptions,
+        metadata: &Metadata,
+        report: &mut PassReport,
+    ) -> Result<Outcome, ProcessError> {
+        if self.encoded_data.is_none() {
+            let resumed = options
+                .checkpoint
+                .as_ref()
+                .and_then(|checkpoint| checkpoint.borrow().lookup(&self.path, metadata));
+            if let Some((encoded_data, digest, data_path)) = resumed {
+                log::debug!(
+                    "Resuming capture of {} from checkpoint",
+                    self.path.display()
+                );
+                self.encoded_data = Some(encoded_data);
+                self.data_path = Some(data_path);
+                self.digest = digest;
+                self.level = Some(options.compression_level(&self.path));
+                return Ok(Outcome::Captured);
+            }
+            if let Some(hash_cache) = &options.hash_cache {
+                let cached = hash_cache.borrow().lookup(&self.path, metadata);
+                if let Some(digest) = cached {
+                    log::debug!("Reusing the cached digest of {}", self.path.display());
+                    let empty = options
+                        .temp_file()
+                        .map_err(|err| options.temp_dir_unavailable(err))?;
+                    hash_cache.borrow_mut().reused += 1;
+                    self.encoded_data = Some(empty);
+                    self.digest = digest;
+                    self.level = None;
+                    return Ok(Outcome::Captured);
+                }
+            }
+        }
+        match self.capture(options, report) {
+            Ok((encoded_data, digest, data_path, read)) => {
+                self.encoded_data = Some(encoded_data);
+                self.data_path = data_path;
+                self.digest = digest;
+                self.level = Some(options.compression_level(&self.path));
+                // every format but tar takes the size of a file from its metadata, as does the
+                // manifest, so a capture holding any other number of bytes is never archived
+                if read != metadata.len() {
+                    log::debug!(
+                        "File {} held {} bytes although its size is {}; will revisit.",
+                        self.path.display(),
+                        read,
+                        metadata.len()
+                    );
+                    return Ok(Outcome::Unstable(Instability::Resized));
+                }
+                Ok(Outcome::Captured)
+            }
+            Err(CaptureFailure::Source(_)) if !self.path.exists() => {
+                Ok(Outcome::Unstable(Instability::Vanished))
+            }
+            Err(CaptureFailure::Source(err)) => Ok(Outcome::Failed(err)),
+            // without temp space no other file can be captured either, best effort or not
+            Err(CaptureFailure::Temp(err)) => Err(options.temp_dir_unavailable(err)),
+            Err(CaptureFailure::Encode(err)) if options.best_effort_files => {
+                Ok(Outcome::Failed(err))
+            }
+            Err(CaptureFailure::Encode(err)) => {
+                Err(ProcessError::EncodeFailed(self.path.clone(), err))
+            }
+        }
+    }
+
+    fn capture(
+        &self,
+        options: &CaptureOptions,
+        report: &mut PassReport,
+    ) -> Result<(File, Digest, Option<PathBuf>, u64), CaptureFailure> {
+        let (mut encoded_data, data_path) = match &options.checkpoint {
+            Some(checkpoint) => {
+                let (data, data_path) = checkpoint
+                    .borrow()
+                    .create_data_file()
+                    .map_err(CaptureFailure::Encode)?;
+                (data, Some(data_path))
+            }
+            None => (options.temp_file().map_err(CaptureFailure::Temp)?, None),
+        };
+        // a temp directory filling up fails every capture after this one too
+        let in_temp_dir = data_path.is_none();
+        let encode_failure = |err: std::io::Error| {
+            let out_of_space = matches!(
+                err.kind(),
+                std::io::ErrorKind::StorageFull | std::io::ErrorKind::QuotaExceeded
+            );
+            if in_temp_dir && out_of_space {
+                CaptureFailure::Temp(err)
+            } else {
+                CaptureFailure::Encode(err)
+            }
+        };
+        let mut encoder = dictionary::encoder(
+            &mut encoded_data,
+            options.compression_level(&self.path),
+            options.dictionary.as_ref(),
+        )
+        .map_err(encode_failure)?;
+        let file =
+            open_source(&self.path, options.preserve_atime).map_err(CaptureFailure::Source)?;
+        let mut reader = BufReader::with_capacity(options.read_buffer_size, file);
+        let mut hasher = Hasher::new(options.hash_algorithm);
+        let mut read = 0;
+        loop {
+            let buffer = reader.fill_buf().map_err(CaptureFailure::Source)?;
+            if buffer.is_empty() {
+                break;
+            }
+            hasher.update(buffer);
+            encoder.write_all(buffer).map_err(encode_failure)?;
+            let length = buffer.len();
+            reader.consume(length);
+            report.bytes_read += length as u64;
+            read += length as u64;
+        }
+        encoder.finish().map_err(encode_failure)?;
+        Ok((encoded_data, hasher.finalize(), data_path, read))
     }
 }
 
+/// Opens a file in the target for reading, asking Linux not to update its access time when
+/// `preserve_atime` is set. Only the file's owner may ask that, so for anyone else's files the
+/// access time is updated as usual.
+#[cfg(target_os = "linux")]
+pub fn open_source(path: &Path, preserve_atime: bool) -> std::io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    if preserve_atime {
+        match File::options()
+            .read(true)
+            .custom_flags(libc::O_NOATIME)
+            .open(path)
+        {
+            Err(err) if err.kind() == std::io::ErrorKind::PermissionDenied => {}
+            opened => return opened,
+        }
+    }
+    File::open(path)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn open_source(path: &Path, _preserve_atime: bool) -> std::io::Result<File> {
+    File::open(path)
+}
+
+/// Reads up to `limit` of the bytes buffered in a named pipe, without waiting for a writer to
+/// open it or for more to be written.
+#[cfg(unix)]
+fn drain_fifo(path: &Path, limit: u64) -> std::io::Result<Vec<u8>> {
+    use std::os::unix::fs::OpenOptionsExt;
+    let fifo = File::options()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(path)?;
+    let mut reader = fifo.take(limit);
+    let mut buffered = Vec::new();
+    let mut buffer = [0; 8192];
+    loop {
+        match reader.read(&mut buffer) {
+            // no writer has it open, or the limit is reached
+            Ok(0) => break,
+            Ok(read) => buffered.extend_from_slice(&buffer[..read]),
+            // everything buffered was read, with a writer still holding it open
+            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => break,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(buffered)
+}
+
+#[cfg(not(unix))]
+fn drain_fifo(_path: &Path, _limit: u64) -> std::io::Result<Vec<u8>> {
+    Ok(Vec::new())
+}
+
+#[cfg(unix)]
+fn is_fifo(metadata: &Metadata) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+    metadata.file_type().is_fifo()
+}
+
+#[cfg(not(unix))]
+fn is_fifo(_metadata: &Metadata) -> bool {
+    false
+}
+
+/// Why a file's content could not be captured.
+enum CaptureFailure {
+    /// The file itself could not be read.
+    Source(std::io::Error),
+    /// The capture could not be written out.
+    Encode(std::io::Error),
+    /// The temp directory had no room for the capture, or no file could be created in it.
+    Temp(std::io::Error),
+}
+
+/// Whether an mtime is further ahead of the local clock than the slack allows, making it bogus
+/// rather than the mark of a modification in progress.
+fn is_future_dated(metadata: &Metadata, slack: Duration) -> bool {
+    let limit = SystemTime::now() + slack;
+    metadata.modified().is_ok_and(|modified| modified > limit)
+}
+
+/// Whether a file was modified more recently than the minimum age. Future mtimes are bogus rather
+/// than recent, so they don't count.
+fn is_too_young(metadata: &Metadata, min_age: Duration, future_slack: Duration) -> bool {
+    let Some(threshold) = SystemTime::now().checked_sub(min_age) else {
+        return true;
+    };
+    !is_future_dated(metadata, future_slack)
+        && metadata
+            .modified()
+            .is_ok_and(|modified| modified > threshold)
+}
+
+/// Whether an mtime is close enough to the visit revision that a later modification could carry
+/// the same timestamp.
+fn within_slack(metadata: &Metadata, visit_revision: SystemTime, slack: Duration) -> bool {
+    let threshold = match visit_revision.checked_sub(slack) {
+        Some(threshold) => threshold,
+        None => return true,
+    };
+    metadata
+        .modified()
+        .is_ok_and(|modified| modified > threshold)
+}
+
 struct SymlinkEntry {
     path: PathBuf,
-    metadata: Metadata,
-    visit_revision: SystemTime,
+    metadata: Option<Metadata>,
+    /// Digest of where the symlink pointed when it was captured, if symlinks are hashed.
+    digest: Option<Digest>,
+    stable: bool,
+    warned_future_mtime: bool,
 }
 
 impl SymlinkEntry {
-    pub fn new<P: AsRef<Path>>(path: P, visit_revision: SystemTime) -> Result<Self, bool> {
-        let path_buf = path.as_ref().to_path_buf();
-        let metadata = match path.as_ref().metadata() {
-            Ok(metadata) => metadata,
-            Err(_) => return Err(!path_buf.exists()),
-        };
-        Ok(Self {
-            path: path_buf,
-            metadata,
-            visit_revision,
-        })
+    pub fn create<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            metadata: None,
+            digest: None,
+            stable: false,
+            warned_future_mtime: false,
+        }
     }
 
-    pub fn visit(&mut self, visit_revision: SystemTime) -> Result<(), bool> {
-        let metadata = match self.path.metadata() {
+    pub fn visit(
+        &mut self,
+        visit_revision: SystemTime,
+        options: &CaptureOptions,
+        report: &mut PassReport,
+    ) {
+        let outcome = self.observe(visit_revision, options, report);
+        self.stable = outcome.is_stable();
+        report.record(&options.policy, &self.path, outcome);
+    }
+
+    fn observe(
+        &mut self,
+        visit_revision: SystemTime,
+        options: &CaptureOptions,
+        report: &mut PassReport,
+    ) -> Outcome {
+        let metadata = match self.path.symlink_metadata() {
             Ok(metadata) => metadata,
-            Err(_) => return Err(!self.path.exists()),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                return Outcome::Unstable(Instability::Vanished)
+            }
+            Err(err) => return Outcome::Failed(err),
         };
-        self.metadata = metadata;
-        self.visit_revision = visit_revision;
-        Ok(())
+        let matches_previous = self
+            .metadata
+            .as_ref()
+            .is_some_and(|previous| same_content(previous, &metadata));
+        if is_future_dated(&metadata, options.future_mtime_slack) {
+            if !self.warned_future_mtime {
+                report.warnings.record(Warning::FutureMtime);
+                log::warn!(
+                    "Symlink {} has an mtime in the future; judging its stability by its size and identity instead.",
+                    self.path.display()
+                );
+                self.warned_future_mtime = true;
+            }
+            if !matches_previous {
+                self.metadata = Some(metadata);
+                return Outcome::Unstable(Instability::FutureDated);
+            }
+        } else if metadata
+            .modified()
+            .is_ok_and(|modified| modified > visit_revision)
+        {
+            log::debug!(
+                "Symlink {} was modified after the visit revision; skipping, will revisit.",
+                self.path.display()
+            );
+            return Outcome::Unstable(Instability::ModifiedAfterRevision);
+        }
+        let unchanged = self.stable && matches_previous;
+        if unchanged {
+            self.metadata = Some(metadata);
+            return Outcome::Unchanged;
+        }
+        if options.hash_symlinks {
+            match self.path.read_link() {
+                Ok(link) => {
+                    self.digest = Some(options.hash_algorithm.digest(&path_bytes(&link)));
+                }
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                    return Outcome::Unstable(Instability::Vanished)
+                }
+                Err(err) => return Outcome::Failed(err),
+            }
+        }
+        self.metadata = Some(metadata);
+        Outcome::Captured
     }
 }
 
 struct Visitor {
     origin: PathBuf,
-    metadata: Metadata,
-    revision: SystemTime,
+    metadata: Option<Metadata>,
+    /// Whether the directory was unmodified when it was last observed.
+    stable: bool,
+    /// Whether the directory was modified within the mtime slack of its revision and its listing
+    /// still has to be confirmed on a later pass.
+    suspect: bool,
+    warned_future_mtime: bool,
+    /// Whether a filter left the directory's own entry out, while still capturing its contents.
+    skipped: bool,
     entries: HashMap<PathBuf, WeakEntry>,
     sub_visitors: HashMap<PathBuf, Visitor>,
     links: HashMap<PathBuf, SymlinkEntry>,
+    /// Resolved paths of this directory and every directory above it, guarding against
+    /// dereferenced links which lead back into their own ancestry.
+    ancestry: Vec<PathBuf>,
+    /// Dereferenced links already reported as loops.
+    loops: HashSet<PathBuf>,
 }
 
 impl Visitor {
-    pub fn create<P: AsRef<Path>>(path: P, revision: SystemTime) -> Result<Self, bool> {
-        let path_buf = path.as_ref().to_path_buf();
-        let metadata = match path.as_ref().metadata() {
-            Ok(metadata) => metadata,
-            Err(_) => return Err(!path_buf.exists()),
-        };
-
-        if let Ok(modified) = metadata.modified() {
-            if modified > revision {
-                log::info!(
-                    "Directory {} was modified after the visit revision; skipping, will revisit.",
-                    path_buf.display()
-                );
-                return Err(true);
-            }
-        }
-
-        Ok(Self {
-            origin: path_buf,
-            metadata,
-            revision,
+    pub fn create<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            origin: path.as_ref().to_path_buf(),
+            metadata: None,
+            stable: false,
+            suspect: false,
+            warned_future_mtime: false,
+            skipped: false,
             entries: HashMap::new(),
             sub_visitors: HashMap::new(),
             links: HashMap::new(),
-        })
+            ancestry: vec![path.as_ref().to_path_buf()],
+            loops: HashSet::new(),
+        }
+    }
+
+    /// Creates the visitor of a subdirectory which resolves to the given path.
+    fn nested<P: AsRef<Path>>(&self, path: P, resolved: PathBuf) -> Self {
+        let mut visitor = Self::create(path);
+        visitor.ancestry = self.ancestry.clone();
+        visitor.ancestry.push(resolved);
+        visitor
+    }
+
+    /// Whether this visits the target directory itself rather than one beneath it.
+    fn is_root(&self) -> bool {
+        self.ancestry.len() == 1
     }
 
     pub fn visit(
         &mut self,
         visit_revision: SystemTime,
-        compression_level: i32,
-    ) -> Result<(), bool> {
-        let metadata = match self.origin.metadata() {
-            Ok(metadata) => metadata,
-            Err(_) => return Err(!self.origin.exists()),
-        };
-
-        if let Ok(modified) = metadata.modified() {
-            if modified > visit_revision {
-                log::info!(
-                    "Directory {} was modified after the visit revision; skipping, will revisit.",
-                    self.origin.display()
-                );
-                return Err(true);
+        options: &CaptureOptions,
+        report: &mut PassReport,
+    ) -> Result<(), ProcessError> {
+        let outcome = match self.origin.metadata() {
+            Ok(metadata) => {
+                // a future mtime is confirmed like one within the slack, since it never moves
+                let future_dated = is_future_dated(&metadata, options.future_mtime_slack);
+                if future_dated && !self.warned_future_mtime {
+                    report.warnings.record(Warning::FutureMtime);
+                    log::warn!(
+                        "Directory {} has an mtime in the future; judging its stability by its listing instead.",
+                        self.origin.display()
+                    );
+                    self.warned_future_mtime = true;
+                }
+                // the listing still has to hold still from one pass to the next
+                let ignore_mtime = options.ignore_root_mtime && self.is_root();
+                let modified_after = !ignore_mtime
+                    && !future_dated
+                    && metadata
+                        .modified()
+                        .is_ok_and(|modified| modified > visit_revision);
+                let within_slack =
+                    !ignore_mtime && within_slack(&metadata, visit_revision, options.mtime_slack);
+                let matches_previous = self
+                    .metadata
+                    .as_ref()
+                    .is_some_and(|previous| same_content(previous, &metadata));
+                self.metadata = Some(metadata);
+                // children are visited even when the directory itself is unstable, so whatever is
+                // stable beneath it is already captured by the next pass
+                let (listing, listing_changed) = self.fvisit(visit_revision, options, report)?;
+                let confirmed =
+                    (self.stable || self.suspect) && matches_previous && !listing_changed;
+                if modified_after {
+                    log::debug!(
+                        "Directory {} was modified after the visit revision; will revisit.",
+                        self.origin.display()
+                    );
+                    Outcome::Unstable(Instability::ModifiedAfterRevision)
+                } else if within_slack && !confirmed {
+                    log::debug!(
+                        "Directory {} was modified within the mtime slack of the visit revision; will confirm.",
+                        self.origin.display()
+                    );
+                    self.suspect = true;
+                    Outcome::Unstable(Instability::WithinSlack)
+                } else {
+                    self.suspect = false;
+                    listing
+                }
             }
-        }
-
-        self.metadata = metadata;
-        self.fvisit(visit_revision, compression_level)
+            Err(_) if !self.origin.exists() => Outcome::Unstable(Instability::Vanished),
+            Err(err) => Outcome::Failed(err),
+        };
+        self.stable = outcome.is_stable();
+        report.record(&options.policy, &self.origin, outcome);
+        Ok(())
     }
 
+    /// Visits every child of the directory, returning the outcome of listing the directory itself
+    /// and whether the listing differs from the previous pass.
     pub fn fvisit(
         &mut self,
         visit_revision: SystemTime,
-        compression_level: i32,
-    ) -> Result<(), bool> {
-        for entry in match self.origin.read_dir() {
+        options: &CaptureOptions,
+        report: &mut PassReport,
+    ) -> Result<(Outcome, bool), ProcessError> {
+        let read_dir = match self.origin.read_dir() {
             Ok(read_dir) => read_dir,
-            Err(err) => {
-                log::error!(
-                    "Failed to read directory {}: {}",
-                    self.origin.display(),
-                    err
-                );
-                return Err(!self.origin.exists());
+            Err(_) if !self.origin.exists() => {
+                return Ok((Outcome::Unstable(Instability::Vanished), true))
+            }
+            Err(err) => return Ok((Outcome::Failed(err), true)),
+        };
+        let known_children = self.children();
+        let mut outcome = Outcome::Unchanged;
+        let mut seen_directories = HashSet::new();
+        let mut seen_files = HashSet::new();
+        let mut seen_links = HashSet::new();
+        for entry in read_dir {
+            if let Some(throttle) = &options.load_throttle {
+                throttle.wait(&options.cancel);
+            }
+            if options.cancel.is_cancelled() {
+                return Err(ProcessError::Cancelled);
+            }
+            // stopping at the first compromise refused, rather than at the end of the pass
+            if let Some(refusal) = report.refusal.take() {
+                return Err(ProcessError::Refused(refusal));
             }
-        } {
             let entry = match entry {
                 Ok(entry) => entry,
                 Err(err) => {
                     log::error!("Failed to read directory entry: {}", err);
-                    return Err(true);
+                    outcome = Outcome::Unstable(Instability::ListingFailed);
+                    continue;
                 }
             };
             let path = entry.path();
-            if path.is_dir() {
-                if self.sub_visitors.contains_key(&path) {
-                    self.sub_visitors
-                        .get_mut(&path)
-                        .unwrap()
-                        .visit(visit_revision, compression_level)?;
-                } else {
-                    let visitor = Visitor::create(&path, visit_revision)?;
-                    self.sub_visitors.insert(path.clone(), visitor);
-                    self.sub_visitors // we want to ensure we can cache what's possible
-                        .get_mut(&path)
-                        .unwrap()
-                        .fvisit(visit_revision, compression_level)?;
+            let kind = if path.is_symlink() && !options.dereference {
+                EntryKind::Symlink
+            } else if path.is_dir() {
+                EntryKind::Directory
+            } else if path.is_file()
+                || (options.drain_fifos.is_some()
+                    && path.metadata().is_ok_and(|metadata| is_fifo(&metadata)))
+            {
+                // a named pipe to drain is captured as a file
+                EntryKind::File
+            } else if path.is_symlink() {
+                EntryKind::Symlink
+            } else {
+                log::error!("Failed to process path {}, what is this?", path.display());
+                if let Some(refusal) =
+                    options
+                        .policy
+                        .refuse(&path, Compromise::Unsupported, UNSUPPORTED)
+                {
+                    return Err(ProcessError::Refused(refusal));
                 }
-            } else if path.is_file() {
-                if self.entries.contains_key(&path) {
+                report.unsupported.push(path);
+                continue;
+            };
+            let decision = match filter::evaluate(&options.filters, &path, kind) {
+                Ok(decision) => decision,
+                Err(err) => {
+                    report.record(&options.policy, &path, Outcome::Failed(err));
+                    continue;
+                }
+            };
+            if decision == FilterDecision::PruneSubtree
+                || (decision == FilterDecision::SkipEntry && kind != EntryKind::Directory)
+            {
+                log::debug!("Filtered out {}", path.display());
+                // files left out for predating `--since` are unchanged rather than excluded
+                if decision == FilterDecision::PruneSubtree {
+                    report.excluded.push(path);
+                }
+                continue;
+            }
+
+            match kind {
+                EntryKind::Directory => {
+                    if !self.sub_visitors.contains_key(&path) {
+                        let resolved = if path.is_symlink() {
+                            match path.canonicalize() {
+                                Ok(resolved) => resolved,
+                                Err(err) => {
+                                    report.record(&options.policy, &path, Outcome::Failed(err));
+                                    continue;
+                                }
+                            }
+                        } else {
+                            self.ancestry.last().unwrap().join(entry.file_name())
+                        };
+                        if self.ancestry.contains(&resolved) {
+                            if let Some(refusal) =
+                                options
+                                    .policy
+                                    .refuse(&path, Compromise::SymlinkLoop, SYMLINK_LOOP)
+                            {
+                                return Err(ProcessError::Refused(refusal));
+                            }
+                            report.loops.push(path.clone());
+                            if self.loops.insert(path.clone()) {
+                                log::warn!(
+                                    "Symlink {} leads back to {}; not dereferencing it again.",
+                                    path.display(),
+                                    resolved.display()
+                                );
+                            }
+                            continue;
+                        }
+                        let visitor = self.nested(&path, resolved);
+                        self.sub_visitors.insert(path.clone(), visitor);
+                    }
+                    let visitor = self.sub_visitors.get_mut(&path).unwrap();
+                    visitor.skipped = decision == FilterDecision::SkipEntry;
+                    visitor.visit(visit_revision, options, report)?;
+                    seen_directories.insert(path);
+                }
+                EntryKind::File => {
                     self.entries
-                        .get_mut(&path)
-                        .unwrap()
-                        .visit(visit_revision, compression_level)?;
-                } else {
-                    let entry = WeakEntry::new(&path, visit_revision, compression_level)?;
-                    self.entries.insert(path.clone(), entry);
+                        .entry(path.clone())
+                        .or_insert_with(|| WeakEntry::create(&path))
+                        .visit(visit_revision, options, report)?;
+                    seen_files.insert(path);
                 }
-            } else if path.is_symlink() {
-                if self.links.contains_key(&path) {
-                    self.links.get_mut(&path).unwrap().visit(visit_revision)?;
-                } else {
-                    let entry = SymlinkEntry::new(&path, visit_revision)?;
-                    self.links.insert(path.clone(), entry);
+                EntryKind::Symlink => {
+                    self.links
+                        .entry(path.clone())
+                        .or_insert_with(|| SymlinkEntry::create(&path))
+                        .visit(visit_revision, options, report);
+                    seen_links.insert(path);
                 }
-            } else {
-                log::error!("Failed to process path {}, what is this?", path.display());
             }
         }
-        Ok(())
+
+        let visited_children = self.children();
+        // anything not listed in this pass was removed (or changed type) since it was captured
+        self.sub_visitors
+            .retain(|path, _| seen_directories.contains(path));
+        self.entries.retain(|path, _| seen_files.contains(path));
+        self.links.retain(|path, _| seen_links.contains(path));
+        // children were either added by this pass or dropped because they weren't listed
+        let listing_changed =
+            visited_children != known_children || self.children() != visited_children;
+        Ok((outcome, listing_changed))
     }
 
-    pub fn compile(self, time_to_match: SystemTime, compiled_entries: &mut Vec<Entry>) {
-        if self.revision != time_to_match {
-            log::debug!(
-                "Skipping compilation of {} because it was modified after the visit revision; {:?} != {:?}",
-                self.origin.display(),
-                self.revision,
-                time_to_match
-            );
-            return;
+    fn children(&self) -> usize {
+        self.sub_visitors.len() + self.entries.len() + self.links.len()
+    }
+
+    /// Forgets the directories beneath this one which hold no file or symlink anywhere beneath
+    /// them, returning whether this one holds any. They're forgotten rather than skipped, so this
+    /// directory's listing doesn't count them either.
+    fn prune_empty(&mut self) -> bool {
+        self.sub_visitors.retain(|_, visitor| visitor.prune_empty());
+        !(self.entries.is_empty() && self.links.is_empty() && self.sub_visitors.is_empty())
+    }
+
+    /// Counts the entries compiled from this directory, the files among them and the size of
+    /// their captures.
+    fn compiled_size(&self) -> (usize, usize, u64) {
+        let mut count = usize::from(self.stable && !self.skipped);
+        let mut files = 0;
+        let mut bytes = 0;
+        for entry in self.entries.values().filter(|entry| entry.stable) {
+            files += 1;
+            bytes += entry.encoded_size();
         }
+        count += files + self.links.values().filter(|link| link.stable).count();
+        for visitor in self.sub_visitors.values() {
+            let (sub_count, sub_files, sub_bytes) = visitor.compiled_size();
+            count += sub_count;
+            files += sub_files;
+            bytes += sub_bytes;
+        }
+        (count, files, bytes)
+    }
 
-        compiled_entries.push(Entry::from(&self));
+    /// Collects the paths beneath and including this directory which `expand` leaves out for not
+    /// being stable, other than files too young to capture, which are recorded as such.
+    fn dropped(&self, dropped: &mut Vec<PathBuf>) {
+        if !self.stable && !self.skipped {
+            dropped.push(self.origin.clone());
+        }
+        dropped.extend(
+            self.entries
+                .iter()
+                .filter(|(_, entry)| !entry.stable && !entry.too_young)
+                .map(|(path, _)| path.clone()),
+        );
+        dropped.extend(
+            self.links
+                .iter()
+                .filter(|(_, link)| !link.stable)
+                .map(|(path, _)| path.clone()),
+        );
+        for visitor in self.sub_visitors.values() {
+            visitor.dropped(dropped);
+        }
+    }
 
-        for (_, entry) in self.entries {
-            if entry.visit_revision == time_to_match {
-                compiled_entries.push(Entry::from(entry));
+    /// Queues the stable children of this directory in path order. Inclusion only depends on
+    /// whether an entry's capture matches its last observation, not on the pass in which it was
+    /// first seen.
+    fn expand(self, pending: &mut Vec<Pending>) {
+        let mut children = Vec::new();
+        for (path, entry) in self.entries {
+            if entry.stable {
+                children.push((path, Pending::Entry(Entry::from(entry))));
+            } else if !entry.too_young {
+                log::info!(
+                    "Skipping compilation of {} because it could not be captured.",
+                    path.display()
+                );
+            }
+        }
+        for (path, link) in self.links {
+            if link.stable {
+                children.push((path, Pending::Entry(Entry::from(link))));
             } else {
-                log::debug!(
-                    "Skipping compilation of {} because it was modified after the visit revision.",
-                    entry.path.display()
+                log::info!(
+                    "Skipping compilation of {} because it could not be captured.",
+                    path.display()
                 );
             }
         }
-        for (_, visitor) in self.sub_visitors {
-            visitor.compile(time_to_match, compiled_entries);
+        for (path, visitor) in self.sub_visitors {
+            children.push((path, Pending::Directory(visitor)));
         }
-        for (_, link) in self.links {
-            if link.visit_revision == time_to_match {
-                compiled_entries.push(Entry::from(link));
-            } else {
-                log::debug!(
-                    "Skipping compilation of {} because it was modified after the visit revision.",
-                    link.path.display()
+        // pending entries are popped from the back
+        children.sort_by(|a, b| b.0.cmp(&a.0));
+        pending.extend(children.into_iter().map(|(_, child)| child));
+    }
+}
+
+/// Streams the stable entries beneath a converged visitor in path order, handing over each
+/// directory's captures only once the directory is reached.
+struct Compiled {
+    pending: Vec<Pending>,
+}
+
+enum Pending {
+    Directory(Visitor),
+    Entry(Entry),
+}
+
+impl Iterator for Compiled {
+    type Item = Entry;
+
+    fn next(&mut self) -> Option<Entry> {
+        while let Some(pending) = self.pending.pop() {
+            let visitor = match pending {
+                Pending::Entry(entry) => return Some(entry),
+                Pending::Directory(visitor) => visitor,
+            };
+            let directory = (visitor.stable && !visitor.skipped).then(|| Entry::from(&visitor));
+            if !visitor.stable {
+                log::info!(
+                    "Skipping compilation of directory {} because it could not be captured.",
+                    visitor.origin.display()
                 );
             }
+            visitor.expand(&mut self.pending);
+            if directory.is_some() {
+                return directory;
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::BTreeMap;
+    use std::io::{Seek, SeekFrom};
+
+    use super::*;
+    use crate::detector::MtimeDetector;
+    use crate::policy;
+
+    /// Options capturing everything, without any mtime slack, so only paths modified after a
+    /// pass's revision are revisited.
+    pub(crate) fn options(temp_dir: &Path) -> CaptureOptions {
+        CaptureOptions {
+            compression_level: 3,
+            level_overrides: Vec::new(),
+            read_buffer_size: DEFAULT_READ_BUFFER_SIZE,
+            filters: Vec::new(),
+            mtime_slack: Duration::ZERO,
+            future_mtime_slack: Duration::from_secs(60),
+            settle: None,
+            best_effort_files: false,
+            policy: Policy::default(),
+            cancel: Cancellation::default(),
+            checkpoint: None,
+            change_detector: Box::new(MtimeDetector),
+            dereference: false,
+            hash_algorithm: HashAlgorithm::Sha256,
+            preserve_atime: false,
+            dictionary: None,
+            metadata_only: false,
+            drain_fifos: None,
+            hash_cache: None,
+            ignore_root_mtime: false,
+            hash_symlinks: false,
+            load_throttle: None,
+            temp_dir: temp_dir.to_path_buf(),
+            min_age: None,
+            prune_empty_directories: false,
+        }
+    }
+
+    pub(crate) fn write(path: &Path, contents: &str) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+    }
+
+    /// Marks the path modified just after the revision, as though it was written while the pass
+    /// was underway, then waits out the mark so the next pass starts after it.
+    fn modified_during(path: &Path, revision: SystemTime) {
+        File::open(path)
+            .unwrap()
+            .set_modified(revision + Duration::from_millis(1))
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+    }
+
+    /// The content every compiled file was captured with, keyed by its path relative to the
+    /// target, along with every compiled directory mapped to `None`.
+    pub(crate) fn decode(
+        target: &Path,
+        entries: impl Iterator<Item = Entry>,
+    ) -> BTreeMap<String, Option<String>> {
+        entries
+            .map(|entry| {
+                let path = entry.path.strip_prefix(target).unwrap();
+                let path = path.to_string_lossy().into_owned();
+                let content = match entry.entry_type {
+                    EntryType::File(mut data, _) => {
+                        data.seek(SeekFrom::Start(0)).unwrap();
+                        let mut content = String::new();
+                        zstd::Decoder::new(data)
+                            .unwrap()
+                            .read_to_string(&mut content)
+                            .unwrap();
+                        Some(content)
+                    }
+                    _ => None,
+                };
+                (path, content)
+            })
+            .collect()
+    }
+
+    /// Takes a snapshot of the target, calling `during_pass` with the number and revision of
+    /// every pass before it visits anything. Returns how many passes ran and what was compiled.
+    fn snapshot(
+        target: &Path,
+        max_iterations: i32,
+        during_pass: impl FnMut(usize, SystemTime),
+    ) -> Result<(usize, BTreeMap<String, Option<String>>), ProcessError> {
+        let temp_dir = tempfile::tempdir().unwrap();
+        snapshot_with(
+            target,
+            &options(temp_dir.path()),
+            max_iterations,
+            during_pass,
+        )
+    }
+
+    /// Takes a snapshot of the target like `snapshot`, capturing with the options.
+    fn snapshot_with(
+        target: &Path,
+        options: &CaptureOptions,
+        max_iterations: i32,
+        during_pass: impl FnMut(usize, SystemTime),
+    ) -> Result<(usize, BTreeMap<String, Option<String>>), ProcessError> {
+        let (passes, compiled) = capture_with(target, options, max_iterations, during_pass)?;
+        Ok((passes, decode(target, compiled)))
+    }
+
+    /// Walks the target the same as `snapshot_with`, returning the entries compiled as they are.
+    fn capture_with(
+        target: &Path,
+        options: &CaptureOptions,
+        max_iterations: i32,
+        mut during_pass: impl FnMut(usize, SystemTime),
+    ) -> Result<(usize, Compiled), ProcessError> {
+        let mut visitor = Visitor::create(target);
+        let mut report = SnapshotReport::default();
+        let mut passes = 0;
+        converge(
+            max_iterations,
+            options,
+            &mut report,
+            |revision, pass_report| {
+                during_pass(passes, revision);
+                passes += 1;
+                visitor.visit(revision, options, pass_report)
+            },
+        )?;
+        let compiled = Compiled {
+            pending: vec![Pending::Directory(visitor)],
+        };
+        Ok((passes, compiled))
+    }
+
+    /// A target of a file at its root and two in a subdirectory.
+    fn fixture() -> tempfile::TempDir {
+        let target = tempfile::tempdir().unwrap();
+        write(&target.path().join("a"), "a");
+        write(&target.path().join("sub/b"), "b");
+        write(&target.path().join("sub/c"), "c");
+        target
+    }
+
+    fn expected(files: &[(&str, &str)], directories: &[&str]) -> BTreeMap<String, Option<String>> {
+        let files = files
+            .iter()
+            .map(|(path, content)| (path.to_string(), Some(content.to_string())));
+        let directories = directories.iter().map(|path| (path.to_string(), None));
+        files.chain(directories).collect()
+    }
+
+    #[test]
+    fn a_still_target_converges_in_one_pass() {
+        let target = fixture();
+        let (passes, compiled) = snapshot(target.path(), 5, |_, _| {}).unwrap();
+        assert_eq!(passes, 1);
+        let files = [("a", "a"), ("sub/b", "b"), ("sub/c", "c")];
+        assert_eq!(compiled, expected(&files, &["", "sub"]));
+    }
+
+    #[test]
+    fn entries_are_compiled_depth_first_with_siblings_in_path_order() {
+        let target = tempfile::tempdir().unwrap();
+        // created out of order, so the order they're compiled in can't line up by chance
+        for path in ["b", "a/z", "a/y/deep", "a-file", "A", "a/y/deeper"] {
+            write(&target.path().join(path), path);
+        }
+        std::os::unix::fs::symlink("b", target.path().join("c")).unwrap();
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut report = SnapshotReport::default();
+        let processed =
+            process_directory(target.path(), 5, &options(temp_dir.path()), &mut report).unwrap();
+        let compiled: Vec<PathBuf> = processed
+            .entries
+            .map(|entry| {
+                entry
+                    .path
+                    .strip_prefix(target.path())
+                    .unwrap()
+                    .to_path_buf()
+            })
+            .collect();
+        let expected = [
+            "",
+            "A",
+            "a",
+            "a/y",
+            "a/y/deep",
+            "a/y/deeper",
+            "a/z",
+            "a-file",
+            "b",
+            "c",
+        ];
+        assert_eq!(compiled, expected.map(PathBuf::from));
+    }
+
+    #[test]
+    fn a_directory_deleted_before_archiving_is_archived_as_captured() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let target = fixture();
+        let sub = target.path().join("sub");
+        std::fs::set_permissions(&sub, std::fs::Permissions::from_mode(0o750)).unwrap();
+        let captured_mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        File::open(&sub)
+            .unwrap()
+            .set_modified(captured_mtime)
+            .unwrap();
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut report = SnapshotReport::default();
+        let processed =
+            process_directory(target.path(), 5, &options(temp_dir.path()), &mut report).unwrap();
+
+        // the walk is done, but nothing has been compiled or archived yet
+        std::fs::remove_dir_all(&sub).unwrap();
+        let tarball_path = temp_dir.path().join("snapshot.tar.zst");
+        crate::archiver::create_tarball(
+            target.path(),
+            processed.entries,
+            &tarball_path,
+            &crate::archiver::tests::tar_options(),
+            None,
+            &mut report,
+        )
+        .unwrap();
+
+        let name = target.path().file_name().unwrap().to_string_lossy();
+        let mut archived = BTreeMap::new();
+        let mut tarball = tar::Archive::new(File::open(&tarball_path).unwrap());
+        for member in tarball.entries().unwrap() {
+            let member = member.unwrap();
+            let header = member.header();
+            archived.insert(
+                member.path().unwrap().to_string_lossy().into_owned(),
+                (header.mode().unwrap() & 0o7777, header.mtime().unwrap()),
+            );
+        }
+        assert_eq!(
+            archived.get(&format!("{}/sub", name)),
+            Some(&(0o750, 1_600_000_000))
+        );
+        for file in ["sub/b", "sub/c"] {
+            assert!(
+                archived.contains_key(&format!("{}/{}", name, file)),
+                "{:?}",
+                archived
+            );
+        }
+        assert!(report.skipped.is_empty(), "{:?}", report.skipped);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn captures_are_written_to_the_temp_dir_and_leave_nothing_behind() {
+        use std::os::fd::AsRawFd;
+
+        let target = fixture();
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut report = SnapshotReport::default();
+        let processed =
+            process_directory(target.path(), 5, &options(temp_dir.path()), &mut report).unwrap();
+        let captures: Vec<File> = processed
+            .entries
+            .filter_map(|entry| match entry.entry_type {
+                EntryType::File(data, _) => Some(data),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(captures.len(), 3);
+        for capture in &captures {
+            let held = std::fs::read_link(format!("/proc/self/fd/{}", capture.as_raw_fd()));
+            let held = held.unwrap();
+            assert!(held.starts_with(temp_dir.path()), "{}", held.display());
+        }
+        // captures are anonymous, so there's nothing to clean up even while they're held
+        assert_eq!(std::fs::read_dir(temp_dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn the_read_buffer_size_changes_nothing_captured() {
+        let target = tempfile::tempdir().unwrap();
+        // spans several default buffers without filling the last
+        let large: String = (0..3 * DEFAULT_READ_BUFFER_SIZE + 13)
+            .map(|index| char::from(b'a' + (index % 26) as u8))
+            .collect();
+        write(&target.path().join("large"), &large);
+        write(&target.path().join("small"), "small");
+        write(&target.path().join("empty"), "");
+        let capture = |read_buffer_size| {
+            let temp_dir = tempfile::tempdir().unwrap();
+            let options = CaptureOptions {
+                read_buffer_size,
+                ..options(temp_dir.path())
+            };
+            let mut report = SnapshotReport::default();
+            let processed = process_directory(target.path(), 5, &options, &mut report).unwrap();
+            let mut digests = BTreeMap::new();
+            let entries: Vec<Entry> = processed.entries.collect();
+            for entry in &entries {
+                if let EntryType::File(_, digest) = &entry.entry_type {
+                    digests.insert(entry.path.clone(), *digest);
+                }
+            }
+            (
+                decode(target.path(), entries.into_iter()),
+                digests,
+                report.bytes_read,
+            )
+        };
+
+        let default = capture(DEFAULT_READ_BUFFER_SIZE);
+        assert_eq!(default.0["large"].as_deref(), Some(large.as_str()));
+        for read_buffer_size in [1, 7, 4096, 4 * DEFAULT_READ_BUFFER_SIZE] {
+            assert!(
+                capture(read_buffer_size) == default,
+                "reading through {} bytes at a time captured something else",
+                read_buffer_size
+            );
+        }
+    }
+
+    #[test]
+    fn files_modified_within_the_mtime_slack_are_confirmed_by_another_pass() {
+        let target = fixture();
+        let rewritten = target.path().join("sub/b");
+        let modified = SystemTime::now() - Duration::from_secs(600);
+        for path in ["a", "sub/b", "sub/c", "sub", ""] {
+            File::open(target.path().join(path))
+                .unwrap()
+                .set_modified(modified)
+                .unwrap();
+        }
+        let temp_dir = tempfile::tempdir().unwrap();
+
+        // outside the slack, nothing needs confirming
+        let outside = CaptureOptions {
+            mtime_slack: Duration::from_secs(60),
+            ..options(temp_dir.path())
+        };
+        let (passes, _) = snapshot_with(target.path(), &outside, 5, |_, _| {}).unwrap();
+        assert_eq!(passes, 1);
+
+        // within it, a rewrite a coarse timestamp can't tell apart is caught by its content
+        let within = CaptureOptions {
+            mtime_slack: Duration::from_secs(3600),
+            ..options(temp_dir.path())
+        };
+        let (passes, compiled) = snapshot_with(target.path(), &within, 5, |pass, _| {
+            if pass == 1 {
+                write(&rewritten, "B");
+                File::open(&rewritten)
+                    .unwrap()
+                    .set_modified(modified)
+                    .unwrap();
+            }
+        })
+        .unwrap();
+        assert_eq!(passes, 3);
+        let files = [("a", "a"), ("sub/b", "B"), ("sub/c", "c")];
+        assert_eq!(compiled, expected(&files, &["", "sub"]));
+    }
+
+    /// Trusts every file to be unchanged, except that the second time it's asked about `grown`,
+    /// which is right before the file is read again, it appends to the file and calls it changed.
+    struct GrowsBeforeRead {
+        grown: PathBuf,
+        asked: std::cell::Cell<usize>,
+    }
+
+    impl ChangeDetector for GrowsBeforeRead {
+        fn is_stable(
+            &self,
+            path: &Path,
+            _metadata: &Metadata,
+            _captured: &CaptureRecord,
+            _revision: SystemTime,
+        ) -> Stability {
+            if path != self.grown {
+                return Stability::Unchanged;
+            }
+            self.asked.set(self.asked.get() + 1);
+            if self.asked.get() != 2 {
+                return Stability::Unchanged;
+            }
+            let mut file = File::options().append(true).open(path).unwrap();
+            file.write_all(b" and more").unwrap();
+            Stability::Changed
+        }
+    }
+
+    /// Calls `scripted` changed the first `changes` times it's asked about it, and unchanged after.
+    struct Scripted {
+        scripted: PathBuf,
+        changes: usize,
+        asked: std::cell::Cell<usize>,
+    }
+
+    impl ChangeDetector for Scripted {
+        fn is_stable(
+            &self,
+            path: &Path,
+            _: &Metadata,
+            _: &CaptureRecord,
+            _: SystemTime,
+        ) -> Stability {
+            if path != self.scripted {
+                return Stability::Unchanged;
+            }
+            self.asked.set(self.asked.get() + 1);
+            if self.asked.get() <= self.changes {
+                Stability::Changed
+            } else {
+                Stability::Unchanged
+            }
+        }
+    }
+
+    #[test]
+    fn passes_go_on_for_as_long_as_the_detector_sees_changes() {
+        let target = fixture();
+        let scripted = target.path().join("sub/b");
+        let temp_dir = tempfile::tempdir().unwrap();
+        let options = |changes| CaptureOptions {
+            change_detector: Box::new(Scripted {
+                scripted: scripted.clone(),
+                changes,
+                asked: Default::default(),
+            }),
+            ..options(temp_dir.path())
+        };
+        for changes in 0..3 {
+            let (passes, compiled) =
+                snapshot_with(target.path(), &options(changes), 5, |_, _| {}).unwrap();
+            assert_eq!(passes, changes + 1, "{} changes", changes);
+            let files = [("a", "a"), ("sub/b", "b"), ("sub/c", "c")];
+            assert_eq!(compiled, expected(&files, &["", "sub"]));
+        }
+
+        match snapshot_with(target.path(), &options(usize::MAX), 3, |_, _| {}) {
+            Err(ProcessError::IterationBoundExceeded(unstable)) => {
+                assert_eq!(unstable, vec![scripted]);
+            }
+            Err(err) => panic!("expected the iteration bound to be exceeded, not {}", err),
+            Ok(_) => panic!("expected the iteration bound to be exceeded"),
+        }
+    }
+
+    #[test]
+    fn files_read_at_another_size_than_they_were_found_at_are_revisited() {
+        let target = fixture();
+        let grown = target.path().join("sub/b");
+        let temp_dir = tempfile::tempdir().unwrap();
+        let options = CaptureOptions {
+            change_detector: Box::new(GrowsBeforeRead {
+                grown: grown.clone(),
+                asked: Default::default(),
+            }),
+            ..options(temp_dir.path())
+        };
+        let (passes, compiled) = capture_with(target.path(), &options, 5, |pass, revision| {
+            // another file changing makes for a second pass, in which the file grows
+            if pass == 0 {
+                write(&target.path().join("a"), "A");
+                modified_during(&target.path().join("a"), revision);
+            }
+        })
+        .unwrap();
+
+        // grown between being looked at and being read, the file is read once more
+        assert_eq!(passes, 3);
+        let entry = compiled
+            .into_iter()
+            .find(|entry| entry.path == grown)
+            .unwrap();
+        let EntryType::File(mut data, _) = entry.entry_type else {
+            panic!("{} wasn't captured as a file", grown.display());
+        };
+        data.seek(SeekFrom::Start(0)).unwrap();
+        let content = zstd::decode_all(data).unwrap();
+        assert_eq!(content, b"b and more");
+        assert_eq!(entry.metadata.len(), content.len() as u64);
+    }
+
+    #[test]
+    fn the_root_can_be_judged_by_its_listing_alone() {
+        let target = fixture();
+        let temp_dir = tempfile::tempdir().unwrap();
+        // a file briefly created and removed during every pass leaves the listing as it was
+        let churn = |directory: &Path, ignore_root_mtime| {
+            let options = CaptureOptions {
+                ignore_root_mtime,
+                ..options(temp_dir.path())
+            };
+            snapshot_with(target.path(), &options, 3, |_, revision| {
+                write(&directory.join("lock"), "locked");
+                std::fs::remove_file(directory.join("lock")).unwrap();
+                modified_during(directory, revision);
+            })
+        };
+
+        let err = churn(target.path(), false).err().unwrap();
+        assert!(
+            matches!(&err, ProcessError::IterationBoundExceeded(paths) if paths == &[target.path()]),
+            "{}",
+            err
+        );
+        let (passes, compiled) = churn(target.path(), true).unwrap();
+        assert_eq!(passes, 1);
+        let files = [("a", "a"), ("sub/b", "b"), ("sub/c", "c")];
+        assert_eq!(compiled, expected(&files, &["", "sub"]));
+
+        // everything beneath the root is still judged by its mtime
+        let sub = target.path().join("sub");
+        let err = churn(&sub, true).err().unwrap();
+        assert!(
+            matches!(&err, ProcessError::IterationBoundExceeded(paths) if paths == std::slice::from_ref(&sub)),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn passes_wait_for_a_burst_of_writes_to_settle() {
+        // a writer changes the file during the first pass and finishes a moment later
+        let burst = |settle| {
+            let target = fixture();
+            let written = target.path().join("sub/b");
+            let temp_dir = tempfile::tempdir().unwrap();
+            let options = CaptureOptions {
+                settle,
+                ..options(temp_dir.path())
+            };
+            let mut writer = None;
+            let (passes, compiled) = snapshot_with(target.path(), &options, 5, |pass, revision| {
+                if pass == 0 {
+                    write(&written, "partial");
+                    modified_during(&written, revision);
+                    let written = written.clone();
+                    writer = Some(std::thread::spawn(move || {
+                        std::thread::sleep(Duration::from_millis(100));
+                        write(&written, "done");
+                        File::open(&written)
+                            .unwrap()
+                            .set_modified(SystemTime::now() - Duration::from_secs(600))
+                            .unwrap();
+                    }));
+                }
+            })
+            .unwrap();
+            writer.unwrap().join().unwrap();
+            (passes, compiled["sub/b"].clone().unwrap())
+        };
+
+        assert_eq!(burst(None), (2, "partial".to_string()));
+        assert_eq!(
+            burst(Some(Duration::from_millis(300))),
+            (2, "done".to_string())
+        );
+    }
+
+    #[test]
+    fn a_file_changed_during_the_first_pass_is_captured_by_the_second() {
+        let target = fixture();
+        let changed = target.path().join("sub/b");
+        let (passes, compiled) = snapshot(target.path(), 5, |pass, revision| {
+            if pass == 0 {
+                write(&changed, "b, rewritten");
+                modified_during(&changed, revision);
+            }
+        })
+        .unwrap();
+        assert_eq!(passes, 2);
+        // the files captured stable by the first pass are kept alongside the one recaptured
+        let files = [("a", "a"), ("sub/b", "b, rewritten"), ("sub/c", "c")];
+        assert_eq!(compiled, expected(&files, &["", "sub"]));
+    }
+
+    #[test]
+    fn a_file_changing_until_the_last_pass_allowed_is_kept() {
+        let target = fixture();
+        let changed = target.path().join("a");
+        let (passes, compiled) = snapshot(target.path(), 3, |pass, revision| {
+            if pass < 3 {
+                write(&changed, &format!("a, rewritten {} times", pass + 1));
+                modified_during(&changed, revision);
+            }
+        })
+        .unwrap();
+        assert_eq!(passes, 4);
+        let files = [
+            ("a", "a, rewritten 3 times"),
+            ("sub/b", "b"),
+            ("sub/c", "c"),
+        ];
+        assert_eq!(compiled, expected(&files, &["", "sub"]));
+    }
+
+    #[test]
+    fn a_file_changing_in_every_pass_exceeds_the_bound() {
+        let target = fixture();
+        let changed = target.path().join("sub/c");
+        let snapshot = snapshot(target.path(), 2, |pass, revision| {
+            write(&changed, &format!("c, rewritten {} times", pass + 1));
+            modified_during(&changed, revision);
+        });
+        match snapshot {
+            Err(ProcessError::IterationBoundExceeded(unstable)) => {
+                assert_eq!(unstable, vec![changed]);
+            }
+            Err(err) => panic!("expected the iteration bound to be exceeded, not {}", err),
+            Ok(_) => panic!("expected the iteration bound to be exceeded"),
         }
     }
+
+    #[test]
+    fn errors_name_the_paths_they_came_from() {
+        let target = fixture();
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut report = SnapshotReport::default();
+
+        let file = target.path().join("a");
+        let err = process_directory(&file, 5, &options(temp_dir.path()), &mut report)
+            .err()
+            .unwrap();
+        assert!(matches!(&err, ProcessError::PathNotDir(path) if *path == file));
+        assert!(
+            err.to_string().contains(&file.display().to_string()),
+            "{}",
+            err
+        );
+
+        let missing = temp_dir.path().join("missing");
+        let err = process_directory(target.path(), 5, &options(&missing), &mut report)
+            .err()
+            .unwrap();
+        assert!(matches!(&err, ProcessError::TempDirUnavailable(path, _) if *path == missing));
+        assert!(
+            err.to_string().contains(&missing.display().to_string()),
+            "{}",
+            err
+        );
+        assert!(err.to_string().contains("--temp-dir"), "{}", err);
+
+        // only the first few of many unstable paths are named
+        let changing: Vec<PathBuf> = (0..7)
+            .map(|index| target.path().join(format!("changing{}", index)))
+            .collect();
+        let err = snapshot(target.path(), 2, |pass, revision| {
+            for path in &changing {
+                write(path, &format!("rewritten {} times", pass + 1));
+                modified_during(path, revision);
+            }
+        })
+        .err()
+        .unwrap();
+        let message = err.to_string();
+        let named = changing
+            .iter()
+            .filter(|path| message.contains(&path.display().to_string()))
+            .count();
+        assert_eq!(named, REPORTED_UNSTABLE_PATHS, "{}", message);
+        assert!(message.ends_with(" (and 2 more)"), "{}", message);
+    }
+
+    #[test]
+    fn unstable_paths_are_summarized_by_how_they_changed() {
+        let unstable: Vec<UnstablePath> = [
+            ("a", Instability::Vanished),
+            ("b", Instability::ChangedWhileCaptured),
+            ("c", Instability::Vanished),
+            ("d", Instability::ModifiedAfterRevision),
+            ("e", Instability::Vanished),
+        ]
+        .into_iter()
+        .map(|(path, reason)| UnstablePath {
+            path: PathBuf::from(path),
+            reason,
+        })
+        .collect();
+        // counted in the order each way of changing was first seen, with no path named
+        assert_eq!(
+            summarize_unstable(&unstable),
+            "3 vanished, 1 changed while captured, 1 modified after the pass started"
+        );
+        assert_eq!(summarize_unstable(&[]), "");
+    }
+
+    #[test]
+    fn custom_filters_decide_what_is_captured() {
+        let target = fixture();
+        write(&target.path().join("pruned/deep/file"), "pruned");
+        write(&target.path().join("flattened/file"), "kept");
+        write(&target.path().join("skipped"), "skipped");
+        write(&target.path().join("panics"), "panics");
+        let asked = std::rc::Rc::new(RefCell::new(Vec::new()));
+        let recorded = std::rc::Rc::clone(&asked);
+        let temp_dir = tempfile::tempdir().unwrap();
+        let options = CaptureOptions {
+            filters: vec![
+                Box::new(move |path: &Path, _: &Metadata, kind| {
+                    recorded.borrow_mut().push(path.to_path_buf());
+                    match (path.file_name().unwrap().to_str().unwrap(), kind) {
+                        ("pruned", _) => FilterDecision::PruneSubtree,
+                        ("flattened", EntryKind::Directory) => FilterDecision::SkipEntry,
+                        ("panics", _) => panic!("a filter gave up"),
+                        _ => FilterDecision::Include,
+                    }
+                }),
+                // the most restrictive of the decisions wins
+                Box::new(|path: &Path, _: &Metadata, _| {
+                    if path.ends_with("skipped") || path.ends_with("pruned") {
+                        FilterDecision::SkipEntry
+                    } else {
+                        FilterDecision::Include
+                    }
+                }),
+            ],
+            ..options(temp_dir.path())
+        };
+        let mut report = SnapshotReport::default();
+        let processed = process_directory(target.path(), 5, &options, &mut report).unwrap();
+        let compiled = decode(target.path(), processed.entries);
+
+        let files = [
+            ("a", "a"),
+            ("flattened/file", "kept"),
+            ("sub/b", "b"),
+            ("sub/c", "c"),
+        ];
+        assert_eq!(compiled, expected(&files, &["", "sub"]));
+        // nothing beneath a pruned directory is even asked about
+        assert!(!asked
+            .borrow()
+            .iter()
+            .any(|path| path.starts_with(target.path().join("pruned/deep"))));
+        let (panicked, pruned) = (target.path().join("panics"), target.path().join("pruned"));
+        assert_eq!(
+            report
+                .skipped
+                .iter()
+                .map(|skipped| (&skipped.path, skipped.code))
+                .collect::<Vec<_>>(),
+            [
+                (&panicked, SkipCode::Unreadable),
+                (&pruned, SkipCode::Excluded)
+            ]
+        );
+    }
+
+    #[test]
+    fn a_subdirectory_which_fails_is_left_out_while_the_rest_is_captured() {
+        let target = fixture();
+        write(&target.path().join("other/d"), "d");
+        let failing = target.path().join("sub");
+        let temp_dir = tempfile::tempdir().unwrap();
+        // giving up on the directory is the nearest thing to it failing to list, even as root
+        let fails_on_sub = || -> Box<dyn EntryFilter> {
+            Box::new(|path: &Path, _: &Metadata, kind| match kind {
+                EntryKind::Directory if path.ends_with("sub") => panic!("sub can't be read"),
+                _ => FilterDecision::Include,
+            })
+        };
+        let lenient = CaptureOptions {
+            filters: vec![fails_on_sub()],
+            ..options(temp_dir.path())
+        };
+        let mut report = SnapshotReport::default();
+        let processed = process_directory(target.path(), 5, &lenient, &mut report).unwrap();
+        let failed: Vec<&PathBuf> = processed.failed.iter().map(|failed| &failed.path).collect();
+        assert_eq!(failed, [&failing]);
+        let compiled = decode(target.path(), processed.entries);
+        assert_eq!(
+            compiled,
+            expected(&[("a", "a"), ("other/d", "d")], &["", "other"])
+        );
+        assert_eq!(
+            report
+                .skipped
+                .iter()
+                .map(|skipped| (&skipped.path, skipped.code))
+                .collect::<Vec<_>>(),
+            [(&failing, SkipCode::Unreadable)]
+        );
+
+        let strict = CaptureOptions {
+            filters: vec![fails_on_sub()],
+            policy: Policy::new(Some(policy::Mode::Strict), &policy::Overrides::default()),
+            ..options(temp_dir.path())
+        };
+        let mut report = SnapshotReport::default();
+        match process_directory(target.path(), 5, &strict, &mut report) {
+            Err(ProcessError::Refused(refusal)) => {
+                assert_eq!(refusal.path, failing);
+                assert_eq!(refusal.compromise, Compromise::Unreadable);
+            }
+            Err(err) => panic!("expected sub to be refused, not {}", err),
+            Ok(_) => panic!("expected sub to be refused"),
+        }
+    }
+
+    #[test]
+    fn a_subtree_created_by_a_later_pass_is_compiled() {
+        let target = fixture();
+        let root = target.path().to_path_buf();
+        let (passes, compiled) = snapshot(&root, 5, |pass, revision| match pass {
+            0 => {
+                write(&root.join("a"), "a, rewritten");
+                modified_during(&root.join("a"), revision);
+            }
+            // only found by the second pass, which the first pass's revision doesn't match
+            1 => {
+                write(&root.join("new/deep/d"), "d");
+                modified_during(&root, revision);
+            }
+            _ => {}
+        })
+        .unwrap();
+        assert_eq!(passes, 3);
+        let files = [
+            ("a", "a, rewritten"),
+            ("new/deep/d", "d"),
+            ("sub/b", "b"),
+            ("sub/c", "c"),
+        ];
+        assert_eq!(compiled, expected(&files, &["", "new", "new/deep", "sub"]));
+    }
+
+    #[test]
+    fn cancelling_one_capture_leaves_another_running() {
+        let target = fixture();
+        let temp_dir = tempfile::tempdir().unwrap();
+        let cancelled = options(temp_dir.path());
+        cancelled.cancel.set_deadline(Instant::now(), false);
+        let mut report = SnapshotReport::default();
+        assert!(matches!(
+            process_directory(target.path(), 5, &cancelled, &mut report),
+            Err(ProcessError::Cancelled)
+        ));
+
+        let mut report = SnapshotReport::default();
+        let processed =
+            process_directory(target.path(), 5, &options(temp_dir.path()), &mut report).unwrap();
+        assert_eq!(processed.file_count, 3);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn refusals_and_warnings_stay_with_the_capture_they_came_from() {
+        let target = fixture();
+        std::os::unix::fs::symlink(".", target.path().join("sub/loop")).unwrap();
+        let temp_dir = tempfile::tempdir().unwrap();
+        let strict = CaptureOptions {
+            dereference: true,
+            policy: Policy::new(Some(policy::Mode::Strict), &policy::Overrides::default()),
+            ..options(temp_dir.path())
+        };
+        let mut report = SnapshotReport::default();
+        match process_directory(target.path(), 5, &strict, &mut report) {
+            Err(ProcessError::Refused(refusal)) => {
+                assert_eq!(refusal.path, target.path().join("sub/loop"));
+                assert_eq!(refusal.compromise, Compromise::SymlinkLoop);
+            }
+            Err(err) => panic!("expected the loop to be refused, not {}", err),
+            Ok(_) => panic!("expected the loop to be refused"),
+        }
+
+        // the next capture starts from its own policy and report, not what the last one left
+        let lenient = CaptureOptions {
+            dereference: true,
+            ..options(temp_dir.path())
+        };
+        let mut report = SnapshotReport::default();
+        process_directory(target.path(), 5, &lenient, &mut report).unwrap();
+        assert!(report.refusal.is_none());
+        assert_eq!(report.warnings.counts()[&Warning::SymlinkLoop], 1);
+    }
 }
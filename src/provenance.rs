@@ -0,0 +1,204 @@
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tar::Archive;
+
+use crate::hashing::HashAlgorithm;
+
+/// Name of the entry at the archive root describing the snapshot the archive holds.
+pub const PROVENANCE_FILE_NAME: &str = ".sit-snapshot.json";
+
+/// Format of the time snapshot ids lead with.
+pub const ID_TIME_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+
+/// Describes how and when a snapshot was taken, so an archive can be told apart without the logs
+/// of the run which wrote it. Stored as plain JSON, so `tar -xOf` can read it.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Provenance {
+    /// Version of saved_in_time which took the snapshot.
+    pub version: String,
+    /// Id the snapshot was given, to pick it out of an archive several snapshots were appended
+    /// to. Missing for snapshots taken before ids were recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// The revision the captured entries were confirmed stable at, in RFC 3339. Missing for a
+    /// partial snapshot, whose entries never settled.
+    pub revision: Option<String>,
+    /// The directory the snapshot was taken of.
+    pub target: PathBuf,
+    /// The path the target was given as, when it's a symlink leading to `target`. Missing for
+    /// targets given as the directory itself.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub invoked_as: Option<PathBuf>,
+    /// The command line options the snapshot was taken with.
+    pub arguments: Vec<String>,
+    pub hash_algorithm: HashAlgorithm,
+    /// Id of the dictionary the captured files were compressed with, which reading them requires.
+    #[serde(default)]
+    pub dictionary_id: Option<u32>,
+    /// Whether only the metadata of files was captured, leaving every file in the archive empty.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub metadata_only: bool,
+    /// Whether the snapshot ran out of time and only holds what was done by then.
+    pub partial: bool,
+    /// Number of entries the snapshot added to the archive, not counting this one.
+    pub entry_count: usize,
+}
+
+impl Provenance {
+    pub fn read<R: Read>(reader: R) -> std::io::Result<Self> {
+        Ok(serde_json::from_reader(reader)?)
+    }
+
+    /// When the snapshot was taken: its revision, or for a partial snapshot the time its id
+    /// leads with.
+    pub fn taken(&self) -> Option<DateTime<Utc>> {
+        match (&self.revision, &self.id) {
+            (Some(revision), _) => DateTime::parse_from_rfc3339(revision)
+                .ok()
+                .map(|taken| taken.with_timezone(&Utc)),
+            (None, Some(id)) => {
+                let (taken, _) = id.split_once('-')?;
+                chrono::NaiveDateTime::parse_from_str(taken, ID_TIME_FORMAT)
+                    .ok()
+                    .map(|taken| taken.and_utc())
+            }
+            (None, None) => None,
+        }
+    }
+
+    pub fn log(&self) {
+        let taken = match &self.revision {
+            Some(revision) => format!("at {}", revision),
+            None => "partially".to_string(),
+        };
+        log::info!(
+            "Snapshot {}of {} with {} entries, taken {} by saved_in_time {} with options: {}",
+            self.id
+                .as_ref()
+                .map(|id| format!("{} ", id))
+                .unwrap_or_default(),
+            self.target.display(),
+            self.entry_count,
+            taken,
+            self.version,
+            self.arguments.join(" ")
+        );
+        if let Some(invoked_as) = &self.invoked_as {
+            log::info!("Snapshot target was given as {}", invoked_as.display());
+        }
+        if let Some(dictionary_id) = self.dictionary_id {
+            log::info!("Snapshot was compressed with dictionary {}", dictionary_id);
+        }
+        if self.metadata_only {
+            log::warn!("Snapshot only captured metadata; its files hold no content");
+        }
+    }
+}
+
+/// Options whose values are left out of the recorded command line, as the URLs they take can
+/// carry credentials.
+const REDACTED_OPTIONS: [&str; 2] = ["--webhook", "--ping-url"];
+
+/// The command line options to record for a snapshot, with the values of options which can hold
+/// credentials replaced, since anyone able to read the archive can read its record.
+pub fn recorded_arguments<I: IntoIterator<Item = String>>(arguments: I) -> Vec<String> {
+    let mut redact_next = false;
+    arguments
+        .into_iter()
+        .map(|argument| {
+            if std::mem::take(&mut redact_next) {
+                return "<redacted>".to_string();
+            }
+            for option in REDACTED_OPTIONS {
+                if argument == option {
+                    redact_next = true;
+                } else if argument
+                    .strip_prefix(option)
+                    .is_some_and(|value| value.starts_with('='))
+                {
+                    return format!("{}=<redacted>", option);
+                }
+            }
+            argument
+        })
+        .collect()
+}
+
+/// Makes up an id for a snapshot of the target taken at the revision: the revision in UTC,
+/// followed by a short hash telling apart snapshots taken within the same second.
+pub fn new_id(target: &Path, revision: &DateTime<Utc>) -> String {
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let mut seed = target.as_os_str().as_encoded_bytes().to_vec();
+    seed.extend_from_slice(&nanos.to_le_bytes());
+    seed.extend_from_slice(&std::process::id().to_le_bytes());
+    let digest = HashAlgorithm::Sha256.digest(&seed);
+    format!(
+        "{}-{}",
+        revision.format(ID_TIME_FORMAT),
+        hex::encode(&digest[..4])
+    )
+}
+
+/// Reads the record of every snapshot written to the archive, seeking past everything else.
+pub fn read_archive<P: AsRef<Path>>(tarball_path: P) -> std::io::Result<Vec<Provenance>> {
+    let mut archive = Archive::new(BufReader::new(File::open(tarball_path)?));
+    let mut records = Vec::new();
+    for entry in archive.entries_with_seek()? {
+        let entry = entry?;
+        if is_provenance(&entry.path()?) {
+            records.push(Provenance::read(entry)?);
+        }
+    }
+    Ok(records)
+}
+
+pub fn is_provenance(relative_path: &Path) -> bool {
+    relative_path == Path::new(PROVENANCE_FILE_NAME)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recorded(arguments: &[&str]) -> Vec<String> {
+        recorded_arguments(arguments.iter().map(|argument| argument.to_string()))
+    }
+
+    #[test]
+    fn urls_which_can_hold_credentials_are_redacted() {
+        assert_eq!(
+            recorded(&[
+                "-t",
+                "world",
+                "--webhook",
+                "https://hooks.example/T0/secret",
+                "--ping-url=https://hc.example/secret",
+                "--webhook-timeout",
+                "5s",
+            ]),
+            [
+                "-t",
+                "world",
+                "--webhook",
+                "<redacted>",
+                "--ping-url=<redacted>",
+                "--webhook-timeout",
+                "5s",
+            ]
+        );
+    }
+
+    #[test]
+    fn other_options_are_recorded_as_given() {
+        let arguments = ["-t", "world", "--webhook-template", "payload.json", "--"];
+        assert_eq!(recorded(&arguments), arguments);
+    }
+}
@@ -0,0 +1,406 @@
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Datelike, Local, Utc};
+
+use crate::catalog::Record;
+use crate::checksum;
+use crate::hashing::HashAlgorithm;
+use crate::provenance;
+
+/// Suffix of the files in a directory which are taken as snapshot archives.
+const ARCHIVE_SUFFIX: &str = ".tar.zst";
+
+/// Names the bucket an archive falls in, given its index among the archives, newest first, and
+/// when it was taken. A rule keeps the newest archive of each bucket.
+type Bucket = fn(usize, &DateTime<Utc>) -> i64;
+
+/// How many archives each rule keeps. An archive is kept if any rule keeps it.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Retention {
+    pub last: usize,
+    pub hourly: usize,
+    pub daily: usize,
+    pub weekly: usize,
+    pub monthly: usize,
+    pub yearly: usize,
+}
+
+impl Retention {
+    fn rules(&self) -> [(&'static str, usize, Bucket); 6] {
+        [
+            ("last", self.last, |index, _| index as i64),
+            // hours are taken in UTC, so the hour repeated when clocks fall back is two buckets
+            ("hourly", self.hourly, |_, taken| {
+                taken.timestamp().div_euclid(3600)
+            }),
+            ("daily", self.daily, |_, taken| {
+                let taken = taken.with_timezone(&Local);
+                i64::from(taken.year()) * 1000 + i64::from(taken.ordinal())
+            }),
+            ("weekly", self.weekly, |_, taken| {
+                let week = taken.with_timezone(&Local).iso_week();
+                i64::from(week.year()) * 100 + i64::from(week.week())
+            }),
+            ("monthly", self.monthly, |_, taken| {
+                let taken = taken.with_timezone(&Local);
+                i64::from(taken.year()) * 100 + i64::from(taken.month())
+            }),
+            ("yearly", self.yearly, |_, taken| {
+                i64::from(taken.with_timezone(&Local).year())
+            }),
+        ]
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules().iter().all(|(_, count, _)| *count == 0)
+    }
+}
+
+/// A snapshot archive, and when it was taken.
+pub struct Archive {
+    pub path: PathBuf,
+    pub taken: DateTime<Utc>,
+}
+
+/// An archive along with the rules which keep it, as `<rule> #<n>`; none when it's to be removed.
+pub struct Decision {
+    pub archive: Archive,
+    pub kept_by: Vec<String>,
+}
+
+/// Finds the archives in a directory, newest first. Each is dated by the revision of the last
+/// snapshot recorded in it, or by its mtime if it records none. Ties are broken by name, so the
+/// same directory always sorts the same way.
+pub fn find_archives<P: AsRef<Path>>(directory: P) -> std::io::Result<Vec<Archive>> {
+    let mut archives = Vec::new();
+    for entry in std::fs::read_dir(directory)? {
+        let entry = entry?;
+        let path = entry.path();
+        let is_archive = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.ends_with(ARCHIVE_SUFFIX));
+        if !is_archive || !entry.file_type()?.is_file() {
+            continue;
+        }
+        let revision = provenance::read_archive(&path)
+            .ok()
+            .and_then(|records| records.into_iter().rev().find_map(|record| record.revision))
+            .and_then(|revision| DateTime::parse_from_rfc3339(&revision).ok());
+        let taken = match revision {
+            Some(revision) => revision.with_timezone(&Utc),
+            None => {
+                log::warn!(
+                    "Archive {} records no snapshot revision; dating it by its mtime",
+                    path.display()
+                );
+                DateTime::<Utc>::from(entry.metadata()?.modified()?)
+            }
+        };
+        archives.push(Archive { path, taken });
+    }
+    archives.sort_by(|a, b| b.taken.cmp(&a.taken).then_with(|| b.path.cmp(&a.path)));
+    Ok(archives)
+}
+
+/// Groups the archives recorded in a catalog by the target they hold snapshots of, each group
+/// newest first, so every target is pruned on its own. An archive several snapshots were appended
+/// to is dated by the newest of them, and archives which no longer exist are left out.
+pub fn catalog_archives(records: Vec<Record>) -> Vec<(PathBuf, Vec<Archive>)> {
+    let mut targets: Vec<(PathBuf, Vec<Archive>)> = Vec::new();
+    for record in records {
+        let Some(taken) = record.taken() else {
+            log::warn!(
+                "Snapshot {} has an unreadable timestamp {}; leaving its archive alone",
+                record.id,
+                record.timestamp
+            );
+            continue;
+        };
+        if !record.output.is_file() {
+            log::debug!(
+                "Archive {} of snapshot {} no longer exists",
+                record.output.display(),
+                record.id
+            );
+            continue;
+        }
+        let index = match targets
+            .iter()
+            .position(|(target, _)| *target == record.target)
+        {
+            Some(index) => index,
+            None => {
+                targets.push((record.target, Vec::new()));
+                targets.len() - 1
+            }
+        };
+        let archives = &mut targets[index].1;
+        match archives
+            .iter_mut()
+            .find(|archive| archive.path == record.output)
+        {
+            Some(archive) => archive.taken = archive.taken.max(taken),
+            None => archives.push(Archive {
+                path: record.output,
+                taken,
+            }),
+        }
+    }
+    for (_, archives) in &mut targets {
+        archives.sort_by(|a, b| b.taken.cmp(&a.taken).then_with(|| b.path.cmp(&a.path)));
+    }
+    targets
+}
+
+/// Decides which archives to keep, given newest first. Each rule walks the archives from the
+/// newest and keeps the newest archive of every bucket (hour, day, week, month or year) until it
+/// has kept as many as it's allowed.
+pub fn select(archives: Vec<Archive>, retention: &Retention) -> Vec<Decision> {
+    let mut decisions: Vec<Decision> = archives
+        .into_iter()
+        .map(|archive| Decision {
+            archive,
+            kept_by: Vec::new(),
+        })
+        .collect();
+    for (name, count, bucket) in retention.rules() {
+        let mut kept = 0;
+        let mut last_bucket = None;
+        for (index, decision) in decisions.iter_mut().enumerate() {
+            if kept == count {
+                break;
+            }
+            let bucket = bucket(index, &decision.archive.taken);
+            if last_bucket != Some(bucket) {
+                last_bucket = Some(bucket);
+                kept += 1;
+                decision.kept_by.push(format!("{} #{}", name, kept));
+            }
+        }
+    }
+    decisions
+}
+
+/// Removes an archive along with any checksum sidecars beside it.
+pub fn remove(archive: &Archive) -> std::io::Result<()> {
+    std::fs::remove_file(&archive.path)?;
+    for algorithm in [HashAlgorithm::Sha256, HashAlgorithm::Blake3] {
+        let sidecar = checksum::sidecar_path(&archive.path, algorithm);
+        match std::fs::remove_file(&sidecar) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Once;
+
+    use super::*;
+
+    /// Central European time, as a POSIX rule so it doesn't depend on the zoneinfo installed:
+    /// clocks go forward from 02:00 to 03:00 on the last Sunday of March, and back from 03:00
+    /// to 02:00 on the last Sunday of October.
+    const ZONE: &str = "CET-1CEST,M3.5.0,M10.5.0/3";
+
+    /// Archives taken at the instants, given newest first and named after them.
+    fn archives(taken: &[&str]) -> Vec<Archive> {
+        // every test sets the same zone, so tests running alongside each other agree on it
+        static LOCAL_ZONE: Once = Once::new();
+        LOCAL_ZONE.call_once(|| std::env::set_var("TZ", ZONE));
+        taken
+            .iter()
+            .map(|taken| Archive {
+                path: PathBuf::from(taken),
+                taken: DateTime::parse_from_rfc3339(taken).unwrap().into(),
+            })
+            .collect()
+    }
+
+    /// The archives kept, by name, given the instants they were taken at newest first.
+    fn kept(taken: &[&str], retention: Retention) -> Vec<String> {
+        select(archives(taken), &retention)
+            .into_iter()
+            .filter(|decision| !decision.kept_by.is_empty())
+            .map(|decision| decision.archive.path.display().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn the_local_zone_observes_daylight_saving_time() {
+        let [summer, winter] =
+            <[Archive; 2]>::try_from(archives(&["2026-07-01T12:00:00Z", "2026-01-01T12:00:00Z"]))
+                .ok()
+                .unwrap();
+        let offset = |archive: &Archive| {
+            archive
+                .taken
+                .with_timezone(&Local)
+                .offset()
+                .local_minus_utc()
+        };
+        assert_eq!((offset(&summer), offset(&winter)), (7200, 3600));
+    }
+
+    #[test]
+    fn the_hour_repeated_when_clocks_fall_back_is_two_hours() {
+        // 02:40 and 02:20 once clocks went back, after 02:40 and 02:20 in summer time
+        let taken = [
+            "2026-10-25T01:40:00Z",
+            "2026-10-25T01:20:00Z",
+            "2026-10-25T00:40:00Z",
+            "2026-10-25T00:20:00Z",
+        ];
+        let hourly = Retention {
+            hourly: 24,
+            ..Retention::default()
+        };
+        assert_eq!(
+            kept(&taken, hourly),
+            ["2026-10-25T01:40:00Z", "2026-10-25T00:40:00Z"]
+        );
+    }
+
+    #[test]
+    fn the_hour_skipped_when_clocks_go_forward_leaves_no_gap() {
+        // 03:30 in summer time, an hour after 01:30 in winter time
+        let taken = ["2026-03-29T01:30:00Z", "2026-03-29T00:30:00Z"];
+        let hourly = Retention {
+            hourly: 2,
+            ..Retention::default()
+        };
+        assert_eq!(kept(&taken, hourly), taken);
+    }
+
+    #[test]
+    fn days_start_at_local_midnight_on_either_side_of_a_transition() {
+        // 23:30 in summer time and 00:30 in winter time on the 29th, which is 23 hours long,
+        // then 23:30 on the 28th
+        let spring = [
+            "2026-03-29T21:30:00Z",
+            "2026-03-28T23:30:00Z",
+            "2026-03-28T22:30:00Z",
+        ];
+        let daily = Retention {
+            daily: 3,
+            ..Retention::default()
+        };
+        assert_eq!(
+            kept(&spring, daily),
+            ["2026-03-29T21:30:00Z", "2026-03-28T22:30:00Z"]
+        );
+
+        // 23:30 in winter time and 00:30 in summer time on the 25th, which is 25 hours long,
+        // then 23:30 on the 24th
+        let autumn = [
+            "2026-10-25T22:30:00Z",
+            "2026-10-24T22:30:00Z",
+            "2026-10-24T21:30:00Z",
+        ];
+        assert_eq!(
+            kept(&autumn, daily),
+            ["2026-10-25T22:30:00Z", "2026-10-24T21:30:00Z"]
+        );
+    }
+
+    #[test]
+    fn weeks_and_months_turn_over_at_local_midnight() {
+        // 00:30 on Monday the 30th in summer time, then 23:30 on the Sunday before
+        let taken = ["2026-03-29T22:30:00Z", "2026-03-29T21:30:00Z"];
+        let weekly = Retention {
+            weekly: 2,
+            ..Retention::default()
+        };
+        assert_eq!(kept(&taken, weekly), taken);
+
+        // 00:30 on the 1st of November in winter time, then 23:30 on the 31st of October
+        let taken = ["2026-10-31T23:30:00Z", "2026-10-31T22:30:00Z"];
+        let monthly = Retention {
+            monthly: 2,
+            ..Retention::default()
+        };
+        assert_eq!(kept(&taken, monthly), taken);
+
+        // a week spanning new year's is one ISO week, but in two years
+        let taken = ["2027-01-01T12:00:00Z", "2026-12-31T12:00:00Z"];
+        assert_eq!(kept(&taken, weekly), ["2027-01-01T12:00:00Z"]);
+        let yearly = Retention {
+            yearly: 2,
+            ..Retention::default()
+        };
+        assert_eq!(kept(&taken, yearly), taken);
+    }
+
+    #[test]
+    fn sparse_histories_keep_the_buckets_there_are() {
+        // months missing in between, and more than one snapshot in some
+        let taken = [
+            "2026-09-15T12:00:00Z",
+            "2026-09-01T12:00:00Z",
+            "2026-05-20T12:00:00Z",
+            "2025-12-31T12:00:00Z",
+            "2025-12-01T12:00:00Z",
+            "2024-02-29T12:00:00Z",
+        ];
+        let monthly = Retention {
+            monthly: 3,
+            ..Retention::default()
+        };
+        assert_eq!(
+            kept(&taken, monthly),
+            [
+                "2026-09-15T12:00:00Z",
+                "2026-05-20T12:00:00Z",
+                "2025-12-31T12:00:00Z"
+            ]
+        );
+
+        // rules asking for more buckets than there are keep one archive of each
+        let generous = Retention {
+            daily: 7,
+            weekly: 4,
+            ..Retention::default()
+        };
+        assert_eq!(
+            kept(&taken, generous),
+            [
+                "2026-09-15T12:00:00Z",
+                "2026-09-01T12:00:00Z",
+                "2026-05-20T12:00:00Z",
+                "2025-12-31T12:00:00Z",
+                "2025-12-01T12:00:00Z",
+                "2024-02-29T12:00:00Z"
+            ]
+        );
+    }
+
+    #[test]
+    fn each_kept_archive_names_the_rules_keeping_it() {
+        let taken = [
+            "2026-10-25T01:40:00Z",
+            "2026-10-25T00:40:00Z",
+            "2026-10-24T12:00:00Z",
+        ];
+        let retention = Retention {
+            last: 1,
+            hourly: 2,
+            daily: 2,
+            ..Retention::default()
+        };
+        let kept_by: Vec<Vec<String>> = select(archives(&taken), &retention)
+            .into_iter()
+            .map(|decision| decision.kept_by)
+            .collect();
+        assert_eq!(
+            kept_by,
+            [
+                vec!["last #1", "hourly #1", "daily #1"],
+                vec!["hourly #2"],
+                vec!["daily #2"],
+            ]
+        );
+    }
+}
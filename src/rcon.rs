@@ -0,0 +1,332 @@
+use std::error::Error;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// How long connecting to the server, or waiting on any one of its responses, may take.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+const LOGIN: i32 = 3;
+const COMMAND: i32 = 2;
+/// The type of a command's output, which some servers also send ahead of a login answer.
+const RESPONSE: i32 = 0;
+/// The request id the server answers a rejected login with.
+const AUTH_FAILED_ID: i32 = -1;
+/// Largest packet the protocol allows, in either direction.
+const MAX_PACKET_SIZE: usize = 4096 + 10;
+
+/// The server saving was paused on, so saving can be resumed however the snapshot ends.
+static PAUSED: Mutex<Option<Rcon>> = Mutex::new(None);
+
+#[derive(Debug)]
+pub enum RconError {
+    Io(std::io::Error),
+    AuthFailed,
+    /// The server answered with something which isn't an RCON packet.
+    Malformed(String),
+}
+
+impl Error for RconError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RconError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for RconError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RconError::Io(err) => write!(f, "RCON connection failed: {}", err),
+            RconError::AuthFailed => write!(f, "RCON password was rejected"),
+            RconError::Malformed(reason) => write!(f, "Malformed RCON packet: {}", reason),
+        }
+    }
+}
+
+impl From<std::io::Error> for RconError {
+    fn from(value: std::io::Error) -> Self {
+        RconError::Io(value)
+    }
+}
+
+/// A logged in connection to a game server's remote console.
+pub struct Rcon {
+    stream: TcpStream,
+    next_id: i32,
+}
+
+impl Rcon {
+    pub fn connect(address: &str, password: &str) -> Result<Self, RconError> {
+        Self::connect_within(address, password, TIMEOUT)
+    }
+
+    /// Connects and logs in, giving up on any step which takes longer than `timeout`.
+    fn connect_within(address: &str, password: &str, timeout: Duration) -> Result<Self, RconError> {
+        let address = address.to_socket_addrs()?.next().ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("{} doesn't resolve to an address", address),
+            )
+        })?;
+        let stream = TcpStream::connect_timeout(&address, timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        let mut rcon = Rcon { stream, next_id: 1 };
+        rcon.request(LOGIN, password)?;
+        Ok(rcon)
+    }
+
+    /// Runs a console command, returning what the server answered with.
+    pub fn command(&mut self, command: &str) -> Result<String, RconError> {
+        self.request(COMMAND, command)
+    }
+
+    fn request(&mut self, kind: i32, body: &str) -> Result<String, RconError> {
+        let id = self.next_id;
+        self.next_id += 1;
+        // length, id and type, then the body and the empty string following it, both terminated
+        let length = 4 + 4 + body.len() + 2;
+        if length + 4 > MAX_PACKET_SIZE {
+            return Err(RconError::Malformed(format!(
+                "request of {} bytes is too large",
+                length
+            )));
+        }
+        let mut packet = Vec::with_capacity(length + 4);
+        packet.extend_from_slice(&(length as i32).to_le_bytes());
+        packet.extend_from_slice(&id.to_le_bytes());
+        packet.extend_from_slice(&kind.to_le_bytes());
+        packet.extend_from_slice(body.as_bytes());
+        packet.extend_from_slice(&[0, 0]);
+        self.stream.write_all(&packet)?;
+
+        // servers may send stray packets first, like the empty response preceding a login answer
+        loop {
+            let (response_id, response_kind, body) = self.read_packet()?;
+            if kind == LOGIN && response_kind == RESPONSE {
+                continue;
+            }
+            if kind == LOGIN && response_id == AUTH_FAILED_ID {
+                return Err(RconError::AuthFailed);
+            }
+            if response_id == id {
+                return Ok(body);
+            }
+        }
+    }
+
+    /// Reads the next packet, returning its id, type and body.
+    fn read_packet(&mut self) -> Result<(i32, i32, String), RconError> {
+        let mut length = [0; 4];
+        self.stream.read_exact(&mut length)?;
+        let length = i32::from_le_bytes(length);
+        if !(10..=MAX_PACKET_SIZE as i32).contains(&length) {
+            return Err(RconError::Malformed(format!("invalid length {}", length)));
+        }
+        let mut packet = vec![0; length as usize];
+        self.stream.read_exact(&mut packet)?;
+        let id = i32::from_le_bytes(packet[0..4].try_into().unwrap());
+        let kind = i32::from_le_bytes(packet[4..8].try_into().unwrap());
+        let body = &packet[8..packet.len() - 2];
+        Ok((id, kind, String::from_utf8_lossy(body).into_owned()))
+    }
+}
+
+/// Stops the server from writing its world out and flushes everything pending, so the world holds
+/// still while it's captured. Saving stays paused until `resume_saving` is called.
+pub fn pause_saving(address: &str, password: &str) -> Result<(), RconError> {
+    let mut rcon = Rcon::connect(address, password)?;
+    log::info!("Connected to RCON at {}", address);
+    let response = rcon.command("save-off")?;
+    log::info!("RCON save-off: {}", response.trim());
+    // the pause is recorded before flushing, so saving is resumed even if the flush fails
+    let mut paused = PAUSED.lock().unwrap();
+    let response = paused.insert(rcon).command("save-all flush")?;
+    log::info!("RCON save-all flush: {}", response.trim());
+    Ok(())
+}
+
+/// Lets the server save again, if saving was paused.
+pub fn resume_saving() {
+    let paused = match PAUSED.lock() {
+        Ok(mut paused) => paused.take(),
+        Err(_) => return,
+    };
+    if let Some(mut rcon) = paused {
+        match rcon.command("save-on") {
+            Ok(response) => log::info!("RCON save-on: {}", response.trim()),
+            Err(err) => log::error!("Failed to resume saving over RCON: {}", err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::thread::JoinHandle;
+
+    use super::*;
+
+    /// Runs a mock server answering a single connection the way `handle` does, returning its
+    /// address along with whatever `handle` returns once the connection is done.
+    fn serve<T: Send + 'static>(
+        handle: impl FnOnce(&mut TcpStream) -> T + Send + 'static,
+    ) -> (String, JoinHandle<T>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            handle(&mut stream)
+        });
+        (address, server)
+    }
+
+    /// Reads a request the way a server does, returning its id, type and body.
+    fn receive(stream: &mut TcpStream) -> (i32, i32, String) {
+        let mut length = [0; 4];
+        stream.read_exact(&mut length).unwrap();
+        let mut packet = vec![0; i32::from_le_bytes(length) as usize];
+        stream.read_exact(&mut packet).unwrap();
+        assert_eq!(&packet[packet.len() - 2..], &[0, 0]);
+        (
+            i32::from_le_bytes(packet[0..4].try_into().unwrap()),
+            i32::from_le_bytes(packet[4..8].try_into().unwrap()),
+            String::from_utf8(packet[8..packet.len() - 2].to_vec()).unwrap(),
+        )
+    }
+
+    fn answer(stream: &mut TcpStream, id: i32, kind: i32, body: &str) {
+        let mut packet = Vec::new();
+        packet.extend_from_slice(&((10 + body.len()) as i32).to_le_bytes());
+        packet.extend_from_slice(&id.to_le_bytes());
+        packet.extend_from_slice(&kind.to_le_bytes());
+        packet.extend_from_slice(body.as_bytes());
+        packet.extend_from_slice(&[0, 0]);
+        stream.write_all(&packet).unwrap();
+    }
+
+    /// Accepts the login, preceded by the empty response Minecraft sends ahead of it.
+    fn log_in(stream: &mut TcpStream) {
+        let (id, kind, password) = receive(stream);
+        assert_eq!((kind, password.as_str()), (LOGIN, "hunter2"));
+        answer(stream, id, RESPONSE, "");
+        answer(stream, id, COMMAND, "");
+    }
+
+    #[test]
+    fn logs_in_and_runs_commands() {
+        let (address, server) = serve(|stream| {
+            log_in(stream);
+            let (id, kind, command) = receive(stream);
+            assert_eq!(kind, COMMAND);
+            answer(stream, id, RESPONSE, "Automatic saving is now disabled");
+            command
+        });
+        let mut rcon = Rcon::connect(&address, "hunter2").unwrap();
+        let response = rcon.command("save-off").unwrap();
+        assert_eq!(response, "Automatic saving is now disabled");
+        assert_eq!(server.join().unwrap(), "save-off");
+    }
+
+    #[test]
+    fn passes_over_answers_to_other_requests() {
+        let (address, server) = serve(|stream| {
+            log_in(stream);
+            let (id, _, _) = receive(stream);
+            answer(stream, id + 100, RESPONSE, "stray");
+            answer(stream, id, RESPONSE, "answer");
+        });
+        let mut rcon = Rcon::connect(&address, "hunter2").unwrap();
+        assert_eq!(rcon.command("list").unwrap(), "answer");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn a_rejected_password_fails_to_log_in() {
+        let (address, server) = serve(|stream| {
+            receive(stream);
+            answer(stream, AUTH_FAILED_ID, COMMAND, "");
+        });
+        let result = Rcon::connect(&address, "wrong");
+        assert!(matches!(result, Err(RconError::AuthFailed)));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn a_packet_of_an_impossible_length_is_malformed() {
+        let (address, server) = serve(|stream| {
+            receive(stream);
+            stream.write_all(&3i32.to_le_bytes()).unwrap();
+        });
+        let result = Rcon::connect(&address, "hunter2");
+        assert!(matches!(result, Err(RconError::Malformed(_))));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn a_command_too_large_for_a_packet_is_never_sent() {
+        let (address, server) = serve(|stream| {
+            log_in(stream);
+            // the connection closes without another request
+            let mut rest = Vec::new();
+            stream.read_to_end(&mut rest).unwrap();
+            rest
+        });
+        let mut rcon = Rcon::connect(&address, "hunter2").unwrap();
+        let result = rcon.command(&"a".repeat(MAX_PACKET_SIZE));
+        assert!(matches!(result, Err(RconError::Malformed(_))));
+        drop(rcon);
+        assert!(server.join().unwrap().is_empty());
+    }
+
+    #[test]
+    fn a_server_which_never_answers_times_out() {
+        let (address, server) = serve(|stream| {
+            receive(stream);
+            // hold the connection open without answering until the client gives up
+            let mut rest = Vec::new();
+            let _ = stream.read_to_end(&mut rest);
+        });
+        let started = std::time::Instant::now();
+        let result = Rcon::connect_within(&address, "hunter2", Duration::from_millis(200));
+        match result {
+            Err(RconError::Io(err)) => assert!(
+                matches!(
+                    err.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ),
+                "{}",
+                err
+            ),
+            Err(err) => panic!("{}", err),
+            Ok(_) => panic!("logged in without an answer"),
+        }
+        assert!(started.elapsed() < TIMEOUT);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn saving_is_paused_flushed_and_resumed() {
+        let (address, server) = serve(|stream| {
+            log_in(stream);
+            let mut commands = Vec::new();
+            for _ in 0..3 {
+                let (id, _, command) = receive(stream);
+                answer(stream, id, RESPONSE, "ok");
+                commands.push(command);
+            }
+            commands
+        });
+        pause_saving(&address, "hunter2").unwrap();
+        resume_saving();
+        // resuming again does nothing once saving was resumed
+        resume_saving();
+        assert_eq!(
+            server.join().unwrap(),
+            ["save-off", "save-all flush", "save-on"]
+        );
+    }
+}
@@ -0,0 +1,525 @@
+use std::collections::HashMap;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime};
+
+use clap::ValueEnum;
+use serde::Serialize;
+
+use crate::archiver::create_file;
+use crate::hashing::HashAlgorithm;
+use crate::manifest::ModifiedWindow;
+use crate::policy::{Compromise, Policy, Refusal};
+use crate::processor::UnstablePath;
+use crate::warnings::{Warning, WarningCounts};
+
+/// File to list the paths left out of the snapshot in, if any.
+static SKIPPED_LIST: OnceLock<PathBuf> = OnceLock::new();
+
+/// Summarizes a snapshot as it's taken. Processing and archiving add to it as they go, so it
+/// describes whatever was done even when a snapshot fails partway.
+#[derive(Serialize, Debug, Default)]
+pub struct SnapshotReport {
+    /// Id of the snapshot, as recorded in its archive and the catalog.
+    pub id: Option<String>,
+    /// The path the target was given as, when it's a symlink to the directory snapshotted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub invoked_as: Option<PathBuf>,
+    /// The revision the captured entries were confirmed stable at, or the one `--at-revision`
+    /// gave, in RFC 3339.
+    pub timestamp: Option<String>,
+    /// Number of passes run over the target.
+    pub iterations: usize,
+    /// Entries `--preflight` found beneath the target before the capture, when it ran.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub estimated_entries: Option<usize>,
+    /// Algorithm the content of captured files was hashed with.
+    pub hash_algorithm: HashAlgorithm,
+    /// Id of the dictionary captured files were compressed with.
+    pub dictionary_id: Option<u32>,
+    /// Whether only the metadata of files was captured, without their content.
+    pub metadata_only: bool,
+    /// Whether the snapshot ran out of time and only holds what was done by then.
+    pub partial: bool,
+    pub files: usize,
+    pub directories: usize,
+    pub symlinks: usize,
+    pub whiteouts: usize,
+    /// Files left out of the archive because they're unchanged since the snapshot diffed against.
+    pub unchanged_files: usize,
+    /// Hidden entries left out by `--exclude-hidden`, counting a hidden directory once for all
+    /// beneath it.
+    pub hidden: usize,
+    /// Files left out by `--min-age` for having been modified too recently.
+    pub too_recent: usize,
+    /// Files left out for having been modified outside `--modified-since` and `--modified-before`.
+    pub outside_window: usize,
+    /// Files and symlinks left out by `--owner` and `--group` for belonging to someone else.
+    pub not_owned: usize,
+    /// Files and symlinks left out by `--at-revision` for having been modified after it.
+    pub after_revision: usize,
+    /// The window files had to be modified within to be captured, when one was given.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub modified_window: Option<ModifiedWindow>,
+    /// Bytes read from the target while capturing, across every pass.
+    pub bytes_read: u64,
+    /// Size of the written archive.
+    pub bytes_written: u64,
+    /// How well the archived files compressed.
+    pub compression: CompressionStats,
+    pub durations: PhaseDurations,
+    /// Paths left out of the snapshot.
+    pub skipped: Vec<SkippedPath>,
+    /// Paths found changing during a pass, once for every pass they were found changing in.
+    pub unstable: Vec<UnstablePath>,
+    /// How many warnings of every kind the snapshot ran into.
+    pub warnings: WarningCounts,
+    /// The first compromise the policy refused, which stops the snapshot.
+    #[serde(skip)]
+    pub refusal: Option<Refusal>,
+    /// The largest files and directories captured, when asked for.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub largest: Option<Largest>,
+    /// What the directories near the top of the target add up to, when asked for.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub breakdown: Option<Breakdown>,
+}
+
+/// Adds up the files archived with their content before and after compression, overall and for
+/// every compression level they were captured at.
+#[derive(Serialize, Debug, Default)]
+pub struct CompressionStats {
+    pub files: usize,
+    pub original_bytes: u64,
+    pub compressed_bytes: u64,
+    /// The same, for every level files were captured at, lowest first.
+    pub levels: Vec<LevelStats>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct LevelStats {
+    pub level: i32,
+    pub files: usize,
+    pub original_bytes: u64,
+    pub compressed_bytes: u64,
+}
+
+impl CompressionStats {
+    /// Tallies an archived file compressed at the level.
+    pub fn record(&mut self, level: i32, original_size: u64, compressed_size: u64) {
+        self.files += 1;
+        self.original_bytes += original_size;
+        self.compressed_bytes += compressed_size;
+        let index = match self
+            .levels
+            .binary_search_by_key(&level, |stats| stats.level)
+        {
+            Ok(index) => index,
+            Err(index) => {
+                self.levels.insert(
+                    index,
+                    LevelStats {
+                        level,
+                        files: 0,
+                        original_bytes: 0,
+                        compressed_bytes: 0,
+                    },
+                );
+                index
+            }
+        };
+        let stats = &mut self.levels[index];
+        stats.files += 1;
+        stats.original_bytes += original_size;
+        stats.compressed_bytes += compressed_size;
+    }
+
+    pub fn log(&self) {
+        if self.files == 0 {
+            return;
+        }
+        log::info!(
+            "Compressed {} files from {} to {}{}",
+            self.files,
+            format_bytes(self.original_bytes),
+            format_bytes(self.compressed_bytes),
+            percentage(self.original_bytes, self.compressed_bytes)
+        );
+        // the overall line already says it all when every file shares a level
+        if self.levels.len() > 1 {
+            for stats in &self.levels {
+                log::info!(
+                    "  level {}: {} files from {} to {}{}",
+                    stats.level,
+                    stats.files,
+                    format_bytes(stats.original_bytes),
+                    format_bytes(stats.compressed_bytes),
+                    percentage(stats.original_bytes, stats.compressed_bytes)
+                );
+            }
+        }
+    }
+}
+
+/// Tallies the largest files captured and the directories holding the most, to find what a
+/// snapshot grew by.
+#[derive(Serialize, Debug, Default)]
+pub struct Largest {
+    /// The largest files, largest first.
+    pub files: Vec<SizedPath>,
+    /// The directories whose files add up to the most, largest first.
+    pub directories: Vec<SizedPath>,
+    #[serde(skip)]
+    limit: usize,
+    /// What every directory holding a file adds up to so far.
+    #[serde(skip)]
+    totals: HashMap<PathBuf, (u64, u64)>,
+}
+
+/// A path with the size of its content, and of its capture once compressed. For a directory, the
+/// sizes add up everything beneath it.
+#[derive(Serialize, Debug, Clone)]
+pub struct SizedPath {
+    pub path: PathBuf,
+    pub size: u64,
+    pub encoded_size: u64,
+}
+
+impl Largest {
+    /// Keeps the `limit` largest files and directories.
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            ..Self::default()
+        }
+    }
+
+    /// Tallies a captured file, at its path in the archive, towards every directory above it.
+    pub fn record(&mut self, relative_path: &Path, size: u64, encoded_size: u64) {
+        for directory in relative_path.ancestors().skip(1) {
+            if directory.as_os_str().is_empty() {
+                break;
+            }
+            let total = self.totals.entry(directory.to_path_buf()).or_default();
+            total.0 += size;
+            total.1 += encoded_size;
+        }
+        self.files.push(SizedPath {
+            path: relative_path.to_path_buf(),
+            size,
+            encoded_size,
+        });
+        // only ever sorted once enough files pile up, rather than for every one
+        if self.files.len() >= self.limit.max(64) * 2 {
+            keep_largest(&mut self.files, self.limit);
+        }
+    }
+
+    /// Settles the largest files and directories once every file is tallied.
+    pub fn finish(&mut self) {
+        keep_largest(&mut self.files, self.limit);
+        self.directories = self
+            .totals
+            .drain()
+            .map(|(path, (size, encoded_size))| SizedPath {
+                path,
+                size,
+                encoded_size,
+            })
+            .collect();
+        keep_largest(&mut self.directories, self.limit);
+    }
+
+    pub fn log(&self) {
+        for (kind, sized) in [("files", &self.files), ("directories", &self.directories)] {
+            log::info!("Largest {}:", kind);
+            for sized in sized {
+                log::info!(
+                    "  {} bytes ({} compressed) {}",
+                    sized.size,
+                    sized.encoded_size,
+                    sized.path.display()
+                );
+            }
+        }
+    }
+}
+
+/// How the rows of a breakdown are ordered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum BreakdownSort {
+    /// Largest once compressed first.
+    #[default]
+    Compressed,
+    /// Largest before compression first.
+    Original,
+    /// Least compressible first.
+    Ratio,
+    /// By path.
+    Path,
+}
+
+/// Adds up the files beneath every directory down to some depth below the target, before and
+/// after compression. Directories holding nothing captured, even because every path in them was
+/// filtered out, are listed with nothing, so what's missing stands out.
+#[derive(Serialize, Debug)]
+pub struct Breakdown {
+    /// How far below the target directories are listed, with 1 for its immediate subdirectories.
+    pub depth: usize,
+    /// The directories, in the order asked for.
+    pub directories: Vec<SizedPath>,
+    #[serde(skip)]
+    sort: BreakdownSort,
+    #[serde(skip)]
+    totals: HashMap<PathBuf, (u64, u64)>,
+}
+
+impl Breakdown {
+    pub fn new(depth: usize, sort: BreakdownSort) -> Self {
+        Self {
+            depth,
+            directories: Vec::new(),
+            sort,
+            totals: HashMap::new(),
+        }
+    }
+
+    /// Whether a path in the archive, nested under the target directory's name, is a row.
+    fn listed(&self, relative_path: &Path) -> bool {
+        (1..=self.depth).contains(&relative_path.components().count().saturating_sub(1))
+    }
+
+    /// Lists a directory, even if nothing beneath it is ever tallied.
+    pub fn record_directory(&mut self, relative_path: &Path) {
+        if self.listed(relative_path) {
+            self.totals.entry(relative_path.to_path_buf()).or_default();
+        }
+    }
+
+    /// Tallies a captured file towards the listed directories above it.
+    pub fn record_file(&mut self, relative_path: &Path, size: u64, encoded_size: u64) {
+        for directory in relative_path.ancestors().skip(1) {
+            if self.listed(directory) {
+                let total = self.totals.entry(directory.to_path_buf()).or_default();
+                total.0 += size;
+                total.1 += encoded_size;
+            }
+        }
+    }
+
+    /// Orders the directories once every file is tallied.
+    pub fn finish(&mut self) {
+        let mut directories: Vec<SizedPath> = self
+            .totals
+            .drain()
+            .map(|(path, (size, encoded_size))| SizedPath {
+                path,
+                size,
+                encoded_size,
+            })
+            .collect();
+        match self.sort {
+            BreakdownSort::Compressed => directories.sort_by(|a, b| {
+                b.encoded_size
+                    .cmp(&a.encoded_size)
+                    .then_with(|| a.path.cmp(&b.path))
+            }),
+            BreakdownSort::Original => {
+                directories.sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.path.cmp(&b.path)))
+            }
+            BreakdownSort::Ratio => directories.sort_by(|a, b| {
+                ratio(b)
+                    .total_cmp(&ratio(a))
+                    .then_with(|| a.path.cmp(&b.path))
+            }),
+            BreakdownSort::Path => directories.sort_by(|a, b| a.path.cmp(&b.path)),
+        }
+        self.directories = directories;
+    }
+
+    pub fn log(&self) {
+        log::info!("Breakdown by directory:");
+        for directory in &self.directories {
+            log::info!(
+                "  {}: {} → {}{}",
+                directory.path.display(),
+                format_bytes(directory.size),
+                format_bytes(directory.encoded_size),
+                percentage(directory.size, directory.encoded_size)
+            );
+        }
+    }
+}
+
+/// How large a directory's captures are next to its content, with 0 for an empty directory.
+fn ratio(sized: &SizedPath) -> f64 {
+    if sized.size == 0 {
+        0.0
+    } else {
+        sized.encoded_size as f64 / sized.size as f64
+    }
+}
+
+/// How large captures are next to their content as a parenthesized percentage, or nothing when
+/// there's no content to compare them to.
+fn percentage(size: u64, encoded_size: u64) -> String {
+    if size > 0 {
+        format!(" ({:.0}%)", encoded_size as f64 / size as f64 * 100.0)
+    } else {
+        String::new()
+    }
+}
+
+/// Writes a byte count the way people read it, in decimal units to one decimal place.
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["KB", "MB", "GB", "TB", "PB"];
+    if bytes < 1000 {
+        return format!("{} B", bytes);
+    }
+    let mut scaled = bytes as f64 / 1000.0;
+    let mut unit = 0;
+    while scaled >= 1000.0 && unit + 1 < UNITS.len() {
+        scaled /= 1000.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", scaled, UNITS[unit])
+}
+
+fn keep_largest(sized: &mut Vec<SizedPath>, limit: usize) {
+    sized.sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.path.cmp(&b.path)));
+    sized.truncate(limit);
+}
+
+/// Milliseconds spent in each phase of a snapshot.
+#[derive(Serialize, Debug, Default)]
+pub struct PhaseDurations {
+    pub capture_ms: u64,
+    pub archive_ms: u64,
+    pub verify_ms: u64,
+}
+
+/// A path left out of the snapshot, and why.
+#[derive(Serialize, Debug)]
+pub struct SkippedPath {
+    pub path: PathBuf,
+    pub code: SkipCode,
+    pub reason: String,
+}
+
+/// Why a path was left out of the snapshot, for telling skipped paths apart without parsing their
+/// reasons.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SkipCode {
+    /// Left out on purpose, like the snapshot's own output or a tagged cache.
+    Excluded,
+    /// Couldn't be read, or captured at all.
+    Unreadable,
+    /// Lies outside the target, like where a symlink points or a listed file.
+    OutsideTarget,
+    /// Never stopped changing before a partial snapshot ran out of time, so it was dropped.
+    Unstable,
+    /// Modified more recently than `--min-age` allows, so it was left out without being read.
+    TooRecent,
+    /// Neither a file, a directory nor a symlink.
+    Unsupported,
+    /// A dereferenced symlink leading back into its own ancestry.
+    SymlinkLoop,
+}
+
+impl SkipCode {
+    /// The compromise leaving out a path for this reason makes, unless it was left out on
+    /// request.
+    pub fn compromise(self) -> Option<Compromise> {
+        match self {
+            SkipCode::Unreadable => Some(Compromise::Unreadable),
+            SkipCode::OutsideTarget => Some(Compromise::OutsideTarget),
+            SkipCode::Unstable => Some(Compromise::Partial),
+            SkipCode::Unsupported => Some(Compromise::Unsupported),
+            SkipCode::SymlinkLoop => Some(Compromise::SymlinkLoop),
+            SkipCode::Excluded | SkipCode::TooRecent => None,
+        }
+    }
+}
+
+impl SnapshotReport {
+    /// Records a path left out of the snapshot. Paths left out on request aren't a problem, but
+    /// every other one is a warning, and a compromise the policy may refuse to carry on past.
+    pub fn skip<P: AsRef<Path>>(
+        &mut self,
+        policy: &Policy,
+        path: P,
+        code: SkipCode,
+        reason: impl ToString,
+    ) {
+        let reason = reason.to_string();
+        let warning = match code {
+            SkipCode::Unreadable => Some(Warning::Unreadable),
+            SkipCode::OutsideTarget => Some(Warning::OutsideTarget),
+            SkipCode::Unstable => Some(Warning::Dropped),
+            SkipCode::Unsupported => Some(Warning::UnsupportedType),
+            SkipCode::SymlinkLoop => Some(Warning::SymlinkLoop),
+            SkipCode::Excluded | SkipCode::TooRecent => None,
+        };
+        if let Some(warning) = warning {
+            self.warnings.record(warning);
+        }
+        if let Some(compromise) = code.compromise() {
+            self.refuse(policy.refuse(&path, compromise, &reason));
+        }
+        self.skipped.push(SkippedPath {
+            path: path.as_ref().to_path_buf(),
+            code,
+            reason,
+        });
+    }
+
+    /// Remembers the refusal, unless one came before it.
+    pub fn refuse(&mut self, refusal: Option<Refusal>) {
+        if self.refusal.is_none() {
+            self.refusal = refusal;
+        }
+    }
+
+    /// The paths left out of the snapshot as compromises, rather than on request.
+    pub fn compromises(&self) -> impl Iterator<Item = (&SkippedPath, Compromise)> {
+        self.skipped
+            .iter()
+            .filter_map(|skipped| Some((skipped, skipped.code.compromise()?)))
+    }
+
+    pub fn stamp(&mut self, revision: SystemTime) {
+        self.timestamp = Some(chrono::DateTime::<chrono::Utc>::from(revision).to_rfc3339());
+    }
+
+    pub fn write<P: AsRef<Path>>(&self, path: P, mode: Option<u32>) -> std::io::Result<()> {
+        let mut writer = BufWriter::new(create_file(path, mode)?);
+        serde_json::to_writer_pretty(&mut writer, self)?;
+        writer.flush()
+    }
+
+    /// Lists every path left out of the snapshot in the file set by `list_skipped_in`, one JSON
+    /// object per line, returning the file written, if there is one.
+    pub fn write_skipped(&self, mode: Option<u32>) -> std::io::Result<Option<&'static Path>> {
+        let Some(path) = SKIPPED_LIST.get() else {
+            return Ok(None);
+        };
+        let mut writer = BufWriter::new(create_file(path, mode)?);
+        for skipped in &self.skipped {
+            serde_json::to_writer(&mut writer, skipped)?;
+            writeln!(writer)?;
+        }
+        writer.flush()?;
+        Ok(Some(path))
+    }
+}
+
+/// Has every report written from now on also list the paths the snapshot left out in this file.
+pub fn list_skipped_in<P: Into<PathBuf>>(path: P) {
+    let _ = SKIPPED_LIST.set(path.into());
+}
+
+pub fn millis(duration: Duration) -> u64 {
+    duration.as_millis() as u64
+}
@@ -0,0 +1,594 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+use globset::GlobSet;
+use tar::Archive;
+
+use crate::archiver::WHITEOUT_PREFIX;
+use crate::batch;
+use crate::dictionary::{self, Dictionary};
+use crate::hashing::HashAlgorithm;
+use crate::index::{self, Index, IndexEntry, IndexedKind};
+use crate::manifest::{self, Manifest};
+use crate::processor::Digest;
+use crate::provenance::{self, Provenance};
+
+/// Options controlling what `restore_tarball` restores.
+#[derive(Default)]
+pub struct RestoreOptions<'a> {
+    /// Dictionary the archive's files were compressed with, if they were.
+    pub dictionary: Option<&'a Dictionary>,
+    /// Check every restored file a snapshot recorded the manifest of against the digest it was
+    /// captured with, failing the restore at the first which doesn't match.
+    pub verify: bool,
+    /// Only restore this many of the snapshots appended to the archive, oldest first, leaving the
+    /// target as the last of them captured it.
+    pub snapshots: Option<usize>,
+    /// Only restore the paths in the archive these globs match, leaving everything else out.
+    pub only: Option<GlobSet>,
+}
+
+/// Extracts a tarball produced by `create_tarball` into the destination directory, decompressing
+/// every file and splitting batched files back out of their batch entries. Whiteouts from
+/// incremental snapshots remove the paths they mark, so an incremental snapshot can be restored
+/// over the restore of its base. Files compressed with a dictionary need that dictionary given.
+/// Restoring only some paths reads just the members holding them when the archive has an index.
+pub fn restore_tarball<P1: AsRef<Path>, P2: AsRef<Path>>(
+    tarball_path: P1,
+    destination: P2,
+    options: &RestoreOptions,
+) -> std::io::Result<usize> {
+    let tarball_path = tarball_path.as_ref();
+    let destination = destination.as_ref();
+    log::info!(
+        "Restoring tarball {} into {}",
+        tarball_path.display(),
+        destination.display()
+    );
+
+    let index = options
+        .only
+        .as_ref()
+        .and_then(|_| Index::read_for(tarball_path));
+    let manifests = match &index {
+        Some(index) => index
+            .read_records(tarball_path)?
+            .into_iter()
+            .map(|(_, manifest)| manifest)
+            .collect(),
+        None => manifest::read_embedded(tarball_path)?,
+    };
+    let recorded: Vec<Option<CapturedDigests>> = if options.verify {
+        manifests
+            .iter()
+            .map(|manifest| manifest.as_ref().map(CapturedDigests::new))
+            .collect()
+    } else {
+        Vec::new()
+    };
+    let mut recorded = recorded.into_iter();
+    let mut restoring = Restoring {
+        destination,
+        options,
+        digests: recorded.next().flatten(),
+        recorded,
+        verified: 0,
+        directories: Vec::new(),
+        restored: 0,
+        restored_snapshots: 0,
+        unfinished: false,
+    };
+
+    match &index {
+        Some(index) => {
+            for indexed in &index.entries {
+                if !restoring.needs(indexed) {
+                    continue;
+                }
+                let mut archive = index::open_member(tarball_path, indexed)?;
+                if !restoring.restore(index::member(&mut archive, indexed)?)? {
+                    break;
+                }
+            }
+        }
+        None => {
+            let mut archive = Archive::new(BufReader::new(File::open(tarball_path)?));
+            for entry in archive.entries()? {
+                if !restoring.restore(entry?)? {
+                    break;
+                }
+            }
+        }
+    }
+
+    // directories are finalized last so restoring their contents doesn't bump their mtimes
+    for (path, mode, owner, mtime) in restoring.directories.into_iter().rev() {
+        apply_metadata(&path, mode, Some(owner), mtime)?;
+    }
+
+    if restoring.unfinished {
+        log::warn!("Archive ends partway through a snapshot; it may be truncated");
+    }
+    // only a whole restore is expected to hold everything listed, as of the last snapshot restored
+    let last_manifest = restoring
+        .restored_snapshots
+        .checked_sub(1)
+        .and_then(|index| manifests.get(index)?.as_ref());
+    if let (None, Some(manifest)) = (&options.only, last_manifest) {
+        let incomplete = manifest::incomplete_directories(&manifest.entries, |path| {
+            safe_join(destination, path)
+                .and_then(std::fs::read_dir)
+                .map_or(0, |read_dir| read_dir.count())
+        });
+        for (path, held, listed) in incomplete {
+            log::warn!(
+                "Restored directory {} holds {} of the {} entries listed in it when it was captured",
+                path.display(),
+                held,
+                listed
+            );
+        }
+    }
+
+    if restoring.verified > 0 {
+        log::info!(
+            "Checked {} restored files against the digests they were captured with",
+            restoring.verified
+        );
+    }
+    Ok(restoring.restored)
+}
+
+/// How far a restore got, as it's handed the members of the archive one by one.
+struct Restoring<'a> {
+    destination: &'a Path,
+    options: &'a RestoreOptions<'a>,
+    /// Digests of the snapshot being restored, followed by those of the snapshots after it.
+    digests: Option<CapturedDigests>,
+    recorded: std::vec::IntoIter<Option<CapturedDigests>>,
+    verified: usize,
+    directories: Vec<(PathBuf, u32, (u64, u64), u64)>,
+    restored: usize,
+    restored_snapshots: usize,
+    /// Whether members were restored since the last snapshot record.
+    unfinished: bool,
+}
+
+impl Restoring<'_> {
+    fn wanted(&self, relative_path: &Path) -> bool {
+        self.options
+            .only
+            .as_ref()
+            .is_none_or(|only| only.is_match(relative_path))
+    }
+
+    /// Whether the indexed member holds anything restored, or ends a snapshot.
+    fn needs(&self, indexed: &IndexEntry) -> bool {
+        match indexed.kind {
+            IndexedKind::Record | IndexedKind::Manifest => true,
+            IndexedKind::Batch => indexed
+                .members
+                .iter()
+                .any(|name| self.wanted(&indexed.path.with_file_name(name))),
+            _ => self.wanted(&whited_out_path(&indexed.path)),
+        }
+    }
+
+    /// Restores one member of the archive, returning whether to carry on with the next.
+    fn restore<R: Read>(&mut self, entry: tar::Entry<R>) -> std::io::Result<bool> {
+        let destination = self.destination;
+        let dictionary = self.options.dictionary;
+        let relative_path = entry.path()?.into_owned();
+        self.unfinished = !provenance::is_provenance(&relative_path);
+        let target_path = safe_join(destination, &relative_path)?;
+        let header = entry.header();
+        let mode = header.mode()?;
+        let owner = (header.uid()?, header.gid()?);
+        let mtime = header.mtime()?;
+
+        match header.entry_type() {
+            // a batch is picked apart member by member, and snapshot records end a snapshot
+            tar::EntryType::Regular
+                if relative_path.file_name() == Some(batch::BATCH_FILE_NAME.as_ref())
+                    || provenance::is_provenance(&relative_path)
+                    || manifest::is_embedded_manifest(&relative_path) => {}
+            _ if !self.wanted(&whited_out_path(&relative_path)) => return Ok(true),
+            _ => {}
+        }
+
+        match header.entry_type() {
+            tar::EntryType::Directory => {
+                log::debug!("Restoring directory {}", relative_path.display());
+                clear_path(&target_path)?;
+                std::fs::create_dir_all(&target_path)?;
+                self.directories.push((target_path, mode, owner, mtime));
+            }
+            tar::EntryType::Symlink => {
+                let link = match entry.link_name()? {
+                    Some(link) => link.into_owned(),
+                    None => {
+                        log::error!("Symlink {} has no target", relative_path.display());
+                        return Ok(true);
+                    }
+                };
+                log::debug!(
+                    "Restoring symlink {} -> {}",
+                    relative_path.display(),
+                    link.display()
+                );
+                create_parent(&target_path)?;
+                clear_path(&target_path)?;
+                create_symlink(&native_path(&link), &target_path)?;
+            }
+            tar::EntryType::Regular if provenance::is_provenance(&relative_path) => {
+                Provenance::read(entry)?.log();
+                self.restored_snapshots += 1;
+                if self.options.snapshots == Some(self.restored_snapshots) {
+                    return Ok(false);
+                }
+                // the record ends its snapshot, so what follows was captured by the next one
+                self.digests = self.recorded.next().flatten();
+            }
+            tar::EntryType::Regular if manifest::is_embedded_manifest(&relative_path) => {}
+            tar::EntryType::Regular if is_whiteout(&relative_path) => {
+                let removed_path = safe_join(destination, &whited_out_path(&relative_path))?;
+                log::debug!("Removing whited out path {}", removed_path.display());
+                let removed = if removed_path.is_dir() && !removed_path.is_symlink() {
+                    std::fs::remove_dir_all(&removed_path)
+                } else {
+                    std::fs::remove_file(&removed_path)
+                };
+                match removed {
+                    Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err),
+                    _ => {}
+                }
+            }
+            _ if relative_path.file_name() == Some(batch::BATCH_FILE_NAME.as_ref()) => {
+                let directory = target_path.parent().unwrap_or(destination).to_path_buf();
+                let (members, data) = batch::read_batch(entry, dictionary)?;
+                log::debug!(
+                    "Restoring {} batched entries from {}",
+                    members.len(),
+                    relative_path.display()
+                );
+                for member in members {
+                    if !self.wanted(&relative_path.with_file_name(&member.name)) {
+                        continue;
+                    }
+                    let member_path = safe_join(&directory, Path::new(&member.name))?;
+                    create_parent(&member_path)?;
+                    let content = &data[member.data];
+                    if let Some(digests) = &self.digests {
+                        let relative_path = relative_path.with_file_name(&member.name);
+                        if digests
+                            .check(&relative_path, |algorithm| Ok(algorithm.digest(content)))?
+                        {
+                            self.verified += 1;
+                        }
+                    }
+                    clear_path(&member_path)?;
+                    std::fs::write(&member_path, content)?;
+                    apply_metadata(
+                        &member_path,
+                        member.mode,
+                        Some((member.uid, member.gid)),
+                        member.mtime,
+                    )?;
+                    self.restored += 1;
+                }
+            }
+            _ => {
+                log::debug!("Restoring file {}", relative_path.display());
+                create_parent(&target_path)?;
+                clear_path(&target_path)?;
+                let mut writer = BufWriter::new(File::create(&target_path)?);
+                // files captured without their content hold no frame to decompress
+                if entry.size() > 0 {
+                    std::io::copy(&mut dictionary::decoder(entry, dictionary)?, &mut writer)?;
+                }
+                writer.flush()?;
+                if let Some(digests) = &self.digests {
+                    // what landed on disk is what gets checked
+                    let checked = digests.check(&relative_path, |algorithm| {
+                        algorithm.digest_reader(BufReader::new(File::open(&target_path)?))
+                    })?;
+                    if checked {
+                        self.verified += 1;
+                    }
+                }
+                apply_metadata(&target_path, mode, Some(owner), mtime)?;
+                self.restored += 1;
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// Where the content of a file is stored in an archive.
+pub enum StoredContent {
+    /// A compressed frame of its own, at this offset and of this size.
+    Frame(u64, u64),
+    /// Already decompressed out of its batch.
+    Batched(Vec<u8>),
+    /// Nothing, as the file was empty or captured without its content.
+    Empty,
+}
+
+/// Writes the content of one file in a tarball produced by `create_tarball` to the writer, as the
+/// last snapshot holding it left it. Headers are read by seeking past everything else, or not at
+/// all when the archive has an index, so only the copy written and the batches of its directory
+/// are decompressed. The records a snapshot generates, like its snapshot record and embedded
+/// manifest, are written as the plain JSON they're stored as. Returns whether the archive holds the
+/// file.
+pub fn cat_file<P: AsRef<Path>, W: Write>(
+    tarball_path: P,
+    relative_path: &Path,
+    dictionary: Option<&Dictionary>,
+    writer: &mut W,
+) -> std::io::Result<bool> {
+    let tarball_path = tarball_path.as_ref();
+    let mut stored = None;
+    match Index::read_for(tarball_path) {
+        Some(index) => {
+            let mut holding = None;
+            for indexed in &index.entries {
+                match indexed.kind {
+                    IndexedKind::File
+                    | IndexedKind::Directory
+                    | IndexedKind::Symlink
+                    | IndexedKind::Record
+                    | IndexedKind::Manifest
+                        if indexed.path == relative_path =>
+                    {
+                        holding = Some(indexed)
+                    }
+                    IndexedKind::Whiteout
+                        if relative_path.starts_with(whited_out_path(&indexed.path)) =>
+                    {
+                        holding = None
+                    }
+                    IndexedKind::Batch
+                        if indexed.path.parent() == relative_path.parent()
+                            && indexed
+                                .members
+                                .iter()
+                                .any(|name| relative_path.file_name() == Some(name.as_ref())) =>
+                    {
+                        holding = Some(indexed)
+                    }
+                    _ => {}
+                }
+            }
+            if let Some(indexed) = holding {
+                let mut archive = index::open_member(tarball_path, indexed)?;
+                let entry = index::member(&mut archive, indexed)?;
+                stored = stored_content(entry, indexed.offset, relative_path, dictionary)?;
+            }
+        }
+        None => {
+            let mut archive = Archive::new(BufReader::new(File::open(tarball_path)?));
+            for entry in archive.entries_with_seek()? {
+                let entry = entry?;
+                let path = entry.path()?.into_owned();
+                if path == relative_path {
+                    stored = stored_content(entry, 0, relative_path, dictionary)?;
+                } else if is_whiteout(&path) && relative_path.starts_with(whited_out_path(&path)) {
+                    stored = None;
+                } else if path.file_name() == Some(batch::BATCH_FILE_NAME.as_ref())
+                    && path.parent() == relative_path.parent()
+                {
+                    // a batch without the file leaves whichever copy came before
+                    if let Some(batched) = stored_content(entry, 0, relative_path, dictionary)? {
+                        stored = Some(batched);
+                    }
+                }
+            }
+        }
+    }
+
+    match stored {
+        // generated records are stored uncompressed
+        Some(StoredContent::Frame(offset, size)) if is_generated(relative_path) => {
+            let mut file = File::open(tarball_path)?;
+            file.seek(SeekFrom::Start(offset))?;
+            std::io::copy(&mut file.take(size), writer)?;
+        }
+        Some(StoredContent::Frame(offset, size)) => {
+            let mut file = File::open(tarball_path)?;
+            file.seek(SeekFrom::Start(offset))?;
+            std::io::copy(
+                &mut dictionary::decoder(file.take(size), dictionary)?,
+                writer,
+            )?;
+        }
+        Some(StoredContent::Batched(content)) => writer.write_all(&content)?,
+        Some(StoredContent::Empty) => {}
+        None => return Ok(false),
+    }
+    writer.flush()?;
+    Ok(true)
+}
+
+/// Whether the path is one of the records a snapshot writes at the archive root, rather than a
+/// captured file.
+fn is_generated(relative_path: &Path) -> bool {
+    provenance::is_provenance(relative_path) || manifest::is_embedded_manifest(relative_path)
+}
+
+/// Where a member of the archive read from `offset` on stores the content of the file, which is
+/// either the member itself or in the batch it is. Nothing for a directory or symlink, which has no
+/// content, or a batch not holding the file.
+pub fn stored_content<R: Read>(
+    entry: tar::Entry<R>,
+    offset: u64,
+    relative_path: &Path,
+    dictionary: Option<&Dictionary>,
+) -> std::io::Result<Option<StoredContent>> {
+    let path = entry.path()?.into_owned();
+    if path.file_name() == Some(batch::BATCH_FILE_NAME.as_ref()) {
+        let (members, mut data) = batch::read_batch(entry, dictionary)?;
+        return Ok(members
+            .into_iter()
+            .find(|member| relative_path.file_name() == Some(member.name.as_ref()))
+            .map(|member| StoredContent::Batched(data.drain(member.data).collect())));
+    }
+    Ok(match entry.header().entry_type() {
+        tar::EntryType::Regular if entry.size() > 0 => Some(StoredContent::Frame(
+            offset + entry.raw_file_position(),
+            entry.size(),
+        )),
+        tar::EntryType::Regular => Some(StoredContent::Empty),
+        _ => None,
+    })
+}
+
+/// Digests of the files a snapshot captured, from the manifest it recorded in the archive.
+struct CapturedDigests {
+    hash_algorithm: HashAlgorithm,
+    digests: HashMap<PathBuf, String>,
+}
+
+impl CapturedDigests {
+    fn new(manifest: &Manifest) -> Self {
+        Self {
+            hash_algorithm: manifest.hash_algorithm,
+            digests: manifest
+                .entries
+                .iter()
+                .filter_map(|entry| Some((entry.path.clone(), entry.digest.clone()?)))
+                .collect(),
+        }
+    }
+
+    /// Checks a restored file against its captured digest, hashing it with `digest`. Returns
+    /// whether there was a digest to check it against.
+    fn check(
+        &self,
+        relative_path: &Path,
+        digest: impl FnOnce(HashAlgorithm) -> std::io::Result<Digest>,
+    ) -> std::io::Result<bool> {
+        let Some(expected) = self.digests.get(relative_path) else {
+            return Ok(false);
+        };
+        if hex::encode(digest(self.hash_algorithm)?) != *expected {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "Restored file {} doesn't match the digest it was captured with",
+                    relative_path.display()
+                ),
+            ));
+        }
+        Ok(true)
+    }
+}
+
+/// The path a whiteout removes, or the path itself for anything else.
+pub fn whited_out_path(relative_path: &Path) -> PathBuf {
+    if !is_whiteout(relative_path) {
+        return relative_path.to_path_buf();
+    }
+    let name = relative_path.file_name().unwrap().to_str().unwrap();
+    relative_path.with_file_name(&name[WHITEOUT_PREFIX.len()..])
+}
+
+pub fn is_whiteout(relative_path: &Path) -> bool {
+    relative_path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.len() > WHITEOUT_PREFIX.len() && name.starts_with(WHITEOUT_PREFIX))
+}
+
+/// Joins an archive path onto the destination, refusing paths that would escape it.
+pub fn safe_join(destination: &Path, relative_path: &Path) -> std::io::Result<PathBuf> {
+    if relative_path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+    {
+        Ok(destination.join(native_path(relative_path)))
+    } else {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "Archive entry escapes the destination: {}",
+                relative_path.display()
+            ),
+        ))
+    }
+}
+
+/// Rebuilds a path stored in the archive, always with forward slashes, with the platform's own
+/// separators.
+fn native_path(stored_path: &Path) -> PathBuf {
+    stored_path.components().collect()
+}
+
+/// Clears the way for a member replacing what an earlier snapshot restored at the path. Only
+/// files and symlinks are removed, as writing a file through a symlink would write wherever it
+/// points.
+pub fn clear_path(path: &Path) -> std::io::Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if !metadata.is_dir() => std::fs::remove_file(path),
+        _ => Ok(()),
+    }
+}
+
+pub fn create_parent(path: &Path) -> std::io::Result<()> {
+    match path.parent() {
+        Some(parent) => std::fs::create_dir_all(parent),
+        None => Ok(()),
+    }
+}
+
+/// Gives a restored path its permissions and mtime, and its owner too when the archive recorded
+/// one.
+pub fn apply_metadata(
+    path: &Path,
+    mode: u32,
+    owner: Option<(u64, u64)>,
+    mtime: u64,
+) -> std::io::Result<()> {
+    File::open(path)?.set_modified(UNIX_EPOCH + Duration::from_secs(mtime))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        // ownership can only be restored when running privileged, like tar
+        if let Some((uid, gid)) = owner {
+            if let Err(err) = std::os::unix::fs::chown(path, Some(uid as u32), Some(gid as u32)) {
+                log::debug!("Failed to restore ownership of {}: {}", path.display(), err);
+            }
+        }
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode & 0o7777))?;
+    }
+    #[cfg(not(unix))]
+    let _ = (mode, owner);
+    Ok(())
+}
+
+#[cfg(unix)]
+pub fn create_symlink(link: &Path, path: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(link, path)
+}
+
+#[cfg(windows)]
+pub fn create_symlink(link: &Path, path: &Path) -> std::io::Result<()> {
+    std::os::windows::fs::symlink_file(link, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stored_paths_are_restored_with_native_separators() {
+        let native: PathBuf = ["t", "sub", "a"].iter().collect();
+        // compared as strings, since paths compare equal by their components whatever separates them
+        assert_eq!(
+            native_path(Path::new("t/sub/a")).as_os_str(),
+            native.as_os_str()
+        );
+    }
+}
@@ -0,0 +1,568 @@
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::ops::{Bound, Range};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+
+use percent_encoding::{percent_decode_str, percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use tiny_http::{Header, Method, Request, Response, Server, StatusCode};
+
+use crate::dictionary::{self, Dictionary};
+use crate::index::{self, Index, IndexedKind};
+use crate::manifest::Manifest;
+use crate::restorer::{stored_content, whited_out_path, StoredContent};
+
+/// Bytes of a path's names left as they are in links, on top of letters and digits.
+const NAME: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// Options controlling what `serve_tarball` serves, and to whom.
+pub struct ServeOptions<'a> {
+    /// Dictionary the archive's files were compressed with, if they were.
+    pub dictionary: Option<&'a Dictionary>,
+    /// Only serve this many of the snapshots appended to the archive, oldest first, showing the
+    /// target as the last of them captured it.
+    pub snapshots: Option<usize>,
+    /// Token every request has to present as `Authorization: Bearer <token>`, if any.
+    pub token: Option<String>,
+}
+
+/// Serves a tarball produced by `create_tarball` over HTTP at the address, the way
+/// `restore_tarball` would restore it, until a signal stops it. Directories are served as pages
+/// listing them and files as their content, which is only decompressed when it's asked for.
+pub fn serve_tarball<P: AsRef<Path>>(
+    tarball_path: P,
+    address: &str,
+    options: &ServeOptions,
+) -> std::io::Result<()> {
+    let tarball_path = tarball_path.as_ref();
+    let index = match Index::read_for(tarball_path) {
+        Some(index) => index,
+        None => {
+            log::info!(
+                "Reading through {}, as it has no index",
+                tarball_path.display()
+            );
+            Index::build(tarball_path, options.dictionary)?
+        }
+    };
+    let tree = Tree::read(tarball_path, &index, options.snapshots)?;
+    log::info!(
+        "Serving {} entries of {}",
+        tree.entries.len() - 1,
+        tarball_path.display()
+    );
+
+    let listener = Arc::new(Server::http(address).map_err(std::io::Error::other)?);
+    log::info!(
+        "Serving {} at http://{}; interrupt to stop",
+        tarball_path.display(),
+        listener.server_addr()
+    );
+    let stopping = listener.clone();
+    if let Err(err) = ctrlc::set_handler(move || {
+        log::info!("Received a signal; stopping once the requests being answered are done");
+        stopping.unblock();
+    }) {
+        log::warn!(
+            "Failed to handle signals; kill the server to stop it: {}",
+            err
+        );
+    }
+
+    let handler = Handler {
+        tarball_path,
+        index: &index,
+        tree: &tree,
+        options,
+    };
+    // a long download goes on beside the requests after it, and leaving the scope waits for
+    // every answer still being sent
+    std::thread::scope(|scope| {
+        for request in listener.incoming_requests() {
+            let handler = &handler;
+            scope.spawn(move || handler.answer(request));
+        }
+    });
+    log::info!("Stopped serving {}", tarball_path.display());
+    Ok(())
+}
+
+/// A path the archive restores.
+struct Served {
+    kind: ServedKind,
+    /// Size of a file's content, once known. Files recorded in a manifest know it from the start;
+    /// the rest learn it by being decompressed.
+    size: OnceLock<u64>,
+    /// Modification time, for paths recorded in a manifest.
+    mtime: Option<u64>,
+}
+
+enum ServedKind {
+    Directory,
+    /// A file, stored alone or in a batch by the member at this position of the index.
+    File(usize),
+    Symlink(PathBuf),
+}
+
+impl Served {
+    fn new(kind: ServedKind) -> Self {
+        let size = OnceLock::new();
+        if let ServedKind::Symlink(link) = &kind {
+            let _ = size.set(link.as_os_str().len() as u64);
+        }
+        Self {
+            kind,
+            size,
+            mtime: None,
+        }
+    }
+}
+
+/// Every path the archive restores, by its path in the archive, with the root as the empty path.
+struct Tree {
+    entries: BTreeMap<PathBuf, Served>,
+}
+
+impl Tree {
+    /// Replays the snapshots in the index, whiteouts included, into the paths they'd restore.
+    fn read(tarball_path: &Path, index: &Index, snapshots: Option<usize>) -> std::io::Result<Self> {
+        let mut tree = Self {
+            entries: BTreeMap::from([(PathBuf::new(), Served::new(ServedKind::Directory))]),
+        };
+        let mut read_snapshots = 0;
+
+        for (position, indexed) in index.entries.iter().enumerate() {
+            match indexed.kind {
+                IndexedKind::Directory => tree.insert(&indexed.path, ServedKind::Directory),
+                IndexedKind::File => tree.insert(&indexed.path, ServedKind::File(position)),
+                IndexedKind::Symlink => tree.insert(
+                    &indexed.path,
+                    ServedKind::Symlink(indexed.link.clone().unwrap_or_default()),
+                ),
+                IndexedKind::Batch => {
+                    for name in &indexed.members {
+                        tree.insert(
+                            &indexed.path.with_file_name(name),
+                            ServedKind::File(position),
+                        );
+                    }
+                }
+                IndexedKind::Whiteout => {
+                    let removed = whited_out_path(&indexed.path);
+                    tree.entries.retain(|path, _| !path.starts_with(&removed));
+                }
+                IndexedKind::Manifest => {
+                    // the manifest saves decompressing files just to learn their size
+                    let mut archive = index::open_member(tarball_path, indexed)?;
+                    let manifest = Manifest::read_from(index::member(&mut archive, indexed)?)?;
+                    for manifest_entry in manifest.entries {
+                        if let Some(served) = tree.entries.get_mut(&manifest_entry.path) {
+                            if matches!(served.kind, ServedKind::File(_)) {
+                                let _ = served.size.set(manifest_entry.size);
+                            }
+                            served.mtime = Some(manifest_entry.mtime);
+                        }
+                    }
+                }
+                IndexedKind::Record => {
+                    read_snapshots += 1;
+                    if snapshots == Some(read_snapshots) {
+                        break;
+                    }
+                }
+            }
+        }
+        Ok(tree)
+    }
+
+    /// Adds the path over whatever was there and beneath it, creating any missing parents. A
+    /// directory added again is left as it was.
+    fn insert(&mut self, path: &Path, kind: ServedKind) {
+        let Some(path) = served_path(path.components()).filter(|path| !path.as_os_str().is_empty())
+        else {
+            log::warn!("Not serving {}", path.display());
+            return;
+        };
+        for parent in path.ancestors().skip(1) {
+            if !self.is_directory(parent) {
+                self.replace(parent, Served::new(ServedKind::Directory));
+            }
+        }
+        if !(matches!(kind, ServedKind::Directory) && self.is_directory(&path)) {
+            self.replace(&path, Served::new(kind));
+        }
+    }
+
+    fn replace(&mut self, path: &Path, served: Served) {
+        self.entries
+            .retain(|existing, _| existing == path || !existing.starts_with(path));
+        self.entries.insert(path.to_path_buf(), served);
+    }
+
+    fn is_directory(&self, path: &Path) -> bool {
+        matches!(
+            self.entries.get(path).map(|served| &served.kind),
+            Some(ServedKind::Directory)
+        )
+    }
+
+    /// The paths directly beneath a directory, in order.
+    fn children<'a>(
+        &'a self,
+        directory: &'a Path,
+    ) -> impl Iterator<Item = (&'a PathBuf, &'a Served)> + 'a {
+        self.entries
+            .range::<Path, _>((Bound::Excluded(directory), Bound::Unbounded))
+            .take_while(move |(path, _)| path.starts_with(directory))
+            .filter(move |(path, _)| path.parent() == Some(directory))
+    }
+}
+
+/// The path named by the components, or nothing for components which could name something
+/// outside the archive.
+fn served_path<'a>(components: impl Iterator<Item = Component<'a>>) -> Option<PathBuf> {
+    let mut path = PathBuf::new();
+    for component in components {
+        match component {
+            Component::Normal(name) => path.push(name),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    Some(path)
+}
+
+/// The path in the archive a request's URL names, or nothing for a URL which doesn't name one.
+/// Names are only ever taken as a whole, so nothing a URL holds reaches past the archive's root.
+fn requested_path(url: &str) -> Option<PathBuf> {
+    let url = url.split(['?', '#']).next()?.strip_prefix('/')?;
+    let mut path = PathBuf::new();
+    for name in url.split('/') {
+        let name: Vec<u8> = percent_decode_str(name).collect();
+        match name.as_slice() {
+            b"" => {}
+            b"." | b".." => return None,
+            name if name.contains(&b'/') || name.contains(&0) => return None,
+            name => path.push(OsStr::from_bytes(name)),
+        }
+    }
+    Some(path)
+}
+
+/// The path in the archive a symlink at `path` points to, or nothing for one pointing outside it.
+fn resolved_link(path: &Path, link: &Path) -> Option<PathBuf> {
+    let mut resolved = path.parent()?.to_path_buf();
+    for component in link.components() {
+        match component {
+            Component::Normal(name) => resolved.push(name),
+            Component::CurDir => {}
+            Component::ParentDir if resolved.pop() => {}
+            _ => return None,
+        }
+    }
+    Some(resolved)
+}
+
+/// The URL a path in the archive is served at.
+fn url_of(path: &Path) -> String {
+    let mut url = String::from("/");
+    for (i, name) in path.iter().enumerate() {
+        if i > 0 {
+            url.push('/');
+        }
+        url.extend(percent_encode(name.as_bytes(), NAME));
+    }
+    url
+}
+
+/// The part of a file a `Range` header asks for.
+enum RequestedRange {
+    Whole,
+    Part(Range<u64>),
+    /// None of the file, as the range starts past its end.
+    Unsatisfiable,
+}
+
+/// Reads a `Range` header for a file of `size` bytes. Anything but a single range of bytes is
+/// ignored, which sends the whole file.
+fn requested_range(header: &str, size: u64) -> RequestedRange {
+    let Some((start, end)) = header
+        .trim()
+        .strip_prefix("bytes=")
+        .filter(|ranges| !ranges.contains(','))
+        .and_then(|range| range.trim().split_once('-'))
+    else {
+        return RequestedRange::Whole;
+    };
+    match (start.parse::<u64>(), end.parse::<u64>()) {
+        // the last `end` bytes
+        (Err(_), Ok(suffix)) if start.is_empty() => match suffix.min(size) {
+            0 => RequestedRange::Unsatisfiable,
+            suffix => RequestedRange::Part(size - suffix..size),
+        },
+        (Ok(start), _) if start >= size => RequestedRange::Unsatisfiable,
+        (Ok(start), Err(_)) if end.is_empty() => RequestedRange::Part(start..size),
+        (Ok(start), Ok(end)) if end >= start => {
+            // an end past the file, up to the largest there is, just means the rest of it
+            RequestedRange::Part(start..size.min(end.saturating_add(1)))
+        }
+        _ => RequestedRange::Whole,
+    }
+}
+
+/// Answers requests for what the tree serves.
+struct Handler<'a> {
+    tarball_path: &'a Path,
+    index: &'a Index,
+    tree: &'a Tree,
+    options: &'a ServeOptions<'a>,
+}
+
+impl<'a> Handler<'a> {
+    fn answer(&self, request: Request) {
+        let method = request.method().clone();
+        let url = request.url().to_string();
+        match self.respond(request) {
+            Ok(status) => log::info!("{} {} {}", method, url, status),
+            Err(err) => log::error!("Failed to answer {} {}: {}", method, url, err),
+        }
+    }
+
+    /// Responds to the request, returning the status it was answered with.
+    fn respond(&self, request: Request) -> std::io::Result<u16> {
+        if !self.authorized(&request) {
+            let response = error_page(
+                401,
+                "This archive is only served to requests with its token",
+            )
+            .with_header(header("WWW-Authenticate", "Bearer"));
+            return reply(request, response);
+        }
+        if !matches!(request.method(), Method::Get | Method::Head) {
+            let response = error_page(405, "Only GET and HEAD requests are served")
+                .with_header(header("Allow", "GET, HEAD"));
+            return reply(request, response);
+        }
+        let Some(path) = requested_path(request.url()) else {
+            return reply(request, error_page(400, "Not a path in the archive"));
+        };
+        let Some(served) = self.tree.entries.get(&path) else {
+            return reply(request, error_page(404, "The archive holds no such path"));
+        };
+        match &served.kind {
+            ServedKind::Directory => {
+                let response = Response::from_string(self.listing(&path))
+                    .with_header(header("Content-Type", "text/html; charset=utf-8"));
+                reply(request, response)
+            }
+            ServedKind::Symlink(link) => match resolved_link(&path, link)
+                .filter(|target| self.tree.entries.contains_key(target))
+            {
+                Some(target) => {
+                    let response =
+                        Response::empty(302).with_header(header("Location", &url_of(&target)));
+                    reply(request, response)
+                }
+                None => reply(
+                    request,
+                    error_page(404, "The symlink points to nothing in the archive"),
+                ),
+            },
+            ServedKind::File(position) => self.send_file(request, &path, served, *position),
+        }
+    }
+
+    /// Whether the request presents the token, if there is one. Every byte is compared whatever
+    /// the token holds, so the time taken gives none of it away.
+    fn authorized(&self, request: &Request) -> bool {
+        let Some(token) = &self.options.token else {
+            return true;
+        };
+        let presented = request_header(request, "Authorization")
+            .and_then(|authorization| authorization.strip_prefix("Bearer "))
+            .unwrap_or_default();
+        presented.len() == token.len()
+            && presented
+                .bytes()
+                .zip(token.bytes())
+                .fold(0, |differs, (a, b)| differs | (a ^ b))
+                == 0
+    }
+
+    /// Sends the content of a file, or the part of it a `Range` header asks for.
+    fn send_file(
+        &self,
+        request: Request,
+        path: &Path,
+        served: &Served,
+        position: usize,
+    ) -> std::io::Result<u16> {
+        let mut headers = vec![
+            header("Content-Type", "application/octet-stream"),
+            header("Accept-Ranges", "bytes"),
+        ];
+        let requested = match request_header(&request, "Range") {
+            Some(range) => requested_range(range, self.size(path, served, position)?),
+            None => RequestedRange::Whole,
+        };
+        let (status, start, length) = match requested {
+            RequestedRange::Whole => (200, 0, served.size.get().copied()),
+            RequestedRange::Part(range) => {
+                let size = self.size(path, served, position)?;
+                headers.push(header(
+                    "Content-Range",
+                    &format!("bytes {}-{}/{}", range.start, range.end - 1, size),
+                ));
+                (206, range.start, Some(range.end - range.start))
+            }
+            RequestedRange::Unsatisfiable => {
+                let size = self.size(path, served, position)?;
+                let response = error_page(416, "The range asked for starts past the end")
+                    .with_header(header("Content-Range", &format!("bytes */{}", size)));
+                return reply(request, response);
+            }
+        };
+
+        let mut content = self.content(path, position)?;
+        // a frame can only be decompressed from its start
+        std::io::copy(&mut (&mut content).take(start), &mut std::io::sink())?;
+        let response = Response::new(
+            StatusCode(status),
+            headers,
+            content.take(length.unwrap_or(u64::MAX)),
+            length.map(|length| length as usize),
+            None,
+        )
+        // a length known up front is sent, however long, so downloads can show their progress
+        .with_chunked_threshold(usize::MAX);
+        reply(request, response)
+    }
+
+    /// The content of a file, decompressed as it's read.
+    fn content(&self, path: &Path, position: usize) -> std::io::Result<Box<dyn Read + 'a>> {
+        let indexed = &self.index.entries[position];
+        let mut archive = index::open_member(self.tarball_path, indexed)?;
+        let stored = stored_content(
+            index::member(&mut archive, indexed)?,
+            indexed.offset,
+            path,
+            self.options.dictionary,
+        )?;
+        Ok(match stored {
+            Some(StoredContent::Frame(offset, size)) => {
+                let mut file = File::open(self.tarball_path)?;
+                file.seek(SeekFrom::Start(offset))?;
+                Box::new(dictionary::decoder(
+                    file.take(size),
+                    self.options.dictionary,
+                )?)
+            }
+            Some(StoredContent::Batched(content)) => Box::new(std::io::Cursor::new(content)),
+            Some(StoredContent::Empty) => Box::new(std::io::empty()),
+            None => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("{} doesn't hold {}", indexed.path.display(), path.display()),
+                ))
+            }
+        })
+    }
+
+    /// The size of a file's content, decompressing it if nothing recorded it.
+    fn size(&self, path: &Path, served: &Served, position: usize) -> std::io::Result<u64> {
+        if let Some(size) = served.size.get() {
+            return Ok(*size);
+        }
+        let size = std::io::copy(&mut self.content(path, position)?, &mut std::io::sink())?;
+        Ok(*served.size.get_or_init(|| size))
+    }
+
+    /// A page listing a directory, with links to everything in it.
+    fn listing(&self, directory: &Path) -> String {
+        let title = escape_html(&format!("/{}", directory.display()));
+        let mut page = format!(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{title}</title>\n\
+             </head>\n<body>\n<h1>{title}</h1>\n<p>{}</p>\n<table>\n\
+             <tr><th>Name</th><th>Size</th><th>Modified</th></tr>\n",
+            escape_html(&self.tarball_path.display().to_string()),
+        );
+        if let Some(parent) = directory.parent() {
+            page.push_str(&format!(
+                "<tr><td><a href=\"{}\">../</a></td><td></td><td></td></tr>\n",
+                url_of(parent)
+            ));
+        }
+        for (path, served) in self.tree.children(directory) {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            let label = match &served.kind {
+                ServedKind::Directory => format!("{}/", name),
+                ServedKind::File(_) => name.to_string(),
+                ServedKind::Symlink(link) => format!("{} -> {}", name, link.display()),
+            };
+            let size = match served.kind {
+                ServedKind::File(_) => served.size.get().map(u64::to_string),
+                _ => None,
+            };
+            let modified = served
+                .mtime
+                .and_then(|mtime| chrono::DateTime::from_timestamp(mtime as i64, 0))
+                .map(|mtime| mtime.format("%Y-%m-%d %H:%M:%S UTC").to_string());
+            page.push_str(&format!(
+                "<tr><td><a href=\"{}\">{}</a></td><td>{}</td><td>{}</td></tr>\n",
+                url_of(path),
+                escape_html(&label),
+                size.unwrap_or_default(),
+                modified.unwrap_or_default()
+            ));
+        }
+        page.push_str("</table>\n</body>\n</html>\n");
+        page
+    }
+}
+
+fn request_header<'r>(request: &'r Request, field: &'static str) -> Option<&'r str> {
+    request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv(field))
+        .map(|header| header.value.as_str())
+}
+
+fn header(field: &str, value: &str) -> Header {
+    Header::from_bytes(field, value).expect("headers are ASCII")
+}
+
+fn error_page(status: u16, message: &str) -> Response<std::io::Cursor<Vec<u8>>> {
+    Response::from_string(format!("{}\n", message))
+        .with_status_code(status)
+        .with_header(header("Content-Type", "text/plain; charset=utf-8"))
+}
+
+/// Sends the response, returning the status it was sent with.
+fn reply<R: Read>(request: Request, response: Response<R>) -> std::io::Result<u16> {
+    let status = response.status_code().0;
+    request.respond(response)?;
+    Ok(status)
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,1022 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, Metadata};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::archiver::{self, link_count, path_bytes, ArchiveSink, ArchivedEntry, TarOptions};
+use crate::detector::file_identity;
+use crate::dictionary;
+use crate::manifest::Manifest;
+use crate::processor::{Digest, Entry};
+use crate::provenance::Provenance;
+use crate::report::SnapshotReport;
+
+/// Magic number starting the superblock, "hsqs" on disk.
+const MAGIC: u32 = 0x7371_7368;
+
+/// Size of the blocks file content is compressed in, the same default as `mksquashfs`.
+const BLOCK_SIZE: usize = 128 * 1024;
+const BLOCK_LOG: u16 = 17;
+
+/// Bytes of inodes, directory listings or ids held by each metadata block before compression.
+const METADATA_BLOCK_SIZE: usize = 8192;
+
+/// Size of the superblock ahead of everything else.
+const SUPERBLOCK_SIZE: u64 = 96;
+
+/// Images are padded to a multiple of this many bytes, so they fill whole device blocks.
+const PADDING: u64 = 4096;
+
+const ZSTD_COMPRESSION: u16 = 6;
+
+/// Superblock flags: files are stored in whole blocks, without packing the ends of files
+/// together as fragments, and nothing has extended attributes.
+const NO_FRAGMENTS: u16 = 0x0010;
+const NO_XATTRS: u16 = 0x0200;
+
+/// Marks a metadata block stored as it is, as compressing it didn't make it smaller.
+const UNCOMPRESSED_METADATA: u16 = 0x8000;
+/// Marks a data block stored as it is, as compressing it didn't make it smaller.
+const UNCOMPRESSED_BLOCK: u32 = 1 << 24;
+
+/// Where an absent table would start, and the fragment and xattr index of an inode without them.
+const NONE: u64 = u64::MAX;
+const NO_FRAGMENT: u32 = u32::MAX;
+const NO_XATTR: u32 = u32::MAX;
+
+const BASIC_DIRECTORY: u16 = 1;
+const BASIC_FILE: u16 = 2;
+const BASIC_SYMLINK: u16 = 3;
+const EXTENDED_DIRECTORY: u16 = 8;
+const EXTENDED_FILE: u16 = 9;
+
+/// Most entries one header of a directory listing covers.
+const LISTING_RUN: usize = 256;
+
+/// Whether the path names a SquashFS image, by `.squashfs` or `.sqfs`.
+pub fn is_squashfs(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension == "squashfs" || extension == "sqfs")
+}
+
+/// Writes the captured entries into a SquashFS image, with every block compressed by zstd at the
+/// snapshot's compression level, the same way `create_tarball` writes a tarball: beside its final
+/// path, only renamed into place once it's complete and synced. The image's root is the target.
+pub fn create_squashfs<P1: AsRef<Path>, P2: AsRef<Path>>(
+    origin: P1,
+    entries: impl IntoIterator<Item = Entry>,
+    image_path: P2,
+    options: &TarOptions,
+    report: &mut SnapshotReport,
+) -> std::io::Result<(Vec<ArchivedEntry>, Manifest)> {
+    let origin = origin.as_ref();
+    let image_path = image_path.as_ref();
+    log::info!("Creating SquashFS image at {}", image_path.display());
+    let mut temp_name = image_path.as_os_str().to_owned();
+    temp_name.push(format!(".tmp-{}", std::process::id()));
+    let temp_path = PathBuf::from(temp_name);
+
+    let written = archiver::create_file(&temp_path, options.output_mode)
+        .and_then(|file| {
+            let sink = SquashfsSink::new(BufWriter::new(file), origin, options)?;
+            let written = archiver::write_archive(origin, entries, sink, options, None, report)?;
+            let file = written
+                .output
+                .into_inner()
+                .map_err(|err| err.into_error())?;
+            file.sync_all()?;
+            report.bytes_written = file.metadata()?.len();
+            Ok((written.archived, written.manifest))
+        })
+        .and_then(|written| {
+            std::fs::rename(&temp_path, image_path)?;
+            archiver::sync_parent(image_path)?;
+            Ok(written)
+        });
+    if written.is_err() {
+        if let Err(err) = std::fs::remove_file(&temp_path) {
+            log::debug!("Failed to remove {}: {}", temp_path.display(), err);
+        }
+    }
+    written
+}
+
+/// What every inode records about its entry.
+#[derive(Clone, Copy)]
+struct Attributes {
+    permissions: u16,
+    uid: u32,
+    gid: u32,
+    mtime: u32,
+}
+
+enum Node {
+    /// Children by name, which listings are sorted by.
+    Directory {
+        attributes: Attributes,
+        children: BTreeMap<Vec<u8>, usize>,
+    },
+    /// Content already written out as blocks starting at `blocks_start`.
+    File {
+        attributes: Attributes,
+        blocks_start: u64,
+        size: u64,
+        block_sizes: Vec<u32>,
+    },
+    Symlink {
+        attributes: Attributes,
+        target: Vec<u8>,
+    },
+}
+
+impl Node {
+    /// The type directory listings give the node, which is that of its basic inode.
+    fn listed_type(&self) -> u16 {
+        match self {
+            Node::Directory { .. } => BASIC_DIRECTORY,
+            Node::File { .. } => BASIC_FILE,
+            Node::Symlink { .. } => BASIC_SYMLINK,
+        }
+    }
+}
+
+/// Writes entries into a SquashFS image. File content is decompressed and written out in blocks
+/// as it arrives, while the tree is kept until the image is finished, when the inodes and
+/// directory listings are written after the content, deepest first, and the superblock in front.
+/// Hard linked files are one inode listed under each of their names.
+pub struct SquashfsSink<W: Write + Seek> {
+    writer: W,
+    options: TarOptions,
+    /// Leading directory of every path in the image, standing for the target itself.
+    root: PathBuf,
+    /// Bytes written so far, which is where the next block starts.
+    written: u64,
+    compressor: zstd::bulk::Compressor<'static>,
+    /// Every node of the tree, the root first.
+    nodes: Vec<Node>,
+    /// Nodes of files, by the identity of the file on disk, for their hard links to share.
+    linked: HashMap<(u64, u64), usize>,
+    /// When the image was made, which directories it had to make up are dated to.
+    made: u32,
+}
+
+impl<W: Write + Seek> SquashfsSink<W> {
+    pub fn new(mut writer: W, origin: &Path, options: &TarOptions) -> std::io::Result<Self> {
+        // the superblock is written over this once everything it points to is
+        writer.write_all(&[0; SUPERBLOCK_SIZE as usize])?;
+        let made = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs().min(u64::from(u32::MAX)) as u32);
+        Ok(Self {
+            writer,
+            options: options.clone(),
+            root: origin.file_name().map(PathBuf::from).unwrap_or_default(),
+            written: SUPERBLOCK_SIZE,
+            compressor: zstd::bulk::Compressor::new(options.compression_level)?,
+            nodes: vec![Node::Directory {
+                attributes: made_up_directory(made),
+                children: BTreeMap::new(),
+            }],
+            linked: HashMap::new(),
+            made,
+        })
+    }
+
+    /// The names leading to a path in the image, relative to the target.
+    fn components(&self, relative_path: &Path) -> Vec<Vec<u8>> {
+        relative_path
+            .strip_prefix(&self.root)
+            .unwrap_or(relative_path)
+            .components()
+            .filter_map(|component| match component {
+                Component::Normal(name) => Some(path_bytes(Path::new(name)).into_owned()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The directory a path goes in, along with its name there, making up any directory on the
+    /// way which wasn't added itself. Nothing for the target itself.
+    fn parent_of(&mut self, relative_path: &Path) -> std::io::Result<Option<(usize, Vec<u8>)>> {
+        let mut components = self.components(relative_path);
+        let Some(name) = components.pop() else {
+            return Ok(None);
+        };
+        let mut parent = 0;
+        for component in components {
+            let next = self.nodes.len();
+            let made = self.made;
+            let Node::Directory { children, .. } = &mut self.nodes[parent] else {
+                return Err(not_a_directory(relative_path));
+            };
+            parent = *children.entry(component).or_insert(next);
+            if parent == next {
+                self.nodes.push(Node::Directory {
+                    attributes: made_up_directory(made),
+                    children: BTreeMap::new(),
+                });
+            }
+        }
+        match &self.nodes[parent] {
+            Node::Directory { .. } => Ok(Some((parent, name))),
+            _ => Err(not_a_directory(relative_path)),
+        }
+    }
+
+    fn insert(&mut self, relative_path: &Path, node: Node) -> std::io::Result<usize> {
+        // made up directories on the way take their places first
+        let (parent, name) = self.slot(relative_path)?;
+        let index = self.nodes.len();
+        self.nodes.push(node);
+        self.list(parent, name, index);
+        Ok(index)
+    }
+
+    /// Lists a node already in the tree under another path too.
+    fn link(&mut self, relative_path: &Path, index: usize) -> std::io::Result<()> {
+        let (parent, name) = self.slot(relative_path)?;
+        self.list(parent, name, index);
+        Ok(())
+    }
+
+    /// The directory a path other than the target goes in, along with its name there.
+    fn slot(&mut self, relative_path: &Path) -> std::io::Result<(usize, Vec<u8>)> {
+        self.parent_of(relative_path)?.ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "The root of a SquashFS image can only be a directory",
+            )
+        })
+    }
+
+    fn list(&mut self, parent: usize, name: Vec<u8>, index: usize) {
+        if let Node::Directory { children, .. } = &mut self.nodes[parent] {
+            children.insert(name, index);
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        self.writer.write_all(bytes)?;
+        self.written += bytes.len() as u64;
+        Ok(())
+    }
+
+    /// Writes content out in compressed blocks, returning how large it was and how large each of
+    /// its blocks came out.
+    fn write_blocks(&mut self, content: &mut dyn Read) -> std::io::Result<(u64, Vec<u32>)> {
+        let mut size = 0;
+        let mut block_sizes = Vec::new();
+        let mut block = Vec::with_capacity(BLOCK_SIZE);
+        loop {
+            block.clear();
+            content.take(BLOCK_SIZE as u64).read_to_end(&mut block)?;
+            if block.is_empty() {
+                break;
+            }
+            size += block.len() as u64;
+            let compressed = self.compressor.compress(&block)?;
+            if compressed.len() < block.len() {
+                self.write(&compressed)?;
+                block_sizes.push(compressed.len() as u32);
+            } else {
+                self.write(&block)?;
+                block_sizes.push(block.len() as u32 | UNCOMPRESSED_BLOCK);
+            }
+            if block.len() < BLOCK_SIZE {
+                break;
+            }
+        }
+        Ok((size, block_sizes))
+    }
+}
+
+impl<W: Write + Seek> ArchiveSink for SquashfsSink<W> {
+    type Output = W;
+    type Error = std::io::Error;
+
+    fn add_file(
+        &mut self,
+        metadata: &Metadata,
+        relative_path: &Path,
+        size: u64,
+        _digest: Option<&Digest>,
+        encoded_size: u64,
+        encoded: &mut dyn Read,
+    ) -> std::io::Result<()> {
+        let identity = file_identity(metadata).filter(|_| link_count(metadata) > 1);
+        if let Some(&linked) = identity.and_then(|identity| self.linked.get(&identity)) {
+            log::debug!("New hard link {} in the image", relative_path.display());
+            std::io::copy(encoded, &mut std::io::sink())?;
+            return self.link(relative_path, linked);
+        }
+
+        let blocks_start = self.written;
+        // a file captured without its content has none to store
+        let (written, block_sizes) = if encoded_size == 0 {
+            (0, Vec::new())
+        } else {
+            // the decoder borrows the dictionary for as long as the content is written out
+            let dictionary = self.options.dictionary.clone();
+            let mut decoder = dictionary::decoder(&mut *encoded, dictionary.as_ref())?;
+            self.write_blocks(&mut decoder)?
+        };
+        std::io::copy(encoded, &mut std::io::sink())?;
+        if encoded_size > 0 && written != size {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "Wrote {} bytes for {} but it was captured with {}",
+                    written,
+                    relative_path.display(),
+                    size
+                ),
+            ));
+        }
+        log::debug!(
+            "New file {} with size {} in the image",
+            relative_path.display(),
+            written
+        );
+        let index = self.insert(
+            relative_path,
+            Node::File {
+                attributes: attributes(metadata, self.options.file_mode),
+                blocks_start,
+                size: written,
+                block_sizes,
+            },
+        )?;
+        if let Some(identity) = identity {
+            self.linked.insert(identity, index);
+        }
+        Ok(())
+    }
+
+    fn add_dir(&mut self, metadata: &Metadata, relative_path: &Path) -> std::io::Result<()> {
+        let own = attributes(metadata, self.options.dir_mode);
+        // the directory may have been made up already for something added beneath it
+        let existing = match self.parent_of(relative_path)? {
+            None => Some(0),
+            Some((parent, name)) => match &self.nodes[parent] {
+                Node::Directory { children, .. } => children.get(&name).copied(),
+                _ => None,
+            },
+        };
+        match existing.map(|index| &mut self.nodes[index]) {
+            Some(Node::Directory { attributes, .. }) => {
+                *attributes = own;
+                Ok(())
+            }
+            _ => {
+                self.insert(
+                    relative_path,
+                    Node::Directory {
+                        attributes: own,
+                        children: BTreeMap::new(),
+                    },
+                )?;
+                Ok(())
+            }
+        }
+    }
+
+    fn add_symlink(
+        &mut self,
+        metadata: &Metadata,
+        relative_path: &Path,
+        target: &Path,
+    ) -> std::io::Result<()> {
+        let node = Node::Symlink {
+            attributes: attributes(metadata, Some(0o777)),
+            target: path_bytes(target).into_owned(),
+        };
+        self.insert(relative_path, node)?;
+        Ok(())
+    }
+
+    fn add_whiteout(&mut self, relative_path: &Path) -> std::io::Result<()> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            format!(
+                "SquashFS images can't record the removal of {}",
+                relative_path.display()
+            ),
+        ))
+    }
+
+    /// SquashFS images only hold the target, to be mounted as it was, so the records describing
+    /// the snapshot are left out of them.
+    fn add_manifest(&mut self, _manifest: &Manifest) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn add_provenance(&mut self, _provenance: &Provenance) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    /// Writes the inode, directory and id tables after the content, then the superblock pointing
+    /// to them, handing back the flushed writer.
+    fn finish(mut self) -> std::io::Result<W> {
+        let level = self.options.compression_level;
+        let mut tables = Tables {
+            inodes: MetadataWriter::new(level),
+            listings: MetadataWriter::new(level),
+            ids: Vec::new(),
+            references: vec![None; self.nodes.len()],
+            numbers: vec![0; self.nodes.len()],
+            links: vec![0; self.nodes.len()],
+        };
+        let mut inode_count = 0;
+        number(&self.nodes, 0, &mut tables, &mut inode_count);
+        let root_reference = tables.write_inode(&self.nodes, 0, inode_count + 1)?;
+
+        let inode_table_start = self.written;
+        let inodes = tables.inodes.finish()?;
+        self.write(&inodes)?;
+        let directory_table_start = self.written;
+        let listings = tables.listings.finish()?;
+        self.write(&listings)?;
+
+        // ids are looked up through the locations of the metadata blocks holding them
+        let id_count = u16::try_from(tables.ids.len()).map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "A SquashFS image can't hold more than 65535 owners",
+            )
+        })?;
+        let mut ids = MetadataWriter::new(level);
+        for id in &tables.ids {
+            ids.write(&id.to_le_bytes())?;
+        }
+        let id_blocks_start = self.written;
+        let (id_blocks, id_block_starts) = ids.finish_with_blocks()?;
+        self.write(&id_blocks)?;
+        let id_table_start = self.written;
+        for start in id_block_starts {
+            self.write(&(id_blocks_start + start).to_le_bytes())?;
+        }
+        let bytes_used = self.written;
+        let padding = bytes_used.next_multiple_of(PADDING) - bytes_used;
+        self.write(&vec![0; padding as usize])?;
+
+        let mut superblock = Vec::with_capacity(SUPERBLOCK_SIZE as usize);
+        superblock.extend_from_slice(&MAGIC.to_le_bytes());
+        superblock.extend_from_slice(&inode_count.to_le_bytes());
+        superblock.extend_from_slice(&self.made.to_le_bytes());
+        superblock.extend_from_slice(&(BLOCK_SIZE as u32).to_le_bytes());
+        // fragment count
+        superblock.extend_from_slice(&0u32.to_le_bytes());
+        superblock.extend_from_slice(&ZSTD_COMPRESSION.to_le_bytes());
+        superblock.extend_from_slice(&BLOCK_LOG.to_le_bytes());
+        superblock.extend_from_slice(&(NO_FRAGMENTS | NO_XATTRS).to_le_bytes());
+        superblock.extend_from_slice(&id_count.to_le_bytes());
+        // version 4.0
+        superblock.extend_from_slice(&4u16.to_le_bytes());
+        superblock.extend_from_slice(&0u16.to_le_bytes());
+        superblock.extend_from_slice(&root_reference.to_le_bytes());
+        superblock.extend_from_slice(&bytes_used.to_le_bytes());
+        superblock.extend_from_slice(&id_table_start.to_le_bytes());
+        // xattr id table
+        superblock.extend_from_slice(&NONE.to_le_bytes());
+        superblock.extend_from_slice(&inode_table_start.to_le_bytes());
+        superblock.extend_from_slice(&directory_table_start.to_le_bytes());
+        // fragment and export tables
+        superblock.extend_from_slice(&NONE.to_le_bytes());
+        superblock.extend_from_slice(&NONE.to_le_bytes());
+        self.writer.seek(SeekFrom::Start(0))?;
+        self.writer.write_all(&superblock)?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// Numbers the inodes beneath a directory deepest first, the way `mksquashfs` does, leaving the
+/// root the last number. Hard linked files are numbered once, however many names they have, and
+/// every name is counted as a link.
+fn number(nodes: &[Node], index: usize, tables: &mut Tables, count: &mut u32) {
+    if let Node::Directory { children, .. } = &nodes[index] {
+        for &child in children.values() {
+            tables.links[child] += 1;
+            if tables.numbers[child] == 0 {
+                number(nodes, child, tables, count);
+            }
+        }
+    }
+    *count += 1;
+    tables.numbers[index] = *count;
+}
+
+/// The tables written after the content, along with what's known about the inodes written so far.
+struct Tables {
+    inodes: MetadataWriter,
+    listings: MetadataWriter,
+    /// Every uid and gid, which inodes refer to by their index here.
+    ids: Vec<u32>,
+    /// Where each node's inode was written, once it was.
+    references: Vec<Option<u64>>,
+    numbers: Vec<u32>,
+    /// Names each node is listed under.
+    links: Vec<u32>,
+}
+
+impl Tables {
+    fn id(&mut self, id: u32) -> u16 {
+        match self.ids.iter().position(|known| *known == id) {
+            Some(index) => index as u16,
+            None => {
+                self.ids.push(id);
+                (self.ids.len() - 1) as u16
+            }
+        }
+    }
+
+    /// Writes a node's inode, after those of everything beneath it, returning where it was
+    /// written.
+    fn write_inode(&mut self, nodes: &[Node], index: usize, parent: u32) -> std::io::Result<u64> {
+        let number = self.numbers[index];
+        let mut inode = Vec::new();
+        let (kind, attributes) = match &nodes[index] {
+            Node::Directory {
+                attributes,
+                children,
+            } => {
+                let mut listed = Vec::with_capacity(children.len());
+                for (name, &child) in children {
+                    let reference = match self.references[child] {
+                        Some(reference) => reference,
+                        None => self.write_inode(nodes, child, number)?,
+                    };
+                    listed.push(Listed {
+                        name,
+                        reference,
+                        number: self.numbers[child],
+                        kind: nodes[child].listed_type(),
+                    });
+                }
+                let (block, offset) = self.listings.position();
+                let listing = listing(&listed);
+                self.listings.write(&listing)?;
+                // "." and ".." are left out of listings, but still counted in their size
+                let size = listing.len() + 3;
+                let subdirectories = listed
+                    .iter()
+                    .filter(|listed| listed.kind == BASIC_DIRECTORY)
+                    .count() as u32;
+                let links = 2 + subdirectories;
+                match u16::try_from(size) {
+                    Ok(size) => {
+                        inode.extend_from_slice(&(block as u32).to_le_bytes());
+                        inode.extend_from_slice(&links.to_le_bytes());
+                        inode.extend_from_slice(&size.to_le_bytes());
+                        inode.extend_from_slice(&offset.to_le_bytes());
+                        inode.extend_from_slice(&parent.to_le_bytes());
+                        (BASIC_DIRECTORY, attributes)
+                    }
+                    Err(_) => {
+                        inode.extend_from_slice(&links.to_le_bytes());
+                        inode.extend_from_slice(&(size as u32).to_le_bytes());
+                        inode.extend_from_slice(&(block as u32).to_le_bytes());
+                        inode.extend_from_slice(&parent.to_le_bytes());
+                        // no index to search the listing by
+                        inode.extend_from_slice(&0u16.to_le_bytes());
+                        inode.extend_from_slice(&offset.to_le_bytes());
+                        inode.extend_from_slice(&NO_XATTR.to_le_bytes());
+                        (EXTENDED_DIRECTORY, attributes)
+                    }
+                }
+            }
+            Node::File {
+                attributes,
+                blocks_start,
+                size,
+                block_sizes,
+            } => {
+                let links = self.links[index];
+                let basic = links == 1
+                    && u32::try_from(*blocks_start).is_ok()
+                    && u32::try_from(*size).is_ok();
+                let kind = if basic {
+                    inode.extend_from_slice(&(*blocks_start as u32).to_le_bytes());
+                    inode.extend_from_slice(&NO_FRAGMENT.to_le_bytes());
+                    inode.extend_from_slice(&0u32.to_le_bytes());
+                    inode.extend_from_slice(&(*size as u32).to_le_bytes());
+                    BASIC_FILE
+                } else {
+                    inode.extend_from_slice(&blocks_start.to_le_bytes());
+                    inode.extend_from_slice(&size.to_le_bytes());
+                    // no sparse blocks
+                    inode.extend_from_slice(&0u64.to_le_bytes());
+                    inode.extend_from_slice(&links.to_le_bytes());
+                    inode.extend_from_slice(&NO_FRAGMENT.to_le_bytes());
+                    inode.extend_from_slice(&0u32.to_le_bytes());
+                    inode.extend_from_slice(&NO_XATTR.to_le_bytes());
+                    EXTENDED_FILE
+                };
+                for block_size in block_sizes {
+                    inode.extend_from_slice(&block_size.to_le_bytes());
+                }
+                (kind, attributes)
+            }
+            Node::Symlink { attributes, target } => {
+                inode.extend_from_slice(&self.links[index].to_le_bytes());
+                inode.extend_from_slice(&(target.len() as u32).to_le_bytes());
+                inode.extend_from_slice(target);
+                (BASIC_SYMLINK, attributes)
+            }
+        };
+
+        let mut header = Vec::with_capacity(16 + inode.len());
+        header.extend_from_slice(&kind.to_le_bytes());
+        header.extend_from_slice(&attributes.permissions.to_le_bytes());
+        let uid = self.id(attributes.uid);
+        let gid = self.id(attributes.gid);
+        header.extend_from_slice(&uid.to_le_bytes());
+        header.extend_from_slice(&gid.to_le_bytes());
+        header.extend_from_slice(&attributes.mtime.to_le_bytes());
+        header.extend_from_slice(&number.to_le_bytes());
+        header.extend_from_slice(&inode);
+
+        let (block, offset) = self.inodes.position();
+        let reference = (block << 16) | u64::from(offset);
+        self.inodes.write(&header)?;
+        self.references[index] = Some(reference);
+        Ok(reference)
+    }
+}
+
+/// An entry of a directory listing.
+struct Listed<'a> {
+    name: &'a [u8],
+    /// Where the entry's inode was written.
+    reference: u64,
+    number: u32,
+    kind: u16,
+}
+
+/// A directory's listing, in runs of entries whose inodes lie in the same metadata block, each
+/// under a header giving that block and the inode number the entries' own are relative to.
+fn listing(listed: &[Listed]) -> Vec<u8> {
+    let mut runs = Vec::new();
+    let mut start = 0;
+    for (position, entry) in listed.iter().enumerate() {
+        let first = &listed[start];
+        if position - start == LISTING_RUN
+            || entry.reference >> 16 != first.reference >> 16
+            || i16::try_from(i64::from(entry.number) - i64::from(first.number)).is_err()
+        {
+            runs.push(&listed[start..position]);
+            start = position;
+        }
+    }
+    if start < listed.len() {
+        runs.push(&listed[start..]);
+    }
+    let mut listing = Vec::new();
+    for run in runs {
+        let first = &run[0];
+        listing.extend_from_slice(&(run.len() as u32 - 1).to_le_bytes());
+        listing.extend_from_slice(&((first.reference >> 16) as u32).to_le_bytes());
+        listing.extend_from_slice(&first.number.to_le_bytes());
+        for entry in run {
+            listing.extend_from_slice(&(entry.reference as u16).to_le_bytes());
+            let difference = (i64::from(entry.number) - i64::from(first.number)) as i16;
+            listing.extend_from_slice(&difference.to_le_bytes());
+            listing.extend_from_slice(&entry.kind.to_le_bytes());
+            listing.extend_from_slice(&(entry.name.len() as u16 - 1).to_le_bytes());
+            listing.extend_from_slice(entry.name);
+        }
+    }
+    listing
+}
+
+/// Packs a table into metadata blocks, each compressed on its own, which entries are found in by
+/// the offset of their block and where they start in it once it's decompressed.
+struct MetadataWriter {
+    level: i32,
+    written: Vec<u8>,
+    /// Where each block written so far starts.
+    block_starts: Vec<u64>,
+    pending: Vec<u8>,
+}
+
+impl MetadataWriter {
+    fn new(level: i32) -> Self {
+        Self {
+            level,
+            written: Vec::new(),
+            block_starts: Vec::new(),
+            pending: Vec::with_capacity(METADATA_BLOCK_SIZE),
+        }
+    }
+
+    /// Where the next bytes written go: the offset of their block in the table, and where they
+    /// start in it.
+    fn position(&self) -> (u64, u16) {
+        (self.written.len() as u64, self.pending.len() as u16)
+    }
+
+    fn write(&mut self, mut bytes: &[u8]) -> std::io::Result<()> {
+        while !bytes.is_empty() {
+            let taken = bytes.len().min(METADATA_BLOCK_SIZE - self.pending.len());
+            self.pending.extend_from_slice(&bytes[..taken]);
+            bytes = &bytes[taken..];
+            if self.pending.len() == METADATA_BLOCK_SIZE {
+                self.write_block()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_block(&mut self) -> std::io::Result<()> {
+        self.block_starts.push(self.written.len() as u64);
+        let compressed = zstd::bulk::compress(&self.pending, self.level)?;
+        if compressed.len() < self.pending.len() {
+            self.written
+                .extend_from_slice(&(compressed.len() as u16).to_le_bytes());
+            self.written.extend_from_slice(&compressed);
+        } else {
+            let size = self.pending.len() as u16 | UNCOMPRESSED_METADATA;
+            self.written.extend_from_slice(&size.to_le_bytes());
+            self.written.extend_from_slice(&self.pending);
+        }
+        self.pending.clear();
+        Ok(())
+    }
+
+    fn finish(self) -> std::io::Result<Vec<u8>> {
+        Ok(self.finish_with_blocks()?.0)
+    }
+
+    /// The whole table, along with where each of its blocks starts in it.
+    fn finish_with_blocks(mut self) -> std::io::Result<(Vec<u8>, Vec<u64>)> {
+        if !self.pending.is_empty() {
+            self.write_block()?;
+        }
+        Ok((self.written, self.block_starts))
+    }
+}
+
+/// Reads back a SquashFS image the way `create_squashfs` writes it, handing every entry to `visit`
+/// by its path in the image, the root's being empty, along with the content of files.
+pub fn read_image<P: AsRef<Path>, E: From<std::io::Error>>(
+    image_path: P,
+    mut visit: impl FnMut(&Path, Option<&mut dyn Read>) -> Result<(), E>,
+) -> Result<(), E> {
+    let mut image = BufReader::new(File::open(image_path)?);
+    let mut superblock = [0; SUPERBLOCK_SIZE as usize];
+    image.read_exact(&mut superblock)?;
+    let mut fields = &superblock[..];
+    if read_u32(&mut fields)? != MAGIC {
+        return Err(invalid_image("a superblock without the SquashFS magic number").into());
+    }
+    let field = |offset: usize| read_u64(&mut &superblock[offset..]);
+    let root_reference = field(32)?;
+    let id_table_start = field(48)?;
+    let inode_table_start = field(64)?;
+    let directory_table_start = field(72)?;
+    // the id table only points to its blocks, which end the directory table
+    image.seek(SeekFrom::Start(id_table_start))?;
+    let id_blocks_start = read_u64(&mut image)?;
+    let inodes = MetadataReader::read(&mut image, inode_table_start, directory_table_start)?;
+    let listings = MetadataReader::read(&mut image, directory_table_start, id_blocks_start)?;
+
+    let mut pending = vec![(PathBuf::new(), root_reference)];
+    while let Some((path, reference)) = pending.pop() {
+        let mut inode = inodes.at(reference >> 16, reference as u16)?;
+        let kind = read_u16(&mut inode)?;
+        // permissions, ids, mtime and inode number
+        inode = inode.get(14..).unwrap_or_default();
+        match kind {
+            BASIC_DIRECTORY | EXTENDED_DIRECTORY => {
+                let (block, size, offset) = if kind == BASIC_DIRECTORY {
+                    let block = read_u32(&mut inode)?;
+                    read_u32(&mut inode)?;
+                    let size = u32::from(read_u16(&mut inode)?);
+                    (block, size, read_u16(&mut inode)?)
+                } else {
+                    read_u32(&mut inode)?;
+                    let size = read_u32(&mut inode)?;
+                    let block = read_u32(&mut inode)?;
+                    read_u32(&mut inode)?;
+                    read_u16(&mut inode)?;
+                    (block, size, read_u16(&mut inode)?)
+                };
+                visit(&path, None)?;
+                let listing = listings.at(u64::from(block), offset)?;
+                // "." and ".." are counted in the size without being listed
+                let size = (size as usize).saturating_sub(3);
+                let listing = listing
+                    .get(..size)
+                    .ok_or_else(|| invalid_image("a listing running past its table"))?;
+                for (name, child) in read_listing(listing)? {
+                    pending.push((path.join(archiver::bytes_path(&name)), child));
+                }
+            }
+            BASIC_FILE | EXTENDED_FILE => {
+                let (blocks_start, size) = if kind == BASIC_FILE {
+                    let blocks_start = u64::from(read_u32(&mut inode)?);
+                    read_u32(&mut inode)?;
+                    read_u32(&mut inode)?;
+                    (blocks_start, u64::from(read_u32(&mut inode)?))
+                } else {
+                    let blocks_start = read_u64(&mut inode)?;
+                    let size = read_u64(&mut inode)?;
+                    // sparse bytes, links, fragment, fragment offset and xattr index
+                    inode = inode.get(24..).unwrap_or_default();
+                    (blocks_start, size)
+                };
+                let block_sizes = (0..size.div_ceil(BLOCK_SIZE as u64))
+                    .map(|_| read_u32(&mut inode))
+                    .collect::<std::io::Result<Vec<_>>>()?;
+                let mut content = BlockReader {
+                    image: &mut image,
+                    next: blocks_start,
+                    block_sizes: block_sizes.into_iter(),
+                    block: Vec::new(),
+                    taken: 0,
+                };
+                visit(&path, Some(&mut content))?;
+            }
+            BASIC_SYMLINK => visit(&path, None)?,
+            _ => return Err(invalid_image("an inode of a type it never writes").into()),
+        }
+    }
+    Ok(())
+}
+
+/// The entries of a directory listing, by name and where their inodes are.
+fn read_listing(mut listing: &[u8]) -> std::io::Result<Vec<(Vec<u8>, u64)>> {
+    let mut entries = Vec::new();
+    while !listing.is_empty() {
+        let count = read_u32(&mut listing)? + 1;
+        let block = u64::from(read_u32(&mut listing)?);
+        // the inode number the entries' own are relative to
+        read_u32(&mut listing)?;
+        for _ in 0..count {
+            let offset = u64::from(read_u16(&mut listing)?);
+            read_u16(&mut listing)?;
+            read_u16(&mut listing)?;
+            let mut name = vec![0; usize::from(read_u16(&mut listing)?) + 1];
+            listing.read_exact(&mut name)?;
+            entries.push((name, (block << 16) | offset));
+        }
+    }
+    Ok(entries)
+}
+
+/// A table of metadata blocks, decompressed whole so entries can be found by the offset of the
+/// block they start in and where they start in it.
+struct MetadataReader {
+    data: Vec<u8>,
+    /// Where each block starts in `data`, by its offset in the table.
+    blocks: HashMap<u64, usize>,
+}
+
+impl MetadataReader {
+    fn read<R: Read + Seek>(image: &mut R, start: u64, end: u64) -> std::io::Result<Self> {
+        image.seek(SeekFrom::Start(start))?;
+        let mut table = Self {
+            data: Vec::new(),
+            blocks: HashMap::new(),
+        };
+        let mut offset = 0;
+        while start + offset < end {
+            table.blocks.insert(offset, table.data.len());
+            let header = read_u16(image)?;
+            let stored = header & !UNCOMPRESSED_METADATA;
+            let mut block = vec![0; usize::from(stored)];
+            image.read_exact(&mut block)?;
+            if header & UNCOMPRESSED_METADATA == 0 {
+                block = zstd::bulk::decompress(&block, METADATA_BLOCK_SIZE)?;
+            }
+            table.data.extend_from_slice(&block);
+            offset += 2 + u64::from(stored);
+        }
+        Ok(table)
+    }
+
+    /// Everything from where an entry starts to the end of the table.
+    fn at(&self, block: u64, offset: u16) -> std::io::Result<&[u8]> {
+        self.blocks
+            .get(&block)
+            .and_then(|start| self.data.get(start + usize::from(offset)..))
+            .ok_or_else(|| invalid_image("a reference to a metadata block it doesn't have"))
+    }
+}
+
+/// Reads a file's content back out of its blocks, decompressing one at a time.
+struct BlockReader<'a, R: Read + Seek> {
+    image: &'a mut R,
+    /// Where the next block starts.
+    next: u64,
+    block_sizes: std::vec::IntoIter<u32>,
+    block: Vec<u8>,
+    /// How much of the current block was read.
+    taken: usize,
+}
+
+impl<R: Read + Seek> Read for BlockReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.taken == self.block.len() {
+            let Some(block_size) = self.block_sizes.next() else {
+                return Ok(0);
+            };
+            let stored = block_size & !UNCOMPRESSED_BLOCK;
+            let mut block = vec![0; stored as usize];
+            self.image.seek(SeekFrom::Start(self.next))?;
+            self.image.read_exact(&mut block)?;
+            self.next += u64::from(stored);
+            if block_size & UNCOMPRESSED_BLOCK == 0 {
+                block = zstd::bulk::decompress(&block, BLOCK_SIZE)?;
+            }
+            self.block = block;
+            self.taken = 0;
+        }
+        let taken = buf.len().min(self.block.len() - self.taken);
+        buf[..taken].copy_from_slice(&self.block[self.taken..self.taken + taken]);
+        self.taken += taken;
+        Ok(taken)
+    }
+}
+
+fn read_u16(reader: &mut impl Read) -> std::io::Result<u16> {
+    let mut bytes = [0; 2];
+    reader.read_exact(&mut bytes)?;
+    Ok(u16::from_le_bytes(bytes))
+}
+
+fn read_u32(reader: &mut impl Read) -> std::io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64(reader: &mut impl Read) -> std::io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn invalid_image(problem: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("SquashFS image holds {}", problem),
+    )
+}
+
+fn not_a_directory(relative_path: &Path) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!(
+            "Can't add {} to the image, as a parent of it isn't a directory",
+            relative_path.display()
+        ),
+    )
+}
+
+/// A directory the image needs for something beneath it, but which wasn't captured itself.
+fn made_up_directory(made: u32) -> Attributes {
+    Attributes {
+        permissions: 0o755,
+        uid: 0,
+        gid: 0,
+        mtime: made,
+    }
+}
+
+fn mtime_of(metadata: &Metadata) -> u32 {
+    // the format has no room for times before the epoch or after 2106
+    metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |since| since.as_secs().min(u64::from(u32::MAX)) as u32)
+}
+
+#[cfg(unix)]
+fn attributes(metadata: &Metadata, mode: Option<u32>) -> Attributes {
+    use std::os::unix::fs::MetadataExt;
+    Attributes {
+        permissions: (mode.unwrap_or(metadata.mode()) & 0o7777) as u16,
+        uid: metadata.uid(),
+        gid: metadata.gid(),
+        mtime: mtime_of(metadata),
+    }
+}
+
+#[cfg(not(unix))]
+fn attributes(metadata: &Metadata, mode: Option<u32>) -> Attributes {
+    let permissions = if metadata.permissions().readonly() {
+        0o555
+    } else {
+        0o755
+    };
+    Attributes {
+        permissions: mode.unwrap_or(permissions) as u16,
+        uid: 0,
+        gid: 0,
+        mtime: mtime_of(metadata),
+    }
+}
@@ -0,0 +1,129 @@
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+
+use crate::cancel::Cancellation;
+use crate::notify;
+
+/// How long a reading of the load average is trusted before it's read again, so checking it
+/// between every file costs next to nothing.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long to wait between readings while paused. Linux only recomputes the load average every
+/// 5 seconds.
+const PAUSE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often a pause checks whether the snapshot was asked to stop.
+const PAUSE_POLL: Duration = Duration::from_millis(100);
+
+/// Pauses the capture while the system's 1-minute load average is above a threshold, so a
+/// snapshot taken while the system is busy gives way to whatever keeps it busy.
+pub struct LoadThrottle {
+    max_load: f64,
+    /// When the load average was last read.
+    checked: Cell<Option<Instant>>,
+    /// How long the capture was paused for in all.
+    paused: Cell<Duration>,
+}
+
+impl LoadThrottle {
+    pub fn new(max_load: f64) -> Self {
+        Self {
+            max_load,
+            checked: Cell::new(None),
+            paused: Cell::new(Duration::ZERO),
+        }
+    }
+
+    /// How long the capture was paused for in all.
+    pub fn paused(&self) -> Duration {
+        self.paused.get()
+    }
+
+    /// Waits for as long as the load average is above the threshold, giving up early once a
+    /// graceful stop is requested. The load average is read at most once a second otherwise.
+    pub fn wait(&self, cancel: &Cancellation) {
+        if self
+            .checked
+            .get()
+            .is_some_and(|checked| checked.elapsed() < CHECK_INTERVAL)
+        {
+            return;
+        }
+        self.checked.set(Some(Instant::now()));
+        let Some(load) = load_average().filter(|load| *load > self.max_load) else {
+            return;
+        };
+        log::info!(
+            "Load average {:.2} is above {}; pausing the capture until it drops",
+            load,
+            self.max_load
+        );
+        notify::status("Paused while the system is busy");
+        let started = Instant::now();
+        let mut load = load;
+        while load > self.max_load {
+            let next_reading = Instant::now() + PAUSE_INTERVAL;
+            while Instant::now() < next_reading {
+                if cancel.is_cancelled() {
+                    self.paused.set(self.paused.get() + started.elapsed());
+                    return;
+                }
+                std::thread::sleep(PAUSE_POLL.min(next_reading - Instant::now()));
+            }
+            // a load average which can no longer be read no longer holds the capture back
+            load = load_average().unwrap_or(0.0);
+            log::debug!("Load average is {:.2}", load);
+        }
+        self.paused.set(self.paused.get() + started.elapsed());
+        log::info!(
+            "Load average dropped to {:.2}; resuming the capture after {} s",
+            load,
+            started.elapsed().as_secs()
+        );
+        notify::status("Visiting the target");
+        self.checked.set(Some(Instant::now()));
+    }
+}
+
+/// The system's load average over the last minute, if the platform has one to read.
+#[cfg(unix)]
+pub fn load_average() -> Option<f64> {
+    let mut loads = [0.0];
+    (unsafe { libc::getloadavg(loads.as_mut_ptr(), 1) } == 1).then_some(loads[0])
+}
+
+#[cfg(not(unix))]
+pub fn load_average() -> Option<f64> {
+    None
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_load_below_the_threshold_never_pauses() {
+        let throttle = LoadThrottle::new(f64::MAX);
+        let started = Instant::now();
+        throttle.wait(&Cancellation::default());
+        assert!(started.elapsed() < PAUSE_POLL);
+        assert_eq!(throttle.paused(), Duration::ZERO);
+    }
+
+    #[test]
+    fn a_pause_lasts_until_the_snapshot_is_stopped() {
+        // no load average is ever below zero, so only stopping ends the pause
+        let throttle = LoadThrottle::new(-1.0);
+        let cancel = Cancellation::default();
+        let stop_after = Duration::from_millis(300);
+        cancel.set_deadline(Instant::now() + stop_after, false);
+        throttle.wait(&cancel);
+        assert!(throttle.paused() >= stop_after);
+        assert!(throttle.paused() < PAUSE_INTERVAL);
+
+        // once read, the load average is trusted for a while before the next pause
+        let started = Instant::now();
+        throttle.wait(&Cancellation::default());
+        assert!(started.elapsed() < PAUSE_POLL);
+    }
+}
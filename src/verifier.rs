@@ -0,0 +1,663 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use tar::Archive;
+
+use crate::archiver::ArchivedEntry;
+use crate::batch;
+use crate::checksum;
+use crate::cpio::{self, CpioCompression};
+use crate::dictionary::{self, Dictionary};
+use crate::hashing::HashAlgorithm;
+use crate::manifest::{self, Manifest};
+use crate::processor::Digest;
+use crate::provenance::{self, Provenance};
+use crate::restorer::is_whiteout;
+use crate::squashfs;
+
+#[derive(Debug)]
+pub enum VerifyError {
+    Io(std::io::Error),
+    MissingEntry(PathBuf),
+    UnexpectedEntry(PathBuf),
+    DigestMismatch(PathBuf),
+    /// The manifest doesn't list this entry as it was archived.
+    ManifestMismatch(PathBuf),
+    /// The archive doesn't match the checksum recorded in this sidecar.
+    ChecksumMismatch(PathBuf),
+    /// This directory holds fewer entries than were listed in it when it was captured.
+    IncompleteDirectory {
+        path: PathBuf,
+        held: usize,
+        listed: usize,
+    },
+    /// Entries follow the last snapshot record, so the snapshot writing them never finished.
+    Truncated,
+}
+
+impl Error for VerifyError {}
+
+impl std::fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VerifyError::Io(err) => write!(f, "Failed to read archive: {}", err),
+            VerifyError::MissingEntry(path) => {
+                write!(f, "Entry missing from archive: {}", path.display())
+            }
+            VerifyError::UnexpectedEntry(path) => {
+                write!(f, "Unexpected entry in archive: {}", path.display())
+            }
+            VerifyError::DigestMismatch(path) => {
+                write!(f, "Digest mismatch for entry: {}", path.display())
+            }
+            VerifyError::ManifestMismatch(path) => {
+                write!(f, "Manifest doesn't match entry: {}", path.display())
+            }
+            VerifyError::ChecksumMismatch(sidecar) => {
+                write!(f, "Archive doesn't match checksum in {}", sidecar.display())
+            }
+            VerifyError::IncompleteDirectory { path, held, listed } => write!(
+                f,
+                "Directory {} holds {} of the {} entries listed in it when it was captured",
+                path.display(),
+                held,
+                listed
+            ),
+            VerifyError::Truncated => {
+                write!(
+                    f,
+                    "Archive ends partway through a snapshot; it may be truncated"
+                )
+            }
+        }
+    }
+}
+
+impl From<std::io::Error> for VerifyError {
+    fn from(value: std::io::Error) -> Self {
+        VerifyError::Io(value)
+    }
+}
+
+/// The entries a written archive should hold, checked off as they're read back out of it.
+struct Expected<'a> {
+    remaining: HashMap<&'a Path, Option<Digest>>,
+    hash_algorithm: HashAlgorithm,
+}
+
+impl<'a> Expected<'a> {
+    fn new(expected: &'a [ArchivedEntry], hash_algorithm: HashAlgorithm) -> Self {
+        Self {
+            remaining: expected
+                .iter()
+                .map(|entry| (entry.path.as_path(), entry.digest))
+                .collect(),
+            hash_algorithm,
+        }
+    }
+
+    fn holds(&self, path: &Path) -> bool {
+        self.remaining.contains_key(path)
+    }
+
+    /// Checks off an entry read back, returning the digest its content should have.
+    fn take(&mut self, path: PathBuf) -> Result<Option<Digest>, VerifyError> {
+        match self.remaining.remove(path.as_path()) {
+            Some(digest) => Ok(digest),
+            None => Err(VerifyError::UnexpectedEntry(path)),
+        }
+    }
+
+    /// Checks off an entry read back, along with its content when it's a file.
+    fn check(&mut self, path: PathBuf, content: Option<&mut dyn Read>) -> Result<(), VerifyError> {
+        match (self.take(path.clone())?, content) {
+            (None, _) => {}
+            (Some(digest), Some(content)) => {
+                if self.hash_algorithm.digest_reader(content)? != digest {
+                    return Err(VerifyError::DigestMismatch(path));
+                }
+            }
+            (Some(_), None) => return Err(VerifyError::DigestMismatch(path)),
+        }
+        log::debug!("Verified entry {}", path.display());
+        Ok(())
+    }
+
+    fn finish(self) -> Result<(), VerifyError> {
+        match self.remaining.into_keys().next() {
+            Some(path) => Err(VerifyError::MissingEntry(path.to_path_buf())),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Re-opens a written tarball and checks that, from `offset` on, it contains exactly the expected
+/// entries, with every file decompressing to content matching the digest captured during
+/// processing with the given algorithm, and that the manifest embedded in it agrees. Entries
+/// before the offset were written by earlier snapshots and aren't checked.
+pub fn verify_tarball<P: AsRef<Path>>(
+    tarball_path: P,
+    offset: u64,
+    expected: &[ArchivedEntry],
+    hash_algorithm: HashAlgorithm,
+    dictionary: Option<&Dictionary>,
+) -> Result<(), VerifyError> {
+    log::info!("Verifying tarball with {} entries", expected.len());
+    let mut remaining = Expected::new(expected, hash_algorithm);
+
+    let mut file = File::open(tarball_path)?;
+    file.seek(SeekFrom::Start(offset))?;
+    let mut archive = Archive::new(BufReader::new(file));
+    for entry in archive.entries()? {
+        let entry = entry?;
+        let path = entry.path()?.into_owned();
+        if provenance::is_provenance(&path) {
+            Provenance::read(entry)?;
+            continue;
+        }
+        if manifest::is_embedded_manifest(&path) {
+            check_manifest(&Manifest::read_from(entry)?, expected)?;
+            continue;
+        }
+        if path.file_name() == Some(batch::BATCH_FILE_NAME.as_ref()) {
+            let directory = path.parent().unwrap_or(Path::new(""));
+            let (members, data) = batch::read_batch(entry, dictionary)?;
+            for member in members {
+                let member_path = directory.join(&member.name);
+                match remaining.take(member_path.clone())? {
+                    Some(digest) if hash_algorithm.digest(&data[member.data]) == digest => {
+                        log::debug!("Verified batched entry {}", member_path.display());
+                    }
+                    _ => return Err(VerifyError::DigestMismatch(member_path)),
+                }
+            }
+            continue;
+        }
+        if let Some(digest) = remaining.take(path.clone())? {
+            if hash_algorithm.digest_reader(dictionary::decoder(entry, dictionary)?)? != digest {
+                return Err(VerifyError::DigestMismatch(path));
+            }
+        }
+        log::debug!("Verified entry {}", path.display());
+    }
+    remaining.finish()
+}
+
+/// Re-opens a written zip archive and checks it holds exactly the expected entries, with every
+/// file matching its captured digest. Reading a file back also checks it against its CRC-32.
+pub fn verify_zip<P: AsRef<Path>>(
+    zip_path: P,
+    expected: &[ArchivedEntry],
+    hash_algorithm: HashAlgorithm,
+) -> Result<(), VerifyError> {
+    log::info!("Verifying zip archive with {} entries", expected.len());
+    let mut remaining = Expected::new(expected, hash_algorithm);
+    let mut archive = zip::ZipArchive::new(BufReader::new(File::open(zip_path)?))
+        .map_err(std::io::Error::from)?;
+    for index in 0..archive.len() {
+        let mut entry = archive.by_index(index).map_err(std::io::Error::from)?;
+        let name = entry.name().map_err(std::io::Error::from)?.into_owned();
+        let path = PathBuf::from(name.trim_end_matches('/'));
+        if entry.is_file() {
+            remaining.check(path, Some(&mut entry))?;
+        } else {
+            remaining.check(path, None)?;
+        }
+    }
+    remaining.finish()
+}
+
+/// Re-opens a written cpio archive and checks it holds exactly the expected entries, with every
+/// file matching its captured digest. Hard links after the first stored with their content are
+/// held to the digest the first one was read back with.
+pub fn verify_cpio<P1: AsRef<Path>, P2: AsRef<Path>>(
+    origin: P1,
+    cpio_path: P2,
+    compression: CpioCompression,
+    expected: &[ArchivedEntry],
+    hash_algorithm: HashAlgorithm,
+) -> Result<(), VerifyError> {
+    log::info!("Verifying cpio archive with {} entries", expected.len());
+    let root = archive_root(origin.as_ref());
+    let mut remaining = Expected::new(expected, hash_algorithm);
+    let mut linked: HashMap<u64, Digest> = HashMap::new();
+    cpio::read_cpio(cpio_path, compression, |member, content| {
+        let path = match member.name.as_path() {
+            name if name == Path::new(".") => root.clone(),
+            name => root.join(name),
+        };
+        if !member.is_file() {
+            return remaining.check(path, None);
+        }
+        let digest = remaining.take(path.clone())?;
+        let read = match linked.get(&member.ino) {
+            Some(first) if member.filesize == 0 => Some(*first),
+            _ if digest.is_some() => Some(hash_algorithm.digest_reader(content)?),
+            _ => None,
+        };
+        if read != digest {
+            return Err(VerifyError::DigestMismatch(path));
+        }
+        if let (Some(read), true) = (read, member.nlink > 1) {
+            linked.entry(member.ino).or_insert(read);
+        }
+        log::debug!("Verified entry {}", path.display());
+        Ok(())
+    })?;
+    remaining.finish()
+}
+
+/// Re-opens a written SquashFS image and checks it holds exactly the expected entries, with every
+/// file matching its captured digest. Directories the image made up for entries beneath them are
+/// passed over.
+pub fn verify_squashfs<P1: AsRef<Path>, P2: AsRef<Path>>(
+    origin: P1,
+    image_path: P2,
+    expected: &[ArchivedEntry],
+    hash_algorithm: HashAlgorithm,
+) -> Result<(), VerifyError> {
+    log::info!("Verifying SquashFS image with {} entries", expected.len());
+    let root = archive_root(origin.as_ref());
+    let mut remaining = Expected::new(expected, hash_algorithm);
+    squashfs::read_image(image_path, |name, content| {
+        let path = match name.as_os_str().is_empty() {
+            true => root.clone(),
+            false => root.join(name),
+        };
+        if content.is_none() && !remaining.holds(&path) {
+            log::debug!("Passing over directory {}", path.display());
+            return Ok(());
+        }
+        remaining.check(path, content)
+    })?;
+    remaining.finish()
+}
+
+/// Checks a written snapshot directory holds exactly the expected entries, with every file
+/// matching its captured digest.
+pub fn verify_farm<P1: AsRef<Path>, P2: AsRef<Path>>(
+    origin: P1,
+    directory: P2,
+    expected: &[ArchivedEntry],
+    hash_algorithm: HashAlgorithm,
+) -> Result<(), VerifyError> {
+    log::info!(
+        "Verifying snapshot directory with {} entries",
+        expected.len()
+    );
+    let root = archive_root(origin.as_ref());
+    let mut remaining = Expected::new(expected, hash_algorithm);
+    remaining.check(root.clone(), None)?;
+    let mut pending = vec![(directory.as_ref().to_path_buf(), root)];
+    while let Some((directory, path)) = pending.pop() {
+        for child in std::fs::read_dir(&directory)? {
+            let child = child?;
+            let child_path = path.join(child.file_name());
+            let file_type = child.file_type()?;
+            if file_type.is_dir() {
+                remaining.check(child_path.clone(), None)?;
+                pending.push((child.path(), child_path));
+            } else if file_type.is_file() {
+                let mut file = BufReader::new(File::open(child.path())?);
+                remaining.check(child_path, Some(&mut file))?;
+            } else {
+                remaining.check(child_path, None)?;
+            }
+        }
+    }
+    remaining.finish()
+}
+
+/// Checks a manifest lists every archived entry, with the digest it was archived with. Entries
+/// only in the manifest were carried over from an earlier snapshot.
+pub fn check_manifest(manifest: &Manifest, expected: &[ArchivedEntry]) -> Result<(), VerifyError> {
+    let listed: HashMap<&Path, Option<&str>> = manifest
+        .entries
+        .iter()
+        .map(|entry| (entry.path.as_path(), entry.digest.as_deref()))
+        .collect();
+    for entry in expected {
+        let matches = match (listed.get(entry.path.as_path()), &entry.digest) {
+            (None, _) => false,
+            (Some(_), None) => true,
+            (Some(listed), Some(digest)) => *listed == Some(hex::encode(digest).as_str()),
+        };
+        if !matches {
+            return Err(VerifyError::ManifestMismatch(entry.path.clone()));
+        }
+    }
+    Ok(())
+}
+
+/// The leading directory archived paths have, standing for the target itself.
+fn archive_root(origin: &Path) -> PathBuf {
+    origin.file_name().map(PathBuf::from).unwrap_or_default()
+}
+
+/// Checks a finished archive on its own, without knowing what it should hold: it must match its
+/// checksum sidecar, if one is given, every file in it must decompress, every snapshot in it must
+/// end with its record, and every directory in the manifests recorded in it must hold as many
+/// entries as were listed in it at capture. Returns the number of files checked. Files compressed
+/// with a dictionary need that dictionary given.
+pub fn check_archive<P1: AsRef<Path>, P2: AsRef<Path>>(
+    tarball_path: P1,
+    sidecar: Option<P2>,
+    dictionary: Option<&Dictionary>,
+) -> Result<usize, VerifyError> {
+    let tarball_path = tarball_path.as_ref();
+    if let Some(sidecar) = sidecar {
+        let sidecar = sidecar.as_ref();
+        if !checksum::verify_sidecar(tarball_path, sidecar)? {
+            return Err(VerifyError::ChecksumMismatch(sidecar.to_path_buf()));
+        }
+        log::info!("Archive matches checksum in {}", sidecar.display());
+    }
+
+    let mut checked = 0;
+    // whether entries were read since the last snapshot record
+    let mut unfinished = false;
+    let mut archive = Archive::new(BufReader::new(File::open(tarball_path)?));
+    for entry in archive.entries()? {
+        let entry = entry?;
+        let path = entry.path()?.into_owned();
+        unfinished = !provenance::is_provenance(&path);
+        if entry.header().entry_type() != tar::EntryType::Regular || is_whiteout(&path) {
+            continue;
+        }
+        if provenance::is_provenance(&path) {
+            Provenance::read(entry)?.log();
+            continue;
+        }
+        if manifest::is_embedded_manifest(&path) {
+            check_directories(&Manifest::read_from(entry)?)?;
+            continue;
+        }
+        if path.file_name() == Some(batch::BATCH_FILE_NAME.as_ref()) {
+            let (members, _) = batch::read_batch(entry, dictionary)?;
+            checked += members.len();
+        } else {
+            // files captured without their content hold no frame to decompress
+            if entry.size() > 0 {
+                std::io::copy(
+                    &mut dictionary::decoder(entry, dictionary)?,
+                    &mut std::io::sink(),
+                )?;
+            }
+            checked += 1;
+        }
+        log::debug!("Checked entry {}", path.display());
+    }
+    if unfinished {
+        return Err(VerifyError::Truncated);
+    }
+    Ok(checked)
+}
+
+/// Checks every directory in the manifest holds as many entries as were listed in it when it was
+/// captured. Fewer means the directory was only partly read.
+fn check_directories(manifest: &Manifest) -> Result<(), VerifyError> {
+    let mut held: HashMap<&Path, usize> = HashMap::new();
+    for entry in &manifest.entries {
+        if let Some(parent) = entry.path.parent() {
+            *held.entry(parent).or_default() += 1;
+        }
+    }
+    let incomplete = manifest::incomplete_directories(&manifest.entries, |path| {
+        held.get(path).copied().unwrap_or_default()
+    });
+    match incomplete.into_iter().next() {
+        Some((path, held, listed)) => Err(VerifyError::IncompleteDirectory {
+            path: path.to_path_buf(),
+            held,
+            listed,
+        }),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archiver::create_tarball;
+    use crate::archiver::tests::tar_options;
+    use crate::processor::tests::{options, write};
+    use crate::processor::{self, Entry};
+    use crate::report::SnapshotReport;
+    use crate::{farm, zip_archive};
+
+    /// The formats written beside tarballs, by the name of the output they're written to.
+    const FORMATS: [&str; 6] = [
+        "out.cpio",
+        "out.cpio.gz",
+        "out.cpio.zst",
+        "out.zip",
+        "out.squashfs",
+        "out",
+    ];
+
+    /// A target holding a nested file, a hard link and a symlink.
+    fn target(root: &Path) -> PathBuf {
+        let target = root.join("target");
+        write(&target.join("file"), "the content of the file");
+        write(&target.join("sub/nested"), "nested content");
+        std::fs::hard_link(target.join("file"), target.join("linked")).unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink("file", target.join("link")).unwrap();
+        target
+    }
+
+    fn capture(target: &Path, temp: &Path) -> impl Iterator<Item = Entry> {
+        let mut report = SnapshotReport::default();
+        processor::process_directory(target, 5, &options(temp), &mut report)
+            .unwrap()
+            .entries
+    }
+
+    /// Writes the target out in the format its name gives, returning what was archived.
+    fn archive(target: &Path, output: &Path) -> Vec<ArchivedEntry> {
+        let temp = tempfile::tempdir().unwrap();
+        let entries = capture(target, temp.path());
+        let mut report = SnapshotReport::default();
+        let options = tar_options();
+        let written = match cpio::output_compression(output) {
+            Some(compression) => {
+                cpio::create_cpio(target, entries, output, compression, &options, &mut report)
+            }
+            None if zip_archive::is_zip(output) => zip_archive::create_zip(
+                target,
+                entries,
+                output,
+                zip_archive::ZipCompression::Deflate,
+                &options,
+                &mut report,
+            ),
+            None if squashfs::is_squashfs(output) => {
+                squashfs::create_squashfs(target, entries, output, &options, &mut report)
+            }
+            None => farm::create_farm(
+                target,
+                entries,
+                output,
+                None,
+                HashAlgorithm::Sha256,
+                &options,
+                &mut report,
+            ),
+        };
+        written.unwrap().0
+    }
+
+    fn verify(target: &Path, output: &Path, expected: &[ArchivedEntry]) -> Result<(), VerifyError> {
+        let algorithm = HashAlgorithm::Sha256;
+        match cpio::output_compression(output) {
+            Some(compression) => verify_cpio(target, output, compression, expected, algorithm),
+            None if zip_archive::is_zip(output) => verify_zip(output, expected, algorithm),
+            None if squashfs::is_squashfs(output) => {
+                verify_squashfs(target, output, expected, algorithm)
+            }
+            None => verify_farm(target, output, expected, algorithm),
+        }
+    }
+
+    #[test]
+    fn every_format_is_verified_against_what_was_archived() {
+        let root = tempfile::tempdir().unwrap();
+        let target = target(root.path());
+        for name in FORMATS {
+            let output = root.path().join(name);
+            let mut archived = archive(&target, &output);
+            verify(&target, &output, &archived).unwrap_or_else(|err| panic!("{}: {}", name, err));
+
+            let missing = PathBuf::from("target/missing");
+            archived.push(ArchivedEntry {
+                path: missing.clone(),
+                digest: None,
+            });
+            match verify(&target, &output, &archived) {
+                Err(VerifyError::MissingEntry(path)) => assert_eq!(path, missing, "{}", name),
+                other => panic!("{}: {:?}", name, other),
+            }
+
+            archived.retain(|entry| entry.path != Path::new("target/sub/nested"));
+            match verify(&target, &output, &archived) {
+                Err(VerifyError::UnexpectedEntry(path)) => {
+                    assert_eq!(path, Path::new("target/sub/nested"), "{}", name)
+                }
+                other => panic!("{}: {:?}", name, other),
+            }
+        }
+    }
+
+    #[test]
+    fn content_changed_after_writing_fails_verification() {
+        let root = tempfile::tempdir().unwrap();
+        let target = target(root.path());
+        // these formats store content this short as it is, so it can be changed in place
+        for name in ["out.cpio", "out.squashfs"] {
+            let output = root.path().join(name);
+            let archived = archive(&target, &output);
+            let mut written = std::fs::read(&output).unwrap();
+            let at = written
+                .windows(b"nested content".len())
+                .position(|window| window == b"nested content")
+                .unwrap();
+            written[at] = b'N';
+            std::fs::write(&output, written).unwrap();
+            match verify(&target, &output, &archived) {
+                Err(VerifyError::DigestMismatch(path)) => {
+                    assert_eq!(path, Path::new("target/sub/nested"), "{}", name)
+                }
+                other => panic!("{}: {:?}", name, other),
+            }
+        }
+
+        let output = root.path().join("out");
+        let archived = archive(&target, &output);
+        std::fs::write(output.join("sub/nested"), "Nested content").unwrap();
+        assert!(matches!(
+            verify(&target, &output, &archived),
+            Err(VerifyError::DigestMismatch(_))
+        ));
+    }
+
+    /// Writes the target to a tarball as a snapshot would, lets `tamper` have at the tarball, then
+    /// verifies it as `--verify-after-write` would.
+    fn tarball_tampered_with(tamper: impl FnOnce(&Path)) -> Result<(), VerifyError> {
+        let root = tempfile::tempdir().unwrap();
+        let target = target(root.path());
+        let temp = tempfile::tempdir().unwrap();
+        let tarball = root.path().join("out.tar.zst");
+        let mut report = SnapshotReport::default();
+        let (archived, _) = create_tarball(
+            &target,
+            capture(&target, temp.path()),
+            &tarball,
+            &tar_options(),
+            None,
+            &mut report,
+        )
+        .unwrap();
+        tamper(&tarball);
+        verify_tarball(&tarball, 0, &archived, HashAlgorithm::Sha256, None)
+    }
+
+    #[test]
+    fn tarballs_are_verified_against_what_was_archived() {
+        tarball_tampered_with(|_| ()).unwrap();
+    }
+
+    #[test]
+    fn tarballs_changed_after_writing_fail_verification() {
+        // every member left as it was but one, whose content is swapped for other valid content
+        let swapped = tarball_tampered_with(|tarball| {
+            let mut rewritten = tar::Builder::new(Vec::new());
+            for member in Archive::new(File::open(tarball).unwrap())
+                .entries()
+                .unwrap()
+            {
+                let mut member = member.unwrap();
+                let mut header = member.header().clone();
+                let mut content = Vec::new();
+                member.read_to_end(&mut content).unwrap();
+                if member.path().unwrap() == Path::new("target/sub/nested") {
+                    content = zstd::encode_all(&b"Nested content"[..], 3).unwrap();
+                    header.set_size(content.len() as u64);
+                    header.set_cksum();
+                }
+                rewritten.append(&header, &content[..]).unwrap();
+            }
+            std::fs::write(tarball, rewritten.into_inner().unwrap()).unwrap();
+        });
+        match swapped {
+            Err(VerifyError::DigestMismatch(path)) => {
+                assert_eq!(path, Path::new("target/sub/nested"))
+            }
+            other => panic!("{:?}", other),
+        }
+
+        let truncated = tarball_tampered_with(|tarball| {
+            let written = std::fs::read(tarball).unwrap();
+            std::fs::write(tarball, &written[..written.len() / 2]).unwrap();
+        });
+        assert!(truncated.is_err());
+    }
+
+    #[test]
+    fn the_manifest_has_to_list_every_archived_file_with_its_digest() {
+        let root = tempfile::tempdir().unwrap();
+        let target = target(root.path());
+        let temp = tempfile::tempdir().unwrap();
+        let mut report = SnapshotReport::default();
+        let (archived, mut manifest) = cpio::create_cpio(
+            &target,
+            capture(&target, temp.path()),
+            root.path().join("out.cpio"),
+            CpioCompression::None,
+            &tar_options(),
+            &mut report,
+        )
+        .unwrap();
+        check_manifest(&manifest, &archived).unwrap();
+
+        let nested = manifest
+            .entries
+            .iter_mut()
+            .find(|entry| entry.path == Path::new("target/sub/nested"))
+            .unwrap();
+        nested.digest = Some(hex::encode([0; 32]));
+        assert!(matches!(
+            check_manifest(&manifest, &archived),
+            Err(VerifyError::ManifestMismatch(_))
+        ));
+        manifest
+            .entries
+            .retain(|entry| entry.path != Path::new("target/sub/nested"));
+        assert!(matches!(
+            check_manifest(&manifest, &archived),
+            Err(VerifyError::ManifestMismatch(_))
+        ));
+    }
+}
@@ -0,0 +1,70 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+/// A kind of problem the snapshot carries on past, counted so that a run which ran into any can be
+/// told apart from a clean one.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Warning {
+    /// A path couldn't be read, and was left out.
+    Unreadable,
+    /// A symlink or listed path leads outside the target, and was left out.
+    OutsideTarget,
+    /// A path never held still before a partial snapshot ran out of time, and was dropped.
+    Dropped,
+    /// A path is neither a file, a directory nor a symlink, and was left out.
+    UnsupportedType,
+    /// An entry's mtime lies in the future.
+    FutureMtime,
+    /// A dereferenced symlink leads back into its own ancestry.
+    SymlinkLoop,
+    /// A file is hard linked from paths the snapshot doesn't hold.
+    PartialHardlink,
+    /// Paths kept changing for every settle window.
+    KeptChanging,
+}
+
+impl std::fmt::Display for Warning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Warning::Unreadable => write!(f, "unreadable"),
+            Warning::OutsideTarget => write!(f, "outside_target"),
+            Warning::Dropped => write!(f, "dropped"),
+            Warning::UnsupportedType => write!(f, "unsupported_type"),
+            Warning::FutureMtime => write!(f, "future_mtime"),
+            Warning::SymlinkLoop => write!(f, "symlink_loop"),
+            Warning::PartialHardlink => write!(f, "partial_hardlink"),
+            Warning::KeptChanging => write!(f, "kept_changing"),
+        }
+    }
+}
+
+/// How many warnings of every kind the snapshot ran into so far, leaving out kinds it didn't.
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct WarningCounts(BTreeMap<Warning, usize>);
+
+impl WarningCounts {
+    /// Counts one more warning of the kind.
+    pub fn record(&mut self, warning: Warning) {
+        *self.0.entry(warning).or_default() += 1;
+    }
+
+    /// Adds the warnings counted elsewhere, such as during a single pass.
+    pub fn add(&mut self, other: &WarningCounts) {
+        for (warning, count) in &other.0 {
+            *self.0.entry(*warning).or_default() += count;
+        }
+    }
+
+    /// How many warnings of every kind were counted.
+    pub fn counts(&self) -> &BTreeMap<Warning, usize> {
+        &self.0
+    }
+
+    /// How many warnings were counted in all.
+    pub fn total(&self) -> usize {
+        self.0.values().sum()
+    }
+}
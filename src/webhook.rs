@@ -0,0 +1,159 @@
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::cancel::Cancellation;
+use crate::report::{self, SnapshotReport};
+
+/// Attempts made after the first fails, waiting a little longer before each.
+const RETRIES: u32 = 2;
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// The webhook armed once the snapshot starts, and since when, waiting for it to end.
+static WEBHOOK: Mutex<Option<(Webhook, Instant)>> = Mutex::new(None);
+
+/// Where and how the outcome of the snapshot is posted.
+pub struct Webhook {
+    pub url: String,
+    /// Body to post instead of the default payload, with `{{name}}` placeholders for its fields.
+    pub template: Option<String>,
+    pub timeout: Duration,
+    pub target: PathBuf,
+    pub output: PathBuf,
+    /// Tells a snapshot which was stopped or timed out apart from one which failed.
+    pub cancel: Cancellation,
+}
+
+/// The outcome of a snapshot, as posted to the webhook.
+#[derive(Serialize)]
+struct Payload {
+    /// `succeeded`, `failed`, `cancelled` or `timed_out`.
+    status: &'static str,
+    exit_code: i32,
+    target: String,
+    output: String,
+    files: usize,
+    directories: usize,
+    symlinks: usize,
+    bytes_read: u64,
+    bytes_written: u64,
+    duration_ms: u64,
+    iterations: usize,
+    skipped: usize,
+    unstable: usize,
+    /// Warnings of every kind, added up.
+    warnings: usize,
+    error: Option<String>,
+}
+
+impl Payload {
+    /// Fills the template's placeholders with the payload's fields. Text is escaped to sit inside
+    /// a JSON string, and a missing error is left empty.
+    fn render(&self, template: &str) -> String {
+        let fields = match serde_json::to_value(self) {
+            Ok(serde_json::Value::Object(fields)) => fields,
+            _ => return template.to_string(),
+        };
+        let mut rendered = template.to_string();
+        for (name, value) in fields {
+            let value = match value {
+                serde_json::Value::String(text) => {
+                    let quoted = serde_json::Value::String(text).to_string();
+                    quoted[1..quoted.len() - 1].to_string()
+                }
+                serde_json::Value::Null => String::new(),
+                value => value.to_string(),
+            };
+            rendered = rendered.replace(&format!("{{{{{}}}}}", name), &value);
+        }
+        rendered
+    }
+}
+
+/// Arms the webhook, which `send` then posts to however the snapshot ends.
+pub fn arm(webhook: Webhook) {
+    *WEBHOOK.lock().unwrap() = Some((webhook, Instant::now()));
+}
+
+/// Posts the outcome of the snapshot to the armed webhook, if it hasn't been posted yet, retrying
+/// a couple of times. Failing to post only gets logged, so it never changes how the snapshot
+/// exits.
+pub fn send(status: i32, report: &SnapshotReport, error: Option<&str>) {
+    let armed = match WEBHOOK.lock() {
+        Ok(mut armed) => armed.take(),
+        Err(_) => return,
+    };
+    let Some((webhook, started)) = armed else {
+        return;
+    };
+    let payload = Payload {
+        status: if status == 0 {
+            "succeeded"
+        } else if webhook.cancel.timed_out() {
+            "timed_out"
+        } else if webhook.cancel.is_cancelled() {
+            "cancelled"
+        } else {
+            "failed"
+        },
+        exit_code: status,
+        target: webhook.target.display().to_string(),
+        output: webhook.output.display().to_string(),
+        files: report.files,
+        directories: report.directories,
+        symlinks: report.symlinks,
+        bytes_read: report.bytes_read,
+        bytes_written: report.bytes_written,
+        duration_ms: report::millis(started.elapsed()),
+        iterations: report.iterations,
+        skipped: report.skipped.len(),
+        unstable: report.unstable.len(),
+        warnings: report.warnings.total(),
+        error: error.map(str::to_string),
+    };
+    let body = match &webhook.template {
+        Some(template) => payload.render(template),
+        None => match serde_json::to_string(&payload) {
+            Ok(body) => body,
+            Err(err) => {
+                log::warn!("Failed to serialize the webhook payload: {}", err);
+                return;
+            }
+        },
+    };
+
+    let agent = ureq::AgentBuilder::new().timeout(webhook.timeout).build();
+    for attempt in 0..=RETRIES {
+        if attempt > 0 {
+            std::thread::sleep(RETRY_DELAY * attempt);
+        }
+        let posted = agent
+            .post(&webhook.url)
+            .set("Content-Type", "application/json")
+            .send_string(&body);
+        match posted {
+            Ok(response) => {
+                log::info!(
+                    "Posted the outcome to the webhook, which answered {}",
+                    response.status()
+                );
+                return;
+            }
+            // the request itself is wrong, so sending it again won't help
+            Err(ureq::Error::Status(code, _)) if code < 500 && code != 429 => {
+                log::warn!("Webhook refused the outcome with {}", code);
+                return;
+            }
+            Err(err) if attempt < RETRIES => {
+                log::info!("Failed to post to the webhook, retrying: {}", err)
+            }
+            Err(err) => log::warn!(
+                "Failed to post to the webhook after {} attempts: {}",
+                RETRIES + 1,
+                err
+            ),
+        }
+    }
+}
@@ -0,0 +1,363 @@
+use std::borrow::Cow;
+use std::fs::{File, Metadata};
+use std::io::{BufReader, BufWriter, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+
+use clap::ValueEnum;
+use zip::extra_fields::ExtraField;
+use zip::write::FullFileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+use crate::archiver::{self, path_bytes, ArchiveSink, ArchivedEntry, TarOptions};
+use crate::dictionary;
+use crate::manifest::Manifest;
+use crate::processor::{Digest, Entry};
+use crate::provenance::Provenance;
+use crate::report::SnapshotReport;
+use crate::restorer::{apply_metadata, clear_path, create_parent, create_symlink, safe_join};
+
+/// Signatures a zip archive starts with: that of its first entry, or that of the end of its
+/// central directory when it has no entries at all.
+const ZIP_MAGICS: [&[u8; 4]; 2] = [b"PK\x03\x04", b"PK\x05\x06"];
+
+/// Id of the extra field holding the mtime of an entry as a unix timestamp, which `unzip` and
+/// Info-ZIP's `zip` read and write. The DOS time every entry has is local, and only to the even
+/// second.
+const EXTENDED_TIMESTAMP_ID: u16 = 0x5455;
+
+/// How every file in a zip archive is compressed, each on its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum ZipCompression {
+    /// Deflate, which every zip tool reads, Windows Explorer among them.
+    #[default]
+    Deflate,
+    /// zstd at the snapshot's compression level, which newer tools read.
+    Zstd,
+}
+
+/// Whether the path names a zip archive, by `.zip`.
+pub fn is_zip(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension == "zip")
+}
+
+/// Whether the file at the path is a zip archive, by the signature it starts with rather than by
+/// its name.
+pub fn is_zip_archive(path: &Path) -> std::io::Result<bool> {
+    let mut magic = [0; 4];
+    match File::open(path)?.read_exact(&mut magic) {
+        Ok(_) => Ok(ZIP_MAGICS.contains(&&magic)),
+        Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+/// Writes the captured entries into a zip archive, with every file compressed on its own as
+/// asked, the same way `create_tarball` writes a tarball: beside its final path, only renamed
+/// into place once it's complete and synced. Entries keep the leading directory standing for the
+/// target, so extracting the archive gives a directory of the target's name.
+pub fn create_zip<P1: AsRef<Path>, P2: AsRef<Path>>(
+    origin: P1,
+    entries: impl IntoIterator<Item = Entry>,
+    zip_path: P2,
+    compression: ZipCompression,
+    options: &TarOptions,
+    report: &mut SnapshotReport,
+) -> std::io::Result<(Vec<ArchivedEntry>, Manifest)> {
+    let origin = origin.as_ref();
+    let zip_path = zip_path.as_ref();
+    log::info!("Creating zip archive at {}", zip_path.display());
+    let mut temp_name = zip_path.as_os_str().to_owned();
+    temp_name.push(format!(".tmp-{}", std::process::id()));
+    let temp_path = PathBuf::from(temp_name);
+
+    let written = archiver::create_file(&temp_path, options.output_mode)
+        .and_then(|file| {
+            let sink = ZipSink::new(BufWriter::new(file), compression, options);
+            let written = archiver::write_archive(origin, entries, sink, options, None, report)?;
+            let file = written
+                .output
+                .into_inner()
+                .map_err(|err| err.into_error())?;
+            file.sync_all()?;
+            report.bytes_written = file.metadata()?.len();
+            Ok((written.archived, written.manifest))
+        })
+        .and_then(|written| {
+            std::fs::rename(&temp_path, zip_path)?;
+            archiver::sync_parent(zip_path)?;
+            Ok(written)
+        });
+    if written.is_err() {
+        if let Err(err) = std::fs::remove_file(&temp_path) {
+            log::debug!("Failed to remove {}: {}", temp_path.display(), err);
+        }
+    }
+    written
+}
+
+/// Writes entries into a zip archive, decompressing every file and compressing it again the way
+/// zip tools expect. The format has no hard links, so every file linked to another is stored
+/// with a copy of its content.
+pub struct ZipSink<W: Write + Seek> {
+    writer: ZipWriter<W>,
+    compression: ZipCompression,
+    options: TarOptions,
+}
+
+impl<W: Write + Seek> ZipSink<W> {
+    pub fn new(writer: W, compression: ZipCompression, options: &TarOptions) -> Self {
+        Self {
+            writer: ZipWriter::new(writer),
+            compression,
+            options: options.clone(),
+        }
+    }
+
+    /// The options an entry with the permissions and mtime is written with.
+    fn entry_options(
+        &self,
+        mode: u32,
+        mtime: u64,
+    ) -> std::io::Result<FullFileOptions<'static, 'static>> {
+        let (method, level) = match self.compression {
+            ZipCompression::Deflate => (CompressionMethod::Deflated, None),
+            ZipCompression::Zstd => (
+                CompressionMethod::Zstd,
+                Some(i64::from(self.options.compression_level)),
+            ),
+        };
+        // times outside what the DOS time holds are left at its earliest, 1980
+        let dos_time = chrono::DateTime::from_timestamp(mtime as i64, 0)
+            .and_then(|time| zip::DateTime::try_from(time.naive_utc()).ok())
+            .unwrap_or_default();
+        let mut options = FullFileOptions::default()
+            .compression_method(method)
+            .compression_level(level)
+            .unix_permissions(mode)
+            .last_modified_time(dos_time);
+        let mut timestamp = vec![1];
+        timestamp.extend_from_slice(&(mtime.min(u64::from(u32::MAX)) as u32).to_le_bytes());
+        options.add_extra_field(EXTENDED_TIMESTAMP_ID, timestamp, false)?;
+        Ok(options)
+    }
+}
+
+impl<W: Write + Seek> ArchiveSink for ZipSink<W> {
+    type Output = W;
+    type Error = std::io::Error;
+
+    fn add_file(
+        &mut self,
+        metadata: &Metadata,
+        relative_path: &Path,
+        size: u64,
+        _digest: Option<&Digest>,
+        encoded_size: u64,
+        encoded: &mut dyn Read,
+    ) -> std::io::Result<()> {
+        let (mode, mtime) = attributes_of(metadata, self.options.file_mode);
+        let name = entry_name(relative_path);
+        log::debug!("New zip entry {} with size {}", name, size);
+        let options = self
+            .entry_options(mode, mtime)?
+            .large_file(size >= zip::ZIP64_BYTES_THR);
+        self.writer.start_file(name, options)?;
+        // files captured without their content hold no frame to decompress
+        if encoded_size > 0 {
+            let dictionary = self.options.dictionary.clone();
+            let mut decoder = dictionary::decoder(&mut *encoded, dictionary.as_ref())?;
+            let copied = std::io::copy(&mut decoder, &mut self.writer)?;
+            if copied != size {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!(
+                        "{} holds {} bytes but was captured with {}",
+                        relative_path.display(),
+                        copied,
+                        size
+                    ),
+                ));
+            }
+        }
+        std::io::copy(encoded, &mut std::io::sink())?;
+        Ok(())
+    }
+
+    fn add_dir(&mut self, metadata: &Metadata, relative_path: &Path) -> std::io::Result<()> {
+        let (mode, mtime) = attributes_of(metadata, self.options.dir_mode);
+        let name = entry_name(relative_path);
+        log::debug!("New zip directory {}", name);
+        let options = self.entry_options(mode, mtime)?;
+        self.writer.add_directory(name, options)?;
+        Ok(())
+    }
+
+    fn add_symlink(
+        &mut self,
+        metadata: &Metadata,
+        relative_path: &Path,
+        target: &Path,
+    ) -> std::io::Result<()> {
+        let (_, mtime) = attributes_of(metadata, None);
+        let name = entry_name(relative_path);
+        let target = path_bytes(target);
+        let target = String::from_utf8_lossy(&target);
+        log::debug!("New zip symlink {} -> {}", name, target);
+        let options = self.entry_options(0o777, mtime)?;
+        self.writer.add_symlink(name, target, options)?;
+        Ok(())
+    }
+
+    fn add_whiteout(&mut self, relative_path: &Path) -> std::io::Result<()> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            format!(
+                "zip archives can't record the removal of {}",
+                relative_path.display()
+            ),
+        ))
+    }
+
+    /// zip archives only hold the target, for tools other than saved_in_time to read, so the
+    /// records describing the snapshot are left out of them.
+    fn add_manifest(&mut self, _manifest: &Manifest) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn add_provenance(&mut self, _provenance: &Provenance) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    /// Ends the archive with its central directory, handing back the flushed writer.
+    fn finish(self) -> std::io::Result<W> {
+        let mut writer = self.writer.finish()?;
+        writer.flush()?;
+        Ok(writer)
+    }
+}
+
+/// The name a path is stored under, always with forward slashes. zip archives name their entries
+/// in UTF-8, so any other bytes are replaced.
+fn entry_name(relative_path: &Path) -> String {
+    let bytes = path_bytes(relative_path);
+    let name = String::from_utf8_lossy(&bytes);
+    if let Cow::Owned(_) = name {
+        log::warn!(
+            "Storing {} under the name {}, as zip archives only name entries in UTF-8",
+            relative_path.display(),
+            name
+        );
+    }
+    name.into_owned()
+}
+
+/// Extracts a zip archive produced by `create_zip` into the destination directory, giving every
+/// file and directory back the permissions and mtime it was captured with. The content of every
+/// file is checked against the CRC-32 stored with it as it's extracted.
+pub fn restore_zip<P1: AsRef<Path>, P2: AsRef<Path>>(
+    zip_path: P1,
+    destination: P2,
+) -> std::io::Result<usize> {
+    let zip_path = zip_path.as_ref();
+    let destination = destination.as_ref();
+    log::info!(
+        "Restoring zip archive {} into {}",
+        zip_path.display(),
+        destination.display()
+    );
+    let mut archive = ZipArchive::new(BufReader::new(File::open(zip_path)?))?;
+    let mut directories = Vec::new();
+    let mut restored = 0;
+    for index in 0..archive.len() {
+        let mut entry = archive.by_index(index)?;
+        let relative_path = entry.enclosed_name().ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "Archive entry escapes the destination: {}",
+                    String::from_utf8_lossy(entry.name_raw())
+                ),
+            )
+        })?;
+        let target_path = safe_join(destination, &relative_path)?;
+        let mtime = entry_mtime(&entry);
+
+        if entry.is_dir() {
+            log::debug!("Restoring directory {}", relative_path.display());
+            clear_path(&target_path)?;
+            std::fs::create_dir_all(&target_path)?;
+            directories.push((target_path, entry.unix_mode().unwrap_or(0o755), mtime));
+        } else if entry.is_symlink() {
+            let mut link = String::new();
+            entry.read_to_string(&mut link)?;
+            log::debug!("Restoring symlink {} -> {}", relative_path.display(), link);
+            create_parent(&target_path)?;
+            clear_path(&target_path)?;
+            create_symlink(
+                &Path::new(&link).components().collect::<PathBuf>(),
+                &target_path,
+            )?;
+        } else {
+            log::debug!("Restoring file {}", relative_path.display());
+            create_parent(&target_path)?;
+            clear_path(&target_path)?;
+            let mut writer = BufWriter::new(File::create(&target_path)?);
+            std::io::copy(&mut entry, &mut writer)?;
+            writer.flush()?;
+            apply_metadata(
+                &target_path,
+                entry.unix_mode().unwrap_or(0o644),
+                None,
+                mtime,
+            )?;
+            restored += 1;
+        }
+    }
+
+    // directories are finalized last so restoring their contents doesn't bump their mtimes
+    for (path, mode, mtime) in directories.into_iter().rev() {
+        apply_metadata(&path, mode, None, mtime)?;
+    }
+    Ok(restored)
+}
+
+/// The mtime of an entry, from its extended timestamp when it has one, or else from its DOS
+/// time, which `create_zip` writes in UTC.
+fn entry_mtime<R: Read>(entry: &zip::read::ZipFile<'_, R>) -> u64 {
+    let extended = entry.extra_data_fields().find_map(|field| match field {
+        ExtraField::ExtendedTimestamp(timestamp) => timestamp.mod_time(),
+        _ => None,
+    });
+    match extended {
+        Some(mtime) => u64::from(mtime),
+        None => entry
+            .last_modified()
+            .and_then(|time| chrono::NaiveDateTime::try_from(time).ok())
+            .map_or(0, |time| time.and_utc().timestamp().max(0) as u64),
+    }
+}
+
+/// The permissions and mtime an entry is written with.
+#[cfg(unix)]
+fn attributes_of(metadata: &Metadata, mode: Option<u32>) -> (u32, u64) {
+    use std::os::unix::fs::MetadataExt;
+    (
+        mode.unwrap_or(metadata.mode()),
+        metadata.mtime().max(0) as u64,
+    )
+}
+
+#[cfg(not(unix))]
+fn attributes_of(metadata: &Metadata, mode: Option<u32>) -> (u32, u64) {
+    let permissions = if metadata.permissions().readonly() {
+        0o555
+    } else {
+        0o755
+    };
+    let mtime = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(std::time::UNIX_EPOCH).ok())
+        .map_or(0, |since| since.as_secs());
+    (mode.unwrap_or(permissions), mtime)
+}
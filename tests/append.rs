@@ -0,0 +1,76 @@
+//! `--append` adds a snapshot to an existing archive, which restores as the latest one left it.
+mod common;
+
+use common::{code, files, restore, sit, snapshot, snapshot_records, write};
+
+#[test]
+fn appended_entries_take_precedence_on_restore() {
+    let root = tempfile::tempdir().unwrap();
+    let target = root.path().join("target");
+    let archive = root.path().join("snapshot.tar.zst");
+    write(&target.join("changed"), "before");
+    write(&target.join("unchanged"), "kept");
+    snapshot(&target, &archive, &[]);
+
+    write(&target.join("changed"), "after");
+    write(&target.join("added"), "new");
+    snapshot(&target, &archive, &["--append"]);
+
+    assert_eq!(snapshot_records(&archive).len(), 2);
+    let restored = tempfile::tempdir().unwrap();
+    restore(&archive, restored.path(), &[]);
+    let expected: Vec<(String, Vec<u8>)> = [
+        ("target/added", "new"),
+        ("target/changed", "after"),
+        ("target/unchanged", "kept"),
+    ]
+    .iter()
+    .map(|(path, content)| (path.to_string(), content.as_bytes().to_vec()))
+    .collect();
+    assert_eq!(
+        files(restored.path()).into_iter().collect::<Vec<_>>(),
+        expected
+    );
+}
+
+#[test]
+fn appending_creates_a_missing_archive() {
+    let root = tempfile::tempdir().unwrap();
+    let target = root.path().join("target");
+    let archive = root.path().join("snapshot.tar.zst");
+    write(&target.join("file"), "content");
+    snapshot(&target, &archive, &["--append"]);
+
+    assert_eq!(snapshot_records(&archive).len(), 1);
+    let restored = tempfile::tempdir().unwrap();
+    restore(&archive, restored.path(), &[]);
+    assert_eq!(
+        std::fs::read(restored.path().join("target/file")).unwrap(),
+        b"content"
+    );
+}
+
+#[test]
+fn archives_compressed_as_a_whole_are_left_alone() {
+    let root = tempfile::tempdir().unwrap();
+    let target = root.path().join("target");
+    let archive = root.path().join("snapshot.tar.zst");
+    write(&target.join("file"), "content");
+    let compressed = zstd::encode_all(&b"not a tarball of ours"[..], 0).unwrap();
+    std::fs::write(&archive, &compressed).unwrap();
+
+    let output = sit([
+        "-t".as_ref(),
+        target.as_os_str(),
+        "-o".as_ref(),
+        archive.as_os_str(),
+        "--append".as_ref(),
+    ]);
+    assert_ne!(code(&output), 0);
+    assert!(
+        String::from_utf8_lossy(&output.stdout).contains("compressed as a whole stream"),
+        "{}",
+        String::from_utf8_lossy(&output.stdout)
+    );
+    assert_eq!(std::fs::read(&archive).unwrap(), compressed);
+}
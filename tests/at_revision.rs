@@ -0,0 +1,121 @@
+//! `--at-revision` stamps a snapshot with a given instant, leaving out files modified after it.
+
+mod common;
+
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use common::{code, sit, snapshot, snapshot_records, tar_members, write};
+
+/// The revision the tests take snapshots at.
+const REVISION: &str = "2023-11-14T22:13:20+00:00";
+
+fn set_modified(path: &Path, modified: SystemTime) {
+    std::fs::File::open(path)
+        .unwrap()
+        .set_modified(modified)
+        .unwrap();
+}
+
+/// Writes a target holding `before`, modified ahead of the revision, and `after`, modified since.
+fn target(root: &Path) -> std::path::PathBuf {
+    let target = root.join("target");
+    let revision = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    write(&target.join("before"), "before");
+    set_modified(&target.join("before"), revision - Duration::from_secs(1));
+    write(&target.join("after"), "after");
+    target
+}
+
+#[test]
+fn snapshots_leave_out_files_modified_after_the_revision_and_carry_its_stamp() {
+    let root = tempfile::tempdir().unwrap();
+    let target = target(root.path());
+    let (archive, report) = (
+        root.path().join("snapshot.tar.zst"),
+        root.path().join("report.json"),
+    );
+    snapshot(
+        &target,
+        &archive,
+        &[
+            "--at-revision",
+            REVISION,
+            "--report",
+            report.to_str().unwrap(),
+        ],
+    );
+
+    let members: Vec<String> = tar_members(&archive)
+        .into_iter()
+        .map(|(path, _)| path)
+        .collect();
+    assert_eq!(members, ["target/", "target/before"]);
+    let report: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(&report).unwrap()).unwrap();
+    assert_eq!(report["after_revision"], 1);
+    assert_eq!(report["timestamp"], REVISION);
+    assert!(report["id"]
+        .as_str()
+        .unwrap()
+        .starts_with("20231114T221320Z-"));
+    let records = snapshot_records(&archive);
+    assert_eq!(records[0]["revision"], REVISION);
+    assert_eq!(records[0]["id"], report["id"]);
+}
+
+#[test]
+fn incremental_snapshots_at_a_revision_keep_what_the_last_one_captured() {
+    let root = tempfile::tempdir().unwrap();
+    let target = target(root.path());
+    let (full, incremental, manifest) = (
+        root.path().join("full.tar.zst"),
+        root.path().join("incremental.tar.zst"),
+        root.path().join("manifest.json"),
+    );
+    snapshot(&target, &full, &["--manifest", manifest.to_str().unwrap()]);
+    snapshot(
+        &target,
+        &incremental,
+        &[
+            "--since-manifest",
+            manifest.to_str().unwrap(),
+            "--at-revision",
+            REVISION,
+        ],
+    );
+
+    // modified since the revision, `after` is left out without being taken as removed
+    let members: Vec<String> = tar_members(&incremental)
+        .into_iter()
+        .map(|(path, _)| path)
+        .collect();
+    assert!(
+        !members.iter().any(|path| path.contains(".wh.")),
+        "{:?}",
+        members
+    );
+    assert!(!members.contains(&"target/after".to_string()));
+}
+
+#[test]
+fn revisions_further_ahead_than_the_slack_are_refused() {
+    let root = tempfile::tempdir().unwrap();
+    let target = target(root.path());
+    let archive = root.path().join("snapshot.tar.zst");
+    let instant = |ahead: &str| {
+        sit([
+            "-t".as_ref(),
+            target.as_os_str(),
+            "-o".as_ref(),
+            archive.as_os_str(),
+            "--at-revision".as_ref(),
+            ahead.as_ref(),
+        ])
+    };
+
+    assert_eq!(code(&instant("2099-01-01T00:00:00Z")), 15);
+    assert!(!archive.exists());
+    let soon = chrono::Utc::now() + chrono::Duration::seconds(30);
+    assert_eq!(code(&instant(&soon.to_rfc3339())), 0);
+}
@@ -0,0 +1,45 @@
+//! `--batch-small-files` compresses the small files of every directory together.
+
+mod common;
+
+use common::{files, restore, snapshot, write};
+
+#[test]
+fn batching_many_tiny_files_shrinks_the_archive_and_restores_them_all() {
+    let target = tempfile::tempdir().unwrap();
+    for index in 0..10_000 {
+        let path = format!("dir{}/file{}", index % 10, index);
+        write(
+            &target.path().join(path),
+            format!("tiny file number {}\n", index),
+        );
+    }
+    let output = tempfile::tempdir().unwrap();
+    let (plain, batched) = (
+        output.path().join("plain.tar.zst"),
+        output.path().join("batched.tar.zst"),
+    );
+    snapshot(target.path(), &plain, &[]);
+    snapshot(target.path(), &batched, &["--batch-small-files", "4096"]);
+
+    let (plain_size, batched_size) = (
+        plain.metadata().unwrap().len(),
+        batched.metadata().unwrap().len(),
+    );
+    // every file of its own costs at least a tar header; batched, a directory's worth share one
+    assert!(
+        batched_size * 4 < plain_size,
+        "batched {} bytes, unbatched {} bytes",
+        batched_size,
+        plain_size
+    );
+
+    let restored = tempfile::tempdir().unwrap();
+    restore(&batched, restored.path(), &[]);
+    let name = target.path().file_name().unwrap().to_str().unwrap();
+    assert_eq!(
+        files(&restored.path().join(name)),
+        files(target.path()),
+        "the batched archive restored different files"
+    );
+}
@@ -0,0 +1,95 @@
+//! A walk killed partway through resumes from the captures `--checkpoint` kept.
+#![cfg(unix)]
+
+mod common;
+
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant, SystemTime};
+
+use common::{files, restore, write};
+
+fn set_modified(path: &Path, modified: SystemTime) {
+    std::fs::File::options()
+        .write(true)
+        .open(path)
+        .unwrap()
+        .set_modified(modified)
+        .unwrap();
+}
+
+#[test]
+fn a_killed_walk_resumes_from_its_captures() {
+    let root = tempfile::tempdir().unwrap();
+    let target = root.path().join("target");
+    let old = SystemTime::now() - Duration::from_secs(2 * 3600);
+    for path in ["a", "sub/b"] {
+        write(&target.join(path), path);
+        set_modified(&target.join(path), old);
+    }
+    // within the slack, so it takes another pass, which waits for it to settle first
+    write(&target.join("young"), "young");
+    let (archive, checkpoint) = (
+        root.path().join("out.tar.zst"),
+        root.path().join("checkpoint"),
+    );
+
+    let mut walk = Command::new(env!("CARGO_BIN_EXE_saved_in_time"))
+        .args(["--log-level", "error", "-t"])
+        .arg(&target)
+        .arg("-o")
+        .arg(&archive)
+        .arg("--checkpoint")
+        .arg(&checkpoint)
+        .args(["--mtime-slack", "1h", "--settle", "1h"])
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    // the first pass is checkpointed before the walk waits out the settle window
+    let deadline = Instant::now() + Duration::from_secs(30);
+    while !checkpoint.join("index.json").exists() {
+        assert!(
+            Instant::now() < deadline,
+            "the first pass was never checkpointed"
+        );
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    assert!(
+        walk.try_wait().unwrap().is_none(),
+        "the walk ended by itself"
+    );
+    walk.kill().unwrap();
+    assert_eq!(walk.wait().unwrap().code(), None);
+    assert!(!archive.exists());
+
+    // the same size and mtime, so only a capture kept from before the kill holds the old content
+    write(&target.join("a"), "A");
+    set_modified(&target.join("a"), old);
+    let resumed = Command::new(env!("CARGO_BIN_EXE_saved_in_time"))
+        .args(["--log-level", "debug", "-t"])
+        .arg(&target)
+        .arg("-o")
+        .arg(&archive)
+        .arg("--checkpoint")
+        .arg(&checkpoint)
+        .args(["--resume", "--mtime-slack", "0s"])
+        .output()
+        .unwrap();
+    let log = String::from_utf8_lossy(&resumed.stdout);
+    assert!(resumed.status.success(), "{}", log);
+    for path in ["a", "sub/b"] {
+        let resumed = format!(
+            "Resuming capture of {} from checkpoint",
+            target.join(path).display()
+        );
+        assert!(log.contains(&resumed), "{}", log);
+    }
+    assert!(!checkpoint.exists());
+
+    let restored = tempfile::tempdir().unwrap();
+    restore(&archive, restored.path(), &[]);
+    let restored = files(&restored.path().join("target"));
+    assert_eq!(restored["a"], b"a");
+    assert_eq!(restored["sub/b"], b"sub/b");
+    assert_eq!(restored["young"], b"young");
+}
@@ -0,0 +1,81 @@
+//! `--chmod-files` and `--chmod-dirs` record one mode for every file or directory.
+#![cfg(unix)]
+
+mod common;
+
+use std::collections::BTreeMap;
+use std::fs::Permissions;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::Path;
+
+use common::{restore, snapshot, write};
+
+/// The permission bits of everything beneath the directory but symlinks, keyed by path.
+fn modes(root: &Path) -> BTreeMap<String, u32> {
+    let mut modes = BTreeMap::new();
+    for path in ["", "script", "private", "sub", "sub/locked", "sub/file"] {
+        modes.insert(
+            path.to_string(),
+            root.join(path).symlink_metadata().unwrap().mode() & 0o7777,
+        );
+    }
+    modes
+}
+
+#[test]
+fn every_file_and_directory_restores_with_the_mode_given() {
+    let root = tempfile::tempdir().unwrap();
+    let target = root.path().join("target");
+    write(&target.join("script"), "#!/bin/sh\n");
+    write(&target.join("private"), "secret");
+    write(&target.join("sub/locked/file"), "locked away");
+    write(&target.join("sub/file"), "content");
+    std::os::unix::fs::symlink("script", target.join("link")).unwrap();
+    for (path, mode) in [
+        ("script", 0o755),
+        ("private", 0o600),
+        ("sub/locked", 0o700),
+        ("sub/file", 0o644),
+    ] {
+        std::fs::set_permissions(target.join(path), Permissions::from_mode(mode)).unwrap();
+    }
+    let output = tempfile::tempdir().unwrap();
+
+    let as_captured = output.path().join("as-captured.tar.zst");
+    snapshot(&target, &as_captured, &[]);
+    let normalized = output.path().join("normalized.tar.zst");
+    snapshot(
+        &target,
+        &normalized,
+        &["--chmod-files", "640", "--chmod-dirs", "750"],
+    );
+
+    let restored = tempfile::tempdir().unwrap();
+    restore(&as_captured, restored.path(), &[]);
+    assert_eq!(modes(&restored.path().join("target")), modes(&target));
+
+    let restored = tempfile::tempdir().unwrap();
+    restore(&normalized, restored.path(), &[]);
+    let restored = restored.path().join("target");
+    let expected: BTreeMap<String, u32> = [
+        ("", 0o750),
+        ("script", 0o640),
+        ("private", 0o640),
+        ("sub", 0o750),
+        ("sub/locked", 0o750),
+        ("sub/file", 0o640),
+    ]
+    .into_iter()
+    .map(|(path, mode)| (path.to_string(), mode))
+    .collect();
+    assert_eq!(modes(&restored), expected);
+    // the content is left as it was, and symlinks have no mode of their own to change
+    assert_eq!(
+        std::fs::read_to_string(restored.join("sub/locked/file")).unwrap(),
+        "locked away"
+    );
+    assert_eq!(
+        std::fs::read_link(restored.join("link")).unwrap(),
+        Path::new("script")
+    );
+}
@@ -0,0 +1,230 @@
+#![allow(dead_code)]
+
+use std::collections::BTreeMap;
+#[cfg(unix)]
+use std::collections::BTreeSet;
+use std::ffi::OsString;
+use std::fs::File;
+#[cfg(unix)]
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::Path;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+
+/// Name of the record describing the snapshot, which differs between otherwise identical runs.
+pub const SNAPSHOT_RECORD: &str = ".sit-snapshot.json";
+
+/// The binary, set to log only errors.
+pub fn command() -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_saved_in_time"));
+    command.args(["--log-level", "error"]);
+    command
+}
+
+/// Runs the binary with the arguments, logging only errors.
+pub fn sit<I, S>(args: I) -> Output
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<std::ffi::OsStr>,
+{
+    command()
+        .args(args)
+        .output()
+        .expect("failed to run saved_in_time")
+}
+
+/// The code the binary exited with.
+pub fn code(output: &Output) -> i32 {
+    output.status.code().expect("saved_in_time was killed")
+}
+
+/// Runs the binary with the arguments, failing the test unless it succeeds.
+pub fn sit_ok<I, S>(args: I) -> Output
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<std::ffi::OsStr>,
+{
+    let output = sit(args);
+    assert_eq!(
+        code(&output),
+        0,
+        "stdout: {}\nstderr: {}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+    output
+}
+
+/// Snapshots the target into the archive with the extra arguments, failing the test unless the
+/// snapshot succeeds.
+pub fn snapshot(target: &Path, archive: &Path, extra: &[&str]) -> Output {
+    let mut args: Vec<OsString> = vec!["-t".into(), target.into(), "-o".into(), archive.into()];
+    args.extend(extra.iter().map(OsString::from));
+    sit_ok(args)
+}
+
+/// Restores the archive into the directory with the extra arguments, failing the test unless the
+/// restore succeeds.
+pub fn restore(archive: &Path, into: &Path, extra: &[&str]) -> Output {
+    let mut args: Vec<OsString> = vec![
+        "restore".into(),
+        archive.into(),
+        "--into".into(),
+        into.into(),
+    ];
+    args.extend(extra.iter().map(OsString::from));
+    sit_ok(args)
+}
+
+/// The content of every file beneath the directory, keyed by its path relative to it.
+pub fn files(root: &Path) -> BTreeMap<String, Vec<u8>> {
+    let mut files = BTreeMap::new();
+    let mut pending = vec![root.to_path_buf()];
+    while let Some(directory) = pending.pop() {
+        for entry in std::fs::read_dir(&directory).unwrap() {
+            let path = entry.unwrap().path();
+            let metadata = path.symlink_metadata().unwrap();
+            if metadata.is_dir() {
+                pending.push(path);
+            } else if metadata.is_file() {
+                let relative = path.strip_prefix(root).unwrap();
+                files.insert(
+                    relative.to_string_lossy().into_owned(),
+                    std::fs::read(&path).unwrap(),
+                );
+            }
+        }
+    }
+    files
+}
+
+/// Writes a file, creating the directories leading to it.
+pub fn write(path: &Path, contents: impl AsRef<[u8]>) {
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    std::fs::write(path, contents).unwrap();
+}
+
+/// Every member of a tarball in order, as its path and raw content, leaving out the snapshot
+/// record.
+pub fn tar_members(archive: &Path) -> Vec<(String, Vec<u8>)> {
+    let mut tarball = tar::Archive::new(File::open(archive).unwrap());
+    let mut members = Vec::new();
+    for member in tarball.entries().unwrap() {
+        let mut member = member.unwrap();
+        let path = member.path().unwrap().to_string_lossy().into_owned();
+        if path == SNAPSHOT_RECORD {
+            continue;
+        }
+        let mut content = Vec::new();
+        std::io::Read::read_to_end(&mut member, &mut content).unwrap();
+        members.push((path, content));
+    }
+    members
+}
+
+/// The record of every snapshot written to the tarball, in the order they were written.
+pub fn snapshot_records(archive: &Path) -> Vec<serde_json::Value> {
+    let mut tarball = tar::Archive::new(File::open(archive).unwrap());
+    let mut records = Vec::new();
+    for member in tarball.entries().unwrap() {
+        let member = member.unwrap();
+        if member.path().unwrap() == Path::new(SNAPSHOT_RECORD) {
+            records.push(serde_json::from_reader(member).unwrap());
+        }
+    }
+    records
+}
+
+/// Whether the tool runs from `PATH`, so tests extracting with it can be skipped without it.
+pub fn installed(tool: &str) -> bool {
+    let found = Command::new(tool)
+        .arg("--help")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok();
+    if !found {
+        eprintln!("{} isn't installed; skipping", tool);
+    }
+    found
+}
+
+/// Writes a target holding what the archive formats store: nested and empty directories,
+/// files with different modes, one larger than a few blocks, an empty one, a symlink and a
+/// hard link.
+#[cfg(unix)]
+pub fn write_fixture(target: &Path) {
+    write(&target.join("file"), "content");
+    write(&target.join("nested/deeper/file"), "nested content");
+    write(&target.join("empty"), "");
+    let large: Vec<u8> = (0..300_000u32).map(|index| (index % 251) as u8).collect();
+    write(&target.join("large"), large);
+    write(&target.join("script"), "#!/bin/sh\n");
+    std::fs::set_permissions(target.join("script"), PermissionsExt::from_mode(0o755)).unwrap();
+    write(&target.join("private"), "secret");
+    std::fs::set_permissions(target.join("private"), PermissionsExt::from_mode(0o600)).unwrap();
+    std::fs::create_dir(target.join("hollow")).unwrap();
+    std::os::unix::fs::symlink("nested/deeper/file", target.join("link")).unwrap();
+    std::fs::hard_link(target.join("file"), target.join("nested/hard")).unwrap();
+}
+
+/// Every path under the directory, relative to it.
+#[cfg(unix)]
+fn walk(root: &Path) -> BTreeSet<PathBuf> {
+    let mut paths = BTreeSet::new();
+    let mut pending = vec![root.to_path_buf()];
+    while let Some(directory) = pending.pop() {
+        for entry in std::fs::read_dir(&directory).unwrap() {
+            let path = entry.unwrap().path();
+            if path.symlink_metadata().unwrap().is_dir() {
+                pending.push(path.clone());
+            }
+            paths.insert(path.strip_prefix(root).unwrap().to_path_buf());
+        }
+    }
+    paths
+}
+
+/// Asserts what a tool extracted from an archive matches the target it was written from:
+/// the same paths, file content, modes and modification times, symlinks and hard links.
+#[cfg(unix)]
+pub fn assert_extracted(target: &Path, extracted: &Path) {
+    let paths = walk(target);
+    assert_eq!(walk(extracted), paths);
+    for path in &paths {
+        let (original, copy) = (target.join(path), extracted.join(path));
+        let (expected, actual) = (
+            original.symlink_metadata().unwrap(),
+            copy.symlink_metadata().unwrap(),
+        );
+        assert_eq!(
+            actual.file_type(),
+            expected.file_type(),
+            "{}",
+            path.display()
+        );
+        if expected.is_symlink() {
+            assert_eq!(
+                std::fs::read_link(&copy).unwrap(),
+                std::fs::read_link(&original).unwrap()
+            );
+            continue;
+        }
+        assert_eq!(actual.mode(), expected.mode(), "{}", path.display());
+        if expected.is_file() {
+            assert_eq!(
+                std::fs::read(&copy).unwrap(),
+                std::fs::read(&original).unwrap(),
+                "{}",
+                path.display()
+            );
+            assert_eq!(actual.mtime(), expected.mtime(), "{}", path.display());
+        }
+    }
+    let (file, hard) = (
+        extracted.join("file").metadata().unwrap(),
+        extracted.join("nested/hard").metadata().unwrap(),
+    );
+    assert_eq!((file.dev(), file.ino()), (hard.dev(), hard.ino()));
+}
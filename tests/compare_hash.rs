@@ -0,0 +1,77 @@
+//! `--compare-hash` takes files which look as they did when last hashed to be unchanged, unread.
+mod common;
+
+use std::fs::File;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use common::{snapshot, tar_members, write};
+
+/// Dates the paths well before the snapshot, so none is modified recently enough to be confirmed
+/// by another pass and read twice. Always the same date, so a rewrite can leave the mtime as it was.
+fn age(paths: &[&Path]) {
+    let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    for path in paths {
+        File::open(path).unwrap().set_modified(modified).unwrap();
+    }
+}
+
+/// The files archived, relative to the target, and the bytes read capturing them.
+fn incremental(root: &Path, name: &str, extra: &[&str]) -> (Vec<String>, u64) {
+    let (archive, report) = (
+        root.join(format!("{}.tar.zst", name)),
+        root.join(format!("{}.json", name)),
+    );
+    let since = root.join("full.json");
+    let mut args = vec![
+        "--since-manifest",
+        since.to_str().unwrap(),
+        "--report",
+        report.to_str().unwrap(),
+    ];
+    args.extend(extra);
+    snapshot(&root.join("target"), &archive, &args);
+    let archived = tar_members(&archive)
+        .into_iter()
+        .filter(|(_, content)| !content.is_empty())
+        .map(|(path, _)| path)
+        .collect();
+    let report: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(report).unwrap()).unwrap();
+    (archived, report["bytes_read"].as_u64().unwrap())
+}
+
+#[test]
+fn cached_files_which_look_unchanged_are_not_read_again() {
+    let root = tempfile::tempdir().unwrap();
+    let target = root.path().join("target");
+    write(&target.join("a"), "aaaa");
+    write(&target.join("b"), "bbbb");
+    age(&[&target.join("a"), &target.join("b"), &target]);
+    let cache = root.path().join("hashes.json");
+    let cache = cache.to_str().unwrap();
+    snapshot(
+        &target,
+        &root.path().join("full.tar.zst"),
+        &[
+            "--manifest",
+            root.path().join("full.json").to_str().unwrap(),
+        ],
+    );
+    // without a cache yet, every file is read and hashed once more to fill it
+    let (archived, bytes_read) = incremental(root.path(), "filling", &["--compare-hash", cache]);
+    assert_eq!((archived.len(), bytes_read), (0, 8));
+    assert!(Path::new(cache).exists());
+
+    // a rewrite leaving the size and mtime as they were is only found by reading the file
+    write(&target.join("b"), "BBBB");
+    write(&target.join("c"), "cc");
+    age(&[&target.join("b"), &target.join("c"), &target]);
+
+    let (archived, bytes_read) = incremental(root.path(), "read", &[]);
+    assert_eq!(archived, ["target/b", "target/c"]);
+    assert_eq!(bytes_read, 10);
+    let (archived, bytes_read) = incremental(root.path(), "cached", &["--compare-hash", cache]);
+    assert_eq!(archived, ["target/c"]);
+    assert_eq!(bytes_read, 2);
+}
@@ -0,0 +1,112 @@
+//! Manifests record how far every captured file compressed, and at which level, and the report
+//! adds them up.
+#![cfg(unix)]
+
+mod common;
+
+use std::collections::BTreeMap;
+
+use common::{snapshot, tar_members, write};
+
+/// Snapshots a target holding a repetitive log and some noise, the noise at level 1, returning
+/// the manifest and report as JSON along with the members of the archive.
+fn snapshot_compressed(
+    extra: &[&str],
+) -> (serde_json::Value, serde_json::Value, Vec<(String, Vec<u8>)>) {
+    let root = tempfile::tempdir().unwrap();
+    let target = root.path().join("target");
+    write(&target.join("app.log"), "the same line\n".repeat(10_000));
+    let noise: Vec<u8> = (0..5_000u32)
+        .map(|index| (index.wrapping_mul(2_654_435_761) >> 13) as u8)
+        .collect();
+    write(&target.join("noise.bin"), noise);
+    std::os::unix::fs::symlink("app.log", target.join("link")).unwrap();
+    let (archive, manifest, report) = (
+        root.path().join("snapshot.tar"),
+        root.path().join("manifest.json"),
+        root.path().join("report.json"),
+    );
+    let mut args = vec![
+        "--level-for",
+        "*.bin=1",
+        "--manifest",
+        manifest.to_str().unwrap(),
+        "--report",
+        report.to_str().unwrap(),
+    ];
+    args.extend(extra);
+    snapshot(&target, &archive, &args);
+    let read = |path| serde_json::from_slice(&std::fs::read(path).unwrap()).unwrap();
+    (read(&manifest), read(&report), tar_members(&archive))
+}
+
+#[test]
+fn captured_files_carry_the_size_of_their_capture_and_its_level() {
+    let (manifest, report, members) = snapshot_compressed(&[]);
+    let captured: BTreeMap<String, usize> = members
+        .into_iter()
+        .map(|(path, content)| (path, content.len()))
+        .collect();
+
+    let entries = manifest["entries"].as_array().unwrap();
+    let recorded: Vec<(&str, Option<u64>, Option<i64>)> = entries
+        .iter()
+        .map(|entry| {
+            (
+                entry["path"].as_str().unwrap(),
+                entry["compressed_size"].as_u64(),
+                entry["level"].as_i64(),
+            )
+        })
+        .collect();
+    let size = |path: &str| Some(captured[path] as u64);
+    // only files have their content captured
+    assert_eq!(
+        recorded,
+        [
+            ("target/", None, None),
+            ("target/app.log", size("target/app.log"), Some(3)),
+            ("target/link", None, None),
+            ("target/noise.bin", size("target/noise.bin"), Some(1)),
+        ]
+    );
+    assert!(captured["target/app.log"] < 1_000);
+
+    let compression = &report["compression"];
+    assert_eq!(compression["files"], 2);
+    assert_eq!(compression["original_bytes"], 140_000 + 5_000);
+    assert_eq!(
+        compression["compressed_bytes"],
+        captured["target/app.log"] + captured["target/noise.bin"]
+    );
+    let levels: Vec<(i64, u64, u64)> = compression["levels"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|level| {
+            (
+                level["level"].as_i64().unwrap(),
+                level["original_bytes"].as_u64().unwrap(),
+                level["compressed_bytes"].as_u64().unwrap(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        levels,
+        [
+            (1, 5_000, captured["target/noise.bin"] as u64),
+            (3, 140_000, captured["target/app.log"] as u64),
+        ]
+    );
+}
+
+#[test]
+fn metadata_only_snapshots_record_no_sizes() {
+    let (manifest, report, _) = snapshot_compressed(&["--metadata-only"]);
+
+    for entry in manifest["entries"].as_array().unwrap() {
+        assert!(entry.get("compressed_size").is_none(), "{}", entry);
+        assert!(entry.get("level").is_none(), "{}", entry);
+    }
+    assert_eq!(report["compression"]["files"], 0);
+}
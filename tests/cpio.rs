@@ -0,0 +1,91 @@
+//! Checks cpio archives extract with the system's `cpio`, skipping when it isn't installed.
+#![cfg(unix)]
+
+mod common;
+
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use common::{assert_extracted, code, installed, sit, write_fixture};
+
+/// Undoes the compression of an archive.
+type Decompress = fn(&[u8]) -> Vec<u8>;
+
+/// Extracts the uncompressed archive with `cpio -i` into the directory.
+fn extract(archive: &[u8], into: &Path) {
+    let mut cpio = Command::new("cpio")
+        .args(["-i", "-d", "-m"])
+        .current_dir(into)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    cpio.stdin.take().unwrap().write_all(archive).unwrap();
+    let output = cpio.wait_with_output().unwrap();
+    assert!(
+        output.status.success(),
+        "cpio failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+/// Snapshots the fixture into an archive named `name`, returning the target and its content
+/// once the decompression given undoes any compression.
+fn snapshot(name: &str, decompress: Decompress) -> (tempfile::TempDir, Vec<u8>) {
+    let target = tempfile::tempdir().unwrap();
+    write_fixture(target.path());
+    let output = tempfile::tempdir().unwrap();
+    let archive = output.path().join(name);
+    let run = sit([
+        "-t".as_ref(),
+        target.path().as_os_str(),
+        "-o".as_ref(),
+        archive.as_os_str(),
+    ]);
+    assert_eq!(
+        code(&run),
+        0,
+        "stderr: {}",
+        String::from_utf8_lossy(&run.stderr)
+    );
+    let content = decompress(&std::fs::read(&archive).unwrap());
+    (target, content)
+}
+
+#[test]
+fn an_archive_extracts_with_cpio() {
+    if !installed("cpio") {
+        return;
+    }
+    let (target, archive) = snapshot("snapshot.cpio", <[u8]>::to_vec);
+    let extracted = tempfile::tempdir().unwrap();
+    extract(&archive, extracted.path());
+    assert_extracted(target.path(), extracted.path());
+}
+
+fn gunzip(archive: &[u8]) -> Vec<u8> {
+    let mut content = Vec::new();
+    std::io::Read::read_to_end(&mut flate2::read::GzDecoder::new(archive), &mut content).unwrap();
+    content
+}
+
+fn unzstd(archive: &[u8]) -> Vec<u8> {
+    zstd::decode_all(archive).unwrap()
+}
+
+#[test]
+fn a_compressed_archive_extracts_with_cpio_once_decompressed() {
+    if !installed("cpio") {
+        return;
+    }
+    let decompressions: [(&str, Decompress); 2] =
+        [("snapshot.cpio.gz", gunzip), ("snapshot.cpio.zst", unzstd)];
+    for (name, decompress) in decompressions {
+        let (target, archive) = snapshot(name, decompress);
+        let extracted = tempfile::tempdir().unwrap();
+        extract(&archive, extracted.path());
+        assert_extracted(target.path(), extracted.path());
+    }
+}
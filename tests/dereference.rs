@@ -0,0 +1,86 @@
+//! `--dereference` captures what symlinks point to in place of the links.
+#![cfg(unix)]
+
+mod common;
+
+use std::ffi::OsString;
+use std::path::Path;
+
+use common::{restore, sit_ok, write};
+
+/// Snapshots the target and restores it, returning the report and the restored target.
+fn round_trip(target: &Path, extra: &[&str]) -> (serde_json::Value, tempfile::TempDir) {
+    let output = tempfile::tempdir().unwrap();
+    let (archive, report) = (
+        output.path().join("snapshot.tar.zst"),
+        output.path().join("report.json"),
+    );
+    let mut args: Vec<OsString> = vec![
+        "-t".into(),
+        target.into(),
+        "-o".into(),
+        archive.clone().into(),
+        "--report".into(),
+        report.clone().into(),
+    ];
+    args.extend(extra.iter().map(OsString::from));
+    sit_ok(args);
+    let restored = tempfile::tempdir().unwrap();
+    restore(&archive, restored.path(), &[]);
+    let report = serde_json::from_slice(&std::fs::read(report).unwrap()).unwrap();
+    (report, restored)
+}
+
+fn fixture() -> tempfile::TempDir {
+    let root = tempfile::tempdir().unwrap();
+    let target = root.path().join("target");
+    write(&target.join("file"), "pointed to");
+    write(&target.join("dir/nested"), "nested");
+    std::os::unix::fs::symlink("file", target.join("file-link")).unwrap();
+    std::os::unix::fs::symlink("dir", target.join("dir-link")).unwrap();
+    std::os::unix::fs::symlink("..", target.join("dir/loop")).unwrap();
+    root
+}
+
+#[test]
+fn symlinks_are_kept_as_links_by_default() {
+    let root = fixture();
+    let (_, restored) = round_trip(&root.path().join("target"), &[]);
+    let restored = restored.path().join("target");
+    for (link, points_to) in [
+        ("file-link", "file"),
+        ("dir-link", "dir"),
+        ("dir/loop", ".."),
+    ] {
+        assert_eq!(
+            std::fs::read_link(restored.join(link)).unwrap(),
+            Path::new(points_to)
+        );
+    }
+}
+
+#[test]
+fn dereferencing_captures_what_links_point_to_and_skips_loops() {
+    let root = fixture();
+    let (report, restored) = round_trip(&root.path().join("target"), &["--dereference"]);
+    let restored = restored.path().join("target");
+    for path in ["file-link", "dir-link", "dir-link/nested"] {
+        assert!(
+            !restored.join(path).symlink_metadata().unwrap().is_symlink(),
+            "{} was restored as a link",
+            path
+        );
+    }
+    assert_eq!(
+        std::fs::read_to_string(restored.join("file-link")).unwrap(),
+        "pointed to"
+    );
+    assert_eq!(
+        std::fs::read_to_string(restored.join("dir-link/nested")).unwrap(),
+        "nested"
+    );
+    // a link back into its own ancestry would expand forever
+    assert!(!restored.join("dir/loop").exists());
+    assert!(!restored.join("dir-link/loop").exists());
+    assert_eq!(report["warnings"]["symlink_loop"], 2, "{}", report);
+}
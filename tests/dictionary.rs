@@ -0,0 +1,120 @@
+//! `--train-dict` trains a zstd dictionary on the target, which `--dict` compresses snapshots with.
+mod common;
+
+use std::path::{Path, PathBuf};
+
+use common::{code, files, restore, sit, sit_ok, snapshot, write};
+
+/// Writes many small files which look alike, as player data does.
+fn players(target: &Path) {
+    for index in 0..200 {
+        write(
+            &target.join(format!("player{}.json", index)),
+            format!(
+                r#"{{"player":"p{}","health":20,"position":{{"x":{},"y":64,"z":{}}},"inventory":["sword","shield","bread"]}}"#,
+                index,
+                index,
+                index * 3
+            ),
+        );
+    }
+}
+
+/// Trains a dictionary on the target, writing it into `root`, and returns where it was written.
+fn train(target: &Path, root: &Path) -> PathBuf {
+    let dictionary = root.join("players.dict");
+    sit_ok([
+        "-t".as_ref(),
+        target.as_os_str(),
+        "-o".as_ref(),
+        root.join("unused.tar.zst").as_os_str(),
+        "--train-dict".as_ref(),
+        dictionary.as_os_str(),
+    ]);
+    assert!(dictionary.exists());
+    dictionary
+}
+
+/// Bytes the captured files took once compressed, as reported.
+fn compressed_bytes(report: &Path) -> u64 {
+    let report: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(report).unwrap()).unwrap();
+    report["compression"]["compressed_bytes"].as_u64().unwrap()
+}
+
+#[test]
+fn snapshots_compressed_with_a_trained_dictionary_are_smaller() {
+    let root = tempfile::tempdir().unwrap();
+    let target = root.path().join("target");
+    players(&target);
+    let dictionary = train(&target, root.path());
+    let dictionary = dictionary.to_str().unwrap();
+
+    let (plain, plain_report) = (
+        root.path().join("plain.tar.zst"),
+        root.path().join("plain.json"),
+    );
+    snapshot(
+        &target,
+        &plain,
+        &["--report", plain_report.to_str().unwrap()],
+    );
+    let (trained, trained_report) = (
+        root.path().join("trained.tar.zst"),
+        root.path().join("trained.json"),
+    );
+    snapshot(
+        &target,
+        &trained,
+        &[
+            "--dict",
+            dictionary,
+            "--report",
+            trained_report.to_str().unwrap(),
+        ],
+    );
+    let (plain_bytes, trained_bytes) = (
+        compressed_bytes(&plain_report),
+        compressed_bytes(&trained_report),
+    );
+    assert!(
+        trained_bytes * 2 < plain_bytes,
+        "{} bytes with the dictionary against {} without",
+        trained_bytes,
+        plain_bytes
+    );
+    assert!(trained.metadata().unwrap().len() <= plain.metadata().unwrap().len());
+
+    let restored = tempfile::tempdir().unwrap();
+    restore(&trained, restored.path(), &["--dict", dictionary]);
+    assert_eq!(
+        files(&restored.path().join("target")),
+        files(&target),
+        "restored files differ"
+    );
+}
+
+#[test]
+fn archives_compressed_with_a_dictionary_are_refused_without_it() {
+    let root = tempfile::tempdir().unwrap();
+    let target = root.path().join("target");
+    players(&target);
+    let dictionary = train(&target, root.path());
+    let archive = root.path().join("trained.tar.zst");
+    snapshot(&target, &archive, &["--dict", dictionary.to_str().unwrap()]);
+
+    let restored = root.path().join("restored");
+    let output = sit([
+        "restore".as_ref(),
+        archive.as_os_str(),
+        "--into".as_ref(),
+        restored.as_os_str(),
+    ]);
+    assert_ne!(code(&output), 0);
+    assert!(
+        String::from_utf8_lossy(&output.stdout).contains("pass it with --dict"),
+        "{}",
+        String::from_utf8_lossy(&output.stdout)
+    );
+    assert!(!restored.join("target/player0.json").exists());
+}
@@ -0,0 +1,115 @@
+//! Manifests count the entries listed in every directory, so a partly read one is caught.
+mod common;
+
+use std::fs::File;
+use std::path::Path;
+
+use common::{code, restore, sit, sit_ok, snapshot, write};
+
+/// Every directory in the manifest, by path, with the entries counted in it.
+fn children(manifest: &serde_json::Value) -> Vec<(String, u64)> {
+    manifest["entries"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter(|entry| entry["kind"] == "directory")
+        .map(|entry| {
+            (
+                entry["path"].as_str().unwrap().to_string(),
+                entry["children"].as_u64().unwrap(),
+            )
+        })
+        .collect()
+}
+
+/// Rewrites the manifest embedded in the archive with `edit` applied to it.
+fn edit_embedded_manifest(archive: &Path, edit: impl Fn(&mut serde_json::Value)) {
+    let mut original = tar::Archive::new(File::open(archive).unwrap());
+    let mut rewritten = tar::Builder::new(Vec::new());
+    for entry in original.entries().unwrap() {
+        let mut entry = entry.unwrap();
+        let mut header = entry.header().clone();
+        let mut data = Vec::new();
+        std::io::Read::read_to_end(&mut entry, &mut data).unwrap();
+        if entry.path().unwrap() == Path::new(".sit-manifest.json") {
+            let mut manifest = serde_json::from_slice(&data).unwrap();
+            edit(&mut manifest);
+            data = serde_json::to_vec(&manifest).unwrap();
+            header.set_size(data.len() as u64);
+            header.set_cksum();
+        }
+        rewritten.append(&header, &data[..]).unwrap();
+    }
+    std::fs::write(archive, rewritten.into_inner().unwrap()).unwrap();
+}
+
+/// A target holding `dir` with two files and a subdirectory in it, and a hidden file.
+fn fixture(root: &Path) -> std::path::PathBuf {
+    let target = root.join("target");
+    write(&target.join("dir/a"), "a");
+    write(&target.join("dir/b"), "b");
+    write(&target.join("dir/sub/c"), "c");
+    write(&target.join("dir/.hidden"), "hidden");
+    target
+}
+
+#[test]
+fn directories_count_the_entries_listed_in_them() {
+    let root = tempfile::tempdir().unwrap();
+    let target = fixture(root.path());
+    let manifest_path = root.path().join("manifest.json");
+    let manifest = |extra: &[&str]| {
+        let mut args = vec!["--manifest", manifest_path.to_str().unwrap()];
+        args.extend(extra);
+        snapshot(&target, &root.path().join("snapshot.tar.zst"), &args);
+        children(&serde_json::from_slice(&std::fs::read(&manifest_path).unwrap()).unwrap())
+    };
+    assert_eq!(
+        manifest(&[]),
+        [
+            ("target/".to_string(), 1),
+            ("target/dir".to_string(), 4),
+            ("target/dir/sub".to_string(), 1),
+        ]
+    );
+
+    // entries a filter leaves out weren't meant to be there, so aren't counted
+    assert_eq!(
+        manifest(&["--exclude-hidden"])[1],
+        ("target/dir".to_string(), 3)
+    );
+}
+
+#[test]
+fn directories_holding_fewer_entries_than_listed_fail_verification() {
+    let root = tempfile::tempdir().unwrap();
+    let target = fixture(root.path());
+    let archive = root.path().join("snapshot.tar.zst");
+    snapshot(&target, &archive, &["--checksum", "sha256"]);
+    sit_ok(["verify".as_ref(), archive.as_os_str()]);
+
+    // as if an entry had been listed but went missing before it was read
+    edit_embedded_manifest(&archive, |manifest| {
+        for entry in manifest["entries"].as_array_mut().unwrap() {
+            if entry["path"] == "target/dir" {
+                entry["children"] = 5.into();
+            }
+        }
+    });
+    // the checksum of the whole archive would catch the edit first
+    std::fs::remove_file(root.path().join("snapshot.tar.zst.sha256")).unwrap();
+    let output = sit(["verify".as_ref(), archive.as_os_str()]);
+    let logged = String::from_utf8_lossy(&output.stdout);
+    assert_ne!(code(&output), 0, "{}", logged);
+    assert!(
+        logged.contains("holds 4 of the 5 entries listed in it"),
+        "{}",
+        logged
+    );
+    assert!(logged.contains("target/dir"), "{}", logged);
+
+    // a restore only warns, restoring everything it holds
+    let restored = tempfile::tempdir().unwrap();
+    restore(&archive, restored.path(), &[]);
+    assert!(restored.path().join("target/dir/sub/c").exists());
+}
@@ -0,0 +1,50 @@
+//! `--drain-fifos` captures what named pipes hold buffered as files.
+#![cfg(unix)]
+
+mod common;
+
+use std::ffi::CString;
+use std::io::{Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use common::{snapshot, tar_members, write};
+
+/// What the archive holds for the path, decompressed, or `None` when it was left out.
+fn archived(archive: &Path, path: &str) -> Option<Vec<u8>> {
+    tar_members(archive)
+        .into_iter()
+        .find(|(member, _)| member == path)
+        .map(|(_, content)| zstd::decode_all(&content[..]).unwrap())
+}
+
+#[test]
+fn buffered_bytes_are_drained_up_to_the_limit() {
+    let root = tempfile::tempdir().unwrap();
+    let target = root.path().join("target");
+    write(&target.join("file"), "content");
+    let fifo = target.join("pipe");
+    let path = CString::new(fifo.as_os_str().as_bytes()).unwrap();
+    assert_eq!(unsafe { libc::mkfifo(path.as_ptr(), 0o644) }, 0);
+    // held open for reading and writing, so opening it never waits and it stays open throughout
+    let mut pipe = std::fs::File::options()
+        .read(true)
+        .write(true)
+        .open(&fifo)
+        .unwrap();
+    pipe.write_all(b"buffered data").unwrap();
+
+    let left_out = root.path().join("left_out.tar.zst");
+    snapshot(&target, &left_out, &[]);
+    assert_eq!(archived(&left_out, "target/pipe"), None);
+    assert_eq!(archived(&left_out, "target/file").unwrap(), b"content");
+
+    let drained = root.path().join("drained.tar.zst");
+    snapshot(&target, &drained, &["--drain-fifos", "8"]);
+    assert_eq!(archived(&drained, "target/pipe").unwrap(), b"buffered");
+
+    // the bytes drained were taken from whatever reads the pipe
+    let mut rest = [0; 5];
+    pipe.read_exact(&mut rest).unwrap();
+    assert_eq!(&rest, b" data");
+}
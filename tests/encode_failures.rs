@@ -0,0 +1,119 @@
+//! A file whose capture fails to encode is left out like an unreadable one, unless the snapshot
+//! is strict. The failure is brought about by capping the size of the files the binary may write,
+//! which fails writing the capture of a large file without touching any smaller one.
+#![cfg(target_os = "linux")]
+
+mod common;
+
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::Output;
+
+use common::{code, command, tar_members, write};
+
+/// Bytes any file the binary writes is capped at.
+const FILE_SIZE_LIMIT: u64 = 64 * 1024;
+
+/// Content zstd can't compress below the cap.
+fn incompressible(len: usize) -> Vec<u8> {
+    let mut state = 0x2545_f491_4f6c_dd1d_u64;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
+
+/// Snapshots the target into the archive with the extra arguments, with every file the binary
+/// writes capped at `FILE_SIZE_LIMIT`.
+fn snapshot_capped(target: &Path, archive: &Path, extra: &[&str]) -> Output {
+    let mut command = command();
+    command
+        .arg("-t")
+        .arg(target)
+        .arg("-o")
+        .arg(archive)
+        .args(extra);
+    unsafe {
+        command.pre_exec(|| {
+            let limit = libc::rlimit {
+                rlim_cur: FILE_SIZE_LIMIT,
+                rlim_max: FILE_SIZE_LIMIT,
+            };
+            if libc::setrlimit(libc::RLIMIT_FSIZE, &limit) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            // writing past the cap fails with EFBIG instead of killing the process
+            libc::signal(libc::SIGXFSZ, libc::SIG_IGN);
+            Ok(())
+        });
+    }
+    command.output().expect("failed to run saved_in_time")
+}
+
+fn members(archive: &Path) -> Vec<String> {
+    tar_members(archive)
+        .into_iter()
+        .map(|(path, _)| path)
+        .collect()
+}
+
+/// A target holding a small file and one whose capture can't be written under the cap.
+fn target(root: &Path) -> std::path::PathBuf {
+    let target = root.join("target");
+    write(&target.join("small"), "small");
+    write(
+        &target.join("large"),
+        incompressible(4 * FILE_SIZE_LIMIT as usize),
+    );
+    target
+}
+
+#[test]
+fn files_failing_to_encode_are_left_out_and_reported() {
+    let root = tempfile::tempdir().unwrap();
+    let target = target(root.path());
+    let (archive, report) = (root.path().join("out.tar"), root.path().join("report.json"));
+
+    let output = snapshot_capped(
+        &target,
+        &archive,
+        &["--keep-going", "--report", report.to_str().unwrap()],
+    );
+    assert_eq!(code(&output), 13);
+    assert_eq!(members(&archive), ["target/", "target/small"]);
+    let report: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(&report).unwrap()).unwrap();
+    assert_eq!(
+        report["skipped"][0]["path"],
+        target.join("large").to_str().unwrap()
+    );
+    assert_eq!(report["skipped"][0]["code"], "unreadable");
+
+    // ignoring file errors, the same snapshot succeeds
+    std::fs::remove_file(&archive).unwrap();
+    let output = snapshot_capped(&target, &archive, &["--ignore-file-errors"]);
+    assert_eq!(code(&output), 0);
+    assert_eq!(members(&archive), ["target/", "target/small"]);
+}
+
+#[test]
+fn strict_snapshots_stop_at_a_file_failing_to_encode() {
+    let root = tempfile::tempdir().unwrap();
+    let target = target(root.path());
+    let archive = root.path().join("out.tar");
+
+    let output = snapshot_capped(&target, &archive, &["--mode", "strict"]);
+    assert_eq!(code(&output), 16);
+    assert!(!archive.exists());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Failed to encode data for"), "{}", stdout);
+    assert!(
+        stdout.contains(&target.join("large").display().to_string()),
+        "{}",
+        stdout
+    );
+}
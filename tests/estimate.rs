@@ -0,0 +1,32 @@
+//! `--estimate` projects the archive a snapshot would write, without writing it.
+
+mod common;
+
+use std::process::Command;
+
+use common::{code, write};
+
+#[test]
+fn estimating_writes_nothing() {
+    let target = tempfile::tempdir().unwrap();
+    for index in 0..10 {
+        write(
+            &target.path().join(format!("file{}", index)),
+            "estimated ".repeat(1000),
+        );
+    }
+    let output = tempfile::tempdir().unwrap();
+    let run = Command::new(env!("CARGO_BIN_EXE_saved_in_time"))
+        .args(["--log-level", "info", "-t"])
+        .arg(target.path())
+        .arg("-o")
+        .arg(output.path().join("snapshot.tar.zst"))
+        .arg("--estimate")
+        .output()
+        .unwrap();
+    let logged = String::from_utf8_lossy(&run.stdout);
+    assert_eq!(code(&run), 0, "{}", logged);
+    assert!(logged.contains("Sampled 10 of 10 files"), "{}", logged);
+    assert!(logged.contains("Projected archive size"), "{}", logged);
+    assert_eq!(std::fs::read_dir(output.path()).unwrap().count(), 0);
+}
@@ -0,0 +1,77 @@
+//! `--exclude-hidden` leaves out dot-named entries and everything beneath them.
+mod common;
+
+use std::path::Path;
+
+use common::{snapshot, tar_members, write};
+
+/// Snapshots a target with hidden entries nested at several depths, returning the paths archived
+/// relative to the target.
+fn archived(extra: &[&str]) -> Vec<String> {
+    let root = tempfile::tempdir().unwrap();
+    let target = root.path().join("target");
+    for path in [
+        ".git/objects/pack",
+        ".hidden",
+        "a/visible",
+        "a/.cache/deep/.deeper/file",
+        "a/.config/settings",
+        "a/.config/.secret",
+        "a/b/.c/d/e",
+        "dir.with.dots/file.with.dots",
+    ] {
+        write(&target.join(path), path);
+    }
+    let archive = root.path().join("snapshot.tar.zst");
+    snapshot(&target, &archive, extra);
+    tar_members(&archive)
+        .into_iter()
+        .filter_map(|(path, _)| {
+            let path = Path::new(&path).strip_prefix("target").ok()?;
+            Some(path.to_string_lossy().into_owned())
+        })
+        .filter(|path| !path.is_empty())
+        .collect()
+}
+
+#[test]
+fn hidden_entries_are_left_out_at_any_depth() {
+    assert_eq!(
+        archived(&["--exclude-hidden"]),
+        [
+            "a",
+            "a/b",
+            "a/visible",
+            "dir.with.dots",
+            "dir.with.dots/file.with.dots",
+        ]
+    );
+}
+
+#[test]
+fn included_hidden_directories_keep_what_is_visible_beneath_them() {
+    assert_eq!(
+        archived(&["--exclude-hidden", "--include", "*/.config"]),
+        [
+            "a",
+            "a/.config",
+            "a/.config/settings",
+            "a/b",
+            "a/visible",
+            "dir.with.dots",
+            "dir.with.dots/file.with.dots",
+        ]
+    );
+}
+
+#[test]
+fn hidden_entries_are_kept_by_default() {
+    let archived = archived(&[]);
+    for path in [
+        ".git/objects/pack",
+        "a/.cache/deep/.deeper/file",
+        "a/b/.c/d/e",
+    ] {
+        assert!(archived.iter().any(|archived| archived == path), "{}", path);
+    }
+}
@@ -0,0 +1,294 @@
+//! Pins the code every way a run can end exits with, since scripts tell them apart by it.
+
+mod common;
+
+use std::ffi::OsString;
+use std::path::Path;
+use std::process::{Command, Output};
+use std::time::{Duration, SystemTime};
+
+use common::{code, sit, tar_members, write};
+
+/// Snapshots the target into a fresh directory with the extra arguments.
+fn snapshot(target: &Path, extra: &[&str]) -> Output {
+    let output = tempfile::tempdir().unwrap();
+    let mut args: Vec<OsString> = vec![
+        "-t".into(),
+        target.into(),
+        "-o".into(),
+        output.path().join("snapshot.tar.zst").into(),
+    ];
+    args.extend(extra.iter().map(OsString::from));
+    sit(args)
+}
+
+/// A target holding a single file, dated far enough back that no pass has to confirm it.
+fn target() -> tempfile::TempDir {
+    let target = tempfile::tempdir().unwrap();
+    let file = target.path().join("file");
+    write(&file, "content");
+    let old = SystemTime::now() - Duration::from_secs(3600);
+    std::fs::File::open(&file)
+        .unwrap()
+        .set_modified(old)
+        .unwrap();
+    target
+}
+
+/// A target holding a symlink leading outside of it.
+#[cfg(unix)]
+fn target_with_escaping_symlink() -> tempfile::TempDir {
+    let target = target();
+    std::os::unix::fs::symlink("/", target.path().join("root")).unwrap();
+    target
+}
+
+fn assert_code(output: &Output, expected: i32) {
+    assert_eq!(
+        code(output),
+        expected,
+        "stdout: {}\nstderr: {}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+#[test]
+fn every_code_is_listed() {
+    // the flag stands alone, without even a log level
+    let listed = Command::new(env!("CARGO_BIN_EXE_saved_in_time"))
+        .arg("--list-exit-codes")
+        .output()
+        .unwrap();
+    assert_code(&listed, 0);
+    let codes: Vec<i32> = String::from_utf8_lossy(&listed.stdout)
+        .lines()
+        .map(|line| line.split_whitespace().next().unwrap().parse().unwrap())
+        .collect();
+    assert_eq!(codes, (0..=18).collect::<Vec<_>>());
+}
+
+#[test]
+fn success_exits_0() {
+    assert_code(&snapshot(target().path(), &[]), 0);
+}
+
+#[test]
+fn an_unreadable_archive_exits_1() {
+    let output = tempfile::tempdir().unwrap();
+    let missing = output.path().join("missing.tar.zst");
+    assert_code(&sit(["list".as_ref(), missing.as_os_str()]), 1);
+}
+
+#[test]
+fn a_missing_target_exits_2() {
+    let target = tempfile::tempdir().unwrap();
+    assert_code(&snapshot(&target.path().join("missing"), &[]), 2);
+}
+
+#[test]
+fn a_target_which_is_a_file_exits_3() {
+    let target = target();
+    assert_code(&snapshot(&target.path().join("file"), &[]), 3);
+}
+
+#[test]
+#[cfg(unix)]
+fn a_symlinked_target_refused_by_choice_exits_3() {
+    let target = target();
+    let link = tempfile::tempdir().unwrap();
+    let symlinked = link.path().join("target");
+    std::os::unix::fs::symlink(target.path(), &symlinked).unwrap();
+    let output = tempfile::tempdir().unwrap();
+    let archive = output.path().join("snapshot.tar.zst");
+
+    let refused = sit([
+        "-t".into(),
+        symlinked.clone().into_os_string(),
+        "-o".into(),
+        archive.clone().into_os_string(),
+        "--target-no-follow".into(),
+    ]);
+    assert_code(&refused, 3);
+    assert!(!archive.exists(), "a refused target still left an archive");
+    let logged = String::from_utf8_lossy(&refused.stdout);
+    assert!(
+        logged.contains(&target.path().canonicalize().unwrap().display().to_string()),
+        "{}",
+        logged
+    );
+
+    // following is the default, and the snapshot is recorded under the resolved directory
+    let followed = sit([
+        "-t".into(),
+        symlinked.into_os_string(),
+        "-o".into(),
+        archive.clone().into_os_string(),
+    ]);
+    assert_code(&followed, 0);
+    let name = target.path().file_name().unwrap().to_string_lossy();
+    let paths: Vec<String> = tar_members(&archive)
+        .into_iter()
+        .map(|(path, _)| path)
+        .collect();
+    assert!(paths.contains(&format!("{}/file", name)), "{:?}", paths);
+}
+
+#[test]
+fn a_directory_differing_from_its_archive_exits_4() {
+    let target = target();
+    let output = tempfile::tempdir().unwrap();
+    let archive = output.path().join("snapshot.tar.zst");
+    let args = [
+        "-t".as_ref(),
+        target.path().as_os_str(),
+        "-o".as_ref(),
+        archive.as_os_str(),
+    ];
+    assert_code(&sit(args), 0);
+    write(&target.path().join("added"), "added");
+    let verify = sit([
+        "verify".as_ref(),
+        archive.as_os_str(),
+        "--against".as_ref(),
+        target.path().as_os_str(),
+    ]);
+    assert_code(&verify, 4);
+}
+
+#[cfg(unix)]
+#[test]
+fn a_strict_snapshot_refusing_a_path_exits_5() {
+    let target = target_with_escaping_symlink();
+    assert_code(&snapshot(target.path(), &["--mode", "strict"]), 5);
+}
+
+#[cfg(unix)]
+#[test]
+fn a_signal_exits_6() {
+    let interrupted = snapshot(target().path(), &["--pre-hook", "kill -INT $PPID"]);
+    assert_code(&interrupted, 6);
+}
+
+#[test]
+fn running_out_of_time_exits_7() {
+    let slow = snapshot(
+        target().path(),
+        &["--timeout", "100ms", "--pre-hook", "sleep 1"],
+    );
+    assert_code(&slow, 7);
+}
+
+#[cfg(unix)]
+#[test]
+fn a_target_locked_by_another_snapshot_exits_8() {
+    let target = target();
+    let output = tempfile::tempdir().unwrap();
+    let inner_code = output.path().join("inner-code");
+    // the pre-hook runs while the outer snapshot holds the lock
+    let inner = format!(
+        "'{}' -t '{}' -o '{}'; echo $? > '{}'",
+        env!("CARGO_BIN_EXE_saved_in_time"),
+        target.path().display(),
+        output.path().join("inner.tar.zst").display(),
+        inner_code.display()
+    );
+    assert_code(&snapshot(target.path(), &["--pre-hook", &inner]), 0);
+    let inner_code = std::fs::read_to_string(inner_code).unwrap();
+    assert_eq!(inner_code.trim(), "8");
+}
+
+#[test]
+fn a_failing_pre_hook_exits_9() {
+    assert_code(&snapshot(target().path(), &["--pre-hook", "exit 3"]), 9);
+}
+
+#[test]
+fn an_empty_snapshot_exits_10() {
+    let empty = tempfile::tempdir().unwrap();
+    assert_code(&snapshot(empty.path(), &["--fail-on-empty"]), 10);
+}
+
+#[test]
+fn an_unreachable_rcon_exits_11() {
+    // a port nothing listens on any more
+    let address = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .to_string();
+    let paused = snapshot(
+        target().path(),
+        &["--rcon", &address, "--rcon-password-env", "PATH"],
+    );
+    assert_code(&paused, 11);
+}
+
+#[test]
+fn a_container_which_cant_be_paused_exits_12() {
+    let paused = snapshot(
+        target().path(),
+        &["--docker-pause", "saved-in-time-no-such-container"],
+    );
+    assert_code(&paused, 12);
+}
+
+#[cfg(unix)]
+#[test]
+fn a_best_effort_snapshot_leaving_paths_out_exits_13() {
+    let target = target_with_escaping_symlink();
+    assert_code(&snapshot(target.path(), &["--mode", "best-effort"]), 13);
+}
+
+#[test]
+fn warnings_exit_14_when_failing_on_them() {
+    let target = target();
+    let future = SystemTime::now() + Duration::from_secs(24 * 3600);
+    let file = std::fs::File::open(target.path().join("file")).unwrap();
+    file.set_modified(future).unwrap();
+    assert_code(&snapshot(target.path(), &["--fail-on-warning"]), 14);
+}
+
+#[test]
+fn bad_arguments_exit_15() {
+    assert_code(&sit(["--no-such-flag"]), 15);
+    assert_code(&sit(["restore"]), 15);
+    let reversed = [
+        "--modified-since",
+        "2024-01-02T00:00:00Z",
+        "--modified-before",
+        "2024-01-01T00:00:00Z",
+    ];
+    assert_code(&snapshot(target().path(), &reversed), 15);
+}
+
+#[test]
+fn an_unusable_temp_dir_exits_16() {
+    let target = target();
+    let missing = target.path().join("no-such-temp-dir");
+    let args = ["--temp-dir", missing.to_str().unwrap()];
+    assert_code(&snapshot(target.path(), &args), 16);
+}
+
+#[test]
+fn exceeding_the_iteration_bound_exits_17() {
+    let target = tempfile::tempdir().unwrap();
+    // written just now, so the first pass has to confirm it on another it isn't allowed
+    write(&target.path().join("file"), "content");
+    let args = ["--iteration-retries", "0", "--mtime-slack", "1h"];
+    assert_code(&snapshot(target.path(), &args), 17);
+}
+
+#[test]
+fn an_archive_which_cant_be_written_exits_18() {
+    let target = target();
+    let output = tempfile::tempdir().unwrap();
+    let archive = output.path().join("missing/snapshot.tar.zst");
+    let args = [
+        "-t".as_ref(),
+        target.path().as_os_str(),
+        "-o".as_ref(),
+        archive.as_os_str(),
+    ];
+    assert_code(&sit(args), 18);
+}
@@ -0,0 +1,104 @@
+//! `--fail-on-empty` refuses a snapshot which captured no files, without writing anything.
+
+mod common;
+
+use std::ffi::OsString;
+use std::path::Path;
+
+use common::{code, sit, tar_members, write};
+
+/// Snapshots the target into the output directory with the extra arguments, returning the exit
+/// code and the snapshot's report.
+fn snapshot(target: &Path, output: &Path, extra: &[&str]) -> (i32, serde_json::Value) {
+    let report = output.join("report.json");
+    let mut args: Vec<OsString> = vec![
+        "-t".into(),
+        target.into(),
+        "-o".into(),
+        output.join("snapshot.tar.zst").into(),
+        "--report".into(),
+        report.clone().into(),
+    ];
+    args.extend(extra.iter().map(OsString::from));
+    let run = sit(args);
+    let report = std::fs::read(&report).unwrap_or_else(|err| {
+        panic!(
+            "no report was written: {}\n{}",
+            err,
+            String::from_utf8_lossy(&run.stdout)
+        )
+    });
+    (code(&run), serde_json::from_slice(&report).unwrap())
+}
+
+/// Names of everything in the output directory.
+fn written(output: &Path) -> Vec<String> {
+    let mut names: Vec<String> = std::fs::read_dir(output)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+    names.sort();
+    names
+}
+
+#[test]
+fn a_target_holding_only_directories_writes_no_archive() {
+    let target = tempfile::tempdir().unwrap();
+    std::fs::create_dir_all(target.path().join("empty/nested")).unwrap();
+    let output = tempfile::tempdir().unwrap();
+    let (code, report) = snapshot(target.path(), output.path(), &["--fail-on-empty"]);
+    assert_eq!(code, 10);
+    assert_eq!(report["files"], 0);
+    // neither the archive nor what it was written to before being renamed into place
+    assert_eq!(written(output.path()), ["report.json"]);
+}
+
+#[test]
+fn a_target_whose_files_are_all_left_out_writes_no_archive() {
+    let target = tempfile::tempdir().unwrap();
+    write(&target.path().join(".hidden"), "hidden");
+    let output = tempfile::tempdir().unwrap();
+    let (code, _) = snapshot(
+        target.path(),
+        output.path(),
+        &["--fail-on-empty", "--exclude-hidden"],
+    );
+    assert_eq!(code, 10);
+    assert_eq!(written(output.path()), ["report.json"]);
+}
+
+#[test]
+fn a_single_file_is_enough_to_archive() {
+    let target = tempfile::tempdir().unwrap();
+    std::fs::create_dir(target.path().join("empty")).unwrap();
+    write(&target.path().join("file"), "content");
+    let output = tempfile::tempdir().unwrap();
+    let (code, report) = snapshot(target.path(), output.path(), &["--fail-on-empty"]);
+    assert_eq!(code, 0);
+    assert_eq!(report["files"], 1);
+    let name = target.path().file_name().unwrap().to_string_lossy();
+    let members = tar_members(&output.path().join("snapshot.tar.zst"));
+    assert!(
+        members
+            .iter()
+            .any(|(path, content)| *path == format!("{}/file", name)
+                && zstd::decode_all(&content[..]).unwrap() == b"content"),
+        "{:?}",
+        members
+    );
+}
+
+#[test]
+fn without_the_flag_an_empty_target_is_archived() {
+    let target = tempfile::tempdir().unwrap();
+    std::fs::create_dir(target.path().join("empty")).unwrap();
+    let output = tempfile::tempdir().unwrap();
+    let (code, _) = snapshot(target.path(), output.path(), &[]);
+    assert_eq!(code, 0);
+    let name = target.path().file_name().unwrap().to_string_lossy();
+    let paths: Vec<String> = tar_members(&output.path().join("snapshot.tar.zst"))
+        .into_iter()
+        .map(|(path, _)| path)
+        .collect();
+    assert_eq!(paths, [format!("{}/", name), format!("{}/empty", name)]);
+}
@@ -0,0 +1,57 @@
+//! `--files-from` captures the listed files instead of walking the target.
+
+mod common;
+
+use std::io::Write;
+use std::process::Stdio;
+
+use common::{code, command, files, restore, tar_members, write};
+
+#[test]
+fn a_list_read_from_stdin_captures_only_the_files_listed() {
+    let target = tempfile::tempdir().unwrap();
+    write(&target.path().join("a"), "a");
+    write(&target.path().join("sub/b"), "b");
+    write(&target.path().join("sub/unlisted"), "unlisted");
+    write(&target.path().join("unlisted"), "unlisted");
+    let output = tempfile::tempdir().unwrap();
+    let archive = output.path().join("snapshot.tar.zst");
+
+    let mut child = command()
+        .arg("-t")
+        .arg(target.path())
+        .arg("-o")
+        .arg(&archive)
+        .args(["--files-from", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // relative and absolute, with a blank line and a file listed twice
+    let list = format!("a\n\n{}\na\n", target.path().join("sub/b").display());
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(list.as_bytes())
+        .unwrap();
+    let run = child.wait_with_output().unwrap();
+    assert_eq!(code(&run), 0, "{}", String::from_utf8_lossy(&run.stdout));
+
+    let name = target.path().file_name().unwrap().to_str().unwrap();
+    let paths: Vec<String> = tar_members(&archive)
+        .into_iter()
+        .map(|(path, _)| path)
+        .collect();
+    assert_eq!(paths, [format!("{}/a", name), format!("{}/sub/b", name)]);
+    let restored = tempfile::tempdir().unwrap();
+    restore(&archive, restored.path(), &[]);
+    let restored = files(&restored.path().join(name));
+    assert_eq!(
+        restored.into_iter().collect::<Vec<_>>(),
+        [
+            ("a".to_string(), b"a".to_vec()),
+            ("sub/b".to_string(), b"b".to_vec())
+        ]
+    );
+}
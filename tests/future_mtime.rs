@@ -0,0 +1,97 @@
+//! Entries dated ahead of the clock are captured with a warning instead of waited on forever.
+
+mod common;
+
+use std::ffi::OsString;
+use std::path::Path;
+use std::process::Output;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use common::{code, restore, sit, write};
+
+fn set_modified(path: &Path, modified: SystemTime) {
+    std::fs::File::open(path)
+        .unwrap()
+        .set_modified(modified)
+        .unwrap();
+}
+
+/// Snapshots the target with a report beside the archive, returning how the run ended and the
+/// report.
+fn snapshot(target: &Path, output: &Path, extra: &[&str]) -> (Output, serde_json::Value) {
+    let report = output.join("report.json");
+    let mut args: Vec<OsString> = vec![
+        "-t".into(),
+        target.into(),
+        "-o".into(),
+        output.join("snapshot.tar.zst").into(),
+        "--report".into(),
+        report.clone().into(),
+    ];
+    args.extend(extra.iter().map(OsString::from));
+    let run = sit(args);
+    let report = serde_json::from_slice(&std::fs::read(report).unwrap()).unwrap();
+    (run, report)
+}
+
+fn seconds(instant: SystemTime) -> u64 {
+    instant.duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+#[test]
+fn entries_dated_a_day_ahead_are_captured_with_a_warning() {
+    let target = tempfile::tempdir().unwrap();
+    let ahead = SystemTime::now() + Duration::from_secs(24 * 3600);
+    write(&target.path().join("ahead/file"), "from tomorrow");
+    write(&target.path().join("now"), "from today");
+    set_modified(&target.path().join("ahead/file"), ahead);
+    set_modified(&target.path().join("ahead"), ahead);
+    let output = tempfile::tempdir().unwrap();
+    // were they taken as modified after every pass, two retries would never be enough
+    let (run, report) = snapshot(target.path(), output.path(), &["--iteration-retries", "2"]);
+    assert_eq!(code(&run), 0, "{}", String::from_utf8_lossy(&run.stdout));
+    assert_eq!(report["warnings"]["future_mtime"], 2, "{}", report);
+
+    let restored = tempfile::tempdir().unwrap();
+    restore(
+        &output.path().join("snapshot.tar.zst"),
+        restored.path(),
+        &[],
+    );
+    let name = target.path().file_name().unwrap();
+    let file = restored.path().join(name).join("ahead/file");
+    assert_eq!(std::fs::read_to_string(&file).unwrap(), "from tomorrow");
+    assert_eq!(
+        seconds(file.metadata().unwrap().modified().unwrap()),
+        seconds(ahead)
+    );
+}
+
+#[test]
+fn the_future_mtime_slack_decides_what_counts_as_ahead() {
+    let target = tempfile::tempdir().unwrap();
+    write(&target.path().join("file"), "five minutes ahead");
+    set_modified(
+        &target.path().join("file"),
+        SystemTime::now() + Duration::from_secs(300),
+    );
+    let output = tempfile::tempdir().unwrap();
+
+    // beyond the default minute, the mtime is taken as bogus and the file captured
+    let (run, report) = snapshot(target.path(), output.path(), &["--iteration-retries", "2"]);
+    assert_eq!(code(&run), 0, "{}", String::from_utf8_lossy(&run.stdout));
+    assert_eq!(report["warnings"]["future_mtime"], 1, "{}", report);
+
+    // within an hour's allowance, it's a write the passes have to wait out
+    let (run, report) = snapshot(
+        target.path(),
+        output.path(),
+        &["--iteration-retries", "2", "--future-mtime-slack", "1h"],
+    );
+    assert_eq!(code(&run), 17, "{}", String::from_utf8_lossy(&run.stdout));
+    assert!(
+        report["warnings"].get("future_mtime").is_none(),
+        "{}",
+        report
+    );
+}
@@ -0,0 +1,96 @@
+//! Hard linked files whose links don't all live in the target are warned of and marked.
+#![cfg(unix)]
+
+mod common;
+
+use std::ffi::OsString;
+use std::path::Path;
+
+use common::{files, restore, sit_ok, write};
+
+/// Snapshots the target with a report and manifest beside the archive, returning the report and
+/// whether the manifest marks every path as partially linked.
+fn snapshot(target: &Path, output: &Path) -> (serde_json::Value, Vec<(String, bool)>) {
+    let (report, manifest) = (output.join("report.json"), output.join("manifest.json"));
+    let args: Vec<OsString> = vec![
+        "-t".into(),
+        target.into(),
+        "-o".into(),
+        output.join("snapshot.tar.zst").into(),
+        "--report".into(),
+        report.clone().into(),
+        "--manifest".into(),
+        manifest.clone().into(),
+    ];
+    sit_ok(args);
+    let report = serde_json::from_slice(&std::fs::read(report).unwrap()).unwrap();
+    let manifest: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(manifest).unwrap()).unwrap();
+    let marked = manifest["entries"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter(|entry| entry["kind"] == "file")
+        .map(|entry| {
+            (
+                entry["path"].as_str().unwrap().to_string(),
+                entry["partial_hardlink"] == true,
+            )
+        })
+        .collect();
+    (report, marked)
+}
+
+#[test]
+fn a_link_set_held_whole_by_the_target_is_not_warned_of() {
+    let root = tempfile::tempdir().unwrap();
+    let target = root.path().join("target");
+    write(&target.join("file"), "linked");
+    write(&target.join("other"), "not linked");
+    std::fs::create_dir(target.join("sub")).unwrap();
+    std::fs::hard_link(target.join("file"), target.join("sub/link")).unwrap();
+    let output = tempfile::tempdir().unwrap();
+    let (report, marked) = snapshot(&target, output.path());
+    assert!(
+        report["warnings"].get("partial_hardlink").is_none(),
+        "{}",
+        report
+    );
+    assert!(marked.iter().all(|(_, partial)| !partial), "{:?}", marked);
+
+    let restored = tempfile::tempdir().unwrap();
+    restore(
+        &output.path().join("snapshot.tar.zst"),
+        restored.path(),
+        &[],
+    );
+    assert_eq!(files(&restored.path().join("target")), files(&target));
+}
+
+#[test]
+fn a_file_linked_from_outside_the_target_is_warned_of_and_marked() {
+    let root = tempfile::tempdir().unwrap();
+    let target = root.path().join("target");
+    write(&target.join("linked"), "linked from outside");
+    write(&target.join("alone"), "not linked");
+    std::fs::hard_link(target.join("linked"), root.path().join("outside")).unwrap();
+    let output = tempfile::tempdir().unwrap();
+    let (report, marked) = snapshot(&target, output.path());
+    assert_eq!(report["warnings"]["partial_hardlink"], 1, "{}", report);
+    assert_eq!(
+        marked,
+        [
+            ("target/alone".to_string(), false),
+            ("target/linked".to_string(), true)
+        ]
+    );
+
+    // the file is still archived whole
+    let restored = tempfile::tempdir().unwrap();
+    restore(
+        &output.path().join("snapshot.tar.zst"),
+        restored.path(),
+        &[],
+    );
+    assert_eq!(files(&restored.path().join("target")), files(&target));
+}
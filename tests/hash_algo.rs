@@ -0,0 +1,101 @@
+//! `--hash-algo` picks what file content is hashed with for the manifest.
+mod common;
+
+use std::path::Path;
+
+use common::{snapshot, tar_members, write};
+use sha2::Digest as _;
+
+/// Every file's digest in the manifest, in path order, along with the algorithm it names.
+fn digests(manifest: &Path) -> (String, Vec<(String, String)>) {
+    let manifest: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(manifest).unwrap()).unwrap();
+    let digests = manifest["entries"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter(|entry| entry["kind"] == "file")
+        .map(|entry| {
+            (
+                entry["path"].as_str().unwrap().to_string(),
+                entry["digest"].as_str().unwrap().to_string(),
+            )
+        })
+        .collect();
+    (
+        manifest["hash_algorithm"].as_str().unwrap().to_string(),
+        digests,
+    )
+}
+
+#[test]
+fn digests_are_taken_with_the_algorithm_given() {
+    let root = tempfile::tempdir().unwrap();
+    let target = root.path().join("target");
+    write(&target.join("a"), "first");
+    write(&target.join("b"), "second");
+    let manifest = root.path().join("manifest.json");
+    let manifest_arg = manifest.to_str().unwrap();
+
+    snapshot(
+        &target,
+        &root.path().join("sha256.tar.zst"),
+        &["--manifest", manifest_arg],
+    );
+    let sha256 = |content: &str| hex::encode(sha2::Sha256::digest(content));
+    assert_eq!(
+        digests(&manifest),
+        (
+            "sha256".to_string(),
+            vec![
+                ("target/a".to_string(), sha256("first")),
+                ("target/b".to_string(), sha256("second")),
+            ]
+        )
+    );
+
+    snapshot(
+        &target,
+        &root.path().join("blake3.tar.zst"),
+        &["--manifest", manifest_arg, "--hash-algo", "blake3"],
+    );
+    let blake3 = |content: &str| blake3::hash(content.as_bytes()).to_hex().to_string();
+    assert_eq!(
+        digests(&manifest),
+        (
+            "blake3".to_string(),
+            vec![
+                ("target/a".to_string(), blake3("first")),
+                ("target/b".to_string(), blake3("second")),
+            ]
+        )
+    );
+}
+
+#[test]
+fn digests_of_another_algorithm_count_every_file_as_changed() {
+    let root = tempfile::tempdir().unwrap();
+    let target = root.path().join("target");
+    write(&target.join("unchanged"), "content");
+    let manifest = root.path().join("manifest.json");
+    let manifest_arg = manifest.to_str().unwrap();
+    snapshot(
+        &target,
+        &root.path().join("full.tar.zst"),
+        &["--manifest", manifest_arg],
+    );
+
+    let archived = |name: &str, algorithm: &str| {
+        let archive = root.path().join(name);
+        snapshot(
+            &target,
+            &archive,
+            &["--since-manifest", manifest_arg, "--hash-algo", algorithm],
+        );
+        tar_members(&archive)
+            .into_iter()
+            .any(|(path, _)| path == "target/unchanged")
+    };
+    assert!(!archived("same.tar.zst", "sha256"));
+    assert!(archived("other.tar.zst", "blake3"));
+}
@@ -0,0 +1,83 @@
+//! `--hash-symlinks` records where symlinks point, so incremental snapshots skip unchanged ones.
+#![cfg(unix)]
+
+mod common;
+
+use std::os::unix::fs::symlink;
+use std::path::Path;
+
+use common::{snapshot, write};
+use sha2::Digest as _;
+
+/// The symlinks an archive holds, by path.
+fn symlinks(archive: &Path) -> Vec<String> {
+    let mut tarball = tar::Archive::new(std::fs::File::open(archive).unwrap());
+    tarball
+        .entries()
+        .unwrap()
+        .map(|member| member.unwrap())
+        .filter(|member| member.header().entry_type().is_symlink())
+        .map(|member| member.path().unwrap().to_string_lossy().into_owned())
+        .collect()
+}
+
+/// Snapshots the target with every symlink hashed, then repoints `moved` and takes an
+/// incremental snapshot, returning the first manifest and the symlinks the second archived.
+fn repoint(extra: &[&str]) -> (serde_json::Value, Vec<String>) {
+    let root = tempfile::tempdir().unwrap();
+    let target = root.path().join("target");
+    write(&target.join("a"), "a");
+    write(&target.join("b"), "b");
+    symlink("a", target.join("kept")).unwrap();
+    symlink("a", target.join("moved")).unwrap();
+    let manifest = root.path().join("full.json");
+    let mut args = vec!["--manifest", manifest.to_str().unwrap()];
+    args.extend(extra);
+    snapshot(&target, &root.path().join("full.tar.zst"), &args);
+
+    std::fs::remove_file(target.join("moved")).unwrap();
+    symlink("b", target.join("moved")).unwrap();
+    let incremental = root.path().join("incremental.tar.zst");
+    let mut args = vec!["--since-manifest", manifest.to_str().unwrap()];
+    args.extend(extra);
+    snapshot(&target, &incremental, &args);
+    let manifest = serde_json::from_slice(&std::fs::read(manifest).unwrap()).unwrap();
+    (manifest, symlinks(&incremental))
+}
+
+#[test]
+fn symlinks_pointing_where_they_did_are_skipped() {
+    let (manifest, archived) = repoint(&["--hash-symlinks"]);
+    let digests: Vec<(&str, &str)> = manifest["entries"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter(|entry| entry["kind"] == "symlink")
+        .map(|entry| {
+            (
+                entry["path"].as_str().unwrap(),
+                entry["digest"].as_str().unwrap(),
+            )
+        })
+        .collect();
+    let pointing_at_a = hex::encode(sha2::Sha256::digest("a"));
+    assert_eq!(
+        digests,
+        [
+            ("target/kept", pointing_at_a.as_str()),
+            ("target/moved", pointing_at_a.as_str()),
+        ]
+    );
+    assert_eq!(archived, ["target/moved"]);
+}
+
+#[test]
+fn symlinks_without_digests_are_archived_as_modified() {
+    let (manifest, archived) = repoint(&[]);
+    assert!(manifest["entries"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .all(|entry| entry["kind"] != "symlink" || entry.get("digest").is_none()));
+    assert_eq!(archived, ["target/kept", "target/moved"]);
+}
@@ -0,0 +1,56 @@
+//! An incremental snapshot restored over its base leaves the target as it was when it was taken.
+
+mod common;
+
+use common::{files, restore, snapshot, tar_members, write};
+
+#[test]
+fn an_incremental_snapshot_restored_over_its_base_removes_what_was_deleted() {
+    let root = tempfile::tempdir().unwrap();
+    let target = root.path().join("world");
+    write(&target.join("changed"), "before");
+    write(&target.join("deleted"), "deleted");
+    write(&target.join("gone/file"), "gone with its directory");
+    write(&target.join("kept/file"), "kept");
+    let output = tempfile::tempdir().unwrap();
+    let (base, incremental, manifest) = (
+        output.path().join("base.tar.zst"),
+        output.path().join("incremental.tar.zst"),
+        output.path().join("base.json"),
+    );
+    snapshot(&target, &base, &["--manifest", manifest.to_str().unwrap()]);
+
+    std::fs::remove_file(target.join("deleted")).unwrap();
+    std::fs::remove_dir_all(target.join("gone")).unwrap();
+    write(&target.join("changed"), "after, and longer");
+    write(&target.join("kept/added"), "added");
+    snapshot(
+        &target,
+        &incremental,
+        &["--since-manifest", manifest.to_str().unwrap()],
+    );
+
+    let paths: Vec<String> = tar_members(&incremental)
+        .into_iter()
+        .map(|(path, _)| path)
+        .collect();
+    for expected in [
+        "world/changed",
+        "world/kept/added",
+        "world/.wh.deleted",
+        "world/.wh.gone",
+    ] {
+        assert!(paths.iter().any(|path| path == expected), "{:?}", paths);
+    }
+    assert!(
+        !paths.iter().any(|path| path == "world/kept/file"),
+        "{:?}",
+        paths
+    );
+
+    let restored = tempfile::tempdir().unwrap();
+    restore(&base, restored.path(), &[]);
+    restore(&incremental, restored.path(), &[]);
+    assert_eq!(files(&restored.path().join("world")), files(&target));
+    assert!(!restored.path().join("world/gone").exists());
+}
@@ -0,0 +1,103 @@
+//! `--index` writes where every member lies in the archive, for reading one without the rest.
+mod common;
+
+use std::path::Path;
+use std::process::Command;
+
+use common::{snapshot, write};
+
+/// Runs `cat` for the path, returning what it printed and what it logged.
+fn cat(archive: &Path, path: &str) -> (String, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_saved_in_time"))
+        .args(["--log-level", "debug", "cat"])
+        .arg(archive)
+        .arg(path)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    let printed = String::from_utf8_lossy(&output.stdout).into_owned();
+    // logs go to stdout along with what's printed, so the content is whatever isn't a log line
+    let content = printed
+        .lines()
+        .filter(|line| !line.starts_with('['))
+        .collect::<Vec<_>>()
+        .join("\n");
+    (content, printed)
+}
+
+#[test]
+fn the_index_gives_where_every_member_lies() {
+    let root = tempfile::tempdir().unwrap();
+    let target = root.path().join("target");
+    write(&target.join("a"), "first");
+    write(&target.join("dir/b"), "second");
+    let archive = root.path().join("snapshot.tar.zst");
+    snapshot(&target, &archive, &["--index"]);
+
+    let index: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(archive.with_extension("zst.idx")).unwrap()).unwrap();
+    let bytes = std::fs::read(&archive).unwrap();
+    assert_eq!(index["archive_size"], bytes.len());
+    let mut end = 0;
+    let mut paths = Vec::new();
+    for entry in index["entries"].as_array().unwrap() {
+        let (offset, length) = (
+            entry["offset"].as_u64().unwrap() as usize,
+            entry["length"].as_u64().unwrap() as usize,
+        );
+        // members follow one another, each starting with its own header
+        assert_eq!(offset, end);
+        end = offset + length;
+        let header = tar::Header::from_byte_slice(&bytes[offset..offset + 512]);
+        assert_eq!(
+            header.path().unwrap().to_string_lossy(),
+            entry["path"].as_str().unwrap()
+        );
+        paths.push(entry["path"].as_str().unwrap().to_string());
+    }
+    // only the end-of-archive marker is left
+    assert_eq!(end + 1024, bytes.len());
+    assert_eq!(
+        paths,
+        [
+            "target/",
+            "target/a",
+            "target/dir",
+            "target/dir/b",
+            ".sit-snapshot.json"
+        ]
+    );
+}
+
+#[test]
+fn cat_reads_through_the_index_until_it_goes_stale() {
+    let root = tempfile::tempdir().unwrap();
+    let target = root.path().join("target");
+    write(&target.join("file"), "first");
+    let archive = root.path().join("snapshot.tar.zst");
+    snapshot(&target, &archive, &["--index"]);
+
+    let (content, logged) = cat(&archive, "target/file");
+    assert_eq!(content, "first");
+    assert!(
+        logged.contains("Reading archive through index"),
+        "{}",
+        logged
+    );
+
+    // appending without updating the index leaves it describing an older archive
+    write(&target.join("file"), "second");
+    snapshot(&target, &archive, &["--append"]);
+    let (content, logged) = cat(&archive, "target/file");
+    assert_eq!(content, "second");
+    assert!(
+        !logged.contains("Reading archive through index"),
+        "{}",
+        logged
+    );
+    assert!(
+        logged.contains("as it wasn't built from the archive as it is now"),
+        "{}",
+        logged
+    );
+}
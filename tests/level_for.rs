@@ -0,0 +1,67 @@
+//! `--level-for` compresses the files matching a glob at their own level.
+mod common;
+
+use common::{restore, snapshot, write};
+
+#[test]
+fn the_first_matching_glob_sets_the_level() {
+    let root = tempfile::tempdir().unwrap();
+    let target = root.path().join("target");
+    for path in [
+        "image.png",
+        "logs/latest.log",
+        "logs/chart.png",
+        "notes.txt",
+    ] {
+        write(&target.join(path), path.repeat(50));
+    }
+    let (archive, manifest) = (
+        root.path().join("snapshot.tar.zst"),
+        root.path().join("manifest.json"),
+    );
+    snapshot(
+        &target,
+        &archive,
+        &[
+            "--level-for",
+            "*.png=1",
+            "--level-for",
+            "*/logs/*=19",
+            "--manifest",
+            manifest.to_str().unwrap(),
+        ],
+    );
+
+    let manifest: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(&manifest).unwrap()).unwrap();
+    let levels: Vec<(&str, i64)> = manifest["entries"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter(|entry| entry["kind"] == "file")
+        .map(|entry| {
+            (
+                entry["path"].as_str().unwrap(),
+                entry["level"].as_i64().unwrap(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        levels,
+        [
+            ("target/image.png", 1),
+            // matched by both globs, so the first given applies
+            ("target/logs/chart.png", 1),
+            ("target/logs/latest.log", 19),
+            ("target/notes.txt", 3),
+        ]
+    );
+
+    // however they were compressed, the files restore the same
+    let restored = tempfile::tempdir().unwrap();
+    restore(&archive, restored.path(), &[]);
+    assert_eq!(
+        common::files(&restored.path().join("target")),
+        common::files(&target)
+    );
+}
@@ -0,0 +1,157 @@
+//! `list --list-format` prints the members of an archive as plain, long, csv or json listings.
+#![cfg(unix)]
+
+mod common;
+
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+
+use common::{sit_ok, snapshot, write};
+
+/// Snapshots a target holding a file with a fixed mode and mtime, a symlink to it, and a file
+/// whose name needs quoting in csv, returning the archive.
+fn archive(root: &Path) -> std::path::PathBuf {
+    let target = root.join("target");
+    write(&target.join("a"), "hello");
+    std::fs::set_permissions(target.join("a"), std::fs::Permissions::from_mode(0o640)).unwrap();
+    std::fs::File::options()
+        .write(true)
+        .open(target.join("a"))
+        .unwrap()
+        .set_modified(UNIX_EPOCH + Duration::from_secs(1_700_000_000))
+        .unwrap();
+    std::os::unix::fs::symlink("a", target.join("link")).unwrap();
+    write(&target.join("sub/c,d"), "x,y");
+    let archive = root.join("archive.tar");
+    snapshot(&target, &archive, &[]);
+    archive
+}
+
+/// The lines `list` prints for the archive in the format.
+fn listing(archive: &Path, format: &str) -> Vec<String> {
+    let output = sit_ok([
+        "list".as_ref(),
+        archive.as_os_str(),
+        "--list-format".as_ref(),
+        format.as_ref(),
+    ]);
+    String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(str::to_string)
+        .collect()
+}
+
+/// The owner of files the test writes, as `list` prints it.
+fn owner(root: &Path) -> (u32, u32) {
+    let metadata = root.metadata().unwrap();
+    (metadata.uid(), metadata.gid())
+}
+
+#[test]
+fn short_listings_give_the_kind_and_path_of_every_member() {
+    let root = tempfile::tempdir().unwrap();
+    let archive = archive(root.path());
+
+    let lines = listing(&archive, "short");
+    assert!(lines[0].starts_with("Snapshot 1: "), "{}", lines[0]);
+    assert!(lines[0].ends_with(", 5 entries"), "{}", lines[0]);
+    assert_eq!(
+        lines[1..],
+        [
+            "  directory  target/",
+            "  file       target/a",
+            "  symlink    target/link -> a",
+            "  directory  target/sub",
+            "  file       target/sub/c,d",
+        ]
+    );
+}
+
+#[test]
+fn long_listings_add_permissions_owner_size_and_mtime() {
+    let root = tempfile::tempdir().unwrap();
+    let archive = archive(root.path());
+    let (uid, gid) = owner(root.path());
+
+    let lines = listing(&archive, "long");
+    assert!(lines[0].starts_with("Snapshot 1: "), "{}", lines[0]);
+    let file = lines
+        .iter()
+        .find(|line| line.ends_with(" target/a"))
+        .unwrap();
+    // the size is that of the file, not of its compressed content
+    assert_eq!(
+        *file,
+        format!(
+            "  file       -rw-r----- {:>11}            5 2023-11-14 22:13 target/a",
+            format!("{}/{}", uid, gid)
+        )
+    );
+    let link = lines
+        .iter()
+        .find(|line| line.contains("target/link"))
+        .unwrap();
+    assert!(link.starts_with("  symlink    lrwxrwxrwx "), "{}", link);
+    assert!(link.ends_with(" target/link -> a"), "{}", link);
+}
+
+#[test]
+fn csv_listings_have_a_header_and_quote_fields_as_needed() {
+    let root = tempfile::tempdir().unwrap();
+    let archive = archive(root.path());
+    let (uid, gid) = owner(root.path());
+
+    let lines = listing(&archive, "csv");
+    assert_eq!(
+        lines[0],
+        "snapshot,id,kind,path,link,mode,uid,gid,size,mtime"
+    );
+    assert_eq!(lines.len(), 6);
+    let id = lines[1].split(',').nth(1).unwrap();
+    assert!(!id.is_empty());
+    assert_eq!(
+        lines[2],
+        format!(
+            "1,{},file,target/a,,0640,{},{},5,2023-11-14T22:13:20+00:00",
+            id, uid, gid
+        )
+    );
+    assert!(
+        lines[3].starts_with(&format!("1,{},symlink,target/link,a,0777,", id)),
+        "{}",
+        lines[3]
+    );
+    assert!(
+        lines[5].starts_with(&format!("1,{},file,\"target/sub/c,d\",,", id)),
+        "{}",
+        lines[5]
+    );
+}
+
+#[test]
+fn json_listings_give_a_line_per_member() {
+    let root = tempfile::tempdir().unwrap();
+    let archive = archive(root.path());
+    let (uid, gid) = owner(root.path());
+
+    let members: Vec<serde_json::Value> = listing(&archive, "json")
+        .iter()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(members.len(), 5);
+    let file = &members[1];
+    assert_eq!(file["snapshot"], 1);
+    assert_eq!(file["kind"], "file");
+    assert_eq!(file["path"], "target/a");
+    assert_eq!(file["link"], serde_json::Value::Null);
+    assert_eq!(file["mode"], "0640");
+    assert_eq!(file["uid"], uid);
+    assert_eq!(file["gid"], gid);
+    assert_eq!(file["size"], 5);
+    assert_eq!(file["mtime"], "2023-11-14T22:13:20+00:00");
+    assert_eq!(members[2]["kind"], "symlink");
+    assert_eq!(members[2]["link"], "a");
+    assert!(members.iter().all(|member| member["id"] == file["id"]));
+}
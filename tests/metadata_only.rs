@@ -0,0 +1,79 @@
+//! `--metadata-only` captures the layout of the target without reading what its files hold.
+#![cfg(unix)]
+
+mod common;
+
+use std::fs::File;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use common::{snapshot, snapshot_records, write, SNAPSHOT_RECORD};
+
+#[test]
+fn files_are_archived_empty_with_their_metadata() {
+    let root = tempfile::tempdir().unwrap();
+    let target = root.path().join("target");
+    write(&target.join("a"), "hello");
+    std::fs::set_permissions(target.join("a"), PermissionsExt::from_mode(0o640)).unwrap();
+    write(&target.join("sub/b"), "hi");
+    let (archive, report, manifest) = (
+        root.path().join("snapshot.tar.zst"),
+        root.path().join("report.json"),
+        root.path().join("manifest.json"),
+    );
+    snapshot(
+        &target,
+        &archive,
+        &[
+            "--metadata-only",
+            "--report",
+            report.to_str().unwrap(),
+            "--manifest",
+            manifest.to_str().unwrap(),
+        ],
+    );
+
+    let mut tarball = tar::Archive::new(File::open(&archive).unwrap());
+    let mut members = Vec::new();
+    for member in tarball.entries().unwrap() {
+        let member = member.unwrap();
+        let header = member.header();
+        if header.entry_type().is_file() && member.path().unwrap() != Path::new(SNAPSHOT_RECORD) {
+            members.push((
+                member.path().unwrap().to_string_lossy().into_owned(),
+                header.size().unwrap(),
+                header.mode().unwrap() & 0o7777,
+            ));
+        }
+    }
+    assert_eq!(
+        members,
+        [
+            ("target/a".to_string(), 0, 0o640),
+            ("target/sub/b".to_string(), 0, 0o644),
+        ]
+    );
+
+    let report: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(&report).unwrap()).unwrap();
+    assert_eq!(report["files"], 2);
+    assert_eq!(report["bytes_read"], 0);
+    assert_eq!(snapshot_records(&archive)[0]["metadata_only"], true);
+    // the manifest still tells how large the files were, but holds no digests of their content
+    let manifest: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(&manifest).unwrap()).unwrap();
+    let files: Vec<(&str, u64, bool)> = manifest["entries"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter(|entry| entry["kind"] == "file")
+        .map(|entry| {
+            (
+                entry["path"].as_str().unwrap(),
+                entry["size"].as_u64().unwrap(),
+                entry.get("digest").is_some(),
+            )
+        })
+        .collect();
+    assert_eq!(files, [("target/a", 5, false), ("target/sub/b", 2, false)]);
+}
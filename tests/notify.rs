@@ -0,0 +1,153 @@
+//! Checks what a run tells systemd over a mock notify socket.
+#![cfg(target_os = "linux")]
+
+mod common;
+
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
+use std::process::{Child, Stdio};
+use std::time::{Duration, Instant};
+
+use common::{command, write};
+
+/// A datagram socket standing in for systemd's, collecting what's sent to it.
+struct NotifySocket {
+    socket: UnixDatagram,
+    _directory: tempfile::TempDir,
+    path: std::path::PathBuf,
+}
+
+impl NotifySocket {
+    fn bind() -> Self {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("notify");
+        let socket = UnixDatagram::bind(&path).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        Self {
+            socket,
+            _directory: directory,
+            path,
+        }
+    }
+
+    /// Runs a snapshot of the target notifying this socket, with the extra arguments.
+    fn spawn(&self, target: &Path, extra: &[&str], watchdog_usec: Option<&str>) -> Child {
+        let output = target.parent().unwrap().join("snapshot.tar.zst");
+        let mut command = command();
+        command
+            .arg("-t")
+            .arg(target)
+            .arg("-o")
+            .arg(output)
+            .args(extra)
+            .env("NOTIFY_SOCKET", &self.path)
+            .env_remove("WATCHDOG_PID")
+            .stdout(Stdio::null());
+        match watchdog_usec {
+            Some(usec) => command.env("WATCHDOG_USEC", usec),
+            None => command.env_remove("WATCHDOG_USEC"),
+        };
+        command.spawn().unwrap()
+    }
+
+    /// The next notification, if one comes soon.
+    fn next(&self) -> Option<String> {
+        let mut buffer = [0; 1024];
+        let received = self.socket.recv(&mut buffer).ok()?;
+        Some(String::from_utf8_lossy(&buffer[..received]).into_owned())
+    }
+
+    /// Every notification sent until the run exits, and shortly after.
+    fn collect(&self, mut child: Child) -> Vec<String> {
+        let mut received = Vec::new();
+        while child.try_wait().unwrap().is_none() {
+            received.extend(self.next());
+        }
+        while let Some(notification) = self.next() {
+            received.push(notification);
+        }
+        received
+    }
+}
+
+fn target(root: &Path) -> std::path::PathBuf {
+    let target = root.join("target");
+    write(&target.join("file"), "content");
+    target
+}
+
+#[test]
+fn a_snapshot_notifies_ready_then_what_its_doing() {
+    let root = tempfile::tempdir().unwrap();
+    let socket = NotifySocket::bind();
+    let child = socket.spawn(&target(root.path()), &[], None);
+    let received = socket.collect(child);
+    assert_eq!(received.first().map(String::as_str), Some("READY=1"));
+    assert!(
+        received.contains(&"STATUS=Archiving".to_string()),
+        "{:?}",
+        received
+    );
+    assert!(!received.contains(&"STOPPING=1".to_string()));
+    assert!(!received.contains(&"WATCHDOG=1".to_string()));
+}
+
+#[test]
+fn other_commands_dont_notify_ready() {
+    let root = tempfile::tempdir().unwrap();
+    let target = target(root.path());
+    let socket = NotifySocket::bind();
+    let child = socket.spawn(&target, &[], None);
+    socket.collect(child);
+
+    let child = command()
+        .arg("list")
+        .arg(root.path().join("snapshot.tar.zst"))
+        .env("NOTIFY_SOCKET", &socket.path)
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    let received = socket.collect(child);
+    assert!(!received.contains(&"READY=1".to_string()), "{:?}", received);
+}
+
+#[test]
+fn the_watchdog_is_fed_while_the_snapshot_runs() {
+    let root = tempfile::tempdir().unwrap();
+    let socket = NotifySocket::bind();
+    // pings every 50 ms while the hook holds the snapshot up
+    let child = socket.spawn(
+        &target(root.path()),
+        &["--pre-hook", "sleep 0.5"],
+        Some("100000"),
+    );
+    let received = socket.collect(child);
+    let pings = received
+        .iter()
+        .filter(|notification| *notification == "WATCHDOG=1")
+        .count();
+    assert!(pings >= 3, "{:?}", received);
+}
+
+#[test]
+fn a_signal_notifies_stopping() {
+    let root = tempfile::tempdir().unwrap();
+    let socket = NotifySocket::bind();
+    let child = socket.spawn(&target(root.path()), &["--pre-hook", "sleep 1"], None);
+    let started = Instant::now();
+    while socket.next().as_deref() != Some("READY=1") {
+        assert!(
+            started.elapsed() < Duration::from_secs(10),
+            "never got ready"
+        );
+    }
+    unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGINT) };
+    let received = socket.collect(child);
+    assert!(
+        received.contains(&"STOPPING=1".to_string()),
+        "{:?}",
+        received
+    );
+}
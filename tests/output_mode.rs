@@ -0,0 +1,72 @@
+//! `--output-mode` sets the permissions of the archive and every file written beside it.
+#![cfg(unix)]
+
+mod common;
+
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use common::{snapshot, write};
+
+/// Snapshots a target with every sidecar, returning the mode of each file written.
+fn output_modes(extra: &[&str]) -> Vec<(String, u32)> {
+    let root = tempfile::tempdir().unwrap();
+    let target = root.path().join("target");
+    write(&target.join("file"), "content");
+    let output = root.path().join("output");
+    std::fs::create_dir(&output).unwrap();
+    let (archive, manifest, report) = (
+        output.join("snapshot.tar.zst"),
+        output.join("manifest.json"),
+        output.join("report.json"),
+    );
+    let mut args = vec![
+        "--checksum",
+        "sha256",
+        "--index",
+        "--manifest",
+        manifest.to_str().unwrap(),
+        "--report",
+        report.to_str().unwrap(),
+    ];
+    args.extend(extra);
+    snapshot(&target, &archive, &args);
+    let mut modes: Vec<(String, u32)> = std::fs::read_dir(&output)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .map(|path| (name(&path), mode(&path)))
+        .collect();
+    modes.sort();
+    modes
+}
+
+fn name(path: &Path) -> String {
+    path.file_name().unwrap().to_string_lossy().into_owned()
+}
+
+fn mode(path: &Path) -> u32 {
+    path.metadata().unwrap().permissions().mode() & 0o7777
+}
+
+fn expected(mode: u32) -> Vec<(String, u32)> {
+    [
+        "manifest.json",
+        "report.json",
+        "snapshot.tar.zst",
+        "snapshot.tar.zst.idx",
+        "snapshot.tar.zst.sha256",
+    ]
+    .iter()
+    .map(|name| (name.to_string(), mode))
+    .collect()
+}
+
+#[test]
+fn output_is_readable_only_by_its_owner_by_default() {
+    assert_eq!(output_modes(&[]), expected(0o600));
+}
+
+#[test]
+fn output_takes_the_mode_given() {
+    assert_eq!(output_modes(&["--output-mode", "640"]), expected(0o640));
+}
@@ -0,0 +1,152 @@
+//! Checks how `--mode` and the flags overriding it decide how a snapshot ends.
+#![cfg(unix)]
+
+mod common;
+
+use std::ffi::OsString;
+use std::path::Path;
+use std::process::Output;
+
+use common::{code, sit, tar_members, write};
+
+/// Snapshots a target holding a file and a symlink leading outside of it with the extra
+/// arguments, returning how the run ended and whether it left an archive.
+fn snapshot(extra: &[&str]) -> (Output, bool) {
+    let target = tempfile::tempdir().unwrap();
+    write(&target.path().join("file"), "content");
+    std::os::unix::fs::symlink("/", target.path().join("root")).unwrap();
+    let output = tempfile::tempdir().unwrap();
+    let archive = output.path().join("snapshot.tar.zst");
+    let mut args: Vec<OsString> = vec![
+        "-t".into(),
+        target.path().into(),
+        "-o".into(),
+        archive.clone().into(),
+    ];
+    args.extend(extra.iter().map(OsString::from));
+    let run = sit(args);
+    (run, Path::new(&archive).exists())
+}
+
+fn assert_ends(extra: &[&str], expected: i32, archived: bool) {
+    let (output, written) = snapshot(extra);
+    assert_eq!(
+        (code(&output), written),
+        (expected, archived),
+        "{:?}\nstderr: {}",
+        extra,
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+#[test]
+fn without_a_mode_leaving_out_a_symlink_only_warns() {
+    assert_ends(&[], 0, true);
+}
+
+#[test]
+fn refusing_overrides_the_default() {
+    assert_ends(&["--refuse", "outside-target"], 5, false);
+}
+
+#[test]
+fn tolerating_overrides_a_strict_mode() {
+    assert_ends(
+        &["--mode", "strict", "--tolerate", "outside-target"],
+        13,
+        true,
+    );
+}
+
+#[test]
+fn refusing_wins_over_tolerating() {
+    assert_ends(
+        &[
+            "--mode",
+            "best-effort",
+            "--tolerate",
+            "outside-target",
+            "--refuse",
+            "outside-target",
+        ],
+        5,
+        false,
+    );
+}
+
+#[test]
+fn the_unreadable_flags_leave_other_compromises_to_the_mode() {
+    assert_ends(&["--mode", "strict", "--ignore-file-errors"], 5, false);
+    assert_ends(&["--mode", "best-effort", "--keep-going"], 13, true);
+}
+
+#[test]
+fn contradictory_shorthands_are_refused_as_bad_arguments() {
+    assert_ends(&["--strict", "--keep-going"], 15, false);
+    assert_ends(&["--strict", "--best-effort-files"], 15, false);
+    assert_ends(&["--ignore-file-errors", "--keep-going"], 15, false);
+    assert_ends(&["--ignore-file-errors", "--strict"], 15, false);
+}
+
+#[test]
+fn allowing_partial_snapshots_is_tolerating_them() {
+    let slow = ["--timeout", "100ms", "--pre-hook", "sleep 1"];
+    let with = |extra: &[&'static str]| [&slow[..], extra].concat();
+    assert_ends(&with(&[]), 7, false);
+    assert_ends(&with(&["--allow-partial"]), 7, true);
+    assert_ends(&with(&["--tolerate", "partial"]), 7, true);
+    // the shorthand is overridden by refusing, like tolerating is
+    assert_ends(&with(&["--allow-partial", "--refuse", "partial"]), 7, false);
+}
+
+/// Snapshots the files listed from a target holding `a` and `b`, with `missing` listed between
+/// them, returning how the run ended, the paths archived and the report.
+fn snapshot_listed(extra: &[&str]) -> (i32, Option<Vec<String>>, serde_json::Value) {
+    let target = tempfile::tempdir().unwrap();
+    write(&target.path().join("a"), "a");
+    write(&target.path().join("b"), "b");
+    let output = tempfile::tempdir().unwrap();
+    let list = output.path().join("list");
+    write(&list, "a\nmissing\nb\n");
+    let archive = output.path().join("snapshot.tar.zst");
+    let report = output.path().join("report.json");
+    let mut args: Vec<OsString> = vec![
+        "-t".into(),
+        target.path().into(),
+        "-o".into(),
+        archive.clone().into(),
+        "--files-from".into(),
+        list.into(),
+        "--report".into(),
+        report.clone().into(),
+    ];
+    args.extend(extra.iter().map(OsString::from));
+    let run = sit(args);
+    let name = target.path().file_name().unwrap().to_string_lossy();
+    let archived = archive.exists().then(|| {
+        tar_members(&archive)
+            .into_iter()
+            .filter_map(|(path, _)| Some(path.strip_prefix(&format!("{}/", name))?.to_string()))
+            .filter(|path| !path.is_empty())
+            .collect()
+    });
+    let report = serde_json::from_slice(&std::fs::read(report).unwrap()).unwrap();
+    (code(&run), archived, report)
+}
+
+#[test]
+fn keeping_going_archives_the_files_which_could_be_read() {
+    let (code, archived, report) = snapshot_listed(&["--keep-going"]);
+    assert_eq!(code, 13);
+    assert_eq!(archived.unwrap(), ["a", "b"]);
+    let skipped = report["skipped"].as_array().unwrap();
+    assert_eq!(skipped.len(), 1, "{:?}", skipped);
+    assert!(skipped[0]["path"].as_str().unwrap().ends_with("/missing"));
+    assert_eq!(skipped[0]["code"], "unreadable");
+
+    // ignoring them archives the same files, without the exit code saying any were left out
+    let (code, archived, _) = snapshot_listed(&["--ignore-file-errors"]);
+    assert_eq!((code, archived.unwrap()), (0, vec!["a".into(), "b".into()]));
+    let (code, archived, _) = snapshot_listed(&["--strict"]);
+    assert_eq!((code, archived), (5, None));
+}
@@ -0,0 +1,83 @@
+//! `--report-skipped` lists every path left out of a snapshot, one JSON object per line.
+#![cfg(unix)]
+
+mod common;
+
+use std::ffi::OsString;
+use std::path::Path;
+
+use common::{code, sit, write};
+
+/// Snapshots the target, returning the exit code and every line of the skipped list as its path
+/// relative to the target and its reason code.
+fn skipped(target: &Path, extra: &[&str]) -> (i32, Vec<(String, String)>) {
+    let output = tempfile::tempdir().unwrap();
+    let listed = output.path().join("skipped.jsonl");
+    let mut args: Vec<OsString> = vec![
+        "-t".into(),
+        target.into(),
+        "-o".into(),
+        output.path().join("snapshot.tar.zst").into(),
+        "--report-skipped".into(),
+        listed.clone().into(),
+    ];
+    args.extend(extra.iter().map(OsString::from));
+    let code = code(&sit(args));
+    let lines = std::fs::read_to_string(listed)
+        .unwrap()
+        .lines()
+        .map(|line| {
+            let line: serde_json::Value = serde_json::from_str(line).unwrap();
+            assert!(line["reason"]
+                .as_str()
+                .is_some_and(|reason| !reason.is_empty()));
+            let path = Path::new(line["path"].as_str().unwrap());
+            (
+                path.strip_prefix(target)
+                    .unwrap()
+                    .to_string_lossy()
+                    .into_owned(),
+                line["code"].as_str().unwrap().to_string(),
+            )
+        })
+        .collect();
+    (code, lines)
+}
+
+#[test]
+fn skipped_paths_are_listed_with_their_reason_codes() {
+    let root = tempfile::tempdir().unwrap();
+    let target = root.path().join("target");
+    write(&target.join("kept"), "kept");
+    write(&target.join(".hidden"), "hidden");
+    write(&root.path().join("outside"), "outside");
+    std::os::unix::fs::symlink(root.path().join("outside"), target.join("link")).unwrap();
+
+    assert_eq!(
+        skipped(&target, &["--exclude-hidden"]),
+        (
+            0,
+            vec![
+                (".hidden".to_string(), "excluded".to_string()),
+                ("link".to_string(), "outside_target".to_string()),
+            ]
+        )
+    );
+}
+
+#[test]
+fn skipped_paths_are_listed_even_when_the_snapshot_fails() {
+    let root = tempfile::tempdir().unwrap();
+    let target = root.path().join("target");
+    write(&target.join("kept"), "kept");
+    let list = root.path().join("list");
+    write(&list, "kept\nmissing\n");
+
+    assert_eq!(
+        skipped(
+            &target,
+            &["--files-from", list.to_str().unwrap(), "--strict"]
+        ),
+        (5, vec![("missing".to_string(), "unreadable".to_string())])
+    );
+}
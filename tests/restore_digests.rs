@@ -0,0 +1,64 @@
+//! Restoring checks every file against the digest recorded by `--checksum`.
+mod common;
+
+use std::fs::File;
+use std::path::Path;
+
+use common::{code, restore, sit, snapshot, write};
+
+/// Rewrites the archive with the content of one member swapped for other, validly compressed,
+/// content, as if it had been corrupted in a way the compression can't catch.
+fn swap_content(archive: &Path, member: &str, content: &str) {
+    let mut original = tar::Archive::new(File::open(archive).unwrap());
+    let mut rewritten = tar::Builder::new(Vec::new());
+    for entry in original.entries().unwrap() {
+        let mut entry = entry.unwrap();
+        let mut header = entry.header().clone();
+        let mut data = Vec::new();
+        std::io::Read::read_to_end(&mut entry, &mut data).unwrap();
+        if entry.path().unwrap() == Path::new(member) {
+            data = zstd::encode_all(content.as_bytes(), 3).unwrap();
+            header.set_size(data.len() as u64);
+            header.set_cksum();
+        }
+        rewritten.append(&header, &data[..]).unwrap();
+    }
+    std::fs::write(archive, rewritten.into_inner().unwrap()).unwrap();
+}
+
+#[test]
+fn restoring_a_corrupted_file_aborts() {
+    let root = tempfile::tempdir().unwrap();
+    let target = root.path().join("target");
+    write(&target.join("a"), "original");
+    write(&target.join("b"), "untouched");
+    let archive = root.path().join("snapshot.tar.zst");
+    snapshot(&target, &archive, &["--checksum", "sha256"]);
+    swap_content(&archive, "target/a", "corrupted");
+
+    let restored = tempfile::tempdir().unwrap();
+    let output = sit([
+        "restore".as_ref(),
+        archive.as_os_str(),
+        "--into".as_ref(),
+        restored.path().as_os_str(),
+    ]);
+    let logged = String::from_utf8_lossy(&output.stdout);
+    assert_ne!(code(&output), 0, "{}", logged);
+    assert!(logged.contains("target/a"), "{}", logged);
+    assert!(
+        logged.contains("doesn't match the digest it was captured with"),
+        "{}",
+        logged
+    );
+    // nothing after the corrupted file is restored
+    assert!(!restored.path().join("target/b").exists());
+
+    // asked not to check, the corrupted content is restored as it is
+    let unchecked = tempfile::tempdir().unwrap();
+    restore(&archive, unchecked.path(), &["--no-verify-restore"]);
+    assert_eq!(
+        std::fs::read_to_string(unchecked.path().join("target/a")).unwrap(),
+        "corrupted"
+    );
+}
@@ -0,0 +1,127 @@
+mod common;
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+use common::{code, sit, write};
+
+const TOKEN: &str = "s3cret";
+
+/// A `serve` process, killed once dropped.
+struct Server {
+    child: Child,
+    address: String,
+}
+
+impl Server {
+    fn start(archive: &Path) -> Self {
+        // a port free a moment ago, which the server binds instead
+        let address = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+        let child = Command::new(env!("CARGO_BIN_EXE_saved_in_time"))
+            .args(["--log-level", "error", "serve"])
+            .arg(archive)
+            .args(["--listen", &address, "--token-env", "SIT_TEST_TOKEN"])
+            .env("SIT_TEST_TOKEN", TOKEN)
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap();
+        let started = Instant::now();
+        while TcpStream::connect(&address).is_err() {
+            assert!(
+                started.elapsed() < Duration::from_secs(10),
+                "serve never listened"
+            );
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        Self { child, address }
+    }
+
+    /// Sends an HTTP/1.0 GET request for the raw path, exactly as given, so the body comes back
+    /// whole rather than chunked. Returns the status and body.
+    fn get(&self, path: &str, headers: &[(&str, &str)]) -> (u16, Vec<u8>) {
+        let mut stream = TcpStream::connect(&self.address).unwrap();
+        let mut request = format!("GET {} HTTP/1.0\r\nHost: {}\r\n", path, self.address);
+        for (name, value) in headers {
+            request.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        let head_end = response
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .expect("no end to the response's headers");
+        let head = String::from_utf8_lossy(&response[..head_end]);
+        let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+        (status, response[head_end + 4..].to_vec())
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn authorized<'a>(headers: &[(&'a str, &'a str)]) -> Vec<(&'a str, &'a str)> {
+    let mut headers = headers.to_vec();
+    headers.push(("Authorization", "Bearer s3cret"));
+    headers
+}
+
+#[test]
+fn serves_files_by_range_to_token_holders_only() {
+    let target = tempfile::tempdir().unwrap();
+    write(&target.path().join("dir/file"), "0123456789");
+    write(&target.path().join("secret"), "outside the archive");
+    let output = tempfile::tempdir().unwrap();
+    let archive = output.path().join("snapshot.tar.zst");
+    let snapshot = sit([
+        "-t".as_ref(),
+        target.path().join("dir").as_os_str(),
+        "-o".as_ref(),
+        archive.as_os_str(),
+    ]);
+    assert_eq!(code(&snapshot), 0);
+    let server = Server::start(&archive);
+
+    let (status, _) = server.get("/dir/file", &[]);
+    assert_eq!(status, 401);
+    let (status, _) = server.get("/dir/file", &[("Authorization", "Bearer wrong")]);
+    assert_eq!(status, 401);
+
+    let (status, body) = server.get("/dir/file", &authorized(&[]));
+    assert_eq!((status, body.as_slice()), (200, b"0123456789".as_slice()));
+    let (status, body) = server.get("/dir/file", &authorized(&[("Range", "bytes=2-4")]));
+    assert_eq!((status, body.as_slice()), (206, b"234".as_slice()));
+    let (status, body) = server.get("/dir/file", &authorized(&[("Range", "bytes=-3")]));
+    assert_eq!((status, body.as_slice()), (206, b"789".as_slice()));
+    // the largest end there is can't overflow working out the range
+    let (status, body) = server.get(
+        "/dir/file",
+        &authorized(&[("Range", "bytes=0-18446744073709551615")]),
+    );
+    assert_eq!((status, body.as_slice()), (206, b"0123456789".as_slice()));
+    let (status, _) = server.get("/dir/file", &authorized(&[("Range", "bytes=10-")]));
+    assert_eq!(status, 416);
+
+    for escaping in [
+        "/dir/../secret",
+        "/dir/%2e%2e/secret",
+        "/dir/..%2fsecret",
+        "/..",
+    ] {
+        let (status, body) = server.get(escaping, &authorized(&[]));
+        assert_eq!(status, 400, "{}", escaping);
+        assert!(!String::from_utf8_lossy(&body).contains("outside the archive"));
+    }
+}
@@ -0,0 +1,70 @@
+//! `--since-time` only captures files modified after an instant, like the one `--stamp-file` keeps.
+
+mod common;
+
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use common::{snapshot, tar_members, write};
+
+fn set_modified(path: &Path, modified: SystemTime) {
+    std::fs::File::open(path)
+        .unwrap()
+        .set_modified(modified)
+        .unwrap();
+}
+
+/// Paths in the archive, relative to the target.
+fn archived(archive: &Path) -> Vec<String> {
+    tar_members(archive)
+        .into_iter()
+        .map(|(path, _)| path.split_once('/').unwrap().1.to_string())
+        .collect()
+}
+
+#[test]
+fn files_modified_at_or_before_the_instant_are_left_out() {
+    let target = tempfile::tempdir().unwrap();
+    let instant = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    for (path, modified) in [
+        ("before", instant - Duration::from_secs(1)),
+        ("at", instant),
+        ("after", instant + Duration::from_secs(1)),
+        ("sub/before", instant - Duration::from_secs(1)),
+        ("sub/after", instant + Duration::from_secs(1)),
+    ] {
+        write(&target.path().join(path), path);
+        set_modified(&target.path().join(path), modified);
+    }
+    let output = tempfile::tempdir().unwrap();
+    let archive = output.path().join("snapshot.tar.zst");
+    snapshot(target.path(), &archive, &["--since-time", "@1700000000"]);
+    // directories are kept, so the files beneath them are still looked at
+    assert_eq!(archived(&archive), ["", "after", "sub", "sub/after"]);
+}
+
+#[test]
+fn the_stamp_of_one_snapshot_starts_the_next() {
+    let target = tempfile::tempdir().unwrap();
+    write(&target.path().join("unchanged"), "unchanged");
+    write(&target.path().join("changed"), "before");
+    let old = SystemTime::now() - Duration::from_secs(3600);
+    for path in ["unchanged", "changed"] {
+        set_modified(&target.path().join(path), old);
+    }
+    let output = tempfile::tempdir().unwrap();
+    let stamp = output.path().join("stamp");
+    let first = output.path().join("first.tar.zst");
+    snapshot(
+        target.path(),
+        &first,
+        &["--stamp-file", stamp.to_str().unwrap()],
+    );
+    assert_eq!(archived(&first), ["", "changed", "unchanged"]);
+
+    write(&target.path().join("changed"), "after");
+    let since = std::fs::read_to_string(&stamp).unwrap();
+    let second = output.path().join("second.tar.zst");
+    snapshot(target.path(), &second, &["--since-time", since.trim()]);
+    assert_eq!(archived(&second), ["", "changed"]);
+}
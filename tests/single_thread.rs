@@ -0,0 +1,71 @@
+mod common;
+
+use std::path::Path;
+use std::process::Command;
+
+use common::{code, tar_members, write};
+
+/// Snapshots the target, returning the archive's members and what was logged.
+fn snapshot(target: &Path, output: &Path, flags: &[&str]) -> (Vec<(String, Vec<u8>)>, String) {
+    let run = Command::new(env!("CARGO_BIN_EXE_saved_in_time"))
+        .args(["--log-level", "debug", "-t"])
+        .arg(target)
+        .arg("-o")
+        .arg(output)
+        .args(flags)
+        .output()
+        .expect("failed to run saved_in_time");
+    let logged = String::from_utf8_lossy(&run.stdout).into_owned();
+    assert_eq!(code(&run), 0, "{}", logged);
+    (tar_members(output), logged)
+}
+
+#[test]
+fn single_threaded_snapshots_match_the_default_across_runs() {
+    let target = tempfile::tempdir().unwrap();
+    // created out of order, so the order they're listed in can't line up by chance
+    for name in ["m", "z/b", "a", "z/a/c", "b"] {
+        write(&target.path().join(name), name.repeat(100));
+    }
+    // large enough that zstd would be worth running with workers, were it allowed to
+    let large: Vec<u8> = (0..9 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+    write(&target.path().join("large"), large);
+
+    let output = tempfile::tempdir().unwrap();
+    let (first, first_log) = snapshot(
+        target.path(),
+        &output.path().join("first.tar.zst"),
+        &["--single-thread"],
+    );
+    let (second, _) = snapshot(
+        target.path(),
+        &output.path().join("second.tar.zst"),
+        &["--single-thread"],
+    );
+    let (default, default_log) =
+        snapshot(target.path(), &output.path().join("default.tar.zst"), &[]);
+
+    let name = target.path().file_name().unwrap().to_string_lossy();
+    let paths: Vec<&str> = first.iter().map(|(path, _)| path.as_str()).collect();
+    let expected: Vec<String> = ["", "a", "b", "large", "m", "z", "z/a", "z/a/c", "z/b"]
+        .iter()
+        .map(|path| match path {
+            &"" => format!("{}/", name),
+            path => format!("{}/{}", name, path),
+        })
+        .collect();
+    assert_eq!(paths, expected);
+    assert!(first == second, "the two runs archived different content");
+    // the flag only makes explicit how every snapshot runs
+    assert!(
+        first == default,
+        "the flag archived different content than the default"
+    );
+    for logged in [first_log, default_log] {
+        assert!(
+            logged.contains("Compressing captures with 1 worker"),
+            "{}",
+            logged
+        );
+    }
+}
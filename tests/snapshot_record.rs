@@ -0,0 +1,100 @@
+//! What the record at the root of every snapshot holds, and how it's read back.
+
+mod common;
+
+use common::{code, sit, snapshot_records, write};
+
+#[test]
+fn urls_given_for_notifying_never_reach_the_archive() {
+    let target = tempfile::tempdir().unwrap();
+    write(&target.path().join("file"), "content");
+    let output = tempfile::tempdir().unwrap();
+    let archive = output.path().join("snapshot.tar.zst");
+    // nothing listens on either, so the snapshot only warns about them
+    let run = sit([
+        "-t".into(),
+        target.path().as_os_str().to_owned(),
+        "-o".into(),
+        archive.clone().into_os_string(),
+        "--webhook".into(),
+        "http://127.0.0.1:9/hooks/webhook-secret".into(),
+        "--ping-url=http://127.0.0.1:9/ping-secret".into(),
+        "--webhook-timeout".into(),
+        "1s".into(),
+    ]);
+    assert_eq!(code(&run), 0, "{}", String::from_utf8_lossy(&run.stdout));
+
+    let records = snapshot_records(&archive);
+    let arguments: Vec<&str> = records[0]["arguments"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|argument| argument.as_str().unwrap())
+        .collect();
+    assert!(arguments.contains(&"--webhook"), "{:?}", arguments);
+    assert!(
+        arguments.contains(&"--ping-url=<redacted>"),
+        "{:?}",
+        arguments
+    );
+    let written = std::fs::read(&archive).unwrap();
+    for secret in ["webhook-secret", "ping-secret"] {
+        assert!(
+            !written
+                .windows(secret.len())
+                .any(|window| window == secret.as_bytes()),
+            "{} was written into the archive",
+            secret
+        );
+    }
+}
+
+#[test]
+fn the_generated_records_cat_as_plain_json() {
+    let target = tempfile::tempdir().unwrap();
+    write(&target.path().join("file"), "content");
+    let output = tempfile::tempdir().unwrap();
+    // with an index, cat finds members through it instead of reading through the archive
+    for (name, extra) in [("plain", None), ("indexed", Some("--index"))] {
+        let archive = output.path().join(format!("{}.tar.zst", name));
+        let mut args = vec![
+            "-t".into(),
+            target.path().as_os_str().to_owned(),
+            "-o".into(),
+            archive.clone().into_os_string(),
+            "--checksum".into(),
+            "sha256".into(),
+        ];
+        args.extend(extra.map(Into::into));
+        let run = sit(args);
+        assert_eq!(code(&run), 0, "{}", String::from_utf8_lossy(&run.stdout));
+
+        let cat = |path: &str| {
+            let run = sit(["cat".as_ref(), archive.as_os_str(), path.as_ref()]);
+            assert_eq!(code(&run), 0, "{}", String::from_utf8_lossy(&run.stdout));
+            serde_json::from_slice::<serde_json::Value>(&run.stdout)
+                .unwrap_or_else(|err| panic!("{} isn't JSON: {}", path, err))
+        };
+        assert_eq!(
+            cat(".sit-snapshot.json"),
+            snapshot_records(&archive)[0],
+            "{}",
+            name
+        );
+        let manifest = cat(".sit-manifest.json");
+        let file = format!(
+            "{}/file",
+            target.path().file_name().unwrap().to_str().unwrap()
+        );
+        assert!(
+            manifest["entries"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .any(|entry| entry["path"] == file.as_str()),
+            "{}: {}",
+            name,
+            manifest
+        );
+    }
+}
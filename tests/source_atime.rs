@@ -0,0 +1,43 @@
+//! `--preserve-source-atime` reads files without updating when they were last accessed.
+#![cfg(target_os = "linux")]
+
+mod common;
+
+use std::fs::FileTimes;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use common::{snapshot, write};
+
+/// Snapshots a file last accessed long before it was modified, which even `relatime` updates on
+/// the next read, returning whether its access time was left alone.
+fn atime_preserved(extra: &[&str]) -> bool {
+    let root = tempfile::tempdir().unwrap();
+    let target = root.path().join("target");
+    let file = target.join("file");
+    write(&file, "content");
+    let accessed = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+    std::fs::File::options()
+        .write(true)
+        .open(&file)
+        .unwrap()
+        .set_times(FileTimes::new().set_accessed(accessed))
+        .unwrap();
+    assert_eq!(atime(&file), accessed);
+
+    snapshot(&target, &root.path().join("snapshot.tar.zst"), extra);
+    atime(&file) == accessed
+}
+
+fn atime(path: &Path) -> SystemTime {
+    path.metadata().unwrap().accessed().unwrap()
+}
+
+#[test]
+fn source_access_times_are_kept_when_asked() {
+    if atime_preserved(&[]) {
+        eprintln!("reads don't update access times on this filesystem; skipping");
+        return;
+    }
+    assert!(atime_preserved(&["--preserve-source-atime"]));
+}
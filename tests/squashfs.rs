@@ -0,0 +1,48 @@
+//! Checks SquashFS images extract with the system's `unsquashfs`, skipping when it isn't
+//! installed.
+#![cfg(unix)]
+
+mod common;
+
+use std::process::Command;
+
+use common::{assert_extracted, code, installed, sit, write_fixture};
+
+#[test]
+fn an_image_extracts_with_unsquashfs() {
+    if !installed("unsquashfs") {
+        return;
+    }
+    let target = tempfile::tempdir().unwrap();
+    write_fixture(target.path());
+    let output = tempfile::tempdir().unwrap();
+    let image = output.path().join("snapshot.squashfs");
+    let run = sit([
+        "-t".as_ref(),
+        target.path().as_os_str(),
+        "-o".as_ref(),
+        image.as_os_str(),
+    ]);
+    assert_eq!(
+        code(&run),
+        0,
+        "stderr: {}",
+        String::from_utf8_lossy(&run.stderr)
+    );
+
+    // unsquashfs creates the directory it extracts into itself
+    let extracted = output.path().join("extracted");
+    let unsquashfs = Command::new("unsquashfs")
+        .arg("-d")
+        .arg(&extracted)
+        .arg(&image)
+        .output()
+        .unwrap();
+    assert!(
+        unsquashfs.status.success(),
+        "unsquashfs failed: {}{}",
+        String::from_utf8_lossy(&unsquashfs.stdout),
+        String::from_utf8_lossy(&unsquashfs.stderr)
+    );
+    assert_extracted(target.path(), &extracted);
+}
@@ -0,0 +1,59 @@
+//! Symlinks into the target are restored pointing at the same entries wherever they land.
+#![cfg(unix)]
+
+mod common;
+
+use std::os::unix::fs::symlink;
+use std::path::Path;
+
+use common::{restore, snapshot, write};
+
+#[test]
+fn absolute_links_into_the_target_are_restored_relative_to_their_directory() {
+    let root = tempfile::tempdir().unwrap();
+    let target = root.path().join("target");
+    write(&target.join("top"), "top");
+    write(&target.join("a/b/deep"), "deep");
+    write(&target.join("c/sibling"), "sibling");
+    // absolute links, up, down, across and to their own directory
+    symlink(target.join("top"), target.join("a/b/up")).unwrap();
+    symlink(target.join("a/b/deep"), target.join("down")).unwrap();
+    symlink(target.join("c/sibling"), target.join("a/across")).unwrap();
+    symlink(target.join("a/b"), target.join("a/b/itself")).unwrap();
+    // relative links are stored as they are
+    symlink("../c/sibling", target.join("a/relative")).unwrap();
+
+    let archive = root.path().join("snapshot.tar.zst");
+    snapshot(&target, &archive, &[]);
+    // moved away from where it was taken, so links still pointing at the target would dangle
+    let restored = tempfile::tempdir().unwrap();
+    restore(&archive, restored.path(), &[]);
+    std::fs::remove_dir_all(&target).unwrap();
+    let restored = restored.path().join("target");
+
+    for (link, stored, content) in [
+        ("a/b/up", "../../top", "top"),
+        ("down", "a/b/deep", "deep"),
+        ("a/across", "../c/sibling", "sibling"),
+        ("a/relative", "../c/sibling", "sibling"),
+    ] {
+        assert_eq!(
+            std::fs::read_link(restored.join(link)).unwrap(),
+            Path::new(stored),
+            "{}",
+            link
+        );
+        assert_eq!(
+            std::fs::read_to_string(restored.join(link)).unwrap(),
+            content
+        );
+    }
+    assert_eq!(
+        std::fs::read_link(restored.join("a/b/itself")).unwrap(),
+        Path::new(".")
+    );
+    assert_eq!(
+        std::fs::read_to_string(restored.join("a/b/itself/deep")).unwrap(),
+        "deep"
+    );
+}
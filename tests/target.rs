@@ -0,0 +1,101 @@
+//! Every way of naming the target snapshots the same directory, recorded under its resolved path.
+
+mod common;
+
+use std::path::Path;
+
+use common::{code, command, snapshot_records, tar_members, write};
+
+/// Snapshots the target as given from the working directory, returning the archive's members
+/// and its snapshot record.
+fn snapshot(
+    working_directory: &Path,
+    target: &Path,
+    output: &Path,
+) -> (Vec<String>, serde_json::Value) {
+    let run = command()
+        .current_dir(working_directory)
+        .arg("-t")
+        .arg(target)
+        .arg("-o")
+        .arg(output)
+        .output()
+        .expect("failed to run saved_in_time");
+    assert_eq!(code(&run), 0, "{}", String::from_utf8_lossy(&run.stdout));
+    let paths = tar_members(output)
+        .into_iter()
+        .map(|(path, _)| path)
+        .collect();
+    let mut records = snapshot_records(output);
+    assert_eq!(records.len(), 1);
+    (paths, records.remove(0))
+}
+
+/// A directory holding the target, named `target`, beside an empty sibling to step out of.
+fn layout() -> tempfile::TempDir {
+    let root = tempfile::tempdir().unwrap();
+    write(&root.path().join("target/file"), "content");
+    write(&root.path().join("target/nested/file"), "nested");
+    std::fs::create_dir(root.path().join("sibling")).unwrap();
+    root
+}
+
+fn assert_target(paths: &[String], record: &serde_json::Value, resolved: &Path) {
+    assert_eq!(
+        paths,
+        [
+            "target/",
+            "target/file",
+            "target/nested",
+            "target/nested/file"
+        ]
+    );
+    assert_eq!(record["target"], resolved.to_str().unwrap());
+}
+
+#[test]
+fn the_current_directory_is_snapshotted_under_its_name() {
+    let root = layout();
+    let resolved = root.path().join("target").canonicalize().unwrap();
+    let output = tempfile::tempdir().unwrap();
+    let (paths, record) = snapshot(
+        &resolved,
+        Path::new("./"),
+        &output.path().join("snapshot.tar.zst"),
+    );
+    assert_target(&paths, &record, &resolved);
+    assert!(record.get("invoked_as").is_none(), "{}", record);
+}
+
+#[test]
+fn a_relative_target_leading_out_of_the_working_directory_is_resolved() {
+    let root = layout();
+    let resolved = root.path().join("target").canonicalize().unwrap();
+    let output = tempfile::tempdir().unwrap();
+    let (paths, record) = snapshot(
+        &root.path().join("sibling"),
+        Path::new("../target"),
+        &output.path().join("snapshot.tar.zst"),
+    );
+    assert_target(&paths, &record, &resolved);
+    assert!(record.get("invoked_as").is_none(), "{}", record);
+}
+
+#[test]
+#[cfg(unix)]
+fn a_symlinked_target_is_recorded_under_its_resolved_path_and_the_link() {
+    let root = layout();
+    let resolved = root.path().join("target").canonicalize().unwrap();
+    std::os::unix::fs::symlink(&resolved, root.path().join("sibling/link")).unwrap();
+    let output = tempfile::tempdir().unwrap();
+    let (paths, record) = snapshot(
+        &root.path().join("sibling"),
+        Path::new("link"),
+        &output.path().join("snapshot.tar.zst"),
+    );
+    assert_target(&paths, &record, &resolved);
+    assert_eq!(
+        record["invoked_as"],
+        root.path().join("sibling/link").to_str().unwrap()
+    );
+}
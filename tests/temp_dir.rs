@@ -0,0 +1,94 @@
+//! Captures are held in `--temp-dir`, which has to be usable, and is left as it was found.
+
+mod common;
+
+use std::ffi::OsString;
+use std::path::Path;
+use std::process::Output;
+
+use common::{code, sit, tar_members, write};
+
+fn snapshot(target: &Path, archive: &Path, temp_dir: &Path) -> Output {
+    let args: Vec<OsString> = vec![
+        "-t".into(),
+        target.into(),
+        "-o".into(),
+        archive.into(),
+        "--temp-dir".into(),
+        temp_dir.into(),
+    ];
+    sit(args)
+}
+
+fn target() -> tempfile::TempDir {
+    let target = tempfile::tempdir().unwrap();
+    write(&target.path().join("file"), "content");
+    write(&target.path().join("nested/file"), "nested");
+    target
+}
+
+fn entries(directory: &Path) -> usize {
+    std::fs::read_dir(directory).unwrap().count()
+}
+
+#[test]
+fn a_temp_dir_which_cant_hold_captures_exits_16_without_writing_anything() {
+    let target = target();
+    let output = tempfile::tempdir().unwrap();
+    let archive = output.path().join("snapshot.tar.zst");
+    let not_a_directory = output.path().join("file");
+    write(&not_a_directory, "");
+    for temp_dir in [output.path().join("missing"), not_a_directory] {
+        let run = snapshot(target.path(), &archive, &temp_dir);
+        assert_eq!(code(&run), 16, "{}", String::from_utf8_lossy(&run.stdout));
+        let logged = String::from_utf8_lossy(&run.stdout);
+        assert!(
+            logged.contains(&temp_dir.display().to_string()),
+            "{}",
+            logged
+        );
+        // only the file standing in as a temp dir
+        assert_eq!(entries(output.path()), 1);
+    }
+}
+
+#[test]
+fn a_usable_temp_dir_is_left_as_it_was_found() {
+    let target = target();
+    let output = tempfile::tempdir().unwrap();
+    let archive = output.path().join("snapshot.tar.zst");
+    let temp_dir = tempfile::tempdir().unwrap();
+    write(&temp_dir.path().join("unrelated"), "left alone");
+    let run = snapshot(target.path(), &archive, temp_dir.path());
+    assert_eq!(code(&run), 0, "{}", String::from_utf8_lossy(&run.stdout));
+    assert_eq!(tar_members(&archive).len(), 4);
+    assert_eq!(entries(temp_dir.path()), 1);
+    assert_eq!(
+        std::fs::read_to_string(temp_dir.path().join("unrelated")).unwrap(),
+        "left alone"
+    );
+}
+
+#[test]
+fn a_temp_dir_inside_the_target_is_left_out_of_it() {
+    let target = target();
+    let temp_dir = target.path().join("scratch");
+    std::fs::create_dir(&temp_dir).unwrap();
+    let output = tempfile::tempdir().unwrap();
+    let archive = output.path().join("snapshot.tar.zst");
+    let run = snapshot(target.path(), &archive, &temp_dir);
+    assert_eq!(code(&run), 0, "{}", String::from_utf8_lossy(&run.stdout));
+    let name = target.path().file_name().unwrap().to_string_lossy();
+    let paths: Vec<String> = tar_members(&archive)
+        .into_iter()
+        .map(|(path, _)| path)
+        .collect();
+    assert!(
+        !paths
+            .iter()
+            .any(|path| path.starts_with(&format!("{}/scratch", name))),
+        "{:?}",
+        paths
+    );
+    assert_eq!(entries(&temp_dir), 0);
+}
@@ -0,0 +1,51 @@
+//! `--timeout` stops a snapshot which runs too long, with `--allow-partial` keeping what it got.
+#![cfg(unix)]
+
+mod common;
+
+use std::ffi::OsString;
+use std::path::Path;
+
+use common::{code, sit, snapshot_records, tar_members, write};
+
+/// Snapshots a target behind a pre-hook outlasting the timeout, returning the exit code.
+fn run_out_of_time(target: &Path, archive: &Path, extra: &[&str]) -> i32 {
+    let mut args: Vec<OsString> = vec![
+        "-t".into(),
+        target.into(),
+        "-o".into(),
+        archive.into(),
+        "--timeout".into(),
+        "200ms".into(),
+        "--pre-hook".into(),
+        "sleep 1".into(),
+    ];
+    args.extend(extra.iter().map(OsString::from));
+    code(&sit(args))
+}
+
+#[test]
+fn snapshots_running_out_of_time_are_abandoned() {
+    let root = tempfile::tempdir().unwrap();
+    let target = root.path().join("target");
+    write(&target.join("file"), "content");
+    let archive = root.path().join("snapshot.tar.zst");
+
+    assert_eq!(run_out_of_time(&target, &archive, &[]), 7);
+    assert!(!archive.exists());
+}
+
+#[test]
+fn snapshots_running_out_of_time_keep_what_they_got_when_allowed() {
+    let root = tempfile::tempdir().unwrap();
+    let target = root.path().join("target");
+    write(&target.join("file"), "content");
+    let archive = root.path().join("snapshot.tar.zst");
+
+    // the timeout's exit code still tells the archive is partial
+    assert_eq!(run_out_of_time(&target, &archive, &["--allow-partial"]), 7);
+    assert_eq!(tar_members(&archive), []);
+    let records = snapshot_records(&archive);
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0]["partial"], true, "{}", records[0]);
+}
@@ -0,0 +1,73 @@
+//! Zip archives restore to the target they were written from, whatever they're named.
+#![cfg(unix)]
+
+mod common;
+
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use common::{files, restore, snapshot, write_fixture};
+
+fn mode(path: &Path) -> u32 {
+    path.symlink_metadata().unwrap().permissions().mode() & 0o7777
+}
+
+fn mtime(path: &Path) -> u64 {
+    path.symlink_metadata()
+        .unwrap()
+        .modified()
+        .unwrap()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Snapshots the fixture into `name` with the extra arguments and restores it, checking what was
+/// restored against the target.
+fn round_trip(name: &str, extra: &[&str]) {
+    let root = tempfile::tempdir().unwrap();
+    let target = root.path().join("target");
+    std::fs::create_dir(&target).unwrap();
+    write_fixture(&target);
+    let archive = root.path().join(name);
+    snapshot(&target, &archive, extra);
+    assert_eq!(&std::fs::read(&archive).unwrap()[..4], b"PK\x03\x04");
+
+    let restored = tempfile::tempdir().unwrap();
+    restore(&archive, restored.path(), &[]);
+    let restored = restored.path().join("target");
+    // zip has no hard links, so a linked file comes back as a copy
+    assert_eq!(files(&restored), files(&target));
+    assert!(restored.join("hollow").is_dir());
+    assert_eq!(
+        std::fs::read_link(restored.join("link")).unwrap(),
+        Path::new("nested/deeper/file")
+    );
+    for path in ["script", "private", "file", "nested/deeper/file"] {
+        assert_eq!(
+            mode(&restored.join(path)),
+            mode(&target.join(path)),
+            "{}",
+            path
+        );
+        assert_eq!(
+            mtime(&restored.join(path)),
+            mtime(&target.join(path)),
+            "{}",
+            path
+        );
+    }
+}
+
+#[test]
+fn deflated_zip_archives_round_trip() {
+    round_trip("snapshot.zip", &[]);
+}
+
+#[test]
+fn zstd_zip_archives_round_trip_whatever_they_are_named() {
+    round_trip(
+        "snapshot.bin",
+        &["--format", "zip", "--zip-compression", "zstd"],
+    );
+}